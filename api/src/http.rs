@@ -4,28 +4,164 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::HashMap;
-use std::io::Result;
+use std::fmt;
+use std::io::{ErrorKind, Result};
+use std::num::NonZeroU32;
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, Sender};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
+use governor::clock::DefaultClock;
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{Quota, RateLimiter};
 use http::uri::Uri;
+use nix::sys::socket::{getsockopt, sockopt};
 use url::Url;
 use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 
-use micro_http::{HttpServer, MediaType, Request, Response, StatusCode};
+use micro_http::{HttpConnection, MediaType, Request, Response, StatusCode};
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::http_endpoint::{
-    error_response, ApiError, ApiRequest, ApiResponse, EventsHandler, ExitHandler, FsBackendInfo,
-    HttpError, HttpResult, InfoHandler, MetricsBackendHandler, MetricsBlobcacheHandler,
-    MetricsFilesHandler, MetricsHandler, MetricsInflightHandler, MetricsPatternHandler,
-    MountHandler, SendFuseFdHandler, TakeoverHandler,
+    error_response, too_many_requests_response, ApiError, ApiRequest, ApiRequestMessage,
+    ApiResponse, BlobcacheHandler, CpuProfileHandler, DumpHandler, EventsHandler, ExitHandler,
+    FsBackendInfo, HealthHandler, HttpError, HttpResult, InfoHandler, MallocInfoHandler,
+    MetricsAccessHandler, MetricsBackendHandler, MetricsBlobProgressHandler,
+    MetricsBlobcacheHandler, MetricsErrorsHandler, MetricsFilesHandler, MetricsHandler,
+    MetricsInflightHandler, MetricsMemoryHandler, MetricsPatternHandler, MetricsPullHandler,
+    MountBackendHandler, MountFreezeHandler, MountHandler, PreheatHandler, SendFuseFdHandler,
+    TakeoverHandler,
 };
 
 const HTTP_ROOT: &str = "/api/v1";
+/// Idle connections are closed after the timeout to release their slots.
+const HTTP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits to protect the API server from abusive clients.
+#[derive(Clone, Debug)]
+pub struct HttpLimits {
+    /// Maximum size of request bodies in bytes.
+    pub max_body_size: usize,
+    /// Maximum number of open connections, more connections are closed once accepted.
+    pub max_connections: usize,
+    /// Maximum number of requests being handled.
+    pub max_pending_requests: usize,
+    /// Requests per second allowed for each user of callers, 0 for no limit.
+    pub rate: u32,
+    /// Maximum burst of requests allowed for each user of callers.
+    pub burst: u32,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        HttpLimits {
+            max_body_size: 0x10_0000,
+            max_connections: 16,
+            max_pending_requests: 64,
+            rate: 0,
+            burst: 0,
+        }
+    }
+}
+
+/// Credentials of the process connecting to the API server, reported by the kernel by
+/// `SO_PEERCRED` so they can't be forged by clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    fn of(stream: &UnixStream) -> Result<Self> {
+        let cred = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)?;
+        Ok(PeerCredentials {
+            pid: cred.pid(),
+            uid: cred.uid(),
+            gid: cred.gid(),
+        })
+    }
+}
+
+impl fmt::Display for PeerCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {} uid {} gid {}", self.pid, self.uid, self.gid)
+    }
+}
+
+/// Admission control of HTTP connections and requests, rejecting those exceeding the limits
+/// instead of queueing them unboundedly.
+struct Admission {
+    max_connections: usize,
+    connections: usize,
+    max_pending: usize,
+    pending: usize,
+    limiter: Option<RateLimiter<u32, DefaultKeyedStateStore<u32>, DefaultClock>>,
+}
+
+impl Admission {
+    fn new(limits: &HttpLimits) -> Self {
+        let limiter = NonZeroU32::new(limits.rate).map(|rate| {
+            let burst = NonZeroU32::new(limits.burst).unwrap_or(rate);
+            RateLimiter::keyed(Quota::per_second(rate).allow_burst(burst))
+        });
+
+        Admission {
+            max_connections: limits.max_connections,
+            connections: 0,
+            max_pending: limits.max_pending_requests,
+            pending: 0,
+            limiter,
+        }
+    }
+
+    /// Check whether to serve a new connection, return the reason if it's rejected.
+    fn connect(&mut self) -> std::result::Result<(), String> {
+        if self.connections >= self.max_connections {
+            return Err(format!(
+                "too many connections, limit {}",
+                self.max_connections
+            ));
+        }
+        self.connections += 1;
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.connections = self.connections.saturating_sub(1);
+    }
+
+    /// Check whether to accept a request from the user `uid`, return the reason if it's rejected.
+    fn admit(&mut self, uid: u32) -> std::result::Result<(), String> {
+        if self.pending >= self.max_pending {
+            return Err(format!(
+                "too many pending requests, limit {}",
+                self.max_pending
+            ));
+        }
+        if let Some(limiter) = self.limiter.as_ref() {
+            if limiter.check_key(&uid).is_err() {
+                return Err(format!("rate limit exceeded by uid {}", uid));
+            }
+            // Forget users whose buckets have been refilled to bound memory usage.
+            if limiter.len() > 1024 {
+                limiter.retain_recent();
+            }
+        }
+        self.pending += 1;
+
+        Ok(())
+    }
+
+    fn complete(&mut self) {
+        self.pending = self.pending.saturating_sub(1);
+    }
+}
 
 /// An HTTP endpoint handler interface
 pub trait EndpointHandler: Sync + Send {
@@ -62,28 +198,46 @@ lazy_static! {
 
         r.routes.insert(endpoint!("/daemon"), Box::new(InfoHandler{}));
         r.routes.insert(endpoint!("/daemon/events"), Box::new(EventsHandler{}));
+        r.routes.insert(endpoint!("/daemon/health"), Box::new(HealthHandler{}));
         r.routes.insert(endpoint!("/daemon/backend"), Box::new(FsBackendInfo{}));
+        r.routes.insert(endpoint!("/daemon/dump"), Box::new(DumpHandler{}));
         r.routes.insert(endpoint!("/daemon/exit"), Box::new(ExitHandler{}));
         r.routes.insert(endpoint!("/daemon/fuse/sendfd"), Box::new(SendFuseFdHandler{}));
         r.routes.insert(endpoint!("/daemon/fuse/takeover"), Box::new(TakeoverHandler{}));
         r.routes.insert(endpoint!("/mount"), Box::new(MountHandler{}));
+        r.routes.insert(endpoint!("/mount/backend"), Box::new(MountBackendHandler{}));
+        r.routes.insert(endpoint!("/mount/freeze"), Box::new(MountFreezeHandler{}));
+        r.routes.insert(endpoint!("/blobcache"), Box::new(BlobcacheHandler{}));
+        r.routes.insert(endpoint!("/cache/preheat"), Box::new(PreheatHandler{}));
         r.routes.insert(endpoint!("/metrics"), Box::new(MetricsHandler{}));
         r.routes.insert(endpoint!("/metrics/files"), Box::new(MetricsFilesHandler{}));
         r.routes.insert(endpoint!("/metrics/pattern"), Box::new(MetricsPatternHandler{}));
+        r.routes.insert(endpoint!("/metrics/access"), Box::new(MetricsAccessHandler{}));
+        r.routes.insert(endpoint!("/metrics/errors"), Box::new(MetricsErrorsHandler{}));
         r.routes.insert(endpoint!("/metrics/backend"), Box::new(MetricsBackendHandler{}));
         r.routes.insert(endpoint!("/metrics/blobcache"), Box::new(MetricsBlobcacheHandler{}));
+        r.routes.insert(endpoint!("/metrics/blobs"), Box::new(MetricsBlobProgressHandler{}));
         r.routes.insert(endpoint!("/metrics/inflight"), Box::new(MetricsInflightHandler{}));
+        r.routes.insert(endpoint!("/metrics/pull"), Box::new(MetricsPullHandler{}));
+        r.routes.insert(endpoint!("/metrics/memory"), Box::new(MetricsMemoryHandler{}));
+        r.routes.insert(endpoint!("/debug/pprof/profile"), Box::new(CpuProfileHandler{}));
+        r.routes.insert(endpoint!("/debug/malloc_info"), Box::new(MallocInfoHandler{}));
         r
     };
 }
 
 fn kick_api_server(
     api_evt: &EventFd,
-    to_api: &Sender<ApiRequest>,
-    from_api: &Receiver<ApiResponse>,
+    to_api: &Sender<ApiRequestMessage>,
     request: ApiRequest,
+    caller: PeerCredentials,
 ) -> ApiResponse {
-    to_api.send(request).map_err(ApiError::RequestSend)?;
+    // Each request carries its own response channel, so responses can't be mixed up when
+    // several requests are being handled concurrently.
+    let (to_http, from_api) = channel();
+    to_api
+        .send((request, caller, to_http))
+        .map_err(ApiError::RequestSend)?;
     api_evt.write(1).map_err(ApiError::EventFdWrite)?;
     from_api.recv().map_err(ApiError::ResponseRecv)?
 }
@@ -113,9 +267,9 @@ fn trace_api_end(
 
 fn handle_http_request(
     request: &Request,
+    caller: PeerCredentials,
     api_notifier: &EventFd,
-    to_api: &Sender<ApiRequest>,
-    from_api: &Receiver<ApiResponse>,
+    to_api: &Sender<ApiRequestMessage>,
 ) -> Response {
     trace_api_begin(request);
     let begin_time = SystemTime::now();
@@ -127,7 +281,7 @@ fn handle_http_request(
         Ok(uri) => match HTTP_ROUTES.routes.get(uri.path()) {
             Some(route) => route
                 .handle_request(&request, &|r| {
-                    kick_api_server(api_notifier, to_api, from_api, r)
+                    kick_api_server(api_notifier, to_api, r, caller)
                 })
                 .unwrap_or_else(|err| error_response(err, StatusCode::BadRequest)),
             None => error_response(HttpError::NoRoute, StatusCode::NotFound),
@@ -173,9 +327,117 @@ pub fn extract_query_part(req: &Request, key: &str) -> Option<String> {
 const EVENT_UNIX_SOCKET: u64 = 1;
 const EVENT_HTTP_DIE: u64 = 2;
 
+/// Shared state of the HTTP server and its connections.
+struct HttpContext {
+    admission: Mutex<Admission>,
+    max_body_size: usize,
+    api_notifier: EventFd,
+    // Cloned for each connection since `Sender` can't be shared among threads.
+    to_api: Mutex<Sender<ApiRequestMessage>>,
+}
+
+/// Serve requests of a connection until it's closed or idle, so a slow request, such as mounting
+/// a filesystem, won't block requests of other connections like querying daemon information.
+fn serve_connection(
+    stream: UnixStream,
+    peer: PeerCredentials,
+    ctx: &HttpContext,
+    to_api: &Sender<ApiRequestMessage>,
+) {
+    if let Err(e) = stream
+        .set_read_timeout(Some(HTTP_IDLE_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(HTTP_IDLE_TIMEOUT)))
+    {
+        error!("HTTP server failed to set connection timeout, {}", e);
+        return;
+    }
+    let mut conn = HttpConnection::new(stream);
+    conn.set_payload_max_size(ctx.max_body_size);
+
+    loop {
+        // Requests parsed before an error are still answered, as well as malformed requests,
+        // which get error responses from micro_http.
+        let read = conn.try_read();
+        while let Some(request) = conn.pop_parsed_request() {
+            let admitted = ctx.admission.lock().unwrap().admit(peer.uid);
+            let response = match admitted {
+                Ok(()) => {
+                    let response = handle_http_request(&request, peer, &ctx.api_notifier, to_api);
+                    ctx.admission.lock().unwrap().complete();
+                    response
+                }
+                Err(reason) => {
+                    warn!("HTTP server rejected request from {}, {}", peer, reason);
+                    let mut response = too_many_requests_response(reason);
+                    response.set_server("Nydus API");
+                    response.set_content_type(MediaType::ApplicationJson);
+                    response
+                }
+            };
+            conn.enqueue_response(response);
+        }
+        while conn.pending_write() {
+            if let Err(e) = conn.try_write() {
+                warn!("HTTP server failed to respond to {}, {:?}", peer, e);
+                return;
+            }
+        }
+        if let Err(e) = read {
+            debug!("HTTP connection of {} closed, {:?}", peer, e);
+            return;
+        }
+    }
+}
+
+// Accept pending connections, each served by a dedicated thread up to the connection limit.
+fn accept_connections(listener: &UnixListener, ctx: &Arc<HttpContext>) {
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) => {
+                error!("HTTP server failed to accept connection, {}", e);
+                return;
+            }
+        };
+        let peer = match stream
+            .set_nonblocking(false)
+            .and_then(|_| PeerCredentials::of(&stream))
+        {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("HTTP server failed to get peer credentials, {}", e);
+                continue;
+            }
+        };
+        // The connection is closed by dropping the stream if rejected.
+        if let Err(reason) = ctx.admission.lock().unwrap().connect() {
+            warn!("HTTP server rejected connection from {}, {}", peer, reason);
+            continue;
+        }
+
+        let conn_ctx = ctx.clone();
+        let to_api = ctx.to_api.lock().unwrap().clone();
+        let spawned = thread::Builder::new()
+            .name("http-connection".to_string())
+            .spawn(move || {
+                serve_connection(stream, peer, &conn_ctx, &to_api);
+                conn_ctx.admission.lock().unwrap().disconnect();
+            });
+        if let Err(e) = spawned {
+            error!(
+                "HTTP server failed to serve connection from {}, {}",
+                peer, e
+            );
+            ctx.admission.lock().unwrap().disconnect();
+        }
+    }
+}
+
 /// Start a HTTP server parsing http requests and send to nydus API server a concrete
 /// request to operate nydus or fetch working status.
-/// The HTTP server sends request by `to_api` channel and wait for response from `from_api` channel
+/// Each connection is served by a dedicated thread, which sends requests by `to_api` channel and
+/// waits for responses from the channel carried by each request.
 /// `api_notifier` is used to notify an execution context to fetch above request and handle it.
 /// We can't forward signal to native rust thread, so we rely on `exit_evtfd` to notify
 /// the server to exit. Therefore, it adds the unix domain socket fd receiving http request
@@ -184,25 +446,29 @@ const EVENT_HTTP_DIE: u64 = 2;
 pub fn start_http_thread(
     path: &str,
     api_notifier: EventFd,
-    to_api: Sender<ApiRequest>,
-    from_api: Receiver<ApiResponse>,
+    to_api: Sender<ApiRequestMessage>,
     exit_evtfd: EventFd,
+    limits: HttpLimits,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     // Try to remove existed unix domain socket
     std::fs::remove_file(path).unwrap_or_default();
-    let socket_path = PathBuf::from(path);
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+
+    let ctx = Arc::new(HttpContext {
+        admission: Mutex::new(Admission::new(&limits)),
+        max_body_size: limits.max_body_size,
+        api_notifier,
+        to_api: Mutex::new(to_api),
+    });
 
     let thread = thread::Builder::new()
         .name("http-server".to_string())
         .spawn(move || {
-            let epoll_fd = Epoll::new().unwrap();
-
-            let mut server = HttpServer::new(socket_path).unwrap();
-            // Must start the server successfully or just die by panic
-            server.start_server().unwrap();
+            let epoll_fd = Epoll::new()?;
             epoll_fd.ctl(
                 ControlOperation::Add,
-                server.epoll().as_raw_fd(),
+                listener.as_raw_fd(),
                 EpollEvent::new(EventSet::IN, EVENT_UNIX_SOCKET),
             )?;
 
@@ -216,7 +482,7 @@ pub fn start_http_thread(
 
             info!("http server started");
 
-            'wait: loop {
+            loop {
                 let num = epoll_fd.wait(-1, events.as_mut_slice()).map_err(|e| {
                     error!("Wait event error. {:?}", e);
                     e
@@ -224,32 +490,8 @@ pub fn start_http_thread(
 
                 for event in &events[..num] {
                     match event.data() {
-                        EVENT_UNIX_SOCKET => match server.requests() {
-                            Ok(request_vec) => {
-                                for server_request in request_vec {
-                                    // Ignore error when sending response
-                                    server
-                                        .respond(server_request.process(|request| {
-                                            handle_http_request(
-                                                request,
-                                                &api_notifier,
-                                                &to_api,
-                                                &from_api,
-                                            )
-                                        }))
-                                        .unwrap_or_else(|e| {
-                                            error!("HTTP server error on response: {}", e)
-                                        });
-                                }
-                            }
-                            Err(e) => {
-                                error!(
-                                    "HTTP server error on retrieving incoming request. Error: {}",
-                                    e
-                                );
-                            }
-                        },
-                        EVENT_HTTP_DIE => break 'wait Ok(()),
+                        EVENT_UNIX_SOCKET => accept_connections(&listener, &ctx),
+                        EVENT_HTTP_DIE => return Ok(()),
                         _ => error!("Invalid event"),
                     }
                 }
@@ -258,3 +500,56 @@ pub fn start_http_thread(
 
     Ok(thread)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admission() {
+        let limits = HttpLimits {
+            max_pending_requests: 3,
+            rate: 1,
+            burst: 2,
+            ..Default::default()
+        };
+        let mut admission = Admission::new(&limits);
+        assert!(admission.admit(1000).is_ok());
+        assert!(admission.admit(1000).is_ok());
+        // Buckets are per user.
+        assert!(admission.admit(1000).is_err());
+        assert!(admission.admit(0).is_ok());
+        // Pending requests are limited for all users.
+        assert!(admission.admit(1001).is_err());
+        admission.complete();
+        assert!(admission.admit(1001).is_ok());
+
+        let mut admission = Admission::new(&HttpLimits::default());
+        for _ in 0..64 {
+            assert!(admission.admit(0).is_ok());
+        }
+        assert!(admission.admit(0).is_err());
+    }
+
+    #[test]
+    fn test_admission_connections() {
+        let limits = HttpLimits {
+            max_connections: 2,
+            ..Default::default()
+        };
+        let mut admission = Admission::new(&limits);
+        assert!(admission.connect().is_ok());
+        assert!(admission.connect().is_ok());
+        assert!(admission.connect().is_err());
+        admission.disconnect();
+        assert!(admission.connect().is_ok());
+    }
+
+    #[test]
+    fn test_peer_credentials() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let peer = PeerCredentials::of(&a).unwrap();
+        assert_eq!(peer.pid as u32, std::process::id());
+        assert_eq!(peer.uid, nix::unistd::getuid().as_raw());
+    }
+}
@@ -8,18 +8,31 @@
 //! to export running metrics. So it will be easier to wrap different crates' Error
 //! into.
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io;
-use std::sync::mpsc::{RecvError, SendError};
+use std::str::FromStr;
+use std::sync::mpsc::{RecvError, SendError, Sender};
+use std::time::Duration;
 
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 
 use serde::Deserialize;
 use serde_json::Error as SerdeError;
 
-use crate::http::{extract_query_part, EndpointHandler};
+use crate::http::{extract_query_part, EndpointHandler, PeerCredentials};
 
 use nydus_utils::metrics::IoStatsError;
+use nydus_utils::profiling::ProfileFormat;
+
+/// Default duration of CPU profiling in seconds.
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+/// Maximum duration of CPU profiling in seconds.
+const MAX_PROFILE_SECONDS: u64 = 300;
+/// Default time in seconds before a frozen mount is thawed automatically.
+const DEFAULT_FREEZE_SECONDS: u64 = 600;
+/// Maximum time in seconds before a frozen mount is thawed automatically.
+const MAX_FREEZE_SECONDS: u64 = 86400;
 
 #[derive(Debug)]
 pub enum DaemonErrorKind {
@@ -33,6 +46,12 @@ pub enum DaemonErrorKind {
     Channel,
     Serde(SerdeError),
     UnexpectedEvent(String),
+    /// Something has already been mounted at the mountpoint, with details of the existing mount.
+    AlreadyExists(String),
+    /// Nothing has been mounted at the mountpoint.
+    NotFound,
+    /// The image to mount violates the content trust policy.
+    PolicyViolation(String),
     Other(String),
 }
 
@@ -52,7 +71,7 @@ pub enum ApiError {
     /// Cannot mount a resource
     MountFailure(DaemonErrorKind),
     /// API request send error
-    RequestSend(SendError<ApiRequest>),
+    RequestSend(SendError<ApiRequestMessage>),
     /// Wrong response payload type
     ResponsePayloadType,
     /// API response receive error
@@ -69,16 +88,36 @@ pub enum ApiResponsePayload {
     Empty,
     /// Nydus daemon general working information.
     DaemonInfo(String),
+    /// Results of health checks, and whether all of the checks have passed.
+    DaemonHealth(String, bool),
     Events(String),
+    /// Path of the file of the daemon state snapshot.
+    DaemonDump(String),
     FsBackendInfo(String),
     /// Nydus filesystem global metrics
     FsGlobalMetrics(String),
     /// Nydus filesystem per-file metrics
     FsFilesMetrics(String),
     FsFilesPatterns(String),
+    /// Ordered list of reads recorded after mount
+    AccessRecords(String),
+    /// Counters and recent details of classified errors
+    ErrorMetrics(String),
     BackendMetrics(String),
     BlobcacheMetrics(String),
+    /// Download progress of blobs
+    BlobProgress(String),
     InflightMetrics(String),
+    /// Per image lazy pull metrics
+    PullMetrics(String),
+    /// Memory used by the daemon and each mount
+    MemoryMetrics(String),
+    /// Information about a mounted filesystem.
+    MountInfo(String),
+    /// CPU profile or allocator statistics of the daemon.
+    Profile(Vec<u8>),
+    /// Id of the new cache preheating task, or status of recent tasks.
+    Preheat(String),
 }
 
 /// This is the response sent by the API server through the mpsc channel.
@@ -88,31 +127,112 @@ pub type HttpResult = std::result::Result<Response, HttpError>;
 #[derive(Debug)]
 pub enum ApiRequest {
     DaemonInfo,
+    /// Run health checks, only those for liveness if it's true.
+    DaemonHealth(bool),
     Events,
+    /// Write a snapshot of the daemon state to the named file in the dump directory, or to a file
+    /// named by the process id and time if `None`.
+    DumpState(Option<String>),
     Mount(String, ApiMountCmd),
+    GetMount(String),
     Remount(String, ApiMountCmd),
+    /// Switch the storage backend of a mount without unmounting it.
+    SwitchBackend(String, ApiBackendCmd),
+    /// Hold reads of a mount until it's thawed or the duration passes.
+    FreezeMount(String, Duration),
+    /// Let reads held by freezing a mount go on.
+    ThawMount(String),
     Umount(String),
     ConfigureDaemon(DaemonConf),
     ExportGlobalMetrics(Option<String>),
     ExportFilesMetrics(Option<String>, bool),
     ExportAccessPatterns(Option<String>),
+    ExportAccessRecords(Option<String>),
+    ExportErrorMetrics(Option<String>),
     ExportBackendMetrics(Option<String>),
     ExportBlobcacheMetrics(Option<String>),
+    ExportBlobProgress(Option<String>),
     ExportInflightMetrics,
+    ExportPullMetrics,
+    ExportMemoryMetrics,
     ExportFsBackendInfo(String),
+    PurgeBlobcache,
+    /// Warm up the blob cache in background.
+    Preheat(ApiPreheatCmd),
+    /// Get status of recent cache preheating tasks.
+    GetPreheat,
+    CpuProfile(Duration, ProfileFormat),
+    MallocInfo,
     SendFuseFd,
     Takeover,
     Exit,
 }
 
+/// Message sent to the API server, carrying the request, credentials of its caller, and a channel to
+/// send back the response.
+pub type ApiRequestMessage = (ApiRequest, PeerCredentials, Sender<ApiResponse>);
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct ApiMountCmd {
     pub source: String,
     #[serde(default)]
     pub fs_type: String,
+    /// Configuration of the mount, merged over the default configuration of nydusd.
+    #[serde(default)]
     pub config: String,
     #[serde(default)]
     pub prefetch_files: Option<Vec<String>>,
+    /// Succeed without doing anything if the same source has already been mounted.
+    #[serde(default)]
+    pub idempotent: bool,
+    /// Labels attached to the mount, such as containerd snapshot labels. The bootstrap is
+    /// fetched from the registry according to the labels if `source` is empty.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Rafs bootstrap encoded in base64, mounted instead of `source` without being written to
+    /// disk.
+    #[serde(default)]
+    pub bootstrap: Option<String>,
+}
+
+/// Storage backend to switch a mount to, in the format of `device.backend` of mount configurations.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiBackendCmd {
+    /// Type of the storage backend, such as `registry`, `oss` or `localfs`.
+    #[serde(rename = "type")]
+    pub backend_type: String,
+    /// Configuration of the storage backend.
+    pub config: serde_json::Value,
+}
+
+/// Request to warm up the blob cache with images and ranges of blobs.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiPreheatCmd {
+    /// Configuration to fetch images, merged over the default configuration of nydusd.
+    #[serde(default)]
+    pub config: String,
+    #[serde(default)]
+    pub images: Vec<ApiPreheatImage>,
+    #[serde(default)]
+    pub blobs: Vec<ApiPreheatBlob>,
+}
+
+/// An image to warm up, by files recorded in a trace of the image or by all of its files.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiPreheatImage {
+    /// Image reference, e.g. `docker.io/library/busybox:latest`.
+    pub reference: String,
+    /// Files and directories to fetch, all files of the image if absent.
+    #[serde(default)]
+    pub files: Option<Vec<String>>,
+}
+
+/// A range of uncompressed data of a blob to warm up.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiPreheatBlob {
+    pub blob_id: String,
+    pub offset: u64,
+    pub size: u64,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -141,18 +261,29 @@ pub enum HttpError {
     ParseBody(SerdeError),
     /// Could not query daemon info
     Info(ApiError),
+    Health(ApiError),
     Events(ApiError),
+    /// Could not dump daemon state
+    Dump(ApiError),
     /// Could not mount resource
     Mount(ApiError),
     GlobalMetrics(ApiError),
     FsFilesMetrics(ApiError),
     Pattern(ApiError),
+    AccessRecords(ApiError),
+    ErrorMetrics(ApiError),
     Configure(ApiError),
     Upgrade(ApiError),
     BlobcacheMetrics(ApiError),
+    BlobProgress(ApiError),
     BackendMetrics(ApiError),
     FsBackendInfo(ApiError),
     InflightMetrics(ApiError),
+    PullMetrics(ApiError),
+    MemoryMetrics(ApiError),
+    PurgeBlobcache(ApiError),
+    Preheat(ApiError),
+    Profile(ApiError),
 }
 
 fn success_response(body: Option<String>) -> Response {
@@ -168,6 +299,24 @@ fn success_response(body: Option<String>) -> Response {
     r
 }
 
+// Unhealthy daemons respond with the failed checks, so probes fail but clients can still tell why.
+fn health_response(body: String, healthy: bool) -> Response {
+    let status_code = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::ServiceUnavailable
+    };
+    let mut r = Response::new(Version::Http11, status_code);
+    r.set_body(Body::new(body));
+    r
+}
+
+fn binary_response(body: Vec<u8>) -> Response {
+    let mut r = Response::new(Version::Http11, StatusCode::OK);
+    r.set_body(Body::new(body));
+    r
+}
+
 #[derive(Serialize, Debug)]
 struct ErrorMessage {
     code: String,
@@ -192,12 +341,56 @@ pub fn error_response(error: HttpError, status: StatusCode) -> Response {
     response
 }
 
+// Something has already been mounted at the requested mountpoint, so return details about the
+// existing mount to help the client to figure out what happened. micro_http has no status code
+// 409, so it's told apart from other bad requests by the error code.
+fn conflict_response(existing: String) -> Response {
+    let mut response = Response::new(Version::Http11, StatusCode::BadRequest);
+
+    let err_msg = ErrorMessage {
+        code: "ALREADY_EXISTS".to_string(),
+        message: existing,
+    };
+    response.set_body(Body::new(err_msg));
+    response
+}
+
+// The image to mount violates the content trust policy, return the reason so the client can tell
+// it apart from other mount failures.
+fn policy_violation_response(reason: String) -> Response {
+    let mut response = Response::new(Version::Http11, StatusCode::BadRequest);
+
+    let err_msg = ErrorMessage {
+        code: "POLICY_VIOLATION".to_string(),
+        message: reason,
+    };
+    response.set_body(Body::new(err_msg));
+    response
+}
+
+/// The request is rejected by limits of the API server, the client should retry later.
+///
+/// micro_http has no status code 429, so the service is reported unavailable.
+pub fn too_many_requests_response(reason: String) -> Response {
+    let mut response = Response::new(Version::Http11, StatusCode::ServiceUnavailable);
+
+    let err_msg = ErrorMessage {
+        code: "TOO_MANY_REQUESTS".to_string(),
+        message: reason,
+    };
+    response.set_body(Body::new(err_msg));
+    response
+}
+
 fn translate_status_code(e: &ApiError) -> StatusCode {
     match e {
         ApiError::DaemonAbnormal(kind) | ApiError::MountFailure(kind) => match kind {
             DaemonErrorKind::NotReady => StatusCode::ServiceUnavailable,
             DaemonErrorKind::Unsupported => StatusCode::NotImplemented,
             DaemonErrorKind::UnexpectedEvent(_) => StatusCode::BadRequest,
+            DaemonErrorKind::AlreadyExists(_) => StatusCode::BadRequest,
+            DaemonErrorKind::NotFound => StatusCode::NotFound,
+            DaemonErrorKind::PolicyViolation(_) => StatusCode::BadRequest,
             _ => StatusCode::InternalServerError,
         },
         ApiError::Metrics(MetricsErrorKind::Stats(IoStatsError::NoCounter)) => StatusCode::NotFound,
@@ -216,16 +409,32 @@ fn convert_to_response<O: FnOnce(ApiError) -> HttpError>(api_resp: ApiResponse,
             match r {
                 Empty => success_response(None),
                 DaemonInfo(d) => success_response(Some(d)),
+                DaemonHealth(d, healthy) => health_response(d, healthy),
                 Events(d) => success_response(Some(d)),
+                DaemonDump(d) => success_response(Some(d)),
                 FsFilesMetrics(d) => success_response(Some(d)),
                 FsGlobalMetrics(d) => success_response(Some(d)),
                 FsFilesPatterns(d) => success_response(Some(d)),
+                AccessRecords(d) => success_response(Some(d)),
+                ErrorMetrics(d) => success_response(Some(d)),
                 BackendMetrics(d) => success_response(Some(d)),
                 BlobcacheMetrics(d) => success_response(Some(d)),
+                BlobProgress(d) => success_response(Some(d)),
                 FsBackendInfo(d) => success_response(Some(d)),
                 InflightMetrics(d) => success_response(Some(d)),
+                PullMetrics(d) => success_response(Some(d)),
+                MemoryMetrics(d) => success_response(Some(d)),
+                MountInfo(d) => success_response(Some(d)),
+                Profile(d) => binary_response(d),
+                Preheat(d) => success_response(Some(d)),
             }
         }
+        Err(ApiError::MountFailure(DaemonErrorKind::AlreadyExists(existing))) => {
+            conflict_response(existing)
+        }
+        Err(ApiError::MountFailure(DaemonErrorKind::PolicyViolation(reason))) => {
+            policy_violation_response(reason)
+        }
         Err(e) => {
             let sc = translate_status_code(&e);
             error_response(op(e), sc)
@@ -255,6 +464,51 @@ impl EndpointHandler for InfoHandler {
     }
 }
 
+pub struct DumpHandler {}
+impl EndpointHandler for DumpHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, None) => {
+                let name = extract_query_part(req, "name");
+                let r = kicker(ApiRequest::DumpState(name));
+                Ok(convert_to_response(r, HttpError::Dump))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct HealthHandler {}
+impl EndpointHandler for HealthHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let liveness = match extract_query_part(req, "probe").as_deref() {
+                    Some("liveness") => true,
+                    Some("readiness") | None => false,
+                    Some(p) => {
+                        return Err(HttpError::QueryString(format!(
+                            "invalid probe {}, should be liveness or readiness",
+                            p
+                        )))
+                    }
+                };
+                let r = kicker(ApiRequest::DaemonHealth(liveness));
+                Ok(convert_to_response(r, HttpError::Health))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
 pub struct EventsHandler {}
 impl EndpointHandler for EventsHandler {
     fn handle_request(
@@ -283,6 +537,10 @@ impl EndpointHandler for MountHandler {
             HttpError::QueryString("'mountpoint' should be specified in query string".to_string())
         })?;
         match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::GetMount(mountpoint));
+                Ok(convert_to_response(r, HttpError::Mount))
+            }
             (Method::Post, Some(body)) => {
                 let cmd = parse_body(body)?;
                 let r = kicker(ApiRequest::Mount(mountpoint, cmd));
@@ -302,6 +560,66 @@ impl EndpointHandler for MountHandler {
     }
 }
 
+pub struct MountBackendHandler {}
+impl EndpointHandler for MountBackendHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        let mountpoint = extract_query_part(req, "mountpoint").ok_or_else(|| {
+            HttpError::QueryString("'mountpoint' should be specified in query string".to_string())
+        })?;
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, Some(body)) => {
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::SwitchBackend(mountpoint, cmd));
+                Ok(convert_to_response(r, HttpError::Mount))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct MountFreezeHandler {}
+impl EndpointHandler for MountFreezeHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        let mountpoint = extract_query_part(req, "mountpoint").ok_or_else(|| {
+            HttpError::QueryString("'mountpoint' should be specified in query string".to_string())
+        })?;
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, None) => {
+                let seconds = match extract_query_part(req, "timeout") {
+                    Some(s) => match s.parse::<u64>() {
+                        Ok(v) if v > 0 && v <= MAX_FREEZE_SECONDS => v,
+                        _ => {
+                            return Err(HttpError::QueryString(format!(
+                                "'timeout' should be in range [1, {}]",
+                                MAX_FREEZE_SECONDS
+                            )))
+                        }
+                    },
+                    None => DEFAULT_FREEZE_SECONDS,
+                };
+                let r = kicker(ApiRequest::FreezeMount(
+                    mountpoint,
+                    Duration::from_secs(seconds),
+                ));
+                Ok(convert_to_response(r, HttpError::Mount))
+            }
+            (Method::Delete, None) => {
+                let r = kicker(ApiRequest::ThawMount(mountpoint));
+                Ok(convert_to_response(r, HttpError::Mount))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
 pub struct MetricsHandler {}
 impl EndpointHandler for MetricsHandler {
     fn handle_request(
@@ -358,6 +676,60 @@ impl EndpointHandler for MetricsPatternHandler {
     }
 }
 
+pub struct MetricsAccessHandler {}
+impl EndpointHandler for MetricsAccessHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let id = extract_query_part(req, "id");
+                let r = kicker(ApiRequest::ExportAccessRecords(id));
+                Ok(convert_to_response(r, HttpError::AccessRecords))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct MetricsErrorsHandler {}
+impl EndpointHandler for MetricsErrorsHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let id = extract_query_part(req, "id");
+                let r = kicker(ApiRequest::ExportErrorMetrics(id));
+                Ok(convert_to_response(r, HttpError::ErrorMetrics))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct MetricsBlobProgressHandler {}
+impl EndpointHandler for MetricsBlobProgressHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let id = extract_query_part(req, "id");
+                let r = kicker(ApiRequest::ExportBlobProgress(id));
+                Ok(convert_to_response(r, HttpError::BlobProgress))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
 pub struct MetricsBackendHandler {}
 impl EndpointHandler for MetricsBackendHandler {
     fn handle_request(
@@ -411,6 +783,44 @@ impl EndpointHandler for MetricsInflightHandler {
     }
 }
 
+/// Report how much data of each mounted image has actually been downloaded compared to the
+/// size of the whole image, so as to show savings of lazy pulling.
+pub struct MetricsPullHandler {}
+impl EndpointHandler for MetricsPullHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::ExportPullMetrics);
+                Ok(convert_to_response(r, HttpError::PullMetrics))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+/// Report resident memory of the daemon and its cap, and memory used by metadata and in-flight
+/// buffers of each mount.
+pub struct MetricsMemoryHandler {}
+impl EndpointHandler for MetricsMemoryHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::ExportMemoryMetrics);
+                Ok(convert_to_response(r, HttpError::MemoryMetrics))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
 pub struct SendFuseFdHandler {}
 impl EndpointHandler for SendFuseFdHandler {
     fn handle_request(
@@ -462,6 +872,45 @@ impl EndpointHandler for ExitHandler {
     }
 }
 
+pub struct BlobcacheHandler {}
+impl EndpointHandler for BlobcacheHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Delete, None) => {
+                let r = kicker(ApiRequest::PurgeBlobcache);
+                Ok(convert_to_response(r, HttpError::PurgeBlobcache))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct PreheatHandler {}
+impl EndpointHandler for PreheatHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Post, Some(body)) => {
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::Preheat(cmd));
+                Ok(convert_to_response(r, HttpError::Preheat))
+            }
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::GetPreheat);
+                Ok(convert_to_response(r, HttpError::Preheat))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
 pub struct FsBackendInfo {}
 
 impl EndpointHandler for FsBackendInfo {
@@ -484,3 +933,54 @@ impl EndpointHandler for FsBackendInfo {
         }
     }
 }
+
+pub struct CpuProfileHandler {}
+impl EndpointHandler for CpuProfileHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let seconds = match extract_query_part(req, "seconds") {
+                    Some(s) => match s.parse::<u64>() {
+                        Ok(v) if v > 0 && v <= MAX_PROFILE_SECONDS => v,
+                        _ => {
+                            return Err(HttpError::QueryString(format!(
+                                "'seconds' should be in range [1, {}]",
+                                MAX_PROFILE_SECONDS
+                            )))
+                        }
+                    },
+                    None => DEFAULT_PROFILE_SECONDS,
+                };
+                let format = match extract_query_part(req, "format") {
+                    Some(f) => ProfileFormat::from_str(&f)
+                        .map_err(|e| HttpError::QueryString(e.to_string()))?,
+                    None => ProfileFormat::Protobuf,
+                };
+                let r = kicker(ApiRequest::CpuProfile(Duration::from_secs(seconds), format));
+                Ok(convert_to_response(r, HttpError::Profile))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct MallocInfoHandler {}
+impl EndpointHandler for MallocInfoHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::MallocInfo);
+                Ok(convert_to_response(r, HttpError::Profile))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
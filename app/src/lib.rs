@@ -8,7 +8,8 @@
 //! The `nydus-app` crates provides common helpers and utilities to support Nydus application:
 //! - Application Building Information: [`struct BuildTimeInfo`](struct.BuildTimeInfo.html) and
 //!   [`fn dump_program_info()`](fn.dump_program_info.html).
-//! - Logging helpers: [`fn setup_logging()`](fn.set_logging.html) and
+//! - Logging helpers: [`fn setup_logging()`](fn.set_logging.html),
+//!   [`fn setup_logging_with_options()`](fn.setup_logging_with_options.html) and
 //!   [`fn log_level_to_verbosity()`](fn.log_level_to_verbosity.html).
 //! - Signal handling: [`fn register_signal_handler()`](signal/fn.register_signal_handler.html).
 //!
@@ -43,11 +44,15 @@ extern crate nydus_error;
 extern crate serde;
 
 use std::env::current_dir;
-use std::io::Result;
+use std::io::{Result, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use flexi_logger::{self, colored_opt_format, opt_format, Logger};
-use log::LevelFilter;
+use flexi_logger::{
+    self, colored_opt_format, opt_format, Age, Cleanup, Criterion, DeferredNow, FormatFunction,
+    Logger, Naming,
+};
+use log::{LevelFilter, Record};
 
 pub mod signal;
 
@@ -108,6 +113,104 @@ impl BuildTimeInfo {
     }
 }
 
+/// Format of log messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Human readable text.
+    Text,
+    /// One JSON object per line, to be shipped to log collectors.
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(einval!(format!("invalid log format {}", s))),
+        }
+    }
+}
+
+/// Age of log files to trigger rotation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogRotationAge {
+    Hourly,
+    Daily,
+}
+
+impl FromStr for LogRotationAge {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hourly" => Ok(LogRotationAge::Hourly),
+            "daily" => Ok(LogRotationAge::Daily),
+            _ => Err(einval!(format!("invalid log rotation age {}", s))),
+        }
+    }
+}
+
+/// Options to control format and rotation of log messages.
+#[derive(Clone, Debug, Default)]
+pub struct LoggingOptions {
+    /// Format of log messages.
+    pub format: LogFormat,
+    /// Rotate the log file when it's bigger than the size in bytes, 0 to disable.
+    pub rotation_size: u64,
+    /// Rotate the log file when it's older than the age.
+    pub rotation_age: Option<LogRotationAge>,
+    /// Number of rotated log files to keep, 0 to keep all of them.
+    pub keep_files: usize,
+}
+
+impl LoggingOptions {
+    fn rotation_criterion(&self) -> Option<Criterion> {
+        let age = self.rotation_age.map(|age| match age {
+            LogRotationAge::Hourly => Age::Hour,
+            LogRotationAge::Daily => Age::Day,
+        });
+
+        match (age, self.rotation_size) {
+            (None, 0) => None,
+            (None, size) => Some(Criterion::Size(size)),
+            (Some(age), 0) => Some(Criterion::Age(age)),
+            (Some(age), size) => Some(Criterion::AgeOrSize(age, size)),
+        }
+    }
+
+    fn cleanup(&self) -> Cleanup {
+        if self.keep_files == 0 {
+            Cleanup::Never
+        } else {
+            Cleanup::KeepLogFiles(self.keep_files)
+        }
+    }
+}
+
+/// Format a log record as a JSON object.
+pub fn json_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> Result<()> {
+    let entry = serde_json::json!({
+        "timestamp": now.now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "file": record.file(),
+        "line": record.line(),
+        "thread": std::thread::current().name(),
+        "message": record.args().to_string(),
+    });
+
+    write!(w, "{}", entry)
+}
+
 /// Setup logging infrastructure for application.
 ///
 /// `log_file_path` is an absolute path to logging files or relative path from current working
@@ -116,9 +219,26 @@ impl BuildTimeInfo {
 /// unless we set it intentionally. I don't like this passion. When the basename of `log_file_path`
 /// is "bar", the newly created log file will be "bar.log"
 pub fn setup_logging(log_file_path: Option<PathBuf>, level: LevelFilter) -> Result<()> {
+    setup_logging_with_options(log_file_path, level, &LoggingOptions::default())
+}
+
+/// Setup logging infrastructure for application, with format and rotation of log messages
+/// controlled by `options`.
+///
+/// Log files are only rotated when logging to `log_file_path`. When rotation is enabled, the
+/// current log file is named with a "_rCURRENT" infix, and rotated log files are numbered.
+pub fn setup_logging_with_options(
+    log_file_path: Option<PathBuf>,
+    level: LevelFilter,
+    options: &LoggingOptions,
+) -> Result<()> {
     if let Some(ref path) = log_file_path {
         // Do not try to canonicalize the path since the file may not exist yet.
 
+        let format: FormatFunction = match options.format {
+            LogFormat::Text => opt_format,
+            LogFormat::Json => json_format,
+        };
         // We rely on rust `log` macro to limit current log level rather than `flexi_logger`
         // So we set `flexi_logger` log level to "trace" which is High enough. Otherwise, we
         // can't change log level to a higher level than what is passed to `flexi_logger`.
@@ -126,7 +246,10 @@ pub fn setup_logging(log_file_path: Option<PathBuf>, level: LevelFilter) -> Resu
             .log_to_file()
             .suppress_timestamp()
             .append()
-            .format(opt_format);
+            .format(format);
+        if let Some(criterion) = options.rotation_criterion() {
+            logger = logger.rotate(criterion, Naming::Numbers, options.cleanup());
+        }
 
         // Parse log file to get the `basename` and `suffix`(extension) because `flexi_logger`
         // will automatically add `.log` suffix if we don't set explicitly, see:
@@ -170,11 +293,15 @@ pub fn setup_logging(log_file_path: Option<PathBuf>, level: LevelFilter) -> Resu
             eother!(e)
         })?;
     } else {
+        let format: FormatFunction = match options.format {
+            LogFormat::Text => colored_opt_format,
+            LogFormat::Json => json_format,
+        };
         // We rely on rust `log` macro to limit current log level rather than `flexi_logger`
         // So we set `flexi_logger` log level to "trace" which is High enough. Otherwise, we
         // can't change log level to a higher level than what is passed to `flexi_logger`.
         Logger::with_env_or_str("trace")
-            .format(colored_opt_format)
+            .format(format)
             .start()
             .map_err(|e| eother!(e))?;
     }
@@ -193,4 +320,31 @@ mod tests {
         assert_eq!(log_level_to_verbosity(log::LevelFilter::Error), 0);
         assert_eq!(log_level_to_verbosity(log::LevelFilter::Warn), 1);
     }
+
+    #[test]
+    fn test_logging_options() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert_eq!(
+            "daily".parse::<LogRotationAge>().unwrap(),
+            LogRotationAge::Daily
+        );
+        assert!("weekly".parse::<LogRotationAge>().is_err());
+
+        let mut options = LoggingOptions::default();
+        assert!(options.rotation_criterion().is_none());
+        options.rotation_size = 0x100000;
+        assert!(matches!(
+            options.rotation_criterion(),
+            Some(Criterion::Size(0x100000))
+        ));
+        options.rotation_age = Some(LogRotationAge::Hourly);
+        assert!(matches!(
+            options.rotation_criterion(),
+            Some(Criterion::AgeOrSize(Age::Hour, 0x100000))
+        ));
+        assert!(matches!(options.cleanup(), Cleanup::Never));
+        options.keep_files = 3;
+        assert!(matches!(options.cleanup(), Cleanup::KeepLogFiles(3)));
+    }
 }
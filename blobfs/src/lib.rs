@@ -526,6 +526,7 @@ impl BlobFs {
         let mut rafs_conf = blob_ondemand_conf.rafs_conf.clone();
         // we must use direct mode to get mmap'd bootstrap.
         rafs_conf.mode = "direct".to_string();
+        rafs_conf.set_default_signature(path);
         let mut bootstrap =
             <dyn RafsIoRead>::from_file(path.to_str().unwrap()).map_err(|e| eother!(e))?;
         let mut rafs = Rafs::new(rafs_conf, "blobfs", &mut bootstrap)
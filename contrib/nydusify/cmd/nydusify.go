@@ -142,6 +142,7 @@ func main() {
 				&cli.StringFlag{Name: "nydus-image", Value: "nydus-image", Usage: "The nydus-image binary path, if unset, search in PATH environment", EnvVars: []string{"NYDUS_IMAGE"}},
 				&cli.BoolFlag{Name: "multi-platform", Value: false, Usage: "Merge OCI & Nydus manifest to manifest index for target image, please ensure that OCI manifest already exists in target image", EnvVars: []string{"MULTI_PLATFORM"}},
 				&cli.StringFlag{Name: "platform", Value: "linux/" + runtime.GOARCH, Usage: "Let nydusify choose image of specified platform from manifest index. Possible value is `amd64` or `arm64`"},
+				&cli.BoolFlag{Name: "all-platforms", Value: false, Usage: "Convert images of all supported platforms in source manifest index and push them as a manifest index, --platform is ignored", EnvVars: []string{"ALL_PLATFORMS"}},
 				&cli.BoolFlag{Name: "docker-v2-format", Value: false, Usage: "Use docker image manifest v2, schema 2 format", EnvVars: []string{"DOCKER_V2_FORMAT"}},
 				&cli.StringFlag{Name: "backend-type", Value: "registry", Usage: "Specify Nydus blob storage backend type", EnvVars: []string{"BACKEND_TYPE"}},
 				&cli.StringFlag{Name: "backend-config", Value: "", Usage: "Specify Nydus blob storage backend in JSON config string", EnvVars: []string{"BACKEND_CONFIG"}},
@@ -160,6 +161,8 @@ func main() {
 				// chosen to make it compatible with the 127 max in graph driver of
 				// docker so that we can pull cache image using docker.
 				&cli.UintFlag{Name: "build-cache-max-records", Value: maxCacheMaxRecords, Usage: "Maximum cache records in cache image", EnvVars: []string{"BUILD_CACHE_MAX_RECORDS"}},
+				&cli.BoolFlag{Name: "check", Value: false, Usage: "Check the converted Nydus image by mounting it with nydusd and comparing with source image", EnvVars: []string{"CHECK"}},
+				&cli.StringFlag{Name: "nydusd", Value: "nydusd", Usage: "The nydusd binary path used by --check, if unset, search in PATH environment", EnvVars: []string{"NYDUSD"}},
 			},
 			Action: func(c *cli.Context) error {
 				logLevel, err := logrus.ParseLevel(c.String("log-level"))
@@ -226,10 +229,26 @@ func main() {
 					return errors.Wrap(err, "Parse source reference")
 				}
 				targetPlatform := c.String("platform")
+				targetPlatforms := []string{targetPlatform}
+				if c.Bool("all-platforms") {
+					targetPlatforms, err = provider.SourcePlatforms(context.Background(), sourceRemote)
+					if err != nil {
+						return err
+					}
+					logrus.Infof("Converting platforms %v", targetPlatforms)
+				}
 
-				sourceProviders, err := provider.DefaultSource(context.Background(), sourceRemote, sourceDir, targetPlatform)
-				if err != nil {
-					return errors.Wrap(err, "Parse source image")
+				sourceProviders := []provider.SourceProvider{}
+				for _, platform := range targetPlatforms {
+					platformSourceDir := sourceDir
+					if c.Bool("all-platforms") {
+						platformSourceDir = filepath.Join(sourceDir, strings.ReplaceAll(platform, "/", "-"))
+					}
+					providers, err := provider.DefaultSource(context.Background(), sourceRemote, platformSourceDir, platform)
+					if err != nil {
+						return errors.Wrapf(err, "Parse source image of platform %s", platform)
+					}
+					sourceProviders = append(sourceProviders, providers...)
 				}
 
 				targetRemote, err := provider.DefaultRemote(target, c.Bool("target-insecure"))
@@ -240,6 +259,7 @@ func main() {
 				opt := converter.Opt{
 					Logger:          logger,
 					SourceProviders: sourceProviders,
+					AllPlatforms:    c.Bool("all-platforms"),
 
 					TargetRemote: targetRemote,
 
@@ -276,7 +296,50 @@ func main() {
 				metrics.Register(fileexporter.New(filepath.Join(opt.WorkDir, "conversion_metrics.prom")))
 				defer metrics.Export()
 
-				return cvt.Convert(context.Background())
+				if err := cvt.Convert(context.Background()); err != nil {
+					return err
+				}
+
+				if !c.Bool("check") {
+					return nil
+				}
+
+				// File data in Nydus image is only checked when the backend config
+				// is provided, otherwise nydusd isn't able to access the blobs.
+				checkBackendType := ""
+				if strings.TrimSpace(backendConfig) != "" {
+					checkBackendType = backendType
+				}
+
+				for _, platform := range targetPlatforms {
+					_, arch, err := provider.ExtractOsArch(platform)
+					if err != nil {
+						return err
+					}
+
+					checker, err := checker.New(checker.Opt{
+						WorkDir:        filepath.Join(opt.WorkDir, "check"),
+						Source:         c.String("source"),
+						Target:         target,
+						MultiPlatform:  opt.MultiPlatform,
+						SourceInsecure: c.Bool("source-insecure"),
+						TargetInsecure: c.Bool("target-insecure"),
+						NydusImagePath: opt.NydusImagePath,
+						NydusdPath:     c.String("nydusd"),
+						BackendType:    checkBackendType,
+						BackendConfig:  backendConfig,
+						ExpectedArch:   arch,
+					})
+					if err != nil {
+						return err
+					}
+
+					if err := checker.Check(context.Background()); err != nil {
+						return errors.Wrapf(err, "Check platform %s", platform)
+					}
+				}
+
+				return nil
 			},
 		},
 		{
@@ -26,6 +26,8 @@ type WorkflowOption struct {
 type Workflow struct {
 	WorkflowOption
 	BuilderVersion      string
+	FsVersion           string
+	ChunkSize           uint32
 	bootstrapPath       string
 	blobsDir            string
 	backendConfig       string
@@ -35,8 +37,10 @@ type Workflow struct {
 }
 
 type debugJSON struct {
-	Version string
-	Blobs   []string
+	Version   string
+	Blobs     []string
+	FsVersion string `json:"fs_version"`
+	ChunkSize uint32 `json:"chunk_size"`
 }
 
 // Dump output json file of every layer to $workdir/bootstraps directory
@@ -60,6 +64,10 @@ func (workflow *Workflow) getLatestBlobPath() (string, error) {
 	// Record builder version of current build environment for easy
 	// debugging and troubleshooting afterwards.
 	workflow.BuilderVersion = data.Version
+	// Record RAFS format of the latest bootstrap, which is written to
+	// annotations of bootstrap layer in Nydus manifest.
+	workflow.FsVersion = data.FsVersion
+	workflow.ChunkSize = data.ChunkSize
 
 	if len(blobIDs) == 0 {
 		return "", nil
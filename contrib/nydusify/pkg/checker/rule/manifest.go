@@ -70,6 +70,10 @@ func (rule *ManifestRule) Validate() error {
 			if layer.Annotations[utils.LayerAnnotationNydusBootstrap] != "true" {
 				return errors.New("invalid bootstrap layer in nydus image manifest")
 			}
+			if fsVersion, ok := layer.Annotations[utils.LayerAnnotationNydusFsVersion]; ok &&
+				fsVersion != "5" && fsVersion != "6" {
+				return errors.Errorf("invalid fs version %s of bootstrap layer in nydus image manifest", fsVersion)
+			}
 		} else {
 			if layer.MediaType != utils.MediaTypeNydusBlob ||
 				layer.Annotations[utils.LayerAnnotationNydusBlob] != "true" {
@@ -6,12 +6,14 @@ package converter
 
 import (
 	"context"
+	"fmt"
 	"os"
 	"path/filepath"
 	"strings"
 	"time"
 
 	"github.com/containerd/containerd/reference/docker"
+	ocispec "github.com/opencontainers/image-spec/specs-go/v1"
 	"github.com/pkg/errors"
 	"github.com/sirupsen/logrus"
 
@@ -63,9 +65,12 @@ type Opt struct {
 	Logger provider.ProgressLogger
 
 	// SourceProviders should be a slice, which means it can support multi-platforms,
-	// for example `linux/amd64` and `linux/arm64`, Nydusify will pick one or more
-	// to convert to Nydus image in the future.
+	// for example `linux/amd64` and `linux/arm64`. More than one provider is only
+	// allowed with AllPlatforms.
 	SourceProviders []provider.SourceProvider
+	// AllPlatforms converts images of all source providers, each presenting a
+	// platform, and assembles them into a manifest index of target image.
+	AllPlatforms bool
 
 	TargetRemote *remote.Remote
 
@@ -94,6 +99,7 @@ type Opt struct {
 type Converter struct {
 	Logger          provider.ProgressLogger
 	SourceProviders []provider.SourceProvider
+	AllPlatforms    bool
 
 	TargetRemote *remote.Remote
 
@@ -130,6 +136,10 @@ func imageRepository(ref string) (string, error) {
 
 func New(opt Opt) (*Converter, error) {
 	// TODO: Add parameters sanity check here
+	if len(opt.SourceProviders) > 1 && !opt.AllPlatforms {
+		return nil, errors.New("Multiple source providers are only allowed to convert all platforms")
+	}
+
 	// Built layer has to go somewhere. Storage backend is the media holing layer blob.
 	backend, err := backend.NewBackend(opt.BackendType, []byte(opt.BackendConfig), opt.TargetRemote)
 	if err != nil {
@@ -139,6 +149,7 @@ func New(opt Opt) (*Converter, error) {
 	return &Converter{
 		Logger:              opt.Logger,
 		SourceProviders:     opt.SourceProviders,
+		AllPlatforms:        opt.AllPlatforms,
 		TargetRemote:        opt.TargetRemote,
 		CacheRemote:         opt.CacheRemote,
 		CacheMaxRecords:     opt.CacheMaxRecords,
@@ -195,39 +206,155 @@ func (cvt *Converter) convert(ctx context.Context) (retErr error) {
 		}
 	}
 
+	if cvt.SourceProviders == nil || len(cvt.SourceProviders) == 0 {
+		return errors.New("Invalid source provider")
+	}
+
 	start := time.Now()
+	sourceLayerCount := 0
+	allBuildLayers := []*buildLayer{}
+	nydusManifests := []*ocispec.Descriptor{}
+	ociManifests := []*ocispec.Descriptor{}
+	// With AllPlatforms every source provider presents an image of a platform, the
+	// images of all platforms are converted one by one and assembled into a manifest
+	// index.
+	for idx, sourceProvider := range cvt.SourceProviders {
+		workDir := cvt.WorkDir
+		if cvt.AllPlatforms {
+			workDir = filepath.Join(cvt.WorkDir, fmt.Sprintf("platform-%d", idx))
+		}
+		buildWorkflow, buildLayers, err := cvt.build(ctx, sourceProvider, cg, chunkDictOpt, workDir)
+		if err != nil {
+			return err
+		}
+		sourceLayerCount += len(buildLayers)
+		allBuildLayers = append(allBuildLayers, buildLayers...)
+
+		// Collect all meta information of current build environment, it will be
+		// written to manifest annotations of Nydus image for easy debugging and
+		// troubleshooting afterwards.
+		buildInfo := NewBuildInfo()
+		buildInfo.SetBuilderVersion(buildWorkflow.BuilderVersion)
+		buildInfo.SetNydusifyVersion(cvt.NydusifyVersion)
+		sourceManifest, err := sourceProvider.Manifest(ctx)
+		if err != nil {
+			return errors.Wrap(err, "Get source manifest")
+		}
+
+		// In the buildkit environment, the source manifest may be empty because
+		// the source image is built from a Dockerfile.
+		if sourceManifest != nil {
+			buildInfo.SetSourceReference(SourceReference{
+				Reference: cvt.Source,
+				Digest:    sourceManifest.Digest.String(),
+			})
+		}
+
+		// Push OCI manifest, Nydus manifest and manifest index
+		mm := &manifestManager{
+			referenceBlobs: blobs,
+			sourceProvider: sourceProvider,
+			remote:         cvt.TargetRemote,
+			backend:        cvt.storageBackend,
+			multiPlatform:  cvt.MultiPlatform,
+			dockerV2Format: cvt.DockerV2Format,
+			buildInfo:      buildInfo,
+			fsVersion:      buildWorkflow.FsVersion,
+			chunkSize:      buildWorkflow.ChunkSize,
+		}
+
+		pushDone := logger.Log(ctx, "[MANI] Push manifest", nil)
+		if !cvt.AllPlatforms {
+			if err := mm.Push(ctx, buildLayers); err != nil {
+				return pushDone(cvt.wrapManifestErr(err))
+			}
+			pushDone(nil)
+			continue
+		}
+
+		// Nydus manifests of all platforms are referenced by the manifest index
+		// pushed at last, so they are pushed by digest.
+		nydusManifest, err := mm.PushManifest(ctx, buildLayers, true)
+		if err != nil {
+			return pushDone(cvt.wrapManifestErr(err))
+		}
+		pushDone(nil)
+		nydusManifests = append(nydusManifests, nydusManifest)
+		if cvt.MultiPlatform {
+			ociManifest, err := mm.SourceManifest(ctx)
+			if err != nil {
+				return err
+			}
+			ociManifests = append(ociManifests, ociManifest)
+		}
+
+		if idx == len(cvt.SourceProviders)-1 {
+			pushDone := logger.Log(ctx, "[MANI] Push manifest index", nil)
+			if err := mm.PushIndex(ctx, nydusManifests, ociManifests); err != nil {
+				return pushDone(cvt.wrapManifestErr(err))
+			}
+			pushDone(nil)
+		}
+	}
+
+	if repo != "" {
+		metrics.ConversionDuration(repo, sourceLayerCount, start)
+	}
+
+	start = time.Now()
+	// Push Nydus cache image to remote registry
+	if err := cg.Export(ctx, allBuildLayers); err != nil {
+		return errors.Wrap(err, "export cache records")
+	}
+
+	if repo != "" {
+		metrics.StoreCacheDuration(repo, start)
+		metrics.ConversionSuccessCount(repo)
+	}
+
+	logrus.Infof("Converted to %s", cvt.TargetRemote.Ref)
+
+	return nil
+}
+
+func (cvt *Converter) wrapManifestErr(err error) error {
+	// When encounter http 400 error during pushing manifest to remote registry, means the
+	// manifest is invalid, maybe the cache layer is not available in registry with a high
+	// probability caused by registry GC, for example the cache image be overwritten by another
+	// conversion progress, and the registry GC be triggered in the same time
+	if cvt.CacheRemote != nil && strings.Contains(err.Error(), "400") {
+		logrus.Warnf("Push manifest: %s", err)
+		return errInvalidCache
+	}
+	return errors.Wrap(err, "Push target manifest")
+}
 
+// build converts layers of the source image to Nydus layers, and pushes them to
+// target registry or storage backend.
+func (cvt *Converter) build(
+	ctx context.Context, sourceProvider provider.SourceProvider, cg *cacheGlue, chunkDictOpt, workDir string,
+) (*build.Workflow, []*buildLayer, error) {
 	// BuildWorkflow builds nydus blob/bootstrap layer by layer
-	bootstrapsDir := filepath.Join(cvt.WorkDir, "bootstraps")
+	bootstrapsDir := filepath.Join(workDir, "bootstraps")
 	if err := os.RemoveAll(bootstrapsDir); err != nil {
-		return errors.Wrap(err, "Remove bootstrap directory")
+		return nil, nil, errors.Wrap(err, "Remove bootstrap directory")
 	}
 	if err := os.MkdirAll(bootstrapsDir, 0755); err != nil {
-		return errors.Wrap(err, "Create bootstrap directory")
+		return nil, nil, errors.Wrap(err, "Create bootstrap directory")
 	}
 	buildWorkflow, err := build.NewWorkflow(build.WorkflowOption{
 		ChunkDict:      chunkDictOpt,
 		NydusImagePath: cvt.NydusImagePath,
 		PrefetchDir:    cvt.PrefetchDir,
-		TargetDir:      cvt.WorkDir,
+		TargetDir:      workDir,
 	})
 	if err != nil {
-		return errors.Wrap(err, "Create build flow")
+		return nil, nil, errors.Wrap(err, "Create build flow")
 	}
 
-	if cvt.SourceProviders == nil || len(cvt.SourceProviders) == 0 {
-		return errors.New("Invalid source provider")
-	}
-
-	// In fact, during parsing image manifest, only one interested tag is inserted.
-	if len(cvt.SourceProviders) != 1 {
-		return errors.New("Should have only one source image")
-	}
-
-	sourceProvider := cvt.SourceProviders[0]
 	sourceLayers, err := sourceProvider.Layers(ctx)
 	if err != nil {
-		return errors.Wrap(err, "Get source layers")
+		return nil, nil, errors.Wrap(err, "Get source layers")
 	}
 	pullWorker := utils.NewQueueWorkerPool(PullWorkerCount, uint(len(sourceLayers)))
 	pushWorker := utils.NewWorkerPool(PushWorkerCount, uint(len(sourceLayers)))
@@ -257,7 +384,7 @@ func (cvt *Converter) convert(ctx context.Context) (retErr error) {
 		}
 
 		if err := pullWorker.Put(&job); err != nil {
-			return errors.Wrap(err, "Put layer pull job to worker")
+			return nil, nil, errors.Wrap(err, "Put layer pull job to worker")
 		}
 	}
 
@@ -268,7 +395,7 @@ func (cvt *Converter) convert(ctx context.Context) (retErr error) {
 		select {
 		case _job := <-jobChan:
 			if _job.Err() != nil {
-				return errors.Wrap(_job.Err(), "Pull source layer")
+				return nil, nil, errors.Wrap(_job.Err(), "Pull source layer")
 			}
 			job := _job.(*mountJob)
 
@@ -289,7 +416,7 @@ func (cvt *Converter) convert(ctx context.Context) (retErr error) {
 			}()
 
 			if err != nil {
-				return errors.Wrap(err, "Build source layer")
+				return nil, nil, errors.Wrap(err, "Build source layer")
 			}
 
 			// Push Nydus layer (bootstrap & blob) to target registry
@@ -300,78 +427,17 @@ func (cvt *Converter) convert(ctx context.Context) (retErr error) {
 			// Should throw the error as soon as possible instead
 			// of waiting for all pull jobs to finish
 			if err != nil {
-				return errors.Wrap(err, "Push Nydus layer in worker")
+				return nil, nil, errors.Wrap(err, "Push Nydus layer in worker")
 			}
 		}
 	}
 
 	// Wait all layer push job finish, then we can push image manifest on next
 	if err := <-pushWorker.Waiter(); err != nil {
-		return errors.Wrap(err, "Push Nydus layer in wait")
-	}
-
-	// Collect all meta information of current build environment, it will be
-	// written to manifest annotations of Nydus image for easy debugging and
-	// troubleshooting afterwards.
-	buildInfo := NewBuildInfo()
-	buildInfo.SetBuilderVersion(buildWorkflow.BuilderVersion)
-	buildInfo.SetNydusifyVersion(cvt.NydusifyVersion)
-	sourceManifest, err := sourceProvider.Manifest(ctx)
-	if err != nil {
-		return errors.Wrap(err, "Get source manifest")
-	}
-
-	// In the buildkit environment, the source manifest may be empty because
-	// the source image is built from a Dockerfile.
-	if sourceManifest != nil {
-		buildInfo.SetSourceReference(SourceReference{
-			Reference: cvt.Source,
-			Digest:    sourceManifest.Digest.String(),
-		})
-	}
-
-	// Push OCI manifest, Nydus manifest and manifest index
-	mm := &manifestManager{
-		referenceBlobs: blobs,
-		sourceProvider: sourceProvider,
-		remote:         cvt.TargetRemote,
-		backend:        cvt.storageBackend,
-		multiPlatform:  cvt.MultiPlatform,
-		dockerV2Format: cvt.DockerV2Format,
-		buildInfo:      buildInfo,
+		return nil, nil, errors.Wrap(err, "Push Nydus layer in wait")
 	}
-	pushDone := logger.Log(ctx, "[MANI] Push manifest", nil)
-	if err := mm.Push(ctx, buildLayers); err != nil {
-		// When encounter http 400 error during pushing manifest to remote registry, means the
-		// manifest is invalid, maybe the cache layer is not available in registry with a high
-		// probability caused by registry GC, for example the cache image be overwritten by another
-		// conversion progress, and the registry GC be triggered in the same time
-		if cvt.CacheRemote != nil && strings.Contains(err.Error(), "400") {
-			logrus.Warnf("Push manifest: %s", err)
-			return pushDone(errInvalidCache)
-		}
-		return pushDone(errors.Wrap(err, "Push target manifest"))
-	}
-	pushDone(nil)
 
-	if repo != "" {
-		metrics.ConversionDuration(repo, len(sourceLayers), start)
-	}
-
-	start = time.Now()
-	// Push Nydus cache image to remote registry
-	if err := cg.Export(ctx, buildLayers); err != nil {
-		return errors.Wrap(err, "export cache records")
-	}
-
-	if repo != "" {
-		metrics.StoreCacheDuration(repo, start)
-		metrics.ConversionSuccessCount(repo)
-	}
-
-	logrus.Infof("Converted to %s", cvt.TargetRemote.Ref)
-
-	return nil
+	return buildWorkflow, buildLayers, nil
 }
 
 // Convert converts source image to target (Nydus) image
@@ -9,6 +9,7 @@ import (
 	"context"
 	"encoding/json"
 	"io/ioutil"
+	"strconv"
 
 	"github.com/containerd/containerd/errdefs"
 	"github.com/containerd/containerd/images"
@@ -34,6 +35,10 @@ type manifestManager struct {
 	multiPlatform  bool
 	dockerV2Format bool
 	buildInfo      *BuildInfo
+	// RAFS version and chunk size of the latest bootstrap, they are
+	// unknown if all layers are hit by the build cache.
+	fsVersion string
+	chunkSize uint32
 }
 
 // Try to get manifests from exists target image
@@ -121,9 +126,14 @@ func (mm *manifestManager) makeManifestIndex(
 
 	// Append the OCI manifest provided by source to manifest list
 	if !foundOCI && ociManifest != nil {
-		ociManifest.Platform = &ocispec.Platform{
-			OS:           utils.SupportedOS,
-			Architecture: utils.SupportedArch,
+		if ociManifest.Platform == nil {
+			ociManifest.Platform = &ocispec.Platform{}
+		}
+		if ociManifest.Platform.OS == "" {
+			ociManifest.Platform.OS = utils.SupportedOS
+		}
+		if ociManifest.Platform.Architecture == "" {
+			ociManifest.Platform.Architecture = utils.SupportedArch
 		}
 		descs = append(descs, *ociManifest)
 	}
@@ -155,14 +165,70 @@ func (mm *manifestManager) CloneSourcePlatform(ctx context.Context, additionalOS
 	}
 
 	// Source image configuration must exist according to OCI image spec.
-	return &ocispec.Platform{
+	platform := &ocispec.Platform{
 		OS:           sourceConfig.OS,
 		Architecture: sourceConfig.Architecture,
 		OSFeatures:   features,
-	}, nil
+	}
+
+	// The variant, e.g. `v8` of `linux/arm64/v8`, is only recorded in the manifest
+	// index of source image, preserve it so clients select the same platform.
+	sourceManifest, err := mm.sourceProvider.Manifest(ctx)
+	if err == nil && sourceManifest != nil && sourceManifest.Platform != nil &&
+		sourceManifest.Platform.Architecture == platform.Architecture {
+		platform.Variant = sourceManifest.Platform.Variant
+	}
+
+	return platform, nil
+}
+
+// SourceManifest gets the OCI manifest descriptor of source image with platform filled,
+// it's nil if source image has no manifest, e.g. built by buildkit.
+func (mm *manifestManager) SourceManifest(ctx context.Context) (*ocispec.Descriptor, error) {
+	sourceManifest, err := mm.sourceProvider.Manifest(ctx)
+	if err != nil {
+		return nil, errors.Wrap(err, "Get source image manifest")
+	}
+	if sourceManifest == nil {
+		return nil, nil
+	}
+
+	p, err := mm.CloneSourcePlatform(ctx, "")
+	if err != nil {
+		return nil, errors.Wrap(err, "clone source platform")
+	}
+	ociManifestDesc := *sourceManifest
+	ociManifestDesc.Platform = p
+
+	return &ociManifestDesc, nil
 }
 
+// Push pushes Nydus image manifest to target image, merged with OCI manifest of
+// source image into a manifest index if `multiPlatform` is enabled.
 func (mm *manifestManager) Push(ctx context.Context, buildLayers []*buildLayer) error {
+	nydusManifestDesc, err := mm.PushManifest(ctx, buildLayers, mm.multiPlatform)
+	if err != nil {
+		return err
+	}
+	if !mm.multiPlatform {
+		return nil
+	}
+
+	ociManifestDesc, err := mm.SourceManifest(ctx)
+	if err != nil {
+		return err
+	}
+
+	return mm.PushIndex(
+		ctx, []*ocispec.Descriptor{nydusManifestDesc}, []*ocispec.Descriptor{ociManifestDesc},
+	)
+}
+
+// PushManifest pushes Nydus image config and manifest, tags the target image with
+// the manifest unless `byDigest`, returns the manifest descriptor with platform filled.
+func (mm *manifestManager) PushManifest(
+	ctx context.Context, buildLayers []*buildLayer, byDigest bool,
+) (*ocispec.Descriptor, error) {
 	layers := []ocispec.Descriptor{}
 	// add reference blobs to annotation
 	blobListInAnnotation := mm.referenceBlobs
@@ -185,16 +251,22 @@ func (mm *manifestManager) Push(ctx context.Context, buildLayers []*buildLayer)
 		if idx == len(buildLayers)-1 {
 			blobListBytes, err := json.Marshal(blobListInAnnotation)
 			if err != nil {
-				return errors.Wrap(err, "Marshal blob list")
+				return nil, errors.Wrap(err, "Marshal blob list")
 			}
 			record.NydusBootstrapDesc.Annotations[utils.LayerAnnotationNydusBlobIDs] = string(blobListBytes)
+			if mm.fsVersion != "" {
+				record.NydusBootstrapDesc.Annotations[utils.LayerAnnotationNydusFsVersion] = mm.fsVersion
+			}
+			if mm.chunkSize != 0 {
+				record.NydusBootstrapDesc.Annotations[utils.LayerAnnotationNydusChunkSize] = strconv.FormatUint(uint64(mm.chunkSize), 10)
+			}
 			layers = append(layers, *record.NydusBootstrapDesc)
 		}
 	}
 
 	ociConfig, err := mm.sourceProvider.Config(ctx)
 	if err != nil {
-		return errors.Wrap(err, "Get source image config")
+		return nil, errors.Wrap(err, "Get source image config")
 	}
 	ociConfig.RootFS.DiffIDs = []digest.Digest{}
 	ociConfig.History = []ocispec.History{}
@@ -204,6 +276,8 @@ func (mm *manifestManager) Push(ctx context.Context, buildLayers []*buildLayer)
 		utils.LayerAnnotationNydusBlob:      true,
 		utils.LayerAnnotationNydusBlobIDs:   true,
 		utils.LayerAnnotationNydusBootstrap: true,
+		utils.LayerAnnotationNydusFsVersion: true,
+		utils.LayerAnnotationNydusChunkSize: true,
 	}
 	for idx, desc := range layers {
 		layerDiffID := digest.Digest(desc.Annotations[utils.LayerAnnotationUncompressed])
@@ -229,11 +303,11 @@ func (mm *manifestManager) Push(ctx context.Context, buildLayers []*buildLayer)
 	}
 	configDesc, configBytes, err := utils.MarshalToDesc(ociConfig, configMediaType)
 	if err != nil {
-		return errors.Wrap(err, "Marshal source image config")
+		return nil, errors.Wrap(err, "Marshal source image config")
 	}
 
 	if err := mm.remote.Push(ctx, *configDesc, true, bytes.NewReader(configBytes)); err != nil {
-		return errors.Wrap(err, "Push Nydus image config")
+		return nil, errors.Wrap(err, "Push Nydus image config")
 	}
 
 	manifestMediaType := ocispec.MediaTypeImageManifest
@@ -259,49 +333,66 @@ func (mm *manifestManager) Push(ctx context.Context, buildLayers []*buildLayer)
 
 	nydusManifestDesc, manifestBytes, err := utils.MarshalToDesc(nydusManifest, manifestMediaType)
 	if err != nil {
-		return errors.Wrap(err, "Marshal Nydus image manifest")
+		return nil, errors.Wrap(err, "Marshal Nydus image manifest")
 	}
 
 	p, err := mm.CloneSourcePlatform(ctx, utils.ManifestOSFeatureNydus)
 	if err != nil {
-		return errors.Wrap(err, "clone source platform")
+		return nil, errors.Wrap(err, "clone source platform")
 	}
 
 	nydusManifestDesc.Platform = p
 
-	if !mm.multiPlatform {
-		if err := mm.remote.Push(ctx, *nydusManifestDesc, false, bytes.NewReader(manifestBytes)); err != nil {
-			return errors.Wrap(err, "Push nydus image manifest")
+	// Record the source manifest of the same platform for tools to find the original
+	// image from the manifest index.
+	sourceManifest, err := mm.sourceProvider.Manifest(ctx)
+	if err != nil {
+		return nil, errors.Wrap(err, "Get source image manifest")
+	}
+	if sourceManifest != nil {
+		nydusManifestDesc.Annotations = map[string]string{
+			utils.ManifestAnnotationNydusSource: sourceManifest.Digest.String(),
 		}
-		return nil
 	}
 
-	if err := mm.remote.Push(ctx, *nydusManifestDesc, true, bytes.NewReader(manifestBytes)); err != nil {
-		return errors.Wrap(err, "Push nydus image manifest")
+	if err := mm.remote.Push(ctx, *nydusManifestDesc, byDigest, bytes.NewReader(manifestBytes)); err != nil {
+		return nil, errors.Wrap(err, "Push nydus image manifest")
 	}
 
-	// Push manifest index, includes OCI manifest and Nydus manifest
-	ociManifestDesc, err := mm.sourceProvider.Manifest(ctx)
-	if err != nil {
-		return errors.Wrap(err, "Get source image manifest")
-	}
+	return nydusManifestDesc, nil
+}
 
-	if ociManifestDesc != nil {
-		p, err := mm.CloneSourcePlatform(ctx, "")
+// PushIndex pushes the manifest index consisting of Nydus manifests and corresponding
+// OCI manifests of all platforms, merged with existing manifests of target image if
+// `multiPlatform` is enabled. OCI manifests may be nil or omitted.
+func (mm *manifestManager) PushIndex(
+	ctx context.Context, nydusManifests []*ocispec.Descriptor, ociManifests []*ocispec.Descriptor,
+) error {
+	existManifests := []ocispec.Descriptor{}
+	if mm.multiPlatform {
+		var err error
+		existManifests, err = mm.getExistsManifests(ctx)
 		if err != nil {
-			return errors.Wrap(err, "clone source platform")
+			return errors.Wrap(err, "Get remote existing manifest index")
 		}
-		ociManifestDesc.Platform = p
 	}
 
-	existManifests, err := mm.getExistsManifests(ctx)
-	if err != nil {
-		return errors.Wrap(err, "Get remote existing manifest index")
+	_index := &ocispec.Index{
+		Versioned: specs.Versioned{
+			SchemaVersion: 2,
+		},
+		Manifests: existManifests,
 	}
-
-	_index, err := mm.makeManifestIndex(ctx, existManifests, nydusManifestDesc, ociManifestDesc)
-	if err != nil {
-		return errors.Wrap(err, "Make manifest index for target")
+	for idx, nydusManifest := range nydusManifests {
+		var ociManifest *ocispec.Descriptor
+		if idx < len(ociManifests) {
+			ociManifest = ociManifests[idx]
+		}
+		var err error
+		_index, err = mm.makeManifestIndex(ctx, _index.Manifests, nydusManifest, ociManifest)
+		if err != nil {
+			return errors.Wrap(err, "Make manifest index for target")
+		}
 	}
 
 	indexMediaType := ocispec.MediaTypeImageIndex
@@ -136,4 +136,19 @@ func TestManifest(t *testing.T) {
 		makeDesc("2", makePlatform("linux/ppc64le", false)),
 		makeDesc("nydus", makePlatform("linux/amd64", true)),
 	}, index.Manifests)
+
+	// Merge manifests of multiple platforms, platforms of OCI manifests are kept
+	nydusArmDesc := makeDesc("nydus-arm64", makePlatform("linux/arm64", true))
+	ociArmDesc := makeDesc("arm64", makePlatform("linux/arm64", false))
+	ociDesc = makeDesc("1", makePlatform("linux/amd64", false))
+	index, err = mm.makeManifestIndex(context.Background(), nil, &nydusDesc, &ociDesc)
+	assert.Nil(t, err)
+	index, err = mm.makeManifestIndex(context.Background(), index.Manifests, &nydusArmDesc, &ociArmDesc)
+	assert.Nil(t, err)
+	assert.Equal(t, []ocispec.Descriptor{
+		makeDesc("1", makePlatform("linux/amd64", false)),
+		makeDesc("nydus", makePlatform("linux/amd64", true)),
+		makeDesc("arm64", makePlatform("linux/arm64", false)),
+		makeDesc("nydus-arm64", makePlatform("linux/arm64", true)),
+	}, index.Manifests)
 }
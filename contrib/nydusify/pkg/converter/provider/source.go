@@ -169,6 +169,24 @@ func ExtractOsArch(platform string) (string, string, error) {
 	return os, arch, nil
 }
 
+// SourcePlatforms lists platforms of all OCI images in the source image, in the form of os/arch
+func SourcePlatforms(ctx context.Context, remote *remote.Remote) ([]string, error) {
+	// The architecture is only used to select an image to parse, not to list platforms.
+	parser, err := parser.New(remote, utils.PlatformArchAMD64)
+	if err != nil {
+		return nil, errors.Wrap(err, "failed to create parser")
+	}
+	platforms, err := parser.Platforms(ctx)
+	if err != nil {
+		return nil, errors.Wrap(err, "List platforms of source image")
+	}
+	if len(platforms) == 0 {
+		return nil, fmt.Errorf("not found OCI manifest of supported platforms in source image")
+	}
+
+	return platforms, nil
+}
+
 // DefaultSource pulls image layers from specify image reference
 func DefaultSource(ctx context.Context, remote *remote.Remote, workDir, platform string) ([]SourceProvider, error) {
 
@@ -171,6 +171,56 @@ func (parser *Parser) matchImagePlatform(desc *ocispec.Descriptor) bool {
 	return false
 }
 
+// Platforms lists platforms of OCI images in the image in the form of os/arch, only
+// linux platforms with supported architectures are listed.
+func (parser *Parser) Platforms(ctx context.Context) ([]string, error) {
+	imageDesc, err := parser.Remote.Resolve(ctx)
+	if err != nil {
+		return nil, errors.Wrap(err, "resolve image")
+	}
+
+	platforms := []string{}
+	addPlatform := func(os, arch string) {
+		if os != utils.SupportedOS || !utils.IsSupportedArch(arch) {
+			return
+		}
+		platform := os + "/" + arch
+		for _, p := range platforms {
+			if p == platform {
+				return
+			}
+		}
+		platforms = append(platforms, platform)
+	}
+
+	switch imageDesc.MediaType {
+	case ocispec.MediaTypeImageManifest, images.MediaTypeDockerSchema2Manifest:
+		manifest, err := parser.pullManifest(ctx, imageDesc)
+		if err != nil {
+			return nil, err
+		}
+		if findNydusBootstrapDesc(manifest) == nil {
+			config, err := parser.pullConfig(ctx, &manifest.Config)
+			if err != nil {
+				return nil, err
+			}
+			addPlatform(config.OS, config.Architecture)
+		}
+	case ocispec.MediaTypeImageIndex, images.MediaTypeDockerSchema2ManifestList:
+		index, err := parser.pullIndex(ctx, imageDesc)
+		if err != nil {
+			return nil, err
+		}
+		for _, desc := range index.Manifests {
+			if desc.Platform != nil && !utils.IsNydusPlatform(desc.Platform) {
+				addPlatform(desc.Platform.OS, desc.Platform.Architecture)
+			}
+		}
+	}
+
+	return platforms, nil
+}
+
 // Parse parses Nydus image reference into Parsed object.
 func (parser *Parser) Parse(ctx context.Context) (*Parsed, error) {
 	logrus.Infof("Parsing image %s", parser.Remote.Ref)
@@ -11,12 +11,17 @@ const (
 
 	ManifestNydusCache = "containerd.io/snapshot/nydus-cache"
 
+	// Digest of the source OCI manifest, annotated on Nydus manifests in manifest index.
+	ManifestAnnotationNydusSource = "containerd.io/snapshot/nydus-source-manifest"
+
 	LayerAnnotationNydusBlob          = "containerd.io/snapshot/nydus-blob"
 	LayerAnnotationNydusBlobDigest    = "containerd.io/snapshot/nydus-blob-digest"
 	LayerAnnotationNydusBlobSize      = "containerd.io/snapshot/nydus-blob-size"
 	LayerAnnotationNydusBlobIDs       = "containerd.io/snapshot/nydus-blob-ids"
 	LayerAnnotationNydusBootstrap     = "containerd.io/snapshot/nydus-bootstrap"
 	LayerAnnotationNydusSourceChainID = "containerd.io/snapshot/nydus-source-chainid"
+	LayerAnnotationNydusFsVersion     = "containerd.io/snapshot/nydus-fs-version"
+	LayerAnnotationNydusChunkSize     = "containerd.io/snapshot/nydus-chunk-size"
 
 	LayerAnnotationUncompressed = "containerd.io/uncompressed"
 )
@@ -0,0 +1,95 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Benchmarks of metadata operations, as regression guards for inode lookup performance.
+//!
+//! Run by `cargo bench -p rafs`.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use rafs::metadata::layout::RAFS_ROOT_INODE;
+use rafs::metadata::{PostWalkAction, RafsInode, RafsMode, RafsSuper};
+
+fn load_bootstrap(mode: RafsMode) -> RafsSuper {
+    let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+    let mut path = PathBuf::from(root_dir);
+    path.push("../tests/texture/bootstrap/image_v2.boot");
+    RafsSuper::load_from_metadata(path.to_str().unwrap(), mode, false).unwrap()
+}
+
+// Collect all directories and their children names to look up.
+fn collect_entries(sb: &RafsSuper) -> Vec<(Arc<dyn RafsInode>, Vec<OsString>)> {
+    let mut entries = Vec::new();
+    let mut dirs = vec![sb.get_inode(RAFS_ROOT_INODE, false).unwrap()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut names = Vec::new();
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx).unwrap();
+            if child.is_dir() {
+                dirs.push(child.clone());
+            }
+            names.push(child.name());
+        }
+        entries.push((dir, names));
+    }
+
+    entries
+}
+
+fn bench_metadata(c: &mut Criterion) {
+    for mode in [RafsMode::Direct, RafsMode::Cached].iter() {
+        let sb = load_bootstrap(mode.clone());
+        // Inode table entries of hardlinks have no inodes of their own in cached mode.
+        let inos: Vec<u64> = (RAFS_ROOT_INODE..=sb.get_max_ino())
+            .filter(|ino| sb.get_inode(*ino, false).is_ok())
+            .collect();
+        let entries = collect_entries(&sb);
+
+        c.bench_function(&format!("{} get_inode", mode), |b| {
+            b.iter(|| {
+                for ino in inos.iter() {
+                    black_box(sb.get_inode(*ino, false).unwrap());
+                }
+            })
+        });
+
+        c.bench_function(&format!("{} getattr", mode), |b| {
+            b.iter(|| {
+                for ino in inos.iter() {
+                    black_box(sb.get_inode(*ino, false).unwrap().get_attr());
+                }
+            })
+        });
+
+        c.bench_function(&format!("{} lookup", mode), |b| {
+            b.iter(|| {
+                for (dir, names) in entries.iter() {
+                    for name in names.iter() {
+                        black_box(dir.get_child_by_name(name).unwrap());
+                    }
+                }
+            })
+        });
+
+        c.bench_function(&format!("{} readdir", mode), |b| {
+            b.iter(|| {
+                for (dir, _) in entries.iter() {
+                    dir.walk_children_inodes(0, &mut |inode, name, ino, offset| {
+                        black_box((inode, name, ino, offset));
+                        Ok(PostWalkAction::Continue)
+                    })
+                    .unwrap();
+                }
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_metadata);
+criterion_main!(benches);
@@ -0,0 +1,31 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fuzz loading and validating Rafs bootstraps, which may come from untrusted parties.
+
+#![no_main]
+
+use std::io::Write;
+
+use libfuzzer_sys::fuzz_target;
+use rafs::metadata::{RafsMode, RafsSuper};
+use vmm_sys_util::tempfile::TempFile;
+
+fuzz_target!(|data: &[u8]| {
+    // Bootstraps are memory mapped in direct mode, so they have to be backed by files.
+    let file = TempFile::new().unwrap();
+    file.as_file().write_all(data).unwrap();
+    let path = file.as_path().to_str().unwrap();
+
+    for mode in [RafsMode::Direct, RafsMode::Cached].iter() {
+        if let Ok(sb) = RafsSuper::load_from_metadata(path, mode.clone(), false) {
+            if sb.validate_tree().is_ok() {
+                // Resolving paths walks up the tree through parent inodes.
+                for ino in 1..=std::cmp::min(sb.get_max_ino(), 1024) {
+                    let _ = sb.path_from_ino(ino);
+                }
+            }
+        }
+    }
+});
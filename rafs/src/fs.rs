@@ -16,15 +16,19 @@
 
 use std::any::Any;
 use std::cmp;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ffi::{CStr, OsStr, OsString};
 use std::fmt;
-use std::fs::File;
-use std::io::Result;
+use std::fs::{self, File};
+use std::io::{Result, Write};
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, SystemTime};
 
 use nix::unistd::{getegid, geteuid};
@@ -32,17 +36,24 @@ use serde::Deserialize;
 
 use fuse_backend_rs::abi::linux_abi::Attr;
 use fuse_backend_rs::api::filesystem::*;
-use fuse_backend_rs::api::BackendFileSystem;
-use nydus_utils::metrics::{self, FopRecorder, StatsFop::*};
+use fuse_backend_rs::api::{BackendFileSystem, CreateIn};
+use nydus_utils::error::{self, with_context, ErrorContext};
+use nydus_utils::metrics::{self, ErrorClass, FopRecorder, StatsFop, StatsFop::*};
 use storage::cache::BlobPrefetchConfig;
-use storage::device::{BlobDevice, BlobPrefetchRequest};
-use storage::factory::FactoryConfig;
+use storage::crypt::CipherConfig;
+use storage::device::{BlobChunkInfo, BlobDevice, BlobInfo, BlobPrefetchRequest};
+use storage::factory::{BackendConfig, FactoryConfig};
 
+use crate::idmap::{IdMapConfig, IdMapper};
 use crate::metadata::layout::RAFS_ROOT_INODE;
+use crate::metadata::shared;
 use crate::metadata::{
-    Inode, PostWalkAction, RafsInode, RafsSuper, RafsSuperMeta, DOT, DOTDOT,
+    fold_name_case, Inode, PostWalkAction, RafsInode, RafsSuper, RafsSuperMeta, DOT, DOTDOT,
     RAFS_DEFAULT_CHUNK_SIZE,
 };
+use crate::scrub::{ScrubConfig, Scrubber};
+use crate::signature::{default_signature_path, SignatureConfig};
+use crate::whole_file::{WholeFileConfig, WholeFileFetcher};
 use crate::{RafsError, RafsIoReader, RafsResult};
 
 /// Type of RAFS fuse handle.
@@ -52,6 +63,10 @@ pub type Handle = u64;
 pub const RAFS_DEFAULT_ATTR_TIMEOUT: u64 = 1 << 32;
 /// Rafs default entry timeout value.
 pub const RAFS_DEFAULT_ENTRY_TIMEOUT: u64 = RAFS_DEFAULT_ATTR_TIMEOUT;
+/// Maximum time to wait for blob caches to be flushed when unmounting or shutting down.
+pub const RAFS_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum time to wait for in-flight reads to finish when switching storage backends or freezing.
+pub const RAFS_SWITCH_BACKEND_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn default_threads_count() -> usize {
     8
@@ -123,6 +138,43 @@ impl TryFrom<&RafsConfig> for BlobPrefetchConfig {
     }
 }
 
+/// Ownership options to expose the filesystem as a data volume instead of a container rootfs.
+#[derive(Clone, Default, Deserialize)]
+pub struct VolumeConfig {
+    /// Offset added to uids of all inodes, deprecated by `RafsConfig::id_map`.
+    #[serde(default)]
+    pub uid_shift: u32,
+    /// Offset added to gids of all inodes, deprecated by `RafsConfig::id_map`.
+    #[serde(default)]
+    pub gid_shift: u32,
+    /// Group owning all inodes and granted read access to them, like `fsGroup` of Kubernetes
+    /// pod security context.
+    #[serde(default)]
+    pub fs_group: Option<u32>,
+}
+
+/// Policy of access times reported for inodes.
+///
+/// Access times aren't recorded by Rafs, since the filesystem is read-only. Reporting a fixed
+/// access time older than the modification time makes guests and overlay filesystems try to
+/// update it on every read, only to get the setattr request rejected.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AtimePolicy {
+    /// Report the modification time as the access time, and accept requests only updating
+    /// access times without recording them.
+    Noatime,
+    /// Report the later of the modification time and the mount time as the access time, and
+    /// reject requests updating access times as for other writes.
+    Relatime,
+}
+
+impl Default for AtimePolicy {
+    fn default() -> Self {
+        AtimePolicy::Noatime
+    }
+}
+
 /// Not everything can be safely exported from configuration.
 /// We trim the unneeded info from here.
 #[macro_export]
@@ -157,12 +209,51 @@ pub struct RafsConfig {
     /// Record filesystem access pattern.
     #[serde(default)]
     pub access_pattern: bool,
+    /// File to save recorded access patterns to when unmounting or shutting down, in the format
+    /// of `/api/v1/metrics/pattern`.
+    #[serde(default)]
+    pub access_pattern_file: String,
     /// Record file name if file access trace log.
     #[serde(default)]
     pub latest_read_files: bool,
+    /// Record the ordered list of reads after mount, up to the number of reads, 0 to disable.
+    #[serde(default)]
+    pub access_records: usize,
     // ZERO value means, amplifying user io is not enabled.
     #[serde(default = "default_amplify_io")]
     pub amplify_io: u32,
+    /// Require the bootstrap to be signed, and verify the signature before loading it.
+    #[serde(default)]
+    pub verify_signature: Option<SignatureConfig>,
+    /// Map uid and gid of inodes, e.g. for user namespaced containers.
+    #[serde(default)]
+    pub id_map: Option<IdMapConfig>,
+    /// Map ownership of inodes to mount the filesystem as a data volume.
+    #[serde(default)]
+    pub volume: Option<VolumeConfig>,
+    /// Keys to decrypt encrypted blobs.
+    #[serde(default)]
+    pub encryption: CipherConfig,
+    /// Fetch whole files read sequentially or opened into the cache, only for Rafs v5.
+    #[serde(default)]
+    pub whole_file: Option<WholeFileConfig>,
+    /// Check existence and sizes of all blobs on the storage backend when mounting.
+    #[serde(default)]
+    pub check_blobs: bool,
+    /// Verify digests of cached chunks, and optionally of remote chunks, in background.
+    #[serde(default)]
+    pub scrub: Option<ScrubConfig>,
+    /// Policy of access times reported for inodes.
+    #[serde(default)]
+    pub atime: AtimePolicy,
+    /// Look up file names case-insensitively, while names are still reported as they are stored.
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// Maximum memory to cache metadata in cached mode, in unit of Byte, 0 means no limit.
+    ///
+    /// Bootstraps estimated to take more memory are mapped in direct mode instead.
+    #[serde(default)]
+    pub max_cached_size: u64,
 }
 
 impl RafsConfig {
@@ -178,6 +269,36 @@ impl RafsConfig {
         let file = File::open(path).map_err(RafsError::LoadConfig)?;
         serde_json::from_reader::<File, RafsConfig>(file).map_err(RafsError::ParseConfig)
     }
+
+    /// Use `<bootstrap>.sig` to verify the bootstrap if signature file is not specified.
+    pub fn set_default_signature(&mut self, bootstrap: &Path) {
+        if let Some(config) = self.verify_signature.as_mut() {
+            if config.signature.is_empty() {
+                config.signature = default_signature_path(bootstrap)
+                    .to_string_lossy()
+                    .to_string();
+            }
+        }
+    }
+
+    /// Get the id mapping configuration, taking over id shifts of the volume configuration.
+    fn id_map_config(&self) -> RafsResult<Option<IdMapConfig>> {
+        let volume = match self.volume.as_ref() {
+            Some(v) if v.uid_shift != 0 || v.gid_shift != 0 => v,
+            _ => return Ok(self.id_map.clone()),
+        };
+        if self.id_map.is_some() {
+            return Err(RafsError::Configure(
+                "id shifts of volume conflict with id_map".to_string(),
+            ));
+        }
+
+        Ok(Some(IdMapConfig {
+            uid_shift: volume.uid_shift,
+            gid_shift: volume.gid_shift,
+            ..Default::default()
+        }))
+    }
 }
 
 impl FromStr for RafsConfig {
@@ -207,8 +328,20 @@ impl fmt::Display for RafsConfig {
 pub struct Rafs {
     id: String,
     device: BlobDevice,
+    // Storage configuration of the device, to derive configurations when switching backends.
+    storage_conf: Mutex<Arc<FactoryConfig>>,
     ios: Arc<metrics::GlobalIoStats>,
     sb: Arc<RafsSuper>,
+    // Key to share the metadata with other mounts of the same bootstrap.
+    sb_key: Mutex<String>,
+    // Shared lock on the bootstrap, held to prevent builders from rewriting it while mounted.
+    bootstrap_lock: Mutex<Option<File>>,
+    // Lookup counts of inodes referenced by the kernel, per-inode state is released once the
+    // kernel forgets all references to the inode.
+    lookup_counts: Mutex<HashMap<Inode, u64>>,
+    // Children of directories by folded names for case-insensitive lookups, indexed on the first
+    // miss in each directory and released with the lookup references of the directory.
+    folded_names: Mutex<HashMap<Inode, Arc<HashMap<OsString, Inode>>>>,
 
     initialized: bool,
     digest_validate: bool,
@@ -221,24 +354,68 @@ pub struct Rafs {
     i_uid: u32,
     i_gid: u32,
     i_time: u64,
+    id_mapper: Option<IdMapper>,
+    volume: Option<VolumeConfig>,
+    whole_file: Option<WholeFileFetcher>,
+    scrub: Option<ScrubConfig>,
+    scrubber: Option<Scrubber>,
+    atime: AtimePolicy,
+    case_insensitive: bool,
+    access_pattern_file: Option<PathBuf>,
 }
 
 impl Rafs {
     /// Create a new instance of `Rafs`.
+    ///
+    /// The bootstrap is read from `r`, which may be a file, or a memfd copied from any reader such
+    /// as an in-memory buffer by [memfd_from_reader()](../trait.RafsIoRead.html).
     pub fn new(conf: RafsConfig, id: &str, r: &mut RafsIoReader) -> RafsResult<Self> {
+        let bootstrap_lock = r.lock_shared()?;
+        // Signed bootstraps are loaded from their own verified copies, so their metadata isn't
+        // shared with other mounts.
+        if let Some(signature) = conf.verify_signature.as_ref() {
+            signature.verify(r)?;
+        }
         let storage_conf = Self::prepare_storage_conf(&conf)?;
-        let mut sb = RafsSuper::new(&conf).map_err(RafsError::FillSuperblock)?;
-        sb.load(r).map_err(RafsError::FillSuperblock)?;
+        let id_mapper = conf
+            .id_map_config()?
+            .as_ref()
+            .map(IdMapper::new)
+            .transpose()?;
+        let sb_key = shared::shared_key(&conf, r).map_err(RafsError::FillSuperblock)?;
+        let sb = match shared::get(&sb_key) {
+            Some(sb) => {
+                info!("share metadata of the bootstrap with other mounts");
+                sb
+            }
+            None => {
+                let mut sb = RafsSuper::new(&conf).map_err(RafsError::FillSuperblock)?;
+                sb.load(r).map_err(RafsError::FillSuperblock)?;
+                let sb = Arc::new(sb);
+                shared::insert(&sb_key, &sb);
+                sb
+            }
+        };
 
         let blob_infos = sb.superblock.get_blob_infos();
         let device =
             BlobDevice::new(&storage_conf, &blob_infos).map_err(RafsError::CreateDevice)?;
+        if conf.check_blobs {
+            device
+                .check_blobs(&blob_infos)
+                .map_err(RafsError::CreateDevice)?;
+        }
 
         let rafs = Rafs {
             id: id.to_string(),
             device,
+            storage_conf: Mutex::new(storage_conf),
             ios: metrics::new(id),
-            sb: Arc::new(sb),
+            sb,
+            sb_key: Mutex::new(sb_key),
+            bootstrap_lock: Mutex::new(bootstrap_lock),
+            lookup_counts: Mutex::new(HashMap::new()),
+            folded_names: Mutex::new(HashMap::new()),
 
             initialized: false,
             digest_validate: conf.digest_validate,
@@ -253,12 +430,36 @@ impl Rafs {
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            id_mapper,
+            volume: conf.volume.clone(),
+            whole_file: conf.whole_file.clone().map(WholeFileFetcher::new),
+            scrub: conf.scrub.clone(),
+            scrubber: None,
+            atime: conf.atime,
+            case_insensitive: conf.case_insensitive,
+            access_pattern_file: if conf.access_pattern && !conf.access_pattern_file.is_empty() {
+                Some(PathBuf::from(&conf.access_pattern_file))
+            } else {
+                None
+            },
         };
 
         rafs.ios.toggle_files_recording(conf.iostats_files);
         rafs.ios.toggle_access_pattern(conf.access_pattern);
         rafs.ios
             .toggle_latest_read_files_recording(conf.latest_read_files);
+        if conf.access_records > 0 {
+            // Hold the metadata weakly, otherwise it can't be switched by `update()`.
+            let sb = Arc::downgrade(&rafs.sb);
+            let recorder = rafs.ios.access_recorder();
+            recorder.set_path_resolver(Box::new(move |ino| {
+                sb.upgrade()?
+                    .path_from_ino(ino)
+                    .ok()
+                    .map(|p| p.to_string_lossy().to_string())
+            }));
+            recorder.enable(conf.access_records);
+        }
 
         Ok(rafs)
     }
@@ -271,14 +472,33 @@ impl Rafs {
             return Err(RafsError::Uninitialized);
         }
 
-        // TODO: seems no need to do self.sb.update()
-        // step 1: update sb.
+        let bootstrap_lock = r.lock_shared()?;
+        if let Some(signature) = conf.verify_signature.as_ref() {
+            signature.verify(r)?;
+        }
+
+        // step 1: update sb if the bootstrap has been changed.
         // No lock is needed thanks to ArcSwap.
-        self.sb.update(r).map_err(|e| {
-            error!("update failed due to {:?}", e);
-            e
-        })?;
-        info!("update sb is successful");
+        let sb_key = shared::shared_key(&conf, r).map_err(RafsError::FillSuperblock)?;
+        let mut cur_key = self.sb_key.lock().unwrap();
+        if *cur_key != sb_key {
+            if Arc::strong_count(&self.sb) > 1 {
+                return Err(RafsError::Configure(
+                    "metadata shared with other mounts can't be switched to another bootstrap"
+                        .to_string(),
+                ));
+            }
+            // The metadata no longer matches the bootstrap it's registered by.
+            shared::remove(&cur_key, &self.sb);
+            self.sb.update(r).map_err(|e| {
+                error!("update failed due to {:?}", e);
+                e
+            })?;
+            *cur_key = sb_key;
+            self.folded_names.lock().unwrap().clear();
+            info!("update sb is successful");
+        }
+        drop(cur_key);
 
         let storage_conf = Self::prepare_storage_conf(&conf)?;
         let blob_infos = self.sb.superblock.get_blob_infos();
@@ -287,11 +507,69 @@ impl Rafs {
         self.device
             .update(&storage_conf, &blob_infos)
             .map_err(RafsError::SwapBackend)?;
+        *self.storage_conf.lock().unwrap() = storage_conf;
         info!("update device is successful");
+        *self.bootstrap_lock.lock().unwrap() = bootstrap_lock;
+
+        Ok(())
+    }
+
+    /// Switch the storage backend of all blobs to `backend`, without unmounting the filesystem.
+    ///
+    /// Cache configuration is kept, so data already cached is still served from the cache.
+    pub fn switch_backend(&self, backend: BackendConfig) -> RafsResult<()> {
+        let mut storage_conf = self.storage_conf.lock().unwrap();
+        let mut conf = storage_conf.as_ref().clone();
+        conf.backend = backend;
+        let conf = Arc::new(conf);
+        let blob_infos = self.sb.superblock.get_blob_infos();
+
+        self.device
+            .switch_backend(&conf, &blob_infos, RAFS_SWITCH_BACKEND_TIMEOUT)
+            .map_err(RafsError::SwapBackend)?;
+        *storage_conf = conf;
+        info!(
+            "switched storage backend of {} to {}",
+            self.id, storage_conf.backend.backend_type
+        );
+
+        Ok(())
+    }
+
+    /// Hold reads of file data until the filesystem is thawed or `timeout` passes.
+    ///
+    /// Reads, prefetching, preheating and serving peers in progress are drained before returning,
+    /// so the blob cache and the storage backend can be maintained, e.g. to migrate cache files or
+    /// rotate credentials. Metadata operations are still served from the bootstrap. Freezing a
+    /// frozen filesystem only resets the time to thaw it.
+    pub fn freeze(&self, timeout: Duration) -> RafsResult<()> {
+        self.device
+            .freeze(timeout, RAFS_SWITCH_BACKEND_TIMEOUT)
+            .map_err(RafsError::Freeze)?;
+        info!("froze {} for at most {:?}", self.id, timeout);
 
         Ok(())
     }
 
+    /// Let reads held by [freeze()](Rafs::freeze) go on.
+    pub fn thaw(&self) {
+        if self.device.is_frozen() {
+            self.device.thaw();
+            info!("thawed {}", self.id);
+        }
+    }
+
+    /// Check whether the filesystem is frozen.
+    pub fn is_frozen(&self) -> bool {
+        self.device.is_frozen()
+    }
+
+    /// Check whether reads of file data are held, by freezing the filesystem or another one
+    /// sharing its blob caches.
+    pub fn holds_reads(&self) -> bool {
+        self.device.holds_reads()
+    }
+
     /// Import an rafs bootstrap to initialize the filesystem instance.
     pub fn import(
         &mut self,
@@ -301,10 +579,34 @@ impl Rafs {
         if self.initialized {
             return Err(RafsError::AlreadyMounted);
         }
+        // The bootstrap may come from untrusted parties, validate it before walking the tree.
+        self.sb
+            .validate_tree()
+            .map_err(RafsError::InvalidBootstrap)?;
         if self.fs_prefetch {
             // Device should be ready before any prefetch.
             self.prefetch(r, prefetch_files)
         }
+        if let Some(config) = self.scrub.clone() {
+            // Metadata shared by mounts is scrubbed by the first of them only.
+            if !shared::claim_scrub(&self.sb) {
+                info!("metadata is scrubbed by another mount sharing it");
+            } else {
+                // Scrubbing is best effort and doesn't affect mounting.
+                match Scrubber::start(
+                    config,
+                    self.sb.clone(),
+                    self.device.clone(),
+                    self.ios.clone(),
+                ) {
+                    Ok(scrubber) => self.scrubber = Some(scrubber),
+                    Err(e) => {
+                        shared::release_scrub(&self.sb);
+                        warn!("failed to start scrubber, {}", e)
+                    }
+                }
+            }
+        }
         self.initialized = true;
 
         Ok(())
@@ -315,9 +617,18 @@ impl Rafs {
         info! {"Destroy rafs"}
 
         if self.initialized {
-            Arc::get_mut(&mut self.sb)
-                .expect("Superblock is no longer used")
-                .destroy();
+            // The scrubber holds references to the metadata and the device.
+            if let Some(mut scrubber) = self.scrubber.take() {
+                scrubber.stop();
+                shared::release_scrub(&self.sb);
+            }
+            // Metadata shared with other mounts is destroyed by the last one.
+            if Arc::strong_count(&self.sb) == 1 {
+                shared::remove(&self.sb_key.lock().unwrap(), &self.sb);
+            }
+            if let Some(sb) = Arc::get_mut(&mut self.sb) {
+                sb.destroy();
+            }
             self.device.close()?;
             self.initialized = false;
         }
@@ -335,9 +646,91 @@ impl Rafs {
         &self.sb.meta
     }
 
+    /// Get information about all data blobs referenced by the filesystem.
+    pub fn blob_infos(&self) -> Vec<Arc<BlobInfo>> {
+        self.sb.superblock.get_blob_infos()
+    }
+
+    /// Get memory used by the filesystem metadata, in unit of Byte.
+    pub fn memory_usage(&self) -> u64 {
+        self.sb.memory_usage()
+    }
+
+    /// Get memory used by state of blob caches of the filesystem, in unit of Byte.
+    pub fn cache_memory_usage(&self) -> u64 {
+        self.device.memory_usage()
+    }
+
+    /// Check whether the storage backend of the filesystem is reachable.
+    pub fn check_backend(&self) -> Result<()> {
+        self.device.check_backend()
+    }
+
+    /// Check whether any data blob of the filesystem has failed permanently, so reads of it fail
+    /// until the blob becomes available again.
+    pub fn check_failed_blobs(&self) -> Result<()> {
+        self.device.check_failed_blobs()
+    }
+
+    /// Write cached data and chunk maps of blob caches back to the storage, so the cache state
+    /// survives restarts of nydusd and the host.
+    pub fn flush(&self) -> Result<()> {
+        let flushed = self.device.flush(RAFS_FLUSH_TIMEOUT);
+        let saved = match self.access_pattern_file.as_ref() {
+            Some(path) => self
+                .ios
+                .export_files_access_patterns()
+                .map_err(|e| eother!(format!("failed to export access patterns, {:?}", e)))
+                .and_then(|p| save_file(path, p.into_bytes(), RAFS_FLUSH_TIMEOUT)),
+            None => Ok(()),
+        };
+
+        flushed.and(saved)
+    }
+
+    /// Release memory used by the filesystem metadata and state of blob caches if possible.
+    pub fn shrink(&self) {
+        self.sb.shrink();
+        self.device.shrink();
+    }
+
+    /// Fetch all data of files in `files` into the blob cache synchronously, directories are
+    /// fetched recursively. All files of the filesystem are fetched if `files` is `None`.
+    ///
+    /// It doesn't need the filesystem to be imported, so caches may be warmed up before mounting.
+    /// Return the amount of data fetched from the storage backend.
+    pub fn fetch_files(&self, files: Option<&[PathBuf]>) -> Result<usize> {
+        let roots = match files {
+            Some(files) => files
+                .iter()
+                .map(|f| self.sb.ino_from_path(f))
+                .collect::<Result<Vec<Inode>>>()?,
+            None => vec![RAFS_ROOT_INODE],
+        };
+
+        let mut inodes = Vec::new();
+        for ino in roots {
+            let inode = self.sb.get_inode(ino, false)?;
+            if inode.is_dir() {
+                inode.collect_descendants_inodes(&mut inodes)?;
+            } else {
+                inodes.push(inode);
+            }
+        }
+
+        let mut total = 0;
+        for inode in inodes.iter().filter(|i| i.is_reg() && i.size() != 0) {
+            let descs = inode.alloc_bio_vecs(0, inode.size() as usize, false)?;
+            total += self.device.fetch_io_vecs(&descs)?;
+        }
+
+        Ok(total)
+    }
+
     fn prepare_storage_conf(conf: &RafsConfig) -> RafsResult<Arc<FactoryConfig>> {
         let mut storage_conf = conf.device.clone();
         storage_conf.cache.cache_validate = conf.digest_validate;
+        storage_conf.cache.cipher_config = conf.encryption.clone();
         storage_conf.cache.prefetch_config = TryFrom::try_from(conf)?;
         Ok(Arc::new(storage_conf))
     }
@@ -369,14 +762,8 @@ impl Rafs {
                 type_: 0,
                 name: name.as_os_str().as_bytes(),
             }) {
-                Ok(0) => {
-                    self.ios.new_file_counter(ino);
-                    Ok(PostWalkAction::Break)
-                }
-                Ok(_) => {
-                    self.ios.new_file_counter(ino);
-                    Ok(PostWalkAction::Continue)
-                } // TODO: should we check `size` here?
+                Ok(0) => Ok(PostWalkAction::Break),
+                Ok(_) => Ok(PostWalkAction::Continue), // TODO: should we check `size` here?
                 Err(e) => Err(e),
             }
         };
@@ -386,6 +773,42 @@ impl Rafs {
         Ok(())
     }
 
+    /// Take a lookup reference of the inode on behalf of the kernel.
+    fn inc_lookup(&self, ino: Inode) {
+        // The root inode is never forgotten, its state lives as long as the filesystem.
+        if ino == self.root_ino() {
+            return;
+        }
+        let mut counts = self.lookup_counts.lock().unwrap();
+        let count = counts.entry(ino).or_insert(0);
+        if *count == 0 {
+            self.ios.new_file_counter(ino);
+        }
+        *count += 1;
+    }
+
+    /// Drop `count` lookup references of the inode, and release its state once all references
+    /// are dropped.
+    fn dec_lookup(&self, ino: Inode, count: u64) {
+        let mut counts = self.lookup_counts.lock().unwrap();
+        if let Some(c) = counts.get_mut(&ino) {
+            *c = c.saturating_sub(count);
+            if *c == 0 {
+                counts.remove(&ino);
+                self.folded_names.lock().unwrap().remove(&ino);
+                self.ios.release_file_counter(ino);
+                if let Some(fetcher) = self.whole_file.as_ref() {
+                    fetcher.detector().forget(ino);
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn lookup_count(&self, ino: Inode) -> u64 {
+        *self.lookup_counts.lock().unwrap().get(&ino).unwrap_or(&0)
+    }
+
     fn negative_entry(&self) -> Entry {
         Entry {
             attr: Attr {
@@ -400,6 +823,47 @@ impl Rafs {
         }
     }
 
+    // Get the child named `name` of the directory `parent`, ignoring case of names if the
+    // filesystem is case-insensitive. The first match is taken if multiple names only differ
+    // in case, which is rejected by builders checking case collision.
+    fn get_child_by_name(
+        &self,
+        parent: &dyn RafsInode,
+        name: &OsStr,
+    ) -> Result<Arc<dyn RafsInode>> {
+        let err = match parent.get_child_by_name(name) {
+            Err(e) if self.case_insensitive => e,
+            res => return res,
+        };
+
+        match self.folded_names(parent)?.get(&fold_name_case(name)) {
+            Some(ino) => self.sb.get_inode(*ino, self.digest_validate),
+            None => Err(err),
+        }
+    }
+
+    // Get children of the directory `parent` by folded names, indexing them on first use.
+    fn folded_names(&self, parent: &dyn RafsInode) -> Result<Arc<HashMap<OsString, Inode>>> {
+        if let Some(names) = self.folded_names.lock().unwrap().get(&parent.ino()) {
+            return Ok(names.clone());
+        }
+
+        let mut names = HashMap::new();
+        parent.walk_children_inodes(0, &mut |_inode, child, ino, _offset| {
+            if child != DOT && child != DOTDOT {
+                names.entry(fold_name_case(&child)).or_insert(ino);
+            }
+            Ok(PostWalkAction::Continue)
+        })?;
+        let names = Arc::new(names);
+        self.folded_names
+            .lock()
+            .unwrap()
+            .insert(parent.ino(), names.clone());
+
+        Ok(names)
+    }
+
     fn get_inode_attr(&self, ino: u64) -> Result<Attr> {
         let inode = self.sb.get_inode(ino, false)?;
         let mut attr = inode.get_attr();
@@ -413,10 +877,12 @@ impl Rafs {
         // Older rafs image doesn't include mtime, in such case we use
         // runtime timestamp.
         if attr.mtime == 0 {
-            attr.atime = self.i_time;
             attr.ctime = self.i_time;
             attr.mtime = self.i_time;
         }
+        let (atime, atimensec) = self.access_time(attr.mtime, attr.mtimensec);
+        attr.atime = atime;
+        attr.atimensec = atimensec;
 
         // Only touch permissions bits. This trick is some sort of workaround
         // since nydusify gives root directory permission of 0o750 and fuse mount
@@ -425,6 +891,7 @@ impl Rafs {
         if attr.ino == self.root_ino() {
             attr.mode = attr.mode & !0o777 | 0o755;
         }
+        self.map_owner(&mut attr.uid, &mut attr.gid, &mut attr.mode);
 
         Ok(attr)
     }
@@ -440,10 +907,13 @@ impl Rafs {
 
         // Older rafs image doesn't include mtime, in such case we use runtime timestamp.
         if entry.attr.st_mtime == 0 {
-            entry.attr.st_atime = self.i_time as i64;
             entry.attr.st_ctime = self.i_time as i64;
             entry.attr.st_mtime = self.i_time as i64;
         }
+        let (atime, atimensec) =
+            self.access_time(entry.attr.st_mtime as u64, entry.attr.st_mtime_nsec as u32);
+        entry.attr.st_atime = atime as i64;
+        entry.attr.st_atime_nsec = atimensec as i64;
 
         // Only touch permissions bits. This trick is some sort of workaround
         // since nydusify gives root directory permission of 0o750 and fuse mount
@@ -452,9 +922,55 @@ impl Rafs {
         if entry.inode == ROOT_ID {
             entry.attr.st_mode = entry.attr.st_mode & !0o777 | 0o755;
         }
+        self.map_owner(
+            &mut entry.attr.st_uid,
+            &mut entry.attr.st_gid,
+            &mut entry.attr.st_mode,
+        );
 
         entry
     }
+
+    /// Get the access time to report for an inode modified at `mtime`, as per the atime policy.
+    fn access_time(&self, mtime: u64, mtimensec: u32) -> (u64, u32) {
+        match self.atime {
+            AtimePolicy::Relatime if self.i_time > mtime => (self.i_time, 0),
+            _ => (mtime, mtimensec),
+        }
+    }
+
+    /// Map uid/gid of an inode, and hand it over to `fs_group` with group read access granted
+    /// if the filesystem is mounted as a data volume.
+    fn map_owner(&self, uid: &mut u32, gid: &mut u32, mode: &mut u32) {
+        if let Some(mapper) = self.id_mapper.as_ref() {
+            mapper.map(uid, gid);
+        }
+        if let Some(volume) = self.volume.as_ref() {
+            if let Some(group) = volume.fs_group {
+                *gid = group;
+                match *mode & libc::S_IFMT {
+                    libc::S_IFDIR => *mode |= libc::S_ISGID | 0o050,
+                    libc::S_IFLNK => {}
+                    _ => *mode |= 0o040,
+                }
+            }
+        }
+    }
+
+    // Account errors reading file data, interrupted reads are not errors of the filesystem.
+    fn read_error(&self, ino: u64, e: std::io::Error) -> std::io::Error {
+        let e = with_context(e, ErrorContext::default().mount(&self.id).inode(ino));
+        if error::raw_os_error(&e) != Some(libc::EINTR) {
+            self.ios.error_stats().record(ino, &e);
+        }
+        error::preserve_errno(e)
+    }
+
+    /// Fail a write-class operation with EROFS and account it as an error of the operation.
+    fn reject_write<T>(&self, fop: StatsFop, ino: u64) -> Result<T> {
+        let _rec = FopRecorder::settle(fop, ino, &self.ios);
+        Err(std::io::Error::from_raw_os_error(libc::EROFS))
+    }
 }
 
 impl Rafs {
@@ -470,6 +986,27 @@ impl Rafs {
         });
     }
 
+    // Fetch all data of the file into the cache in background.
+    fn fetch_whole_file(&self, ino: Inode, inode: &dyn RafsInode) {
+        let fetcher = match self.whole_file.as_ref() {
+            Some(fetcher) => fetcher,
+            None => return,
+        };
+        let descs = match inode.alloc_bio_vecs(0, inode.size() as usize, false) {
+            Ok(descs) => descs,
+            Err(e) => {
+                warn!("failed to get chunks of file {}, {}", ino, e);
+                fetcher.detector().forget(ino);
+                return;
+            }
+        };
+        // Reads fall back to fetching chunks on demand until the file is fetched, or if it fails
+        // to be fetched.
+        if let Err(e) = fetcher.fetch(ino, descs, self.device.clone()) {
+            warn!("failed to queue whole file {} to fetch, {}", ino, e);
+        }
+    }
+
     /// for blobfs
     pub fn fetch_range_synchronous(&self, prefetches: &[BlobPrefetchRequest]) -> Result<()> {
         self.device.fetch_range_synchronous(prefetches)
@@ -540,6 +1077,52 @@ impl Rafs {
     }
 }
 
+// Atomically replace `path` with `data` and wait at most `timeout` for it to reach the disk.
+fn save_file(path: &Path, data: Vec<u8>, timeout: Duration) -> Result<()> {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = PathBuf::from(tmp);
+    let path = path.to_path_buf();
+    let (tx, rx) = channel();
+
+    thread::Builder::new()
+        .name("rafs_save_file".to_string())
+        .spawn(move || {
+            let res = (|| {
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&tmp)?;
+                file.write_all(&data)?;
+                file.sync_all()?;
+                fs::rename(&tmp, &path)?;
+                if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                    File::open(dir)?.sync_all()?;
+                }
+                Ok(())
+            })();
+            if res.is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+            let _ = tx.send(res);
+        })?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(RecvTimeoutError::Timeout) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "timed out saving access patterns",
+        )),
+        Err(RecvTimeoutError::Disconnected) => Err(eother!("failed to save access patterns")),
+    }
+}
+
 impl BackendFileSystem for Rafs {
     fn mount(&self) -> Result<(Entry, u64)> {
         let root_inode = self.sb.get_inode(self.root_ino(), self.digest_validate)?;
@@ -574,6 +1157,7 @@ impl FileSystem for Rafs {
         )
     }
 
+    // Blob caches are flushed by the daemon when unmounting and shutting down.
     fn destroy(&self) {}
 
     fn lookup(&self, _ctx: &Context, ino: u64, name: &CStr) -> Result<Entry> {
@@ -585,28 +1169,31 @@ impl FileSystem for Rafs {
         }
 
         rec.mark_success(0);
-        if target == DOT || (ino == ROOT_ID && target == DOTDOT) {
+        let entry = if target == DOT || (ino == ROOT_ID && target == DOTDOT) {
             let mut entry = self.get_inode_entry(parent);
             entry.inode = ino;
-            Ok(entry)
+            entry
         } else if target == DOTDOT {
-            Ok(self
-                .sb
+            self.sb
                 .get_inode(parent.parent(), self.digest_validate)
                 .map(|i| self.get_inode_entry(i))
-                .unwrap_or_else(|_| self.negative_entry()))
+                .unwrap_or_else(|_| self.negative_entry())
         } else {
-            Ok(parent
-                .get_child_by_name(target)
-                .map(|i| {
-                    self.ios.new_file_counter(i.ino());
-                    self.get_inode_entry(i)
-                })
-                .unwrap_or_else(|_| self.negative_entry()))
+            self.get_child_by_name(parent.as_ref(), target)
+                .map(|i| self.get_inode_entry(i))
+                .unwrap_or_else(|_| self.negative_entry())
+        };
+        // The kernel takes a lookup reference for each positive entry replied.
+        if entry.inode != 0 {
+            self.inc_lookup(entry.inode);
         }
+
+        Ok(entry)
     }
 
-    fn forget(&self, _ctx: &Context, _inode: u64, _count: u64) {}
+    fn forget(&self, _ctx: &Context, inode: u64, count: u64) {
+        self.dec_lookup(inode, count);
+    }
 
     fn batch_forget(&self, ctx: &Context, requests: Vec<(u64, u64)>) {
         for (inode, count) in requests {
@@ -671,9 +1258,31 @@ impl FileSystem for Rafs {
 
         let real_size = cmp::min(size as u64, inode_size - offset);
         let mut result = 0;
-        let mut descs = inode.alloc_bio_vecs(offset, real_size as usize, true)?;
+        let mut descs = inode
+            .alloc_bio_vecs(offset, real_size as usize, true)
+            .map_err(|e| self.read_error(ino, ErrorClass::MetadataCorruption.error(e)))?;
         debug_assert!(!descs.is_empty() && !descs[0].bi_vec.is_empty());
 
+        let access_recorder = self.ios.access_recorder();
+        if access_recorder.is_enabled() {
+            let chunks = descs
+                .iter()
+                .flat_map(|desc| desc.bi_vec.iter())
+                .map(|bio| (bio.blob.blob_index(), bio.chunkinfo.id()))
+                .collect();
+            access_recorder.record(ino, offset, real_size as u32, chunks);
+        }
+
+        if let Some(fetcher) = self.whole_file.as_ref() {
+            if self.sb.meta.is_v5()
+                && fetcher
+                    .detector()
+                    .record(ino, inode_size, offset, real_size)
+            {
+                self.fetch_whole_file(ino, inode.as_ref());
+            }
+        }
+
         // Try to amplify user io for Rafs v5, to improve performance.
         if self.sb.meta.is_v5() && size < self.amplify_io {
             let all_chunks_ready = self.device.is_all_chunk_ready(&descs);
@@ -702,7 +1311,10 @@ impl FileSystem for Rafs {
             debug_assert!(desc.bi_size != 0);
 
             // Avoid copying `desc`
-            let r = self.device.read_to(w, desc)?;
+            let r = self
+                .device
+                .read_to(w, desc)
+                .map_err(|e| self.read_error(ino, e))?;
             result += r;
             recorder.mark_success(r);
             if r != desc.bi_size {
@@ -717,10 +1329,21 @@ impl FileSystem for Rafs {
     fn open(
         &self,
         _ctx: &Context,
-        _inode: Self::Inode,
-        _flags: u32,
+        inode: Self::Inode,
+        flags: u32,
         _fuse_flags: u32,
     ) -> Result<(Option<Self::Handle>, OpenOptions)> {
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY || flags as i32 & libc::O_TRUNC != 0 {
+            return self.reject_write(Open, inode);
+        }
+        if let Some(fetcher) = self.whole_file.as_ref() {
+            if self.sb.meta.is_v5() {
+                let rafs_inode = self.sb.get_inode(inode, false)?;
+                if rafs_inode.is_reg() && fetcher.detector().record_open(inode, rafs_inode.size()) {
+                    self.fetch_whole_file(inode, rafs_inode.as_ref());
+                }
+            }
+        }
         // Keep cache since we are readonly
         Ok((None, OpenOptions::KEEP_CACHE))
     }
@@ -845,7 +1468,14 @@ impl FileSystem for Rafs {
 
         self.do_readdir(ino, size, offset, &mut |dir_entry| {
             let inode = self.sb.get_inode(dir_entry.ino, self.digest_validate)?;
-            add_entry(dir_entry, self.get_inode_entry(inode))
+            // The kernel doesn't take lookup references for "." and "..".
+            let is_dot = dir_entry.name == DOT.as_bytes() || dir_entry.name == DOTDOT.as_bytes();
+            let entry_ino = dir_entry.ino;
+            let ret = add_entry(dir_entry, self.get_inode_entry(inode))?;
+            if ret > 0 && !is_dot {
+                self.inc_lookup(entry_ino);
+            }
+            Ok(ret)
         })
         .map(|r| {
             rec.mark_success(0);
@@ -886,6 +1516,17 @@ impl FileSystem for Rafs {
             return Err(eacces!("permission denied"));
         }
 
+        // Like the kernel, deny write access to regular files, directories and symlinks on
+        // read-only filesystems, regardless of permission bits.
+        if (mode & libc::W_OK) != 0
+            && matches!(
+                st.mode & libc::S_IFMT,
+                libc::S_IFREG | libc::S_IFDIR | libc::S_IFLNK
+            )
+        {
+            return Err(std::io::Error::from_raw_os_error(libc::EROFS));
+        }
+
         if (mode & libc::W_OK) != 0
             && ctx.uid != 0
             && (st.uid != ctx.uid || st.mode & 0o200 == 0)
@@ -909,6 +1550,141 @@ impl FileSystem for Rafs {
         rec.mark_success(0);
         Ok(())
     }
+
+    fn setattr(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        _attr: libc::stat64,
+        _handle: Option<u64>,
+        valid: SetattrValid,
+    ) -> Result<(libc::stat64, Duration)> {
+        // Updates of access times only are ignored with noatime, as if the filesystem was
+        // mounted with `noatime`.
+        let atime = SetattrValid::ATIME | SetattrValid::ATIME_NOW;
+        if self.atime == AtimePolicy::Noatime
+            && valid.intersects(atime)
+            && (valid - atime).is_empty()
+        {
+            let mut rec = FopRecorder::settle(Setattr, inode, &self.ios);
+            let attr = self.get_inode_attr(inode)?;
+            rec.mark_success(0);
+            return Ok((attr.into(), self.sb.meta.attr_timeout));
+        }
+
+        self.reject_write(Setattr, inode)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        _handle: u64,
+        _r: &mut dyn ZeroCopyReader,
+        _size: u32,
+        _offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _flags: u32,
+        _fuse_flags: u32,
+    ) -> Result<usize> {
+        self.reject_write(Write, inode)
+    }
+
+    fn mknod(
+        &self,
+        _ctx: &Context,
+        parent: u64,
+        _name: &CStr,
+        _mode: u32,
+        _rdev: u32,
+        _umask: u32,
+    ) -> Result<Entry> {
+        self.reject_write(Mknod, parent)
+    }
+
+    fn mkdir(
+        &self,
+        _ctx: &Context,
+        parent: u64,
+        _name: &CStr,
+        _mode: u32,
+        _umask: u32,
+    ) -> Result<Entry> {
+        self.reject_write(Mkdir, parent)
+    }
+
+    fn unlink(&self, _ctx: &Context, parent: u64, _name: &CStr) -> Result<()> {
+        self.reject_write(Unlink, parent)
+    }
+
+    fn rmdir(&self, _ctx: &Context, parent: u64, _name: &CStr) -> Result<()> {
+        self.reject_write(Rmdir, parent)
+    }
+
+    fn symlink(
+        &self,
+        _ctx: &Context,
+        _linkname: &CStr,
+        parent: u64,
+        _name: &CStr,
+    ) -> Result<Entry> {
+        self.reject_write(Symlink, parent)
+    }
+
+    fn rename(
+        &self,
+        _ctx: &Context,
+        olddir: u64,
+        _oldname: &CStr,
+        _newdir: u64,
+        _newname: &CStr,
+        _flags: u32,
+    ) -> Result<()> {
+        self.reject_write(Rename, olddir)
+    }
+
+    fn link(&self, _ctx: &Context, inode: u64, _newparent: u64, _newname: &CStr) -> Result<Entry> {
+        self.reject_write(Link, inode)
+    }
+
+    fn create(
+        &self,
+        _ctx: &Context,
+        parent: u64,
+        _name: &CStr,
+        _args: CreateIn,
+    ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+        self.reject_write(Create, parent)
+    }
+
+    fn setxattr(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        _name: &CStr,
+        _value: &[u8],
+        _flags: u32,
+    ) -> Result<()> {
+        self.reject_write(Setxattr, inode)
+    }
+
+    fn removexattr(&self, _ctx: &Context, inode: u64, _name: &CStr) -> Result<()> {
+        self.reject_write(Removexattr, inode)
+    }
+
+    fn fallocate(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        _handle: u64,
+        _mode: u32,
+        _offset: u64,
+        _length: u64,
+    ) -> Result<()> {
+        self.reject_write(Fallocate, inode)
+    }
 }
 
 #[cfg(test)]
@@ -957,6 +1733,24 @@ pub(crate) mod tests {
         Box::new(rafs)
     }
 
+    #[test]
+    fn it_should_save_access_patterns_on_flush() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let path = dir.as_path().join("patterns.json");
+        let mut rafs = new_rafs_backend();
+        rafs.ios.toggle_access_pattern(true);
+        rafs.ios.new_file_counter(RAFS_ROOT_INODE);
+        rafs.access_pattern_file = Some(path.clone());
+
+        rafs.flush().unwrap();
+        let patterns: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert!(patterns.is_array());
+        // Flushing again replaces the file and leaves no temporary file behind.
+        rafs.flush().unwrap();
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 1);
+    }
+
     #[test]
     fn it_should_create_new_rafs_fs() {
         let rafs = new_rafs_backend();
@@ -968,6 +1762,166 @@ pub(crate) mod tests {
         assert_eq!(attr.mode & 0o777, 0o755);
     }
 
+    #[test]
+    fn it_should_take_over_volume_id_shifts() {
+        let mut config = RafsConfig::new();
+        config.volume = Some(VolumeConfig {
+            uid_shift: 1000,
+            ..Default::default()
+        });
+        let id_map = config.id_map_config().unwrap().unwrap();
+        assert_eq!((id_map.uid_shift, id_map.gid_shift), (1000, 0));
+
+        config.id_map = Some(IdMapConfig::default());
+        assert!(config.id_map_config().is_err());
+        config.volume = None;
+        assert!(config.id_map_config().unwrap().is_some());
+    }
+
+    #[test]
+    fn it_should_map_owner() {
+        let mut rafs = new_rafs_backend();
+        let orig = rafs.get_inode_attr(1).unwrap();
+        let config = IdMapConfig {
+            uid_shift: 100000,
+            gid_shift: 200000,
+            ..Default::default()
+        };
+        rafs.id_mapper = Some(IdMapper::new(&config).unwrap());
+        let attr = rafs.get_inode_attr(1).unwrap();
+        assert_eq!(attr.uid, orig.uid + 100000);
+        assert_eq!(attr.gid, orig.gid + 200000);
+        assert_eq!(attr.mode, orig.mode);
+        let entry = rafs.get_inode_entry(rafs.sb.get_inode(1, false).unwrap());
+        assert_eq!(entry.attr.st_uid, orig.uid + 100000);
+        assert_eq!(entry.attr.st_gid, orig.gid + 200000);
+
+        rafs.volume = Some(VolumeConfig {
+            fs_group: Some(2000),
+            ..Default::default()
+        });
+        let attr = rafs.get_inode_attr(1).unwrap();
+        assert_eq!(attr.uid, orig.uid + 100000);
+        assert_eq!(attr.gid, 2000);
+        assert_eq!(attr.mode, orig.mode | libc::S_ISGID | 0o050);
+    }
+
+    #[test]
+    fn it_should_report_atime() {
+        let mut rafs = new_rafs_backend();
+        let ctx = &Context {
+            gid: 0,
+            pid: 1,
+            uid: 0,
+        };
+        let attr = rafs.get_inode_attr(1).unwrap();
+        assert_eq!(attr.atime, attr.mtime);
+        assert_eq!(attr.atimensec, attr.mtimensec);
+        let entry = rafs.get_inode_entry(rafs.sb.get_inode(1, false).unwrap());
+        assert_eq!(entry.attr.st_atime, entry.attr.st_mtime);
+
+        let stat: libc::stat64 = unsafe { std::mem::zeroed() };
+        let (st, _) = rafs
+            .setattr(ctx, 1, stat, None, SetattrValid::ATIME_NOW)
+            .unwrap();
+        assert_eq!(st.st_atime, attr.atime as i64);
+        let err = rafs
+            .setattr(
+                ctx,
+                1,
+                stat,
+                None,
+                SetattrValid::ATIME | SetattrValid::MTIME,
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+
+        rafs.atime = AtimePolicy::Relatime;
+        let attr = rafs.get_inode_attr(1).unwrap();
+        assert_eq!(attr.atime, cmp::max(attr.mtime, rafs.i_time));
+        assert!(rafs
+            .setattr(ctx, 1, stat, None, SetattrValid::ATIME)
+            .is_err());
+    }
+
+    #[test]
+    fn it_should_forget_inodes() {
+        let rafs = new_rafs_backend();
+        let ctx = &Context {
+            gid: 0,
+            pid: 1,
+            uid: 0,
+        };
+        let mut names = Vec::new();
+        rafs.readdir(ctx, ROOT_ID, 0, 4096, 0, &mut |e| {
+            names.push((e.ino, e.name.to_vec()));
+            Ok(1)
+        })
+        .unwrap();
+        let (ino, name) = names
+            .into_iter()
+            .find(|(_, n)| n.as_slice() != DOT.as_bytes() && n.as_slice() != DOTDOT.as_bytes())
+            .unwrap();
+        let mut name = name;
+        name.push(0);
+        let name = CStr::from_bytes_with_nul(&name).unwrap();
+        // Readdir doesn't take lookup references.
+        assert_eq!(rafs.lookup_count(ino), 0);
+
+        assert_eq!(rafs.lookup(ctx, ROOT_ID, name).unwrap().inode, ino);
+        assert_eq!(rafs.lookup(ctx, ROOT_ID, name).unwrap().inode, ino);
+        assert_eq!(rafs.lookup_count(ino), 2);
+        rafs.forget(ctx, ino, 1);
+        assert_eq!(rafs.lookup_count(ino), 1);
+        rafs.batch_forget(ctx, vec![(ino, 1), (ino, 1)]);
+        assert_eq!(rafs.lookup_count(ino), 0);
+        assert!(rafs.lookup_counts.lock().unwrap().is_empty());
+
+        // The root inode is never forgotten.
+        let dot = CStr::from_bytes_with_nul(b".\0").unwrap();
+        rafs.lookup(ctx, ROOT_ID, dot).unwrap();
+        assert_eq!(rafs.lookup_count(ROOT_ID), 0);
+    }
+
+    #[test]
+    fn it_should_lookup_case_insensitively() {
+        let mut rafs = new_rafs_backend();
+        let ctx = &Context {
+            gid: 0,
+            pid: 1,
+            uid: 0,
+        };
+        let mut names = Vec::new();
+        rafs.readdir(ctx, ROOT_ID, 0, 4096, 0, &mut |e| {
+            names.push((e.ino, e.name.to_vec()));
+            Ok(1)
+        })
+        .unwrap();
+        let (ino, name) = names
+            .into_iter()
+            .find(|(_, n)| n.iter().any(|c| c.is_ascii_lowercase()))
+            .unwrap();
+        let mut upper = name.to_ascii_uppercase();
+        upper.push(0);
+        let upper = CStr::from_bytes_with_nul(&upper).unwrap();
+        let missing = CStr::from_bytes_with_nul(b"NO-SUCH-FILE\0").unwrap();
+
+        assert_eq!(rafs.lookup(ctx, ROOT_ID, upper).unwrap().inode, 0);
+        assert!(rafs.folded_names.lock().unwrap().is_empty());
+
+        rafs.case_insensitive = true;
+        assert_eq!(rafs.lookup(ctx, ROOT_ID, upper).unwrap().inode, ino);
+        assert_eq!(rafs.lookup(ctx, ROOT_ID, missing).unwrap().inode, 0);
+        // Children of the directory are indexed once for all lookups.
+        let names = rafs.folded_names.lock().unwrap()[&ROOT_ID].clone();
+        assert_eq!(names.get(OsStr::from_bytes(&name)), Some(&ino));
+        rafs.lookup(ctx, ROOT_ID, upper).unwrap();
+        assert!(Arc::ptr_eq(
+            &names,
+            &rafs.folded_names.lock().unwrap()[&ROOT_ID]
+        ));
+    }
+
     #[test]
     fn it_should_access() {
         let rafs = new_rafs_backend();
@@ -981,6 +1935,35 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn it_should_reject_write() {
+        let rafs = new_rafs_backend();
+        let ctx = &Context {
+            gid: 0,
+            pid: 1,
+            uid: 0,
+        };
+        let name = CStr::from_bytes_with_nul(b"foo\0").unwrap();
+        let is_erofs = |e: std::io::Error| e.raw_os_error() == Some(libc::EROFS);
+
+        assert!(is_erofs(rafs.unlink(ctx, 1, name).unwrap_err()));
+        assert!(is_erofs(rafs.mkdir(ctx, 1, name, 0o755, 0).err().unwrap()));
+        assert!(is_erofs(rafs.rename(ctx, 1, name, 1, name, 0).unwrap_err()));
+        assert!(is_erofs(rafs.removexattr(ctx, 1, name).unwrap_err()));
+        assert!(is_erofs(
+            rafs.open(ctx, 1, libc::O_RDWR as u32, 0).unwrap_err()
+        ));
+        assert!(is_erofs(
+            rafs.open(ctx, 1, (libc::O_RDONLY | libc::O_TRUNC) as u32, 0)
+                .unwrap_err()
+        ));
+        assert!(rafs.open(ctx, 1, libc::O_RDONLY as u32, 0).is_ok());
+        assert!(is_erofs(
+            rafs.access(ctx, 1, libc::W_OK as u32).unwrap_err()
+        ));
+        assert!(rafs.access(ctx, 1, libc::R_OK as u32).is_ok());
+    }
+
     #[test]
     fn it_should_listxattr() {
         let rafs = new_rafs_backend();
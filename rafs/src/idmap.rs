@@ -0,0 +1,238 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Uid and gid mapping of Rafs inodes.
+//!
+//! Images are usually built as root, and mapping ids of inodes allows to serve them to user
+//! namespaced or non-root mapped containers without rebuilding. Ids are mapped either by a fixed
+//! offset, or by a map file in the format of `/proc/<pid>/uid_map`, each line of which maps a
+//! range of ids in the image to ids presented by the filesystem:
+//! ```text
+//! <first id in image> <first mapped id> <count>
+//! ```
+//! Like the kernel, ids out of all ranges of a map file are mapped to the overflow id 65534.
+
+use std::fs;
+use std::io::Result;
+
+use serde::Deserialize;
+
+use crate::{RafsError, RafsResult};
+
+/// Id presented for ids out of all ranges of a map file.
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// Configuration information to map uid and gid of inodes.
+#[derive(Clone, Default, Deserialize)]
+pub struct IdMapConfig {
+    /// Offset added to uids of all inodes.
+    #[serde(default)]
+    pub uid_shift: u32,
+    /// Offset added to gids of all inodes.
+    #[serde(default)]
+    pub gid_shift: u32,
+    /// Path to the uid map file, conflicting with `uid_shift`.
+    #[serde(default)]
+    pub uid_map: String,
+    /// Path to the gid map file, conflicting with `gid_shift`.
+    #[serde(default)]
+    pub gid_map: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct IdRange {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum IdMap {
+    Shift(u32),
+    Ranges(Vec<IdRange>),
+}
+
+impl IdMap {
+    fn new(shift: u32, map_file: &str) -> RafsResult<Self> {
+        if map_file.is_empty() {
+            return Ok(IdMap::Shift(shift));
+        }
+        if shift != 0 {
+            return Err(RafsError::Configure(format!(
+                "id shift conflicts with id map file {}",
+                map_file
+            )));
+        }
+
+        let content = fs::read_to_string(map_file).map_err(|e| {
+            RafsError::Configure(format!("failed to read id map file {}, {}", map_file, e))
+        })?;
+        let ranges = Self::parse(&content).map_err(|e| {
+            RafsError::Configure(format!("invalid id map file {}, {}", map_file, e))
+        })?;
+
+        Ok(IdMap::Ranges(ranges))
+    }
+
+    fn parse(content: &str) -> Result<Vec<IdRange>> {
+        let mut ranges: Vec<IdRange> = Vec::new();
+
+        for line in content.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line
+                .split_whitespace()
+                .map(|f| f.parse::<u32>())
+                .collect::<std::result::Result<Vec<u32>, _>>()
+                .map_err(|e| einval!(format!("{}: {}", line, e)))?;
+            if fields.len() != 3 {
+                return Err(einval!(format!("{}: expect 3 fields", line)));
+            }
+            let range = IdRange {
+                inside: fields[0],
+                outside: fields[1],
+                count: fields[2],
+            };
+            // Like the kernel, the id -1 can't be mapped from or to.
+            if range.count == 0
+                || range.inside.checked_add(range.count).is_none()
+                || range.outside.checked_add(range.count).is_none()
+            {
+                return Err(einval!(format!("{}: invalid id range", line)));
+            }
+            let overlapped = |a: u32, b: u32, count: u32| a < b + count && b < a + range.count;
+            if ranges.iter().any(|r| {
+                overlapped(range.inside, r.inside, r.count)
+                    || overlapped(range.outside, r.outside, r.count)
+            }) {
+                return Err(einval!(format!("{}: overlapped id range", line)));
+            }
+            ranges.push(range);
+        }
+
+        if ranges.is_empty() {
+            return Err(einval!("no id range"));
+        }
+
+        Ok(ranges)
+    }
+
+    fn map(&self, id: u32) -> u32 {
+        match self {
+            // Ids shifted out of range are presented as the overflow id rather than clamped.
+            IdMap::Shift(shift) => match id.checked_add(*shift) {
+                Some(id) if id != u32::MAX => id,
+                _ => OVERFLOW_ID,
+            },
+            IdMap::Ranges(ranges) => ranges
+                .iter()
+                .find(|r| id >= r.inside && id - r.inside < r.count)
+                .map(|r| r.outside + (id - r.inside))
+                .unwrap_or(OVERFLOW_ID),
+        }
+    }
+}
+
+/// Mapper of uid and gid of inodes.
+#[derive(Clone, Debug)]
+pub struct IdMapper {
+    uid: IdMap,
+    gid: IdMap,
+}
+
+impl IdMapper {
+    /// Create a mapper from the configuration, loading map files if given.
+    pub fn new(config: &IdMapConfig) -> RafsResult<Self> {
+        Ok(IdMapper {
+            uid: IdMap::new(config.uid_shift, &config.uid_map)?,
+            gid: IdMap::new(config.gid_shift, &config.gid_map)?,
+        })
+    }
+
+    /// Map uid and gid of an inode.
+    pub fn map(&self, uid: &mut u32, gid: &mut u32) {
+        *uid = self.uid.map(*uid);
+        *gid = self.gid.map(*gid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_id_map_parse() {
+        let ranges = IdMap::parse("# comment\n0 100000 1000\n\n  1000 1000 1\n").unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                IdRange {
+                    inside: 0,
+                    outside: 100000,
+                    count: 1000
+                },
+                IdRange {
+                    inside: 1000,
+                    outside: 1000,
+                    count: 1
+                }
+            ]
+        );
+
+        assert!(IdMap::parse("").is_err());
+        assert!(IdMap::parse("0 100000").is_err());
+        assert!(IdMap::parse("0 100000 0").is_err());
+        assert!(IdMap::parse("0 100000 x").is_err());
+        assert!(IdMap::parse("1 4294967295 2").is_err());
+        assert!(IdMap::parse("0 100000 1000\n999 0 1").is_err());
+        assert!(IdMap::parse("0 4294967295 1").is_err());
+        assert!(IdMap::parse("4294967294 0 1").is_ok());
+        // Overlapped mapped ids.
+        assert!(IdMap::parse("0 100000 1000\n1000 100999 1").is_err());
+        assert!(IdMap::parse("0 100000 1000\n1000 101000 1").is_ok());
+    }
+
+    #[test]
+    fn test_id_mapper() {
+        let mapper = IdMapper::new(&IdMapConfig {
+            uid_shift: 100000,
+            gid_shift: u32::MAX,
+            ..Default::default()
+        })
+        .unwrap();
+        let (mut uid, mut gid) = (1000, 1000);
+        mapper.map(&mut uid, &mut gid);
+        assert_eq!((uid, gid), (101000, OVERFLOW_ID));
+        let (mut uid, mut gid) = (u32::MAX - 100001, 0);
+        mapper.map(&mut uid, &mut gid);
+        assert_eq!((uid, gid), (u32::MAX - 1, OVERFLOW_ID));
+        let (mut uid, mut gid) = (u32::MAX - 100000, 0);
+        mapper.map(&mut uid, &mut gid);
+        assert_eq!(uid, OVERFLOW_ID);
+
+        let tmp = TempFile::new().unwrap();
+        tmp.as_file().write_all(b"0 200000 65536\n").unwrap();
+        let config = IdMapConfig {
+            uid_map: tmp.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let mapper = IdMapper::new(&config).unwrap();
+        let (mut uid, mut gid) = (1, 1);
+        mapper.map(&mut uid, &mut gid);
+        assert_eq!((uid, gid), (200001, 1));
+        let (mut uid, mut gid) = (70000, 0);
+        mapper.map(&mut uid, &mut gid);
+        assert_eq!((uid, gid), (OVERFLOW_ID, 0));
+
+        let config = IdMapConfig {
+            uid_shift: 1,
+            uid_map: tmp.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        assert!(IdMapper::new(&config).is_err());
+    }
+}
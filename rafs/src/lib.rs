@@ -24,6 +24,7 @@
 //! sub modules:
 //! - [fs](fs/index.html): the Rafs core to glue fuse, storage backend and filesystem metadata.
 //! - [metadata](rafs/metadata/index.html): defines and accesses Rafs filesystem metadata.
+//! - [signature](rafs/signature/index.html): signs and verifies Rafs bootstraps.
 //!
 //! For more information, please refer to
 //! [Dragonfly Image Service](https://github.com/dragonflyoss/image-service)
@@ -38,46 +39,59 @@ extern crate nydus_error;
 extern crate storage;
 
 use std::any::Any;
-use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::fs::File;
-use std::io::{BufWriter, Error, Read, Result, Seek, SeekFrom, Write};
-use std::os::unix::io::AsRawFd;
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
 
+use crate::metadata::BootstrapError;
+
 pub mod fs;
+pub mod idmap;
 pub mod metadata;
 #[cfg(test)]
 pub mod mock;
+pub mod scrub;
+pub mod signature;
+pub mod whole_file;
 
 /// Error codes for rafs related operations.
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum RafsError {
+    #[error("operation is not supported")]
     Unsupported,
+    #[error("filesystem is not initialized yet")]
     Uninitialized,
+    #[error("filesystem is already mounted")]
     AlreadyMounted,
+    #[error("failed to read metadata from {1}, {0}")]
     ReadMetadata(Error, String),
+    #[error("failed to load configuration, {0}")]
     LoadConfig(Error),
+    #[error("failed to parse configuration, {0}")]
     ParseConfig(serde_json::Error),
+    #[error("failed to switch storage backend, {0}")]
     SwapBackend(Error),
+    #[error("failed to freeze filesystem, {0}")]
+    Freeze(Error),
+    #[error("failed to load filesystem metadata, {0}")]
     FillSuperblock(Error),
+    #[error("failed to create blob device, {0}")]
     CreateDevice(Error),
+    #[error("failed to prefetch data, {0}")]
     Prefetch(String),
+    #[error("invalid configuration, {0}")]
     Configure(String),
+    #[error("incompatible inode format {0:#x}")]
     Incompatible(u16),
+    #[error("illegal {0:?} metadata, {1}")]
     IllegalMetaStruct(MetaType, String),
-}
-
-impl std::error::Error for RafsError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
-    }
-}
-
-impl Display for RafsError {
-    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        write!(f, "{}", format!("{:?}", self))?;
-        Ok(())
-    }
+    #[error("failed to verify signature of bootstrap, {0}")]
+    VerifySignature(Error),
+    #[error("invalid bootstrap, {0}")]
+    InvalidBootstrap(BootstrapError),
+    #[error("failed to lock bootstrap, {0}")]
+    LockBootstrap(Error),
 }
 
 #[derive(Debug)]
@@ -199,6 +213,39 @@ impl dyn RafsIoRead {
         })
     }
 
+    /// Take a shared advisory lock on the bootstrap, to detect builders still writing it with an
+    /// exclusive lock held.
+    ///
+    /// The lock is held until the returned file, which shares the open file description with the
+    /// reader, is dropped. `None` is returned if the underlying file doesn't support locking.
+    pub fn lock_shared(&self) -> RafsResult<Option<File>> {
+        // Safe because the fd is valid during the call.
+        let fd = unsafe { libc::dup(self.as_raw_fd()) };
+        if fd < 0 {
+            return Err(RafsError::LockBootstrap(last_error!(
+                "failed to duplicate bootstrap fd"
+            )));
+        }
+        // Safe because we own the duplicated fd.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        // Safe because the fd is valid.
+        if unsafe { libc::flock(fd, libc::LOCK_SH | libc::LOCK_NB) } < 0 {
+            let e = Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Err(RafsError::LockBootstrap(eio!(
+                    "bootstrap is being written by others"
+                ))),
+                _ => {
+                    warn!("failed to lock bootstrap, {}", e);
+                    Ok(None)
+                }
+            };
+        }
+
+        Ok(Some(file))
+    }
+
     /// Create a reader from a file path.
     pub fn from_file(path: impl AsRef<Path>) -> RafsResult<RafsIoReader> {
         let f = File::open(&path).map_err(|e| {
@@ -207,6 +254,46 @@ impl dyn RafsIoRead {
 
         Ok(Box::new(f))
     }
+
+    /// Copy bootstrap data read from `r`, such as an in-memory buffer, into a sealed memfd.
+    ///
+    /// The memfd may be used as a reader, or opened as a bootstrap file by `/proc/self/fd/<fd>`,
+    /// so it can be mapped by the direct mode and no temporary file is written to disk. Data
+    /// beyond `max_size` bytes is rejected.
+    pub fn memfd_from_reader<R: Read>(r: R, max_size: u64) -> RafsResult<File> {
+        let err = |e: Error| RafsError::ReadMetadata(e, "memory".to_string());
+        // Safe because the name is a valid C string.
+        let fd = unsafe {
+            libc::memfd_create(
+                b"rafs-bootstrap\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            return Err(err(last_error!("failed to create memfd for bootstrap")));
+        }
+        // Safe because we own the new fd.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let size =
+            std::io::copy(&mut r.take(max_size.saturating_add(1)), &mut file).map_err(err)?;
+        if size > max_size {
+            return Err(err(Error::new(
+                ErrorKind::InvalidData,
+                format!("bootstrap is larger than {} bytes", max_size),
+            )));
+        }
+
+        // The bootstrap mustn't change while being used as filesystem metadata.
+        let seals =
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+        // Safe because the fd is valid.
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(err(last_error!("failed to seal bootstrap memfd")));
+        }
+        file.seek(SeekFrom::Start(0)).map_err(err)?;
+
+        Ok(file)
+    }
 }
 
 #[cfg(test)]
@@ -214,6 +301,62 @@ mod tests {
     use super::*;
     use vmm_sys_util::tempfile::TempFile;
 
+    #[test]
+    fn test_lock_bootstrap() {
+        let file = TempFile::new().unwrap();
+        let reader: RafsIoReader = Box::new(File::open(file.as_path()).unwrap());
+        let lock = reader.lock_shared().unwrap();
+        assert!(lock.is_some());
+        // Shared locks don't conflict with each other.
+        let reader2: RafsIoReader = Box::new(File::open(file.as_path()).unwrap());
+        assert!(reader2.lock_shared().unwrap().is_some());
+        drop(reader2);
+
+        let writer = File::open(file.as_path()).unwrap();
+        // Safe because the fd is valid.
+        let ret = unsafe { libc::flock(writer.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        assert!(ret < 0);
+        drop(lock);
+        drop(reader);
+        let ret = unsafe { libc::flock(writer.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        assert_eq!(ret, 0);
+
+        let reader: RafsIoReader = Box::new(File::open(file.as_path()).unwrap());
+        assert!(matches!(
+            reader.lock_shared(),
+            Err(RafsError::LockBootstrap(_))
+        ));
+    }
+
+    #[test]
+    fn test_reader_from_memory() {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let path =
+            std::path::PathBuf::from(root_dir).join("../tests/texture/bootstrap/image_v2.boot");
+        let data = std::fs::read(&path).unwrap();
+
+        assert!(
+            <dyn RafsIoRead>::memfd_from_reader(data.as_slice(), data.len() as u64 - 1).is_err()
+        );
+        let file = <dyn RafsIoRead>::memfd_from_reader(data.as_slice(), data.len() as u64).unwrap();
+        let mut reader: RafsIoReader = Box::new(file);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        reader.seek_to_offset(0).unwrap();
+        let mut sb = metadata::RafsSuper {
+            mode: metadata::RafsMode::Direct,
+            ..Default::default()
+        };
+        sb.load(&mut reader).unwrap();
+
+        // The bootstrap is sealed.
+        let ret =
+            unsafe { libc::write(reader.as_raw_fd(), buf.as_ptr() as *const libc::c_void, 1) };
+        assert!(ret < 0);
+    }
+
     #[test]
     fn test_rafs_io_writer() {
         let mut file = TempFile::new().unwrap().into_file();
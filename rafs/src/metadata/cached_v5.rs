@@ -9,7 +9,7 @@
 //! file system. And currently the cache layer only supports readonly file systems.
 
 use std::any::Any;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::io::SeekFrom;
 use std::io::{ErrorKind, Read, Result};
@@ -21,7 +21,7 @@ use std::sync::Arc;
 use fuse_backend_rs::abi::linux_abi;
 use fuse_backend_rs::api::filesystem::Entry;
 use nydus_utils::digest::Algorithm;
-use nydus_utils::{digest::RafsDigest, ByteSize};
+use nydus_utils::{digest::RafsDigest, div_round_up, ByteSize};
 use storage::device::v5::BlobV5ChunkInfo;
 use storage::device::{BlobChunkFlags, BlobChunkInfo, BlobInfo};
 
@@ -33,15 +33,60 @@ use crate::metadata::layout::{bytes_to_os_str, parse_xattr, RAFS_ROOT_INODE};
 use crate::metadata::{
     BlobIoVec, ChildInodeHandler, Inode, PostWalkAction, RafsError, RafsInode, RafsResult,
     RafsSuperBlobs, RafsSuperBlock, RafsSuperInodes, RafsSuperMeta, XattrName, XattrValue, DOT,
-    DOTDOT, RAFS_ATTR_BLOCK_SIZE, RAFS_MAX_NAME,
+    DOTDOT, RAFS_ATTR_BLOCK_SIZE, RAFS_MAX_METADATA_SIZE, RAFS_MAX_NAME,
 };
 use crate::RafsIoReader;
 
+/// Index of cached inodes by inode number.
+///
+/// Rafs v5 allocates inode numbers contiguously from the root inode, so inodes are kept in an
+/// array indexed by inode number and looking up an inode is O(1).
+#[derive(Default)]
+struct CachedInodeIndex {
+    inodes: Vec<Option<Arc<CachedInodeV5>>>,
+    count: usize,
+}
+
+impl CachedInodeIndex {
+    fn reserve(&mut self, max_ino: Inode) {
+        if self.inodes.len() <= max_ino as usize {
+            self.inodes.resize(max_ino as usize + 1, None);
+        }
+    }
+
+    fn get(&self, ino: Inode) -> Option<&Arc<CachedInodeV5>> {
+        self.inodes.get(ino as usize)?.as_ref()
+    }
+
+    fn get_mut(&mut self, ino: Inode) -> Option<&mut Arc<CachedInodeV5>> {
+        self.inodes.get_mut(ino as usize)?.as_mut()
+    }
+
+    fn insert(&mut self, ino: Inode, inode: Arc<CachedInodeV5>) {
+        self.reserve(ino);
+        if self.inodes[ino as usize].replace(inode).is_none() {
+            self.count += 1;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn clear(&mut self) {
+        self.inodes.clear();
+        self.count = 0;
+    }
+}
+
+/// Number of inodes loaded between two progress messages.
+const LOAD_PROGRESS_INTERVAL: u32 = 1 << 20;
+
 /// Cached Rafs v5 super block.
 pub struct CachedSuperBlockV5 {
     s_blob: Arc<RafsV5BlobTable>,
     s_meta: Arc<RafsSuperMeta>,
-    s_inodes: BTreeMap<Inode, Arc<CachedInodeV5>>,
+    s_inodes: CachedInodeIndex,
     max_inode: Inode,
     validate_digest: bool,
 }
@@ -52,19 +97,36 @@ impl CachedSuperBlockV5 {
         CachedSuperBlockV5 {
             s_blob: Arc::new(RafsV5BlobTable::new()),
             s_meta: Arc::new(meta),
-            s_inodes: BTreeMap::new(),
+            s_inodes: CachedInodeIndex::default(),
             max_inode: RAFS_ROOT_INODE,
             validate_digest,
         }
     }
 
+    /// Estimate memory needed to cache metadata of a bootstrap of `size` bytes, in unit of Byte.
+    ///
+    /// Names, symlink targets, chunks and extended attributes are cached in structures no larger
+    /// than twice of their on disk size, and each inode takes a fixed size object and an index slot.
+    pub fn estimate_memory(meta: &RafsSuperMeta, size: u64) -> u64 {
+        let per_inode =
+            (size_of::<CachedInodeV5>() + size_of::<Option<Arc<CachedInodeV5>>>()) as u64;
+        (meta.inode_table_entries as u64)
+            .saturating_mul(per_inode)
+            .saturating_add(size.saturating_mul(2))
+    }
+
     /// Load all inodes into memory.
     ///
     /// Rafs v5 layout is based on BFS, which means parents always are in front of children.
     fn load_all_inodes(&mut self, r: &mut RafsIoReader) -> Result<()> {
-        let mut dir_ino_set = Vec::with_capacity(self.s_meta.inode_table_entries as usize);
+        let total = self.s_meta.inode_table_entries;
+        let mut dir_ino_set = Vec::with_capacity(total as usize);
+        self.s_inodes.reserve(total as Inode);
 
-        for _idx in 0..self.s_meta.inode_table_entries {
+        for idx in 0..total {
+            if idx > 0 && idx % LOAD_PROGRESS_INTERVAL == 0 {
+                info!("loaded {}/{} inodes into memory", idx, total);
+            }
             let mut inode = CachedInodeV5::new(self.s_blob.clone(), self.s_meta.clone());
             match inode.load(&self.s_meta, r) {
                 Ok(_) => {
@@ -83,6 +145,10 @@ impl CachedSuperBlockV5 {
                     return Err(e);
                 }
             }
+            // Inode numbers are indexes into the inode table.
+            if inode.ino() > self.s_meta.inode_table_entries as Inode {
+                return Err(einval!(format!("invalid inode number {}", inode.ino())));
+            }
 
             let child_inode = self.hash_inode(Arc::new(inode))?;
             if child_inode.is_dir() {
@@ -92,13 +158,13 @@ impl CachedSuperBlockV5 {
                 // we have to lock inode everywhere for mutability. It really hurts.
                 dir_ino_set.push(child_inode.i_ino);
             } else {
-                self.add_into_parent(child_inode);
+                self.add_into_parent(child_inode)?;
             }
         }
 
         // Add directories to its parent in reverse order.
         for ino in dir_ino_set.iter().rev() {
-            self.add_into_parent(self.get_node(*ino)?);
+            self.add_into_parent(self.get_node(*ino)?)?;
         }
         debug!("all {} inodes loaded", self.s_inodes.len());
 
@@ -106,11 +172,11 @@ impl CachedSuperBlockV5 {
     }
 
     fn get_node(&self, ino: Inode) -> Result<Arc<CachedInodeV5>> {
-        Ok(self.s_inodes.get(&ino).ok_or_else(|| enoent!())?.clone())
+        Ok(self.s_inodes.get(ino).ok_or_else(|| enoent!())?.clone())
     }
 
     fn get_node_mut(&mut self, ino: Inode) -> Result<&mut Arc<CachedInodeV5>> {
-        self.s_inodes.get_mut(&ino).ok_or_else(|| enoent!())
+        self.s_inodes.get_mut(ino).ok_or_else(|| enoent!())
     }
 
     fn hash_inode(&mut self, inode: Arc<CachedInodeV5>) -> Result<Arc<CachedInodeV5>> {
@@ -119,7 +185,7 @@ impl CachedSuperBlockV5 {
         }
 
         if inode.is_hardlink() {
-            if let Some(i) = self.s_inodes.get(&inode.i_ino) {
+            if let Some(i) = self.s_inodes.get(inode.i_ino) {
                 // Keep it as is, directory digest algorithm has dependency on it.
                 if !i.i_data.is_empty() {
                     return Ok(inode);
@@ -131,10 +197,21 @@ impl CachedSuperBlockV5 {
         Ok(inode)
     }
 
-    fn add_into_parent(&mut self, child_inode: Arc<CachedInodeV5>) {
+    fn add_into_parent(&mut self, child_inode: Arc<CachedInodeV5>) -> Result<()> {
         if let Ok(parent_inode) = self.get_node_mut(child_inode.parent()) {
-            Arc::get_mut(parent_inode).unwrap().add_child(child_inode);
+            // The parent may be shared if it's not a directory, as in malformed bootstraps.
+            Arc::get_mut(parent_inode)
+                .ok_or_else(|| {
+                    einval!(format!(
+                        "invalid parent inode {} of inode {}",
+                        child_inode.parent(),
+                        child_inode.ino()
+                    ))
+                })?
+                .add_child(child_inode);
         }
+
+        Ok(())
     }
 }
 
@@ -145,7 +222,7 @@ impl RafsSuperInodes for CachedSuperBlockV5 {
 
     fn get_inode(&self, ino: Inode, _digest_validate: bool) -> Result<Arc<dyn RafsInode>> {
         self.s_inodes
-            .get(&ino)
+            .get(ino)
             .map_or(Err(enoent!()), |i| Ok(i.clone()))
     }
 
@@ -220,6 +297,24 @@ impl RafsSuperBlock for CachedSuperBlockV5 {
     fn root_ino(&self) -> u64 {
         RAFS_ROOT_INODE
     }
+
+    // An estimation of memory used by cached inodes, chunks and xattrs.
+    fn memory_usage(&self) -> u64 {
+        let mut size = self.s_inodes.inodes.capacity() * size_of::<Option<Arc<CachedInodeV5>>>();
+        for inode in self.s_inodes.inodes.iter().flatten() {
+            size += size_of::<CachedInodeV5>() + inode.i_name.len() + inode.i_target.len();
+            size += inode.i_data.len()
+                * (size_of::<Arc<CachedChunkInfoV5>>() + size_of::<CachedChunkInfoV5>());
+            size += inode.i_child.len() * size_of::<Arc<CachedInodeV5>>();
+            size += inode
+                .i_xattr
+                .iter()
+                .map(|(k, v)| k.len() + v.len())
+                .sum::<usize>();
+        }
+
+        size as u64
+    }
 }
 
 /// Cached Rafs v5 inode metadata.
@@ -289,9 +384,16 @@ impl CachedInodeV5 {
             let mut xattrs = RafsV5XAttrsTable::new();
             r.read_exact(xattrs.as_mut())?;
             xattrs.size = u64::from_le(xattrs.size);
+            if xattrs.size > RAFS_MAX_METADATA_SIZE as u64 {
+                return Err(einval!(format!("invalid xattr table size {}", xattrs.size)));
+            }
 
-            let mut xattr_buf = vec![0u8; xattrs.aligned_size()];
-            r.read_exact(xattr_buf.as_mut_slice())?;
+            // Don't trust the size to preallocate buffer, the bootstrap may be truncated.
+            let size = xattrs.aligned_size();
+            let mut xattr_buf = Vec::new();
+            if r.by_ref().take(size as u64).read_to_end(&mut xattr_buf)? != size {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
+            }
             parse_xattr(&xattr_buf, xattrs.size(), |name, value| {
                 self.i_xattr.insert(name.to_os_string(), value);
                 true
@@ -366,7 +468,7 @@ impl RafsInode for CachedInodeV5 {
             return Err(einval!("invalid inode"));
         }
         if self.is_reg() {
-            let chunks = (self.i_size + chunk_size - 1) / chunk_size;
+            let chunks = div_round_up(self.i_size, chunk_size);
             if !self.has_hole() && chunks != self.i_data.len() as u64 {
                 return Err(einval!("invalid chunk count"));
             }
@@ -27,13 +27,15 @@ use std::ops::Deref;
 use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
 use std::slice;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use arc_swap::{ArcSwap, Guard};
 use nydus_utils::digest::{Algorithm, RafsDigest};
+use nydus_utils::div_round_up;
 use storage::device::v5::BlobV5ChunkInfo;
 use storage::device::{BlobChunkFlags, BlobChunkInfo, BlobInfo, BlobIoVec};
-use storage::utils::readahead;
+use storage::utils::{readahead, release_pages, resident_size};
 
 use crate::metadata::layout::v5::{
     rafsv5_align, rafsv5_alloc_bio_vecs, rafsv5_validate_digest, RafsV5BlobTable, RafsV5ChunkInfo,
@@ -45,9 +47,9 @@ use crate::metadata::layout::{
     RAFS_ROOT_INODE,
 };
 use crate::metadata::{
-    Attr, ChildInodeHandler, Entry, Inode, PostWalkAction, RafsInode, RafsSuperBlobs,
-    RafsSuperBlock, RafsSuperInodes, RafsSuperMeta, DOT, DOTDOT, RAFS_ATTR_BLOCK_SIZE,
-    RAFS_MAX_METADATA_SIZE, RAFS_MAX_NAME,
+    Attr, BootstrapMapping, ChildInodeHandler, Entry, Inode, PostWalkAction, RafsInode,
+    RafsSuperBlobs, RafsSuperBlock, RafsSuperInodes, RafsSuperMeta, DOT, DOTDOT,
+    RAFS_ATTR_BLOCK_SIZE, RAFS_MAX_METADATA_SIZE, RAFS_MAX_NAME,
 };
 use crate::{RafsError, RafsIoReader, RafsResult};
 
@@ -89,6 +91,30 @@ macro_rules! impl_chunkinfo_getter {
     };
 }
 
+/// Bitmap of inodes which have been validated, so they aren't validated again on each access.
+#[derive(Default)]
+struct ValidatedInodes(Vec<AtomicU64>);
+
+impl ValidatedInodes {
+    fn new(max_ino: Inode) -> Self {
+        let words = (max_ino as usize >> 6) + 1;
+        ValidatedInodes((0..words).map(|_| AtomicU64::new(0)).collect())
+    }
+
+    fn contains(&self, ino: Inode) -> bool {
+        self.0
+            .get(ino as usize >> 6)
+            .map(|v| v.load(Ordering::Relaxed) & (1 << (ino & 0x3f)) != 0)
+            .unwrap_or(false)
+    }
+
+    fn insert(&self, ino: Inode) {
+        if let Some(v) = self.0.get(ino as usize >> 6) {
+            v.fetch_or(1 << (ino & 0x3f), Ordering::Relaxed);
+        }
+    }
+}
+
 /// The underlying struct to maintain memory mapped bootstrap for a file system.
 ///
 /// Only the DirectMappingState may store raw pointers.
@@ -102,10 +128,14 @@ struct DirectMappingState {
     blob_table: Arc<RafsV5BlobTable>,
     base: *const u8,
     end: *const u8,
+    // Size of the mapping, which may be bigger than the bootstrap file.
     size: usize,
+    // Whether the bootstrap is copied into anonymous memory, which can't be released.
+    copied: bool,
     fd: RawFd,
     mmapped_inode_table: bool,
     validate_digest: bool,
+    validated_inodes: Arc<ValidatedInodes>,
 }
 
 impl DirectMappingState {
@@ -118,8 +148,10 @@ impl DirectMappingState {
             base: std::ptr::null(),
             end: std::ptr::null(),
             size: 0,
+            copied: false,
             mmapped_inode_table: false,
             validate_digest,
+            validated_inodes: Arc::new(ValidatedInodes::default()),
         }
     }
 
@@ -142,14 +174,7 @@ impl DirectMappingState {
 
     #[inline]
     fn validate_range(&self, offset: usize, size: usize) -> Result<()> {
-        let start = self.base.wrapping_add(offset);
-        let end = start.wrapping_add(size);
-
-        if start > end || start < self.base || end < self.base || end > self.end {
-            return Err(einval!("invalid range"));
-        }
-
-        Ok(())
+        BootstrapMapping::validate_range(self.base, self.end, offset, size)
     }
 }
 
@@ -162,7 +187,7 @@ impl Drop for DirectMappingState {
             }
         }
         if !self.base.is_null() {
-            unsafe { libc::munmap(self.base as *mut u8 as *mut libc::c_void, self.size) };
+            BootstrapMapping::unmap(self.base, self.size);
             self.base = std::ptr::null();
             self.end = std::ptr::null();
             self.size = 0;
@@ -201,14 +226,17 @@ impl DirectSuperBlockV5 {
         state: &DirectMappingState,
     ) -> Result<OndiskInodeWrapper> {
         let offset = state.inode_table.get(ino)? as usize;
-        let _inode = state.cast_to_ref::<RafsV5Inode>(state.base, offset)?;
         let wrapper = OndiskInodeWrapper {
             mapping: self.clone(),
             offset,
         };
 
-        // TODO: use bitmap to record validation result.
-        wrapper.validate(state.meta.inodes_count, state.meta.chunk_size as u64)?;
+        // Inodes are validated on first access only, so looking up an inode is O(1) array math.
+        if !state.validated_inodes.contains(ino) {
+            let _inode = state.cast_to_ref::<RafsV5Inode>(state.base, offset)?;
+            wrapper.validate(state.meta.inodes_count, state.meta.chunk_size as u64)?;
+            state.validated_inodes.insert(ino);
+        }
 
         Ok(wrapper)
     }
@@ -275,22 +303,8 @@ impl DirectSuperBlockV5 {
         readahead(fd, 0, len);
 
         // Mmap the bootstrap file into current process for direct access
-        let base = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                size,
-                libc::PROT_READ,
-                libc::MAP_NORESERVE | libc::MAP_PRIVATE,
-                fd,
-                0,
-            )
-        } as *const u8;
-        if base as *mut core::ffi::c_void == libc::MAP_FAILED {
-            return Err(last_error!("failed to mmap bootstrap"));
-        }
-        if base.is_null() {
-            return Err(ebadf!("failed to mmap bootstrap"));
-        }
+        let mapping = BootstrapMapping::new(fd, size)?;
+        let base = mapping.base;
         // Safe because the mmap area should covered the range [start, end)
         let end = unsafe { base.add(size) };
 
@@ -330,9 +344,13 @@ impl DirectSuperBlockV5 {
             fd: file.into_raw_fd(),
             base,
             end,
-            size,
+            size: mapping.size,
+            copied: mapping.copied,
             mmapped_inode_table: true,
             validate_digest,
+            validated_inodes: Arc::new(ValidatedInodes::new(
+                old_state.meta.inode_table_entries as Inode,
+            )),
         };
 
         // Swap new and old DirectMappingState object, the old object will be destroyed when the
@@ -404,6 +422,18 @@ impl RafsSuperBlock for DirectSuperBlockV5 {
     fn root_ino(&self) -> u64 {
         RAFS_ROOT_INODE
     }
+
+    fn memory_usage(&self) -> u64 {
+        let state = self.state.load();
+        resident_size(state.base, state.size)
+    }
+
+    fn shrink(&self) {
+        let state = self.state.load();
+        if !state.copied {
+            release_pages(state.base, state.size)
+        }
+    }
 }
 
 pub struct OndiskInodeWrapper {
@@ -444,10 +474,12 @@ impl OndiskInodeWrapper {
         if inode.has_xattr() {
             let offset = self.offset + inode.size();
             state.validate_range(offset, size_of::<RafsV5XAttrsTable>())?;
-            unsafe {
-                let xattrs = state.base.add(offset) as *const RafsV5XAttrsTable;
-                Ok(size_of::<RafsV5XAttrsTable>() + (*xattrs).aligned_size())
+            let xattrs = unsafe { &*(state.base.add(offset) as *const RafsV5XAttrsTable) };
+            // Avoid overflow when aligning the size, it's validated against the mapping later.
+            if xattrs.size() > state.size {
+                return Err(einval!("invalid xattr table size"));
             }
+            Ok(size_of::<RafsV5XAttrsTable>() + xattrs.aligned_size())
         } else {
             Ok(0)
         }
@@ -535,7 +567,7 @@ impl RafsInode for OndiskInodeWrapper {
         };
 
         if inode.is_reg() {
-            let chunks = (inode.i_size + chunk_size - 1) / chunk_size;
+            let chunks = div_round_up(inode.i_size, chunk_size);
             if !inode.has_hole() && chunks != inode.i_child_count as u64 {
                 return Err(einval!(format!(
                     "invalid chunk count, ino {}, expected {}, actual {}",
@@ -554,10 +586,11 @@ impl RafsInode for OndiskInodeWrapper {
             }
             let size = inode.size() + xattr_size;
             state.validate_range(self.offset, size)?;
-        } else if inode.is_symlink() {
-            if inode.i_symlink_size == 0 {
+        } else {
+            if inode.is_symlink() && inode.i_symlink_size == 0 {
                 return Err(einval!("invalid symlink target"));
             }
+            state.validate_range(self.offset, inode.size() + xattr_size)?;
         }
         if !inode.is_hardlink() && inode.i_parent >= inode.i_ino {
             return Err(einval!("invalid parent inode"));
@@ -679,7 +712,8 @@ impl RafsInode for OndiskInodeWrapper {
             return Err(enoent!("invalid child index"));
         }
 
-        self.mapping.get_inode((idx + child_index) as Inode, false)
+        self.mapping
+            .get_inode(idx as Inode + child_index as Inode, false)
     }
 
     #[inline]
@@ -808,7 +842,7 @@ impl RafsInode for OndiskInodeWrapper {
         let mut child_dirs: Vec<Arc<dyn RafsInode>> = Vec::new();
 
         for idx in child_index..(child_index + child_count) {
-            let child_inode = self.mapping.get_inode(idx, false).unwrap();
+            let child_inode = self.mapping.get_inode(idx, false)?;
             if child_inode.is_dir() {
                 trace!("Got dir {:?}", child_inode.name());
                 child_dirs.push(child_inode);
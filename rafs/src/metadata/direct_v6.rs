@@ -44,8 +44,8 @@ use crate::metadata::{
         XattrName, XattrValue,
     },
     {
-        Attr, ChildInodeHandler, Entry, Inode, PostWalkAction, RafsInode, RafsSuperBlobs,
-        RafsSuperBlock, RafsSuperInodes, RafsSuperMeta, RAFS_ATTR_BLOCK_SIZE,
+        Attr, BootstrapMapping, ChildInodeHandler, Entry, Inode, PostWalkAction, RafsInode,
+        RafsSuperBlobs, RafsSuperBlock, RafsSuperInodes, RafsSuperMeta, RAFS_ATTR_BLOCK_SIZE,
     },
 };
 use crate::{MetaType, RafsError, RafsIoReader, RafsResult};
@@ -54,7 +54,7 @@ use nydus_utils::{
     div_round_up, round_up,
 };
 use storage::device::{BlobChunkInfo, BlobInfo, BlobIoChunk, BlobIoDesc, BlobIoVec};
-use storage::utils::readahead;
+use storage::utils::{readahead, release_pages, resident_size};
 
 // Safe to Send/Sync because the underlying data structures are readonly
 unsafe impl Send for DirectSuperBlockV6 {}
@@ -76,7 +76,10 @@ struct DirectMappingState {
     blob_table: Arc<RafsV6BlobTable>,
     base: *const u8,
     end: *const u8,
+    // Size of the mapping, which may be bigger than the bootstrap file.
     size: usize,
+    // Whether the bootstrap is copied into anonymous memory, which can't be released.
+    copied: bool,
     fd: RawFd,
     validate_digest: bool,
 }
@@ -90,44 +93,22 @@ impl DirectMappingState {
             base: std::ptr::null(),
             end: std::ptr::null(),
             size: 0,
+            copied: false,
             // mmapped_inode_table: false,
             validate_digest,
         }
     }
 
-    // /// Mmap to bootstrap ondisk data directly.
-    // fn cast_to_ref<T>(&self, base: *const u8, offset: usize) -> Result<&T> {
-    //     let start = base.wrapping_add(offset);
-    //     let end = start.wrapping_add(size_of::<T>());
-
-    //     if start > end
-    //         || start < self.base
-    //         || end < self.base
-    //         || end > self.end
-    //         || start as usize & (std::mem::align_of::<T>() - 1) != 0
-    //     {
-    //         return Err(einval!("invalid mmap offset"));
-    //     }
-
-    //     Ok(unsafe { &*(start as *const T) })
-    // }
-
-    // #[inline]
-    // fn validate_range(&self, offset: usize, size: usize) -> Result<()> {
-    //     let start = self.base.wrapping_add(offset);
-    //     let end = start.wrapping_add(size);
-
-    //     if start > end || start < self.base || end < self.base || end > self.end {
-    //         return Err(einval!("invalid range"));
-    //     }
-
-    //     Ok(())
-    // }
+    #[inline]
+    fn validate_range(&self, offset: usize, size: usize) -> Result<()> {
+        BootstrapMapping::validate_range(self.base, self.end, offset, size)
+    }
 }
+
 impl Drop for DirectMappingState {
     fn drop(&mut self) {
         if !self.base.is_null() {
-            unsafe { libc::munmap(self.base as *mut u8 as *mut libc::c_void, self.size) };
+            BootstrapMapping::unmap(self.base, self.size);
             self.base = std::ptr::null();
             self.end = std::ptr::null();
             self.size = 0;
@@ -167,20 +148,30 @@ impl DirectSuperBlockV6 {
     }
 
     pub fn inode_wrapper(&self, nid: u64) -> Result<Arc<OndiskInodeWrapper>> {
-        // TODO(chge): ensure safety
-        let offset = self.calculate_inode_offset(nid) as usize;
+        let state = self.state.load();
+        let offset = self
+            .calculate_inode_offset(nid)
+            .ok_or_else(|| einval!(format!("invalid inode nid {}", nid)))?;
+        // The compact inode is a prefix of the extended inode, so it's safe to check the format.
+        state.validate_range(offset, size_of::<RafsV6InodeCompact>())?;
         let inode = self.disk_inode(offset);
         let blocks_count = div_round_up(inode.size(), EROFS_BLOCK_SIZE);
-        Ok(Arc::new(OndiskInodeWrapper {
+        let wrapper = OndiskInodeWrapper {
             mapping: self.clone(),
             offset,
             blocks_count,
-        }))
+        };
+
+        wrapper.validate(state.meta.inodes_count, state.meta.chunk_size as u64)?;
+
+        Ok(Arc::new(wrapper))
     }
 
-    fn calculate_inode_offset(&self, nid: u64) -> u64 {
+    fn calculate_inode_offset(&self, nid: u64) -> Option<usize> {
         let meta_offset = self.state.load().meta.meta_blkaddr as u64 * EROFS_BLOCK_SIZE;
-        meta_offset + nid * EROFS_INODE_SLOT_SIZE as u64
+        nid.checked_mul(EROFS_INODE_SLOT_SIZE as u64)
+            .and_then(|v| v.checked_add(meta_offset))
+            .map(|v| v as usize)
     }
 
     #[allow(clippy::cast_ptr_alignment)]
@@ -213,22 +204,8 @@ impl DirectSuperBlockV6 {
         readahead(fd, 0, len);
 
         // Mmap the bootstrap file into current process for direct access
-        let base = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                size,
-                libc::PROT_READ,
-                libc::MAP_NORESERVE | libc::MAP_PRIVATE,
-                fd,
-                0,
-            )
-        } as *const u8;
-        if base as *mut core::ffi::c_void == libc::MAP_FAILED {
-            return Err(last_error!("failed to mmap bootstrap"));
-        }
-        if base.is_null() {
-            return Err(ebadf!("failed to mmap bootstrap"));
-        }
+        let mapping = BootstrapMapping::new(fd, size)?;
+        let base = mapping.base;
         // Safe because the mmap area should covered the range [start, end)
         let end = unsafe { base.add(size) };
 
@@ -248,7 +225,8 @@ impl DirectSuperBlockV6 {
             fd: file.into_raw_fd(),
             base,
             end,
-            size,
+            size: mapping.size,
+            copied: mapping.copied,
             validate_digest,
         };
 
@@ -311,6 +289,18 @@ impl RafsSuperBlock for DirectSuperBlockV6 {
     fn root_ino(&self) -> u64 {
         self.state.load().meta.root_nid as u64
     }
+
+    fn memory_usage(&self) -> u64 {
+        let state = self.state.load();
+        resident_size(state.base, state.size)
+    }
+
+    fn shrink(&self) {
+        let state = self.state.load();
+        if !state.copied {
+            release_pages(state.base, state.size)
+        }
+    }
 }
 
 pub struct OndiskInodeWrapper {
@@ -388,16 +378,26 @@ impl OndiskInodeWrapper {
                     }
                 }
             }
-            _ => {
-                panic!("layout is {}", layout)
-            }
+            _ => return Err(RafsError::Incompatible(inode.format())),
         };
 
         Ok(r)
     }
 
+    // Size of the data block `index`, the last block may be partial.
+    fn block_size(&self, index: usize) -> usize {
+        let offset = index as u64 * EROFS_BLOCK_SIZE;
+        std::cmp::min(self.size().saturating_sub(offset), EROFS_BLOCK_SIZE) as usize
+    }
+
     fn get_entry(&self, block_index: usize, index: usize) -> RafsResult<&RafsV6Dirent> {
-        // TODO: We indeed need safety check here.
+        // Data blocks have been validated by `validate()`, entries must be within the block.
+        if size_of::<RafsV6Dirent>() * (index + 1) > self.block_size(block_index) {
+            return Err(RafsError::IllegalMetaStruct(
+                MetaType::Dir,
+                format!("entry {} of block {} out of range", index, block_index),
+            ));
+        }
         let block_mapping = self.data_block_mapping(block_index)?;
         Ok(unsafe {
             &*(block_mapping.add(size_of::<RafsV6Dirent>() * index) as *const RafsV6Dirent)
@@ -413,11 +413,13 @@ impl OndiskInodeWrapper {
         max_entries: usize,
     ) -> RafsResult<&OsStr> {
         let block_mapping = self.data_block_mapping(block_index)?;
+        let block_size = self.block_size(block_index);
         let de = self.get_entry(block_index, index)?;
-        if index < max_entries - 1 {
+        let name_offset = de.e_nameoff as usize;
+        let len = if index + 1 < max_entries {
             let next_de = self.get_entry(block_index, index + 1)?;
             let (next_de_name_off, de_name_off) = (next_de.e_nameoff, de.e_nameoff);
-            let len = next_de.e_nameoff.checked_sub(de.e_nameoff).ok_or_else(|| {
+            next_de.e_nameoff.checked_sub(de.e_nameoff).ok_or_else(|| {
                 error!(
                     "nid {} entry index {} block index {} next dir entry {:?} current dir entry {:?}",
                     self.ino(), index, block_index, next_de, de
@@ -426,43 +428,30 @@ impl OndiskInodeWrapper {
                     MetaType::Dir,
                     format!("cur {} next {}", next_de_name_off, de_name_off),
                 )
-            })?;
-
-            let n = unsafe {
-                bytes_to_os_str(std::slice::from_raw_parts(
-                    block_mapping.add(de.e_nameoff as usize),
-                    len as usize,
-                ))
-            };
+            })? as usize
+        } else {
+            // Name of the last entry ends at the first NUL or the end of the block.
+            block_size.saturating_sub(name_offset)
+        };
+        if name_offset + len > block_size {
+            return Err(RafsError::IllegalMetaStruct(
+                MetaType::Dir,
+                format!(
+                    "name of entry {} of block {} out of range",
+                    index, block_index
+                ),
+            ));
+        }
 
-            Ok(n)
+        let name = unsafe { slice::from_raw_parts(block_mapping.add(name_offset), len) };
+        let name = if index + 1 < max_entries {
+            name
         } else {
-            unsafe {
-                let head_de = self.get_entry(block_index, 0)?;
-                let s = (de.e_nameoff - head_de.e_nameoff) as u64
-                    + (size_of::<RafsV6Dirent>() * max_entries) as u64;
-
-                let e = slice::from_raw_parts(
-                    block_mapping.add(de.e_nameoff as usize),
-                    (self.size() % EROFS_BLOCK_SIZE - s) as usize,
-                );
-                // Use this trick to temporarily decide entry name's length. Improve this?
-                let mut l: usize = 0;
-                for i in e {
-                    if *i != 0 {
-                        l += 1;
-                        if self.size() % EROFS_BLOCK_SIZE - s == l as u64 {
-                            break;
-                        }
-                    } else {
-                        break;
-                    }
-                }
+            let l = name.iter().position(|c| *c == 0).unwrap_or(len);
+            &name[..l]
+        };
 
-                let n = bytes_to_os_str(&e[0..l]);
-                Ok(n)
-            }
-        }
+        Ok(bytes_to_os_str(name))
     }
 
     fn mode_format_bits(&self) -> u32 {
@@ -476,23 +465,24 @@ impl OndiskInodeWrapper {
         content_offset: u32,
         content_len: u32,
         user_io: bool,
-    ) -> BlobIoDesc {
+    ) -> Result<BlobIoDesc> {
         let state = self.mapping.state.load();
-        let blob_table = &state.blob_table.entries;
 
-        let blob_index = chunk_addr.blob_index() - 1;
+        // Blob indexes in chunk addresses start from 1.
+        let blob_index = (chunk_addr.blob_index() as u32)
+            .checked_sub(1)
+            .ok_or_else(|| einval!("invalid blob index 0 of chunk"))?;
         let chunk_index = chunk_addr.blob_comp_index();
-        let io_chunk = BlobIoChunk::Address(blob_index as u32, chunk_index);
+        let io_chunk = BlobIoChunk::Address(blob_index, chunk_index);
+        let blob = state.blob_table.get(blob_index)?;
 
-        let blob = blob_table[blob_index as usize].clone();
-
-        BlobIoDesc::new(
+        Ok(BlobIoDesc::new(
             blob,
             io_chunk,
             content_offset,
             content_len as usize,
             user_io,
-        )
+        ))
     }
 
     fn chunk_size(&self) -> u32 {
@@ -522,11 +512,20 @@ impl OndiskInodeWrapper {
 
     fn chunk_addresses(&self, head_chunk_index: u32) -> RafsResult<&[RafsV6InodeChunkAddr]> {
         let total_chunk_addresses = div_round_up(self.size(), self.chunk_size() as u64) as u32;
+        let format = self.disk_inode().format();
 
-        assert_eq!(
-            self.disk_inode().format() >> EROFS_I_VERSION_BITS,
-            EROFS_INODE_CHUNK_BASED
-        );
+        // Chunk addresses have been validated by `validate()`.
+        if format >> EROFS_I_VERSION_BITS != EROFS_INODE_CHUNK_BASED {
+            return Err(RafsError::Incompatible(format));
+        }
+        let count = total_chunk_addresses
+            .checked_sub(head_chunk_index)
+            .ok_or_else(|| {
+                RafsError::IllegalMetaStruct(
+                    MetaType::Regular,
+                    format!("chunk index {} out of range", head_chunk_index),
+                )
+            })?;
 
         let m = self.mapping.state.load();
         let indices = unsafe {
@@ -543,7 +542,7 @@ impl OndiskInodeWrapper {
             std::slice::from_raw_parts(
                 indices.add(head_chunk_index as usize * size_of::<RafsV6InodeChunkAddr>())
                     as *const RafsV6InodeChunkAddr,
-                (total_chunk_addresses - head_chunk_index) as usize,
+                count as usize,
             )
         };
 
@@ -554,9 +553,49 @@ impl OndiskInodeWrapper {
 // TODO(chge): Still work on this trait implementation. Remove below `allow` attribute.
 #[allow(unused_variables)]
 impl RafsInode for OndiskInodeWrapper {
-    #[allow(clippy::collapsible_if)]
     fn validate(&self, _inode_count: u64, chunk_size: u64) -> Result<()> {
-        todo!()
+        let state = self.mapping.state.load();
+        let inode = self.disk_inode();
+        let inode_size = self.this_inode_size() + self.xattr_size() as usize;
+        state.validate_range(self.offset, inode_size)?;
+
+        if (inode.format() & (!(((1 << EROFS_I_DATALAYOUT_BITS) - 1) << 1 | EROFS_I_VERSION_BITS)))
+            != 0
+        {
+            return Err(err_invalidate_data(RafsError::Incompatible(inode.format())));
+        }
+
+        let size = inode.size();
+        match inode.format() >> EROFS_I_VERSION_BITS {
+            EROFS_INODE_FLAT_PLAIN => {
+                let offset = inode.union() as u64 * EROFS_BLOCK_SIZE;
+                state.validate_range(offset as usize, size as usize)?;
+            }
+            EROFS_INODE_FLAT_INLINE => {
+                let offset = inode.union() as u64 * EROFS_BLOCK_SIZE;
+                let head = self.blocks_count().saturating_sub(1) * EROFS_BLOCK_SIZE;
+                state.validate_range(offset as usize, head as usize)?;
+                state.validate_range(self.offset + inode_size, (size - head) as usize)?;
+            }
+            EROFS_INODE_CHUNK_BASED => {
+                if !chunk_size.is_power_of_two() {
+                    return Err(einval!(format!("invalid chunk size {}", chunk_size)));
+                }
+                let offset =
+                    round_up(inode_size as u64, size_of::<RafsV6InodeChunkAddr>() as u64) as usize;
+                let count = div_round_up(size, chunk_size) as usize;
+                let len = count
+                    .checked_mul(size_of::<RafsV6InodeChunkAddr>())
+                    .ok_or_else(|| einval!("invalid chunk count"))?;
+                state.validate_range(self.offset + offset, len)?;
+            }
+            layout => return Err(einval!(format!("unsupported inode layout {}", layout))),
+        }
+        if self.is_symlink() && (size == 0 || size > EROFS_BLOCK_SIZE) {
+            return Err(einval!("invalid symlink target"));
+        }
+
+        Ok(())
     }
 
     fn get_entry(&self) -> Entry {
@@ -603,7 +642,8 @@ impl RafsInode for OndiskInodeWrapper {
     /// # Safety
     /// It depends on Self::validate() to ensure valid memory layout.
     fn get_symlink(&self) -> Result<OsString> {
-        // FIXME: assume that symlink can't be that long, tail packing is ignored
+        // Symlink targets are shorter than a block, stored inline after the inode, or in a block
+        // of the metadata blob by older builders. Either way no data blob is touched.
         let inode = self.disk_inode();
         let data = self.data_block_mapping(0).map_err(err_invalidate_data)?;
         let s = unsafe {
@@ -770,7 +810,15 @@ impl RafsInode for OndiskInodeWrapper {
         while size as usize
             <= total as usize * size_of::<RafsV6XattrEntry>() - size_of::<RafsV6XattrIbodyHeader>()
         {
+            m.validate_range(
+                cur as usize - m.base as usize,
+                size_of::<RafsV6XattrEntry>(),
+            )?;
             let e = unsafe { &*(cur as *const RafsV6XattrEntry) };
+            m.validate_range(
+                cur as usize - m.base as usize,
+                size_of::<RafsV6XattrEntry>() + e.name_len() as usize + e.value_size() as usize,
+            )?;
 
             let mut xa_name = recover_namespace(e.name_index())?;
 
@@ -821,7 +869,15 @@ impl RafsInode for OndiskInodeWrapper {
         while size as usize
             <= total as usize * size_of::<RafsV6XattrEntry>() - size_of::<RafsV6XattrIbodyHeader>()
         {
+            m.validate_range(
+                cur as usize - m.base as usize,
+                size_of::<RafsV6XattrEntry>(),
+            )?;
             let e = unsafe { &*(cur as *const RafsV6XattrEntry) };
+            m.validate_range(
+                cur as usize - m.base as usize,
+                size_of::<RafsV6XattrEntry>() + e.name_len() as usize + e.value_size() as usize,
+            )?;
 
             let ns = recover_namespace(e.name_index())?;
             let mut xa = ns.into_vec();
@@ -939,7 +995,7 @@ impl RafsInode for OndiskInodeWrapper {
 
         // Safe to unwrap because chunks is not empty to reach here.
         let first_chunk_addr = chunks.first().unwrap();
-        let desc = self.make_chunk_io(first_chunk_addr, content_offset, content_len, user_io);
+        let desc = self.make_chunk_io(first_chunk_addr, content_offset, content_len, user_io)?;
 
         let mut descs = BlobIoVec::new();
         descs.bi_vec.push(desc);
@@ -950,9 +1006,10 @@ impl RafsInode for OndiskInodeWrapper {
             // Handle the rest of chunks since they shares the same content length = 0.
             for c in chunks.iter().skip(1) {
                 content_len = std::cmp::min(chunk_size, left);
-                let desc = self.make_chunk_io(c, 0, content_len, user_io);
+                let desc = self.make_chunk_io(c, 0, content_len, user_io)?;
 
-                if c.blob_index() as u32 != descs.bi_vec[0].blob.blob_index() {
+                // Split chunks by blobs, one blob IO vector for each blob.
+                if desc.blob.blob_index() != descs.bi_vec[0].blob.blob_index() {
                     vec.push(descs);
                     descs = BlobIoVec::new();
                 }
@@ -5,7 +5,7 @@
 
 //! Rafs filesystem metadata layout and data structures.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::ffi::{OsStr, OsString};
 use std::io::Result;
@@ -205,17 +205,18 @@ pub fn parse_xattr_value(data: &[u8], size: usize, name: &OsStr) -> Result<Optio
 
 /// Rafs inode extended attributes.
 ///
-/// An extended attribute is a (String, String) pair associated with a inode.
-#[derive(Clone, Default)]
+/// An extended attribute is a (String, String) pair associated with a inode. Pairs are sorted
+/// by name, so they are always stored in the same order to generate reproducible images.
+#[derive(Clone, Default, PartialEq)]
 pub struct RafsXAttrs {
-    pairs: HashMap<OsString, XattrValue>,
+    pairs: BTreeMap<OsString, XattrValue>,
 }
 
 impl RafsXAttrs {
     /// Create a new instance of `RafsV5Xattrs`.
     pub fn new() -> Self {
         Self {
-            pairs: HashMap::new(),
+            pairs: BTreeMap::new(),
         }
     }
 
@@ -250,6 +251,11 @@ impl RafsXAttrs {
     pub fn is_empty(&self) -> bool {
         self.pairs.is_empty()
     }
+
+    /// Get an iterator over all extended attributes.
+    pub fn iter(&self) -> impl Iterator<Item = (&OsString, &XattrValue)> {
+        self.pairs.iter()
+    }
 }
 
 pub(crate) struct MetaRange {
@@ -365,6 +371,17 @@ mod tests {
         assert_eq!(value, Some(vec![b'b']));
     }
 
+    #[test]
+    fn test_xattrs_order() {
+        let mut xattrs = RafsXAttrs::new();
+        xattrs.add(OsString::from("user.c"), vec![b'c']);
+        xattrs.add(OsString::from("security.a"), vec![b'a']);
+        xattrs.add(OsString::from("user.b"), vec![b'b']);
+
+        let names: Vec<&OsString> = xattrs.iter().map(|(k, _)| k).collect();
+        assert_eq!(names, vec!["security.a", "user.b", "user.c"]);
+    }
+
     #[test]
     fn test_meta_range() {
         assert!(MetaRange::new(u64::MAX, 1, true).is_err());
@@ -44,8 +44,8 @@ use std::sync::Arc;
 
 use nydus_utils::digest::{self, DigestHasher, RafsDigest};
 use nydus_utils::ByteSize;
-use storage::compress;
 use storage::device::{BlobFeatures, BlobIoDesc, BlobIoVec};
+use storage::{compress, crypt};
 
 use crate::metadata::layout::{bytes_to_os_str, MetaRange, RafsXAttrs, RAFS_SUPER_VERSION_V5};
 use crate::metadata::{
@@ -65,8 +65,10 @@ pub(crate) const RAFSV5_SUPERBLOCK_SIZE: usize = 8192;
 pub(crate) const RAFSV5_EXT_BLOB_ENTRY_SIZE: usize = 64;
 
 const RAFSV5_SUPER_MAGIC: u32 = 0x5241_4653;
-const RAFSV5_SUPERBLOCK_RESERVED_SIZE: usize = RAFSV5_SUPERBLOCK_SIZE - 80;
-const RAFSV5_EXT_BLOB_RESERVED_SIZE: usize = RAFSV5_EXT_BLOB_ENTRY_SIZE - 24;
+const RAFSV5_SUPERBLOCK_RESERVED_SIZE: usize = RAFSV5_SUPERBLOCK_SIZE - 120;
+// Size of buffer to read the metadata blob when calculating its digest.
+const RAFSV5_META_DIGEST_BUF_SIZE: usize = 0x10_0000;
+const RAFSV5_EXT_BLOB_RESERVED_SIZE: usize = RAFSV5_EXT_BLOB_ENTRY_SIZE - 60;
 
 /// Trait to get information about a Rafs v5 inode.
 pub(crate) trait RafsV5InodeOps {
@@ -160,7 +162,11 @@ pub struct RafsV5SuperBlock {
     s_blob_table_size: u32,
     s_extended_blob_table_entries: u32, // 72 bytes
     /// Extended Blob Table
-    s_extended_blob_table_offset: u64, // 80 bytes --- reduce me from `RAFS_SUPERBLOCK_RESERVED_SIZE`
+    s_extended_blob_table_offset: u64, // 80 bytes
+    /// Size of the whole metadata blob, zero if the metadata digest is not recorded.
+    s_meta_size: u64,
+    /// Digest of the whole metadata blob, with this field zeroed.
+    s_meta_digest: [u8; 32], // 120 bytes --- reduce me from `RAFS_SUPERBLOCK_RESERVED_SIZE`
     /// Unused area
     s_reserved: [u8; RAFSV5_SUPERBLOCK_RESERVED_SIZE],
 }
@@ -288,6 +294,11 @@ impl RafsV5SuperBlock {
         self.s_flags |= RafsSuperFlags::HAS_XATTR.bits();
     }
 
+    /// Mark that data chunks of some blobs are encrypted.
+    pub fn set_encrypted(&mut self) {
+        self.s_flags |= RafsSuperFlags::ENCRYPTED.bits();
+    }
+
     impl_pub_getter_setter!(magic, set_magic, s_magic, u32);
     impl_pub_getter_setter!(version, set_version, s_fs_version, u32);
     impl_pub_getter_setter!(sb_size, set_sb_size, s_sb_size, u32);
@@ -337,6 +348,73 @@ impl RafsV5SuperBlock {
         s_extended_blob_table_entries,
         u32
     );
+    impl_pub_getter_setter!(meta_size, set_meta_size, s_meta_size, u64);
+
+    /// Get digest of the whole metadata blob recorded in the super block.
+    pub fn meta_digest(&self) -> RafsDigest {
+        RafsDigest::from(self.s_meta_digest)
+    }
+
+    /// Record digest of the whole metadata blob, to detect corruption when loading it.
+    ///
+    /// The size of the metadata blob should be set before calculating the digest.
+    pub fn set_meta_digest(&mut self, digest: RafsDigest) {
+        self.s_meta_digest = digest.data;
+    }
+
+    /// Calculate digest of the metadata blob of `meta_size` bytes read from `r`, with the digest
+    /// field in the super block zeroed.
+    pub fn calculate_meta_digest(
+        &self,
+        r: &mut RafsIoReader,
+        meta_size: u64,
+    ) -> Result<RafsDigest> {
+        let digester = digest::Algorithm::from(RafsSuperFlags::from_bits_truncate(self.flags()));
+        let mut hasher = RafsDigest::hasher(digester);
+        let mut sb = *self;
+        sb.s_meta_digest = [0u8; 32];
+        hasher.digest_update(sb.as_ref());
+
+        r.seek_to_offset(RAFSV5_SUPERBLOCK_SIZE as u64)?;
+        let mut buf = vec![0u8; RAFSV5_META_DIGEST_BUF_SIZE];
+        let mut left = meta_size.saturating_sub(RAFSV5_SUPERBLOCK_SIZE as u64);
+        while left > 0 {
+            let size = cmp::min(left, buf.len() as u64) as usize;
+            r.read_exact(&mut buf[..size])?;
+            hasher.digest_update(&buf[..size]);
+            left -= size as u64;
+        }
+
+        Ok(hasher.digest_finalize())
+    }
+
+    /// Verify the metadata blob read from `r` is neither truncated nor modified, if its size and
+    /// digest are recorded in the super block.
+    ///
+    /// The reader is rewound to the end of the super block.
+    pub fn verify_meta_digest(&self, r: &mut RafsIoReader, meta_size: u64) -> Result<()> {
+        // Metadata blobs generated by old builders don't record the digest.
+        if self.meta_size() != 0 {
+            if self.meta_size() != meta_size {
+                return Err(einval!(format!(
+                    "metadata blob is truncated or extended, expected size {}, actual size {}",
+                    self.meta_size(),
+                    meta_size
+                )));
+            }
+            let digest = self.calculate_meta_digest(r, meta_size)?;
+            if digest != self.meta_digest() {
+                return Err(einval!(format!(
+                    "metadata blob is corrupted or being modified, expected digest {}, actual digest {}",
+                    self.meta_digest(),
+                    digest
+                )));
+            }
+        }
+        r.seek_to_offset(RAFSV5_SUPERBLOCK_SIZE as u64)?;
+
+        Ok(())
+    }
 
     /// Load a super block from a `RafsIoReader` object.
     pub fn load(&mut self, r: &mut RafsIoReader) -> Result<()> {
@@ -379,6 +457,8 @@ impl Default for RafsV5SuperBlock {
             s_blob_table_offset: u64::to_le(0),
             s_extended_blob_table_offset: u64::to_le(0),
             s_extended_blob_table_entries: u32::to_le(0),
+            s_meta_size: u64::to_le(0),
+            s_meta_digest: [0u8; 32],
             s_reserved: [0u8; RAFSV5_SUPERBLOCK_RESERVED_SIZE],
         }
     }
@@ -670,7 +750,7 @@ impl RafsV5BlobTable {
             debug!("blob {:?} lies on", blob_id);
 
             let index = self.entries.len();
-            let (chunk_count, uncompressed_size, compressed_size, blob_features) =
+            let (chunk_count, uncompressed_size, compressed_size, blob_features, cipher) =
                 // For compatibility, blob table might not be associated with extended blob table.
                 if !self.extended.entries.is_empty() {
                     let ext_len = self.extended.entries.len();
@@ -679,9 +759,12 @@ impl RafsV5BlobTable {
                         return Err(einval!());
                     }
                     let entry = &self.extended.entries[index];
-                    (entry.chunk_count, entry.uncompressed_size, entry.compressed_size, BlobFeatures::empty())
+                    let cipher = crypt::Algorithm::try_from(u32::from_le(entry.cipher)).map_err(|_| {
+                        einval!(format!("invalid cipher algorithm {} of blob {}", entry.cipher, blob_id))
+                    })?;
+                    (entry.chunk_count, entry.uncompressed_size, entry.compressed_size, BlobFeatures::empty(), Some((cipher, entry.cipher_key_id())))
                 } else {
-                    (0, 0, 0, BlobFeatures::V5_NO_EXT_BLOB_TABLE)
+                    (0, 0, 0, BlobFeatures::V5_NO_EXT_BLOB_TABLE, None)
                 };
 
             let mut blob_info = BlobInfo::new(
@@ -697,6 +780,9 @@ impl RafsV5BlobTable {
             blob_info.set_compressor(flags.into());
             blob_info.set_digester(flags.into());
             blob_info.set_readahead(readahead_offset as u64, readahead_size as u64);
+            if let Some((cipher, key_id)) = cipher {
+                blob_info.set_cipher(cipher, key_id);
+            }
 
             self.entries.push(Arc::new(blob_info));
         }
@@ -704,6 +790,23 @@ impl RafsV5BlobTable {
         Ok(())
     }
 
+    /// Record the cipher and key id used to encrypt chunk data of the blob.
+    pub fn set_cipher(
+        &mut self,
+        blob_index: u32,
+        cipher: crypt::Algorithm,
+        key_id: &str,
+    ) -> Result<()> {
+        let index = blob_index as usize;
+        if index >= self.entries.len() || index >= self.extended.entries.len() {
+            return Err(enoent!("blob not found"));
+        }
+        Arc::make_mut(&mut self.extended.entries[index]).set_cipher(cipher, key_id)?;
+        Arc::make_mut(&mut self.entries[index]).set_cipher(cipher, key_id.to_string());
+
+        Ok(())
+    }
+
     /// Get the base blob information array.
     pub fn get_all(&self) -> Vec<Arc<BlobInfo>> {
         self.entries.clone()
@@ -755,6 +858,10 @@ pub struct RafsV5ExtBlobEntry {
     pub reserved1: [u8; 4],     //   --  8 Bytes
     pub uncompressed_size: u64, // -- 16 Bytes
     pub compressed_size: u64,   // -- 24 Bytes
+    /// Algorithm to encrypt chunk data, `crypt::Algorithm::None` if not encrypted.
+    pub cipher: u32, // -- 28 Bytes
+    /// Id of the key to encrypt chunk data, padded with '\0'.
+    pub cipher_key_id: [u8; crypt::MAX_KEY_ID_SIZE], // -- 60 Bytes
     pub reserved2: [u8; RAFSV5_EXT_BLOB_RESERVED_SIZE],
 }
 
@@ -766,6 +873,8 @@ impl Debug for RafsV5ExtBlobEntry {
             .field("chunk_count", &self.chunk_count)
             .field("blob_cache_size", &self.uncompressed_size)
             .field("compressed_blob_size", &self.compressed_size)
+            .field("cipher", &self.cipher)
+            .field("cipher_key_id", &self.cipher_key_id())
             .finish()
     }
 }
@@ -777,6 +886,8 @@ impl Default for RafsV5ExtBlobEntry {
             reserved1: [0; 4],
             uncompressed_size: 0,
             compressed_size: 0,
+            cipher: crypt::Algorithm::None as u32,
+            cipher_key_id: [0; crypt::MAX_KEY_ID_SIZE],
             reserved2: [0; RAFSV5_EXT_BLOB_RESERVED_SIZE],
         }
     }
@@ -791,6 +902,28 @@ impl RafsV5ExtBlobEntry {
             ..Default::default()
         }
     }
+
+    /// Get id of the key to encrypt chunk data.
+    pub fn cipher_key_id(&self) -> String {
+        let len = self
+            .cipher_key_id
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(crypt::MAX_KEY_ID_SIZE);
+        String::from_utf8_lossy(&self.cipher_key_id[..len]).to_string()
+    }
+
+    /// Set the algorithm and id of the key to encrypt chunk data.
+    pub fn set_cipher(&mut self, cipher: crypt::Algorithm, key_id: &str) -> Result<()> {
+        if key_id.len() > crypt::MAX_KEY_ID_SIZE || key_id.as_bytes().contains(&0) {
+            return Err(einval!(format!("invalid cipher key id {}", key_id)));
+        }
+        self.cipher = cipher as u32;
+        self.cipher_key_id = [0; crypt::MAX_KEY_ID_SIZE];
+        self.cipher_key_id[..key_id.len()].copy_from_slice(key_id.as_bytes());
+
+        Ok(())
+    }
 }
 
 /// Rafs v5 on disk extended blob information table.
@@ -870,6 +1003,8 @@ impl RafsStore for RafsV5ExtBlobTable {
                 w.write_all(&entry.reserved1)?;
                 w.write_all(&u64::to_le_bytes(entry.uncompressed_size))?;
                 w.write_all(&u64::to_le_bytes(entry.compressed_size))?;
+                w.write_all(&u32::to_le_bytes(entry.cipher))?;
+                w.write_all(&entry.cipher_key_id)?;
                 w.write_all(&entry.reserved2)?;
                 size += RAFSV5_EXT_BLOB_ENTRY_SIZE;
                 Ok(())
@@ -1410,7 +1545,7 @@ pub(crate) fn rafsv5_validate_digest(
 pub mod tests {
     use std::fs::OpenOptions;
     use std::io::BufWriter;
-    use std::io::{SeekFrom, Write};
+    use std::io::{Seek, SeekFrom, Write};
 
     use storage::device::BlobChunkInfo;
     use vmm_sys_util::tempfile::TempFile;
@@ -1528,6 +1663,13 @@ pub mod tests {
         for i in 0..5 {
             table.add(i * 3, 100, 100);
         }
+        let entry = Arc::make_mut(&mut table.entries[1]);
+        assert!(entry
+            .set_cipher(crypt::Algorithm::Aes256Gcm, &"k".repeat(33))
+            .is_err());
+        entry
+            .set_cipher(crypt::Algorithm::Aes256Gcm, "key1")
+            .unwrap();
 
         // Store extended blob table
         let file = OpenOptions::new()
@@ -1564,6 +1706,13 @@ pub mod tests {
                 [0u8; RAFSV5_EXT_BLOB_RESERVED_SIZE]
             );
         }
+        assert_eq!(table.get(0).unwrap().cipher, crypt::Algorithm::None as u32);
+        assert_eq!(table.get(0).unwrap().cipher_key_id(), "");
+        assert_eq!(
+            table.get(1).unwrap().cipher,
+            crypt::Algorithm::Aes256Gcm as u32
+        );
+        assert_eq!(table.get(1).unwrap().cipher_key_id(), "key1");
     }
 
     #[derive(Default, Copy, Clone)]
@@ -1732,6 +1881,54 @@ pub mod tests {
         assert_eq!(rafsv5_align(9), 16);
     }
 
+    #[test]
+    fn test_rafsv5_meta_digest() {
+        let temp = TempFile::new().unwrap();
+        let mut w = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp.as_path())
+            .unwrap();
+        let mut sb = RafsV5SuperBlock::new();
+        sb.set_digester(digest::Algorithm::Sha256);
+        w.write_all(sb.as_ref()).unwrap();
+        w.write_all(&[0x5au8; 4096]).unwrap();
+
+        // Digest is not verified if it's not recorded.
+        let meta_size = RAFSV5_SUPERBLOCK_SIZE as u64 + 4096;
+        let mut r: RafsIoReader = Box::new(w.try_clone().unwrap());
+        sb.verify_meta_digest(&mut r, meta_size - 1).unwrap();
+
+        sb.set_meta_size(meta_size);
+        let digest = sb.calculate_meta_digest(&mut r, meta_size).unwrap();
+        sb.set_meta_digest(digest);
+        w.seek(SeekFrom::Start(0)).unwrap();
+        w.write_all(sb.as_ref()).unwrap();
+
+        r.seek_to_offset(0).unwrap();
+        let mut loaded = RafsV5SuperBlock::read(&mut r).unwrap();
+        assert_eq!(loaded.meta_digest(), digest);
+        loaded.verify_meta_digest(&mut r, meta_size).unwrap();
+        assert_eq!(
+            r.seek(SeekFrom::Current(0)).unwrap(),
+            RAFSV5_SUPERBLOCK_SIZE as u64
+        );
+
+        // Truncated metadata blob.
+        assert!(loaded.verify_meta_digest(&mut r, meta_size - 1).is_err());
+
+        // Modified metadata blob.
+        w.seek(SeekFrom::Start(meta_size - 1)).unwrap();
+        w.write_all(&[0xa5u8]).unwrap();
+        assert!(loaded.verify_meta_digest(&mut r, meta_size).is_err());
+
+        // Modified super block.
+        loaded.set_inodes_count(1);
+        w.seek(SeekFrom::Start(meta_size - 1)).unwrap();
+        w.write_all(&[0x5au8]).unwrap();
+        assert!(loaded.verify_meta_digest(&mut r, meta_size).is_err());
+    }
+
     #[test]
     fn test_rafsv5_superflags() {
         assert_eq!(
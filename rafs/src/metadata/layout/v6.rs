@@ -35,6 +35,9 @@ pub const EROFS_INODE_CHUNK_BASED: u16 = 4;
 /// EROFS device table offset.
 pub const EROFS_DEVTABLE_OFFSET: u16 =
     EROFS_SUPER_OFFSET + EROFS_SUPER_BLOCK_SIZE + EROFS_EXT_SUPER_BLOCK_SIZE;
+/// Maximum number of blobs referenced by a Rafs v6 image, chunks refer to blobs by 8-bit indexes
+/// starting from 1.
+pub const RAFS_V6_MAX_BLOBS: usize = u8::MAX as usize;
 
 pub const EROFS_I_VERSION_BIT: u16 = 0;
 pub const EROFS_I_VERSION_BITS: u16 = 1;
@@ -1391,7 +1394,15 @@ impl RafsV6BlobTable {
             )));
         }
 
-        for idx in 0..(blob_table_size as usize / size_of::<RafsV6Blob>()) {
+        let count = blob_table_size as usize / size_of::<RafsV6Blob>();
+        if count > RAFS_V6_MAX_BLOBS {
+            return Err(einval!(format!(
+                "Rafs v6 blob table has {} blobs, exceeding limit {}",
+                count, RAFS_V6_MAX_BLOBS
+            )));
+        }
+
+        for idx in 0..count {
             let mut blob = RafsV6Blob::default();
             r.read_exact(blob.as_mut())?;
             if !blob.validate(idx as u32, chunk_size, flags) {
@@ -1683,6 +1694,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_rafs_v6_blob_table_limit() {
+        let temp = TempFile::new().unwrap();
+        let r = OpenOptions::new().read(true).open(temp.as_path()).unwrap();
+        let mut reader: Box<dyn RafsIoRead> = Box::new(r);
+        let mut table = RafsV6BlobTable::new();
+        let size = (RAFS_V6_MAX_BLOBS + 1) * size_of::<RafsV6Blob>();
+
+        let err = table
+            .load(
+                &mut reader,
+                size as u32,
+                RAFS_MAX_CHUNK_SIZE as u32,
+                RafsSuperFlags::empty(),
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert!(table.entries.is_empty());
+    }
+
     #[test]
     fn test_rafs_v6_inode_extended() {
         let temp = TempFile::new().unwrap();
@@ -6,6 +6,7 @@
 use super::cached_v5::CachedSuperBlockV5;
 use super::direct_v5::DirectSuperBlockV5;
 use super::layout::v5::{RafsV5PrefetchTable, RafsV5SuperBlock};
+use super::shared;
 use super::*;
 
 impl RafsSuper {
@@ -18,6 +19,7 @@ impl RafsSuper {
             return Ok(false);
         }
         sb.validate(end)?;
+        shared::verify_v5_meta_digest(&sb, r, end)?;
 
         self.meta.magic = sb.magic();
         self.meta.version = sb.version();
@@ -44,6 +46,20 @@ impl RafsSuper {
                 self.superblock = Arc::new(inodes);
             }
             RafsMode::Cached => {
+                // Huge bootstraps may take more memory than available when all inodes are cached,
+                // so map them in direct mode instead, letting the kernel page metadata in and out.
+                let estimated = CachedSuperBlockV5::estimate_memory(&self.meta, end);
+                if self.max_cached_size > 0 && estimated > self.max_cached_size {
+                    warn!(
+                        "caching {} inodes takes about {} bytes exceeding the limit {}, fall back to direct mode",
+                        self.meta.inode_table_entries, estimated, self.max_cached_size
+                    );
+                    self.mode = RafsMode::Direct;
+                    let mut inodes = DirectSuperBlockV5::new(&self.meta, self.validate_digest);
+                    inodes.load(r)?;
+                    self.superblock = Arc::new(inodes);
+                    return Ok(true);
+                }
                 let mut inodes = CachedSuperBlockV5::new(self.meta, self.validate_digest);
                 inodes.load(r)?;
                 self.superblock = Arc::new(inodes);
@@ -122,10 +138,13 @@ impl RafsSuper {
         Ok(hint_entries)
     }
 
-    pub(crate) fn skip_v5_superblock(&self, r: &mut RafsIoReader) -> Result<()> {
-        let _ = RafsV5SuperBlock::read(r)?;
-
-        Ok(())
+    // Verify the metadata blob to update to, and skip its super block.
+    pub(crate) fn verify_v5_superblock(&self, r: &mut RafsIoReader) -> Result<()> {
+        let end = r.seek_to_end(0)?;
+        r.seek_to_offset(0)?;
+        let sb = RafsV5SuperBlock::read(r)?;
+        sb.validate(end)?;
+        shared::verify_v5_meta_digest(&sb, r, end)
     }
 
     // TODO: Add a UT for me.
@@ -39,15 +39,17 @@ impl RafsSuper {
         self.meta.meta_blkaddr = sb.s_meta_blkaddr;
         self.meta.root_nid = sb.s_root_nid;
 
-        match self.mode {
-            RafsMode::Direct => {
-                let mut sb_v6 = DirectSuperBlockV6::new(&self.meta, self.validate_digest);
-                sb_v6.load(r)?;
-                self.superblock = Arc::new(sb_v6);
-                Ok(true)
-            }
-            RafsMode::Cached => Err(enosys!("Rafs v6 does not support cached mode")),
+        // There's no cached superblock for Rafs v6 which is designed to be mapped, so map it in
+        // direct mode instead of buffering all metadata.
+        if self.mode == RafsMode::Cached {
+            warn!("Rafs v6 does not support cached mode, fall back to direct mode");
+            self.mode = RafsMode::Direct;
         }
+        let mut sb_v6 = DirectSuperBlockV6::new(&self.meta, self.validate_digest);
+        sb_v6.load(r)?;
+        self.superblock = Arc::new(sb_v6);
+
+        Ok(true)
     }
 }
 
@@ -6,12 +6,13 @@
 //! Structs and Traits for RAFS file system meta data management.
 
 use std::any::Any;
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::fs::OpenOptions;
 use std::io::{Error, Result};
-use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::RawFd;
 use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
@@ -24,6 +25,9 @@ use serde::Serialize;
 use serde_with::{serde_as, DisplayFromStr};
 use storage::compress;
 use storage::device::{BlobChunkInfo, BlobInfo, BlobIoVec};
+use storage::utils::{
+    advise_hugepage, hugepage_mode, map_hugetlb, pread, HugePageMode, HUGEPAGE_SIZE,
+};
 
 use self::layout::{XattrName, XattrValue, RAFS_SUPER_VERSION_V5, RAFS_SUPER_VERSION_V6};
 use self::noop::NoopSuperBlock;
@@ -37,6 +41,7 @@ pub mod layout;
 mod md_v5;
 mod md_v6;
 mod noop;
+pub(crate) mod shared;
 
 pub use storage::{RAFS_DEFAULT_CHUNK_SIZE, RAFS_MAX_CHUNK_SIZE};
 
@@ -52,6 +57,21 @@ pub const RAFS_MAX_METADATA_SIZE: usize = 0x8000_0000;
 pub const DOT: &str = ".";
 /// File name for Unix parent directory.
 pub const DOTDOT: &str = "..";
+/// Extended attribute recording creation time of a file as `<seconds>.<nanoseconds>` since the
+/// Unix epoch, FUSE has no field to report the creation time.
+pub const RAFS_XATTR_BTIME: &str = "user.nydus.btime";
+
+/// Fold case of a file name for case-insensitive comparison.
+///
+/// UTF-8 names are folded to Unicode lower case, other names are folded to ASCII lower case.
+/// Characters are mapped to lower case one by one, which isn't full Unicode case folding, so names
+/// such as "straße" and "STRASSE" are still different.
+pub fn fold_name_case(name: &OsStr) -> OsString {
+    match name.to_str() {
+        Some(s) => OsString::from(s.to_lowercase()),
+        None => OsString::from_vec(name.as_bytes().to_ascii_lowercase()),
+    }
+}
 
 /// Type of RAFS inode number.
 pub type Inode = u64;
@@ -94,6 +114,121 @@ pub trait RafsSuperBlock: RafsSuperBlobs + RafsSuperInodes + Send + Sync {
     fn get_blob_infos(&self) -> Vec<Arc<BlobInfo>>;
 
     fn root_ino(&self) -> u64;
+
+    /// Get memory used by the filesystem metadata, in unit of Byte.
+    fn memory_usage(&self) -> u64 {
+        0
+    }
+
+    /// Release memory used by the filesystem metadata, which will be reloaded on demand.
+    fn shrink(&self) {}
+}
+
+/// Memory mapping of a bootstrap file for direct access.
+pub(crate) struct BootstrapMapping {
+    pub base: *const u8,
+    /// Size of the mapping, which may be bigger than the bootstrap file.
+    pub size: usize,
+    /// Whether the bootstrap is copied into anonymous memory instead of mapped from the file.
+    pub copied: bool,
+}
+
+impl BootstrapMapping {
+    /// Map `size` bytes of the bootstrap file `fd` readonly, backed by huge pages according to
+    /// the process wide huge page mode.
+    ///
+    /// Huge pages from hugetlbfs can't back a file mapping, so the bootstrap is copied into
+    /// anonymous memory in the `Explicit` mode.
+    pub fn new(fd: RawFd, size: usize) -> Result<Self> {
+        let mode = hugepage_mode();
+        if mode == HugePageMode::Explicit && size >= HUGEPAGE_SIZE {
+            match Self::copy_to_hugetlb(fd, size) {
+                Ok(mapping) => return Ok(mapping),
+                Err(e) => warn!("fall back to normal pages for bootstrap, {}", e),
+            }
+        }
+
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ,
+                libc::MAP_NORESERVE | libc::MAP_PRIVATE,
+                fd,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(last_error!("failed to mmap bootstrap"));
+        }
+        if base.is_null() {
+            return Err(ebadf!("failed to mmap bootstrap"));
+        }
+        if mode != HugePageMode::Never {
+            advise_hugepage(base as *const u8, size);
+        }
+
+        Ok(BootstrapMapping {
+            base: base as *const u8,
+            size,
+            copied: false,
+        })
+    }
+
+    fn copy_to_hugetlb(fd: RawFd, size: usize) -> Result<Self> {
+        let map_size = (size + HUGEPAGE_SIZE - 1) & !(HUGEPAGE_SIZE - 1);
+        let base = map_hugetlb(map_size)?;
+
+        // Safe because the mapping is valid for `map_size` bytes.
+        let buf = unsafe { std::slice::from_raw_parts_mut(base, size) };
+        let mut pos = 0;
+        let ret = loop {
+            if pos >= size {
+                break Ok(());
+            }
+            match pread(fd, &mut buf[pos..], pos as u64) {
+                Ok(0) => break Err(eio!("bootstrap file is truncated")),
+                Ok(n) => pos += n,
+                Err(e) => break Err(e),
+            }
+        };
+        let ret = ret.and_then(|_| {
+            match unsafe { libc::mprotect(base as *mut libc::c_void, map_size, libc::PROT_READ) } {
+                0 => Ok(()),
+                _ => Err(last_error!("failed to protect bootstrap mapping")),
+            }
+        });
+        if let Err(e) = ret {
+            Self::unmap(base, map_size);
+            return Err(e);
+        }
+
+        Ok(BootstrapMapping {
+            base,
+            size: map_size,
+            copied: true,
+        })
+    }
+
+    /// Unmap memory range [`base`, `base + size`) mapped by `BootstrapMapping::new()`.
+    pub fn unmap(base: *const u8, size: usize) {
+        unsafe { libc::munmap(base as *mut libc::c_void, size) };
+    }
+
+    /// Validate that range [`base + offset`, `base + offset + size`) is within [`base`, `end`).
+    #[inline]
+    pub fn validate_range(
+        base: *const u8,
+        end: *const u8,
+        offset: usize,
+        size: usize,
+    ) -> Result<()> {
+        let len = (end as usize).saturating_sub(base as usize);
+        match offset.checked_add(size) {
+            Some(v) if v <= len => Ok(()),
+            _ => Err(einval!("invalid range")),
+        }
+    }
 }
 
 pub enum PostWalkAction {
@@ -238,6 +373,8 @@ bitflags! {
         const HAS_XATTR = 0x0000_0020;
         // V5: Data chunks are compressed with gzip
         const COMPRESS_GZIP = 0x0000_0040;
+        /// V5: Data chunks of some blobs are encrypted.
+        const ENCRYPTED = 0x0000_0080;
     }
 }
 
@@ -399,12 +536,51 @@ impl Display for RafsMode {
     }
 }
 
+/// Errors found when validating the directory tree of a bootstrap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootstrapError {
+    /// The inode can't be loaded or is malformed.
+    InvalidInode(Inode, String),
+    /// The directory entry name is empty, too long, `.`, `..`, or contains `/` or NUL.
+    InvalidName(Inode),
+    /// The parent inode number doesn't match the directory containing the entry.
+    InvalidParent {
+        ino: Inode,
+        parent: Inode,
+        dir: Inode,
+    },
+    /// The directory is reachable from more than one directory entry.
+    Cycle(Inode),
+    /// More directory entries than the inode table may hold are reachable.
+    TooManyEntries(u64),
+}
+
+impl Display for BootstrapError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::InvalidInode(ino, msg) => write!(f, "invalid inode {}, {}", ino, msg),
+            Self::InvalidName(ino) => write!(f, "invalid name of inode {}", ino),
+            Self::InvalidParent { ino, parent, dir } => write!(
+                f,
+                "inode {} in directory {} has parent {}",
+                ino, dir, parent
+            ),
+            Self::Cycle(ino) => write!(f, "directory {} is reachable more than once", ino),
+            Self::TooManyEntries(max) => write!(f, "more than {} directory entries", max),
+        }
+    }
+}
+
+impl std::error::Error for BootstrapError {}
+
 /// Cached Rafs super block and inode information.
 pub struct RafsSuper {
     /// Rafs metadata working mode.
     pub mode: RafsMode,
     /// Whether validate data read from storage backend.
     pub validate_digest: bool,
+    /// Maximum memory to cache metadata in cached mode, in unit of Byte, 0 means no limit.
+    pub max_cached_size: u64,
     /// Cached metadata from on disk super block.
     pub meta: RafsSuperMeta,
     /// Rafs filesystem super block.
@@ -416,6 +592,7 @@ impl Default for RafsSuper {
         Self {
             mode: RafsMode::Direct,
             validate_digest: false,
+            max_cached_size: 0,
             meta: RafsSuperMeta::default(),
             superblock: Arc::new(NoopSuperBlock::new()),
         }
@@ -434,6 +611,7 @@ impl RafsSuper {
         }
 
         rs.validate_digest = conf.digest_validate;
+        rs.max_cached_size = conf.max_cached_size;
 
         Ok(rs)
     }
@@ -478,7 +656,7 @@ impl RafsSuper {
     /// Update the filesystem metadata and storage backend.
     pub fn update(&self, r: &mut RafsIoReader) -> RafsResult<()> {
         if self.meta.is_v5() {
-            self.skip_v5_superblock(r)
+            self.verify_v5_superblock(r)
                 .map_err(RafsError::FillSuperblock)?;
         }
 
@@ -504,6 +682,85 @@ impl RafsSuper {
         self.superblock.get_max_ino()
     }
 
+    /// Get memory used by the filesystem metadata, in unit of Byte.
+    pub fn memory_usage(&self) -> u64 {
+        self.superblock.memory_usage()
+    }
+
+    /// Release memory used by the filesystem metadata if possible.
+    pub fn shrink(&self) {
+        self.superblock.shrink()
+    }
+
+    /// Validate the directory tree of the filesystem, which may come from untrusted parties.
+    ///
+    /// Walk the tree from the root directory, to make sure each directory is reachable by only
+    /// one path, entry names are valid and parent inode numbers are consistent. So later tree
+    /// walks will neither loop forever nor generate invalid paths.
+    pub fn validate_tree(&self) -> std::result::Result<(), BootstrapError> {
+        // Only Rafs v5 keeps child and parent links in inodes.
+        if !self.meta.is_v5() {
+            return Ok(());
+        }
+
+        let max_entries = self.meta.inode_table_entries as u64;
+        let root = self
+            .get_inode(ROOT_ID, false)
+            .map_err(|e| BootstrapError::InvalidInode(ROOT_ID, e.to_string()))?;
+        if !root.is_dir() {
+            return Err(BootstrapError::InvalidInode(
+                ROOT_ID,
+                "root is not a directory".to_string(),
+            ));
+        }
+
+        let mut visited = HashSet::new();
+        let mut dirs = VecDeque::new();
+        let mut entries = 1u64;
+        visited.insert(ROOT_ID);
+        dirs.push_back(root);
+
+        while let Some(dir) = dirs.pop_front() {
+            let dir_ino = dir.ino();
+            for idx in 0..dir.get_child_count() {
+                entries += 1;
+                if entries > max_entries {
+                    return Err(BootstrapError::TooManyEntries(max_entries));
+                }
+
+                let child = dir.get_child_by_index(idx).map_err(|e| {
+                    BootstrapError::InvalidInode(dir_ino, format!("child {}, {}", idx, e))
+                })?;
+                let ino = child.ino();
+                let name = child.name();
+                let bytes = name.as_bytes();
+                if bytes.is_empty()
+                    || bytes.len() > RAFS_MAX_NAME
+                    || name == DOT
+                    || name == DOTDOT
+                    || bytes.iter().any(|c| *c == b'/' || *c == 0)
+                {
+                    return Err(BootstrapError::InvalidName(ino));
+                }
+                if !child.is_hardlink() && child.parent() != dir_ino {
+                    return Err(BootstrapError::InvalidParent {
+                        ino,
+                        parent: child.parent(),
+                        dir: dir_ino,
+                    });
+                }
+                if child.is_dir() {
+                    if !visited.insert(ino) {
+                        return Err(BootstrapError::Cycle(ino));
+                    }
+                    dirs.push_back(child);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Convert an inode number to a file path.
     pub fn path_from_ino(&self, ino: Inode) -> Result<PathBuf> {
         if ino == ROOT_ID {
@@ -513,6 +770,9 @@ impl RafsSuper {
         let mut path = PathBuf::new();
         let mut cur_ino = ino;
         let mut inode;
+        // Bound the walk in case of parent loops in malformed metadata, each path component
+        // takes at least two bytes of PATH_MAX.
+        let mut depth = 0;
 
         loop {
             inode = self.get_inode(cur_ino, false)?;
@@ -521,8 +781,11 @@ impl RafsSuper {
 
             if inode.ino() == ROOT_ID {
                 break;
+            } else if depth > libc::PATH_MAX / 2 {
+                return Err(einval!(format!("parent loop of inode {}", ino)));
             } else {
                 cur_ino = inode.parent();
+                depth += 1;
             }
         }
 
@@ -712,4 +975,95 @@ mod tests {
         assert_eq!(&format!("{}", RafsMode::Direct), "direct");
         assert_eq!(&format!("{}", RafsMode::Cached), "cached");
     }
+
+    #[test]
+    fn test_fold_name_case() {
+        assert_eq!(fold_name_case(OsStr::new("ReadMe.TXT")), "readme.txt");
+        assert_eq!(fold_name_case(OsStr::new("ÄÖÜ")), "äöü");
+        assert_ne!(
+            fold_name_case(OsStr::new("straße")),
+            fold_name_case(OsStr::new("STRASSE"))
+        );
+        assert_eq!(
+            fold_name_case(OsStr::from_bytes(b"AB\xff")),
+            OsStr::from_bytes(b"ab\xff")
+        );
+    }
+
+    #[test]
+    fn test_bootstrap_mapping_validate_range() {
+        let buf = [0u8; 16];
+        let base = buf.as_ptr();
+        let end = base.wrapping_add(buf.len());
+
+        BootstrapMapping::validate_range(base, end, 0, 16).unwrap();
+        BootstrapMapping::validate_range(base, end, 8, 8).unwrap();
+        BootstrapMapping::validate_range(base, end, 16, 0).unwrap();
+        BootstrapMapping::validate_range(base, end, 8, 9).unwrap_err();
+        BootstrapMapping::validate_range(base, end, 17, 0).unwrap_err();
+        BootstrapMapping::validate_range(base, end, usize::MAX, 2).unwrap_err();
+        BootstrapMapping::validate_range(std::ptr::null(), std::ptr::null(), 0, 1).unwrap_err();
+    }
+
+    fn test_bootstrap_path() -> PathBuf {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        PathBuf::from(root_dir).join("../tests/texture/bootstrap/image_v2.boot")
+    }
+
+    #[test]
+    fn test_validate_tree() {
+        let path = test_bootstrap_path();
+        for mode in [RafsMode::Direct, RafsMode::Cached].iter() {
+            let sb =
+                RafsSuper::load_from_metadata(path.to_str().unwrap(), mode.clone(), false).unwrap();
+            sb.validate_tree().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_max_cached_size() {
+        let path = test_bootstrap_path();
+        for (limit, mode) in [(0, RafsMode::Cached), (1, RafsMode::Direct)].iter() {
+            let mut sb = RafsSuper {
+                mode: RafsMode::Cached,
+                max_cached_size: *limit,
+                ..Default::default()
+            };
+            let mut reader = Box::new(std::fs::File::open(&path).unwrap()) as RafsIoReader;
+            sb.load(&mut reader).unwrap();
+            assert_eq!(&sb.mode, mode);
+            sb.validate_tree().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load_corrupted_bootstrap() {
+        use std::os::unix::fs::FileExt;
+        use vmm_sys_util::tempfile::TempFile;
+
+        let data = std::fs::read(test_bootstrap_path()).unwrap();
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap();
+        std::fs::write(path, &data).unwrap();
+
+        // Corrupt fields of the super block and samples of the inode table and inodes, loading
+        // and validating the bootstrap must fail gracefully instead of panicking or hanging.
+        let offsets = (0..0x100)
+            .step_by(8)
+            .chain((0x2000..data.len()).step_by(data.len() / 64));
+        for offset in offsets {
+            let end = std::cmp::min(offset + 8, data.len());
+            file.as_file()
+                .write_at(&[0xffu8; 8][..end - offset], offset as u64)
+                .unwrap();
+            for mode in [RafsMode::Direct, RafsMode::Cached].iter() {
+                if let Ok(sb) = RafsSuper::load_from_metadata(path, mode.clone(), false) {
+                    let _ = sb.validate_tree();
+                }
+            }
+            file.as_file()
+                .write_at(&data[offset..end], offset as u64)
+                .unwrap();
+        }
+    }
 }
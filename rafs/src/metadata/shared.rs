@@ -0,0 +1,286 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Share parsed filesystem metadata among mounts of the same bootstrap.
+//!
+//! Popular base images are often mounted many times by a node, and each mount used to load its
+//! own copy of the metadata. Loaded metadata is now registered by the digest of the bootstrap
+//! and the options to load it, so mounts of the same bootstrap share one [RafsSuper] object,
+//! even if each mount has its own copy of the bootstrap file. Only weak references are kept by
+//! the registry, so the metadata is released once all mounts sharing it are gone.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Read, Result, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, Weak};
+
+use lazy_static::lazy_static;
+use nix::sys::stat::fstat;
+use nydus_utils::digest::{self, RafsDigest};
+
+use super::layout::v5::{RafsV5SuperBlock, RAFSV5_SUPERBLOCK_SIZE};
+use super::RafsSuper;
+use crate::fs::RafsConfig;
+use crate::RafsIoReader;
+
+/// Size of the bootstrap header covering super blocks of both Rafs v5 and v6.
+const SUPER_BLOCK_SIZE: u64 = 0x2000;
+/// Maximum number of bootstraps remembered as verified.
+const MAX_VERIFIED_BOOTSTRAPS: usize = 0x1000;
+
+lazy_static! {
+    static ref SHARED_SUPERS: Mutex<HashMap<String, Weak<RafsSuper>>> = Mutex::new(HashMap::new());
+    static ref SCRUBBED_SUPERS: Mutex<HashSet<usize>> = Mutex::new(HashSet::new());
+    static ref VERIFIED_BOOTSTRAPS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Get the identity of the bootstrap file `r` by its device, inode number, size and modification
+/// time, which changes once the file is rewritten.
+pub(crate) fn bootstrap_id(r: &RafsIoReader) -> Result<String> {
+    let st = fstat(r.as_raw_fd()).map_err(|e| Error::from_raw_os_error(e as i32))?;
+
+    Ok(format!(
+        "{}-{}-{}-{}.{}",
+        st.st_dev, st.st_ino, st.st_size, st.st_mtime, st.st_mtime_nsec
+    ))
+}
+
+/// Get the key to share metadata loaded from the bootstrap `r` with configuration `conf`.
+///
+/// Bootstraps generated by recent builders are identified by the digest of the whole bootstrap
+/// recorded in the super block, which is verified once, so copies of the same bootstrap share
+/// the metadata. Other bootstraps are identified by the file and the digest of the super block.
+/// `r` is rewound to the beginning.
+pub(crate) fn shared_key(conf: &RafsConfig, r: &mut RafsIoReader) -> Result<String> {
+    let end = r.seek_to_end(0)?;
+    r.seek_to_offset(0)?;
+    let mut sb = RafsV5SuperBlock::new();
+    let id = if end > RAFSV5_SUPERBLOCK_SIZE as u64
+        && r.read_exact(sb.as_mut()).is_ok()
+        && sb.is_rafs_v5()
+        && sb.meta_size() != 0
+    {
+        // Don't let a bootstrap claim the metadata of another one by a forged digest.
+        verify_v5_meta_digest(&sb, r, end)?;
+        format!("digest-{}", sb.meta_digest())
+    } else {
+        let mut buf = Vec::with_capacity(SUPER_BLOCK_SIZE as usize);
+        r.seek(SeekFrom::Start(0))?;
+        r.by_ref().take(SUPER_BLOCK_SIZE).read_to_end(&mut buf)?;
+        let digest = RafsDigest::from_buf(&buf, digest::Algorithm::Blake3);
+        format!("file-{}-{}", bootstrap_id(r)?, digest)
+    };
+    r.seek(SeekFrom::Start(0))?;
+
+    // Metadata loaded with distinct memory limits may be cached or mapped differently.
+    Ok(format!(
+        "{}-{}-{}-{}",
+        id, conf.mode, conf.digest_validate, conf.max_cached_size
+    ))
+}
+
+/// Verify digest of the Rafs v5 bootstrap `r` of `end` bytes recorded by its super block `sb`,
+/// unless the file has been verified since last modified, and skip the super block.
+pub(crate) fn verify_v5_meta_digest(
+    sb: &RafsV5SuperBlock,
+    r: &mut RafsIoReader,
+    end: u64,
+) -> Result<()> {
+    let id = bootstrap_id(r)?;
+    if is_verified(&id, &sb.meta_digest()) {
+        r.seek_to_offset(RAFSV5_SUPERBLOCK_SIZE as u64)?;
+        return Ok(());
+    }
+    sb.verify_meta_digest(r, end)?;
+    set_verified(&id, &sb.meta_digest());
+
+    Ok(())
+}
+
+/// Check whether the bootstrap with identity `id` has been verified against the digest `digest`
+/// recorded by its super block.
+pub(crate) fn is_verified(id: &str, digest: &RafsDigest) -> bool {
+    VERIFIED_BOOTSTRAPS
+        .lock()
+        .unwrap()
+        .contains(&format!("{}-{}", id, digest))
+}
+
+/// Remember the bootstrap with identity `id` has been verified against the digest `digest`, so it
+/// isn't read as a whole again when mounted later.
+pub(crate) fn set_verified(id: &str, digest: &RafsDigest) {
+    let mut verified = VERIFIED_BOOTSTRAPS.lock().unwrap();
+    if verified.len() >= MAX_VERIFIED_BOOTSTRAPS {
+        verified.clear();
+    }
+    verified.insert(format!("{}-{}", id, digest));
+}
+
+/// Claim scrubbing of metadata `sb`, return false if it's scrubbed by another mount sharing it.
+pub(crate) fn claim_scrub(sb: &Arc<RafsSuper>) -> bool {
+    SCRUBBED_SUPERS
+        .lock()
+        .unwrap()
+        .insert(Arc::as_ptr(sb) as usize)
+}
+
+/// Release scrubbing of metadata `sb` claimed by `claim_scrub()`.
+pub(crate) fn release_scrub(sb: &Arc<RafsSuper>) {
+    SCRUBBED_SUPERS
+        .lock()
+        .unwrap()
+        .remove(&(Arc::as_ptr(sb) as usize));
+}
+
+/// Get metadata registered by `key` if it's still used by other mounts.
+pub(crate) fn get(key: &str) -> Option<Arc<RafsSuper>> {
+    let mut supers = SHARED_SUPERS.lock().unwrap();
+    let sb = supers.get(key).and_then(|sb| sb.upgrade());
+    if sb.is_none() {
+        supers.remove(key);
+    }
+
+    sb
+}
+
+/// Register metadata `sb` by `key`.
+pub(crate) fn insert(key: &str, sb: &Arc<RafsSuper>) {
+    let mut supers = SHARED_SUPERS.lock().unwrap();
+    // Drop entries of released metadata to bound the registry.
+    supers.retain(|_, v| v.strong_count() > 0);
+    supers.insert(key.to_string(), Arc::downgrade(sb));
+}
+
+/// Unregister metadata `sb` by `key`, before it's changed to reflect another bootstrap.
+pub(crate) fn remove(key: &str, sb: &Arc<RafsSuper>) {
+    let mut supers = SHARED_SUPERS.lock().unwrap();
+    if supers
+        .get(key)
+        .map_or(false, |v| v.as_ptr() == Arc::as_ptr(sb))
+    {
+        supers.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_shared_key() {
+        let conf = RafsConfig::new();
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0x5a; 0x3000]).unwrap();
+        let mut r = Box::new(file.as_file().try_clone().unwrap()) as RafsIoReader;
+
+        let key = shared_key(&conf, &mut r).unwrap();
+        assert_eq!(r.seek(SeekFrom::Current(0)).unwrap(), 0);
+        assert_eq!(shared_key(&conf, &mut r).unwrap(), key);
+
+        let mut conf2 = RafsConfig::new();
+        conf2.digest_validate = true;
+        assert_ne!(shared_key(&conf2, &mut r).unwrap(), key);
+
+        let mut conf3 = RafsConfig::new();
+        conf3.max_cached_size = 0x1000;
+        assert_ne!(shared_key(&conf3, &mut r).unwrap(), key);
+
+        // Rewriting the file changes its identity, even with the same size and super block.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        file.as_file().write_all_at(&[0xa5; 0x10], 0x2000).unwrap();
+        assert_ne!(shared_key(&conf, &mut r).unwrap(), key);
+        // Files with the same content are different bootstraps without recorded digests.
+        let file2 = TempFile::new().unwrap();
+        file2.as_file().write_all(&[0x5a; 0x3000]).unwrap();
+        let mut r2 = Box::new(file2.into_file()) as RafsIoReader;
+        assert_ne!(shared_key(&conf, &mut r2).unwrap(), key);
+    }
+
+    // Write a Rafs v5 bootstrap recording the digest of `data` following the super block.
+    fn write_bootstrap(data: &[u8]) -> TempFile {
+        let file = TempFile::new().unwrap();
+        let mut sb = RafsV5SuperBlock::new();
+        sb.set_digester(digest::Algorithm::Sha256);
+        file.as_file().write_all(sb.as_ref()).unwrap();
+        file.as_file().write_all(data).unwrap();
+
+        let meta_size = (RAFSV5_SUPERBLOCK_SIZE + data.len()) as u64;
+        let mut r = Box::new(file.as_file().try_clone().unwrap()) as RafsIoReader;
+        sb.set_meta_size(meta_size);
+        sb.set_meta_digest(sb.calculate_meta_digest(&mut r, meta_size).unwrap());
+        file.as_file().write_all_at(sb.as_ref(), 0).unwrap();
+
+        file
+    }
+
+    #[test]
+    fn test_shared_key_digest() {
+        let conf = RafsConfig::new();
+        let file = write_bootstrap(&[0x5a; 0x1000]);
+        let mut r = Box::new(file.as_file().try_clone().unwrap()) as RafsIoReader;
+        let key = shared_key(&conf, &mut r).unwrap();
+        assert_eq!(r.seek(SeekFrom::Current(0)).unwrap(), 0);
+        let digest = RafsV5SuperBlock::read(&mut r).unwrap().meta_digest();
+        assert!(is_verified(&bootstrap_id(&r).unwrap(), &digest));
+
+        // Copies of the same bootstrap share the metadata.
+        let file2 = write_bootstrap(&[0x5a; 0x1000]);
+        let mut r2 = Box::new(file2.as_file().try_clone().unwrap()) as RafsIoReader;
+        assert_eq!(shared_key(&conf, &mut r2).unwrap(), key);
+        let file3 = write_bootstrap(&[0xa5; 0x1000]);
+        let mut r3 = Box::new(file3.as_file().try_clone().unwrap()) as RafsIoReader;
+        assert_ne!(shared_key(&conf, &mut r3).unwrap(), key);
+
+        // A bootstrap mismatching its recorded digest can't claim the metadata.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        file2
+            .as_file()
+            .write_all_at(&[0xa5; 0x10], RAFSV5_SUPERBLOCK_SIZE as u64)
+            .unwrap();
+        assert!(shared_key(&conf, &mut r2).is_err());
+    }
+
+    #[test]
+    fn test_verified_bootstraps() {
+        let digest = RafsDigest::from_buf(b"test", digest::Algorithm::Blake3);
+        let digest2 = RafsDigest::from_buf(b"test2", digest::Algorithm::Blake3);
+        assert!(!is_verified("test-verified", &digest));
+        set_verified("test-verified", &digest);
+        assert!(is_verified("test-verified", &digest));
+        assert!(!is_verified("test-verified", &digest2));
+    }
+
+    #[test]
+    fn test_claim_scrub() {
+        let sb = Arc::new(RafsSuper::default());
+        assert!(claim_scrub(&sb));
+        assert!(!claim_scrub(&sb));
+        release_scrub(&sb);
+        assert!(claim_scrub(&sb));
+        release_scrub(&sb);
+    }
+
+    #[test]
+    fn test_shared_supers() {
+        let sb = Arc::new(RafsSuper::default());
+        assert!(get("test-shared-supers").is_none());
+
+        insert("test-shared-supers", &sb);
+        let sb2 = get("test-shared-supers").unwrap();
+        assert!(Arc::ptr_eq(&sb, &sb2));
+
+        // Only the registered metadata is unregistered.
+        remove("test-shared-supers", &Arc::new(RafsSuper::default()));
+        assert!(get("test-shared-supers").is_some());
+        remove("test-shared-supers", &sb);
+        assert!(get("test-shared-supers").is_none());
+
+        insert("test-shared-supers", &sb);
+        drop(sb);
+        drop(sb2);
+        assert!(get("test-shared-supers").is_none());
+    }
+}
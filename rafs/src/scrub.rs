@@ -0,0 +1,383 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verify digests of chunks in background to detect corruption before users hit it.
+//!
+//! Chunk data in the cache may be corrupted by failing disks or by tools touching the cache
+//! directory, and data on the storage backend may be damaged too. Without digest validation on
+//! the read path, which is costly, corruption is only noticed by applications reading garbage.
+//! A low priority scrubber thread periodically walks all files of the filesystem, verifies digests
+//! of cached chunks, and optionally of a sample of chunks on the storage backend. Corrupted chunks
+//! are reported by scrub and error metrics, and by the events stream.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use nydus_utils::metrics::{ErrorClass, GlobalIoStats, Metric, ERROR_HOLDER};
+use serde::Deserialize;
+use storage::device::{BlobChunkInfo, BlobDevice};
+
+use crate::metadata::layout::RAFS_ROOT_INODE;
+use crate::metadata::{PostWalkAction, RafsInode, RafsSuper, DOT, DOTDOT};
+
+/// Amount of file data to map to chunks at a time.
+const SCRUB_WINDOW: u64 = 4 << 20;
+
+fn default_interval() -> u64 {
+    86400
+}
+
+fn default_bandwidth_rate() -> u64 {
+    10 << 20
+}
+
+/// Configuration information to verify chunk data in background.
+#[derive(Clone, Deserialize)]
+pub struct ScrubConfig {
+    /// Interval in seconds between the start of two passes over all chunks.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Verify one of every `remote_sample_rate` chunks against the storage backend, zero to only
+    /// verify cached chunks.
+    #[serde(default)]
+    pub remote_sample_rate: u32,
+    /// Maximum amount of chunk data in Bytes to verify per second, zero means no limit.
+    #[serde(default = "default_bandwidth_rate")]
+    pub bandwidth_rate: u64,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig {
+            interval: default_interval(),
+            remote_sample_rate: 0,
+            bandwidth_rate: default_bandwidth_rate(),
+        }
+    }
+}
+
+/// Decide whether to verify the `index`th chunk of a pass against the storage backend.
+fn sample_remote(index: u64, rate: u32) -> bool {
+    rate != 0 && index % rate as u64 == 0
+}
+
+/// Set of chunks of a blob, indexed by chunk index.
+struct ChunkBitmap(Vec<u64>);
+
+impl ChunkBitmap {
+    fn new(count: u32) -> Self {
+        ChunkBitmap(vec![0; (count as usize + 63) / 64])
+    }
+
+    /// Add the chunk `index` to the set, return false if already added.
+    fn insert(&mut self, index: u32) -> bool {
+        match self.0.get_mut(index as usize / 64) {
+            Some(word) => {
+                let bit = 1u64 << (index % 64);
+                let inserted = *word & bit == 0;
+                *word |= bit;
+                inserted
+            }
+            // Chunks beyond the recorded count of the blob can't be tracked, always verify them.
+            None => true,
+        }
+    }
+}
+
+// Progress of a pass over all chunks.
+struct ScrubPass {
+    start: Instant,
+    index: u64,
+    bytes: u64,
+    scrubbed: Vec<ChunkBitmap>,
+}
+
+impl ScrubPass {
+    // Mark the chunk as verified in this pass, return false if already verified.
+    fn mark(&mut self, blob_index: u32, chunk_index: u32) -> bool {
+        match self.scrubbed.get_mut(blob_index as usize) {
+            Some(bitmap) => bitmap.insert(chunk_index),
+            None => true,
+        }
+    }
+}
+
+// Stop flag of the scrubber thread, with a condition variable to wake it up from throttling.
+type StopSignal = Arc<(Mutex<bool>, Condvar)>;
+
+// Wait until `deadline` unless the scrubber is stopped, return false if stopped.
+fn wait_until(stop: &StopSignal, deadline: Instant) -> bool {
+    let (lock, cvar) = &**stop;
+    let mut stopped = lock.lock().unwrap();
+    loop {
+        if *stopped {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        stopped = cvar.wait_timeout(stopped, deadline - now).unwrap().0;
+    }
+}
+
+/// Handle to the scrubber thread of a filesystem instance.
+pub(crate) struct Scrubber {
+    stop: StopSignal,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Scrubber {
+    /// Start to verify chunks of the filesystem `sb` in background.
+    pub fn start(
+        config: ScrubConfig,
+        sb: Arc<RafsSuper>,
+        device: BlobDevice,
+        ios: Arc<GlobalIoStats>,
+    ) -> std::io::Result<Self> {
+        let stop: StopSignal = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = stop.clone();
+        let handle = std::thread::Builder::new()
+            .name("scrubber".to_string())
+            .spawn(move || {
+                // Scrubbing competes with serving user requests, so run at the lowest priority.
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+                if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, 19) } < 0 {
+                    warn!("failed to lower priority of scrubber, {}", last_error!());
+                }
+                let worker = ScrubWorker {
+                    config,
+                    sb,
+                    device,
+                    ios,
+                    stop: signal,
+                };
+                worker.run();
+            })?;
+
+        Ok(Scrubber {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Stop the scrubber thread and wait for it to exit.
+    pub fn stop(&mut self) {
+        let (lock, cvar) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct ScrubWorker {
+    config: ScrubConfig,
+    sb: Arc<RafsSuper>,
+    device: BlobDevice,
+    ios: Arc<GlobalIoStats>,
+    stop: StopSignal,
+}
+
+impl ScrubWorker {
+    fn run(&self) {
+        loop {
+            let start = Instant::now();
+            match self.scrub() {
+                Ok(true) => {
+                    self.ios.scrub_stats().passes.inc();
+                    info!("scrubbed chunks in {:?}", start.elapsed());
+                }
+                Ok(false) => return,
+                Err(e) => warn!("failed to scrub chunks, {}", e),
+            }
+
+            let next = start + Duration::from_secs(self.config.interval);
+            if !wait_until(&self.stop, next) {
+                return;
+            }
+        }
+    }
+
+    // Verify all chunks of the filesystem once, return false if stopped.
+    //
+    // Directories are walked one at a time and files are verified window by window, so memory
+    // usage doesn't grow with the number of files and chunks of the filesystem.
+    fn scrub(&self) -> std::io::Result<bool> {
+        let mut pass = ScrubPass {
+            start: Instant::now(),
+            index: 0,
+            bytes: 0,
+            scrubbed: self
+                .sb
+                .superblock
+                .get_blob_infos()
+                .iter()
+                .map(|b| ChunkBitmap::new(b.chunk_count()))
+                .collect(),
+        };
+        let mut dirs = vec![self.sb.get_inode(RAFS_ROOT_INODE, false)?];
+
+        while let Some(dir) = dirs.pop() {
+            let mut result = Ok(true);
+            dir.walk_children_inodes(0, &mut |inode, name, ino, _| {
+                if name == DOT || name == DOTDOT {
+                    return Ok(PostWalkAction::Continue);
+                }
+                let inode = match inode {
+                    Some(inode) => inode,
+                    None => self.sb.get_inode(ino, false)?,
+                };
+                if inode.is_dir() {
+                    dirs.push(inode);
+                } else if inode.is_reg() && inode.size() != 0 {
+                    result = self.scrub_file(inode.as_ref(), &mut pass);
+                    if !matches!(result, Ok(true)) {
+                        return Ok(PostWalkAction::Break);
+                    }
+                }
+                Ok(PostWalkAction::Continue)
+            })?;
+            if !result? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Verify chunks of a regular file, return false if stopped.
+    fn scrub_file(&self, inode: &dyn RafsInode, pass: &mut ScrubPass) -> std::io::Result<bool> {
+        let mut offset = 0;
+        while offset < inode.size() {
+            let size = std::cmp::min(SCRUB_WINDOW, inode.size() - offset);
+            let descs = inode.alloc_bio_vecs(offset, size as usize, false)?;
+            offset += size;
+
+            for desc in descs.iter().flat_map(|d| d.bi_vec.iter()) {
+                // Chunks deduplicated among files or crossing windows are verified once.
+                if !pass.mark(desc.blob.blob_index(), desc.chunkinfo.id()) {
+                    continue;
+                }
+                let remote = sample_remote(pass.index, self.config.remote_sample_rate);
+                pass.index += 1;
+
+                let size = desc.chunkinfo.uncompress_size() as u64;
+                match self.device.scrub_chunk(desc, false) {
+                    Ok(true) => {
+                        self.ios.scrub_stats().cached_chunks.inc();
+                        pass.bytes += size;
+                    }
+                    Ok(false) => {}
+                    Err(e) => self.report(inode.ino(), &e, false),
+                }
+                if remote {
+                    match self.device.scrub_chunk(desc, true) {
+                        Ok(true) => {
+                            self.ios.scrub_stats().remote_chunks.inc();
+                            pass.bytes += size;
+                        }
+                        Ok(false) => {}
+                        Err(e) => self.report(inode.ino(), &e, true),
+                    }
+                }
+
+                // Throttle by the bandwidth rate, and check whether stopped in the meanwhile.
+                let mut next = Instant::now();
+                if self.config.bandwidth_rate != 0 {
+                    let expected = Duration::from_secs_f64(
+                        pass.bytes as f64 / self.config.bandwidth_rate as f64,
+                    );
+                    next = std::cmp::max(next, pass.start + expected);
+                }
+                if !wait_until(&self.stop, next) {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn report(&self, ino: u64, err: &std::io::Error, remote: bool) {
+        if ErrorClass::of(err) != ErrorClass::DigestMismatch {
+            // Failures to access the cache or the storage backend aren't corruption.
+            warn!("failed to scrub chunk of file {}, {}", ino, err);
+            return;
+        }
+
+        let stats = self.ios.scrub_stats();
+        let location = if remote {
+            stats.corrupted_remote_chunks.inc();
+            "storage backend"
+        } else {
+            stats.corrupted_cached_chunks.inc();
+            "cache"
+        };
+        self.ios.error_stats().record(ino, err);
+
+        let path = self
+            .sb
+            .path_from_ino(ino)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| format!("inode {}", ino));
+        let event = format!("corrupted chunk of {} found in {}, {}", path, location, err);
+        error!("{}", event);
+        ERROR_HOLDER
+            .lock()
+            .unwrap()
+            .push(&event)
+            .unwrap_or_else(|_| error!("Failed when try to hold event"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_remote() {
+        assert!(!sample_remote(0, 0));
+        assert!(!sample_remote(7, 0));
+        assert!(sample_remote(0, 1));
+        assert!(sample_remote(5, 1));
+        assert!(sample_remote(0, 4));
+        assert!(!sample_remote(3, 4));
+        assert!(sample_remote(8, 4));
+    }
+
+    #[test]
+    fn test_chunk_bitmap() {
+        let mut bitmap = ChunkBitmap::new(65);
+        assert!(bitmap.insert(0));
+        assert!(!bitmap.insert(0));
+        assert!(bitmap.insert(64));
+        assert!(!bitmap.insert(64));
+        assert!(bitmap.insert(63));
+        // Out of range chunks are never deduplicated.
+        assert!(bitmap.insert(128));
+        assert!(bitmap.insert(128));
+    }
+
+    #[test]
+    fn test_wait_until() {
+        let stop: StopSignal = Arc::new((Mutex::new(false), Condvar::new()));
+        assert!(wait_until(&stop, Instant::now()));
+        assert!(wait_until(
+            &stop,
+            Instant::now() + Duration::from_millis(10)
+        ));
+
+        *stop.0.lock().unwrap() = true;
+        assert!(!wait_until(&stop, Instant::now() + Duration::from_secs(60)));
+    }
+}
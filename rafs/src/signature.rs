@@ -0,0 +1,182 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detached signatures to protect Rafs bootstraps from being tampered.
+//!
+//! A signature is generated over the whole bootstrap file and stored as base64 text in a separate
+//! file, `<bootstrap>.sig` by default. Two types of keys in PEM format are supported:
+//! - ed25519 keys, signing the bootstrap directly.
+//! - ECDSA keys, signing the sha256 digest of the bootstrap, which is compatible with signatures
+//!   generated by `cosign sign-blob`.
+
+use std::fs;
+use std::io::{Read, Result, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private};
+use openssl::sign::{Signer, Verifier};
+use serde::Deserialize;
+
+use crate::metadata::RAFS_MAX_METADATA_SIZE;
+use crate::{RafsError, RafsIoRead, RafsIoReader, RafsResult};
+
+/// Suffix of the default signature file name of a bootstrap.
+pub const SIGNATURE_FILE_SUFFIX: &str = ".sig";
+
+/// Configuration information to verify signature of bootstraps.
+#[derive(Clone, Default, Deserialize)]
+pub struct SignatureConfig {
+    /// Path to the public key in PEM format.
+    pub public_key: String,
+    /// Path to the signature file, `<bootstrap>.sig` if not specified.
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl SignatureConfig {
+    /// Verify the bootstrap read from `r`, and replace `r` by a sealed memfd holding the verified
+    /// data, so the bootstrap can't be modified between being verified and being loaded.
+    pub fn verify(&self, r: &mut RafsIoReader) -> RafsResult<()> {
+        if self.signature.is_empty() {
+            return Err(RafsError::VerifySignature(einval!(
+                "no signature file is specified for the bootstrap"
+            )));
+        }
+
+        let key = fs::read(&self.public_key)
+            .map_err(|e| RafsError::VerifySignature(eother!(format!("read public key, {}", e))))?;
+        let signature = fs::read_to_string(&self.signature)
+            .map_err(|e| RafsError::VerifySignature(eother!(format!("read signature, {}", e))))?;
+        let mut data = Vec::new();
+        r.seek(SeekFrom::Start(0))
+            .and_then(|_| {
+                r.by_ref()
+                    .take(RAFS_MAX_METADATA_SIZE as u64 + 1)
+                    .read_to_end(&mut data)
+            })
+            .map_err(|e| RafsError::ReadMetadata(e, "bootstrap".to_string()))?;
+        verify(&key, &data, &signature).map_err(RafsError::VerifySignature)?;
+
+        let file =
+            <dyn RafsIoRead>::memfd_from_reader(data.as_slice(), RAFS_MAX_METADATA_SIZE as u64)?;
+        *r = Box::new(file);
+
+        Ok(())
+    }
+}
+
+/// Get path of the default signature file for `bootstrap`.
+pub fn default_signature_path(bootstrap: &Path) -> PathBuf {
+    let mut path = bootstrap.as_os_str().to_owned();
+    path.push(SIGNATURE_FILE_SUFFIX);
+
+    PathBuf::from(path)
+}
+
+/// Sign `data` with the private key `key` in PEM format, return the base64 encoded signature.
+pub fn sign(key: &[u8], data: &[u8]) -> Result<String> {
+    let key = PKey::private_key_from_pem(key).map_err(|e| einval!(e))?;
+    let mut signer = new_signer(&key)?;
+    let signature = signer.sign_oneshot_to_vec(data).map_err(|e| eother!(e))?;
+
+    Ok(base64::encode_block(&signature))
+}
+
+/// Verify the base64 encoded `signature` of `data` with the public key `key` in PEM format.
+pub fn verify(key: &[u8], data: &[u8], signature: &str) -> Result<()> {
+    let key = PKey::public_key_from_pem(key).map_err(|e| einval!(e))?;
+    let signature = base64::decode_block(signature.trim()).map_err(|e| einval!(e))?;
+    let mut verifier = new_verifier(&key)?;
+
+    match verifier.verify_oneshot(&signature, data) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(einval!("signature mismatches with data")),
+        Err(e) => Err(einval!(e)),
+    }
+}
+
+fn new_signer(key: &PKeyRef<Private>) -> Result<Signer> {
+    let signer = match key.id() {
+        Id::ED25519 => Signer::new_without_digest(key),
+        Id::EC => Signer::new(MessageDigest::sha256(), key),
+        id => return Err(einval!(format!("unsupported signing key type {:?}", id))),
+    };
+
+    signer.map_err(|e| eother!(e))
+}
+
+fn new_verifier<T: HasPublic>(key: &PKeyRef<T>) -> Result<Verifier> {
+    let verifier = match key.id() {
+        Id::ED25519 => Verifier::new_without_digest(key),
+        Id::EC => Verifier::new(MessageDigest::sha256(), key),
+        id => return Err(einval!(format!("unsupported verifying key type {:?}", id))),
+    };
+
+    verifier.map_err(|e| eother!(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ecdsa = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        for key in [ed25519, ecdsa].iter() {
+            let private_key = key.private_key_to_pem_pkcs8().unwrap();
+            let public_key = key.public_key_to_pem().unwrap();
+            let data = b"rafs bootstrap";
+
+            let signature = sign(&private_key, data).unwrap();
+            verify(&public_key, data, &signature).unwrap();
+            verify(&public_key, data, &format!("{}\n", signature)).unwrap();
+            assert!(verify(&public_key, b"tampered bootstrap", &signature).is_err());
+            assert!(verify(&public_key, data, "invalid signature").is_err());
+        }
+    }
+
+    #[test]
+    fn test_verify_bootstrap() {
+        let key = PKey::generate_ed25519().unwrap();
+        let bootstrap = TempFile::new().unwrap();
+        let signature = TempFile::new().unwrap();
+        let public_key = TempFile::new().unwrap();
+        let data = b"rafs bootstrap";
+        fs::write(bootstrap.as_path(), data).unwrap();
+        fs::write(public_key.as_path(), key.public_key_to_pem().unwrap()).unwrap();
+        let private_key = key.private_key_to_pem_pkcs8().unwrap();
+        fs::write(signature.as_path(), sign(&private_key, data).unwrap()).unwrap();
+        let config = SignatureConfig {
+            public_key: public_key.as_path().to_str().unwrap().to_string(),
+            signature: signature.as_path().to_str().unwrap().to_string(),
+        };
+
+        // The verified data is loaded even if the bootstrap is modified afterwards.
+        let mut reader = <dyn RafsIoRead>::from_file(bootstrap.as_path()).unwrap();
+        config.verify(&mut reader).unwrap();
+        fs::write(bootstrap.as_path(), b"tampered bootstrap").unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        let mut reader = <dyn RafsIoRead>::from_file(bootstrap.as_path()).unwrap();
+        assert!(config.verify(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_default_signature_path() {
+        assert_eq!(
+            default_signature_path(Path::new("/path/to/bootstrap")),
+            PathBuf::from("/path/to/bootstrap.sig")
+        );
+    }
+}
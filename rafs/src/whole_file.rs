@@ -0,0 +1,271 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fetch whole files consumed sequentially into the cache.
+//!
+//! Large files read from the beginning to the end, like model weights, are fetched chunk by chunk
+//! on demand, ending up with thousands of small range requests to the storage backend. Once a
+//! file has been read sequentially from its beginning for a number of reads, all data of the file
+//! is fetched into the cache in background, by one backend request for each bounded range of
+//! continuous chunks. Files are fetched one by one by a worker thread, and files failing to be
+//! fetched may be fetched again by following sequential reads. Reads following the fetch wait for
+//! the chunks being fetched instead of fetching them again.
+//!
+//! For storage backends with high per-request overhead, chunk level laziness may be disabled by
+//! `fetch_on_open`, so files are fetched as a whole in background once they are opened for the
+//! first time, without holding the open request.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Result;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::Deserialize;
+use storage::device::{BlobDevice, BlobIoVec};
+
+use crate::metadata::Inode;
+
+// Maximum number of files to track sequential reads of, to bound memory usage.
+const MAX_TRACKED_FILES: usize = 4096;
+// Maximum number of files waiting to be fetched as a whole.
+const MAX_PENDING_FILES: usize = 16;
+
+fn default_min_file_size() -> u64 {
+    16 << 20
+}
+
+fn default_sequential_reads() -> u32 {
+    4
+}
+
+/// Configuration information to fetch whole files read sequentially.
+#[derive(Clone, Deserialize)]
+pub struct WholeFileConfig {
+    /// Minimum size of files to fetch as a whole.
+    #[serde(default = "default_min_file_size")]
+    pub min_file_size: u64,
+    /// Number of sequential reads from the beginning of a file to trigger fetching it as a whole.
+    #[serde(default = "default_sequential_reads")]
+    pub sequential_reads: u32,
+    /// Fetch files as a whole when they are opened, instead of waiting for sequential reads.
+    #[serde(default)]
+    pub fetch_on_open: bool,
+}
+
+impl Default for WholeFileConfig {
+    fn default() -> Self {
+        WholeFileConfig {
+            min_file_size: default_min_file_size(),
+            sequential_reads: default_sequential_reads(),
+            fetch_on_open: false,
+        }
+    }
+}
+
+/// Detector of files read sequentially from their beginning.
+pub(crate) struct SequentialDetector {
+    config: WholeFileConfig,
+    // Offset of the next sequential read and number of sequential reads of files.
+    streams: Mutex<HashMap<Inode, (u64, u32)>>,
+    // Files which have been fetched as a whole.
+    fetched: Mutex<HashSet<Inode>>,
+}
+
+impl SequentialDetector {
+    pub fn new(config: WholeFileConfig) -> Self {
+        SequentialDetector {
+            config,
+            streams: Mutex::new(HashMap::new()),
+            fetched: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Record a read of `size` bytes at `offset` from the file `ino` of `file_size` bytes, return
+    /// true if the whole file should be fetched.
+    pub fn record(&self, ino: Inode, file_size: u64, offset: u64, size: u64) -> bool {
+        if file_size < self.config.min_file_size || self.fetched.lock().unwrap().contains(&ino) {
+            return false;
+        }
+
+        let mut streams = self.streams.lock().unwrap();
+        let reads = match streams.get(&ino).copied() {
+            Some((next, reads)) if next == offset => {
+                streams.insert(ino, (next + size, reads + 1));
+                reads + 1
+            }
+            _ if offset == 0 => {
+                if streams.len() >= MAX_TRACKED_FILES {
+                    streams.clear();
+                }
+                streams.insert(ino, (size, 1));
+                1
+            }
+            _ => {
+                streams.remove(&ino);
+                return false;
+            }
+        };
+        if reads < self.config.sequential_reads {
+            return false;
+        }
+
+        streams.remove(&ino);
+        self.fetched.lock().unwrap().insert(ino)
+    }
+
+    /// Record an open of the file `ino` of `file_size` bytes, return true if the whole file should
+    /// be fetched.
+    pub fn record_open(&self, ino: Inode, file_size: u64) -> bool {
+        if !self.config.fetch_on_open || file_size == 0 || file_size < self.config.min_file_size {
+            return false;
+        }
+
+        self.streams.lock().unwrap().remove(&ino);
+        self.fetched.lock().unwrap().insert(ino)
+    }
+
+    /// Release state of the file `ino`, once it's forgotten by the kernel.
+    pub fn forget(&self, ino: Inode) {
+        self.streams.lock().unwrap().remove(&ino);
+        self.fetched.lock().unwrap().remove(&ino);
+    }
+}
+
+// A file to fetch as a whole.
+struct WholeFile {
+    ino: Inode,
+    descs: Vec<BlobIoVec>,
+    device: BlobDevice,
+}
+
+/// Fetcher of whole files, which are fetched in background one by one.
+pub(crate) struct WholeFileFetcher {
+    detector: Arc<SequentialDetector>,
+    queue: Mutex<Option<SyncSender<WholeFile>>>,
+}
+
+impl WholeFileFetcher {
+    pub fn new(config: WholeFileConfig) -> Self {
+        WholeFileFetcher {
+            detector: Arc::new(SequentialDetector::new(config)),
+            queue: Mutex::new(None),
+        }
+    }
+
+    /// Get the detector deciding which files to fetch.
+    pub fn detector(&self) -> &SequentialDetector {
+        &self.detector
+    }
+
+    /// Fetch all data of the file `ino` described by `descs` in background.
+    ///
+    /// The file is left to be detected again if there are too many files waiting to be fetched.
+    pub fn fetch(&self, ino: Inode, descs: Vec<BlobIoVec>, device: BlobDevice) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_none() {
+            *queue = Some(self.start_worker()?);
+        }
+        let file = WholeFile { ino, descs, device };
+        match queue.as_ref().unwrap().try_send(file) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.detector.forget(ino);
+                Err(eother!("too many files waiting to be fetched"))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                *queue = None;
+                self.detector.forget(ino);
+                Err(eother!("worker to fetch whole files has exited"))
+            }
+        }
+    }
+
+    // Start the worker fetching queued files, which exits once the fetcher is dropped.
+    fn start_worker(&self) -> Result<SyncSender<WholeFile>> {
+        let (sender, receiver) = sync_channel::<WholeFile>(MAX_PENDING_FILES);
+        let detector = self.detector.clone();
+        thread::Builder::new()
+            .name("whole_file_fetch".to_string())
+            .spawn(move || {
+                while let Ok(file) = receiver.recv() {
+                    fetch_whole_file(&detector, file.ino, &file.descs, &file.device);
+                }
+            })?;
+
+        Ok(sender)
+    }
+}
+
+// Fetch all data of the file `ino` described by `descs`, and let the file be detected again if
+// it fails to be fetched.
+fn fetch_whole_file(
+    detector: &SequentialDetector,
+    ino: Inode,
+    descs: &[BlobIoVec],
+    device: &BlobDevice,
+) {
+    match device.fetch_io_vecs(descs) {
+        Ok(size) => info!("fetched whole file {}, {} bytes from backend", ino, size),
+        Err(e) => {
+            warn!("failed to fetch whole file {}, {}", ino, e);
+            detector.forget(ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequential_detector() {
+        let detector = SequentialDetector::new(WholeFileConfig {
+            min_file_size: 0x10000,
+            sequential_reads: 3,
+            fetch_on_open: false,
+        });
+
+        // Small files are never fetched as a whole.
+        assert!(!detector.record(1, 0x1000, 0, 0x1000));
+
+        assert!(!detector.record(2, 0x10000, 0, 0x1000));
+        assert!(!detector.record(2, 0x10000, 0x1000, 0x1000));
+        assert!(detector.record(2, 0x10000, 0x2000, 0x1000));
+        assert!(!detector.record(2, 0x10000, 0x3000, 0x1000));
+        assert!(!detector.record(2, 0x10000, 0, 0x1000));
+
+        // Random reads reset the detection.
+        assert!(!detector.record(3, 0x10000, 0, 0x1000));
+        assert!(!detector.record(3, 0x10000, 0x8000, 0x1000));
+        assert!(!detector.record(3, 0x10000, 0x9000, 0x1000));
+        assert!(!detector.record(3, 0x10000, 0xa000, 0x1000));
+
+        detector.forget(2);
+        assert!(!detector.record(2, 0x10000, 0, 0x1000));
+        assert!(!detector.record(2, 0x10000, 0x1000, 0x1000));
+        assert!(detector.record(2, 0x10000, 0x2000, 0x1000));
+        assert!(!detector.record_open(2, 0x10000));
+    }
+
+    #[test]
+    fn test_fetch_on_open() {
+        let detector = SequentialDetector::new(WholeFileConfig {
+            min_file_size: 0,
+            sequential_reads: 3,
+            fetch_on_open: true,
+        });
+
+        assert!(!detector.record_open(1, 0));
+        assert!(detector.record_open(2, 0x1000));
+        assert!(!detector.record_open(2, 0x1000));
+        // Files fetched on open aren't fetched again by sequential reads.
+        assert!(!detector.record(2, 0x1000, 0, 0x400));
+        assert!(!detector.record(2, 0x1000, 0x400, 0x400));
+        assert!(!detector.record(2, 0x1000, 0x800, 0x400));
+
+        detector.forget(2);
+        assert!(detector.record_open(2, 0x1000));
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A benchmark tool to replay I/O workloads against a nydus filesystem.
+
+#![deny(warnings)]
+#[macro_use(crate_version)]
+extern crate clap;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate anyhow;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::{App, Arg};
+use nydus_app::{setup_logging, BuildTimeInfo};
+
+use crate::mount::NydusdMount;
+use crate::stats::{OpStats, Report};
+use crate::workload::{Op, OpKind, WorkloadType};
+
+mod mount;
+mod stats;
+mod workload;
+
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Execute `ops` under `root` and collect statistics by operation kind.
+fn run_ops(root: &Path, ops: &[Op], block_size: usize) -> BTreeMap<OpKind, OpStats> {
+    let mut stats: BTreeMap<OpKind, OpStats> = BTreeMap::new();
+    let mut buf = vec![0u8; block_size];
+
+    for op in ops {
+        let start = Instant::now();
+        let result = op.execute(root, &mut buf);
+        let latency = start.elapsed();
+        let entry = stats.entry(op.kind()).or_default();
+        match result {
+            Ok(bytes) => entry.record(latency, bytes),
+            Err(e) => {
+                debug!("failed to execute {:?}, {:?}", op, e);
+                entry.record_error();
+            }
+        }
+    }
+
+    stats
+}
+
+/// Distribute `ops` to `threads` worker threads in a round-robin way and run them concurrently.
+fn run(name: &str, root: &Path, ops: Vec<Op>, threads: usize, block_size: usize) -> Result<Report> {
+    let mut queues = vec![Vec::new(); threads];
+    for (idx, op) in ops.into_iter().enumerate() {
+        queues[idx % threads].push(op);
+    }
+
+    let start = Instant::now();
+    let handles = queues
+        .into_iter()
+        .enumerate()
+        .map(|(idx, ops)| {
+            let root = root.to_path_buf();
+            thread::Builder::new()
+                .name(format!("bench_worker_{}", idx))
+                .spawn(move || run_ops(&root, &ops, block_size))
+                .context("failed to spawn worker thread")
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut stats: BTreeMap<OpKind, OpStats> = BTreeMap::new();
+    for handle in handles {
+        let result = handle
+            .join()
+            .map_err(|_| anyhow!("worker thread panicked"))?;
+        for (kind, s) in result {
+            stats.entry(kind).or_default().merge(s);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    Ok(Report::new(name, threads, elapsed, stats))
+}
+
+fn main() -> Result<()> {
+    let (bti_string, _) = BuildTimeInfo::dump(crate_version!());
+
+    let cmd = App::new("")
+        .version(bti_string.as_str())
+        .about("Replay I/O workloads against a nydus filesystem and report throughput and latency.")
+        .arg(
+            Arg::with_name("mountpoint")
+                .long("mountpoint")
+                .short("M")
+                .help("Mountpoint of the nydus filesystem")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("bootstrap")
+                .long("bootstrap")
+                .short("B")
+                .help("Bootstrap file to mount at the mountpoint by spawning nydusd, otherwise the filesystem should have been mounted")
+                .takes_value(true)
+                .requires("config"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("C")
+                .help("Configuration file of nydusd to mount the bootstrap")
+                .takes_value(true)
+                .requires("bootstrap"),
+        )
+        .arg(
+            Arg::with_name("nydusd")
+                .long("nydusd")
+                .help("Path to the nydusd binary")
+                .takes_value(true)
+                .default_value("nydusd"),
+        )
+        .arg(
+            Arg::with_name("workload")
+                .long("workload")
+                .short("w")
+                .help("Type of the I/O workload to replay")
+                .takes_value(true)
+                .default_value("sequential")
+                .possible_values(&["sequential", "random", "metadata", "trace"]),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .short("t")
+                .help("Trace file recording operations to replay, which implies the trace workload")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("block-size")
+                .long("block-size")
+                .short("b")
+                .help("Size of read requests in bytes")
+                .takes_value(true)
+                .default_value("131072"),
+        )
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .short("n")
+                .help("Number of read requests of the random workload")
+                .takes_value(true)
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .help("Seed to generate the random workload, for repeatable benchmarks")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .short("T")
+                .help("Number of worker threads to replay the workload")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("output-json")
+                .long("output-json")
+                .short("J")
+                .help("JSON file to save the benchmark report")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .short("l")
+                .help("Specify the logging level")
+                .default_value("info")
+                .possible_values(&["trace", "debug", "info", "warn", "error"])
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let level = cmd.value_of("log-level").unwrap().parse().unwrap();
+    setup_logging(None, level)?;
+
+    let mountpoint = PathBuf::from(cmd.value_of("mountpoint").unwrap());
+    let block_size: usize = cmd
+        .value_of("block-size")
+        .unwrap()
+        .parse()
+        .context("invalid block size")?;
+    if block_size == 0 {
+        bail!("block size must be greater than zero");
+    }
+    let count: usize = cmd
+        .value_of("count")
+        .unwrap()
+        .parse()
+        .context("invalid request count")?;
+    let seed: u64 = cmd
+        .value_of("seed")
+        .unwrap()
+        .parse()
+        .context("invalid seed")?;
+    let threads: usize = cmd
+        .value_of("threads")
+        .unwrap()
+        .parse()
+        .context("invalid thread number")?;
+    if threads == 0 {
+        bail!("thread number must be greater than zero");
+    }
+    let workload: WorkloadType = if cmd.is_present("trace") {
+        WorkloadType::Trace
+    } else {
+        cmd.value_of("workload").unwrap().parse()?
+    };
+    if workload == WorkloadType::Trace && !cmd.is_present("trace") {
+        bail!("trace workload requires a trace file specified by --trace");
+    }
+
+    let _mount = match cmd.value_of("bootstrap") {
+        Some(bootstrap) => Some(NydusdMount::new(
+            cmd.value_of("nydusd").unwrap(),
+            Path::new(bootstrap),
+            Path::new(cmd.value_of("config").unwrap()),
+            &mountpoint,
+            MOUNT_TIMEOUT,
+        )?),
+        None => None,
+    };
+
+    let ops = match cmd.value_of("trace") {
+        Some(trace) => workload::load_trace(Path::new(trace))?,
+        None => workload::generate(&mountpoint, workload, block_size as u64, count, seed)?,
+    };
+    info!(
+        "replaying {} operations with {} threads",
+        ops.len(),
+        threads
+    );
+
+    let name = match cmd.value_of("trace") {
+        Some(trace) => format!("trace {}", trace),
+        None => cmd.value_of("workload").unwrap().to_string(),
+    };
+    let report = run(&name, &mountpoint, ops, threads, block_size)?;
+    print!("{}", report);
+
+    if let Some(path) = cmd.value_of("output-json") {
+        let file = File::create(path).with_context(|| format!("failed to create {}", path))?;
+        serde_json::to_writer_pretty(file, &report)
+            .with_context(|| format!("failed to write report to {}", path))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mount a nydus image by spawning nydusd for benchmarking.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+const MOUNT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A nydusd process serving the mounted image, which is stopped on drop.
+pub struct NydusdMount {
+    child: Child,
+}
+
+impl NydusdMount {
+    /// Spawn `nydusd` to mount `bootstrap` at `mountpoint` with configuration file `config`, and
+    /// wait until the filesystem is ready.
+    pub fn new(
+        nydusd: &str,
+        bootstrap: &Path,
+        config: &Path,
+        mountpoint: &Path,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let child = Command::new(nydusd)
+            .arg("--bootstrap")
+            .arg(bootstrap)
+            .arg("--config")
+            .arg(config)
+            .arg("--mountpoint")
+            .arg(mountpoint)
+            .args(&["--log-level", "warn"])
+            .spawn()
+            .with_context(|| format!("failed to spawn {}", nydusd))?;
+        let mut mount = NydusdMount { child };
+
+        let start = Instant::now();
+        while !is_mountpoint(mountpoint)? {
+            if let Some(status) = mount.child.try_wait()? {
+                bail!("nydusd exited before the image is mounted, {}", status);
+            }
+            if start.elapsed() > timeout {
+                bail!("timeout to wait for nydusd to mount {:?}", mountpoint);
+            }
+            thread::sleep(MOUNT_CHECK_INTERVAL);
+        }
+        info!(
+            "image mounted at {:?} in {} ms",
+            mountpoint,
+            start.elapsed().as_millis()
+        );
+
+        Ok(mount)
+    }
+}
+
+impl Drop for NydusdMount {
+    fn drop(&mut self) {
+        // nydusd umounts the filesystem on exit.
+        let pid = Pid::from_raw(self.child.id() as i32);
+        if let Err(e) = kill(pid, Signal::SIGTERM) {
+            warn!("failed to stop nydusd, {}", e);
+        }
+        if let Err(e) = self.child.wait() {
+            warn!("failed to wait for nydusd, {}", e);
+        }
+    }
+}
+
+/// Check whether a filesystem is mounted at `path`, by comparing device of `path` and its parent.
+fn is_mountpoint(path: &Path) -> Result<bool> {
+    let dev = path
+        .metadata()
+        .with_context(|| format!("failed to stat {:?}", path))?
+        .dev();
+    let parent = path.join("..");
+    let parent_dev = parent
+        .metadata()
+        .with_context(|| format!("failed to stat {:?}", parent))?
+        .dev();
+
+    Ok(dev != parent_dev)
+}
@@ -0,0 +1,195 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Throughput and latency statistics of benchmark operations.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::workload::OpKind;
+
+/// Latency samples and data size of operations of the same kind.
+#[derive(Default)]
+pub struct OpStats {
+    latencies: Vec<Duration>,
+    bytes: u64,
+    errors: u64,
+}
+
+impl OpStats {
+    pub fn record(&mut self, latency: Duration, bytes: u64) {
+        self.latencies.push(latency);
+        self.bytes += bytes;
+    }
+
+    pub fn record_error(&mut self) {
+        self.errors += 1;
+    }
+
+    pub fn merge(&mut self, other: OpStats) {
+        self.latencies.extend(other.latencies);
+        self.bytes += other.bytes;
+        self.errors += other.errors;
+    }
+}
+
+/// Latency percentile in microseconds, with the nearest-rank method.
+fn percentile(sorted: &[Duration], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1) - 1].as_micros() as u64
+}
+
+/// Summary of operations of the same kind.
+#[derive(Debug, Serialize)]
+pub struct OpSummary {
+    pub ops: u64,
+    pub errors: u64,
+    pub bytes: u64,
+    pub latency_avg_us: u64,
+    pub latency_p50_us: u64,
+    pub latency_p90_us: u64,
+    pub latency_p99_us: u64,
+    pub latency_max_us: u64,
+}
+
+impl From<OpStats> for OpSummary {
+    fn from(mut stats: OpStats) -> Self {
+        stats.latencies.sort_unstable();
+        let ops = stats.latencies.len() as u64;
+        let total: Duration = stats.latencies.iter().sum();
+        let latency_avg_us = if ops == 0 {
+            0
+        } else {
+            total.as_micros() as u64 / ops
+        };
+
+        OpSummary {
+            ops,
+            errors: stats.errors,
+            bytes: stats.bytes,
+            latency_avg_us,
+            latency_p50_us: percentile(&stats.latencies, 50.0),
+            latency_p90_us: percentile(&stats.latencies, 90.0),
+            latency_p99_us: percentile(&stats.latencies, 99.0),
+            latency_max_us: percentile(&stats.latencies, 100.0),
+        }
+    }
+}
+
+/// Report of a benchmark run.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub workload: String,
+    pub threads: usize,
+    pub elapsed_ms: u64,
+    pub bytes: u64,
+    /// Read throughput in bytes per second.
+    pub throughput: u64,
+    pub ops_per_sec: u64,
+    pub ops: BTreeMap<&'static str, OpSummary>,
+}
+
+impl Report {
+    pub fn new(
+        workload: &str,
+        threads: usize,
+        elapsed: Duration,
+        stats: BTreeMap<OpKind, OpStats>,
+    ) -> Self {
+        let ops: BTreeMap<&'static str, OpSummary> = stats
+            .into_iter()
+            .map(|(kind, stats)| (kind.name(), OpSummary::from(stats)))
+            .collect();
+        let bytes = ops.values().map(|s| s.bytes).sum();
+        let nr_ops: u64 = ops.values().map(|s| s.ops).sum();
+        let secs = elapsed.as_secs_f64();
+        let (throughput, ops_per_sec) = if secs > 0.0 {
+            ((bytes as f64 / secs) as u64, (nr_ops as f64 / secs) as u64)
+        } else {
+            (0, 0)
+        };
+
+        Report {
+            workload: workload.to_string(),
+            threads,
+            elapsed_ms: elapsed.as_millis() as u64,
+            bytes,
+            throughput,
+            ops_per_sec,
+            ops,
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "workload {}, {} threads, elapsed {} ms",
+            self.workload, self.threads, self.elapsed_ms
+        )?;
+        writeln!(
+            f,
+            "read {} bytes, throughput {:.2} MB/s, {} ops/s",
+            self.bytes,
+            self.throughput as f64 / 1_048_576.0,
+            self.ops_per_sec
+        )?;
+        writeln!(
+            f,
+            "{:<8} {:>10} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "op", "count", "errors", "avg(us)", "p50(us)", "p90(us)", "p99(us)", "max(us)"
+        )?;
+        for (name, s) in self.ops.iter() {
+            writeln!(
+                f,
+                "{:<8} {:>10} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                name,
+                s.ops,
+                s.errors,
+                s.latency_avg_us,
+                s.latency_p50_us,
+                s.latency_p90_us,
+                s.latency_p99_us,
+                s.latency_max_us
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_op_summary() {
+        let mut stats = OpStats::default();
+        for us in (1..=100).rev() {
+            stats.record(Duration::from_micros(us), 10);
+        }
+        stats.record_error();
+
+        let summary = OpSummary::from(stats);
+        assert_eq!(summary.ops, 100);
+        assert_eq!(summary.errors, 1);
+        assert_eq!(summary.bytes, 1000);
+        assert_eq!(summary.latency_avg_us, 50);
+        assert_eq!(summary.latency_p50_us, 50);
+        assert_eq!(summary.latency_p90_us, 90);
+        assert_eq!(summary.latency_p99_us, 99);
+        assert_eq!(summary.latency_max_us, 100);
+
+        let summary = OpSummary::from(OpStats::default());
+        assert_eq!(summary.ops, 0);
+        assert_eq!(summary.latency_p99_us, 0);
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! I/O workloads to replay against a mounted filesystem.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+/// Kinds of workloads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkloadType {
+    /// Read all regular files from the beginning to the end, in the order of directory walk.
+    Sequential,
+    /// Read blocks at random offsets of random regular files.
+    Random,
+    /// Stat all entries and list all directories, without reading file data.
+    Metadata,
+    /// Replay operations recorded in a trace file.
+    Trace,
+}
+
+impl FromStr for WorkloadType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sequential" => Ok(Self::Sequential),
+            "random" => Ok(Self::Random),
+            "metadata" => Ok(Self::Metadata),
+            "trace" => Ok(Self::Trace),
+            _ => Err(anyhow!("invalid workload type {}", s)),
+        }
+    }
+}
+
+/// Kinds of operations, to classify latency statistics.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum OpKind {
+    Read,
+    Stat,
+    Readdir,
+}
+
+impl OpKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Stat => "stat",
+            Self::Readdir => "readdir",
+        }
+    }
+}
+
+/// A single operation, with paths relative to the mountpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    /// Read `size` bytes at `offset`, `None` size means reading to the end of file.
+    Read {
+        path: PathBuf,
+        offset: u64,
+        size: Option<u64>,
+    },
+    Stat(PathBuf),
+    Readdir(PathBuf),
+}
+
+impl Op {
+    pub fn kind(&self) -> OpKind {
+        match self {
+            Self::Read { .. } => OpKind::Read,
+            Self::Stat(_) => OpKind::Stat,
+            Self::Readdir(_) => OpKind::Readdir,
+        }
+    }
+
+    /// Execute the operation under `root`, return number of bytes read.
+    pub fn execute(&self, root: &Path, buf: &mut [u8]) -> Result<u64> {
+        match self {
+            Self::Read { path, offset, size } => {
+                let path = root.join(path);
+                let file =
+                    File::open(&path).with_context(|| format!("failed to open {:?}", path))?;
+                let mut total = 0u64;
+                loop {
+                    let len = match size {
+                        Some(size) if *size - total < buf.len() as u64 => (*size - total) as usize,
+                        _ => buf.len(),
+                    };
+                    if len == 0 {
+                        break;
+                    }
+                    let n = file
+                        .read_at(&mut buf[..len], offset + total)
+                        .with_context(|| format!("failed to read {:?}", path))?;
+                    if n == 0 {
+                        break;
+                    }
+                    total += n as u64;
+                }
+                Ok(total)
+            }
+            Self::Stat(path) => {
+                let path = root.join(path);
+                fs::symlink_metadata(&path)
+                    .with_context(|| format!("failed to stat {:?}", path))?;
+                Ok(0)
+            }
+            Self::Readdir(path) => {
+                let path = root.join(path);
+                for entry in
+                    fs::read_dir(&path).with_context(|| format!("failed to list {:?}", path))?
+                {
+                    entry?;
+                }
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// Deterministic pseudo random number generator, so random workloads are repeatable.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // The state must be non-zero.
+        Self((seed ^ 0x9e37_79b9_7f4a_7c15).max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Relative paths and sizes of regular files.
+type FileList = Vec<(PathBuf, u64)>;
+
+/// Walk the filesystem under `root`, return relative paths of directories, and relative paths
+/// and sizes of regular files, in a stable order.
+fn walk(root: &Path) -> Result<(Vec<PathBuf>, FileList)> {
+    let mut dirs = vec![PathBuf::new()];
+    let mut files = Vec::new();
+    let mut idx = 0;
+
+    while idx < dirs.len() {
+        let dir = root.join(&dirs[idx]);
+        let mut entries = fs::read_dir(&dir)
+            .with_context(|| format!("failed to list {:?}", dir))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = dirs[idx].join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file() {
+                files.push((path, entry.metadata()?.len()));
+            }
+        }
+        idx += 1;
+    }
+
+    Ok((dirs, files))
+}
+
+/// Generate operations of a synthetic workload over the filesystem mounted at `root`.
+pub fn generate(
+    root: &Path,
+    workload: WorkloadType,
+    block_size: u64,
+    count: usize,
+    seed: u64,
+) -> Result<Vec<Op>> {
+    let (dirs, files) = walk(root)?;
+
+    let ops = match workload {
+        WorkloadType::Sequential => files
+            .into_iter()
+            .map(|(path, _)| Op::Read {
+                path,
+                offset: 0,
+                size: None,
+            })
+            .collect(),
+        WorkloadType::Random => {
+            let files: Vec<_> = files.into_iter().filter(|(_, size)| *size > 0).collect();
+            if files.is_empty() {
+                bail!("no regular file with data to read");
+            }
+            let mut rng = XorShift::new(seed);
+            (0..count)
+                .map(|_| {
+                    let (path, size) = &files[(rng.next() % files.len() as u64) as usize];
+                    let blocks = (size + block_size - 1) / block_size;
+                    Op::Read {
+                        path: path.clone(),
+                        offset: (rng.next() % blocks) * block_size,
+                        size: Some(block_size),
+                    }
+                })
+                .collect()
+        }
+        WorkloadType::Metadata => {
+            let mut ops: Vec<Op> = dirs.iter().cloned().map(Op::Readdir).collect();
+            ops.extend(dirs.into_iter().skip(1).map(Op::Stat));
+            ops.extend(files.into_iter().map(|(path, _)| Op::Stat(path)));
+            ops
+        }
+        WorkloadType::Trace => bail!("trace workload must be loaded from a trace file"),
+    };
+
+    Ok(ops)
+}
+
+/// Load operations from a trace file.
+///
+/// Each line of the trace file is one of:
+/// - `<path>`: read the whole file, as generated by `nydus-image prefetch-list`.
+/// - `read\t<path>[\t<offset>\t<size>]`: read the whole file or a range of the file.
+/// - `stat\t<path>`: get attributes of the entry.
+/// - `readdir\t<path>`: list the directory.
+///
+/// Fields are separated by tabs, so paths may contain spaces. Empty lines and lines starting with
+/// `#` are ignored. Paths are relative to the root of the filesystem, with or without the leading
+/// `/`.
+pub fn load_trace(path: &Path) -> Result<Vec<Op>> {
+    let file = File::open(path).with_context(|| format!("failed to open trace {:?}", path))?;
+    parse_trace(BufReader::new(file)).with_context(|| format!("invalid trace {:?}", path))
+}
+
+fn parse_trace<R: BufRead>(reader: R) -> Result<Vec<Op>> {
+    let mut ops = Vec::new();
+
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let op = match fields.as_slice() {
+            [path] => Op::Read {
+                path: relative_path(path),
+                offset: 0,
+                size: None,
+            },
+            ["read", path] => Op::Read {
+                path: relative_path(path),
+                offset: 0,
+                size: None,
+            },
+            ["read", path, offset, size] => Op::Read {
+                path: relative_path(path),
+                offset: offset
+                    .parse()
+                    .with_context(|| format!("invalid offset at line {}", idx + 1))?,
+                size: Some(
+                    size.parse()
+                        .with_context(|| format!("invalid size at line {}", idx + 1))?,
+                ),
+            },
+            ["stat", path] => Op::Stat(relative_path(path)),
+            ["readdir", path] => Op::Readdir(relative_path(path)),
+            _ => bail!("invalid operation at line {}: {}", idx + 1, line),
+        };
+        ops.push(op);
+    }
+
+    Ok(ops)
+}
+
+fn relative_path(path: &str) -> PathBuf {
+    PathBuf::from(path.trim_start_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_parse_trace() {
+        let trace = "# comment\n/a/b\n\nread\tc\t4096\t8192\nstat\t/d\nreaddir\td/\n/e f\n\
+                     read\tg h\nread\ti j\t0\t1\n";
+        let ops = parse_trace(trace.as_bytes()).unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Read {
+                    path: PathBuf::from("a/b"),
+                    offset: 0,
+                    size: None
+                },
+                Op::Read {
+                    path: PathBuf::from("c"),
+                    offset: 4096,
+                    size: Some(8192)
+                },
+                Op::Stat(PathBuf::from("d")),
+                Op::Readdir(PathBuf::from("d/")),
+                Op::Read {
+                    path: PathBuf::from("e f"),
+                    offset: 0,
+                    size: None
+                },
+                Op::Read {
+                    path: PathBuf::from("g h"),
+                    offset: 0,
+                    size: None
+                },
+                Op::Read {
+                    path: PathBuf::from("i j"),
+                    offset: 0,
+                    size: Some(1)
+                },
+            ]
+        );
+
+        assert!(parse_trace("read\ta\t1\n".as_bytes()).is_err());
+        assert!(parse_trace("write\ta\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_generate() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.as_path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("a"), vec![1u8; 10000]).unwrap();
+        fs::write(root.join("sub/b"), vec![2u8; 100]).unwrap();
+        fs::write(root.join("empty"), b"").unwrap();
+
+        let ops = generate(root, WorkloadType::Sequential, 4096, 0, 0).unwrap();
+        assert_eq!(ops.len(), 3);
+        let mut buf = vec![0u8; 4096];
+        let total: u64 = ops
+            .iter()
+            .map(|op| op.execute(root, &mut buf).unwrap())
+            .sum();
+        assert_eq!(total, 10100);
+
+        let ops = generate(root, WorkloadType::Random, 4096, 100, 1).unwrap();
+        assert_eq!(ops.len(), 100);
+        assert_eq!(
+            ops,
+            generate(root, WorkloadType::Random, 4096, 100, 1).unwrap()
+        );
+        for op in ops.iter() {
+            assert!(op.execute(root, &mut buf).unwrap() > 0);
+        }
+
+        let ops = generate(root, WorkloadType::Metadata, 4096, 0, 0).unwrap();
+        assert_eq!(ops.len(), 6);
+        assert!(ops.iter().all(|op| op.execute(root, &mut buf).is_ok()));
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Build an image layer from changes between two directories, e.g.
+//!
+//! ```
+//! nydus-image create ... --diff /path/to/lower /path/to/upper
+//! ```
+//!
+//! Where both directories are complete root filesystems, such as a pair of committed snapshots.
+//! Files added or modified in the upper directory are dumped into the layer, and files missing
+//! from the upper directory are represented by whiteouts as per `--whiteout-spec`.
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::builder::Builder;
+use crate::core::blob::Blob;
+use crate::core::bootstrap::Bootstrap;
+use crate::core::context::{
+    BlobContext, BlobManager, BootstrapManager, BuildContext, BuildOutput, RafsVersion,
+};
+use crate::core::node::{Node, Overlay};
+use crate::core::tree::Tree;
+
+const COMPARE_BUF_SIZE: usize = 0x10000;
+
+struct DirDiffTreeBuilder<'a> {
+    ctx: &'a BuildContext,
+    lower_root: &'a Path,
+}
+
+impl<'a> DirDiffTreeBuilder<'a> {
+    fn new_node(&self, root: &Path, path: PathBuf, overlay: Overlay) -> Result<Node> {
+        Node::new(
+            self.ctx.fs_version,
+            root.to_path_buf(),
+            path.clone(),
+            overlay,
+            self.ctx.chunk_size,
+            self.ctx.explicit_uidgid,
+        )
+        .with_context(|| format!("failed to create node {:?}", path))
+    }
+
+    /// Load the whole subtree of an entry added to the upper directory.
+    fn load_tree(&self, node: Node) -> Result<Tree> {
+        let mut tree = Tree::new(node);
+        if !tree.node.is_dir() {
+            return Ok(tree);
+        }
+
+        let entries = fs::read_dir(tree.node.path())
+            .with_context(|| format!("failed to read dir {:?}", tree.node.path()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let child = self.new_node(&self.ctx.source_path, path, Overlay::UpperAddition)?;
+            tree.children.push(self.load_tree(child)?);
+        }
+
+        Ok(tree)
+    }
+
+    /// Compare children of the upper directory `upper` with the lower directory at `lower`,
+    /// return trees of changed entries.
+    fn diff_children(&self, upper: &Node, lower: &Path) -> Result<Vec<Tree>> {
+        let mut result = Vec::new();
+        let mut lower_names = fs::read_dir(lower)
+            .with_context(|| format!("failed to read dir {:?}", lower))?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<std::io::Result<BTreeSet<OsString>>>()?;
+
+        let entries = fs::read_dir(upper.path())
+            .with_context(|| format!("failed to read dir {:?}", upper.path()))?;
+        for entry in entries {
+            let entry = entry?;
+            let node =
+                self.new_node(&self.ctx.source_path, entry.path(), Overlay::UpperAddition)?;
+            // Additions
+            if !lower_names.remove(&entry.file_name()) {
+                result.push(self.load_tree(node)?);
+                continue;
+            }
+
+            let lower_node = self.new_node(
+                self.lower_root,
+                lower.join(entry.file_name()),
+                Overlay::Lower,
+            )?;
+            if node.is_dir() && lower_node.is_dir() {
+                let children = self.diff_children(&node, lower_node.path())?;
+                if !children.is_empty() || !Self::is_unchanged(&node, &lower_node)? {
+                    let mut tree = Tree::new(node);
+                    tree.children = children;
+                    result.push(tree);
+                }
+            } else if !Self::is_unchanged(&node, &lower_node)? {
+                // Modifications, children of the lower entry are hidden if it's replaced by
+                // a non-directory.
+                result.push(self.load_tree(node)?);
+            }
+        }
+
+        // Removals
+        for name in lower_names {
+            let lower_node = self.new_node(self.lower_root, lower.join(name), Overlay::Lower)?;
+            let whiteout = lower_node.create_whiteout(self.ctx)?;
+            result.push(Tree::new(whiteout));
+        }
+
+        Ok(result)
+    }
+
+    /// Check whether an upper entry is the same as the lower entry.
+    ///
+    /// Like other snapshot differs, regular files are compared by metadata, and only compared by
+    /// content if modification time has been truncated to seconds.
+    fn is_unchanged(upper: &Node, lower: &Node) -> Result<bool> {
+        if upper.inode.mode() != lower.inode.mode()
+            || upper.inode.uid() != lower.inode.uid()
+            || upper.inode.gid() != lower.inode.gid()
+            || upper.inode.mtime() != lower.inode.mtime()
+            || upper.inode.mtime_nsec() != lower.inode.mtime_nsec()
+            || upper.rdev != lower.rdev
+            || upper.symlink != lower.symlink
+            || upper.xattrs != lower.xattrs
+        {
+            return Ok(false);
+        }
+
+        if upper.is_reg() {
+            if upper.inode.size() != lower.inode.size() {
+                return Ok(false);
+            }
+            if upper.inode.mtime_nsec() == 0 {
+                return Self::is_same_content(upper.path(), lower.path());
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn is_same_content(upper: &Path, lower: &Path) -> Result<bool> {
+        let mut upper_file =
+            File::open(upper).with_context(|| format!("failed to open file {:?}", upper))?;
+        let mut lower_file =
+            File::open(lower).with_context(|| format!("failed to open file {:?}", lower))?;
+        let mut upper_buf = vec![0u8; COMPARE_BUF_SIZE];
+        let mut lower_buf = vec![0u8; COMPARE_BUF_SIZE];
+
+        loop {
+            let size = upper_file.read(&mut upper_buf)?;
+            if size == 0 {
+                return Ok(lower_file.read(&mut lower_buf[..1])? == 0);
+            }
+            if lower_file.read_exact(&mut lower_buf[..size]).is_err()
+                || upper_buf[..size] != lower_buf[..size]
+            {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+pub(crate) struct DirDiffBuilder {
+    lower: PathBuf,
+}
+
+impl DirDiffBuilder {
+    pub fn new(lower: PathBuf) -> Self {
+        Self { lower }
+    }
+
+    /// Build node tree holding changes from the lower directory to the upper directory.
+    fn build_tree_from_diff(&mut self, ctx: &BuildContext) -> Result<Tree> {
+        let tree_builder = DirDiffTreeBuilder {
+            ctx,
+            lower_root: &self.lower,
+        };
+        let root = tree_builder.new_node(
+            &ctx.source_path,
+            ctx.source_path.clone(),
+            Overlay::UpperAddition,
+        )?;
+        let children = timing_tracer!(
+            { tree_builder.diff_children(&root, &self.lower) },
+            "load_from_directory"
+        )?;
+
+        let mut tree = Tree::new(root);
+        tree.children = children;
+
+        Ok(tree)
+    }
+}
+
+impl Builder for DirDiffBuilder {
+    fn build(
+        &mut self,
+        ctx: &mut BuildContext,
+        bootstrap_mgr: &mut BootstrapManager,
+        blob_mgr: &mut BlobManager,
+    ) -> Result<BuildOutput> {
+        let mut bootstrap_ctx = bootstrap_mgr.create_ctx()?;
+        let mut tree = self
+            .build_tree_from_diff(ctx)
+            .context("failed to build tree from directory diff")?;
+        let mut bootstrap = Bootstrap::new()?;
+        // Whiteouts are kept in the layer unless there's a parent bootstrap to apply to.
+        if bootstrap_ctx.layered {
+            ctx.prefetch.disable();
+            bootstrap.build(ctx, &mut bootstrap_ctx, &mut tree)?;
+            tree = bootstrap.apply(ctx, &mut bootstrap_ctx, bootstrap_mgr, blob_mgr, None)?;
+        }
+        // Convert the hierarchy tree into an array, stored in `bootstrap_ctx.nodes`.
+        timing_tracer!(
+            { bootstrap.build(ctx, &mut bootstrap_ctx, &mut tree) },
+            "build_bootstrap"
+        )?;
+
+        // Dump blob file
+        let mut blob_ctx = BlobContext::new(ctx.blob_id.clone(), ctx.blob_storage.clone())?;
+        blob_ctx.set_chunk_dict(blob_mgr.get_chunk_dict());
+        blob_ctx.set_chunk_size(ctx.chunk_size);
+        blob_ctx.set_meta_info_enabled(true);
+        blob_mgr.extend_blob_table_from_chunk_dict()?;
+
+        let blob_index = blob_mgr.alloc_index()?;
+        let mut blob = Blob::new();
+        let blob_exists = timing_tracer!(
+            {
+                blob.dump(
+                    ctx,
+                    &mut blob_ctx,
+                    blob_index,
+                    &mut bootstrap_ctx.nodes,
+                    &mut blob_mgr.chunk_dict_cache,
+                )
+            },
+            "dump_blob"
+        )?;
+
+        // Add new blob to blob table
+        blob_mgr.add(if blob_exists { Some(blob_ctx) } else { None });
+
+        // Dump bootstrap file
+        match ctx.fs_version {
+            RafsVersion::V5 => {
+                let blob_table = blob_mgr.to_blob_table_v5(ctx, None)?;
+                bootstrap.dump_rafsv5(ctx, &mut bootstrap_ctx, &blob_table)?
+            }
+            RafsVersion::V6 => {
+                let blob_table = blob_mgr.to_blob_table_v6(ctx, None)?;
+                bootstrap.dump_rafsv6(ctx, &mut bootstrap_ctx, &blob_table)?
+            }
+        }
+
+        bootstrap_mgr.add(bootstrap_ctx);
+        BuildOutput::new(&blob_mgr, &bootstrap_mgr)
+    }
+}
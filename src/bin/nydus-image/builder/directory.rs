@@ -24,12 +24,16 @@ impl FilesystemTreeBuilder {
         Self {}
     }
 
-    /// Walk directory to build node tree by DFS
+    /// Walk directory to build node tree by DFS.
+    ///
+    /// `included` indicates whether `parent` has been selected by include filters, then all its
+    /// descendants are selected too.
     fn load_children(
         &self,
         ctx: &mut BuildContext,
         bootstrap_ctx: &mut BootstrapContext,
         parent: &mut Node,
+        included: bool,
     ) -> Result<Vec<Tree>> {
         let mut result = Vec::new();
         if !parent.is_dir() {
@@ -43,7 +47,13 @@ impl FilesystemTreeBuilder {
         event_tracer!("load_from_directory", +children.len());
         for child in children {
             let path = child.path();
-            let child = Node::new(
+            let target = Node::generate_target(&path, &ctx.source_path);
+            if ctx.filter.is_excluded(&target) {
+                trace!("skip file {:?} excluded by filter", target);
+                continue;
+            }
+
+            let mut child = Node::new(
                 ctx.fs_version,
                 ctx.source_path.clone(),
                 path.clone(),
@@ -52,6 +62,9 @@ impl FilesystemTreeBuilder {
                 parent.explicit_uidgid,
             )
             .with_context(|| format!("failed to create node {:?}", path))?;
+            if ctx.record_btime {
+                child.record_btime()?;
+            }
 
             // as per OCI spec, whiteout file should not be present within final image
             // or filesystem, only existed in layers.
@@ -62,8 +75,19 @@ impl FilesystemTreeBuilder {
                 continue;
             }
 
+            if ctx.filter.is_skipped(&child) && child.whiteout_type(ctx.whiteout_spec).is_none() {
+                trace!("skip special file {:?}", target);
+                continue;
+            }
+
+            let included = included || ctx.filter.is_included(&target);
             let mut child = Tree::new(child);
-            child.children = self.load_children(ctx, bootstrap_ctx, &mut child.node)?;
+            child.children = self.load_children(ctx, bootstrap_ctx, &mut child.node, included)?;
+            // Drop files not selected by include filters, and directories without any
+            // selected descendant.
+            if !included && child.children.is_empty() {
+                continue;
+            }
             result.push(child);
         }
 
@@ -84,7 +108,7 @@ impl DirectoryBuilder {
         ctx: &mut BuildContext,
         bootstrap_ctx: &mut BootstrapContext,
     ) -> Result<Tree> {
-        let node = Node::new(
+        let mut node = Node::new(
             ctx.fs_version,
             ctx.source_path.clone(),
             ctx.source_path.clone(),
@@ -92,11 +116,14 @@ impl DirectoryBuilder {
             ctx.chunk_size,
             ctx.explicit_uidgid,
         )?;
+        if ctx.record_btime {
+            node.record_btime()?;
+        }
         let mut tree = Tree::new(node);
         let tree_builder = FilesystemTreeBuilder::new();
 
         tree.children = timing_tracer!(
-            { tree_builder.load_children(ctx, bootstrap_ctx, &mut tree.node) },
+            { tree_builder.load_children(ctx, bootstrap_ctx, &mut tree.node, false) },
             "load_from_directory"
         )?;
 
@@ -112,10 +139,21 @@ impl Builder for DirectoryBuilder {
         blob_mgr: &mut BlobManager,
     ) -> Result<BuildOutput> {
         let mut bootstrap_ctx = bootstrap_mgr.create_ctx()?;
+        // The source directory is a complete filesystem rather than a layer when building
+        // incrementally, so there's no whiteout to apply.
+        if ctx.incremental {
+            bootstrap_ctx.layered = false;
+        }
         // Scan source directory to build upper layer tree.
         let mut tree = self.build_tree_from_fs(ctx, &mut bootstrap_ctx)?;
         let mut bootstrap = Bootstrap::new()?;
-        if bootstrap_ctx.layered {
+        if ctx.incremental {
+            // Only dump files added or changed relative to the parent bootstrap.
+            let lower = bootstrap.load_parent_bootstrap(ctx, bootstrap_mgr, blob_mgr)?;
+            let reused = timing_tracer!({ tree.reuse_lower(&lower, ctx) }, "reuse_lower_tree");
+            event_tracer!("reused_files", +reused);
+            info!("reuse {} unchanged files from parent bootstrap", reused);
+        } else if bootstrap_ctx.layered {
             // Merge with lower layer if there's one, do not prepare `prefetch` list during merging.
             ctx.prefetch.disable();
             bootstrap.build(ctx, &mut bootstrap_ctx, &mut tree)?;
@@ -168,3 +206,68 @@ impl Builder for DirectoryBuilder {
         BuildOutput::new(&blob_mgr, &bootstrap_mgr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    use rafs::metadata::RAFS_DEFAULT_CHUNK_SIZE;
+    use vmm_sys_util::tempdir::TempDir;
+
+    use crate::core::context::ArtifactStorage;
+
+    fn build_inodes(source: &Path) -> Vec<(PathBuf, u64, u32)> {
+        let mut ctx = BuildContext {
+            source_path: source.to_path_buf(),
+            chunk_size: RAFS_DEFAULT_CHUNK_SIZE as u32,
+            ..Default::default()
+        };
+        let storage = ArtifactStorage::FileDir(source.to_path_buf());
+        let mut bootstrap_ctx = BootstrapContext::new(storage, false).unwrap();
+        let mut tree = DirectoryBuilder::new()
+            .build_tree_from_fs(&mut ctx, &mut bootstrap_ctx)
+            .unwrap();
+        let mut bootstrap = Bootstrap::new().unwrap();
+        bootstrap
+            .build(&mut ctx, &mut bootstrap_ctx, &mut tree)
+            .unwrap();
+
+        bootstrap_ctx
+            .nodes
+            .iter()
+            .map(|n| (n.target().clone(), n.inode.ino(), n.inode.nlink()))
+            .collect()
+    }
+
+    #[test]
+    fn test_fixed_inode_numbers() {
+        // Create the same tree in different orders, so source inode numbers differ.
+        let dir1 = TempDir::new().unwrap();
+        let src1 = dir1.as_path();
+        fs::create_dir(src1.join("a")).unwrap();
+        fs::write(src1.join("a/x"), b"x").unwrap();
+        fs::write(src1.join("b"), b"b").unwrap();
+        fs::hard_link(src1.join("b"), src1.join("c")).unwrap();
+
+        let dir2 = TempDir::new().unwrap();
+        let src2 = dir2.as_path();
+        fs::write(src2.join("c"), b"b").unwrap();
+        fs::hard_link(src2.join("c"), src2.join("b")).unwrap();
+        fs::create_dir(src2.join("a")).unwrap();
+        fs::write(src2.join("a/x"), b"x").unwrap();
+
+        let inodes = build_inodes(src1);
+        assert_eq!(inodes, build_inodes(src2));
+        // Inodes are numbered in the order of walking the tree sorted by name, and hardlinks
+        // share the number of the first one.
+        let expected = vec![
+            (PathBuf::from("/"), 1, 3),
+            (PathBuf::from("/a"), 2, 2),
+            (PathBuf::from("/b"), 3, 2),
+            (PathBuf::from("/c"), 3, 2),
+            (PathBuf::from("/a/x"), 5, 1),
+        ];
+        assert_eq!(inodes, expected);
+    }
+}
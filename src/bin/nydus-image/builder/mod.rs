@@ -7,12 +7,16 @@ use anyhow::Result;
 use crate::core::context::{BlobManager, BootstrapManager, BuildContext, BuildOutput};
 
 pub(crate) use diff::DiffBuilder;
+pub(crate) use dir_diff::DirDiffBuilder;
 pub(crate) use directory::DirectoryBuilder;
 pub(crate) use stargz::StargzBuilder;
+pub(crate) use tarball::{TarballBuilder, TARBALL_STDIN};
 
 mod diff;
+mod dir_diff;
 mod directory;
 mod stargz;
+mod tarball;
 
 pub(crate) trait Builder {
     fn build(
@@ -0,0 +1,496 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Build a RAFS filesystem from a tar stream, such as an image layer tarball.
+//!
+//! Entries are read from the tar stream sequentially and data of regular files is dumped into
+//! the data blob on the fly, so the stream doesn't need to be seekable or extracted onto disk.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use nix::sys::stat::makedev;
+use nydus_utils::{div_round_up, ByteSize};
+use rafs::metadata::layout::v5::RafsV5Inode;
+use rafs::metadata::layout::RafsXAttrs;
+use rafs::metadata::Inode;
+use tar::{Archive, Entry, EntryType};
+
+use crate::builder::Builder;
+use crate::core::blob::Blob;
+use crate::core::bootstrap::Bootstrap;
+use crate::core::chunk_dict::HashChunkDict;
+use crate::core::context::{
+    BlobContext, BlobManager, BootstrapManager, BuildContext, BuildOutput, RafsVersion,
+};
+use crate::core::node::{InodeWrapper, Node, Overlay};
+use crate::core::tree::Tree;
+
+/// Source path to read the tar stream from stdin.
+pub const TARBALL_STDIN: &str = "-";
+
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+struct TarballTreeBuilder<'a> {
+    ctx: &'a BuildContext,
+    blob_ctx: &'a mut BlobContext,
+    blob_index: u32,
+    chunk_dict: &'a mut HashChunkDict,
+    /// Whether to keep whiteouts for applying to the parent bootstrap.
+    layered: bool,
+    next_ino: Inode,
+    /// Directories in the tree, to create missing parent directories.
+    dirs: HashSet<PathBuf>,
+    /// Regular files indexed by target path, to resolve hardlinks.
+    files: HashMap<PathBuf, Node>,
+}
+
+impl<'a> TarballTreeBuilder<'a> {
+    fn build<R: Read>(&mut self, reader: R) -> Result<Tree> {
+        let mut archive = Archive::new(reader);
+        let root = self.new_dir_node(PathBuf::from("/"));
+        let mut tree = Tree::new(root);
+        self.dirs.insert(PathBuf::from("/"));
+
+        let entries = archive.entries().context("failed to read tar stream")?;
+        for entry in entries {
+            let mut entry = entry.context("failed to read tar entry")?;
+            let path = entry.path().context("invalid path of tar entry")?;
+            let target = Self::normalize_path(&path)?;
+            let node = match self
+                .parse_entry(&mut entry, &target)
+                .with_context(|| format!("failed to parse tar entry {:?}", target))?
+            {
+                Some(node) => node,
+                None => continue,
+            };
+
+            // As per OCI spec, whiteout file should not be present within final image
+            // or filesystem, only existed in layers.
+            if !self.layered
+                && node.whiteout_type(self.ctx.whiteout_spec).is_some()
+                && !node.is_overlayfs_opaque(self.ctx.whiteout_spec)
+            {
+                continue;
+            }
+
+            self.make_lost_dirs(&mut tree, &target)?;
+            if node.is_dir() {
+                self.dirs.insert(target.clone());
+            } else if self.dirs.remove(&target) {
+                // Entries under a directory replaced by a non-directory are gone with it.
+                self.dirs.retain(|dir| !dir.starts_with(&target));
+                self.files
+                    .retain(|path, _| path == &target || !path.starts_with(&target));
+            }
+            if !tree.apply(&node, false, self.ctx.whiteout_spec)? {
+                bail!("failed to add tar entry {:?} to tree", target);
+            }
+        }
+
+        Ok(tree)
+    }
+
+    /// Convert path of a tar entry, such as `./usr/bin`, to an absolute target path.
+    fn normalize_path(path: &Path) -> Result<PathBuf> {
+        let mut target = PathBuf::from("/");
+        for comp in path.components() {
+            match comp {
+                Component::Normal(name) => target.push(name),
+                Component::RootDir | Component::CurDir => {}
+                _ => bail!("invalid path {:?} of tar entry", path),
+            }
+        }
+
+        Ok(target)
+    }
+
+    /// Create parent directories which are missing from the tar stream.
+    fn make_lost_dirs(&mut self, tree: &mut Tree, target: &Path) -> Result<()> {
+        if let Some(parent) = target.parent() {
+            if !self.dirs.contains(parent) {
+                self.make_lost_dirs(tree, parent)?;
+                let node = self.new_dir_node(parent.to_path_buf());
+                if !tree.apply(&node, false, self.ctx.whiteout_spec)? {
+                    bail!("failed to add directory {:?} to tree", parent);
+                }
+                self.dirs.insert(parent.to_path_buf());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn new_dir_node(&mut self, target: PathBuf) -> Node {
+        let inode = self.new_inode(&target, libc::S_IFDIR | 0o755, 0, &RafsXAttrs::new());
+        self.new_node(target, inode, RafsXAttrs::new(), None, 0)
+    }
+
+    /// Convert a tar entry into a node, return None for entries unsupported by RAFS.
+    fn parse_entry<R: Read>(
+        &mut self,
+        entry: &mut Entry<R>,
+        target: &Path,
+    ) -> Result<Option<Node>> {
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let file_type = match entry_type {
+            EntryType::Directory => libc::S_IFDIR,
+            EntryType::Regular | EntryType::Continuous => libc::S_IFREG,
+            EntryType::Symlink => libc::S_IFLNK,
+            EntryType::Char => libc::S_IFCHR,
+            EntryType::Block => libc::S_IFBLK,
+            EntryType::Fifo => libc::S_IFIFO,
+            EntryType::Link => {
+                let link = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("hardlink target is missing"))?;
+                let link = Self::normalize_path(&link)?;
+                return self.new_hardlink(target, &link).map(Some);
+            }
+            EntryType::XGlobalHeader => return Ok(None),
+            _ => {
+                warn!("skip tar entry {:?} of type {:?}", target, entry_type);
+                return Ok(None);
+            }
+        };
+
+        let mode = header.mode()? & 0o7777 | file_type;
+        let uid = header.uid()? as u32;
+        let gid = header.gid()? as u32;
+        let mtime = header.mtime()?;
+        let rdev = if entry_type == EntryType::Char || entry_type == EntryType::Block {
+            makedev(
+                header.device_major()?.unwrap_or_default() as u64,
+                header.device_minor()?.unwrap_or_default() as u64,
+            )
+        } else {
+            0
+        };
+        let symlink = if entry_type == EntryType::Symlink {
+            let link = entry
+                .link_name()?
+                .ok_or_else(|| anyhow!("symlink target is missing"))?;
+            Some(link.into_owned().into_os_string())
+        } else {
+            None
+        };
+
+        let mut xattrs = RafsXAttrs::new();
+        if let Some(extensions) = entry.pax_extensions()? {
+            for ext in extensions {
+                let ext = ext?;
+                if let Some(name) = ext
+                    .key()
+                    .ok()
+                    .and_then(|k| k.strip_prefix(PAX_XATTR_PREFIX))
+                {
+                    xattrs.add(OsString::from(name), ext.value_bytes().to_vec());
+                }
+            }
+        }
+
+        let size = match symlink.as_ref() {
+            Some(link) => link.byte_size() as u64,
+            None if file_type == libc::S_IFREG => entry.size(),
+            None => 0,
+        };
+        let mut inode = self.new_inode(target, mode, size, &xattrs);
+        if self.ctx.explicit_uidgid {
+            inode.i_uid = uid;
+            inode.i_gid = gid;
+        }
+        inode.i_mtime = mtime;
+        inode.i_rdev = rdev as u32;
+        let mut node = self.new_node(target.to_path_buf(), inode, xattrs, symlink, rdev);
+
+        if node.is_reg() {
+            node.inode
+                .set_child_count(node.chunk_count(self.ctx.chunk_size as u64));
+            node.dump_blob_from_reader(
+                self.ctx,
+                self.blob_ctx,
+                self.blob_index,
+                self.chunk_dict,
+                entry,
+            )?;
+            self.files.insert(target.to_path_buf(), node.clone());
+        } else {
+            node.dump_non_reg_digest(self.ctx)?;
+        }
+
+        Ok(Some(node))
+    }
+
+    /// Create a hardlink node sharing the inode and data chunks of regular file `link`.
+    fn new_hardlink(&mut self, target: &Path, link: &Path) -> Result<Node> {
+        let mut node = self
+            .files
+            .get(link)
+            .ok_or_else(|| anyhow!("hardlink target {:?} is not a regular file", link))?
+            .clone();
+        let name_size = target.file_name().unwrap_or_default().byte_size() as u16;
+        match &mut node.inode {
+            InodeWrapper::V5(i) | InodeWrapper::V6(i) => i.i_name_size = name_size,
+        }
+        node.path = target.to_path_buf();
+        node.target = target.to_path_buf();
+        node.target_vec = Node::generate_target_vec(target);
+
+        Ok(node)
+    }
+
+    fn new_inode(
+        &mut self,
+        target: &Path,
+        mode: u32,
+        size: u64,
+        xattrs: &RafsXAttrs,
+    ) -> RafsV5Inode {
+        let name = target.file_name().unwrap_or_else(|| OsStr::new("/"));
+        let mut inode = RafsV5Inode::new();
+        self.next_ino += 1;
+
+        inode.i_ino = self.next_ino;
+        inode.i_mode = mode;
+        inode.i_size = size;
+        // Ignore actual nlink value and calculate from hardlinks in the tree instead.
+        inode.i_nlink = 1;
+        inode.i_name_size = name.byte_size() as u16;
+        inode.i_blocks = div_round_up(size + xattrs.aligned_size_v5() as u64, 512);
+
+        inode
+    }
+
+    fn new_node(
+        &self,
+        target: PathBuf,
+        inode: RafsV5Inode,
+        xattrs: RafsXAttrs,
+        symlink: Option<OsString>,
+        rdev: u64,
+    ) -> Node {
+        let mut inode = match self.ctx.fs_version {
+            RafsVersion::V5 => InodeWrapper::V5(inode),
+            RafsVersion::V6 => InodeWrapper::V6(inode),
+        };
+        if !xattrs.is_empty() {
+            inode.set_has_xattr(true);
+        }
+        if let Some(link) = symlink.as_ref() {
+            inode.set_symlink_size(link.byte_size());
+        }
+
+        let mut node = Node {
+            index: 0,
+            src_ino: inode.ino(),
+            src_dev: u64::MAX,
+            rdev,
+            overlay: Overlay::UpperAddition,
+            explicit_uidgid: self.ctx.explicit_uidgid,
+            source: PathBuf::from("/"),
+            target_vec: Node::generate_target_vec(&target),
+            path: target.clone(),
+            target,
+            inode,
+            chunks: Vec::new(),
+            symlink,
+            xattrs,
+            ctime: 0,
+            offset: 0,
+            dirents: Vec::new(),
+            v6_datalayout: 0,
+            v6_compact_inode: false,
+            v6_force_extended_inode: false,
+        };
+        node.set_v6_inode_compact();
+
+        node
+    }
+}
+
+pub(crate) struct TarballBuilder {}
+
+impl TarballBuilder {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn build_tree_from_tarball(
+        &mut self,
+        ctx: &BuildContext,
+        blob_ctx: &mut BlobContext,
+        blob_index: u32,
+        chunk_dict: &mut HashChunkDict,
+        layered: bool,
+    ) -> Result<Tree> {
+        let mut tree_builder = TarballTreeBuilder {
+            ctx,
+            blob_ctx,
+            blob_index,
+            chunk_dict,
+            layered,
+            next_ino: 0,
+            dirs: HashSet::new(),
+            files: HashMap::new(),
+        };
+
+        if ctx.source_path == Path::new(TARBALL_STDIN) {
+            let stdin = io::stdin();
+            tree_builder.build(stdin.lock())
+        } else {
+            let file = File::open(&ctx.source_path)
+                .with_context(|| format!("failed to open tarball {:?}", ctx.source_path))?;
+            tree_builder.build(file)
+        }
+    }
+}
+
+impl Builder for TarballBuilder {
+    fn build(
+        &mut self,
+        ctx: &mut BuildContext,
+        bootstrap_mgr: &mut BootstrapManager,
+        blob_mgr: &mut BlobManager,
+    ) -> Result<BuildOutput> {
+        let mut bootstrap_ctx = bootstrap_mgr.create_ctx()?;
+        let mut bootstrap = Bootstrap::new()?;
+        // Load the parent bootstrap before reading the tar stream, so its blob table is
+        // settled and its chunks can be used for deduplication.
+        let lower = if bootstrap_ctx.layered {
+            Some(bootstrap.load_parent_bootstrap(ctx, bootstrap_mgr, blob_mgr)?)
+        } else {
+            None
+        };
+
+        let mut blob_ctx = BlobContext::new(ctx.blob_id.clone(), ctx.blob_storage.clone())?;
+        blob_ctx.set_chunk_dict(blob_mgr.get_chunk_dict());
+        blob_ctx.set_chunk_size(ctx.chunk_size);
+        blob_ctx.set_meta_info_enabled(true);
+        blob_mgr.extend_blob_table_from_chunk_dict()?;
+        let blob_index = blob_mgr.alloc_index()?;
+
+        // Data of regular files is dumped into the blob while building the tree.
+        let mut tree = timing_tracer!(
+            {
+                self.build_tree_from_tarball(
+                    ctx,
+                    &mut blob_ctx,
+                    blob_index,
+                    &mut blob_mgr.chunk_dict_cache,
+                    bootstrap_ctx.layered,
+                )
+            },
+            "load_from_tarball"
+        )?;
+
+        if lower.is_some() {
+            // Merge with lower layer, do not prepare `prefetch` list during merging.
+            ctx.prefetch.disable();
+            bootstrap.build(ctx, &mut bootstrap_ctx, &mut tree)?;
+            tree = bootstrap.apply(ctx, &mut bootstrap_ctx, bootstrap_mgr, blob_mgr, lower)?;
+        }
+        // Convert the hierarchy tree into an array, stored in `bootstrap_ctx.nodes`.
+        timing_tracer!(
+            { bootstrap.build(ctx, &mut bootstrap_ctx, &mut tree) },
+            "build_bootstrap"
+        )?;
+
+        // Finish the blob file
+        let mut blob = Blob::new();
+        let blob_exists = timing_tracer!(
+            {
+                blob.dump(
+                    ctx,
+                    &mut blob_ctx,
+                    blob_index,
+                    &mut bootstrap_ctx.nodes,
+                    &mut blob_mgr.chunk_dict_cache,
+                )
+            },
+            "dump_blob"
+        )?;
+
+        // Add new blob to blob table
+        blob_mgr.add(if blob_exists { Some(blob_ctx) } else { None });
+
+        // Dump bootstrap file
+        match ctx.fs_version {
+            RafsVersion::V5 => {
+                let blob_table = blob_mgr.to_blob_table_v5(ctx, None)?;
+                bootstrap.dump_rafsv5(ctx, &mut bootstrap_ctx, &blob_table)?
+            }
+            RafsVersion::V6 => {
+                let blob_table = blob_mgr.to_blob_table_v6(ctx, None)?;
+                bootstrap.dump_rafsv6(ctx, &mut bootstrap_ctx, &blob_table)?
+            }
+        }
+
+        bootstrap_mgr.add(bootstrap_ctx);
+        BuildOutput::new(blob_mgr, bootstrap_mgr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rafs::metadata::RAFS_DEFAULT_CHUNK_SIZE;
+
+    fn append_entry(tar: &mut tar::Builder<Vec<u8>>, path: &str, entry_type: EntryType) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o755);
+        header.set_size(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+        tar.append_data(&mut header, path, io::empty()).unwrap();
+    }
+
+    #[test]
+    fn test_replace_dir_with_file() {
+        let mut tar = tar::Builder::new(Vec::new());
+        append_entry(&mut tar, "a/", EntryType::Directory);
+        append_entry(&mut tar, "a/d/", EntryType::Directory);
+        append_entry(&mut tar, "a/d/f", EntryType::Regular);
+        // Replace the directory by a file, then create it again with new entries.
+        append_entry(&mut tar, "a", EntryType::Regular);
+        append_entry(&mut tar, "a/", EntryType::Directory);
+        append_entry(&mut tar, "a/d/g", EntryType::Regular);
+        let data = tar.into_inner().unwrap();
+
+        let ctx = BuildContext {
+            chunk_size: RAFS_DEFAULT_CHUNK_SIZE as u32,
+            ..Default::default()
+        };
+        let mut blob_ctx = BlobContext::new_with_writer("blob".to_string(), None);
+        let mut chunk_dict = HashChunkDict::default();
+        let mut tree_builder = TarballTreeBuilder {
+            ctx: &ctx,
+            blob_ctx: &mut blob_ctx,
+            blob_index: 0,
+            chunk_dict: &mut chunk_dict,
+            layered: false,
+            next_ino: 0,
+            dirs: HashSet::new(),
+            files: HashMap::new(),
+        };
+        let tree = tree_builder.build(data.as_slice()).unwrap();
+        assert!(!tree_builder.files.contains_key(Path::new("/a/d/f")));
+
+        let a = &tree.children[0];
+        assert!(a.node.is_dir());
+        assert_eq!(a.children.len(), 1);
+        let d = &a.children[0];
+        assert_eq!(d.node.target(), Path::new("/a/d"));
+        assert!(d.node.is_dir());
+        assert_eq!(d.children.len(), 1);
+        assert_eq!(d.children[0].node.name(), "g");
+    }
+}
@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::mem;
 use std::os::unix::ffi::OsStrExt;
 
 use anyhow::{Context, Result};
@@ -13,6 +14,7 @@ use storage::meta::{BlobChunkInfoOndisk, BlobMetaHeaderOndisk};
 use super::chunk_dict::ChunkDict;
 use super::context::{BlobContext, BuildContext, SourceType};
 use super::node::Node;
+use super::pipeline::ChunkPipeline;
 
 pub struct Blob {}
 
@@ -35,16 +37,36 @@ impl Blob {
                 let (inodes, prefetch_entries) = blob_ctx
                     .blob_layout
                     .layout_blob_simple(&ctx.prefetch, nodes)?;
-                for (idx, inode) in inodes.iter().enumerate() {
-                    let node = &mut nodes[*inode];
-                    let size = node
-                        .dump_blob(ctx, blob_ctx, blob_index, chunk_dict)
+                if ctx.threads > 1 {
+                    let mut pipeline =
+                        ChunkPipeline::new(ctx.threads, ctx.digester, ctx.compressor)?;
+                    pipeline
+                        .dump(
+                            ctx,
+                            blob_ctx,
+                            blob_index,
+                            nodes,
+                            &inodes,
+                            prefetch_entries,
+                            chunk_dict,
+                        )
                         .context("failed to dump blob chunks")?;
-                    if idx < prefetch_entries {
-                        blob_ctx.blob_readahead_size += size;
+                } else {
+                    for (idx, inode) in inodes.iter().enumerate() {
+                        let node = &mut nodes[*inode];
+                        let size = node
+                            .dump_blob(ctx, blob_ctx, blob_index, chunk_dict)
+                            .context("failed to dump blob chunks")?;
+                        if idx < prefetch_entries {
+                            blob_ctx.blob_readahead_size += size;
+                        }
                     }
                 }
-                self.dump_meta_data(blob_ctx)?;
+                Self::dump_meta_data(blob_ctx)?;
+            }
+            SourceType::Tarball => {
+                // Data chunks have been dumped while reading the tar stream.
+                Self::dump_meta_data(blob_ctx)?;
             }
             SourceType::StargzIndex => {
                 for node in nodes {
@@ -68,20 +90,41 @@ impl Blob {
             }
         }
 
+        Self::finish(ctx, blob_ctx)?;
+
+        let blob_exists = blob_ctx.compressed_blob_size > 0;
+
+        Ok(blob_exists)
+    }
+
+    /// Seal the blob being dumped on reaching the maximum blob size, and continue dumping data
+    /// chunks into a new blob following it in the blob table.
+    pub fn split(ctx: &BuildContext, blob_ctx: &mut BlobContext) -> Result<()> {
+        let next = blob_ctx.new_successor()?;
+        let mut sealed = mem::replace(blob_ctx, next);
+        blob_ctx.sealed_blobs = mem::take(&mut sealed.sealed_blobs);
+        Self::dump_meta_data(&mut sealed)?;
+        Self::finish(ctx, &mut sealed)?;
+        info!(
+            "split blob {} of {} bytes",
+            sealed.blob_id, sealed.compressed_blob_size
+        );
+        blob_ctx.sealed_blobs.push(sealed);
+
+        Ok(())
+    }
+
+    fn finish(ctx: &BuildContext, blob_ctx: &mut BlobContext) -> Result<()> {
         // Name blob id by blob hash if not specified.
         if blob_ctx.blob_id.is_empty() {
             blob_ctx.blob_id = format!("{:x}", blob_ctx.blob_hash.clone().finalize());
         }
 
         blob_ctx.set_blob_readahead_size(ctx);
-        blob_ctx.flush()?;
-
-        let blob_exists = blob_ctx.compressed_blob_size > 0;
-
-        Ok(blob_exists)
+        blob_ctx.flush()
     }
 
-    fn dump_meta_data(&mut self, blob_ctx: &mut BlobContext) -> Result<()> {
+    fn dump_meta_data(blob_ctx: &mut BlobContext) -> Result<()> {
         if !blob_ctx.blob_meta_info_enabled {
             return Ok(());
         }
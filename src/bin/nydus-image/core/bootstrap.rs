@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::ffi::OsString;
@@ -16,11 +17,12 @@ use rafs::metadata::layout::v5::{
 use rafs::metadata::layout::v6::{
     align_offset, calculate_nid, RafsV6BlobTable, RafsV6Device, RafsV6SuperBlock,
     RafsV6SuperBlockExt, EROFS_BLOCK_SIZE, EROFS_DEVTABLE_OFFSET, EROFS_INODE_SLOT_SIZE,
+    RAFS_V6_MAX_BLOBS,
 };
-use rafs::RafsIoWrite;
+use rafs::{RafsIoReader, RafsIoWrite};
 
 use rafs::metadata::layout::RAFS_ROOT_INODE;
-use rafs::metadata::{RafsMode, RafsStore, RafsSuper};
+use rafs::metadata::{fold_name_case, RafsMode, RafsStore, RafsSuper};
 
 use super::context::{BlobManager, BootstrapContext, BootstrapManager, BuildContext, SourceType};
 use super::node::{Node, WhiteoutType, OVERLAYFS_WHITEOUT_OPAQUE};
@@ -61,6 +63,7 @@ impl Bootstrap {
     ) -> Result<()> {
         tree.node.index = RAFS_ROOT_INODE;
         tree.node.inode.set_ino(RAFS_ROOT_INODE);
+        Self::normalize_timestamps(ctx, &mut tree.node);
         // Filesystem walking skips root inode within subsequent while loop, however, we allow
         // user to pass the source root as prefetch hint. Check it here.
         ctx.prefetch.insert_if_need(&tree.node);
@@ -142,6 +145,9 @@ impl Bootstrap {
         // binary search.
         tree.children
             .sort_by_key(|child| child.node.name().to_os_string());
+        if ctx.check_case_collision {
+            Self::check_case_collision(tree)?;
+        }
 
         // Maybe the parent is not a directory in multi-layers build scenario, so we check here.
         if parent.is_dir() {
@@ -164,6 +170,7 @@ impl Bootstrap {
             let index = nodes.len() as u64 + 1;
             child.node.index = index;
             child.node.inode.set_parent(parent_ino);
+            Self::normalize_timestamps(ctx, &mut child.node);
 
             // Hardlink handle, all hardlink nodes' ino, nlink should be the same,
             // because the real_ino may be conflicted between different layers,
@@ -257,6 +264,32 @@ impl Bootstrap {
         Ok(())
     }
 
+    /// Check that no two children of the directory have names only differing in case.
+    fn check_case_collision(tree: &Tree) -> Result<()> {
+        let mut names = HashMap::with_capacity(tree.children.len());
+        for child in tree.children.iter() {
+            let name = child.node.name();
+            if let Some(other) = names.insert(fold_name_case(name), name) {
+                bail!(
+                    "names {:?} and {:?} in directory {:?} only differ in case",
+                    other,
+                    name,
+                    tree.node.target()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clear modification time of inodes in the upper layer if requested, inodes from the
+    /// parent bootstrap are kept untouched.
+    fn normalize_timestamps(ctx: &BuildContext, node: &mut Node) {
+        if ctx.zero_timestamps && !node.overlay.is_lower_layer() {
+            node.inode.set_mtime(0, 0);
+        }
+    }
+
     /// Rafsv6 update offset
     fn update_dirents(&self, nodes: &mut Vec<Node>, tree: &mut Tree, parent_offset: u64) {
         let node = &mut nodes[tree.node.index as usize - 1];
@@ -295,7 +328,8 @@ impl Bootstrap {
         }
     }
 
-    fn load_parent_bootstrap(
+    /// Load the lower tree from the parent bootstrap, and reuse its blob table and chunks.
+    pub fn load_parent_bootstrap(
         &mut self,
         ctx: &mut BuildContext,
         bootstrap_mgr: &mut BootstrapManager,
@@ -416,6 +450,9 @@ impl Bootstrap {
         if ctx.source_type == SourceType::StargzIndex {
             super_block.set_block_size(STARGZ_DEFAULT_BLOCK_SIZE);
         }
+        if blob_table.entries.iter().any(|b| !b.cipher().is_none()) {
+            super_block.set_encrypted();
+        }
 
         // Set inodes and chunks
         let mut inode_offset = (super_block_size
@@ -488,6 +525,21 @@ impl Bootstrap {
             Result<()>
         )?;
 
+        // Record size and digest of the bootstrap, so nydusd can detect truncated or modified
+        // bootstraps when loading them.
+        bootstrap_writer.flush()?;
+        let meta_size = bootstrap_writer.seek_to_end()?;
+        super_block.set_meta_size(meta_size);
+        let mut reader: RafsIoReader = Box::new(bootstrap_writer.file.get_ref().try_clone()?);
+        let meta_digest = super_block
+            .calculate_meta_digest(&mut reader, meta_size)
+            .context("failed to calculate bootstrap digest")?;
+        super_block.set_meta_digest(meta_digest);
+        bootstrap_writer.seek_to_offset(0)?;
+        super_block
+            .store(&mut bootstrap_writer)
+            .context("failed to store superblock")?;
+
         bootstrap_writer.release(Some(bootstrap_ctx.name.as_str()))?;
 
         Ok(())
@@ -511,6 +563,13 @@ impl Bootstrap {
         // |   |         |devslot     |             |                                                                   |
         // +---+---------+------------+-------------+-------------------------------------------------------------------+
 
+        if blob_table.entries.len() > RAFS_V6_MAX_BLOBS {
+            bail!(
+                "Rafs v6 image can't reference {} blobs, at most {} blobs are supported",
+                blob_table.entries.len(),
+                RAFS_V6_MAX_BLOBS
+            );
+        }
         let blob_table_size = blob_table.size() as u64;
         let mut bootstrap_writer = bootstrap_ctx.create_writer()?;
 
@@ -641,3 +700,44 @@ impl Bootstrap {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::context::RafsVersion;
+    use crate::core::node::Overlay;
+    use rafs::metadata::RAFS_DEFAULT_CHUNK_SIZE;
+    use std::fs::File;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_check_case_collision() {
+        let dir = TempDir::new().unwrap();
+        let new_node = |name: &str| {
+            let path = dir.as_path().join(name);
+            if !name.is_empty() {
+                File::create(&path).unwrap();
+            }
+            Node::new(
+                RafsVersion::V5,
+                dir.as_path().to_path_buf(),
+                path,
+                Overlay::UpperAddition,
+                RAFS_DEFAULT_CHUNK_SIZE as u32,
+                false,
+            )
+            .unwrap()
+        };
+
+        let mut tree = Tree::new(new_node(""));
+        tree.children.push(Tree::new(new_node("Makefile")));
+        tree.children.push(Tree::new(new_node("README")));
+        tree.children.push(Tree::new(new_node("straße")));
+        tree.children.push(Tree::new(new_node("STRASSE")));
+        assert!(Bootstrap::check_case_collision(&tree).is_ok());
+
+        tree.children.push(Tree::new(new_node("readme")));
+        let err = Bootstrap::check_case_collision(&tree).unwrap_err();
+        assert!(err.to_string().contains("\"README\" and \"readme\""));
+    }
+}
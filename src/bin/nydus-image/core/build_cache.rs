@@ -0,0 +1,424 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache of data chunks generated by previous builds, keyed by digest of file content.
+//!
+//! When rebuilding an image with most files unchanged, e.g. in CI, data chunks of unchanged files
+//! are fetched from the cache instead of being chunked and compressed again. The cache directory
+//! contains:
+//! - `chunks.data`: compressed data of all cached chunks.
+//! - `index.json`: digests of data chunks of each file, and location of each chunk in the data
+//!   file.
+//!
+//! The cache is only valid for builds with the same digest algorithm, compression algorithm and
+//! chunk size, otherwise it's reset. It's safe to remove the cache directory between builds.
+//! Builds sharing a cache directory are serialized by a lock on the `lock` file, and cached
+//! chunks are validated against their digests when reused.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use nydus_utils::digest::{self, DigestHasher, RafsDigest};
+use serde::{Deserialize, Serialize};
+use storage::compress;
+
+const BUILD_CACHE_VERSION: u32 = 1;
+const BUILD_CACHE_INDEX: &str = "index.json";
+const BUILD_CACHE_DATA: &str = "chunks.data";
+const BUILD_CACHE_LOCK: &str = "lock";
+const FILE_DIGEST_BUF_SIZE: usize = 0x10_0000;
+
+/// Location of compressed chunk data in the cache data file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CachedChunk {
+    offset: u64,
+    compressed_size: u32,
+    pub uncompressed_size: u32,
+    pub is_compressed: bool,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct BuildCacheIndex {
+    version: u32,
+    digester: String,
+    compressor: String,
+    chunk_size: u32,
+    /// Digests of data chunks of files, indexed by digest of file content.
+    files: HashMap<String, Vec<String>>,
+    /// Cached data chunks, indexed by chunk digest.
+    chunks: HashMap<String, CachedChunk>,
+}
+
+struct BuildCacheState {
+    index: BuildCacheIndex,
+    data_size: u64,
+    updated: bool,
+}
+
+/// Build cache shared by threads of a build.
+///
+/// Only the index is protected by a mutex, chunk data is read, written and validated without
+/// holding it.
+pub struct BuildCache {
+    dir: PathBuf,
+    digester: digest::Algorithm,
+    compressor: compress::Algorithm,
+    data: File,
+    state: Mutex<BuildCacheState>,
+    // Locked until the cache is dropped, to keep other builders off.
+    _lock: File,
+}
+
+impl BuildCache {
+    /// Open the build cache in directory `dir`, which is created if it doesn't exist yet.
+    pub fn open(
+        dir: &Path,
+        digester: digest::Algorithm,
+        compressor: compress::Algorithm,
+        chunk_size: u32,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create build cache directory {:?}", dir))?;
+        let lock = Self::lock(dir)?;
+
+        let index_path = dir.join(BUILD_CACHE_INDEX);
+        let mut index = match File::open(&index_path) {
+            Ok(f) => serde_json::from_reader(f).unwrap_or_else(|e| {
+                warn!("invalid build cache index {:?}, {}", index_path, e);
+                BuildCacheIndex::default()
+            }),
+            Err(_) => BuildCacheIndex::default(),
+        };
+        let valid = index.version == BUILD_CACHE_VERSION
+            && index.digester == digester.to_string()
+            && index.compressor == compressor.to_string()
+            && index.chunk_size == chunk_size;
+        if !valid {
+            if !index.files.is_empty() {
+                info!(
+                    "reset build cache {:?} generated with different options",
+                    dir
+                );
+            }
+            index = BuildCacheIndex {
+                version: BUILD_CACHE_VERSION,
+                digester: digester.to_string(),
+                compressor: compressor.to_string(),
+                chunk_size,
+                ..Default::default()
+            };
+        }
+
+        let data_path = dir.join(BUILD_CACHE_DATA);
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(!valid)
+            .open(&data_path)
+            .with_context(|| format!("failed to open build cache data {:?}", data_path))?;
+        let data_size = data.metadata()?.len();
+
+        Ok(BuildCache {
+            dir: dir.to_path_buf(),
+            digester,
+            compressor,
+            data,
+            state: Mutex::new(BuildCacheState {
+                index,
+                data_size,
+                updated: !valid,
+            }),
+            _lock: lock,
+        })
+    }
+
+    // Lock the cache directory exclusively, waiting for other builders using it to finish.
+    fn lock(dir: &Path) -> Result<File> {
+        let path = dir.join(BUILD_CACHE_LOCK);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("failed to open build cache lock {:?}", path))?;
+        // Safe because the fd is valid.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            info!(
+                "waiting for other builders to release build cache {:?}",
+                dir
+            );
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+                bail!(
+                    "failed to lock build cache {:?}, {}",
+                    path,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Calculate digest of the whole content of file `path`.
+    ///
+    /// It doesn't access the cache, so it can be called without locking the cache.
+    pub fn file_digest(path: &Path, digester: digest::Algorithm) -> Result<RafsDigest> {
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open node file {:?}", path))?;
+        let mut hasher = RafsDigest::hasher(digester);
+        let mut buf = vec![0u8; FILE_DIGEST_BUF_SIZE];
+
+        loop {
+            let size = file
+                .read(&mut buf)
+                .with_context(|| format!("failed to read node file {:?}", path))?;
+            if size == 0 {
+                break;
+            }
+            hasher.digest_update(&buf[..size]);
+        }
+
+        Ok(hasher.digest_finalize())
+    }
+
+    /// Get cached data chunks of the file with content digest `file_digest`.
+    pub fn get_file_chunks(
+        &self,
+        file_digest: &RafsDigest,
+    ) -> Option<Vec<(RafsDigest, CachedChunk)>> {
+        let state = self.state.lock().unwrap();
+        let ids = state.index.files.get(&file_digest.to_string())?;
+        let mut chunks = Vec::with_capacity(ids.len());
+
+        for id in ids {
+            let chunk = state.index.chunks.get(id)?;
+            chunks.push((digest_from_hex(id)?, *chunk));
+        }
+
+        Some(chunks)
+    }
+
+    /// Read compressed data of cached chunk `id`, validated against the chunk digest.
+    pub fn read_chunk(&self, id: &RafsDigest, chunk: &CachedChunk) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; chunk.compressed_size as usize];
+        self.data
+            .read_exact_at(&mut buf, chunk.offset)
+            .context("failed to read chunk from build cache")?;
+
+        let digest = if chunk.is_compressed {
+            let mut data = vec![0u8; chunk.uncompressed_size as usize];
+            compress::decompress(&buf, None, &mut data, self.compressor)
+                .ok()
+                .filter(|size| *size == data.len())
+                .map(|_| RafsDigest::from_buf(&data, self.digester))
+        } else {
+            Some(RafsDigest::from_buf(&buf, self.digester))
+        };
+        if digest.as_ref() != Some(id) {
+            bail!(
+                "chunk {} in build cache {:?} is corrupted, please remove the cache directory",
+                id,
+                self.dir
+            );
+        }
+
+        Ok(buf)
+    }
+
+    /// Add compressed data of chunk `id` into the cache.
+    pub fn add_chunk(
+        &self,
+        id: &RafsDigest,
+        compressed: &[u8],
+        is_compressed: bool,
+        uncompressed_size: u32,
+    ) -> Result<()> {
+        let key = id.to_string();
+        // Reserve space in the data file, and write data without holding the lock.
+        let offset = {
+            let mut state = self.state.lock().unwrap();
+            if state.index.chunks.contains_key(&key) {
+                return Ok(());
+            }
+            let offset = state.data_size;
+            state.data_size += compressed.len() as u64;
+            offset
+        };
+
+        self.data
+            .write_all_at(compressed, offset)
+            .context("failed to write chunk into build cache")?;
+        let mut state = self.state.lock().unwrap();
+        state.index.chunks.insert(
+            key,
+            CachedChunk {
+                offset,
+                compressed_size: compressed.len() as u32,
+                uncompressed_size,
+                is_compressed,
+            },
+        );
+        state.updated = true;
+
+        Ok(())
+    }
+
+    /// Record data chunks of the file with content digest `file_digest`.
+    ///
+    /// The file is cached only if data of all its chunks is available in the cache, which may not
+    /// be true for chunks deduplicated by a chunk dictionary.
+    pub fn add_file(&self, file_digest: &RafsDigest, chunk_ids: &[RafsDigest]) {
+        let ids: Vec<String> = chunk_ids.iter().map(|id| id.to_string()).collect();
+        let mut state = self.state.lock().unwrap();
+        if ids.iter().all(|id| state.index.chunks.contains_key(id)) {
+            state.index.files.insert(file_digest.to_string(), ids);
+            state.updated = true;
+        }
+    }
+
+    /// Save the cache index, so the cache can be used by following builds.
+    pub fn persist(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.updated {
+            return Ok(());
+        }
+
+        self.data.sync_data()?;
+        let index_path = self.dir.join(BUILD_CACHE_INDEX);
+        let tmp_path = self.dir.join(format!("{}.tmp", BUILD_CACHE_INDEX));
+        let mut tmp = File::create(&tmp_path)
+            .with_context(|| format!("failed to create build cache index {:?}", tmp_path))?;
+        serde_json::to_writer(&mut tmp, &state.index)?;
+        tmp.flush()?;
+        fs::rename(&tmp_path, &index_path)
+            .with_context(|| format!("failed to save build cache index {:?}", index_path))?;
+        state.updated = false;
+
+        info!(
+            "build cache {:?}: {} files, {} chunks, {} bytes",
+            self.dir,
+            state.index.files.len(),
+            state.index.chunks.len(),
+            state.data_size
+        );
+
+        Ok(())
+    }
+}
+
+fn digest_from_hex(s: &str) -> Option<RafsDigest> {
+    let mut digest = RafsDigest::default();
+    if s.len() != digest.data.len() * 2 {
+        return None;
+    }
+    for (idx, byte) in digest.data.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_build_cache() {
+        let dir = TempDir::new().unwrap();
+        let digester = digest::Algorithm::Blake3;
+        let compressor = compress::Algorithm::Lz4Block;
+        let chunk1 = RafsDigest::from_buf(b"chunk1", digester);
+        let chunk2 = RafsDigest::from_buf(b"chunk2", digester);
+        let chunk3 = RafsDigest::from_buf(b"chunk3", digester);
+        let file1 = RafsDigest::from_buf(b"file1", digester);
+        let file2 = RafsDigest::from_buf(b"file2", digester);
+        let file3 = RafsDigest::from_buf(b"file3", digester);
+
+        let cache = BuildCache::open(dir.as_path(), digester, compressor, 0x100000).unwrap();
+        assert!(cache.get_file_chunks(&file1).is_none());
+        cache.add_chunk(&chunk1, b"chunk1", false, 6).unwrap();
+        cache.add_chunk(&chunk1, b"chunk1", false, 6).unwrap();
+        cache.add_file(&file1, &[chunk1, chunk1]);
+        // Data of chunk2 is unavailable.
+        cache.add_file(&file2, &[chunk1, chunk2]);
+        // Data of chunk3 doesn't match its digest.
+        cache.add_chunk(&chunk3, b"chunk1", false, 6).unwrap();
+        cache.add_file(&file3, &[chunk3]);
+        cache.persist().unwrap();
+        drop(cache);
+
+        let cache = BuildCache::open(dir.as_path(), digester, compressor, 0x100000).unwrap();
+        let chunks = cache.get_file_chunks(&file1).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].0, chunk1);
+        assert_eq!(chunks[0].1.uncompressed_size, 6);
+        assert!(!chunks[0].1.is_compressed);
+        assert_eq!(cache.read_chunk(&chunk1, &chunks[1].1).unwrap(), b"chunk1");
+        assert!(cache.get_file_chunks(&file2).is_none());
+        let chunks = cache.get_file_chunks(&file3).unwrap();
+        assert!(cache.read_chunk(&chunk3, &chunks[0].1).is_err());
+        drop(cache);
+
+        // The cache is reset with different build options.
+        let cache = BuildCache::open(dir.as_path(), digester, compressor, 0x200000).unwrap();
+        assert!(cache.get_file_chunks(&file1).is_none());
+    }
+
+    #[test]
+    fn test_build_cache_compressed_chunk() {
+        let dir = TempDir::new().unwrap();
+        let digester = digest::Algorithm::Sha256;
+        let compressor = compress::Algorithm::Lz4Block;
+        let data = vec![0x5au8; 0x1000];
+        let chunk = RafsDigest::from_buf(&data, digester);
+        let (compressed, is_compressed) = compress::compress(&data, compressor).unwrap();
+        assert!(is_compressed);
+
+        let cache = BuildCache::open(dir.as_path(), digester, compressor, 0x100000).unwrap();
+        cache
+            .add_chunk(&chunk, &compressed, true, data.len() as u32)
+            .unwrap();
+        let cached = cache.state.lock().unwrap().index.chunks[&chunk.to_string()];
+        assert_eq!(
+            cache.read_chunk(&chunk, &cached).unwrap(),
+            compressed.as_ref()
+        );
+    }
+
+    #[test]
+    fn test_build_cache_lock() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().to_path_buf();
+        let digester = digest::Algorithm::Blake3;
+        let compressor = compress::Algorithm::Lz4Block;
+        let cache = BuildCache::open(&path, digester, compressor, 0x100000).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let builder = std::thread::spawn(move || {
+            let _cache = BuildCache::open(&path, digester, compressor, 0x100000).unwrap();
+            tx.send(()).unwrap();
+        });
+        // The other builder waits until the cache is released.
+        assert!(rx
+            .recv_timeout(std::time::Duration::from_millis(200))
+            .is_err());
+        drop(cache);
+        rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        builder.join().unwrap();
+    }
+
+    #[test]
+    fn test_digest_from_hex() {
+        let digest = RafsDigest::from_buf(b"data", digest::Algorithm::Sha256);
+        assert_eq!(digest_from_hex(&digest.to_string()), Some(digest));
+        assert!(digest_from_hex("invalid").is_none());
+    }
+}
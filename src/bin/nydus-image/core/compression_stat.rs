@@ -0,0 +1,144 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Statistics about compression and deduplication of data chunks generated by the builder.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+/// Maximum depth of directories to collect statistics for, files in deeper directories are
+/// accounted into their ancestor at this depth.
+const DIR_STAT_DEPTH: usize = 3;
+/// Key for files without extension.
+const NO_EXTENSION: &str = "<none>";
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct CompressionStatEntry {
+    /// Size of file data before compression.
+    pub original_size: u64,
+    /// Size of file data written into the data blob after compression.
+    pub compressed_size: u64,
+    /// Number of data chunks, including deduplicated chunks.
+    pub chunks: u64,
+    /// Number of chunks deduplicated against chunks already in blobs or the chunk dictionary.
+    pub dedup_chunks: u64,
+    /// Size of file data not written into the data blob thanks to chunk deduplication.
+    pub dedup_size: u64,
+}
+
+impl CompressionStatEntry {
+    fn add(&mut self, uncompressed_size: u32, compressed_size: Option<u32>) {
+        self.original_size += uncompressed_size as u64;
+        self.chunks += 1;
+        match compressed_size {
+            Some(size) => self.compressed_size += size as u64,
+            None => {
+                self.dedup_chunks += 1;
+                self.dedup_size += uncompressed_size as u64;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.original_size += other.original_size;
+        self.compressed_size += other.compressed_size;
+        self.chunks += other.chunks;
+        self.dedup_chunks += other.dedup_chunks;
+        self.dedup_size += other.dedup_size;
+    }
+}
+
+/// Compression statistics aggregated by directory and by file extension.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CompressionStat {
+    pub total: CompressionStatEntry,
+    /// Statistics for directories up to depth of `DIR_STAT_DEPTH`, including all descendants.
+    pub directories: BTreeMap<String, CompressionStatEntry>,
+    /// Statistics for file name extensions.
+    pub extensions: BTreeMap<String, CompressionStatEntry>,
+}
+
+impl CompressionStat {
+    /// Account a data chunk of the file at `target` in the image.
+    ///
+    /// `compressed_size` is None if the chunk has been deduplicated.
+    pub fn add_chunk(
+        &mut self,
+        target: &Path,
+        uncompressed_size: u32,
+        compressed_size: Option<u32>,
+    ) {
+        self.total.add(uncompressed_size, compressed_size);
+
+        if let Some(parent) = target.parent() {
+            let mut dir = PathBuf::new();
+            // The first component is the root directory.
+            for component in parent.components().take(DIR_STAT_DEPTH + 1) {
+                dir.push(component);
+                self.directories
+                    .entry(dir.to_string_lossy().to_string())
+                    .or_default()
+                    .add(uncompressed_size, compressed_size);
+            }
+        }
+
+        let extension = target
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_else(|| NO_EXTENSION.to_string());
+        self.extensions
+            .entry(extension)
+            .or_default()
+            .add(uncompressed_size, compressed_size);
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.total.merge(&other.total);
+        for (dir, entry) in other.directories.iter() {
+            self.directories
+                .entry(dir.clone())
+                .or_default()
+                .merge(entry);
+        }
+        for (ext, entry) in other.extensions.iter() {
+            self.extensions.entry(ext.clone()).or_default().merge(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_stat() {
+        let mut stat = CompressionStat::default();
+        stat.add_chunk(Path::new("/usr/lib/a/b/libc.so"), 0x1000, Some(0x800));
+        stat.add_chunk(Path::new("/usr/lib/a/b/libc.so"), 0x1000, None);
+        stat.add_chunk(Path::new("/README"), 0x100, Some(0x100));
+
+        assert_eq!(stat.total.original_size, 0x2100);
+        assert_eq!(stat.total.compressed_size, 0x900);
+        assert_eq!(stat.total.chunks, 3);
+        assert_eq!(stat.total.dedup_chunks, 1);
+        assert_eq!(stat.total.dedup_size, 0x1000);
+
+        assert_eq!(stat.directories.len(), 4);
+        assert_eq!(stat.directories["/"].chunks, 3);
+        assert_eq!(stat.directories["/usr"].chunks, 2);
+        assert_eq!(stat.directories["/usr/lib/a"].original_size, 0x2000);
+        assert!(!stat.directories.contains_key("/usr/lib/a/b"));
+
+        assert_eq!(stat.extensions["so"].compressed_size, 0x800);
+        assert_eq!(stat.extensions[NO_EXTENSION].original_size, 0x100);
+
+        let mut merged = CompressionStat::default();
+        merged.merge(&stat);
+        merged.merge(&stat);
+        assert_eq!(merged.total.chunks, 6);
+        assert_eq!(merged.directories["/usr"].dedup_chunks, 2);
+    }
+}
@@ -9,6 +9,8 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::{remove_file, rename, File, OpenOptions};
 use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -27,12 +29,17 @@ use rafs::metadata::layout::v6::EROFS_BLOCK_SIZE;
 use rafs::metadata::RafsSuperFlags;
 use rafs::metadata::{Inode, RAFS_DEFAULT_CHUNK_SIZE, RAFS_MAX_CHUNK_SIZE};
 use rafs::{RafsIoReader, RafsIoWrite};
-use storage::compress;
+use storage::backend::BlobUploader;
 use storage::device::BlobFeatures;
 use storage::device::BlobInfo;
+use storage::factory::{BackendConfig, BLOB_FACTORY};
 use storage::meta::{BlobChunkInfoOndisk, BlobMetaHeaderOndisk};
+use storage::{compress, crypt};
 
+use super::build_cache::BuildCache;
 use super::chunk_dict::{ChunkDict, HashChunkDict};
+use super::compression_stat::CompressionStat;
+use super::filter::Filter;
 use super::layout::BlobLayout;
 use super::node::{ChunkWrapper, Node, WhiteoutSpec};
 use super::prefetch::{Prefetch, PrefetchPolicy};
@@ -69,6 +76,7 @@ pub enum SourceType {
     Directory,
     StargzIndex,
     Diff,
+    Tarball,
 }
 
 impl Default for SourceType {
@@ -84,6 +92,7 @@ impl FromStr for SourceType {
             "directory" => Ok(Self::Directory),
             "stargz_index" => Ok(Self::StargzIndex),
             "diff" => Ok(Self::Diff),
+            "tarball" => Ok(Self::Tarball),
             _ => Err(anyhow!("invalid source type")),
         }
     }
@@ -95,6 +104,8 @@ pub enum ArtifactStorage {
     SingleFile(PathBuf),
     // Will rename it from tmp file as user didn't specify a name.
     FileDir(PathBuf),
+    // Upload to the storage backend directly, only for data blobs.
+    Backend(BackendConfig),
 }
 
 impl Default for ArtifactStorage {
@@ -108,6 +119,8 @@ impl ArtifactStorage {
         match self {
             Self::SingleFile(path) => path.to_path_buf(),
             Self::FileDir(base) => base.join(name),
+            // Bootstraps are never uploaded to storage backends.
+            Self::Backend(_) => PathBuf::new(),
         }
     }
 }
@@ -120,6 +133,8 @@ pub struct ArtifactBufferWriter {
     // Keep this because tmp file will be removed automatically when it is dropped.
     // But we will rename/link the tmp file before it is removed.
     tmp_file: Option<TempFile>,
+    // Exclusive lock on the single file, held until the writer is dropped.
+    _lock: Option<File>,
 }
 
 impl RafsIoWrite for ArtifactBufferWriter {
@@ -148,19 +163,34 @@ impl ArtifactBufferWriter {
     pub fn new(storage: ArtifactStorage) -> Result<Self> {
         match storage {
             ArtifactStorage::SingleFile(ref p) => {
-                let b = BufWriter::with_capacity(
-                    BUF_WRITER_CAPACITY,
-                    OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .truncate(true)
-                        .open(p)
-                        .with_context(|| format!("failed to open file {:?}", p))?,
-                );
+                // Hold an exclusive lock until the file is completely written, so nydusd won't
+                // load it while it's being written, and it won't be truncated while mounted.
+                let lock = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(p)
+                    .with_context(|| format!("failed to open file {:?}", p))?;
+                // Safe because the fd is valid.
+                if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+                    bail!(
+                        "failed to lock file {:?}, {}",
+                        p,
+                        std::io::Error::last_os_error()
+                    );
+                }
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(p)
+                    .with_context(|| format!("failed to open file {:?}", p))?;
+                let b = BufWriter::with_capacity(BUF_WRITER_CAPACITY, file);
                 Ok(Self {
                     file: b,
                     storage,
                     tmp_file: None,
+                    _lock: Some(lock),
                 })
             }
             ArtifactStorage::FileDir(ref p) => {
@@ -173,8 +203,12 @@ impl ArtifactBufferWriter {
                     file: BufWriter::with_capacity(BUF_WRITER_CAPACITY, tmp2),
                     storage,
                     tmp_file: Some(tmp),
+                    _lock: None,
                 })
             }
+            ArtifactStorage::Backend(ref c) => {
+                bail!("can't write to {} storage backend as file", c.backend_type)
+            }
         }
     }
 
@@ -220,6 +254,73 @@ impl ArtifactBufferWriter {
     }
 }
 
+/// BlobWriter writes blob data to a local file, or uploads it to a storage backend on the fly.
+pub enum BlobWriter {
+    File(ArtifactBufferWriter),
+    Backend {
+        uploader: Box<dyn BlobUploader>,
+        pos: u64,
+    },
+}
+
+impl BlobWriter {
+    pub fn new(storage: ArtifactStorage, blob_id: &str) -> Result<Self> {
+        match storage {
+            ArtifactStorage::Backend(config) => {
+                let blob_id = if blob_id.is_empty() {
+                    None
+                } else {
+                    Some(blob_id)
+                };
+                let backend_type = config.backend_type.clone();
+                let uploader = BLOB_FACTORY
+                    .new_uploader(config, blob_id)
+                    .with_context(|| format!("failed to upload blob to {}", backend_type))?;
+                Ok(Self::Backend { uploader, pos: 0 })
+            }
+            _ => Ok(Self::File(ArtifactBufferWriter::new(storage)?)),
+        }
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        match self {
+            Self::File(writer) => writer.write_all(buf),
+            Self::Backend { uploader, pos } => {
+                uploader
+                    .write(buf)
+                    .map_err(|e| anyhow!("failed to upload blob data, {:?}", e))?;
+                *pos += buf.len() as u64;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn get_pos(&mut self) -> Result<u64> {
+        match self {
+            Self::File(writer) => writer.get_pos(),
+            Self::Backend { pos, .. } => Ok(*pos),
+        }
+    }
+
+    /// Commit the blob as `name`, or discard it if `name` is None.
+    pub fn release(self, name: Option<&str>) -> Result<()> {
+        match self {
+            Self::File(writer) => writer.release(name),
+            Self::Backend { mut uploader, .. } => {
+                if let Some(n) = name {
+                    uploader
+                        .commit(n)
+                        .map_err(|e| anyhow!("failed to commit blob {}, {:?}", n, e))
+                } else {
+                    uploader
+                        .abort()
+                        .map_err(|e| anyhow!("failed to abort blob upload, {:?}", e))
+                }
+            }
+        }
+    }
+}
+
 /// BlobContext is used to hold the blob information of a layer during build.
 pub struct BlobContext {
     /// Blob id (user specified or sha256(blob)).
@@ -252,23 +353,36 @@ pub struct BlobContext {
     pub chunk_data_buf: Vec<u8>,
     /// ChunkDict which would be loaded when builder start
     pub chunk_dict: Arc<dyn ChunkDict>,
-
-    // Blob writer for writing to disk file.
-    pub writer: Option<ArtifactBufferWriter>,
+    /// Compression statistics of chunks dumped into the blob.
+    pub compression_stat: CompressionStat,
+    /// Algorithm to encrypt chunk data of the blob.
+    pub cipher: crypt::Algorithm,
+    /// Id of the key to encrypt chunk data of the blob.
+    pub cipher_key_id: String,
+
+    // Blob writer for writing to disk file or storage backend.
+    pub writer: Option<BlobWriter>,
+    /// Blobs sealed on reaching the maximum blob size, followed by this blob in the blob table.
+    pub sealed_blobs: Vec<BlobContext>,
+    // Storage to write blobs following this one when the blob is split.
+    blob_storage: Option<ArtifactStorage>,
 }
 
 impl BlobContext {
     pub fn new(blob_id: String, blob_stor: Option<ArtifactStorage>) -> Result<Self> {
-        let writer = if let Some(blob_stor) = blob_stor {
-            Some(ArtifactBufferWriter::new(blob_stor)?)
+        let writer = if let Some(blob_stor) = blob_stor.as_ref() {
+            Some(BlobWriter::new(blob_stor.clone(), &blob_id)?)
         } else {
             None
         };
 
-        Ok(Self::new_with_writer(blob_id, writer))
+        let mut ctx = Self::new_with_writer(blob_id, writer);
+        ctx.blob_storage = blob_stor;
+
+        Ok(ctx)
     }
 
-    pub fn new_with_writer(blob_id: String, writer: Option<ArtifactBufferWriter>) -> Self {
+    pub fn new_with_writer(blob_id: String, writer: Option<BlobWriter>) -> Self {
         let size = if writer.is_some() {
             RAFS_MAX_CHUNK_SIZE as usize
         } else {
@@ -294,9 +408,42 @@ impl BlobContext {
             chunk_size: RAFS_DEFAULT_CHUNK_SIZE as u32,
             chunk_data_buf: vec![0u8; size],
             chunk_dict: Arc::new(()),
+            compression_stat: CompressionStat::default(),
+            cipher: crypt::Algorithm::None,
+            cipher_key_id: String::new(),
 
             writer,
+            sealed_blobs: Vec::new(),
+            blob_storage: None,
+        }
+    }
+
+    /// Create an empty blob to follow this blob when it's split, with the same settings.
+    pub fn new_successor(&self) -> Result<Self> {
+        let mut ctx = Self::new(String::new(), self.blob_storage.clone())?;
+        ctx.chunk_dict = self.chunk_dict.clone();
+        ctx.chunk_size = self.chunk_size;
+        ctx.blob_meta_info_enabled = self.blob_meta_info_enabled;
+        ctx.cipher = self.cipher;
+        ctx.cipher_key_id = self.cipher_key_id.clone();
+
+        Ok(ctx)
+    }
+
+    /// Check whether the blob must be split before dumping a chunk of `size` bytes into it, to
+    /// keep the blob within `max_size` bytes including the chunk information array appended.
+    pub fn need_split(&self, max_size: u64, size: usize) -> bool {
+        if max_size == 0 || self.compressed_blob_size == 0 {
+            return false;
         }
+        let meta_size = if self.blob_meta_info_enabled {
+            (self.blob_meta_info.len() as u64 + 1) * size_of::<BlobChunkInfoOndisk>() as u64
+                + size_of::<BlobMetaHeaderOndisk>() as u64
+        } else {
+            0
+        };
+
+        self.compressed_blob_size + size as u64 + meta_size > max_size
     }
 
     pub fn set_chunk_dict(&mut self, dict: Arc<dyn ChunkDict>) {
@@ -377,6 +524,8 @@ impl From<&BlobInfo> for BlobContext {
         ctx.chunk_count = blob.chunk_count();
         ctx.decompressed_blob_size = blob.uncompressed_size();
         ctx.compressed_blob_size = blob.compressed_size();
+        ctx.cipher = blob.cipher();
+        ctx.cipher_key_id = blob.cipher_key_id().to_owned();
 
         ctx
     }
@@ -425,8 +574,14 @@ impl BlobManager {
 
     /// Add a blob context to manager
     ///
-    /// This should be paired with Self::alloc_index() and keep in consistence.
-    pub fn add(&mut self, blob_ctx: Option<BlobContext>) {
+    /// This should be paired with Self::alloc_index() and keep in consistence. Blobs sealed when
+    /// splitting the blob are added before it, in the order of their blob indexes.
+    pub fn add(&mut self, mut blob_ctx: Option<BlobContext>) {
+        if let Some(ctx) = blob_ctx.as_mut() {
+            for sealed in ctx.sealed_blobs.drain(..) {
+                self.blobs.push(Some(sealed));
+            }
+        }
         self.blobs.push(blob_ctx);
     }
 
@@ -443,6 +598,15 @@ impl BlobManager {
         self.blobs.last().unwrap_or(&None).as_ref()
     }
 
+    /// Get compression statistics of all blobs generated in this build.
+    pub fn get_compression_stat(&self) -> CompressionStat {
+        let mut stat = CompressionStat::default();
+        for blob_ctx in self.blobs.iter().flatten() {
+            stat.merge(&blob_ctx.compression_stat);
+        }
+        stat
+    }
+
     pub fn from_blob_table(&mut self, blob_table: Vec<Arc<BlobInfo>>) {
         self.blobs = blob_table
             .iter()
@@ -528,6 +692,10 @@ impl BlobManager {
                     blob_features,
                     flags,
                 );
+                if !ctx.cipher.is_none() {
+                    let index = blob_table.entries.len() as u32 - 1;
+                    blob_table.set_cipher(index, ctx.cipher, &ctx.cipher_key_id)?;
+                }
             }
             if idx == up_idx {
                 break;
@@ -708,6 +876,37 @@ pub struct BuildContext {
     /// Storage writing blob to single file or a directory.
     pub blob_storage: Option<ArtifactStorage>,
     pub has_xattr: bool,
+
+    /// Build from a complete filesystem instead of a layer, and only dump files added or changed
+    /// relative to the parent bootstrap.
+    pub incremental: bool,
+
+    /// Number of worker threads to digest and compress data chunks.
+    pub threads: usize,
+
+    /// Clear modification time of inodes to generate reproducible images.
+    pub zero_timestamps: bool,
+
+    /// Record creation time of files by extended attributes.
+    pub record_btime: bool,
+
+    /// Reject directories containing names which only differ in case, so the image may be mounted
+    /// case-insensitively.
+    pub check_case_collision: bool,
+
+    /// Filters to select files from the source directory.
+    pub filter: Filter,
+
+    /// Cache of data chunks generated by previous builds.
+    pub build_cache: Option<Arc<BuildCache>>,
+
+    /// Cipher to encrypt data chunks, and id of its key recorded in the blob table.
+    pub cipher: Option<Arc<crypt::Cipher>>,
+    pub cipher_key_id: String,
+
+    /// Maximum size of a data blob, 0 for no limit. Data chunks are dumped into new blobs once
+    /// the limit is reached, so one build may generate multiple blobs.
+    pub blob_max_size: u64,
 }
 
 impl BuildContext {
@@ -741,6 +940,16 @@ impl BuildContext {
             prefetch,
             blob_storage,
             has_xattr: false,
+            incremental: false,
+            threads: 1,
+            zero_timestamps: false,
+            record_btime: false,
+            check_case_collision: false,
+            filter: Filter::default(),
+            build_cache: None,
+            cipher: None,
+            cipher_key_id: String::new(),
+            blob_max_size: 0,
         }
     }
 
@@ -751,6 +960,43 @@ impl BuildContext {
     pub fn set_chunk_size(&mut self, chunk_size: u32) {
         self.chunk_size = chunk_size;
     }
+
+    pub fn set_incremental(&mut self, incremental: bool) {
+        self.incremental = incremental;
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    pub fn set_zero_timestamps(&mut self, zero_timestamps: bool) {
+        self.zero_timestamps = zero_timestamps;
+    }
+
+    pub fn set_record_btime(&mut self, record_btime: bool) {
+        self.record_btime = record_btime;
+    }
+
+    pub fn set_check_case_collision(&mut self, check_case_collision: bool) {
+        self.check_case_collision = check_case_collision;
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    pub fn set_build_cache(&mut self, cache: BuildCache) {
+        self.build_cache = Some(Arc::new(cache));
+    }
+
+    pub fn set_cipher(&mut self, cipher: crypt::Cipher, key_id: String) {
+        self.cipher = Some(Arc::new(cipher));
+        self.cipher_key_id = key_id;
+    }
+
+    pub fn set_blob_max_size(&mut self, blob_max_size: u64) {
+        self.blob_max_size = blob_max_size;
+    }
 }
 
 #[derive(Serialize, Default, Debug, Clone)]
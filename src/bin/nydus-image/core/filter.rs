@@ -0,0 +1,136 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filters to select files from the source directory when building images.
+//!
+//! Patterns are shell style globs. A pattern without `/` matches file names at any depth,
+//! otherwise it matches paths relative to the root of the source directory.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+
+use super::node::Node;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Clone)]
+struct FilterPattern {
+    pattern: Pattern,
+    /// Whether to match file names instead of the whole paths.
+    name_only: bool,
+}
+
+impl FilterPattern {
+    fn new(pattern: &str) -> Result<Self> {
+        let name_only = !pattern.contains('/');
+        let pattern = if name_only || pattern.starts_with('/') {
+            pattern.to_string()
+        } else {
+            format!("/{}", pattern)
+        };
+        let pattern = Pattern::new(pattern.trim_end_matches('/'))
+            .with_context(|| format!("invalid filter pattern {}", pattern))?;
+
+        Ok(Self { pattern, name_only })
+    }
+
+    fn matches(&self, target: &Path) -> bool {
+        if self.name_only {
+            target
+                .file_name()
+                .map(|name| {
+                    self.pattern
+                        .matches_with(&name.to_string_lossy(), MATCH_OPTIONS)
+                })
+                .unwrap_or(false)
+        } else {
+            self.pattern.matches_path_with(target, MATCH_OPTIONS)
+        }
+    }
+}
+
+/// Select files to build into the image.
+#[derive(Clone, Default)]
+pub struct Filter {
+    include: Vec<FilterPattern>,
+    exclude: Vec<FilterPattern>,
+    skip_special: bool,
+}
+
+impl Filter {
+    pub fn new(include: &[&str], exclude: &[&str], skip_special: bool) -> Result<Self> {
+        Ok(Self {
+            include: include
+                .iter()
+                .map(|p| FilterPattern::new(*p))
+                .collect::<Result<_>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| FilterPattern::new(*p))
+                .collect::<Result<_>>()?,
+            skip_special,
+        })
+    }
+
+    /// Check whether any filter is specified.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && !self.skip_special
+    }
+
+    /// Check whether the file at `target` is selected by include patterns.
+    ///
+    /// All files are selected if there's no include pattern. Descendants of a selected
+    /// directory are selected too.
+    pub fn is_included(&self, target: &Path) -> bool {
+        self.include.is_empty() || self.include.iter().any(|p| p.matches(target))
+    }
+
+    /// Check whether the file at `target` and all its descendants should be dropped.
+    pub fn is_excluded(&self, target: &Path) -> bool {
+        self.exclude.iter().any(|p| p.matches(target))
+    }
+
+    /// Check whether `node` should be dropped because it's a socket or device file.
+    pub fn is_skipped(&self, node: &Node) -> bool {
+        self.skip_special
+            && (node.inode.is_sock() || node.inode.is_chrdev() || node.inode.is_blkdev())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_patterns() {
+        let filter = Filter::new(
+            &["/usr/bin", "etc/*.conf"],
+            &["*.log", "/var/cache/**"],
+            false,
+        )
+        .unwrap();
+
+        assert!(filter.is_included(Path::new("/usr/bin")));
+        assert!(filter.is_included(Path::new("/etc/a.conf")));
+        assert!(!filter.is_included(Path::new("/etc/sub/a.conf")));
+        assert!(!filter.is_included(Path::new("/usr")));
+
+        assert!(filter.is_excluded(Path::new("/a.log")));
+        assert!(filter.is_excluded(Path::new("/var/log/a.log")));
+        assert!(filter.is_excluded(Path::new("/var/cache/apt")));
+        assert!(filter.is_excluded(Path::new("/var/cache/apt/archives")));
+        assert!(!filter.is_excluded(Path::new("/var/log")));
+
+        let filter = Filter::new(&[], &[], false).unwrap();
+        assert!(filter.is_empty());
+        assert!(filter.is_included(Path::new("/usr")));
+        assert!(!filter.is_excluded(Path::new("/usr")));
+    }
+}
@@ -4,9 +4,13 @@
 
 pub(crate) mod blob;
 pub(crate) mod bootstrap;
+pub(crate) mod build_cache;
 pub(crate) mod chunk_dict;
+pub(crate) mod compression_stat;
 pub(crate) mod context;
+pub(crate) mod filter;
 pub(crate) mod layout;
 pub(crate) mod node;
+pub(crate) mod pipeline;
 pub(crate) mod prefetch;
 pub(crate) mod tree;
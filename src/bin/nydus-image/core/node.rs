@@ -10,12 +10,13 @@ use std::fmt::{self, Display, Formatter};
 use std::fs::{self, File};
 use std::io::Read;
 use std::io::SeekFrom;
-use std::mem::size_of;
+use std::mem::{self, size_of};
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 use anyhow::{Context, Error, Result};
 use nix::sys::stat;
@@ -36,12 +37,15 @@ use rafs::metadata::layout::v6::{
     EROFS_INODE_CHUNK_BASED, EROFS_INODE_FLAT_INLINE, EROFS_INODE_FLAT_PLAIN,
 };
 use rafs::metadata::layout::RafsXAttrs;
-use rafs::metadata::{Inode, RafsInode, RafsStore};
+use rafs::metadata::{Inode, RafsInode, RafsStore, RAFS_XATTR_BTIME};
 use rafs::RafsIoWrite;
 use storage::compress;
+use storage::crypt;
 use storage::device::v5::BlobV5ChunkInfo;
 use storage::device::{BlobChunkFlags, BlobChunkInfo};
 
+use super::blob::Blob;
+use super::build_cache::BuildCache;
 use super::chunk_dict::ChunkDict;
 use super::context::{BlobContext, BootstrapContext, BuildContext, RafsVersion};
 use super::tree::Tree;
@@ -102,6 +106,8 @@ pub enum WhiteoutSpec {
     Oci,
     /// "whiteouts and opaque directories" in https://www.kernel.org/doc/Documentation/filesystems/overlayfs.txt
     Overlayfs,
+    /// Keep whiteouts as ordinary files, for layers to be merged later.
+    Disabled,
 }
 
 impl Default for WhiteoutSpec {
@@ -117,6 +123,7 @@ impl FromStr for WhiteoutSpec {
         match s {
             "oci" => Ok(Self::Oci),
             "overlayfs" => Ok(Self::Overlayfs),
+            "none" => Ok(Self::Disabled),
             _ => Err(anyhow!("invalid whiteout spec")),
         }
     }
@@ -263,6 +270,76 @@ impl Node {
         Ok(node)
     }
 
+    /// Create a whiteout node to remove the inode from lower layers, as per `ctx.whiteout_spec`.
+    ///
+    /// The whiteout has no backing file, so it's marked as removal to skip dumping blob data and
+    /// its digest is set here.
+    pub fn create_whiteout(&self, ctx: &BuildContext) -> Result<Node> {
+        let (name, mode, rdev) = match ctx.whiteout_spec {
+            WhiteoutSpec::Oci => {
+                let mut name = OsString::from(OCISPEC_WHITEOUT_PREFIX);
+                name.push(self.name());
+                (name, libc::S_IFREG | 0o644, 0)
+            }
+            WhiteoutSpec::Overlayfs => (
+                self.name().to_os_string(),
+                libc::S_IFCHR,
+                stat::makedev(0, 0),
+            ),
+            WhiteoutSpec::Disabled => bail!("can't remove {:?} without whiteout", self.target),
+        };
+
+        let mut inode = RafsV5Inode::new();
+        inode.i_mode = mode;
+        inode.i_rdev = rdev as u32;
+        inode.i_nlink = 1;
+        inode.i_name_size = name.byte_size() as u16;
+
+        let mut node = self.clone();
+        node.inode = match self.inode {
+            InodeWrapper::V5(_) => InodeWrapper::V5(inode),
+            InodeWrapper::V6(_) => InodeWrapper::V6(inode),
+        };
+        node.rdev = rdev;
+        node.chunks.clear();
+        node.xattrs = RafsXAttrs::default();
+        node.symlink = None;
+        node.overlay = Overlay::UpperRemoval;
+        node.path = self.path.with_file_name(&name);
+        node.target = self.target.with_file_name(&name);
+        node.target_vec = Self::generate_target_vec(&node.target);
+        node.set_v6_inode_compact();
+        if node.is_reg() {
+            node.dump_reg_digest(ctx);
+        } else {
+            node.dump_non_reg_digest(ctx)?;
+        }
+
+        Ok(node)
+    }
+
+    /// Record creation time of the source file by the `user.nydus.btime` extended attribute.
+    ///
+    /// Nothing is recorded if the source filesystem doesn't provide creation time.
+    pub fn record_btime(&mut self) -> Result<()> {
+        let meta = self
+            .path
+            .symlink_metadata()
+            .with_context(|| format!("failed to get metadata from {:?}", self.path))?;
+        let created = match meta.created() {
+            Ok(t) => t,
+            Err(_) => return Ok(()),
+        };
+        // Files created before the epoch aren't expected, record them as created at the epoch.
+        let btime = created.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let value = format!("{}.{:09}", btime.as_secs(), btime.subsec_nanos());
+        self.xattrs
+            .add(OsString::from(RAFS_XATTR_BTIME), value.into_bytes());
+        self.inode.set_has_xattr(true);
+        // Size of extended attributes is accounted into blocks of the inode.
+        self.build_inode_stat()
+    }
+
     /// Delete an extend attribute with id `key`.
     pub fn remove_xattr(&mut self, key: &OsStr) {
         self.xattrs.remove(key);
@@ -278,141 +355,354 @@ impl Node {
         blob_index: u32,
         chunk_dict: &mut T,
     ) -> Result<u64> {
-        if self.is_dir() {
+        if !self.is_reg() {
+            self.dump_non_reg_digest(ctx)?;
             return Ok(0);
-        } else if self.is_symlink() {
+        } else if let Some(cache) = ctx.build_cache.as_ref() {
+            return self.dump_blob_with_cache(ctx, blob_ctx, blob_index, chunk_dict, cache);
+        }
+
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("failed to open node file {:?}", self.path))?;
+        self.dump_blob_from_reader(ctx, blob_ctx, blob_index, chunk_dict, &mut file)
+    }
+
+    /// Dump data chunks from the build cache if the file content has been cached, otherwise
+    /// dump them from the file and record them into the build cache.
+    fn dump_blob_with_cache<T: ChunkDict>(
+        &mut self,
+        ctx: &BuildContext,
+        blob_ctx: &mut BlobContext,
+        blob_index: u32,
+        chunk_dict: &mut T,
+        cache: &BuildCache,
+    ) -> Result<u64> {
+        let file_digest = BuildCache::file_digest(&self.path, ctx.digester)?;
+        let cached = cache.get_file_chunks(&file_digest);
+
+        let blob_size = match cached {
+            Some(chunks) if chunks.len() == self.inode.child_count() as usize => {
+                let mut blob_size = 0u64;
+                for (index, (chunk_id, chunk)) in chunks.iter().enumerate() {
+                    let (file_offset, chunk_size) =
+                        self.chunk_range(index as u32, blob_ctx.chunk_size)?;
+                    if self.dedup_chunk(
+                        ctx,
+                        blob_ctx,
+                        chunk_dict,
+                        *chunk_id,
+                        file_offset,
+                        chunk_size,
+                    ) {
+                        continue;
+                    }
+                    let compressed = cache.read_chunk(chunk_id, chunk)?;
+                    blob_size += self.dump_chunk(
+                        ctx,
+                        blob_ctx,
+                        blob_index,
+                        chunk_dict,
+                        *chunk_id,
+                        file_offset,
+                        chunk_size,
+                        &compressed,
+                        chunk.is_compressed,
+                    )?;
+                }
+                self.dump_reg_digest(ctx);
+                blob_size
+            }
+            _ => {
+                let mut file = File::open(&self.path)
+                    .with_context(|| format!("failed to open node file {:?}", self.path))?;
+                self.dump_blob_from_reader(ctx, blob_ctx, blob_index, chunk_dict, &mut file)?
+            }
+        };
+
+        let chunk_ids: Vec<RafsDigest> = self.chunks.iter().map(|c| *c.id()).collect();
+        cache.add_file(&file_digest, &chunk_ids);
+
+        Ok(blob_size)
+    }
+
+    /// Dump data of a regular file read from `reader`, which must provide exactly
+    /// `inode.size()` bytes, such as an entry of a tar stream.
+    pub fn dump_blob_from_reader<T: ChunkDict, R: Read>(
+        &mut self,
+        ctx: &BuildContext,
+        blob_ctx: &mut BlobContext,
+        blob_index: u32,
+        chunk_dict: &mut T,
+        reader: &mut R,
+    ) -> Result<u64> {
+        // Take the scratch buffer out of `blob_ctx`, so chunk data can be referenced while
+        // updating the blob context.
+        let mut chunk_data_buf = mem::take(&mut blob_ctx.chunk_data_buf);
+        let ret = self.dump_file_chunks(
+            ctx,
+            blob_ctx,
+            blob_index,
+            chunk_dict,
+            reader,
+            &mut chunk_data_buf,
+        );
+        blob_ctx.chunk_data_buf = chunk_data_buf;
+
+        ret
+    }
+
+    fn dump_file_chunks<T: ChunkDict, R: Read>(
+        &mut self,
+        ctx: &BuildContext,
+        blob_ctx: &mut BlobContext,
+        blob_index: u32,
+        chunk_dict: &mut T,
+        reader: &mut R,
+        chunk_data_buf: &mut [u8],
+    ) -> Result<u64> {
+        let mut blob_size = 0u64;
+
+        // `child_count` of regular file is reused as `chunk_count`.
+        for i in 0..self.inode.child_count() {
+            let (file_offset, chunk_size) = self.chunk_range(i, blob_ctx.chunk_size)?;
+            let chunk_data = &mut chunk_data_buf[0..chunk_size as usize];
+            reader
+                .read_exact(chunk_data)
+                .with_context(|| format!("failed to read node file {:?}", self.path))?;
+
+            // TODO: check for hole chunks. One possible way is to always save
+            // a global hole chunk and check for digest duplication
+            let chunk_id = RafsDigest::from_buf(chunk_data, ctx.digester);
+            if self.dedup_chunk(ctx, blob_ctx, chunk_dict, chunk_id, file_offset, chunk_size) {
+                continue;
+            }
+
+            // Compress chunk data
+            let (compressed, is_compressed) = compress::compress(chunk_data, ctx.compressor)
+                .with_context(|| format!("failed to compress node file {:?}", self.path))?;
+            blob_size += self.dump_chunk(
+                ctx,
+                blob_ctx,
+                blob_index,
+                chunk_dict,
+                chunk_id,
+                file_offset,
+                chunk_size,
+                &compressed,
+                is_compressed,
+            )?;
+        }
+
+        self.dump_reg_digest(ctx);
+
+        Ok(blob_size)
+    }
+
+    /// Set digest for inodes without data chunks, such as directories, symlinks and special files.
+    pub fn dump_non_reg_digest(&mut self, ctx: &BuildContext) -> Result<()> {
+        if self.is_symlink() {
             if let Some(symlink) = self.symlink.as_ref() {
                 self.inode
                     .set_digest(RafsDigest::from_buf(symlink.as_bytes(), ctx.digester));
-                return Ok(0);
             } else {
                 return Err(Error::msg("inode's symblink is invalid."));
             }
         } else if self.is_special() {
             self.inode
                 .set_digest(RafsDigest::hasher(ctx.digester).digest_finalize());
-            return Ok(0);
         }
 
-        let mut file = File::open(&self.path)
-            .with_context(|| format!("failed to open node file {:?}", self.path))?;
+        Ok(())
+    }
+
+    /// Set digest for regular file inodes from digests of all its data chunks.
+    pub fn dump_reg_digest(&mut self, ctx: &BuildContext) {
         let mut inode_hasher = RafsDigest::hasher(ctx.digester);
-        let mut blob_size = 0u64;
+        for chunk in &self.chunks {
+            inode_hasher.digest_update(chunk.id().as_ref());
+        }
+        self.inode.set_digest(inode_hasher.digest_finalize());
+    }
 
-        // `child_count` of regular file is reused as `chunk_count`.
-        for i in 0..self.inode.child_count() {
-            let chunk_size = blob_ctx.chunk_size;
-            let file_offset = i as u64 * chunk_size as u64;
-            let chunk_size = if i == self.inode.child_count() - 1 {
-                (self.inode.size() as u64)
-                    .checked_sub((chunk_size * i) as u64)
-                    .ok_or_else(|| {
-                        anyhow!("the rest chunk size of inode is bigger than chunk_size")
-                    })? as u32
-            } else {
-                chunk_size
-            };
+    /// Get file offset and size of the `index`th data chunk of regular file.
+    pub fn chunk_range(&self, index: u32, chunk_size: u32) -> Result<(u64, u32)> {
+        let file_offset = index as u64 * chunk_size as u64;
+        let chunk_size = if index == self.inode.child_count() - 1 {
+            (self.inode.size() as u64)
+                .checked_sub(file_offset)
+                .ok_or_else(|| anyhow!("the rest chunk size of inode is bigger than chunk_size"))?
+                as u32
+        } else {
+            chunk_size
+        };
 
-            let mut chunk_data = &mut blob_ctx.chunk_data_buf[0..chunk_size as usize];
-            file.read_exact(&mut chunk_data)
-                .with_context(|| format!("failed to read node file {:?}", self.path))?;
+        Ok((file_offset, chunk_size))
+    }
 
-            // TODO: check for hole chunks. One possible way is to always save
-            // a global hole chunk and check for digest duplication
-            let chunk_id = RafsDigest::from_buf(chunk_data, ctx.digester);
-            inode_hasher.digest_update(chunk_id.as_ref());
+    /// Try to deduplicate a data chunk by matching chunk digest with chunks in the blob being
+    /// built or in the chunk dictionary.
+    ///
+    /// Return true if the chunk has been deduplicated and appended to the inode.
+    pub fn dedup_chunk<T: ChunkDict>(
+        &mut self,
+        ctx: &BuildContext,
+        blob_ctx: &mut BlobContext,
+        chunk_dict: &T,
+        chunk_id: RafsDigest,
+        file_offset: u64,
+        chunk_size: u32,
+    ) -> bool {
+        let exist_chunk = match blob_ctx.chunk_dict.get_chunk(&chunk_id) {
+            Some(v) => Some((v, true)),
+            None => chunk_dict.get_chunk(&chunk_id).map(|v| (v, false)),
+        };
+        if let Some((cached_chunk, from_dict)) = exist_chunk {
+            // TODO: we should also compare the actual data to avoid chunk digest conflicts.
+            // hole cached_chunk may have zero uncompressed size
+            if cached_chunk.uncompressed_size() == 0
+                || cached_chunk.uncompressed_size() == chunk_size
+            {
+                // The chunks of hardlink should be always deduplicated.
+                if !self.is_hardlink() {
+                    event_tracer!("dedup_decompressed_size", +chunk_size);
+                    event_tracer!("dedup_chunks", +1);
+                    blob_ctx
+                        .compression_stat
+                        .add_chunk(&self.target, chunk_size, None);
+                }
 
-            let mut chunk = self.inode.create_chunk();
-            chunk.set_id(chunk_id);
+                let mut chunk = self.inode.create_chunk();
+                chunk.set_id(chunk_id);
+                chunk.copy_from(cached_chunk);
+                chunk.set_file_offset(file_offset);
+                if from_dict {
+                    let idx = blob_ctx.chunk_dict.get_real_blob_idx(chunk.blob_index());
+                    chunk.set_blob_index(idx);
+                }
+                trace!(
+                    "\t\tbuilding duplicated chunk: {} compressor {}",
+                    chunk,
+                    ctx.compressor
+                );
 
-            // Check whether we already have the same chunk data by matching chunk digest.
-            let exist_chunk = match blob_ctx.chunk_dict.get_chunk(&chunk_id) {
-                Some(v) => Some((v, true)),
-                None => chunk_dict.get_chunk(&chunk_id).map(|v| (v, false)),
-            };
-            if let Some((cached_chunk, from_dict)) = exist_chunk {
-                // TODO: we should also compare the actual data to avoid chunk digest conflicts.
-                // hole cached_chunk may have zero uncompressed size
-                if cached_chunk.uncompressed_size() == 0
-                    || cached_chunk.uncompressed_size() == chunk_size
-                {
-                    // The chunks of hardlink should be always deduplicated.
-                    if !self.is_hardlink() {
-                        event_tracer!("dedup_decompressed_size", +chunk_size);
-                        event_tracer!("dedup_chunks", +1);
-                    }
+                self.chunks.push(chunk);
+                return true;
+            }
+        }
+
+        false
+    }
 
-                    chunk.copy_from(cached_chunk);
-                    chunk.set_file_offset(file_offset);
-                    if from_dict {
-                        let idx = blob_ctx.chunk_dict.get_real_blob_idx(chunk.blob_index());
-                        chunk.set_blob_index(idx);
+    /// Write compressed chunk data into the data blob and append the chunk to the inode.
+    ///
+    /// Return size of data written into the data blob.
+    #[allow(clippy::too_many_arguments)]
+    pub fn dump_chunk<T: ChunkDict>(
+        &mut self,
+        ctx: &BuildContext,
+        blob_ctx: &mut BlobContext,
+        blob_index: u32,
+        chunk_dict: &mut T,
+        chunk_id: RafsDigest,
+        file_offset: u64,
+        chunk_size: u32,
+        compressed: &[u8],
+        is_compressed: bool,
+    ) -> Result<u64> {
+        let compressed_size = compressed.len()
+            + ctx
+                .cipher
+                .as_ref()
+                .map(|c| c.algorithm().overhead())
+                .unwrap_or_default();
+        if blob_ctx.need_split(ctx.blob_max_size, compressed_size) {
+            Blob::split(ctx, blob_ctx)?;
+        }
+        // Blobs split from the blob being dumped precede it in the blob table.
+        let blob_index = blob_index + blob_ctx.sealed_blobs.len() as u32;
+        let chunk_index = blob_ctx.alloc_index()?;
+
+        // Chunks are encrypted after compression, the build cache keeps unencrypted chunks so they
+        // may be reused by builds with different keys.
+        let encrypted;
+        let data = match ctx.cipher.as_ref() {
+            Some(cipher) => {
+                // Encrypted chunks are bound to the blob id, which can't be named by the digest
+                // of the encrypted blob itself.
+                if blob_ctx.blob_id.is_empty() {
+                    bail!("encrypting chunks requires the blob id to be specified");
+                }
+                let aad = crypt::chunk_aad(&blob_ctx.blob_id, chunk_index);
+                encrypted = cipher
+                    .encrypt(compressed, &aad)
+                    .context("failed to encrypt chunk")?;
+                if blob_ctx.cipher.is_none() {
+                    if blob_ctx.compressed_blob_size > 0 {
+                        bail!(
+                            "can't encrypt chunks of blob {} with plain chunks",
+                            blob_ctx.blob_id
+                        );
                     }
-                    trace!(
-                        "\t\tbuilding duplicated chunk: {} compressor {}",
-                        chunk,
-                        ctx.compressor
-                    );
-
-                    self.chunks.push(chunk);
-                    continue;
+                    blob_ctx.cipher = cipher.algorithm();
+                    blob_ctx.cipher_key_id = ctx.cipher_key_id.clone();
+                } else if blob_ctx.cipher != cipher.algorithm()
+                    || blob_ctx.cipher_key_id != ctx.cipher_key_id
+                {
+                    bail!("blob {} is encrypted by another key", blob_ctx.blob_id);
                 }
+                &encrypted[..]
             }
+            None => compressed,
+        };
 
-            // Compress chunk data
-            let (compressed, is_compressed) = compress::compress(&chunk_data, ctx.compressor)
-                .with_context(|| format!("failed to compress node file {:?}", self.path))?;
-            let compressed_size = compressed.len();
-
-            // Move cursor to offset of next chunk
-            let aligned_chunk_size = if ctx.aligned_chunk {
-                // Safe to unwrap because `chunk_size` is much less than u32::MAX.
-                try_round_up_4k(chunk_size).unwrap()
-            } else {
-                chunk_size
-            };
+        // Move cursor to offset of next chunk
+        let aligned_chunk_size = if ctx.aligned_chunk {
+            // Safe to unwrap because `chunk_size` is much less than u32::MAX.
+            try_round_up_4k(chunk_size).unwrap()
+        } else {
+            chunk_size
+        };
 
-            let pre_decompress_offset = blob_ctx.decompress_offset;
-            let pre_compress_offset = blob_ctx.compress_offset;
-
-            blob_ctx.compress_offset += compressed_size as u64;
-            blob_ctx.decompressed_blob_size =
-                blob_ctx.decompress_offset + aligned_chunk_size as u64;
-            blob_ctx.compressed_blob_size += compressed_size as u64;
-            blob_ctx.decompress_offset += aligned_chunk_size as u64;
-            blob_ctx.blob_hash.update(&compressed);
-
-            // Dump compressed chunk data to blob
-            event_tracer!("blob_decompressed_size", +chunk_size);
-            event_tracer!("blob_compressed_size", +compressed_size);
-            if let Some(writer) = &mut blob_ctx.writer {
-                writer
-                    .write_all(&compressed)
-                    .context("failed to write blob")?;
-            }
+        let pre_decompress_offset = blob_ctx.decompress_offset;
+        let pre_compress_offset = blob_ctx.compress_offset;
 
-            let chunk_index = blob_ctx.alloc_index()?;
-            chunk.set_chunk_info(
-                blob_index,
-                chunk_index,
-                file_offset,
-                pre_decompress_offset,
-                pre_compress_offset,
-                compressed_size,
-                chunk_size,
-                is_compressed,
-            )?;
+        blob_ctx.compress_offset += compressed_size as u64;
+        blob_ctx.decompressed_blob_size = blob_ctx.decompress_offset + aligned_chunk_size as u64;
+        blob_ctx.compressed_blob_size += compressed_size as u64;
+        blob_ctx.decompress_offset += aligned_chunk_size as u64;
+        blob_ctx.blob_hash.update(data);
 
-            blob_ctx.add_chunk_meta_info(&chunk)?;
-            chunk_dict.add_chunk(chunk.clone());
-            self.chunks.push(chunk);
-            blob_size += compressed_size as u64;
+        // Dump compressed chunk data to blob
+        event_tracer!("blob_decompressed_size", +chunk_size);
+        event_tracer!("blob_compressed_size", +compressed_size);
+        blob_ctx
+            .compression_stat
+            .add_chunk(&self.target, chunk_size, Some(compressed_size as u32));
+        if let Some(writer) = &mut blob_ctx.writer {
+            writer.write_all(data).context("failed to write blob")?;
         }
 
-        // Finish inode digest calculation
-        self.inode.set_digest(inode_hasher.digest_finalize());
+        let mut chunk = self.inode.create_chunk();
+        chunk.set_id(chunk_id);
+        chunk.set_chunk_info(
+            blob_index,
+            chunk_index,
+            file_offset,
+            pre_decompress_offset,
+            pre_compress_offset,
+            compressed_size,
+            chunk_size,
+            is_compressed,
+        )?;
 
-        Ok(blob_size)
+        blob_ctx.add_chunk_meta_info(&chunk)?;
+        if let Some(cache) = ctx.build_cache.as_ref() {
+            cache.add_chunk(&chunk_id, compressed, is_compressed, chunk_size)?;
+        }
+        chunk_dict.add_chunk(chunk.clone());
+        self.chunks.push(chunk);
+
+        Ok(compressed_size as u64)
     }
 
     pub fn dump_bootstrap_v5(
@@ -464,7 +754,7 @@ impl Node {
         }
     }
 
-    fn set_v6_inode_compact(&mut self) {
+    pub(crate) fn set_v6_inode_compact(&mut self) {
         if self.v6_force_extended_inode
             || self.inode.uid() > std::u16::MAX as u32
             || self.inode.gid() > std::u16::MAX as u32
@@ -812,6 +1102,66 @@ impl Node {
         self.inode.is_special()
     }
 
+    /// Check whether a regular file from the source directory is unchanged compared with a node
+    /// loaded from the parent bootstrap, so the data chunks of the lower node could be reused.
+    ///
+    /// Hardlinks are always rebuilt to keep the hardlink relationship correct. Modification time
+    /// is lost if the parent bootstrap is built with zero timestamps, so data of the file is
+    /// compared with chunk digests of the lower node instead.
+    pub fn is_unchanged_from(&self, lower: &Node, ctx: &BuildContext) -> bool {
+        if !self.is_reg() || !lower.is_reg() || lower.is_hardlink() {
+            return false;
+        }
+        match self.meta() {
+            Ok(meta) if meta.st_nlink() == 1 => {}
+            _ => return false,
+        }
+
+        // Same chunk count implies same chunk size, because chunk size is power of two.
+        let same_meta = self.inode.mode() == lower.inode.mode()
+            && self.inode.uid() == lower.inode.uid()
+            && self.inode.gid() == lower.inode.gid()
+            && self.inode.size() == lower.inode.size()
+            && self.chunk_count(ctx.chunk_size as u64) as usize == lower.chunks.len()
+            && self.xattrs == lower.xattrs;
+        if !same_meta {
+            return false;
+        }
+        if self.inode.mtime() == lower.inode.mtime()
+            && self.inode.mtime_nsec() == lower.inode.mtime_nsec()
+        {
+            return true;
+        }
+
+        ctx.zero_timestamps
+            && lower.inode.mtime() == 0
+            && lower.inode.mtime_nsec() == 0
+            && self.has_same_data(lower, ctx)
+    }
+
+    // Check whether data of the file matches digests of data chunks of the lower node.
+    fn has_same_data(&self, lower: &Node, ctx: &BuildContext) -> bool {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+        let chunk_size = ctx.chunk_size as u64;
+        let mut buf = vec![0u8; ctx.chunk_size as usize];
+
+        for (idx, chunk) in lower.chunks.iter().enumerate() {
+            let offset = idx as u64 * chunk_size;
+            let size = std::cmp::min(chunk_size, self.inode.size().saturating_sub(offset));
+            let data = &mut buf[..size as usize];
+            if file.read_exact(data).is_err()
+                || RafsDigest::from_buf(data, ctx.digester) != *chunk.id()
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+
     pub fn chunk_count(&self, chunk_size: u64) -> u32 {
         if self.is_reg() {
             let chunks = div_round_up(self.inode.size(), chunk_size);
@@ -959,6 +1309,7 @@ impl Node {
                     return Some(WhiteoutType::OverlayFsOpaque);
                 }
             }
+            WhiteoutSpec::Disabled => {}
         }
 
         None
@@ -993,6 +1344,14 @@ impl Node {
             bootstrap_ctx.offset += self.inode.child_count() as u64 * unit;
             self.v6_datalayout = EROFS_INODE_CHUNK_BASED;
         } else if self.is_symlink() {
+            // Keep the symlink target inline right after the inode rather than in a separate
+            // block, so readlink is served from the block of the inode. The inode starts at the
+            // next block if the remaining space of the current block can't hold both.
+            let size = self.size_with_xattr() as u64 + self.inode.size();
+            let avail = EROFS_BLOCK_SIZE - bootstrap_ctx.offset % EROFS_BLOCK_SIZE;
+            if size > avail && size <= EROFS_BLOCK_SIZE {
+                bootstrap_ctx.align_offset(EROFS_BLOCK_SIZE);
+            }
             self.set_v6_offset_with_tail(bootstrap_ctx, self.inode.size());
         } else {
             self.offset = bootstrap_ctx.offset;
@@ -1303,6 +1662,19 @@ impl InodeWrapper {
         }
     }
 
+    pub fn set_mtime(&mut self, mtime: u64, mtime_nsec: u32) {
+        match self {
+            InodeWrapper::V5(i) => {
+                i.i_mtime = mtime;
+                i.i_mtime_nsec = mtime_nsec;
+            }
+            InodeWrapper::V6(i) => {
+                i.i_mtime = mtime;
+                i.i_mtime_nsec = mtime_nsec;
+            }
+        }
+    }
+
     pub fn mtime_nsec(&self) -> u32 {
         match self {
             InodeWrapper::V5(i) => i.i_mtime_nsec,
@@ -1565,6 +1937,13 @@ impl ChunkWrapper {
         }
     }
 
+    pub fn is_compressed(&self) -> bool {
+        match self {
+            ChunkWrapper::V5(c) => c.flags.contains(BlobChunkFlags::COMPRESSED),
+            ChunkWrapper::V6(c) => c.flags.contains(BlobChunkFlags::COMPRESSED),
+        }
+    }
+
     pub fn file_offset(&self) -> u64 {
         match self {
             ChunkWrapper::V5(c) => c.file_offset,
@@ -1706,12 +2085,146 @@ fn to_rafsv5_chunk_info(cki: &dyn BlobV5ChunkInfo) -> RafsV5ChunkInfo {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::context::{ArtifactStorage, BootstrapContext};
+    use crate::core::chunk_dict::HashChunkDict;
+    use crate::core::context::{ArtifactStorage, BlobManager, BootstrapContext};
     use rafs::metadata::layout::v6::EROFS_INODE_CHUNK_BASED;
     use rafs::metadata::RAFS_DEFAULT_CHUNK_SIZE;
     use std::fs::File;
+    use std::io::Write;
     use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
 
+    #[test]
+    fn test_is_unchanged_from() {
+        let pa = TempDir::new().unwrap();
+        let pa_aa = TempFile::new_in(pa.as_path()).unwrap();
+        pa_aa.as_file().write_all(&[0u8; 0x1000]).unwrap();
+        let node = Node::new(
+            RafsVersion::V5,
+            pa.as_path().to_path_buf(),
+            pa_aa.as_path().to_path_buf(),
+            Overlay::UpperAddition,
+            RAFS_DEFAULT_CHUNK_SIZE as u32,
+            false,
+        )
+        .unwrap();
+        let mut ctx = BuildContext {
+            chunk_size: RAFS_DEFAULT_CHUNK_SIZE as u32,
+            ..Default::default()
+        };
+
+        let mut lower = node.clone();
+        lower.overlay = Overlay::Lower;
+        let mut chunk = ChunkWrapper::new(RafsVersion::V5);
+        chunk.set_id(RafsDigest::from_buf(&[0u8; 0x1000], ctx.digester));
+        lower.chunks.push(chunk);
+        assert!(node.is_unchanged_from(&lower, &ctx));
+        ctx.chunk_size = 0x800;
+        assert!(!node.is_unchanged_from(&lower, &ctx));
+        ctx.chunk_size = RAFS_DEFAULT_CHUNK_SIZE as u32;
+
+        // Data is compared if the parent bootstrap is built with zero timestamps.
+        lower.inode.set_mtime(0, 0);
+        assert!(!node.is_unchanged_from(&lower, &ctx));
+        ctx.zero_timestamps = true;
+        assert!(node.is_unchanged_from(&lower, &ctx));
+        lower.chunks[0].set_id(RafsDigest::from_buf(&[1u8; 0x1000], ctx.digester));
+        assert!(!node.is_unchanged_from(&lower, &ctx));
+
+        lower.inode.set_size(0x2000);
+        assert!(!node.is_unchanged_from(&lower, &ctx));
+
+        let dir_node = Node::new(
+            RafsVersion::V5,
+            pa.as_path().to_path_buf(),
+            pa.as_path().to_path_buf(),
+            Overlay::UpperAddition,
+            RAFS_DEFAULT_CHUNK_SIZE as u32,
+            false,
+        )
+        .unwrap();
+        assert!(!dir_node.is_unchanged_from(&dir_node, &ctx));
+    }
+
+    #[test]
+    fn test_record_btime() {
+        let dir = TempDir::new().unwrap();
+        let file = TempFile::new_in(dir.as_path()).unwrap();
+        let mut node = Node::new(
+            RafsVersion::V5,
+            dir.as_path().to_path_buf(),
+            file.as_path().to_path_buf(),
+            Overlay::UpperAddition,
+            RAFS_DEFAULT_CHUNK_SIZE as u32,
+            false,
+        )
+        .unwrap();
+        node.record_btime().unwrap();
+
+        let key = OsString::from(RAFS_XATTR_BTIME);
+        match file.as_path().metadata().unwrap().created() {
+            Ok(created) => {
+                let value = node.xattrs.get(&key).unwrap();
+                let value = std::str::from_utf8(value).unwrap();
+                let secs = created.duration_since(UNIX_EPOCH).unwrap().as_secs();
+                assert!(value.starts_with(&format!("{}.", secs)));
+                assert_eq!(value.len(), secs.to_string().len() + 10);
+                assert!(node.inode.has_xattr());
+            }
+            // The creation time isn't available on the filesystem.
+            Err(_) => assert!(node.xattrs.get(&key).is_none()),
+        }
+    }
+
+    #[test]
+    fn test_dump_chunk_split_blob() {
+        let src_dir = TempDir::new().unwrap();
+        let blob_dir = TempDir::new().unwrap();
+        let file = TempFile::new_in(src_dir.as_path()).unwrap();
+        let data: Vec<u8> = (0..0x3000u32).map(|i| (i / 0x1000) as u8 + 1).collect();
+        file.as_file().write_all(&data).unwrap();
+
+        let mut ctx = BuildContext {
+            compressor: compress::Algorithm::None,
+            ..Default::default()
+        };
+        ctx.set_chunk_size(0x1000);
+        // Two chunks with the chunk information array fit into a blob, but not three.
+        ctx.set_blob_max_size(0x3100);
+        let storage = ArtifactStorage::FileDir(blob_dir.as_path().to_path_buf());
+        let mut blob_ctx = BlobContext::new(String::new(), Some(storage)).unwrap();
+        blob_ctx.set_chunk_size(0x1000);
+        blob_ctx.set_meta_info_enabled(true);
+
+        let mut node = Node::new(
+            RafsVersion::V6,
+            src_dir.as_path().to_path_buf(),
+            file.as_path().to_path_buf(),
+            Overlay::UpperAddition,
+            0x1000,
+            false,
+        )
+        .unwrap();
+        let mut chunk_dict = HashChunkDict::default();
+        node.dump_blob(&ctx, &mut blob_ctx, 0, &mut chunk_dict)
+            .unwrap();
+
+        assert_eq!(blob_ctx.sealed_blobs.len(), 1);
+        let sealed = &blob_ctx.sealed_blobs[0];
+        assert_eq!(sealed.chunk_count, 2);
+        assert!(blob_dir.as_path().join(&sealed.blob_id).exists());
+        assert_eq!(blob_ctx.chunk_count, 1);
+        let indexes: Vec<(u32, u32, u64)> = node
+            .chunks
+            .iter()
+            .map(|c| (c.blob_index(), c.index(), c.compressed_offset()))
+            .collect();
+        assert_eq!(indexes, vec![(0, 0, 0), (0, 1, 0x1000), (1, 0, 0)]);
+
+        let mut blob_mgr = BlobManager::new();
+        blob_mgr.add(Some(blob_ctx));
+        assert_eq!(blob_mgr.len(), 2);
+    }
+
     #[test]
     fn test_set_v6_offset() {
         let pa = TempDir::new().unwrap();
@@ -1740,7 +2253,32 @@ mod tests {
         assert_eq!(node.v6_compact_inode, true);
         assert_eq!(bootstrap_ctx.offset, 40);
 
-        // symlink and dir are handled in the same way.
+        // symlink target is always inlined.
+        std::os::unix::fs::symlink("target", pa.as_path().join("link")).unwrap();
+        let mut link_node = Node::new(
+            RafsVersion::V6,
+            pa.as_path().to_path_buf(),
+            pa.as_path().join("link"),
+            Overlay::UpperAddition,
+            RAFS_DEFAULT_CHUNK_SIZE as u32,
+            false,
+        )
+        .unwrap();
+        bootstrap_ctx.offset = 4096 - 32;
+        link_node.set_v6_offset(&mut bootstrap_ctx);
+        assert_eq!(link_node.offset, 4096);
+        assert_eq!(link_node.v6_datalayout, EROFS_INODE_FLAT_INLINE);
+        assert_eq!(
+            bootstrap_ctx.offset,
+            4096 + link_node.size_with_xattr() as u64 + 6
+        );
+
+        bootstrap_ctx.offset = 4096 + 64;
+        link_node.set_v6_offset(&mut bootstrap_ctx);
+        assert_eq!(link_node.offset, 4096 + 64);
+        assert_eq!(link_node.v6_datalayout, EROFS_INODE_FLAT_INLINE);
+
+        // dir is handled in the same way.
         let mut dir_node = Node::new(
             RafsVersion::V6,
             pa.as_path().to_path_buf(),
@@ -0,0 +1,274 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Multi-threaded pipeline to generate data chunks for regular files.
+//!
+//! The pipeline is composed of:
+//! - a producer, which walks regular files and splits them into chunk jobs.
+//! - worker threads, which read, digest and compress chunk data.
+//! - an ordered writer, which deduplicates chunks and writes them into the data blob.
+//!
+//! Chunks are committed in the same order as the single-threaded builder does, so the
+//! generated image is identical no matter how many worker threads are used. Chunks of files
+//! found in the build cache are fetched from the cache instead of being sent to worker threads.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{Context, Result};
+use nydus_utils::digest::{self, RafsDigest};
+use storage::compress;
+
+use super::build_cache::BuildCache;
+use super::chunk_dict::ChunkDict;
+use super::context::{BlobContext, BuildContext};
+use super::node::Node;
+
+/// Maximum number of inflight chunk jobs per worker thread, to bound memory consumption.
+const JOBS_PER_WORKER: usize = 4;
+
+struct ChunkJob {
+    seq: usize,
+    file: Arc<File>,
+    path: PathBuf,
+    file_offset: u64,
+    chunk_size: u32,
+}
+
+impl ChunkJob {
+    fn run(
+        &self,
+        digester: digest::Algorithm,
+        compressor: compress::Algorithm,
+    ) -> Result<ChunkData> {
+        let mut buf = vec![0u8; self.chunk_size as usize];
+        self.file
+            .read_exact_at(&mut buf, self.file_offset)
+            .with_context(|| format!("failed to read node file {:?}", self.path))?;
+
+        let id = RafsDigest::from_buf(&buf, digester);
+        let (compressed, is_compressed) = compress::compress(&buf, compressor)
+            .with_context(|| format!("failed to compress node file {:?}", self.path))?;
+        // Avoid copying chunk data if it's stored uncompressed.
+        let compressed = match compressed {
+            Cow::Owned(data) => Some(data),
+            Cow::Borrowed(_) => None,
+        };
+
+        Ok(ChunkData {
+            id,
+            compressed: compressed.unwrap_or(buf),
+            is_compressed,
+        })
+    }
+}
+
+struct ChunkData {
+    id: RafsDigest,
+    compressed: Vec<u8>,
+    is_compressed: bool,
+}
+
+/// Pipeline to dump data chunks of regular files with a pool of worker threads.
+pub struct ChunkPipeline {
+    job_tx: Option<Sender<ChunkJob>>,
+    result_rx: Receiver<(usize, Result<ChunkData>)>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ChunkPipeline {
+    pub fn new(
+        threads: usize,
+        digester: digest::Algorithm,
+        compressor: compress::Algorithm,
+    ) -> Result<Self> {
+        let (job_tx, job_rx) = channel::<ChunkJob>();
+        let (result_tx, result_rx) = channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let mut workers = Vec::with_capacity(threads);
+
+        for idx in 0..threads {
+            let job_rx = job_rx.clone();
+            let result_tx = result_tx.clone();
+            let worker = thread::Builder::new()
+                .name(format!("chunk_worker_{}", idx))
+                .spawn(move || loop {
+                    // The lock is released before handling the job.
+                    let job = match job_rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let ret = job.run(digester, compressor);
+                    if result_tx.send((job.seq, ret)).is_err() {
+                        break;
+                    }
+                })
+                .context("failed to create chunk worker thread")?;
+            workers.push(worker);
+        }
+
+        Ok(Self {
+            job_tx: Some(job_tx),
+            result_rx,
+            workers,
+        })
+    }
+
+    /// Dump data chunks of `nodes` selected by `inodes` into the data blob, in order.
+    ///
+    /// The first `prefetch_entries` inodes are accounted into the blob readahead size.
+    #[allow(clippy::too_many_arguments)]
+    pub fn dump<T: ChunkDict>(
+        &mut self,
+        ctx: &BuildContext,
+        blob_ctx: &mut BlobContext,
+        blob_index: u32,
+        nodes: &mut [Node],
+        inodes: &[usize],
+        prefetch_entries: usize,
+        chunk_dict: &mut T,
+    ) -> Result<()> {
+        // Set digest for inodes without data chunks, and collect chunks to dump in order.
+        let mut chunks = Vec::new();
+        let mut file_digests = HashMap::new();
+        let mut cached_chunks = HashMap::new();
+        for (pos, index) in inodes.iter().enumerate() {
+            let node = &mut nodes[*index];
+            if !node.is_reg() {
+                node.dump_non_reg_digest(ctx)?;
+            } else if node.inode.child_count() == 0 {
+                node.dump_reg_digest(ctx);
+            } else {
+                if let Some(cache) = ctx.build_cache.as_ref() {
+                    let file_digest = BuildCache::file_digest(&node.path, ctx.digester)?;
+                    if let Some(cached) = cache.get_file_chunks(&file_digest) {
+                        if cached.len() == node.inode.child_count() as usize {
+                            cached_chunks.insert(pos, cached);
+                        }
+                    }
+                    file_digests.insert(pos, file_digest);
+                }
+                // `child_count` of regular file is reused as `chunk_count`.
+                for chunk_index in 0..node.inode.child_count() {
+                    chunks.push((pos, chunk_index));
+                }
+            }
+        }
+
+        let window = self.workers.len().max(1) * JOBS_PER_WORKER;
+        let mut pending = BTreeMap::new();
+        let mut file: Option<Arc<File>> = None;
+        let mut next_job = 0;
+        let mut next_commit = 0;
+
+        while next_commit < chunks.len() {
+            // Feed worker threads with chunk jobs.
+            while next_job < chunks.len() && next_job - next_commit < window {
+                let (pos, chunk_index) = chunks[next_job];
+                let node = &nodes[inodes[pos]];
+                if let Some(cached) = cached_chunks.get(&pos) {
+                    let (id, chunk) = &cached[chunk_index as usize];
+                    // Safe to unwrap because there are cached chunks only if the cache is enabled.
+                    let compressed = ctx.build_cache.as_ref().unwrap().read_chunk(id, chunk)?;
+                    let data = ChunkData {
+                        id: *id,
+                        compressed,
+                        is_compressed: chunk.is_compressed,
+                    };
+                    pending.insert(next_job, data);
+                    next_job += 1;
+                    continue;
+                }
+                if chunk_index == 0 {
+                    let f = File::open(&node.path)
+                        .with_context(|| format!("failed to open node file {:?}", node.path))?;
+                    file = Some(Arc::new(f));
+                }
+                let (file_offset, chunk_size) =
+                    node.chunk_range(chunk_index, blob_ctx.chunk_size)?;
+                let job = ChunkJob {
+                    seq: next_job,
+                    // Safe to unwrap because the file is opened for the first chunk of the file.
+                    file: file.as_ref().unwrap().clone(),
+                    path: node.path.clone(),
+                    file_offset,
+                    chunk_size,
+                };
+                self.send_job(job)?;
+                next_job += 1;
+            }
+
+            if !pending.contains_key(&next_commit) {
+                let (seq, ret) = self
+                    .result_rx
+                    .recv()
+                    .context("chunk worker threads exited unexpectedly")?;
+                pending.insert(seq, ret?);
+            }
+
+            // Commit chunks in order.
+            while let Some(data) = pending.remove(&next_commit) {
+                let (pos, chunk_index) = chunks[next_commit];
+                let node = &mut nodes[inodes[pos]];
+                let (file_offset, chunk_size) =
+                    node.chunk_range(chunk_index, blob_ctx.chunk_size)?;
+                if !node.dedup_chunk(ctx, blob_ctx, chunk_dict, data.id, file_offset, chunk_size) {
+                    let size = node.dump_chunk(
+                        ctx,
+                        blob_ctx,
+                        blob_index,
+                        chunk_dict,
+                        data.id,
+                        file_offset,
+                        chunk_size,
+                        &data.compressed,
+                        data.is_compressed,
+                    )?;
+                    if pos < prefetch_entries {
+                        blob_ctx.blob_readahead_size += size;
+                    }
+                }
+                if chunk_index == node.inode.child_count() - 1 {
+                    node.dump_reg_digest(ctx);
+                    if let Some(file_digest) = file_digests.get(&pos) {
+                        // Safe to unwrap because file digests are calculated only if the cache
+                        // is enabled.
+                        let chunk_ids: Vec<_> = node.chunks.iter().map(|c| *c.id()).collect();
+                        let cache = ctx.build_cache.as_ref().unwrap();
+                        cache.add_file(file_digest, &chunk_ids);
+                    }
+                }
+                next_commit += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_job(&self, job: ChunkJob) -> Result<()> {
+        self.job_tx
+            .as_ref()
+            .ok_or_else(|| anyhow!("chunk pipeline has been closed"))?
+            .send(job)
+            .map_err(|_| anyhow!("chunk worker threads exited unexpectedly"))
+    }
+}
+
+impl Drop for ChunkPipeline {
+    fn drop(&mut self) {
+        // Close the job channel to notify worker threads to exit.
+        self.job_tx.take();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("failed to join chunk worker thread");
+            }
+        }
+    }
+}
@@ -3,6 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
@@ -42,20 +44,24 @@ impl FromStr for PrefetchPolicy {
     }
 }
 
-/// Gather readahead file paths line by line from stdin.
+/// Readahead files with inode indexes to be decided, and the order they are given in.
+type ReadaheadPatterns = (BTreeMap<PathBuf, Option<u64>>, Vec<PathBuf>);
+
+/// Gather readahead file paths line by line from stdin or a hint file, the order of paths is
+/// returned as well.
 ///
 /// Input format:
 ///    printf "/relative/path/to/rootfs/1\n/relative/path/to/rootfs/2"
 /// This routine does not guarantee that specified file must exist in local filesystem,
 /// this is because we can't guarantee that source rootfs directory of parent bootstrap
 /// is located in local file system.
-fn gather_readahead_patterns() -> Result<BTreeMap<PathBuf, Option<u64>>> {
-    let stdin = std::io::stdin();
+fn gather_readahead_patterns<R: BufRead>(mut reader: R) -> Result<ReadaheadPatterns> {
     let mut files = BTreeMap::new();
+    let mut order = Vec::new();
 
     loop {
         let mut file = String::new();
-        let size = stdin
+        let size = reader
             .read_line(&mut file)
             .context("failed to parse readahead files")?;
         if size == 0 {
@@ -77,10 +83,12 @@ fn gather_readahead_patterns() -> Result<BTreeMap<PathBuf, Option<u64>>> {
             file, file_trimmed
         );
         // The inode index is not decided yet, but will do during fs-walk.
-        files.insert(file_trimmed, None);
+        if files.insert(file_trimmed.clone(), None).is_none() {
+            order.push(file_trimmed);
+        }
     }
 
-    Ok(files)
+    Ok((files, order))
 }
 
 #[derive(Default, Clone)]
@@ -94,6 +102,10 @@ pub struct Prefetch {
     /// file's inode number, by which its inode index of inode table can be calculated.
     readahead_patterns: BTreeMap<PathBuf, Option<u64>>,
 
+    /// Patterns in the order they are specified, prefetch table entries follow this order so
+    /// files are prefetched in the order they are expected to be accessed.
+    readahead_order: Vec<PathBuf>,
+
     /// Readahead file list, use BTreeMap to keep stable iteration order.
     /// Files from this collection are all regular files and will be persisted to blob following
     /// a certain scheme.
@@ -101,17 +113,26 @@ pub struct Prefetch {
 }
 
 impl Prefetch {
-    pub fn new(policy: PrefetchPolicy) -> Result<Self> {
-        let readahead_patterns = if policy != PrefetchPolicy::None {
-            gather_readahead_patterns().context("failed to get readahead files")?
+    /// Create prefetch hints with `policy`, read from `hint_file` if specified, otherwise from
+    /// stdin.
+    pub fn new(policy: PrefetchPolicy, hint_file: Option<&Path>) -> Result<Self> {
+        let (readahead_patterns, readahead_order) = if policy == PrefetchPolicy::None {
+            (BTreeMap::new(), Vec::new())
+        } else if let Some(path) = hint_file {
+            let file = File::open(path)
+                .with_context(|| format!("failed to open prefetch hint file {:?}", path))?;
+            gather_readahead_patterns(BufReader::new(file))
+                .with_context(|| format!("failed to get readahead files from {:?}", path))?
         } else {
-            BTreeMap::new()
+            gather_readahead_patterns(std::io::stdin().lock())
+                .context("failed to get readahead files")?
         };
 
         Ok(Self {
             policy,
             disabled: false,
             readahead_patterns,
+            readahead_order,
             readahead_files: BTreeMap::new(),
         })
     }
@@ -162,7 +183,11 @@ impl Prefetch {
     pub fn get_rafsv5_prefetch_table(&mut self) -> Option<RafsV5PrefetchTable> {
         if self.policy == PrefetchPolicy::Fs {
             let mut prefetch_table = RafsV5PrefetchTable::new();
-            for i in self.readahead_patterns.values().filter_map(|v| *v) {
+            for i in self
+                .readahead_order
+                .iter()
+                .filter_map(|p| self.readahead_patterns.get(p).copied().flatten())
+            {
                 prefetch_table.add_entry(i as u32);
             }
             Some(prefetch_table)
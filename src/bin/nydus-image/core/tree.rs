@@ -25,6 +25,7 @@ use rafs::metadata::layout::{bytes_to_os_str, RafsXAttrs, RAFS_ROOT_INODE};
 use rafs::metadata::{Inode, RafsInode, RafsSuper};
 
 use super::chunk_dict::ChunkDict;
+use super::context::BuildContext;
 use super::node::{ChunkWrapper, InodeWrapper, Node, Overlay, WhiteoutSpec, WhiteoutType};
 
 /// An in-memory tree structure to maintain information and topology of filesystem nodes.
@@ -60,6 +61,44 @@ impl Tree {
         Ok(tree)
     }
 
+    /// Reuse unchanged regular files from the lower tree, which is loaded from a parent bootstrap.
+    ///
+    /// The tree is built from a complete filesystem instead of a layer, so files unchanged
+    /// relative to the parent image are replaced by lower nodes, which refer to data chunks in
+    /// blobs of the parent image. Only files added or changed will be dumped into the new blob.
+    /// Return number of reused files.
+    pub fn reuse_lower(&mut self, lower: &Tree, ctx: &BuildContext) -> usize {
+        let mut count = 0;
+
+        for child in self.children.iter_mut() {
+            // Children of a tree loaded from bootstrap are sorted by name.
+            let idx = match lower
+                .children
+                .binary_search_by(|c| c.node.name().cmp(child.node.name()))
+            {
+                Ok(idx) => idx,
+                Err(_) => continue,
+            };
+            let lower_child = &lower.children[idx];
+
+            if child.node.is_dir() {
+                if lower_child.node.is_dir() {
+                    count += child.reuse_lower(lower_child, ctx);
+                }
+            } else if child.node.is_unchanged_from(&lower_child.node, ctx) {
+                child.node = lower_child.node.clone();
+                // Reused nodes are kept as in the parent bootstrap, which may not have zero
+                // timestamps.
+                if ctx.zero_timestamps {
+                    child.node.inode.set_mtime(0, 0);
+                }
+                count += 1;
+            }
+        }
+
+        count
+    }
+
     /// Walk all nodes in deep first mode.
     pub fn iterate<F>(&self, cb: &mut F) -> Result<()>
     where
@@ -129,10 +168,13 @@ impl Tree {
                 if depth == target_paths_len - 1 {
                     let mut node = target.clone();
                     node.overlay = Overlay::UpperModification;
-                    *child = Tree {
-                        node,
-                        children: child.children.clone(),
+                    // Children of the lower node are hidden if it's replaced by a non-directory.
+                    let children = if node.is_dir() {
+                        child.children.clone()
+                    } else {
+                        Vec::new()
                     };
+                    *child = Tree { node, children };
                     return Ok(true);
                 }
                 if child.node.is_dir() {
@@ -354,3 +396,54 @@ impl<'a> MetadataTreeBuilder<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::context::RafsVersion;
+    use rafs::metadata::RAFS_DEFAULT_CHUNK_SIZE;
+    use std::fs::{self, File};
+    use std::path::Path;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn new_node(source: &Path, path: PathBuf) -> Node {
+        Node::new(
+            RafsVersion::V5,
+            source.to_path_buf(),
+            path,
+            Overlay::UpperAddition,
+            RAFS_DEFAULT_CHUNK_SIZE as u32,
+            false,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply_modification() {
+        let lower = TempDir::new().unwrap();
+        let lower_dir = lower.as_path().join("a");
+        fs::create_dir(&lower_dir).unwrap();
+        File::create(lower_dir.join("f")).unwrap();
+        let mut tree = Tree::new(new_node(lower.as_path(), lower.as_path().to_path_buf()));
+        let mut dir = Tree::new(new_node(lower.as_path(), lower_dir.clone()));
+        dir.children
+            .push(Tree::new(new_node(lower.as_path(), lower_dir.join("f"))));
+        tree.children.push(dir);
+
+        // Children of a directory replaced by a directory are kept.
+        let upper = TempDir::new().unwrap();
+        fs::create_dir(upper.as_path().join("a")).unwrap();
+        let node = new_node(upper.as_path(), upper.as_path().join("a"));
+        assert!(tree.apply(&node, false, WhiteoutSpec::Disabled).unwrap());
+        assert_eq!(tree.children[0].node.overlay, Overlay::UpperModification);
+        assert_eq!(tree.children[0].children.len(), 1);
+
+        // Children of a directory replaced by a file are hidden.
+        let upper = TempDir::new().unwrap();
+        File::create(upper.as_path().join("a")).unwrap();
+        let node = new_node(upper.as_path(), upper.as_path().join("a"));
+        assert!(tree.apply(&node, false, WhiteoutSpec::Disabled).unwrap());
+        assert!(tree.children[0].node.is_reg());
+        assert!(tree.children[0].children.is_empty());
+    }
+}
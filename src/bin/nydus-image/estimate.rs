@@ -0,0 +1,246 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Estimate the size of a nydus image without building it.
+//!
+//! Building a big image takes long, so it's helpful to know how chunk size, compression algorithm
+//! and chunk dictionary affect the image before a build. The estimator walks the source directory
+//! as the directory builder does, splits regular files into chunks, and deduplicates chunks within
+//! the image and against the chunk dictionary. Chunks to be written into the data blob are
+//! compressed by all supported algorithms, but nothing is written.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use nydus_utils::digest::{self, RafsDigest};
+use serde::Serialize;
+use storage::compress;
+
+use crate::core::chunk_dict::ChunkDict;
+use crate::core::filter::Filter;
+use crate::core::node::Node;
+
+/// Compression algorithms to estimate the data blob size for, with names used by `--compressor`.
+const ALGORITHMS: [(&str, compress::Algorithm); 3] = [
+    ("none", compress::Algorithm::None),
+    ("lz4_block", compress::Algorithm::Lz4Block),
+    ("gzip", compress::Algorithm::GZip),
+];
+
+#[derive(Debug, Default, Serialize)]
+pub struct Estimation {
+    /// Number of regular files, hard links are counted once.
+    pub files: u64,
+    /// Size of file data.
+    pub original_size: u64,
+    /// Number of data chunks, including deduplicated chunks.
+    pub chunks: u64,
+    /// Number of chunks deduplicated against other chunks of the image.
+    pub dedup_chunks: u64,
+    /// Size of file data deduplicated against other chunks of the image.
+    pub dedup_size: u64,
+    /// Number of chunks found in the chunk dictionary.
+    pub dict_chunks: u64,
+    /// Size of file data found in the chunk dictionary.
+    pub dict_size: u64,
+    /// Number of chunks to be written into the data blob.
+    pub blob_chunks: u64,
+    /// Size of the data blob compressed by each algorithm.
+    pub blob_sizes: BTreeMap<String, u64>,
+}
+
+pub struct Estimator {
+    chunk_size: u32,
+    digester: digest::Algorithm,
+    filter: Filter,
+    chunk_dict: Option<Arc<dyn ChunkDict>>,
+    chunks: HashSet<RafsDigest>,
+    inodes: HashSet<(u64, u64)>,
+    estimation: Estimation,
+}
+
+impl Estimator {
+    pub fn new(
+        chunk_size: u32,
+        digester: digest::Algorithm,
+        filter: Filter,
+        chunk_dict: Option<Arc<dyn ChunkDict>>,
+    ) -> Self {
+        let mut estimation = Estimation::default();
+        for (name, _) in ALGORITHMS.iter() {
+            estimation.blob_sizes.insert(name.to_string(), 0);
+        }
+
+        Self {
+            chunk_size,
+            digester,
+            filter,
+            chunk_dict,
+            chunks: HashSet::new(),
+            inodes: HashSet::new(),
+            estimation,
+        }
+    }
+
+    /// Walk the source directory `source` and estimate the image to build from it.
+    pub fn estimate(mut self, source: &Path) -> Result<Estimation> {
+        self.walk(source, source, false)?;
+        Ok(self.estimation)
+    }
+
+    /// Walk the directory `dir` by DFS, with the same file filters as the directory builder.
+    ///
+    /// `included` indicates whether `dir` has been selected by include filters.
+    fn walk(&mut self, root: &Path, dir: &Path, included: bool) -> Result<()> {
+        let mut children = fs::read_dir(dir)
+            .with_context(|| format!("failed to read dir {:?}", dir))?
+            .collect::<std::result::Result<Vec<_>, std::io::Error>>()?;
+        // Walk in a stable order, so hard links are always accounted to the same path.
+        children.sort_by_key(|c| c.file_name());
+
+        for child in children {
+            let path = child.path();
+            let target = Node::generate_target(&path, root);
+            if self.filter.is_excluded(&target) {
+                continue;
+            }
+            let included = included || self.filter.is_included(&target);
+            let meta = fs::symlink_metadata(&path)
+                .with_context(|| format!("failed to get metadata of {:?}", path))?;
+            if meta.is_dir() {
+                self.walk(root, &path, included)?;
+            } else if included && meta.is_file() && self.inodes.insert((meta.dev(), meta.ino())) {
+                self.estimate_file(&path)
+                    .with_context(|| format!("failed to estimate file {:?}", path))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn estimate_file(&mut self, path: &Path) -> Result<()> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; self.chunk_size as usize];
+        let est = &mut self.estimation;
+        est.files += 1;
+
+        loop {
+            let size = Self::read_chunk(&mut file, &mut buf)?;
+            if size == 0 {
+                break;
+            }
+            let data = &buf[..size];
+            est.chunks += 1;
+            est.original_size += size as u64;
+
+            let id = RafsDigest::from_buf(data, self.digester);
+            if self
+                .chunk_dict
+                .as_ref()
+                .map_or(false, |d| d.get_chunk(&id).is_some())
+            {
+                est.dict_chunks += 1;
+                est.dict_size += size as u64;
+            } else if !self.chunks.insert(id) {
+                est.dedup_chunks += 1;
+                est.dedup_size += size as u64;
+            } else {
+                est.blob_chunks += 1;
+                for (name, algorithm) in ALGORITHMS.iter() {
+                    // Chunks are stored uncompressed if compression doesn't make them smaller.
+                    let (compressed, _) = compress::compress(data, *algorithm)?;
+                    *est.blob_sizes.entry(name.to_string()).or_default() += compressed.len() as u64;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Fill the buffer unless reaching end of the file, return the amount of data read.
+    fn read_chunk(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+        let mut size = 0;
+        while size < buf.len() {
+            match file.read(&mut buf[size..])? {
+                0 => break,
+                n => size += n,
+            }
+        }
+        Ok(size)
+    }
+}
+
+impl Estimation {
+    pub fn dump_json(&self, path: &Path) -> Result<()> {
+        let w = OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Output file {:?} can't be opened", path))?;
+
+        serde_json::to_writer(w, self).context("Write output file failed")?;
+
+        Ok(())
+    }
+
+    pub fn dump(&self) {
+        println!("Files:                 {}", self.files);
+        println!("Original Size:         {}", self.original_size);
+        println!("Chunks:                {}", self.chunks);
+        println!(
+            "Deduplicated Chunks:   {} ({} bytes)",
+            self.dedup_chunks, self.dedup_size
+        );
+        println!(
+            "Chunk Dict Chunks:     {} ({} bytes)",
+            self.dict_chunks, self.dict_size
+        );
+        println!("Blob Chunks:           {}", self.blob_chunks);
+        for (name, size) in self.blob_sizes.iter() {
+            println!("Blob Size ({}): {}", name, size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_estimate() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.as_path();
+        fs::create_dir(root.join("sub")).unwrap();
+        let mut file = File::create(root.join("a")).unwrap();
+        file.write_all(&[1u8; 0x1000]).unwrap();
+        file.write_all(&[2u8; 0x1000]).unwrap();
+        file.write_all(&[1u8; 0x100]).unwrap();
+        fs::write(root.join("sub/b"), &[1u8; 0x1000]).unwrap();
+        fs::hard_link(root.join("a"), root.join("sub/c")).unwrap();
+        fs::write(root.join("sub/d.log"), &[3u8; 0x1000]).unwrap();
+
+        let filter = Filter::new(&[], &["*.log"], false).unwrap();
+        let estimator = Estimator::new(0x1000, digest::Algorithm::Blake3, filter, None);
+        let est = estimator.estimate(root).unwrap();
+
+        assert_eq!(est.files, 2);
+        assert_eq!(est.original_size, 0x3100);
+        assert_eq!(est.chunks, 4);
+        assert_eq!(est.dedup_chunks, 1);
+        assert_eq!(est.dedup_size, 0x1000);
+        assert_eq!(est.dict_chunks, 0);
+        assert_eq!(est.blob_chunks, 3);
+        assert_eq!(est.blob_sizes["none"], 0x2100);
+        assert!(est.blob_sizes["lz4_block"] < 0x2100);
+        assert!(est.blob_sizes["gzip"] < 0x2100);
+    }
+}
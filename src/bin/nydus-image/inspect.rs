@@ -7,7 +7,7 @@ use std::fs::Permissions;
 use std::io::Write;
 use std::ops::DerefMut;
 use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
@@ -179,7 +179,7 @@ impl RafsInspector {
         let mut chunks = None;
 
         if inode.has_xattr() {
-            let xattr_header_offset = inode_offset + inode.size() as u32;
+            let xattr_header_offset = inode_offset + inode.inode_size() as u32;
             r.seek_to_offset(xattr_header_offset as u64)?;
             // TODO: implement `load()` for `OndiskXattr`
             let mut xattrs_header = RafsV5XAttrsTable::new();
@@ -187,7 +187,7 @@ impl RafsInspector {
             xattr_pairs_aligned_size = xattrs_header.aligned_size() as u32 + 8;
         }
 
-        let chunks_offset = inode_offset + inode.size() as u32 + xattr_pairs_aligned_size;
+        let chunks_offset = inode_offset + inode.inode_size() as u32 + xattr_pairs_aligned_size;
 
         r.seek_to_offset(chunks_offset as u64)?;
 
@@ -235,14 +235,62 @@ Blocks:             {blocks}"#,
         );
     }
 
+    fn inode_to_json(inode: &InodeWrapper, name: &str, index: usize) -> Value {
+        json!({
+            "inode": inode.ino(),
+            "index": index,
+            "name": name,
+            "size": inode.size(),
+            "parent": inode.parent(),
+            "mode": inode.mode(),
+            "nlink": inode.nlink(),
+            "uid": inode.uid(),
+            "gid": inode.gid(),
+            "mtime": inode.mtime(),
+            "mtime_nsec": inode.mtime_nsec(),
+            "blocks": inode.blocks(),
+        })
+    }
+
+    fn chunks_to_json(&self, chunks: &[RafsV5ChunkInfo]) -> Result<Value> {
+        let mut value = json!([]);
+        for c in chunks {
+            let v = json!({
+                "chunk_id": c.block_id.to_string(),
+                "blob_id": self.state.get_blob_id(c.blob_index)?,
+                "blob_index": c.blob_index,
+                "index": c.index,
+                "file_offset": c.file_offset,
+                "compressed_offset": c.compress_offset,
+                "compressed_size": c.compress_size,
+                "decompressed_offset": c.uncompress_offset,
+                "decompressed_size": c.uncompress_size,
+            });
+            value.as_array_mut().unwrap().push(v);
+        }
+
+        Ok(value)
+    }
+
     pub fn iter_dir(
         &self,
+        op: impl FnMut(&OsStr, &InodeWrapper, u32, u32) -> Action,
+    ) -> Result<()> {
+        self.iter_dir_at(self.cur_dir_index, op)
+    }
+
+    fn iter_dir_at(
+        &self,
+        dir_index: u32,
         mut op: impl FnMut(&OsStr, &InodeWrapper, u32, u32) -> Action,
     ) -> Result<()> {
-        let (dir_inode, _) = self.load_inode_by_index(self.cur_dir_index as usize)?;
+        let (dir_inode, _) = self.load_inode_by_index(dir_index as usize)?;
         let parent_ino = dir_inode.ino();
 
         let children_count = dir_inode.child_count();
+        if children_count == 0 {
+            return Ok(());
+        }
         // Somehow, the it has subtract 1 to identify the first child file's index in inode table.
         let first_index = dir_inode.child_index() - 1;
         let last_index = first_index + children_count - 1;
@@ -310,6 +358,50 @@ Blocks:             {blocks}"#,
         Ok(())
     }
 
+    /// Look up a file by its path, which is relative to current directory if not absolute.
+    ///
+    /// Return index of the inode within inodes table, the inode and offset of the inode.
+    fn lookup_path(&self, path: &str) -> Result<Option<(u32, InodeWrapper, u32)>> {
+        let mut found: Option<(u32, Option<InodeWrapper>, u32)> =
+            Some((self.cur_dir_index, None, 0));
+        if path.starts_with('/') {
+            found = Some((0, None, 0));
+        }
+
+        for name in Path::new(path).components() {
+            let name = match name {
+                Component::Normal(n) => n,
+                Component::RootDir | Component::CurDir => continue,
+                _ => bail!("unsupported path component in {:?}", path),
+            };
+            let dir_index = match found.take() {
+                Some((idx, None, _)) => idx,
+                Some((idx, Some(inode), _)) if inode.is_dir() => idx,
+                _ => return Ok(None),
+            };
+
+            self.iter_dir_at(dir_index, |f, inode, idx, offset| {
+                if f == name {
+                    found = Some((idx, Some(inode.clone()), offset));
+                    return Action::Break;
+                }
+                Action::Continue
+            })?;
+        }
+
+        match found {
+            Some((idx, Some(inode), offset)) => Ok(Some((idx, inode, offset))),
+            Some((idx, None, _)) => {
+                let (inode, _) = self.load_inode_by_index(idx as usize)?;
+                let offset = match &self.state {
+                    RafsState::V5(s) => s.inodes_table.data[idx as usize] << 3,
+                };
+                Ok(Some((idx, inode, offset)))
+            }
+            None => Ok(None),
+        }
+    }
+
     fn path_from_ino(&self, mut ino: u64) -> Result<PathBuf> {
         let mut path = PathBuf::new();
         let mut entries = Vec::<PathBuf>::new();
@@ -464,64 +556,117 @@ Blocks:             {blocks}"#,
     }
 
     pub fn cmd_stat_file(&self, name: &str) -> Result<Option<Value>> {
-        let b = self.bootstrap.clone();
+        let (index, inode, offset) = match self.lookup_path(name)? {
+            Some(v) => v,
+            None => {
+                if self.request_mode {
+                    bail!("File {:?} does not exist", name);
+                }
+                println!("File does not exist");
+                return Ok(None);
+            }
+        };
 
-        if name == "." {
-            let (dir_inode, name) = self.load_inode_by_index(self.cur_dir_index as usize)?;
-            Self::stat_single_file(
-                &dir_inode,
-                &name.to_string_lossy(),
-                self.cur_dir_index as usize,
-            );
-            return Ok(None);
+        let chunks = {
+            let mut guard = self.bootstrap.lock().unwrap();
+            let bootstrap = guard.deref_mut();
+            Self::list_chunks(bootstrap, &inode, offset)?.unwrap_or_default()
+        };
+
+        if self.request_mode {
+            let mut value = Self::inode_to_json(&inode, name, index as usize);
+            value["chunks"] = self.chunks_to_json(&chunks)?;
+            return Ok(Some(value));
         }
 
-        self.iter_dir(|f, inode, idx, offset| {
-            if f == name {
-                let mut guard = b.lock().unwrap();
-                let bootstrap = guard.deref_mut();
-                let chunks = Self::list_chunks(bootstrap, inode, offset);
-                Self::stat_single_file(inode, name, idx as usize);
-
-                if let Ok(Some(cks)) = chunks {
-                    println!("    Chunks list:");
-                    for (i, c) in cks.iter().enumerate() {
-                        let blob_id = if let Ok(id) = self.state.get_blob_id(c.blob_index) {
-                            id.to_owned()
-                        } else {
-                            error!(
-                                "Blob index is {} . But no blob entry associate with it",
-                                c.blob_index
-                            );
-                            return Action::Break;
-                        };
+        Self::stat_single_file(&inode, name, index as usize);
+        if !chunks.is_empty() {
+            println!("    Chunks list:");
+        }
+        for (i, c) in chunks.iter().enumerate() {
+            let blob_id = self.state.get_blob_id(c.blob_index).map_err(|e| {
+                anyhow!(
+                    "Blob index is {} . But no blob entry associate with it, {:?}",
+                    c.blob_index,
+                    e
+                )
+            })?;
 
-                        println!(
-                            r#"        {} ->
+            println!(
+                r#"        {} ->
             file offset: {file_offset}, chunk index: {chunk_index}
             compressed size: {compressed_size}, decompressed size: {decompressed_size}
             compressed offset: {compressed_offset}, decompressed offset: {decompressed_offset},
             blob id: {blob_id}, chunk id: {chunk_id}
         "#,
-                            i,
-                            chunk_index = c.index,
-                            file_offset = c.file_offset,
-                            compressed_size = c.compress_size,
-                            decompressed_size = c.uncompress_size,
-                            decompressed_offset = c.uncompress_offset,
-                            compressed_offset = c.compress_offset,
-                            blob_id = blob_id,
-                            chunk_id = c.block_id
-                        );
-                    }
+                i,
+                chunk_index = c.index,
+                file_offset = c.file_offset,
+                compressed_size = c.compress_size,
+                decompressed_size = c.uncompress_size,
+                decompressed_offset = c.uncompress_offset,
+                compressed_offset = c.compress_offset,
+                blob_id = blob_id,
+                chunk_id = c.block_id
+            );
+        }
+
+        Ok(None)
+    }
+
+    /// List all regular files in the filesystem with their chunk layout.
+    fn cmd_list_files(&self) -> Result<Option<Value>> {
+        let b = self.bootstrap.clone();
+        let mut value = json!([]);
+        let mut result = Ok(());
+
+        self.walk_fs(0, &mut |_name, inode, _index, offset| {
+            if !inode.is_reg() {
+                return Action::Continue;
+            }
+
+            let chunks = {
+                // Not expect poisoned lock
+                let mut guard = b.lock().unwrap();
+                let bootstrap = guard.deref_mut();
+                Self::list_chunks(bootstrap, inode, offset)
+            };
+            let (path, chunks) = match (self.path_from_ino(inode.ino()), chunks) {
+                (Ok(p), Ok(c)) => (p, c.unwrap_or_default()),
+                (Err(e), _) | (_, Err(e)) => {
+                    result = Err(e);
+                    return Action::Break;
                 }
+            };
 
-                return Action::Break;
+            if self.request_mode {
+                match self.chunks_to_json(&chunks) {
+                    Ok(v) => value.as_array_mut().unwrap().push(json!({
+                        "inode": inode.ino(),
+                        "path": path,
+                        "size": inode.size(),
+                        "chunks": v,
+                    })),
+                    Err(e) => {
+                        result = Err(e);
+                        return Action::Break;
+                    }
+                }
+            } else {
+                println!(
+                    r#"Inode Number:{inode_number:10} | Size: {size:10} | Chunks: {chunks:6} | Path: {path:?}"#,
+                    inode_number = inode.ino(),
+                    size = inode.size(),
+                    chunks = chunks.len(),
+                    path = path,
+                );
             }
+
             Action::Continue
         })?;
+        result?;
 
-        Ok(None)
+        Ok(if self.request_mode { Some(value) } else { None })
     }
 
     fn cmd_change_dir(&mut self, name: &str) -> Result<Option<Value>> {
@@ -571,18 +716,43 @@ Blocks:             {blocks}"#,
         let (meta, _) = Self::load_meta(bootstrap)?;
 
         let o = if self.request_mode {
-            Some(json!({"inodes_count": meta.inodes_count}))
+            Some(json!({
+                "version": meta.fs_version,
+                "inodes_count": meta.inodes_count,
+                "chunk_size": meta.chunk_size,
+                "flags": meta.flags.to_string(),
+                "inode_table_offset": meta.inode_table_offset,
+                "inode_table_entries": meta.inode_table_entries,
+                "prefetch_table_offset": meta.prefetch_table_offset,
+                "prefetch_table_entries": meta.prefetch_table_entries,
+                "blob_table_offset": meta.blob_table_offset,
+                "blob_table_size": meta.blob_table_size,
+                "extended_blob_table_offset": meta.extended_blob_table_offset,
+                "extended_blob_table_entries": meta.extended_blob_table_entries,
+            }))
         } else {
             println!(
                 r#"
     Version:            {version}
     Inodes Count:       {inodes_count}
     Chunk Size:         {chunk_size}
-    Flags:              {flags}"#,
+    Flags:              {flags}
+    Inode Table:        offset {inode_table_offset}, entries {inode_table_entries}
+    Prefetch Table:     offset {prefetch_table_offset}, entries {prefetch_table_entries}
+    Blob Table:         offset {blob_table_offset}, size {blob_table_size}
+    Ext Blob Table:     offset {extended_blob_table_offset}, entries {extended_blob_table_entries}"#,
                 version = meta.fs_version,
                 inodes_count = meta.inodes_count,
                 chunk_size = meta.chunk_size,
                 flags = meta.flags,
+                inode_table_offset = meta.inode_table_offset,
+                inode_table_entries = meta.inode_table_entries,
+                prefetch_table_offset = meta.prefetch_table_offset,
+                prefetch_table_entries = meta.prefetch_table_entries,
+                blob_table_offset = meta.blob_table_offset,
+                blob_table_size = meta.blob_table_size,
+                extended_blob_table_offset = meta.extended_blob_table_offset,
+                extended_blob_table_entries = meta.extended_blob_table_entries,
             );
 
             None
@@ -749,6 +919,7 @@ impl Executor {
             ("ls", None) => inspector.cmd_list_dir(),
             ("cd", Some(dir)) => inspector.cmd_change_dir(dir),
             ("stat", Some(file_name)) => inspector.cmd_stat_file(file_name),
+            ("files", None) => inspector.cmd_list_files(),
             ("blobs", None) => inspector.cmd_list_blobs(),
             ("prefetch", None) => inspector.cmd_list_prefetch(),
             ("chunk", Some(argument)) => {
@@ -788,7 +959,8 @@ impl Executor {
     stats:              Display global rafs metadata
     ls:                 Show files in current directory
     cd DIR:             Change current directory
-    stat FILE_NAME:     Show particular information of rafs inode
+    stat PATH:          Show particular information and chunks of rafs inode by its path
+    files:              List all regular files with their chunk layout
     blobs:              Show blobs table
     prefetch:           Show prefetch table
     chunk OFFSET:       List basic info of a single chunk together with a list of files that share it
@@ -25,27 +25,44 @@ use serde::Serialize;
 
 use nydus_app::{setup_logging, BuildTimeInfo};
 use nydus_utils::digest;
+use rafs::metadata::{RafsMode, RafsSuper};
+use rafs::signature::{default_signature_path, sign};
 use rafs::RafsIoReader;
-use storage::{compress, RAFS_DEFAULT_CHUNK_SIZE};
+use storage::factory::BackendConfig;
+use storage::{compress, crypt, RAFS_DEFAULT_CHUNK_SIZE};
 
-use crate::builder::{Builder, DiffBuilder, DirectoryBuilder, StargzBuilder};
+use crate::builder::{
+    Builder, DiffBuilder, DirDiffBuilder, DirectoryBuilder, StargzBuilder, TarballBuilder,
+    TARBALL_STDIN,
+};
+use crate::core::build_cache::BuildCache;
 use crate::core::chunk_dict::import_chunk_dict;
+use crate::core::compression_stat::CompressionStat;
 use crate::core::context::{
     ArtifactStorage, BlobManager, BootstrapManager, BuildContext, BuildOutput, BuildOutputBlob,
     RafsVersion, SourceType,
 };
+use crate::core::filter::Filter;
 use crate::core::node::{self, WhiteoutSpec};
-use crate::core::prefetch::Prefetch;
+use crate::core::prefetch::{Prefetch, PrefetchPolicy};
 use crate::core::tree;
+use crate::estimate::Estimator;
+use crate::merge::Merger;
+use crate::prefetch_list::PrefetchListGenerator;
 use crate::trace::{EventTracerClass, TimingTracerClass, TraceClass};
-use crate::validator::Validator;
+use crate::unpack::Unpacker;
+use crate::validator::{BlobSource, ValidationReport, Validator};
 
 #[macro_use]
 mod trace;
 mod builder;
 mod core;
+mod estimate;
 mod inspect;
+mod merge;
+mod prefetch_list;
 mod stat;
+mod unpack;
 mod validator;
 
 const BLOB_ID_MAXIMUM_LENGTH: usize = 255;
@@ -64,8 +81,20 @@ pub struct OutputSerializer {
     /// Represents all bootstrap names for every snapshot in diff build,
     /// ordered by snapshot index, not include the skipped (cached) snapshots.
     bootstraps: Vec<String>,
+    /// RAFS version of the generated bootstrap, "5" or "6".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fs_version: Option<String>,
+    /// Chunk size of the generated bootstrap, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_size: Option<u32>,
     /// Performance trace info for current build.
     trace: serde_json::Map<String, serde_json::Value>,
+    /// Validation report for `check` subcommand.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check: Option<ValidationReport>,
+    /// Compression and deduplication statistics of data chunks for `create` subcommand.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<CompressionStat>,
 }
 
 impl OutputSerializer {
@@ -73,6 +102,8 @@ impl OutputSerializer {
         matches: &clap::ArgMatches,
         build_output: &BuildOutput,
         build_info: &BuildTimeInfo,
+        bootstrap_path: &Path,
+        compression: Option<CompressionStat>,
     ) -> Result<()> {
         let output_json: Option<PathBuf> = matches
             .value_of("output-json")
@@ -86,6 +117,13 @@ impl OutputSerializer {
                 .open(f)
                 .with_context(|| format!("Output file {:?} can't be opened", f))?;
 
+            // Report the format of the generated bootstrap rather than the build options.
+            let rs = RafsSuper::load_from_metadata(
+                &bootstrap_path.to_string_lossy(),
+                RafsMode::Direct,
+                false,
+            )
+            .with_context(|| format!("failed to load bootstrap {:?}", bootstrap_path))?;
             let trace = root_tracer!().dump_summary_map().unwrap_or_default();
             let version = format!("{}-{}", build_info.package_ver, build_info.git_commit);
             let output = Self {
@@ -93,7 +131,11 @@ impl OutputSerializer {
                 blobs: build_output.get_exists_blobs(),
                 ordered_blobs: build_output.blobs.clone(),
                 bootstraps: build_output.bootstraps.clone(),
+                fs_version: Some(if rs.meta.is_v6() { "6" } else { "5" }.to_string()),
+                chunk_size: Some(rs.meta.chunk_size),
                 trace,
+                check: None,
+                compression,
             };
 
             serde_json::to_writer(w, &output).context("Write output file failed")?;
@@ -105,7 +147,7 @@ impl OutputSerializer {
     fn dump_with_check(
         matches: &clap::ArgMatches,
         build_info: &BuildTimeInfo,
-        blob_ids: Vec<String>,
+        report: ValidationReport,
     ) -> Result<()> {
         let output_json: Option<PathBuf> = matches
             .value_of("output-json")
@@ -123,10 +165,14 @@ impl OutputSerializer {
             let version = format!("{}-{}", build_info.package_ver, build_info.git_commit);
             let output = Self {
                 version,
-                blobs: blob_ids,
+                blobs: report.blobs.clone(),
                 ordered_blobs: Vec::new(),
                 bootstraps: Vec::new(),
+                fs_version: None,
+                chunk_size: None,
                 trace,
+                check: Some(report),
+                compression: None,
             };
 
             serde_json::to_writer(w, &output).context("Write output file failed")?;
@@ -149,10 +195,19 @@ fn main() -> Result<()> {
                 .about("Creates a nydus image from source")
                 .arg(
                     Arg::with_name("SOURCE")
-                        .help("source path to build the nydus image from")
-                        .required(true)
+                        .help("source path to build the nydus image from, `-` to read a tarball from stdin")
+                        .required_unless("diff")
                         .multiple(true),
                 )
+                .arg(
+                    Arg::with_name("diff")
+                        .long("diff")
+                        .help("build a layer from changes between the lower and upper directories, with whiteouts for removed files")
+                        .takes_value(true)
+                        .number_of_values(2)
+                        .value_names(&["LOWER", "UPPER"])
+                        .conflicts_with("SOURCE")
+                )
                 .arg(
                     Arg::with_name("source-type")
                         .long("source-type")
@@ -160,7 +215,7 @@ fn main() -> Result<()> {
                         .help("type of the source:")
                         .takes_value(true)
                         .default_value("directory")
-                        .possible_values(&["directory", "stargz_index", "diff"])
+                        .possible_values(&["directory", "stargz_index", "diff", "tarball"])
                 )
                 .arg(
                     Arg::with_name("diff-overlay-hint")
@@ -234,6 +289,47 @@ fn main() -> Result<()> {
                         .default_value("blake3")
                         .possible_values(&["blake3", "sha256"]),
                 )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .help("number of worker threads to digest and compress data chunks, default to the number of CPUs")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("blob-max-size")
+                        .long("blob-max-size")
+                        .help("split data into multiple blobs of at most the size in bytes, with an optional K, M or G suffix, e.g. 4G")
+                        .takes_value(true)
+                        .conflicts_with("blob-id"),
+                )
+                .arg(
+                    Arg::with_name("build-cache")
+                        .long("build-cache")
+                        .help("directory to cache data chunks between builds, to skip chunking and compressing unchanged files")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cipher")
+                        .long("cipher")
+                        .help("algorithm to encrypt data chunks of the blob:")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("none")
+                        .possible_values(&["none", "aes256-gcm"]),
+                )
+                .arg(
+                    Arg::with_name("cipher-key-file")
+                        .long("cipher-key-file")
+                        .help("path of the file containing the raw key to encrypt data chunks")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cipher-key-id")
+                        .long("cipher-key-id")
+                        .help("id of the key to encrypt data chunks, recorded in the bootstrap to look up the key when mounting")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("fs-version")
                         .long("fs-version")
@@ -251,6 +347,13 @@ fn main() -> Result<()> {
                         .takes_value(true)
                         .required(false),
                 )
+                .arg(
+                    Arg::with_name("incremental")
+                        .long("incremental")
+                        .help("treat SOURCE as a complete filesystem and only dump files added or changed relative to the parent bootstrap")
+                        .takes_value(false)
+                        .requires("parent-bootstrap"),
+                )
                 .arg(
                     Arg::with_name("prefetch-policy")
                         .long("prefetch-policy")
@@ -261,6 +364,25 @@ fn main() -> Result<()> {
                         .default_value("none")
                         .possible_values(&["fs", "blob", "none"]),
                 )
+                .arg(
+                    Arg::with_name("prefetch-files")
+                        .long("prefetch-files")
+                        .help("read prefetch hints from the file instead of stdin, one path per line")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("sign-key")
+                        .long("sign-key")
+                        .help("sign the generated bootstrap with the ed25519 or ECDSA private key in PEM format")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("signature")
+                        .long("signature")
+                        .help("path to store the detached signature of the bootstrap, default to <bootstrap>.sig")
+                        .requires("sign-key")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("repeatable")
                         .long("repeatable")
@@ -269,6 +391,53 @@ fn main() -> Result<()> {
                         .takes_value(false)
                         .required(false),
                 )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .help("only build files matching the glob pattern into the image, may be specified multiple times")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .help("skip files matching the glob pattern and their descendants, may be specified multiple times")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("skip-special-files")
+                        .long("skip-special-files")
+                        .help("skip unix domain sockets and character/block device files")
+                        .takes_value(false)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("zero-timestamps")
+                        .long("zero-timestamps")
+                        .help("clear modification time of all files to generate reproducible nydus image")
+                        .takes_value(false)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("record-btime")
+                        .long("record-btime")
+                        .help("record creation time of files by the `user.nydus.btime` extended attribute")
+                        .takes_value(false)
+                        .required(false)
+                        .conflicts_with("zero-timestamps"),
+                )
+                .arg(
+                    Arg::with_name("check-case-collision")
+                        .long("check-case-collision")
+                        .help("fail if names in a directory only differ in case, to mount the image case-insensitively")
+                        .takes_value(false)
+                        .required(false),
+                )
                 .arg(
                     Arg::with_name("disable-check")
                         .long("disable-check")
@@ -284,7 +453,7 @@ fn main() -> Result<()> {
                         .takes_value(true)
                         .required(true)
                         .default_value("oci")
-                        .possible_values(&["oci", "overlayfs"])
+                        .possible_values(&["oci", "overlayfs", "none"])
                 )
                 .arg(
                     Arg::with_name("output-json")
@@ -317,15 +486,71 @@ fn main() -> Result<()> {
                 .arg(
                     Arg::with_name("backend-type")
                         .long("backend-type")
-                        .help("[deprecated!] Blob storage backend type, only support localfs for compatibility. Try use --blob instead.")
+                        .help("Blob storage backend type to upload the blob to while building, localfs is deprecated, try use --blob instead.")
                         .takes_value(true)
                         .requires("backend-config")
-                        .possible_values(&["localfs"]),
+                        .possible_values(&["localfs", "registry", "oss"]),
                 )
                 .arg(
                     Arg::with_name("backend-config")
                         .long("backend-config")
-                        .help("[deprecated!] Blob storage backend config - JSON string, only support localfs for compatibility")
+                        .help("Blob storage backend config - JSON string")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("estimate")
+                .about("Estimates the size of a nydus image to build from a directory without writing anything")
+                .arg(
+                    Arg::with_name("SOURCE")
+                        .help("source directory to build the nydus image from (required)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("chunk-size")
+                        .long("chunk-size")
+                        .short("S")
+                        .help("size of nydus image data chunk, must be power of two and between 0x1000-0x100000:")
+                        .default_value("0x100000")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("digester")
+                        .long("digester")
+                        .short("d")
+                        .help("algorithm to digest data chunks:")
+                        .takes_value(true)
+                        .default_value("blake3")
+                        .possible_values(&["blake3", "sha256"]),
+                )
+                .arg(
+                    Arg::with_name("chunk-dict")
+                        .long("chunk-dict")
+                        .short("M")
+                        .help("Specify a chunk dictionary to estimate chunk deduplication against")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .help("only estimate files matching the glob pattern, may be specified multiple times")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .help("skip files matching the glob pattern and their descendants, may be specified multiple times")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .short("J")
+                        .help("path to JSON output file")
                         .takes_value(true)
                 )
         )
@@ -347,6 +572,28 @@ fn main() -> Result<()> {
                         .help("verbose output")
                         .required(false),
                 )
+                .arg(
+                    Arg::with_name("blob-dir")
+                        .long("blob-dir")
+                        .short("D")
+                        .help("directory holding nydus image's data blobs, to verify chunk data digests")
+                        .takes_value(true)
+                        .conflicts_with("backend-type")
+                )
+                .arg(
+                    Arg::with_name("backend-type")
+                        .long("backend-type")
+                        .help("Blob storage backend type to read data blobs from, to verify chunk data digests")
+                        .takes_value(true)
+                        .requires("backend-config")
+                        .possible_values(&["localfs", "registry", "oss"]),
+                )
+                .arg(
+                    Arg::with_name("backend-config")
+                        .long("backend-config")
+                        .help("Blob storage backend config - JSON string")
+                        .takes_value(true)
+                )
                 .arg(
                     Arg::with_name("output-json")
                         .long("output-json")
@@ -410,6 +657,103 @@ fn main() -> Result<()> {
                         .takes_value(true)
                 )
         )
+        .subcommand(
+            SubCommand::with_name("unpack")
+                .about("Unpack nydus image's filesystem into a tar archive")
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .short("B")
+                        .help("path to nydus image's metadata blob (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("blob-dir")
+                        .long("blob-dir")
+                        .short("D")
+                        .help("directory holding nydus image's data blobs (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("O")
+                        .help("path to the output tar archive (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("prefetch-list")
+                .about("Generate prefetch hints from file access patterns recorded by nydusd")
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .short("B")
+                        .help("path to nydus image's metadata blob (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("access-patterns")
+                        .long("access-patterns")
+                        .short("A")
+                        .help("JSON file of access patterns exported by nydusd's `/api/v1/metrics/pattern` API (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("O")
+                        .help("path to the generated prefetch hint file, defaults to stdout")
+                        .takes_value(true),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merge bootstraps of multiple layers into a single bootstrap")
+                .arg(
+                    Arg::with_name("SOURCE")
+                        .help("bootstraps of layers to merge, ordered from the lowest layer to the top layer")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .short("B")
+                        .help("path to the merged bootstrap (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("whiteout-spec")
+                        .long("whiteout-spec")
+                        .short("W")
+                        .help("type of whiteout specification of layers:")
+                        .takes_value(true)
+                        .required(true)
+                        .default_value("oci")
+                        .possible_values(&["oci", "overlayfs"])
+                )
+                .arg(
+                    Arg::with_name("disable-check")
+                        .long("disable-check")
+                        .help("disable validation of metadata after merging")
+                        .takes_value(false)
+                        .required(false)
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .short("J")
+                        .help("JSON output path for merge result")
+                        .takes_value(true)
+                )
+        )
         .arg(
             Arg::with_name("log-level")
                 .long("log-level")
@@ -432,12 +776,20 @@ fn main() -> Result<()> {
 
     if let Some(matches) = cmd.subcommand_matches("create") {
         Command::create(matches, &build_info)
+    } else if let Some(matches) = cmd.subcommand_matches("estimate") {
+        Command::estimate(matches)
     } else if let Some(matches) = cmd.subcommand_matches("check") {
         Command::check(matches, &build_info)
     } else if let Some(matches) = cmd.subcommand_matches("inspect") {
         Command::inspect(matches)
     } else if let Some(matches) = cmd.subcommand_matches("stat") {
         Command::stat(matches)
+    } else if let Some(matches) = cmd.subcommand_matches("unpack") {
+        Command::unpack(matches)
+    } else if let Some(matches) = cmd.subcommand_matches("merge") {
+        Command::merge(matches, &build_info)
+    } else if let Some(matches) = cmd.subcommand_matches("prefetch-list") {
+        Command::prefetch_list(matches)
     } else {
         println!("{}", cmd.usage());
         Ok(())
@@ -451,12 +803,23 @@ impl Command {
         let blob_id = Self::get_blob_id(&matches)?;
         let chunk_size = Self::get_chunk_size(&matches)?;
         let parent_bootstrap = Self::get_parent_bootstrap(&matches)?;
-        let source_path = PathBuf::from(matches.value_of("SOURCE").unwrap());
+        // Safe to unwrap because `diff` takes exactly two values.
+        let diff_lower = matches
+            .values_of("diff")
+            .map(|mut paths| PathBuf::from(paths.next().unwrap()));
+        let source_path = match matches.values_of("diff") {
+            Some(paths) => PathBuf::from(paths.last().unwrap()),
+            // Safe to unwrap because `SOURCE` is required unless `diff` is specified.
+            None => PathBuf::from(matches.value_of("SOURCE").unwrap()),
+        };
         let extra_paths: Vec<PathBuf> = matches
             .values_of("SOURCE")
             .map(|paths| paths.map(PathBuf::from).skip(1).collect())
-            .unwrap();
+            .unwrap_or_default();
         let source_type: SourceType = matches.value_of("source-type").unwrap().parse()?;
+        if diff_lower.is_some() && source_type != SourceType::Directory {
+            bail!("--diff only supports the directory source type");
+        }
         let blob_stor = Self::get_blob_storage(&matches, source_type)?;
         let repeatable = matches.is_present("repeatable");
         let version = Self::get_fs_version(&matches)?;
@@ -477,6 +840,17 @@ impl Command {
         match source_type {
             SourceType::Directory | SourceType::Diff => {
                 Self::ensure_directory(&source_path)?;
+                if let Some(lower) = diff_lower.as_ref() {
+                    Self::ensure_directory(lower)?;
+                    if whiteout_spec == WhiteoutSpec::Disabled {
+                        bail!("--diff requires whiteouts to represent removed files");
+                    }
+                }
+            }
+            SourceType::Tarball => {
+                if source_path != Path::new(TARBALL_STDIN) {
+                    Self::ensure_file(&source_path)?;
+                }
             }
             SourceType::StargzIndex => {
                 Self::ensure_file(&source_path)?;
@@ -498,7 +872,17 @@ impl Command {
             .value_of("prefetch-policy")
             .unwrap_or_default()
             .parse()?;
-        let prefetch = Prefetch::new(prefetch_policy)?;
+        let prefetch_files = matches.value_of("prefetch-files").map(Path::new);
+        if prefetch_files.is_some() && prefetch_policy == PrefetchPolicy::None {
+            bail!("--prefetch-files requires a prefetch policy other than none");
+        }
+        if prefetch_files.is_none()
+            && prefetch_policy != PrefetchPolicy::None
+            && source_path == Path::new(TARBALL_STDIN)
+        {
+            bail!("--prefetch-files is required to read tar stream from stdin with prefetch");
+        }
+        let prefetch = Prefetch::new(prefetch_policy, prefetch_files)?;
 
         let mut build_ctx = BuildContext::new(
             blob_id,
@@ -514,6 +898,62 @@ impl Command {
         );
         build_ctx.set_fs_version(version);
         build_ctx.set_chunk_size(chunk_size);
+        build_ctx.set_threads(Self::get_threads(&matches)?);
+        build_ctx.set_zero_timestamps(matches.is_present("zero-timestamps"));
+        if matches.is_present("record-btime") {
+            if source_type != SourceType::Directory || diff_lower.is_some() {
+                bail!("--record-btime only supports the directory source type");
+            }
+            build_ctx.set_record_btime(true);
+        }
+        build_ctx.set_check_case_collision(matches.is_present("check-case-collision"));
+        let filter = Self::get_filter(&matches)?;
+        if !filter.is_empty() && (source_type != SourceType::Directory || diff_lower.is_some()) {
+            bail!("file filters only support the directory source type");
+        }
+        build_ctx.set_filter(filter);
+        if matches.is_present("incremental") {
+            if source_type != SourceType::Directory || diff_lower.is_some() {
+                bail!("--incremental only supports the directory source type");
+            }
+            build_ctx.set_incremental(true);
+        }
+        if let Some(max_size) = Self::get_blob_max_size(&matches, chunk_size)? {
+            if source_type != SourceType::Directory && source_type != SourceType::Tarball {
+                bail!("--blob-max-size only supports the directory and tarball source types");
+            }
+            if !matches!(
+                build_ctx.blob_storage,
+                Some(ArtifactStorage::FileDir(_)) | Some(ArtifactStorage::Backend(_))
+            ) {
+                bail!("--blob-max-size requires --blob-dir or a storage backend to store multiple blobs");
+            }
+            build_ctx.set_blob_max_size(max_size);
+        }
+        if let Some(cache_dir) = matches.value_of("build-cache") {
+            if source_type != SourceType::Directory && source_type != SourceType::Diff {
+                bail!("--build-cache only supports the directory and diff source types");
+            }
+            let cache = BuildCache::open(Path::new(cache_dir), digester, compressor, chunk_size)?;
+            build_ctx.set_build_cache(cache);
+        }
+        if let Some((cipher, key_id)) = Self::get_cipher(&matches)? {
+            if version.is_v6()
+                || (source_type != SourceType::Directory && source_type != SourceType::Tarball)
+                || diff_lower.is_some()
+            {
+                bail!("--cipher only supports rafs v5 images built from directories or tarballs");
+            }
+            // Chunks are authenticated along with the id of their blob.
+            if build_ctx.blob_id.is_empty() {
+                bail!("--cipher requires --blob-id");
+            }
+            // Reused chunks would stay unencrypted or encrypted by other keys.
+            if matches.is_present("parent-bootstrap") || matches.is_present("chunk-dict") {
+                bail!("--cipher can't reuse chunks from --parent-bootstrap or --chunk-dict");
+            }
+            build_ctx.set_cipher(cipher, key_id);
+        }
 
         let mut blob_mgr = BlobManager::new();
         if let Some(chunk_dict_arg) = matches.value_of("chunk-dict") {
@@ -543,8 +983,12 @@ impl Command {
 
         let diff_overlay_hint = matches.is_present("diff-overlay-hint");
         let mut builder: Box<dyn Builder> = match source_type {
-            SourceType::Directory => Box::new(DirectoryBuilder::new()),
+            SourceType::Directory => match diff_lower {
+                Some(lower) => Box::new(DirDiffBuilder::new(lower)),
+                None => Box::new(DirectoryBuilder::new()),
+            },
             SourceType::StargzIndex => Box::new(StargzBuilder::new()),
+            SourceType::Tarball => Box::new(TarballBuilder::new()),
             SourceType::Diff => Box::new(DiffBuilder::new(
                 extra_paths,
                 diff_overlay_hint,
@@ -560,6 +1004,10 @@ impl Command {
             "total_build"
         )?;
 
+        if let Some(cache) = build_ctx.build_cache.as_ref() {
+            cache.persist().context("failed to save build cache")?;
+        }
+
         // Some operations like listing xattr pairs of certain namespace need the process
         // to be privileged. Therefore, trace what euid and egid are
         event_tracer!("euid", "{}", geteuid());
@@ -568,22 +1016,90 @@ impl Command {
         // Validate output bootstrap file
         let bootstrap_path = bootstrap_mgr.get_bootstrap_path(&build_output.bootstrap_name);
         Self::validate_image(&matches, &bootstrap_path)?;
-        OutputSerializer::dump(matches, &build_output, &build_info)?;
+        if let Some(key) = matches.value_of("sign-key") {
+            Self::sign_bootstrap(
+                &bootstrap_path,
+                Path::new(key),
+                matches.value_of("signature"),
+            )?;
+        }
+        let compression = blob_mgr.get_compression_stat();
+        info!(
+            "data chunks: original size {}, compressed size {}, deduplicated size {}",
+            compression.total.original_size,
+            compression.total.compressed_size,
+            compression.total.dedup_size
+        );
+        OutputSerializer::dump(
+            matches,
+            &build_output,
+            &build_info,
+            &bootstrap_path,
+            Some(compression),
+        )?;
         info!("build successfully: {:?}", build_output,);
 
         Ok(())
     }
 
+    fn estimate(matches: &clap::ArgMatches) -> Result<()> {
+        // Safe to unwrap because it's a required argument.
+        let source_path = Path::new(matches.value_of("SOURCE").unwrap());
+        Self::ensure_directory(source_path)?;
+        let chunk_size = Self::get_chunk_size(&matches)?;
+        let digester = matches.value_of("digester").unwrap_or_default().parse()?;
+        let filter = Self::get_filter(&matches)?;
+        let chunk_dict = match matches.value_of("chunk-dict") {
+            Some(arg) => Some(import_chunk_dict(arg)?),
+            None => None,
+        };
+
+        let estimator = Estimator::new(chunk_size, digester, filter, chunk_dict);
+        let estimation = estimator
+            .estimate(source_path)
+            .with_context(|| format!("failed to estimate image from {:?}", source_path))?;
+
+        if let Some(path) = matches.value_of("output-json").map(PathBuf::from) {
+            estimation.dump_json(&path)?;
+        } else {
+            estimation.dump();
+        }
+
+        Ok(())
+    }
+
     fn check(matches: &clap::ArgMatches, build_info: &BuildTimeInfo) -> Result<()> {
         let bootstrap_path = Self::get_bootstrap(matches)?;
         let verbose = matches.is_present("verbose");
-        let mut validator = Validator::new(bootstrap_path)?;
-        let blob_ids = validator
+        let blob_source = if let Some(backend_type) = matches.value_of("backend-type") {
+            // Safe to unwrap because `backend-config` is required by `backend-type`.
+            let config_json = matches.value_of("backend-config").unwrap();
+            let config = BackendConfig::from_str(backend_type, config_json)
+                .context("invalid backend config")?;
+            Some(BlobSource::Backend(config))
+        } else {
+            matches
+                .value_of("blob-dir")
+                .map(|d| BlobSource::Dir(PathBuf::from(d)))
+        };
+        let mut validator = Validator::new(bootstrap_path, blob_source)?;
+        let report = validator
             .check(verbose)
             .with_context(|| format!("failed to check bootstrap {:?}", bootstrap_path))?;
 
-        info!("bootstrap is valid, blobs: {:?}", blob_ids);
-        OutputSerializer::dump_with_check(matches, &build_info, blob_ids)?;
+        for e in &report.errors {
+            error!("{}", e);
+        }
+        if !report.orphan_blobs.is_empty() {
+            warn!("orphan blobs: {:?}", report.orphan_blobs);
+        }
+        let valid = report.is_valid();
+        OutputSerializer::dump_with_check(matches, &build_info, report)?;
+
+        if !valid {
+            bail!("bootstrap {:?} is invalid", bootstrap_path);
+        }
+        info!("bootstrap is valid");
 
         Ok(())
     }
@@ -655,6 +1171,96 @@ impl Command {
         Ok(())
     }
 
+    fn unpack(matches: &clap::ArgMatches) -> Result<()> {
+        let bootstrap_path = Self::get_bootstrap(matches)?;
+        // Safe to unwrap because they are required arguments.
+        let blob_dir = Path::new(matches.value_of("blob-dir").unwrap());
+        let output = Path::new(matches.value_of("output").unwrap());
+
+        let mut unpacker = Unpacker::new(bootstrap_path, blob_dir)?;
+        unpacker
+            .unpack(output)
+            .with_context(|| format!("failed to unpack bootstrap {:?}", bootstrap_path))?;
+        info!("unpacked image into {:?}", output);
+
+        Ok(())
+    }
+
+    fn prefetch_list(matches: &clap::ArgMatches) -> Result<()> {
+        let bootstrap_path = Self::get_bootstrap(matches)?;
+        // Safe to unwrap because it's a required argument.
+        let patterns = Path::new(matches.value_of("access-patterns").unwrap());
+
+        let files = PrefetchListGenerator::generate(bootstrap_path, patterns)
+            .context("failed to generate prefetch list")?;
+        let mut list = String::new();
+        for file in files.iter() {
+            list.push_str(&format!("{}\n", file.display()));
+        }
+
+        if let Some(output) = matches.value_of("output") {
+            fs::write(output, list)
+                .with_context(|| format!("failed to write prefetch list to {:?}", output))?;
+            info!(
+                "generated prefetch list of {} files into {:?}",
+                files.len(),
+                output
+            );
+        } else {
+            print!("{}", list);
+        }
+
+        Ok(())
+    }
+
+    fn merge(matches: &clap::ArgMatches, build_info: &BuildTimeInfo) -> Result<()> {
+        let bootstrap_path = Self::get_bootstrap(matches)?;
+        // Safe to unwrap because it's a required argument.
+        let sources: Vec<PathBuf> = matches
+            .values_of("SOURCE")
+            .unwrap()
+            .map(PathBuf::from)
+            .collect();
+        for source in sources.iter() {
+            Self::ensure_file(source)?;
+        }
+        let whiteout_spec: WhiteoutSpec = matches
+            .value_of("whiteout-spec")
+            .unwrap_or_default()
+            .parse()?;
+
+        // Image configuration is overridden by the bootstrap of the lowest layer.
+        let mut build_ctx = BuildContext::new(
+            String::new(),
+            false,
+            compress::Algorithm::default(),
+            digest::Algorithm::default(),
+            true,
+            whiteout_spec,
+            SourceType::Directory,
+            PathBuf::new(),
+            Prefetch::default(),
+            None,
+        );
+        let build_output = timing_tracer!(
+            {
+                Merger::merge(&mut build_ctx, &sources, bootstrap_path)
+                    .context("failed to merge bootstraps")
+            },
+            "total_build"
+        )?;
+
+        Self::validate_image(&matches, bootstrap_path)?;
+        OutputSerializer::dump(matches, &build_output, &build_info, bootstrap_path, None)?;
+        info!(
+            "merged {} bootstraps into {:?}",
+            sources.len(),
+            bootstrap_path
+        );
+
+        Ok(())
+    }
+
     fn get_bootstrap<'a>(matches: &'a clap::ArgMatches) -> Result<&'a Path> {
         match matches.value_of("bootstrap") {
             None => bail!("missing parameter `bootstrap`"),
@@ -662,18 +1268,27 @@ impl Command {
         }
     }
 
-    // Must specify a path to blob file.
-    // For cli/binary interface compatibility sake, keep option `backend-config`, but
-    // it only receives "localfs" backend type and it will be REMOVED in the future
+    // Must specify a path to blob file or a storage backend to upload the blob to.
+    // For cli/binary interface compatibility sake, keep option `backend-config` for "localfs"
+    // backend type, but it will be REMOVED in the future
     fn get_blob_storage(
         matches: &clap::ArgMatches,
         source_type: SourceType,
     ) -> Result<Option<ArtifactStorage>> {
-        // Must specify a path to blob file.
-        // For cli/binary interface compatibility sake, keep option `backend-config`, but
-        // it only receives "localfs" backend type and it will be REMOVED in the future
-        let blob_stor = if source_type == SourceType::Directory || source_type == SourceType::Diff {
-            if let Some(p) = matches
+        let backend_type = matches.value_of("backend-type").unwrap_or_default();
+        let blob_stor = if source_type != SourceType::StargzIndex {
+            if backend_type == "registry" || backend_type == "oss" {
+                if backend_type == "oss"
+                    && matches.value_of("blob-id").unwrap_or_default().is_empty()
+                {
+                    bail!("--blob-id is required to upload blob to oss backend");
+                }
+                // Safe to unwrap because `backend-config` is required by `backend-type`.
+                let config_json = matches.value_of("backend-config").unwrap();
+                let config = BackendConfig::from_str(backend_type, config_json)
+                    .context("invalid backend config")?;
+                Some(ArtifactStorage::Backend(config))
+            } else if let Some(p) = matches
                 .value_of("blob")
                 .map(|b| ArtifactStorage::SingleFile(b.into()))
             {
@@ -748,8 +1363,8 @@ impl Command {
     #[allow(dead_code)]
     fn validate_image(matches: &clap::ArgMatches, bootstrap_path: &Path) -> Result<()> {
         if !matches.is_present("disable-check") {
-            let mut validator = Validator::new(&bootstrap_path)?;
-            timing_tracer!(
+            let mut validator = Validator::new(&bootstrap_path, None)?;
+            let report = timing_tracer!(
                 {
                     validator
                         .check(false)
@@ -757,11 +1372,34 @@ impl Command {
                 },
                 "validate_bootstrap"
             )?;
+            if !report.is_valid() {
+                for e in &report.errors {
+                    error!("{}", e);
+                }
+                bail!("generated bootstrap {:?} is invalid", bootstrap_path);
+            }
         }
 
         Ok(())
     }
 
+    /// Generate detached signature for the bootstrap with private key `key`.
+    fn sign_bootstrap(bootstrap_path: &Path, key: &Path, signature: Option<&str>) -> Result<()> {
+        let key = fs::read(key).with_context(|| format!("failed to read sign key {:?}", key))?;
+        let data = fs::read(bootstrap_path)
+            .with_context(|| format!("failed to read bootstrap {:?}", bootstrap_path))?;
+        let signature_path = signature
+            .map(PathBuf::from)
+            .unwrap_or_else(|| default_signature_path(bootstrap_path));
+
+        let signature = sign(&key, &data).context("failed to sign bootstrap")?;
+        fs::write(&signature_path, signature)
+            .with_context(|| format!("failed to write signature {:?}", signature_path))?;
+        info!("bootstrap signature is saved to {:?}", signature_path);
+
+        Ok(())
+    }
+
     fn get_chunk_size(matches: &clap::ArgMatches) -> Result<u32> {
         match matches.value_of("chunk-size") {
             None => Ok(RAFS_DEFAULT_CHUNK_SIZE as u32),
@@ -780,6 +1418,88 @@ impl Command {
         }
     }
 
+    fn get_filter(matches: &clap::ArgMatches) -> Result<Filter> {
+        let include: Vec<&str> = matches
+            .values_of("include")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        let exclude: Vec<&str> = matches
+            .values_of("exclude")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+
+        Filter::new(&include, &exclude, matches.is_present("skip-special-files"))
+    }
+
+    fn get_blob_max_size(matches: &clap::ArgMatches, chunk_size: u32) -> Result<Option<u64>> {
+        let v = match matches.value_of("blob-max-size") {
+            None => return Ok(None),
+            Some(v) => v.trim(),
+        };
+        let (num, shift) = match v.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&v[..v.len() - 1], 10),
+            Some('M') => (&v[..v.len() - 1], 20),
+            Some('G') => (&v[..v.len() - 1], 30),
+            _ => (v, 0),
+        };
+        let size = num
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(1 << shift))
+            .ok_or_else(|| anyhow!("invalid blob max size {}", v))?;
+        // A blob must be able to hold at least one data chunk with the chunk information array.
+        if size < 2 * chunk_size as u64 {
+            bail!(
+                "blob max size {} must be at least twice the chunk size {}",
+                size,
+                chunk_size
+            );
+        }
+
+        Ok(Some(size))
+    }
+
+    fn get_threads(matches: &clap::ArgMatches) -> Result<usize> {
+        match matches.value_of("threads") {
+            None => Ok(num_cpus::get()),
+            Some(v) => {
+                let threads: usize = v
+                    .parse()
+                    .with_context(|| format!("invalid thread number {}", v))?;
+                if threads == 0 {
+                    bail!("invalid thread number: {}", threads);
+                }
+                Ok(threads)
+            }
+        }
+    }
+
+    fn get_cipher(matches: &clap::ArgMatches) -> Result<Option<(crypt::Cipher, String)>> {
+        let algorithm: crypt::Algorithm = matches.value_of("cipher").unwrap_or("none").parse()?;
+        let key_file = matches.value_of("cipher-key-file");
+        let key_id = matches.value_of("cipher-key-id");
+        if algorithm.is_none() {
+            if key_file.is_some() || key_id.is_some() {
+                bail!("--cipher-key-file and --cipher-key-id require --cipher");
+            }
+            return Ok(None);
+        }
+
+        let key_file = key_file.ok_or_else(|| anyhow!("--cipher requires --cipher-key-file"))?;
+        let key_id = key_id.ok_or_else(|| anyhow!("--cipher requires --cipher-key-id"))?;
+        if key_id.is_empty() || key_id.len() > crypt::MAX_KEY_ID_SIZE || key_id.contains('\0') {
+            bail!(
+                "cipher key id should be 1 to {} bytes without NUL",
+                crypt::MAX_KEY_ID_SIZE
+            );
+        }
+        let key = fs::read(key_file)
+            .with_context(|| format!("failed to read cipher key file {}", key_file))?;
+        let cipher = crypt::Cipher::new(algorithm, &key)?;
+
+        Ok(Some((cipher, key_id.to_string())))
+    }
+
     fn get_fs_version(matches: &clap::ArgMatches) -> Result<RafsVersion> {
         match matches.value_of("fs-version") {
             None => Ok(RafsVersion::V6),
@@ -0,0 +1,213 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Merge bootstraps of multiple independently built layers into a single bootstrap.
+//!
+//! Layers to be merged should be built with `--whiteout-spec none`, so whiteouts are kept in
+//! their bootstraps as ordinary files, then applied by the merger with overlay semantics.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rafs::metadata::{RafsMode, RafsSuper};
+use storage::device::BlobInfo;
+
+use crate::core::bootstrap::Bootstrap;
+use crate::core::context::{
+    ArtifactStorage, BlobManager, BootstrapManager, BuildContext, BuildOutput, RafsVersion,
+};
+use crate::core::node::{Node, Overlay, WhiteoutSpec, WhiteoutType, OVERLAYFS_WHITEOUT_OPAQUE};
+use crate::core::tree::Tree;
+
+pub struct Merger {}
+
+impl Merger {
+    /// Merge bootstraps in `sources`, ordered from the lowest layer to the top layer, into the
+    /// bootstrap at `target`.
+    pub fn merge(
+        ctx: &mut BuildContext,
+        sources: &[PathBuf],
+        target: &Path,
+    ) -> Result<BuildOutput> {
+        let mut blob_infos: Vec<Arc<BlobInfo>> = Vec::new();
+        let mut merged: Option<Tree> = None;
+
+        for (layer_idx, source) in sources.iter().enumerate() {
+            let path = source
+                .to_str()
+                .ok_or_else(|| anyhow!("bootstrap path {:?} is invalid", source))?;
+            let rs = RafsSuper::load_from_metadata(path, RafsMode::Direct, true)
+                .with_context(|| format!("failed to load bootstrap {:?}", source))?;
+            Self::check_layer_config(ctx, &rs, layer_idx)
+                .with_context(|| format!("bootstrap {:?} can't be merged", source))?;
+
+            // Map blob indexes of the layer to indexes in the merged blob table, layers may
+            // share blobs from the same chunk dictionary.
+            let mut blob_index_map = Vec::new();
+            for blob in rs.superblock.get_blob_infos() {
+                let index = match blob_infos
+                    .iter()
+                    .position(|b| b.blob_id() == blob.blob_id())
+                {
+                    Some(index) => index,
+                    None => {
+                        blob_infos.push(blob);
+                        blob_infos.len() - 1
+                    }
+                };
+                blob_index_map.push(index as u32);
+            }
+
+            let mut layer = Tree::from_bootstrap(&rs, &mut ())
+                .with_context(|| format!("failed to load tree from bootstrap {:?}", source))?;
+            Self::prepare_layer(&mut layer, layer_idx as u64, &blob_index_map)?;
+
+            let mut tree = merged
+                .take()
+                .unwrap_or_else(|| Tree::new(layer.node.clone()));
+            Self::apply_layer(&mut tree, &layer, ctx.whiteout_spec)
+                .with_context(|| format!("failed to apply bootstrap {:?}", source))?;
+            merged = Some(tree);
+        }
+
+        let mut tree = merged.ok_or_else(|| anyhow!("no bootstrap to merge"))?;
+        let mut blob_mgr = BlobManager::new();
+        blob_mgr.from_blob_table(blob_infos);
+        let mut bootstrap_mgr =
+            BootstrapManager::new(ArtifactStorage::SingleFile(target.to_path_buf()), None);
+        let mut bootstrap_ctx = bootstrap_mgr.create_ctx()?;
+        let mut bootstrap = Bootstrap::new()?;
+
+        bootstrap.build(ctx, &mut bootstrap_ctx, &mut tree)?;
+        match ctx.fs_version {
+            RafsVersion::V5 => {
+                let blob_table = blob_mgr.to_blob_table_v5(ctx, None)?;
+                bootstrap.dump_rafsv5(ctx, &mut bootstrap_ctx, &blob_table)?
+            }
+            RafsVersion::V6 => {
+                let blob_table = blob_mgr.to_blob_table_v6(ctx, None)?;
+                bootstrap.dump_rafsv6(ctx, &mut bootstrap_ctx, &blob_table)?
+            }
+        }
+
+        bootstrap_mgr.add(bootstrap_ctx);
+        BuildOutput::new(&blob_mgr, &bootstrap_mgr)
+    }
+
+    /// Take image configuration from the lowest layer, and ensure upper layers are consistent.
+    fn check_layer_config(ctx: &mut BuildContext, rs: &RafsSuper, layer_idx: usize) -> Result<()> {
+        let fs_version = if rs.meta.is_v6() {
+            RafsVersion::V6
+        } else {
+            RafsVersion::V5
+        };
+
+        if layer_idx == 0 {
+            ctx.fs_version = fs_version;
+            ctx.aligned_chunk = fs_version.is_v6();
+            ctx.compressor = rs.meta.get_compressor();
+            ctx.digester = rs.meta.get_digester();
+            ctx.chunk_size = rs.meta.chunk_size;
+            ctx.explicit_uidgid = rs.meta.explicit_uidgid();
+            return Ok(());
+        }
+
+        if ctx.fs_version != fs_version {
+            bail!("inconsistent fs version with lower layers");
+        }
+        if ctx.compressor != rs.meta.get_compressor() {
+            bail!(
+                "inconsistent compressor, current {}, lower {}",
+                rs.meta.get_compressor(),
+                ctx.compressor
+            );
+        }
+        if ctx.digester != rs.meta.get_digester() {
+            bail!(
+                "inconsistent digester, current {}, lower {}",
+                rs.meta.get_digester(),
+                ctx.digester
+            );
+        }
+        if ctx.chunk_size != rs.meta.chunk_size {
+            bail!(
+                "inconsistent chunk size, current 0x{:x}, lower 0x{:x}",
+                rs.meta.chunk_size,
+                ctx.chunk_size
+            );
+        }
+        if ctx.explicit_uidgid != rs.meta.explicit_uidgid() {
+            bail!("inconsistent explicit uid/gid flag with lower layers");
+        }
+
+        Ok(())
+    }
+
+    /// Mark nodes of the layer as upper nodes and relocate their chunks to the merged blob table.
+    fn prepare_layer(tree: &mut Tree, layer_idx: u64, blob_index_map: &[u32]) -> Result<()> {
+        let node = &mut tree.node;
+        // Inode numbers are unique within a layer only, so use layer index as device id to
+        // detect hardlinks correctly.
+        node.src_dev = layer_idx;
+        node.overlay = Overlay::UpperAddition;
+        let target = &node.target;
+        for chunk in node.chunks.iter_mut() {
+            let index = blob_index_map
+                .get(chunk.blob_index() as usize)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{:?}: chunk refers to invalid blob index {}",
+                        target,
+                        chunk.blob_index()
+                    )
+                })?;
+            chunk.set_blob_index(*index);
+        }
+
+        for child in tree.children.iter_mut() {
+            Self::prepare_layer(child, layer_idx, blob_index_map)?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply nodes of an upper layer to the merged tree.
+    fn apply_layer(tree: &mut Tree, layer: &Tree, whiteout_spec: WhiteoutSpec) -> Result<()> {
+        let mut whiteouts = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+
+        layer.iterate(&mut |node: &Node| {
+            match node.whiteout_type(whiteout_spec) {
+                Some(WhiteoutType::OverlayFsOpaque) => {
+                    whiteouts.push(node.clone());
+                    // The opaque directory itself is kept after removing lower children.
+                    let mut node = node.clone();
+                    node.remove_xattr(&OsString::from(OVERLAYFS_WHITEOUT_OPAQUE));
+                    nodes.push(node);
+                }
+                Some(_) => whiteouts.push(node.clone()),
+                None => nodes.push(node.clone()),
+            }
+            true
+        })?;
+
+        // Removals and opaques only apply to lower layers, so handle them before other changes
+        // of the same layer.
+        for node in whiteouts.iter() {
+            tree.apply(node, true, whiteout_spec)?;
+        }
+        for node in nodes.iter() {
+            if !tree.apply(node, false, whiteout_spec)? {
+                bail!(
+                    "failed to apply {:?}, parent directory is missing",
+                    node.target()
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
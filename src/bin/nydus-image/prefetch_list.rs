@@ -0,0 +1,87 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Generate prefetch hints from file access patterns recorded by nydusd.
+//!
+//! With `"access_pattern": true` in the rafs configuration, nydusd records the first access time
+//! of every file read from the mounted image, which can be exported by the `/api/v1/metrics/pattern`
+//! API. Files are sorted by their first access time and converted to paths in the image, so the
+//! generated list can be fed to `nydus-image create --prefetch-files` when rebuilding the image.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rafs::metadata::{RafsMode, RafsSuper};
+use serde::Deserialize;
+
+/// A file access record exported by nydusd.
+#[derive(Debug, Deserialize)]
+struct AccessRecord {
+    ino: u64,
+    nr_read: u64,
+    first_access_time_secs: u64,
+    first_access_time_nanos: u32,
+}
+
+pub struct PrefetchListGenerator {}
+
+impl PrefetchListGenerator {
+    /// Convert access patterns in `patterns` to paths of accessed files in the image `bootstrap`,
+    /// ordered by their first access time.
+    pub fn generate(bootstrap: &Path, patterns: &Path) -> Result<Vec<PathBuf>> {
+        let file = File::open(patterns)
+            .with_context(|| format!("failed to open access patterns {:?}", patterns))?;
+        let inodes = Self::parse_access_patterns(BufReader::new(file))
+            .with_context(|| format!("failed to parse access patterns {:?}", patterns))?;
+
+        let path = bootstrap
+            .to_str()
+            .ok_or_else(|| anyhow!("bootstrap path {:?} is invalid", bootstrap))?;
+        let rs = RafsSuper::load_from_metadata(path, RafsMode::Direct, true)
+            .with_context(|| format!("failed to load bootstrap {:?}", bootstrap))?;
+
+        let mut files = Vec::with_capacity(inodes.len());
+        for ino in inodes {
+            match rs.path_from_ino(ino) {
+                // Strip the trailing separator appended when joining path components.
+                Ok(path) => files.push(path.components().collect()),
+                Err(e) => warn!("skip inode {} not found in bootstrap, {}", ino, e),
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Get inode numbers of files which have been read, ordered by their first access time.
+    fn parse_access_patterns<R: Read>(reader: R) -> Result<Vec<u64>> {
+        let mut records: Vec<AccessRecord> = serde_json::from_reader(reader)?;
+
+        records.retain(|r| r.nr_read != 0);
+        records.sort_by_key(|r| (r.first_access_time_secs, r.first_access_time_nanos, r.ino));
+
+        Ok(records.iter().map(|r| r.ino).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_access_patterns() {
+        let patterns = r#"[
+            {"ino": 3, "nr_read": 2, "first_access_time_secs": 1650000001, "first_access_time_nanos": 10},
+            {"ino": 5, "nr_read": 1, "first_access_time_secs": 1650000000, "first_access_time_nanos": 20},
+            {"ino": 7, "nr_read": 0, "first_access_time_secs": 0, "first_access_time_nanos": 0},
+            {"ino": 2, "nr_read": 4, "first_access_time_secs": 1650000001, "first_access_time_nanos": 5}
+        ]"#;
+
+        let inodes = PrefetchListGenerator::parse_access_patterns(patterns.as_bytes()).unwrap();
+        assert_eq!(inodes, vec![5, 2, 3]);
+
+        assert!(PrefetchListGenerator::parse_access_patterns("{}".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Unpack a RAFS filesystem image into a tar archive.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Cursor, Error, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use nix::sys::stat;
+use rafs::metadata::{RafsMode, RafsSuper};
+use storage::compress;
+use storage::device::BlobInfo;
+use tar::{EntryType, Header};
+
+use crate::core::node::{ChunkWrapper, Node};
+use crate::core::tree::Tree;
+
+const PAX_HEADER_NAME: &str = "././@PaxHeader";
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Unpack a RAFS filesystem image into a standard tar archive.
+pub struct Unpacker {
+    sb: RafsSuper,
+    /// Directory holding data blobs named by blob id.
+    blob_dir: PathBuf,
+    blob_infos: Vec<Arc<BlobInfo>>,
+    blob_files: HashMap<u32, File>,
+    /// Paths of unpacked regular files, indexed by inode number, to detect hardlinks.
+    hardlinks: HashMap<u64, PathBuf>,
+}
+
+impl Unpacker {
+    pub fn new(bootstrap_path: &Path, blob_dir: &Path) -> Result<Self> {
+        let path = bootstrap_path
+            .to_str()
+            .ok_or_else(|| anyhow!("bootstrap path is invalid"))?;
+        let sb = RafsSuper::load_from_metadata(path, RafsMode::Direct, true)?;
+        let blob_infos = sb.superblock.get_blob_infos();
+
+        Ok(Self {
+            sb,
+            blob_dir: blob_dir.to_path_buf(),
+            blob_infos,
+            blob_files: HashMap::new(),
+            hardlinks: HashMap::new(),
+        })
+    }
+
+    /// Unpack the filesystem into a tar file at `output`.
+    pub fn unpack(&mut self, output: &Path) -> Result<()> {
+        let tree = Tree::from_bootstrap(&self.sb, &mut ())
+            .context("failed to load bootstrap for unpacker")?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output)
+            .with_context(|| format!("failed to create output file {:?}", output))?;
+        let mut builder = tar::Builder::new(BufWriter::new(file));
+
+        self.unpack_tree(&mut builder, &tree)?;
+
+        let mut writer = builder
+            .into_inner()
+            .context("failed to finish tar archive")?;
+        writer.flush().context("failed to flush tar archive")?;
+
+        Ok(())
+    }
+
+    fn unpack_tree<W: Write>(&mut self, builder: &mut tar::Builder<W>, tree: &Tree) -> Result<()> {
+        // The root directory has no entry in tar archive.
+        if tree.node.target() != Path::new("/") {
+            self.unpack_node(builder, &tree.node)
+                .with_context(|| format!("failed to unpack {:?}", tree.node.target()))?;
+        }
+        for child in &tree.children {
+            self.unpack_tree(builder, child)?;
+        }
+
+        Ok(())
+    }
+
+    fn unpack_node<W: Write>(&mut self, builder: &mut tar::Builder<W>, node: &Node) -> Result<()> {
+        let path = node
+            .target()
+            .strip_prefix("/")
+            .unwrap_or_else(|_| node.target().as_path());
+        let inode = &node.inode;
+        if inode.is_sock() {
+            // Unix domain sockets can't be represented in tar archive.
+            warn!("skip socket file {:?}", node.target());
+            return Ok(());
+        }
+
+        let mut header = Header::new_gnu();
+
+        header.set_mode(inode.mode() & 0o7777);
+        header.set_uid(inode.uid() as u64);
+        header.set_gid(inode.gid() as u64);
+        header.set_mtime(inode.mtime());
+        header.set_size(0);
+
+        if !node.xattrs.is_empty() {
+            Self::append_xattrs(builder, node)?;
+        }
+
+        if inode.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+            builder.append_data(&mut header, path, std::io::empty())?;
+        } else if inode.is_symlink() {
+            let target = node
+                .symlink
+                .as_ref()
+                .ok_or_else(|| anyhow!("symlink target is missing"))?;
+            header.set_entry_type(EntryType::Symlink);
+            builder.append_link(&mut header, path, target)?;
+        } else if inode.is_reg() {
+            if inode.nlink() > 1 {
+                if let Some(target) = self.hardlinks.get(&inode.ino()) {
+                    header.set_entry_type(EntryType::Link);
+                    builder.append_link(&mut header, path, target)?;
+                    return Ok(());
+                }
+                self.hardlinks.insert(inode.ino(), path.to_path_buf());
+            }
+            let size = node
+                .chunks
+                .iter()
+                .fold(0u64, |size, c| size + c.uncompressed_size() as u64);
+            if size != inode.size() {
+                bail!(
+                    "size of chunks {} doesn't match file size {}",
+                    size,
+                    inode.size()
+                );
+            }
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(size);
+            let reader = FileReader {
+                unpacker: self,
+                chunks: &node.chunks,
+                index: 0,
+                buf: Cursor::new(Vec::new()),
+            };
+            builder.append_data(&mut header, path, reader)?;
+        } else if inode.is_special() {
+            let entry_type = if inode.is_chrdev() {
+                EntryType::Char
+            } else if inode.is_blkdev() {
+                EntryType::Block
+            } else {
+                EntryType::Fifo
+            };
+            header.set_entry_type(entry_type);
+            header.set_device_major(stat::major(node.rdev) as u32)?;
+            header.set_device_minor(stat::minor(node.rdev) as u32)?;
+            builder.append_data(&mut header, path, std::io::empty())?;
+        } else {
+            bail!("unknown file type, mode 0x{:x}", inode.mode());
+        }
+
+        Ok(())
+    }
+
+    /// Append extended attributes as a PAX extended header for the following entry.
+    fn append_xattrs<W: Write>(builder: &mut tar::Builder<W>, node: &Node) -> Result<()> {
+        let mut data = Vec::new();
+
+        for (key, value) in node.xattrs.iter() {
+            let len = PAX_XATTR_PREFIX.len() + key.as_bytes().len() + value.len() + 3;
+            // The length field of a record includes the length of itself.
+            let mut total = len + len.to_string().len();
+            if total.to_string().len() > len.to_string().len() {
+                total += 1;
+            }
+            data.extend_from_slice(format!("{} {}", total, PAX_XATTR_PREFIX).as_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.push(b'=');
+            data.extend_from_slice(value);
+            data.push(b'\n');
+        }
+
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::XHeader);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        // Name of PAX extended header is informative only.
+        let name = PAX_HEADER_NAME.as_bytes();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_cksum();
+        builder.append(&header, Cursor::new(data))?;
+
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, chunk: &ChunkWrapper) -> Result<Vec<u8>> {
+        let blob_index = chunk.blob_index();
+        let blob_info = self
+            .blob_infos
+            .get(blob_index as usize)
+            .ok_or_else(|| anyhow!("invalid blob index {}", blob_index))?
+            .clone();
+        if blob_info.is_stargz() {
+            bail!(
+                "unpacking stargz blob {} is unsupported",
+                blob_info.blob_id()
+            );
+        }
+        if !blob_info.cipher().is_none() {
+            bail!(
+                "unpacking encrypted blob {} is unsupported",
+                blob_info.blob_id()
+            );
+        }
+
+        if !self.blob_files.contains_key(&blob_index) {
+            let path = self.blob_dir.join(blob_info.blob_id());
+            let file = File::open(&path)
+                .with_context(|| format!("failed to open blob file {:?}", path))?;
+            self.blob_files.insert(blob_index, file);
+        }
+        // Safe to unwrap because the blob file has been opened above.
+        let file = self.blob_files.get(&blob_index).unwrap();
+
+        let mut buf = vec![0u8; chunk.compressed_size() as usize];
+        file.read_exact_at(&mut buf, chunk.compressed_offset())
+            .with_context(|| format!("failed to read chunk {}", chunk.id()))?;
+        if !chunk.is_compressed() {
+            return Ok(buf);
+        }
+
+        let mut data = vec![0u8; chunk.uncompressed_size() as usize];
+        compress::decompress(&buf, None, &mut data, blob_info.compressor())
+            .with_context(|| format!("failed to decompress chunk {}", chunk.id()))?;
+
+        Ok(data)
+    }
+}
+
+/// Reader to fetch data of a regular file chunk by chunk.
+struct FileReader<'a> {
+    unpacker: &'a mut Unpacker,
+    chunks: &'a [ChunkWrapper],
+    index: usize,
+    buf: Cursor<Vec<u8>>,
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let count = self.buf.read(buf)?;
+            if count > 0 || buf.is_empty() || self.index >= self.chunks.len() {
+                return Ok(count);
+            }
+
+            let data = self
+                .unpacker
+                .read_chunk(&self.chunks[self.index])
+                .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))?;
+            self.buf = Cursor::new(data);
+            self.index += 1;
+        }
+    }
+}
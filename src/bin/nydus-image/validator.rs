@@ -4,49 +4,314 @@
 
 //! Validator for RAFS format
 
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Context, Error, Result};
+use nydus_utils::digest::RafsDigest;
 use rafs::metadata::{RafsMode, RafsSuper};
+use serde::Serialize;
+use storage::backend::BlobReader;
+use storage::compress;
+use storage::device::BlobInfo;
+use storage::factory::{BackendConfig, BLOB_FACTORY};
 
+use crate::core::node::{ChunkWrapper, Node};
 use crate::tree::Tree;
 
+/// Machine-readable report for bootstrap validation.
+#[derive(Serialize, Default)]
+pub struct ValidationReport {
+    /// Blob ids in the blob table, ordered by blob index.
+    pub blobs: Vec<String>,
+    /// Number of inodes in the filesystem tree.
+    pub inodes: u64,
+    /// Number of chunks referenced by regular files.
+    pub chunks: u64,
+    /// Number of chunks whose data has been verified against data blobs.
+    pub verified_chunks: u64,
+    /// Blobs in the blob table which are not referenced by any chunk.
+    pub orphan_blobs: Vec<String>,
+    /// Inconsistencies found in the bootstrap.
+    pub errors: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Where to read data blobs from to verify chunk data.
+pub enum BlobSource {
+    /// Directory holding data blobs named by blob id.
+    Dir(PathBuf),
+    /// Storage backend holding data blobs.
+    Backend(BackendConfig),
+}
+
+// An opened data blob.
+enum Blob {
+    File(File),
+    Reader(Arc<dyn BlobReader>),
+}
+
+impl Blob {
+    fn open(source: &BlobSource, blob_id: &str) -> Result<Self> {
+        match source {
+            BlobSource::Dir(dir) => {
+                let path = dir.join(blob_id);
+                let file = File::open(&path)
+                    .with_context(|| format!("failed to open blob file {:?}", path))?;
+                Ok(Blob::File(file))
+            }
+            BlobSource::Backend(config) => {
+                let reader = BLOB_FACTORY
+                    .new_reader(config.clone(), blob_id)
+                    .with_context(|| {
+                        format!("failed to open blob from {} backend", config.backend_type)
+                    })?;
+                Ok(Blob::Reader(reader))
+            }
+        }
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        match self {
+            Blob::File(file) => file.read_exact_at(buf, offset)?,
+            Blob::Reader(reader) => {
+                let size = reader
+                    .read(buf, offset)
+                    .map_err(|e| anyhow!("failed to read from backend, {:?}", e))?;
+                if size != buf.len() {
+                    bail!("short read from backend, {} of {} bytes", size, buf.len());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct Validator {
     sb: RafsSuper,
+    /// Source of data blobs, to verify chunk data.
+    blob_source: Option<BlobSource>,
+    blobs: HashMap<u32, Option<Blob>>,
+    verified: HashSet<(u32, u64)>,
+    referenced: Vec<bool>,
 }
 
 impl Validator {
-    pub fn new(bootstrap_path: &Path) -> Result<Self> {
+    pub fn new(bootstrap_path: &Path, blob_source: Option<BlobSource>) -> Result<Self> {
         let path = bootstrap_path
             .to_str()
             .ok_or_else(|| Error::msg("bootstrap path is invalid"))?;
         let sb = RafsSuper::load_from_metadata(path, RafsMode::Direct, true)?;
 
-        Ok(Self { sb })
+        Ok(Self {
+            sb,
+            blob_source,
+            blobs: HashMap::new(),
+            verified: HashSet::new(),
+            referenced: Vec::new(),
+        })
     }
 
-    pub fn check(&mut self, verbosity: bool) -> Result<Vec<String>> {
+    pub fn check(&mut self, verbosity: bool) -> Result<ValidationReport> {
         let err = "failed to load bootstrap for validator";
         let tree = Tree::from_bootstrap(&self.sb, &mut ()).context(err)?;
+        let blob_infos = self.sb.superblock.get_blob_infos();
+        let mut report = ValidationReport {
+            blobs: blob_infos
+                .iter()
+                .map(|entry| entry.blob_id().to_owned())
+                .collect::<Vec<String>>(),
+            ..Default::default()
+        };
 
-        tree.iterate(&mut |node| {
-            if verbosity {
-                info!("{}", node);
-                for chunk in &node.chunks {
-                    debug!("chunk {}", chunk);
+        if !tree.node.is_dir() {
+            report
+                .errors
+                .push(format!("root inode {} is not a directory", tree.node.index));
+        }
+
+        self.referenced = vec![false; blob_infos.len()];
+        self.check_tree(&tree, &blob_infos, verbosity, &mut report);
+
+        report.orphan_blobs = blob_infos
+            .iter()
+            .zip(self.referenced.iter())
+            .filter(|(_, referenced)| !**referenced)
+            .map(|(entry, _)| entry.blob_id().to_owned())
+            .collect();
+
+        Ok(report)
+    }
+
+    fn check_tree(
+        &mut self,
+        tree: &Tree,
+        blob_infos: &[Arc<BlobInfo>],
+        verbosity: bool,
+        report: &mut ValidationReport,
+    ) {
+        let node = &tree.node;
+        if verbosity {
+            info!("{}", node);
+            for chunk in &node.chunks {
+                debug!("chunk {}", chunk);
+            }
+        }
+        report.inodes += 1;
+
+        if node.is_reg() {
+            self.check_chunks(node, blob_infos, report);
+        } else if !node.chunks.is_empty() {
+            report.errors.push(format!(
+                "{:?}: non-regular file has {} chunks",
+                node.target(),
+                node.chunks.len()
+            ));
+        }
+
+        if !tree.children.is_empty() && !node.is_dir() {
+            report.errors.push(format!(
+                "{:?}: non-directory inode has children",
+                node.target()
+            ));
+        }
+
+        let mut names = HashSet::new();
+        for child in &tree.children {
+            if !names.insert(child.node.name()) {
+                report.errors.push(format!(
+                    "{:?}: duplicated directory entry {:?}",
+                    node.target(),
+                    child.node.name()
+                ));
+            }
+            self.check_tree(child, blob_infos, verbosity, report);
+        }
+    }
+
+    fn check_chunks(
+        &mut self,
+        node: &Node,
+        blob_infos: &[Arc<BlobInfo>],
+        report: &mut ValidationReport,
+    ) {
+        let mut file_offset = 0u64;
+
+        for chunk in &node.chunks {
+            report.chunks += 1;
+
+            let blob_index = chunk.blob_index();
+            if blob_index as usize >= blob_infos.len() {
+                report.errors.push(format!(
+                    "{:?}: chunk {} refers to invalid blob index {}, blob table size {}",
+                    node.target(),
+                    chunk.id(),
+                    blob_index,
+                    blob_infos.len()
+                ));
+                continue;
+            }
+            self.referenced[blob_index as usize] = true;
+
+            if chunk.file_offset() != file_offset {
+                report.errors.push(format!(
+                    "{:?}: chunk {} has file offset {}, expect {}",
+                    node.target(),
+                    chunk.id(),
+                    chunk.file_offset(),
+                    file_offset
+                ));
+            }
+            if chunk.uncompressed_size() == 0 || chunk.compressed_size() == 0 {
+                report.errors.push(format!(
+                    "{:?}: chunk {} has zero size",
+                    node.target(),
+                    chunk.id()
+                ));
+            }
+            file_offset = chunk.file_offset() + chunk.uncompressed_size() as u64;
+
+            if self.blob_source.is_some()
+                && self
+                    .verified
+                    .insert((blob_index, chunk.compressed_offset()))
+            {
+                match self.verify_chunk_data(chunk, &blob_infos[blob_index as usize]) {
+                    Ok(true) => report.verified_chunks += 1,
+                    Ok(false) => {}
+                    Err(e) => report.errors.push(format!(
+                        "{:?}: chunk {} in blob {}: {:#}",
+                        node.target(),
+                        chunk.id(),
+                        blob_infos[blob_index as usize].blob_id(),
+                        e
+                    )),
                 }
             }
-            true
-        })?;
+        }
 
-        let blob_ids = self
-            .sb
-            .superblock
-            .get_blob_infos()
-            .iter()
-            .map(|entry| entry.blob_id().to_owned())
-            .collect::<Vec<String>>();
+        if file_offset != node.inode.size() {
+            report.errors.push(format!(
+                "{:?}: chunks cover {} bytes, but file size is {}",
+                node.target(),
+                file_offset,
+                node.inode.size()
+            ));
+        }
+    }
+
+    /// Read chunk data from the data blob and compare its digest with the chunk id.
+    ///
+    /// Return false if the chunk data is not verifiable, such as stargz blobs.
+    fn verify_chunk_data(&mut self, chunk: &ChunkWrapper, blob_info: &BlobInfo) -> Result<bool> {
+        // Keys to decrypt chunks aren't available to the validator.
+        if blob_info.is_stargz() || !blob_info.cipher().is_none() {
+            return Ok(false);
+        }
+
+        let blob_index = chunk.blob_index();
+        if !self.blobs.contains_key(&blob_index) {
+            // Safe to unwrap because it's only called when `blob_source` is specified.
+            let source = self.blob_source.as_ref().unwrap();
+            match Blob::open(source, blob_info.blob_id()) {
+                Ok(blob) => self.blobs.insert(blob_index, Some(blob)),
+                Err(e) => {
+                    self.blobs.insert(blob_index, None);
+                    return Err(e);
+                }
+            };
+        }
+        let blob = match self.blobs.get(&blob_index) {
+            Some(Some(b)) => b,
+            // Blob has been reported missing.
+            _ => return Ok(false),
+        };
+
+        let mut buf = vec![0u8; chunk.compressed_size() as usize];
+        blob.read_exact_at(&mut buf, chunk.compressed_offset())
+            .context("failed to read chunk data")?;
+        let data = if chunk.is_compressed() {
+            let mut data = vec![0u8; chunk.uncompressed_size() as usize];
+            compress::decompress(&buf, None, &mut data, blob_info.compressor())
+                .context("failed to decompress chunk data")?;
+            data
+        } else {
+            buf
+        };
+
+        let digest = RafsDigest::from_buf(&data, self.sb.meta.get_digester());
+        if &digest != chunk.id() {
+            bail!("digest mismatch, calculated {}", digest);
+        }
 
-        Ok(blob_ids)
+        Ok(true)
     }
 }
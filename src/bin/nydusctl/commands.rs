@@ -353,7 +353,7 @@ Profile:                {profile}
                     println!("    mounted_time:           {}", backend.mounted_time);
                     match backend.backend_type {
                         FsBackendType::PassthroughFs => {}
-                        FsBackendType::Rafs => {
+                        FsBackendType::Rafs | FsBackendType::Stargz => {
                             let fs: RafsConfig =
                                 serde_json::from_value(backend.config.unwrap().clone()).unwrap();
                             print!(
@@ -412,3 +412,90 @@ impl CommandUmount {
             .await
     }
 }
+
+pub(crate) struct CommandList {}
+
+impl CommandList {
+    pub async fn execute(
+        &self,
+        raw: bool,
+        client: &NydusdClient,
+        _params: Option<CommandParams>,
+    ) -> Result<()> {
+        let info = client.get("daemon").await?;
+        let backend_list = info["backend_collection"]
+            .as_object()
+            .ok_or_else(|| anyhow!("invalid daemon info, no backend collection"))?;
+
+        if raw {
+            println!("{}", serde_json::Value::from(backend_list.clone()));
+        } else {
+            println!(
+                "{:<32} {:<16} {:<32} SOURCE",
+                "MOUNTPOINT", "TYPE", "MOUNTED TIME"
+            );
+            for backend_obj in backend_list.values() {
+                let backend: FsBackendDesc = serde_json::from_value(backend_obj.clone())?;
+                println!(
+                    "{:<32} {:<16} {:<32} {}",
+                    backend.mountpoint,
+                    backend.backend_type.to_string(),
+                    backend.mounted_time.to_string(),
+                    backend.source,
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) struct CommandCachePurge {}
+
+impl CommandCachePurge {
+    pub async fn execute(
+        &self,
+        _raw: bool,
+        client: &NydusdClient,
+        _params: Option<CommandParams>,
+    ) -> Result<()> {
+        client.delete("blobcache", None, None).await
+    }
+}
+
+/// Times to query state of the new nydusd, before giving up the upgrade.
+const UPGRADE_WAIT_RETRIES: u32 = 50;
+
+pub(crate) struct CommandUpgrade {}
+
+impl CommandUpgrade {
+    pub async fn execute(
+        &self,
+        _raw: bool,
+        client: &NydusdClient,
+        params: Option<CommandParams>,
+    ) -> Result<()> {
+        let p = params.unwrap();
+        let new_client = NydusdClient::new(&p["new-sock"]);
+
+        // The old nydusd sends its fuse fd to the supervisor, then the new nydusd takes
+        // it over from the supervisor.
+        client.put("daemon/fuse/sendfd", None).await?;
+        new_client.put("daemon/fuse/takeover", None).await?;
+
+        let mut running = false;
+        for _ in 0..UPGRADE_WAIT_RETRIES {
+            let info = new_client.get("daemon").await?;
+            if info["state"] == "RUNNING" {
+                running = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        if !running {
+            bail!("new nydusd failed to get into RUNNING state");
+        }
+
+        client.put("daemon/exit", None).await
+    }
+}
@@ -21,7 +21,8 @@ mod client;
 mod commands;
 
 use commands::{
-    CommandBackend, CommandBlobcache, CommandDaemon, CommandFsStats, CommandMount, CommandUmount,
+    CommandBackend, CommandBlobcache, CommandCachePurge, CommandDaemon, CommandFsStats,
+    CommandList, CommandMount, CommandUmount, CommandUpgrade,
 };
 
 #[tokio::main]
@@ -118,7 +119,7 @@ async fn main() -> Result<()> {
                 )
                 .arg(
                     Arg::with_name("type")
-                        .possible_values(&["rafs", "passthrough_fs"])
+                        .possible_values(&["rafs", "passthrough_fs", "stargz"])
                         .long("type")
                         .required(true)
                         .takes_value(true),
@@ -133,6 +134,26 @@ async fn main() -> Result<()> {
                         .takes_value(true)
                         .index(1),
                 ),
+        )
+        .subcommand(SubCommand::with_name("list").about("List attached file system backends"))
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Manage blob caches of nydusd")
+                .subcommand(
+                    SubCommand::with_name("purge")
+                        .about("Purge cached data of blobs not used by any file system backend"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade")
+                .about("Hot upgrade nydusd by handing over the fuse session to a new nydusd")
+                .arg(
+                    Arg::with_name("new-sock")
+                        .long("new-sock")
+                        .help("Unix domain socket path of the new nydusd, which waits for taking over")
+                        .required(true)
+                        .takes_value(true),
+                ),
         );
 
     let cmd = app.get_matches();
@@ -222,5 +243,32 @@ async fn main() -> Result<()> {
         cmd.execute(raw, &client, Some(context)).await?
     }
 
+    if cmd.subcommand_matches("list").is_some() {
+        let cmd = CommandList {};
+        cmd.execute(raw, &client, None).await?
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("cache") {
+        if matches.subcommand_matches("purge").is_some() {
+            let cmd = CommandCachePurge {};
+            cmd.execute(raw, &client, None).await?
+        } else {
+            println!("{}", matches.usage());
+        }
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("upgrade") {
+        // Safe to unwrap as it is required by clap
+        let mut context = HashMap::new();
+
+        context.insert(
+            "new-sock".to_string(),
+            matches.value_of("new-sock").unwrap().to_string(),
+        );
+
+        let cmd = CommandUpgrade {};
+        cmd.execute(raw, &client, Some(context)).await?
+    }
+
     Ok(())
 }
@@ -4,9 +4,12 @@
 // SPDX-License-Identifier: (Apache-2.0 AND BSD-3-Clause)
 
 use std::convert::From;
+use std::fs::File;
 use std::str::FromStr;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use event_manager::{EventOps, EventSubscriber, Events};
 use nix::sys::signal::{kill, SIGTERM};
@@ -15,17 +18,29 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 use nydus::{FsBackendType, NydusError};
 use nydus_api::http_endpoint::{
-    ApiError, ApiMountCmd, ApiRequest, ApiResponse, ApiResponsePayload, ApiResult, DaemonConf,
-    DaemonErrorKind, MetricsErrorKind,
+    ApiBackendCmd, ApiError, ApiMountCmd, ApiPreheatCmd, ApiRequest, ApiRequestMessage,
+    ApiResponse, ApiResponsePayload, ApiResult, DaemonConf, DaemonErrorKind, MetricsErrorKind,
 };
 use nydus_utils::metrics;
-
-use crate::daemon::{DaemonError, FsBackendMountCmd, FsBackendUmountCmd, NydusDaemon};
+use nydus_utils::profiling::{self, ProfileFormat};
+use rafs::metadata::RAFS_MAX_METADATA_SIZE;
+use rafs::RafsIoRead;
+use storage::factory::{BackendConfig, BLOB_FACTORY};
+
+use crate::audit::{AuditEntry, AuditLog};
+use crate::daemon::{
+    DaemonError, DaemonResult, FsBackendMountCmd, FsBackendUmountCmd, NydusDaemon,
+};
 #[cfg(fusedev)]
 use crate::fusedev::FusedevDaemon;
+use crate::policy::check_mount_acl;
+use crate::preheat;
 
 type Result<T> = ApiResult<T>;
 
+/// Number of working threads to handle API requests concurrently.
+const API_WORKER_THREADS: usize = 4;
+
 impl From<DaemonError> for DaemonErrorKind {
     fn from(e: DaemonError) -> Self {
         use DaemonError::*;
@@ -35,6 +50,9 @@ impl From<DaemonError> for DaemonErrorKind {
             Unsupported => DaemonErrorKind::Unsupported,
             Serde(e) => DaemonErrorKind::Serde(e),
             UnexpectedEvent(e) => DaemonErrorKind::UnexpectedEvent(format!("{:?}", e)),
+            AlreadyExists => DaemonErrorKind::AlreadyExists(String::new()),
+            NotFound => DaemonErrorKind::NotFound,
+            PolicyViolation(e) => DaemonErrorKind::PolicyViolation(e),
             o => DaemonErrorKind::Other(o.to_string()),
         }
     }
@@ -50,31 +68,83 @@ impl From<NydusError> for DaemonError {
 }
 
 pub struct ApiServer {
-    to_http: Sender<ApiResponse>,
-    daemon: Arc<dyn NydusDaemon>,
+    daemon: Arc<dyn NydusDaemon + Send + Sync>,
+    // Serialize requests which change state of the daemon, such as mount and takeover.
+    state_lock: Mutex<()>,
+    // Whether to serve profiling requests under `/debug`.
+    debug_api: bool,
+    // Audit log of requests changing state of the daemon.
+    audit_log: Option<AuditLog>,
 }
 
 impl ApiServer {
     pub fn new(
-        to_http: Sender<ApiResponse>,
-        daemon: Arc<dyn NydusDaemon>,
+        daemon: Arc<dyn NydusDaemon + Send + Sync>,
+        debug_api: bool,
+        audit_log: Option<AuditLog>,
     ) -> std::io::Result<Self> {
-        Ok(ApiServer { to_http, daemon })
+        Ok(ApiServer {
+            daemon,
+            state_lock: Mutex::new(()),
+            debug_api,
+            audit_log,
+        })
     }
 
-    fn process_request(&self, from_http: &Receiver<ApiRequest>) -> std::io::Result<()> {
-        let request = from_http
-            .recv()
-            .map_err(|e| epipe!(format!("receive API channel failed {}", e)))?;
+    /// Working thread loop to handle API requests dispatched by `ApiSeverSubscriber`.
+    fn run(&self, from_subscriber: Arc<Mutex<Receiver<ApiRequestMessage>>>) {
+        loop {
+            let msg = from_subscriber.lock().unwrap().recv();
+            match msg {
+                Ok((request, caller, to_http)) => {
+                    let entry = self
+                        .audit_log
+                        .as_ref()
+                        .and_then(|_| AuditEntry::new(&request));
+                    let resp = self.process_request(request);
+                    if let (Some(audit_log), Some(entry)) = (self.audit_log.as_ref(), entry) {
+                        audit_log.record(entry, caller, &resp);
+                    }
+                    Self::respond(&to_http, resp);
+                }
+                // The API server subscriber has gone.
+                Err(_) => break,
+            }
+        }
+    }
 
-        let resp = match request {
+    fn process_request(&self, request: ApiRequest) -> ApiResponse {
+        // Queries and metrics are served concurrently, while requests changing daemon state
+        // are handled one by one.
+        let _guard = match request {
+            ApiRequest::ConfigureDaemon(_)
+            | ApiRequest::Exit
+            | ApiRequest::Mount(_, _)
+            | ApiRequest::Remount(_, _)
+            | ApiRequest::SwitchBackend(_, _)
+            | ApiRequest::FreezeMount(_, _)
+            | ApiRequest::ThawMount(_)
+            | ApiRequest::Umount(_)
+            | ApiRequest::SendFuseFd
+            | ApiRequest::Takeover
+            | ApiRequest::PurgeBlobcache => Some(self.state_lock.lock().unwrap()),
+            _ => None,
+        };
+
+        match request {
             ApiRequest::DaemonInfo => self.daemon_info(),
+            ApiRequest::DaemonHealth(liveness) => self.daemon_health(liveness),
             ApiRequest::ExportFsBackendInfo(mountpoint) => self.backend_info(&mountpoint),
+            ApiRequest::DumpState(name) => self.dump_state(name),
             ApiRequest::ConfigureDaemon(conf) => self.configure_daemon(conf),
             ApiRequest::Exit => self.do_exit(),
 
             ApiRequest::Mount(mountpoint, info) => self.do_mount(mountpoint, info),
+            ApiRequest::GetMount(mountpoint) => self.get_mount(&mountpoint),
             ApiRequest::Remount(mountpoint, info) => self.do_remount(mountpoint, info),
+            ApiRequest::SwitchBackend(mountpoint, cmd) => self.switch_backend(&mountpoint, cmd),
+            ApiRequest::FreezeMount(mountpoint, timeout) => self.freeze(&mountpoint, timeout),
+            ApiRequest::ThawMount(mountpoint) => self.thaw(&mountpoint),
             ApiRequest::Umount(mountpoint) => self.do_umount(mountpoint),
 
             ApiRequest::Events => Self::events(),
@@ -83,21 +153,28 @@ impl ApiServer {
                 Self::export_files_metrics(id, latest_read_files)
             }
             ApiRequest::ExportAccessPatterns(id) => Self::export_access_patterns(id),
+            ApiRequest::ExportAccessRecords(id) => Self::export_access_records(id),
+            ApiRequest::ExportErrorMetrics(id) => Self::export_error_metrics(id),
             ApiRequest::ExportBackendMetrics(id) => Self::export_backend_metrics(id),
             ApiRequest::ExportBlobcacheMetrics(id) => Self::export_blobcache_metrics(id),
+            ApiRequest::ExportBlobProgress(id) => Self::export_blob_progress(id),
             ApiRequest::ExportInflightMetrics => self.export_inflight_metrics(),
+            ApiRequest::ExportPullMetrics => self.export_pull_metrics(),
+            ApiRequest::ExportMemoryMetrics => self.export_memory_metrics(),
+
+            ApiRequest::PurgeBlobcache => Self::purge_blobcache(),
+            ApiRequest::Preheat(cmd) => Self::preheat(cmd),
+            ApiRequest::GetPreheat => Self::preheat_status(),
+            ApiRequest::CpuProfile(duration, format) => self.cpu_profile(duration, format),
+            ApiRequest::MallocInfo => self.malloc_info(),
 
             ApiRequest::SendFuseFd => self.send_fuse_fd(),
             ApiRequest::Takeover => self.do_takeover(),
-        };
-
-        self.respond(resp);
-
-        Ok(())
+        }
     }
 
-    fn respond(&self, resp: Result<ApiResponsePayload>) {
-        if let Err(e) = self.to_http.send(resp) {
+    fn respond(to_http: &Sender<ApiResponse>, resp: Result<ApiResponsePayload>) {
+        if let Err(e) = to_http.send(resp) {
             error!("send API response failed {}", e);
         }
     }
@@ -110,6 +187,14 @@ impl ApiServer {
         Ok(ApiResponsePayload::DaemonInfo(info))
     }
 
+    fn daemon_health(&self, liveness: bool) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let (health, healthy) = d
+            .export_health(liveness)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Daemon(e.into())))?;
+        Ok(ApiResponsePayload::DaemonHealth(health, healthy))
+    }
+
     fn backend_info(&self, mountpoint: &str) -> ApiResponse {
         let d = self.daemon.as_ref();
         let info = d
@@ -118,6 +203,15 @@ impl ApiServer {
         Ok(ApiResponsePayload::FsBackendInfo(info))
     }
 
+    fn dump_state(&self, name: Option<String>) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let path = d
+            .dump_state(name.as_deref())
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        let resp = serde_json::json!({ "path": path }).to_string();
+        Ok(ApiResponsePayload::DaemonDump(resp))
+    }
+
     fn configure_daemon(&self, conf: DaemonConf) -> ApiResponse {
         conf.log_level
             .parse::<log::LevelFilter>()
@@ -155,6 +249,18 @@ impl ApiServer {
             .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
     }
 
+    fn export_access_records(id: Option<String>) -> ApiResponse {
+        metrics::export_access_records(&id)
+            .map(ApiResponsePayload::AccessRecords)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
+    }
+
+    fn export_error_metrics(id: Option<String>) -> ApiResponse {
+        metrics::export_error_stats(&id)
+            .map(ApiResponsePayload::ErrorMetrics)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
+    }
+
     fn export_backend_metrics(id: Option<String>) -> ApiResponse {
         metrics::export_backend_metrics(&id)
             .map(ApiResponsePayload::BackendMetrics)
@@ -167,6 +273,26 @@ impl ApiServer {
             .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
     }
 
+    fn export_blob_progress(id: Option<String>) -> ApiResponse {
+        metrics::export_blob_progress(&id)
+            .map(ApiResponsePayload::BlobProgress)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
+    }
+
+    fn export_pull_metrics(&self) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        d.export_pull_metrics()
+            .map(ApiResponsePayload::PullMetrics)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Daemon(e.into())))
+    }
+
+    fn export_memory_metrics(&self) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        d.export_memory_metrics()
+            .map(ApiResponsePayload::MemoryMetrics)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Daemon(e.into())))
+    }
+
     /// Detect if there is fop being hang.
     /// `ApiResponsePayload::Empty` will be converted to http status code 204, which means
     /// there is no requests being processed right now.
@@ -230,6 +356,26 @@ impl ApiServer {
     fn do_mount(&self, mountpoint: String, cmd: ApiMountCmd) -> ApiResponse {
         let fs_type = FsBackendType::from_str(&cmd.fs_type)
             .map_err(|e| ApiError::MountFailure(DaemonError::from(e).into()))?;
+        if let Some(desc) = self.daemon.backend_collection().get(&mountpoint) {
+            // The bootstrap fetched by snapshot labels is identified by the labels.
+            let same_source = if cmd.source.is_empty() {
+                !cmd.labels.is_empty() && desc.labels == cmd.labels
+            } else {
+                desc.source == cmd.source
+            };
+            if cmd.idempotent && desc.backend_type == fs_type && same_source {
+                info!("{} has already been mounted at {}", desc.source, mountpoint);
+                return Ok(ApiResponsePayload::Empty);
+            }
+            let existing = serde_json::to_string(desc)
+                .map_err(|e| ApiError::MountFailure(DaemonErrorKind::Serde(e)))?;
+            return Err(ApiError::MountFailure(DaemonErrorKind::AlreadyExists(
+                existing,
+            )));
+        }
+        let source_file = mount_source_file(&mountpoint, &fs_type, &cmd)
+            .map_err(|e| ApiError::MountFailure(e.into()))?;
+
         self.daemon
             .mount(FsBackendMountCmd {
                 fs_type,
@@ -237,14 +383,31 @@ impl ApiServer {
                 config: cmd.config,
                 source: cmd.source,
                 prefetch_files: cmd.prefetch_files,
+                labels: cmd.labels,
+                source_file: source_file.map(Arc::new),
             })
             .map(|_| ApiResponsePayload::Empty)
             .map_err(|e| ApiError::MountFailure(e.into()))
     }
 
+    /// Get information about the filesystem mounted at `mountpoint`, so clients can tell whether
+    /// the mount is ready.
+    fn get_mount(&self, mountpoint: &str) -> ApiResponse {
+        let collection = self.daemon.backend_collection();
+        let desc = collection
+            .get(mountpoint)
+            .ok_or(ApiError::MountFailure(DaemonErrorKind::NotFound))?;
+
+        serde_json::to_string(desc)
+            .map(ApiResponsePayload::MountInfo)
+            .map_err(|e| ApiError::MountFailure(DaemonErrorKind::Serde(e)))
+    }
+
     fn do_remount(&self, mountpoint: String, cmd: ApiMountCmd) -> ApiResponse {
         let fs_type = FsBackendType::from_str(&cmd.fs_type)
             .map_err(|e| ApiError::MountFailure(DaemonError::from(e).into()))?;
+        let source_file = mount_source_file(&mountpoint, &fs_type, &cmd)
+            .map_err(|e| ApiError::MountFailure(e.into()))?;
         self.daemon
             .remount(FsBackendMountCmd {
                 fs_type,
@@ -252,11 +415,38 @@ impl ApiServer {
                 config: cmd.config,
                 source: cmd.source,
                 prefetch_files: cmd.prefetch_files,
+                labels: cmd.labels,
+                source_file: source_file.map(Arc::new),
             })
             .map(|_| ApiResponsePayload::Empty)
             .map_err(|e| ApiError::MountFailure(e.into()))
     }
 
+    fn switch_backend(&self, mountpoint: &str, cmd: ApiBackendCmd) -> ApiResponse {
+        let backend = BackendConfig {
+            backend_type: cmd.backend_type,
+            backend_config: cmd.config,
+        };
+        self.daemon
+            .switch_backend(mountpoint, backend)
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::MountFailure(e.into()))
+    }
+
+    fn freeze(&self, mountpoint: &str, timeout: Duration) -> ApiResponse {
+        self.daemon
+            .freeze(mountpoint, timeout)
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::MountFailure(e.into()))
+    }
+
+    fn thaw(&self, mountpoint: &str) -> ApiResponse {
+        self.daemon
+            .thaw(mountpoint)
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::MountFailure(e.into()))
+    }
+
     fn do_umount(&self, mountpoint: String) -> ApiResponse {
         self.daemon
             .umount(FsBackendUmountCmd { mountpoint })
@@ -264,6 +454,45 @@ impl ApiServer {
             .map_err(|e| ApiError::MountFailure(e.into()))
     }
 
+    /// Purge cached data of blobs which are not used by any mounted filesystem.
+    fn purge_blobcache() -> ApiResponse {
+        BLOB_FACTORY.gc();
+        info!("purge unused blob caches by http request");
+        Ok(ApiResponsePayload::Empty)
+    }
+
+    fn preheat(cmd: ApiPreheatCmd) -> ApiResponse {
+        let id = preheat::start_preheat(cmd).map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        info!("start preheat task {} by http request", id);
+        let resp = serde_json::json!({ "id": id }).to_string();
+        Ok(ApiResponsePayload::Preheat(resp))
+    }
+
+    fn preheat_status() -> ApiResponse {
+        let status =
+            preheat::export_preheat_status().map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::Preheat(status))
+    }
+
+    fn cpu_profile(&self, duration: Duration, format: ProfileFormat) -> ApiResponse {
+        if !self.debug_api {
+            return Err(ApiError::DaemonAbnormal(DaemonErrorKind::Unsupported));
+        }
+        info!("collecting CPU profile for {:?} by http request", duration);
+        profiling::cpu_profile(duration, format)
+            .map(ApiResponsePayload::Profile)
+            .map_err(|e| ApiError::DaemonAbnormal(DaemonErrorKind::Other(e.to_string())))
+    }
+
+    fn malloc_info(&self) -> ApiResponse {
+        if !self.debug_api {
+            return Err(ApiError::DaemonAbnormal(DaemonErrorKind::Unsupported));
+        }
+        profiling::malloc_info()
+            .map(ApiResponsePayload::Profile)
+            .map_err(|e| ApiError::DaemonAbnormal(DaemonErrorKind::Other(e.to_string())))
+    }
+
     fn send_fuse_fd(&self) -> ApiResponse {
         let d = self.daemon.as_ref();
 
@@ -286,23 +515,69 @@ impl ApiServer {
     }
 }
 
+/// Open the local source of the mount request, which is the bootstrap passed in the request if
+/// there's one, or the file checked by the mount access control list.
+fn mount_source_file(
+    mountpoint: &str,
+    fs_type: &FsBackendType,
+    cmd: &ApiMountCmd,
+) -> DaemonResult<Option<File>> {
+    let bootstrap = match cmd.bootstrap.as_ref() {
+        Some(bootstrap) => bootstrap,
+        None => return check_mount_acl(mountpoint, fs_type, &cmd.source),
+    };
+    if *fs_type != FsBackendType::Rafs || !cmd.source.is_empty() {
+        return Err(DaemonError::InvalidArguments(
+            "bootstrap can only be passed to rafs mounts without source".to_string(),
+        ));
+    }
+    let data = base64::decode(bootstrap)
+        .map_err(|e| DaemonError::InvalidArguments(format!("invalid bootstrap, {}", e)))?;
+    let file = <dyn RafsIoRead>::memfd_from_reader(data.as_slice(), RAFS_MAX_METADATA_SIZE as u64)?;
+
+    Ok(Some(file))
+}
+
 pub struct ApiSeverSubscriber {
     event_fd: EventFd,
-    server: ApiServer,
-    api_receiver: Receiver<ApiRequest>,
+    api_receiver: Receiver<ApiRequestMessage>,
+    to_workers: Sender<ApiRequestMessage>,
 }
 
 impl ApiSeverSubscriber {
-    pub fn new(server: ApiServer, api_receiver: Receiver<ApiRequest>) -> std::io::Result<Self> {
-        match EventFd::new(0) {
-            Ok(fd) => Ok(Self {
-                event_fd: fd,
-                server,
-                api_receiver,
-            }),
-            Err(e) => {
-                error!("Creating event fd failed. {}", e);
-                Err(e)
+    pub fn new(
+        server: ApiServer,
+        api_receiver: Receiver<ApiRequestMessage>,
+    ) -> std::io::Result<Self> {
+        let event_fd = EventFd::new(0).map_err(|e| {
+            error!("Creating event fd failed. {}", e);
+            e
+        })?;
+
+        let server = Arc::new(server);
+        let (to_workers, from_subscriber) = channel::<ApiRequestMessage>();
+        let from_subscriber = Arc::new(Mutex::new(from_subscriber));
+        for num in 0..API_WORKER_THREADS {
+            let server = server.clone();
+            let receiver = from_subscriber.clone();
+            thread::Builder::new()
+                .name(format!("api-worker-{}", num))
+                .spawn(move || server.run(receiver))?;
+        }
+
+        Ok(Self {
+            event_fd,
+            api_receiver,
+            to_workers,
+        })
+    }
+
+    /// Dispatch all pending API requests to the working threads, so a slow request won't block
+    /// the event loop and other requests.
+    fn dispatch_requests(&self) {
+        while let Ok(msg) = self.api_receiver.try_recv() {
+            if let Err(e) = self.to_workers.send(msg) {
+                error!("API server dispatch request failed, {}", e);
             }
         }
     }
@@ -320,11 +595,7 @@ impl EventSubscriber for ApiSeverSubscriber {
             .map_err(|e| last_error!(e))
             .unwrap_or_else(|_| {});
         match events.event_set() {
-            EventSet::IN => {
-                self.server
-                    .process_request(&self.api_receiver)
-                    .unwrap_or_else(|e| error!("API server process events failed, {}", e));
-            }
+            EventSet::IN => self.dispatch_requests(),
             EventSet::ERROR => {
                 error!("Got error on the monitored event.");
             }
@@ -342,3 +613,37 @@ impl EventSubscriber for ApiSeverSubscriber {
             .expect("Cannot register event")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_mount_source_file_from_bootstrap() {
+        let data = std::fs::read("./tests/texture/bootstrap/image_v2.boot").unwrap();
+        let mut cmd = ApiMountCmd {
+            source: String::new(),
+            fs_type: "rafs".to_string(),
+            config: String::new(),
+            prefetch_files: None,
+            idempotent: false,
+            labels: Default::default(),
+            bootstrap: Some(base64::encode(&data)),
+        };
+
+        let mut file = mount_source_file("/sub", &FsBackendType::Rafs, &cmd)
+            .unwrap()
+            .unwrap();
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        assert!(mount_source_file("/sub", &FsBackendType::PassthroughFs, &cmd).is_err());
+        cmd.source = "/path/to/bootstrap".to_string();
+        assert!(mount_source_file("/sub", &FsBackendType::Rafs, &cmd).is_err());
+        cmd.source = String::new();
+        cmd.bootstrap = Some("not base64!".to_string());
+        assert!(mount_source_file("/sub", &FsBackendType::Rafs, &cmd).is_err());
+    }
+}
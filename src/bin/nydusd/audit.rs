@@ -0,0 +1,188 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Audit log of administrative operations requested by the API server.
+//!
+//! Each operation changing state of the daemon, such as mount, umount, configuration change,
+//! cache purge and upgrade, is appended to the audit log file as a line of JSON, with the caller,
+//! parameters and outcome of the operation. The caller is identified by credentials of the peer
+//! process of the API socket, which are reported by the kernel instead of claimed by the client. Mount configurations may carry credentials of storage
+//! backends, so only their digests are recorded.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+
+use nydus_api::http::PeerCredentials;
+use nydus_api::http_endpoint::{ApiMountCmd, ApiRequest, ApiResponse};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+/// An operation to be audited, described before handling the request.
+pub struct AuditEntry {
+    operation: &'static str,
+    params: Value,
+}
+
+impl AuditEntry {
+    /// Describe `request`, return `None` if it doesn't change state of the daemon.
+    pub fn new(request: &ApiRequest) -> Option<Self> {
+        let (operation, params) = match request {
+            ApiRequest::ConfigureDaemon(conf) => {
+                ("configure", json!({ "log_level": conf.log_level }))
+            }
+            ApiRequest::Mount(mountpoint, cmd) => ("mount", Self::mount_params(mountpoint, cmd)),
+            ApiRequest::Remount(mountpoint, cmd) => {
+                ("remount", Self::mount_params(mountpoint, cmd))
+            }
+            ApiRequest::SwitchBackend(mountpoint, cmd) => (
+                "switch_backend",
+                json!({
+                    "mountpoint": mountpoint,
+                    "type": cmd.backend_type,
+                    "config_sha256": format!(
+                        "{:x}",
+                        Sha256::digest(cmd.config.to_string().as_bytes())
+                    ),
+                }),
+            ),
+            ApiRequest::FreezeMount(mountpoint, timeout) => (
+                "freeze",
+                json!({ "mountpoint": mountpoint, "timeout": timeout.as_secs() }),
+            ),
+            ApiRequest::ThawMount(mountpoint) => ("thaw", json!({ "mountpoint": mountpoint })),
+            ApiRequest::Umount(mountpoint) => ("umount", json!({ "mountpoint": mountpoint })),
+            ApiRequest::DumpState(name) => ("dump_state", json!({ "name": name })),
+            ApiRequest::PurgeBlobcache => ("purge_blobcache", json!({})),
+            ApiRequest::Preheat(cmd) => (
+                "preheat",
+                json!({
+                    "config_sha256": format!("{:x}", Sha256::digest(cmd.config.as_bytes())),
+                    "images": cmd.images.iter().map(|i| &i.reference).collect::<Vec<_>>(),
+                    "blobs": cmd.blobs.iter().map(|b| &b.blob_id).collect::<Vec<_>>(),
+                }),
+            ),
+            ApiRequest::SendFuseFd => ("send_fuse_fd", json!({})),
+            ApiRequest::Takeover => ("takeover", json!({})),
+            ApiRequest::Exit => ("exit", json!({})),
+            _ => return None,
+        };
+
+        Some(AuditEntry { operation, params })
+    }
+
+    fn mount_params(mountpoint: &str, cmd: &ApiMountCmd) -> Value {
+        json!({
+            "mountpoint": mountpoint,
+            "source": cmd.source,
+            "fs_type": cmd.fs_type,
+            "config_sha256": format!("{:x}", Sha256::digest(cmd.config.as_bytes())),
+            "prefetch_files": cmd.prefetch_files,
+            "labels": cmd.labels,
+            "bootstrap_sha256": cmd
+                .bootstrap
+                .as_ref()
+                .map(|b| format!("{:x}", Sha256::digest(b.as_bytes()))),
+        })
+    }
+}
+
+/// Append-only audit log file.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open the audit log file at `path` for appending, creating it if it doesn't exist.
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record the operation of `entry` requested by `caller` and its outcome `resp`.
+    pub fn record(&self, entry: AuditEntry, caller: PeerCredentials, resp: &ApiResponse) {
+        let (outcome, error) = match resp {
+            Ok(_) => ("success", None),
+            Err(e) => ("failure", Some(format!("{:?}", e))),
+        };
+        let record = json!({
+            "time": chrono::Local::now().to_rfc3339(),
+            "caller": {
+                "pid": caller.pid,
+                "uid": caller.uid,
+                "gid": caller.gid,
+            },
+            "operation": entry.operation,
+            "params": entry.params,
+            "outcome": outcome,
+            "error": error,
+        });
+
+        let mut line = record.to_string();
+        line.push('\n');
+        // Write the whole record by one call, so records are never interleaved.
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("failed to write audit record of {}, {}", entry.operation, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nydus_api::http_endpoint::{ApiError, ApiResponsePayload};
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_audit_log() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("audit.log");
+        let path = path.to_str().unwrap();
+        let log = AuditLog::open(path).unwrap();
+
+        assert!(AuditEntry::new(&ApiRequest::DaemonInfo).is_none());
+        let cmd = ApiMountCmd {
+            source: "/bootstrap".to_string(),
+            fs_type: "rafs".to_string(),
+            config: "{\"secret\": \"password\"}".to_string(),
+            prefetch_files: None,
+            idempotent: false,
+            labels: Default::default(),
+            bootstrap: None,
+        };
+        let entry = AuditEntry::new(&ApiRequest::Mount("/sub".to_string(), cmd)).unwrap();
+        let caller = PeerCredentials {
+            pid: 100,
+            uid: 1000,
+            gid: 1001,
+        };
+        log.record(entry, caller, &Ok(ApiResponsePayload::Empty));
+        let entry = AuditEntry::new(&ApiRequest::Umount("/sub".to_string())).unwrap();
+        log.record(entry, caller, &Err(ApiError::ResponsePayloadType));
+
+        let content = std::fs::read_to_string(path).unwrap();
+        assert!(!content.contains("password"));
+        let records: Vec<Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["caller"]["pid"], 100);
+        assert_eq!(records[0]["caller"]["uid"], 1000);
+        assert_eq!(records[0]["caller"]["gid"], 1001);
+        assert_eq!(records[0]["operation"], "mount");
+        assert_eq!(records[0]["params"]["source"], "/bootstrap");
+        assert_eq!(records[0]["outcome"], "success");
+        assert_eq!(records[1]["operation"], "umount");
+        assert_eq!(records[1]["outcome"], "failure");
+    }
+}
@@ -5,40 +5,59 @@
 // SPDX-License-Identifier: (Apache-2.0 AND BSD-3-Clause)
 
 use std::any::Any;
+use std::cell::RefCell;
 use std::cmp::PartialEq;
 use std::collections::HashMap;
 use std::convert::From;
+use std::ffi::{CStr, CString};
 use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::Result;
 use std::ops::Deref;
-use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
 use std::process::id;
 use std::str::FromStr;
 use std::sync::{
-    atomic::Ordering,
+    atomic::{AtomicU64, Ordering},
     mpsc::{Receiver, Sender},
-    Arc, MutexGuard,
+    Arc, MutexGuard, RwLock,
 };
 use std::thread;
-use std::{error, fmt, io};
+use std::time::Duration;
 
 use event_manager::{EventOps, EventSubscriber, Events};
-use fuse_backend_rs::api::{vfs::VfsError, BackendFileSystem, Vfs};
+use fuse_backend_rs::abi::linux_abi::{InHeader, InterruptIn, Opcode, OutHeader, ROOT_ID};
+use fuse_backend_rs::api::filesystem::{Context, FileSystem};
+#[cfg(feature = "virtiofs")]
+use fuse_backend_rs::api::server::MetricsHook;
+use fuse_backend_rs::api::{vfs::VfsError, BackendFileSystem, Vfs, VFS_MAX_INO};
 use fuse_backend_rs::passthrough::{Config, PassthroughFs};
-use fuse_backend_rs::transport::Error as FuseTransportError;
+use fuse_backend_rs::transport::{Error as FuseTransportError, Reader};
 use fuse_backend_rs::Error as FuseError;
 use rust_fsm::*;
 use serde::{self, Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
-use nydus::{FsBackendDesc, FsBackendType};
+use nydus::{FsBackendDesc, FsBackendType, LABEL_IMAGE_REF};
 use nydus_app::BuildTimeInfo;
+use nydus_utils::metrics::{self, process_rss, MemoryMetrics, PullMetrics};
+use nydus_utils::tracing::{self, SpanGuard};
 use rafs::{
     fs::{Rafs, RafsConfig},
     trim_backend_config, RafsError, RafsIoRead,
 };
+use storage::backend::request;
+use storage::device::BlobInfo;
+use storage::factory::BackendConfig;
 
+use crate::policy::check_trust_policy;
+use crate::snapshot;
+use crate::stargz;
 use crate::upgrade::{self, UpgradeManager, UpgradeMgrError};
 use crate::EVENT_MANAGER_RUN;
 
@@ -76,74 +95,107 @@ impl From<i32> for DaemonState {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum DaemonError {
     /// Invalid arguments provided.
+    #[error("Invalid argument: {0}")]
     InvalidArguments(String),
     /// Invalid config provided
+    #[error("Invalid config: {0}")]
     InvalidConfig(String),
     /// Failed to handle event other than input event.
+    #[error("Failed to handle event other than input event")]
     HandleEventNotEpollIn,
     /// Failed to handle unknown event.
+    #[error("Failed to handle unknown event")]
     HandleEventUnknownEvent,
     /// No memory configured.
+    #[error("No memory configured")]
     NoMemoryConfigured,
     /// Fail to walk descriptor chain
+    #[error("Failed to walk descriptor chain")]
     IterateQueue,
     /// Invalid Virtio descriptor chain.
+    #[error("Invalid descriptor chain: {0}")]
     InvalidDescriptorChain(FuseTransportError),
     /// Processing queue failed.
+    #[error("Failed to process queue: {0}")]
     ProcessQueue(FuseError),
     /// Cannot create epoll context.
+    #[error("Failed to create epoll context: {0}")]
     Epoll(io::Error),
     /// Cannot clone event fd.
+    #[error("Failed to clone event fd: {0}")]
     EventFdClone(io::Error),
     /// Cannot spawn a new thread
+    #[error("Failed to spawn thread: {0}")]
     ThreadSpawn(io::Error),
     /// Failure against Passthrough FS.
+    #[error("Passthrough fs error: {0}")]
     PassthroughFs(io::Error),
     /// Daemon related error
+    #[error("Daemon error: {0}")]
     DaemonFailure(String),
 
+    #[error("{0}")]
     Common(String),
+    #[error("Not found")]
     NotFound,
+    #[error("Already exists")]
     AlreadyExists,
+    #[error("Serde error: {0}")]
     Serde(SerdeError),
+    #[error("Upgrade manager error: {0:?}")]
     UpgradeManager(UpgradeMgrError),
+    #[error("Vfs error: {}", vfs_error_message(.0))]
     Vfs(VfsError),
+    #[error("Rafs error: {0}")]
     Rafs(RafsError),
     /// Daemon does not reach the stable working state yet,
     /// some capabilities may not be provided.
+    #[error("Daemon is not ready yet")]
     NotReady,
     /// Daemon can't fulfill external requests.
+    #[error("Unsupported operation")]
     Unsupported,
     /// State-machine related error codes if something bad happens when to communicate with state-machine
+    #[error("State machine channel error: {0}")]
     Channel(String),
     /// Input event to stat-machine is not expected.
+    #[error("Unexpected state machine event: {0:?}")]
     UnexpectedEvent(DaemonStateMachineInput),
     /// File system backend service related errors.
+    #[error("Failed to start service: {0}")]
     StartService(String),
+    #[error("Service is stopped")]
     ServiceStop,
     /// Wait daemon failure
+    #[error("Failed to wait daemon: {0}")]
     WaitDaemon(io::Error),
+    #[error("Session is shut down: {0}")]
     SessionShutdown(FuseTransportError),
+    #[error("Failed to downcast: {0}")]
     Downcast(String),
+    #[error("Filesystem type mismatch: {0}")]
     FsTypeMismatch(String),
+    /// The image to mount violates the content trust policy.
+    #[error("Policy violation: {0}")]
+    PolicyViolation(String),
 }
 
-impl fmt::Display for DaemonError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::InvalidArguments(s) => write!(f, "Invalid argument: {}", s),
-            Self::InvalidConfig(s) => write!(f, "Invalid config: {}", s),
-            Self::DaemonFailure(s) => write!(f, "Daemon error: {}", s),
-            _ => write!(f, "{:?}", self),
-        }
+// VfsError doesn't implement Display, so print errors it carries with their Display.
+fn vfs_error_message(e: &VfsError) -> String {
+    match e {
+        VfsError::Unsupported => "unsupported operation".to_string(),
+        VfsError::Mount(e) => format!("failed to mount backend filesystem, {}", e),
+        VfsError::InodeIndex(s) => format!("invalid inode index, {}", s),
+        VfsError::FsIndex(e) => format!("failed to allocate filesystem index, {}", e),
+        VfsError::PathWalk(e) => format!("failed to walk path, {}", e),
+        VfsError::NotFound(s) => format!("{} not found", s),
+        VfsError::Initialize(s) => format!("failed to initialize filesystem, {}", s),
     }
 }
 
-impl error::Error for DaemonError {}
-
 impl From<DaemonError> for io::Error {
     fn from(e: DaemonError) -> Self {
         einval!(e)
@@ -174,6 +226,212 @@ pub struct DaemonInfo {
     pub backend_collection: FsBackendCollection,
 }
 
+/// Result of a health check of the daemon.
+#[derive(Serialize)]
+pub struct HealthCheck {
+    pub name: String,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl HealthCheck {
+    fn new(name: String, result: Result<()>) -> Self {
+        HealthCheck {
+            name,
+            healthy: result.is_ok(),
+            message: result.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Results of health checks of the daemon, for liveness or readiness probes.
+#[derive(Serialize)]
+pub struct DaemonHealth {
+    pub healthy: bool,
+    pub state: DaemonState,
+    pub checks: Vec<HealthCheck>,
+}
+
+/// Lazy pull metrics of an image mounted by the daemon.
+#[derive(Serialize)]
+pub struct ImagePullMetrics {
+    pub mountpoint: String,
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_ref: Option<String>,
+    #[serde(flatten)]
+    pub metrics: PullMetrics,
+}
+
+/// Memory used by a filesystem mounted by the daemon.
+#[derive(Serialize)]
+pub struct MountMemoryMetrics {
+    pub mountpoint: String,
+    #[serde(flatten)]
+    pub metrics: MemoryMetrics,
+}
+
+/// Memory used by the daemon and its mounted filesystems.
+#[derive(Serialize)]
+pub struct DaemonMemoryMetrics {
+    /// Resident set size of the daemon process.
+    pub rss: u64,
+    /// Cap of the resident set size triggering reclaiming of memory, 0 if there's no cap.
+    pub limit: u64,
+    /// Free chunk buffers cached for reuse by all mounts.
+    pub buffer_pool: u64,
+    pub mounts: Vec<MountMemoryMetrics>,
+}
+
+/// State of a filesystem mounted by the daemon.
+#[derive(Serialize)]
+pub struct MountState {
+    #[serde(flatten)]
+    pub desc: FsBackendDesc,
+    /// Metadata of the rafs image, such as number of inodes and chunks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Memory used by metadata of the rafs image, including cached inodes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_usage: Option<u64>,
+}
+
+/// Snapshot of the daemon state, for offline debugging.
+#[derive(Serialize)]
+pub struct DaemonStateDump {
+    pub timestamp: String,
+    pub pid: u32,
+    pub version: BuildTimeInfo,
+    pub id: Option<String>,
+    pub supervisor: Option<String>,
+    pub state: DaemonState,
+    /// Default configuration of rafs mounts, without credentials.
+    pub default_config: Option<serde_json::Value>,
+    pub mounts: Vec<MountState>,
+    /// Requests being handled, `None` if there's none or the daemon doesn't track them.
+    pub inflight: Option<serde_json::Value>,
+    pub memory: serde_json::Value,
+    /// Metrics of filesystems, storage backends and blob caches, and recent events.
+    pub metrics: serde_json::Value,
+}
+
+/// Check whether files can be created in the directory `dir`, by creating and removing one.
+fn check_writable(dir: &Path) -> Result<()> {
+    // Each check creates its own file, so concurrent checks don't remove files of each other.
+    static PROBE_SEQ: AtomicU64 = AtomicU64::new(0);
+    let seq = PROBE_SEQ.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!(".nydusd-health-{}-{}", id(), seq));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)
+}
+
+lazy_static! {
+    /// Default configuration of Rafs mounts, from the configuration file given at startup.
+    static ref DEFAULT_FS_CONFIG: RwLock<Option<serde_json::Value>> = RwLock::new(None);
+    /// Mounted filesystems by their index in the VFS, to find the mount of a FUSE request.
+    static ref MOUNTED_FS: RwLock<HashMap<u8, Arc<BackFileSystem>>> = RwLock::new(HashMap::new());
+    /// Directory to write snapshots of the daemon state to.
+    static ref DUMP_DIR: RwLock<PathBuf> = RwLock::new(std::env::temp_dir());
+}
+
+/// Set the default configuration of Rafs mounts.
+///
+/// The configuration of each mount is merged over the default configuration, so mounts may
+/// override digest validation, cache and backend settings individually, or omit the configuration
+/// to use the default one.
+pub fn set_default_fs_config(config: &str) -> DaemonResult<()> {
+    let config = serde_json::from_str(config).map_err(DaemonError::Serde)?;
+    *DEFAULT_FS_CONFIG.write().unwrap() = Some(config);
+    Ok(())
+}
+
+/// Set the directory to write snapshots of the daemon state to, the temporary directory by default.
+pub fn set_dump_dir(dir: &str) -> DaemonResult<()> {
+    if !Path::new(dir).is_dir() {
+        return Err(DaemonError::InvalidArguments(format!(
+            "dump directory {} is not a directory",
+            dir
+        )));
+    }
+    *DUMP_DIR.write().unwrap() = PathBuf::from(dir);
+    Ok(())
+}
+
+// Merge `overlay` into `base`, objects are merged recursively, null values remove the entries
+// and other values are replaced.
+fn merge_config(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (k, v) in overlay {
+                if v.is_null() {
+                    base.remove(&k);
+                    continue;
+                }
+                match base.get_mut(&k) {
+                    Some(b) => merge_config(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// Check whether the storage backend configured by `overlay` is a different service from `base`,
+// whose settings such as credentials must not be inherited.
+fn is_other_backend(base: &serde_json::Value, overlay: &serde_json::Value) -> bool {
+    let differs = |v: &serde_json::Value, b: &serde_json::Value| !v.is_null() && v != b;
+    differs(&overlay["type"], &base["type"])
+        || differs(&overlay["config"]["host"], &base["config"]["host"])
+        || differs(&overlay["config"]["endpoint"], &base["config"]["endpoint"])
+}
+
+// Merge the mount configuration `overlay` into the default configuration `base`.
+//
+// The storage backend of `base` is replaced wholesale if `overlay` configures another backend
+// service, so credentials of the default backend are never sent to other services.
+fn merge_fs_config(base: &mut serde_json::Value, mut overlay: serde_json::Value) {
+    if let Some(backend) = overlay
+        .get_mut("device")
+        .and_then(|d| d.get_mut("backend"))
+        .map(|b| b.take())
+    {
+        if is_other_backend(&base["device"]["backend"], &backend) {
+            if let Some(device) = base.get_mut("device").and_then(|d| d.as_object_mut()) {
+                device.remove("backend");
+            }
+        }
+        overlay["device"]["backend"] = backend;
+    }
+    merge_config(base, overlay);
+}
+
+/// Get the effective configuration of a mount with configuration `config`.
+pub fn effective_fs_config(config: &str) -> DaemonResult<String> {
+    let default = DEFAULT_FS_CONFIG.read().unwrap().clone();
+    let mut effective = match default {
+        Some(default) => default,
+        None => return Ok(config.to_string()),
+    };
+    if !config.trim().is_empty() {
+        let overlay = serde_json::from_str(config).map_err(DaemonError::Serde)?;
+        merge_fs_config(&mut effective, overlay);
+    }
+
+    serde_json::to_string(&effective).map_err(DaemonError::Serde)
+}
+
+/// Cap of the resident set size of the daemon set by `start_memory_monitor()`.
+static MEMORY_LIMIT: AtomicU64 = AtomicU64::new(0);
+/// Interval to check the resident set size of the daemon against the cap.
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Clone)]
 pub struct FsBackendMountCmd {
     pub fs_type: FsBackendType,
@@ -181,6 +439,21 @@ pub struct FsBackendMountCmd {
     pub config: String,
     pub mountpoint: String,
     pub prefetch_files: Option<Vec<String>>,
+    /// Labels attached to the mount, such as containerd snapshot labels.
+    pub labels: HashMap<String, String>,
+    /// Local source opened when checked by the mount access control list, mounted instead of
+    /// opening `source` again.
+    pub source_file: Option<Arc<File>>,
+}
+
+impl FsBackendMountCmd {
+    /// Get the path to open the local source by.
+    fn source_path(&self) -> String {
+        match self.source_file.as_ref() {
+            Some(file) => format!("/proc/self/fd/{}", file.as_raw_fd()),
+            None => self.source.clone(),
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -192,10 +465,10 @@ pub struct FsBackendUmountCmd {
 pub struct FsBackendCollection(HashMap<String, FsBackendDesc>);
 
 impl FsBackendCollection {
-    fn add(&mut self, id: &str, cmd: &FsBackendMountCmd) -> DaemonResult<()> {
+    pub fn add(&mut self, id: &str, cmd: &FsBackendMountCmd) -> DaemonResult<()> {
         // We only wash Rafs backend now.
         let fs_config = match cmd.fs_type {
-            FsBackendType::Rafs => {
+            FsBackendType::Rafs | FsBackendType::Stargz => {
                 let mut config: serde_json::Value =
                     serde_json::from_str(&cmd.config).map_err(DaemonError::Serde)?;
                 trim_backend_config!(
@@ -216,8 +489,10 @@ impl FsBackendCollection {
         let desc = FsBackendDesc {
             backend_type: cmd.fs_type.clone(),
             mountpoint: cmd.mountpoint.clone(),
+            source: cmd.source.clone(),
             mounted_time: chrono::Local::now(),
             config: fs_config,
+            labels: cmd.labels.clone(),
         };
 
         self.0.insert(id.to_string(), desc);
@@ -225,9 +500,39 @@ impl FsBackendCollection {
         Ok(())
     }
 
-    fn del(&mut self, id: &str) {
+    // Record the storage backend a mount has been switched to, with credentials removed.
+    fn set_backend(&mut self, id: &str, backend: &BackendConfig) -> DaemonResult<()> {
+        if let Some(config) = self.0.get_mut(id).and_then(|d| d.config.as_mut()) {
+            config["device"]["backend"] =
+                serde_json::to_value(backend).map_err(DaemonError::Serde)?;
+            trim_backend_config!(
+                config,
+                "access_key_id",
+                "access_key_secret",
+                "auth",
+                "token"
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn del(&mut self, id: &str) {
         self.0.remove(id);
     }
+
+    pub fn get(&self, id: &str) -> Option<&FsBackendDesc> {
+        self.0.get(id)
+    }
+
+    /// Get the number of mounts of each filesystem type.
+    pub fn count_by_type(&self) -> HashMap<String, u64> {
+        let mut counts = HashMap::new();
+        for desc in self.0.values() {
+            *counts.entry(desc.backend_type.to_string()).or_insert(0) += 1;
+        }
+        counts
+    }
 }
 
 pub trait NydusDaemon: DaemonStateMachineSubscriber {
@@ -272,6 +577,95 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
 
         serde_json::to_string(&response).map_err(DaemonError::Serde)
     }
+
+    /// Check whether the session with the kernel or the virtual machine is still alive.
+    fn check_session(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Ask the kernel to drop cached attributes and page cache of inode `ino` of the `Vfs`.
+    ///
+    /// It's a no-op unless the transport supports FUSE notifications, e.g. virtio-fs guests don't.
+    fn notify_inval_inode(&self, _ino: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Ask the kernel to drop the cached dentry `name` under directory `parent`, together with
+    /// all dentries below it.
+    ///
+    /// It's a no-op unless the transport supports FUSE notifications, e.g. virtio-fs guests don't.
+    fn notify_inval_entry(&self, _parent: u64, _name: &CStr) -> Result<()> {
+        Ok(())
+    }
+
+    /// Run health checks, and export results and whether all of the checks have passed.
+    ///
+    /// Liveness probes only check the session, while readiness probes also check the daemon has
+    /// been running, storage backends are reachable, and cache directories are writable.
+    fn export_health(&self, liveness: bool) -> DaemonResult<(String, bool)> {
+        let state = self.get_state();
+        let mut checks = vec![HealthCheck::new(
+            "session".to_string(),
+            self.check_session(),
+        )];
+        if !liveness {
+            let running = if state == DaemonState::RUNNING {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("daemon is {}", state),
+                ))
+            };
+            checks.push(HealthCheck::new("state".to_string(), running));
+
+            let mounts: Vec<FsBackendDesc> =
+                self.backend_collection().0.values().cloned().collect();
+            for desc in mounts {
+                checks.append(&mut self.check_mount(&desc)?);
+            }
+        }
+
+        let healthy = checks.iter().all(|c| c.healthy);
+        let health = DaemonHealth {
+            healthy,
+            state,
+            checks,
+        };
+        let resp = serde_json::to_string(&health).map_err(DaemonError::Serde)?;
+
+        Ok((resp, healthy))
+    }
+
+    /// Check the storage backend and cache directory of the rafs image mounted as `desc`.
+    fn check_mount(&self, desc: &FsBackendDesc) -> DaemonResult<Vec<HealthCheck>> {
+        let mut checks = Vec::new();
+        if let Some(fs) = self.backend_from_mountpoint(&desc.mountpoint)? {
+            if let Some(rafs) = fs.deref().as_any().downcast_ref::<Rafs>() {
+                checks.push(HealthCheck::new(
+                    format!("backend:{}", desc.mountpoint),
+                    rafs.check_backend(),
+                ));
+                checks.push(HealthCheck::new(
+                    format!("blobs:{}", desc.mountpoint),
+                    rafs.check_failed_blobs(),
+                ));
+            }
+        }
+        if let Some(work_dir) = desc
+            .config
+            .as_ref()
+            .and_then(|c| c["device"]["cache"]["config"]["work_dir"].as_str())
+        {
+            checks.push(HealthCheck::new(
+                format!("cache:{}", desc.mountpoint),
+                check_writable(Path::new(work_dir)),
+            ));
+        }
+
+        Ok(checks)
+    }
+
     fn export_backend_info(&self, mountpoint: &str) -> DaemonResult<String> {
         let fs = self
             .backend_from_mountpoint(mountpoint)?
@@ -284,6 +678,219 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
         Ok(resp)
     }
 
+    /// Export per image metrics about how much data has actually been downloaded compared to
+    /// the size of the whole image, and blob cache hit rates.
+    fn export_pull_metrics(&self) -> DaemonResult<String> {
+        let mounts: Vec<FsBackendDesc> = self.backend_collection().0.values().cloned().collect();
+        let mut images = Vec::new();
+        for desc in mounts {
+            if let Some((cache_id, blobs)) = self.image_blobs(&desc)? {
+                let blobs: Vec<(String, u64)> = blobs
+                    .iter()
+                    .map(|b| (b.blob_id().to_string(), b.compressed_size()))
+                    .collect();
+                images.push(ImagePullMetrics {
+                    image_ref: desc.labels.get(LABEL_IMAGE_REF).cloned(),
+                    metrics: PullMetrics::collect(&cache_id, &blobs),
+                    mountpoint: desc.mountpoint,
+                    source: desc.source,
+                });
+            }
+        }
+        images.sort_by(|a, b| a.mountpoint.cmp(&b.mountpoint));
+
+        serde_json::to_string(&images).map_err(DaemonError::Serde)
+    }
+
+    /// Get the blob cache id and data blobs of the rafs image mounted as `desc`, or `None` if
+    /// it's not a rafs image.
+    fn image_blobs(
+        &self,
+        desc: &FsBackendDesc,
+    ) -> DaemonResult<Option<(String, Vec<Arc<BlobInfo>>)>> {
+        let fs = match self.backend_from_mountpoint(&desc.mountpoint)? {
+            Some(fs) => fs,
+            None => return Ok(None),
+        };
+        let cache_id = desc
+            .config
+            .as_ref()
+            .and_then(|c| c["device"]["id"].as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        Ok(fs
+            .deref()
+            .as_any()
+            .downcast_ref::<Rafs>()
+            .map(|rafs| (cache_id, rafs.blob_infos())))
+    }
+
+    /// Export memory used by the daemon, and by metadata, blob cache state and in-flight buffers
+    /// of each mount.
+    fn export_memory_metrics(&self) -> DaemonResult<String> {
+        let mounts: Vec<FsBackendDesc> = self.backend_collection().0.values().cloned().collect();
+        let mut metrics = DaemonMemoryMetrics {
+            rss: process_rss().map_err(|e| {
+                DaemonError::Common(format!("failed to get resident set size, {}", e))
+            })?,
+            limit: MEMORY_LIMIT.load(Ordering::Relaxed),
+            buffer_pool: storage::cache::buffer_pool_size(),
+            mounts: Vec::new(),
+        };
+        for desc in mounts {
+            if let Some(m) = self.image_memory(&desc)? {
+                metrics.mounts.push(MountMemoryMetrics {
+                    metrics: m,
+                    mountpoint: desc.mountpoint,
+                });
+            }
+        }
+        metrics
+            .mounts
+            .sort_by(|a, b| a.mountpoint.cmp(&b.mountpoint));
+
+        serde_json::to_string(&metrics).map_err(DaemonError::Serde)
+    }
+
+    /// Get memory used by the rafs image mounted as `desc`, or `None` if it's not a rafs image.
+    fn image_memory(&self, desc: &FsBackendDesc) -> DaemonResult<Option<MemoryMetrics>> {
+        let fs = match self.backend_from_mountpoint(&desc.mountpoint)? {
+            Some(fs) => fs,
+            None => return Ok(None),
+        };
+        let cache_id = desc
+            .config
+            .as_ref()
+            .and_then(|c| c["device"]["id"].as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        Ok(fs.deref().as_any().downcast_ref::<Rafs>().map(|rafs| {
+            MemoryMetrics::collect(&cache_id, rafs.memory_usage(), rafs.cache_memory_usage())
+        }))
+    }
+
+    /// Write a snapshot of the daemon state to the file `name` in the dump directory, or to a file
+    /// named by the process id and time if `name` is `None`, and return path of the file.
+    fn dump_state(&self, name: Option<&str>) -> DaemonResult<String> {
+        let timestamp = chrono::Local::now();
+        let name = match name {
+            // API clients may only choose the file name, not where the snapshot is written.
+            Some(n) if n.is_empty() || n.contains('/') || n.contains("..") => {
+                return Err(DaemonError::InvalidArguments(format!(
+                    "invalid dump file name {}",
+                    n
+                )))
+            }
+            Some(n) => n.to_string(),
+            None => format!("nydusd-{}-{}.json", id(), timestamp.format("%Y%m%d%H%M%S")),
+        };
+        let path = DUMP_DIR.read().unwrap().join(name);
+
+        let descs: Vec<FsBackendDesc> = self.backend_collection().0.values().cloned().collect();
+        let mut mounts = Vec::new();
+        for desc in descs {
+            let fs = self.backend_from_mountpoint(&desc.mountpoint)?;
+            let (metadata, memory_usage) = match fs
+                .as_ref()
+                .and_then(|fs| fs.deref().as_any().downcast_ref::<Rafs>())
+            {
+                Some(rafs) => (
+                    Some(serde_json::to_value(rafs.metadata()).map_err(DaemonError::Serde)?),
+                    Some(rafs.memory_usage()),
+                ),
+                None => (None, None),
+            };
+            mounts.push(MountState {
+                desc,
+                metadata,
+                memory_usage,
+            });
+        }
+        mounts.sort_by(|a, b| a.desc.mountpoint.cmp(&b.desc.mountpoint));
+
+        let default_config = DEFAULT_FS_CONFIG.read().unwrap().clone().map(|mut config| {
+            trim_backend_config!(
+                config,
+                "access_key_id",
+                "access_key_secret",
+                "auth",
+                "token"
+            );
+            config
+        });
+        let inflight = match self.export_inflight_ops() {
+            Ok(Some(ops)) => Some(serde_json::from_str(&ops).map_err(DaemonError::Serde)?),
+            Ok(None) | Err(DaemonError::Unsupported) => None,
+            Err(e) => return Err(e),
+        };
+        let memory =
+            serde_json::from_str(&self.export_memory_metrics()?).map_err(DaemonError::Serde)?;
+        let metrics = metrics::export_all_metrics()
+            .map_err(|e| DaemonError::Common(format!("failed to export metrics, {:?}", e)))?;
+
+        let dump = DaemonStateDump {
+            timestamp: timestamp.to_rfc3339(),
+            pid: id(),
+            version: self.version(),
+            id: self.id(),
+            supervisor: self.supervisor(),
+            state: self.get_state(),
+            default_config,
+            mounts,
+            inflight,
+            memory,
+            metrics,
+        };
+
+        // The snapshot may reveal file names of images, so only the owner may read it.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|e| {
+                DaemonError::Common(format!("failed to create {}, {}", path.display(), e))
+            })?;
+        serde_json::to_writer_pretty(file, &dump).map_err(DaemonError::Serde)?;
+        info!("dumped daemon state to {}", path.display());
+
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// Release memory used by metadata and blob cache state of all mounted rafs images, and free
+    /// chunk buffers cached for reuse. Released memory is reloaded or reallocated on demand.
+    fn shrink_memory(&self) -> DaemonResult<()> {
+        let mountpoints: Vec<String> = self.backend_collection().0.keys().cloned().collect();
+        for mp in mountpoints {
+            if let Some(fs) = self.backend_from_mountpoint(&mp)? {
+                if let Some(rafs) = fs.deref().as_any().downcast_ref::<Rafs>() {
+                    rafs.shrink();
+                }
+            }
+        }
+        storage::cache::trim_buffer_pool();
+
+        Ok(())
+    }
+
+    /// Flush blob caches of all mounted rafs images, so the cache state survives restarts.
+    fn flush_caches(&self) -> DaemonResult<()> {
+        let mountpoints: Vec<String> = self.backend_collection().0.keys().cloned().collect();
+        for mp in mountpoints {
+            if let Some(fs) = self.backend_from_mountpoint(&mp)? {
+                if let Some(rafs) = fs.deref().as_any().downcast_ref::<Rafs>() {
+                    rafs.flush().unwrap_or_else(|e| {
+                        error!("failed to flush blob caches of mount {}, {}", mp, e)
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn backend_from_mountpoint(&self, mp: &str) -> DaemonResult<Option<Arc<BackFileSystem>>> {
         let r = self.get_vfs().get_rootfs(mp)?;
         Ok(r)
@@ -292,12 +899,22 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
 
     // NOTE: This method is not thread-safe, however, it is acceptable as
     // mount/umount/remount/restore_mount is invoked from single thread in FSM
-    fn mount(&self, cmd: FsBackendMountCmd) -> DaemonResult<()> {
+    fn mount(&self, mut cmd: FsBackendMountCmd) -> DaemonResult<()> {
+        let span = tracing::span("mount");
+        span.set_attribute_str("mountpoint", &cmd.mountpoint);
+        span.set_attribute_str("source", &cmd.source);
         if self.backend_from_mountpoint(&cmd.mountpoint)?.is_some() {
             return Err(DaemonError::AlreadyExists);
         }
+        if cmd.fs_type != FsBackendType::PassthroughFs {
+            cmd.config = effective_fs_config(&cmd.config)?;
+        }
+        snapshot::prepare_mount(&mut cmd)?;
         let backend = fs_backend_factory(&cmd)?;
         let index = self.get_vfs().mount(backend, &cmd.mountpoint)?;
+        if let Some(fs) = self.backend_from_mountpoint(&cmd.mountpoint)? {
+            MOUNTED_FS.write().unwrap().insert(index, fs);
+        }
         info!("{} mounted at {}", &cmd.fs_type, &cmd.mountpoint);
         self.backend_collection().add(&cmd.mountpoint, &cmd)?;
 
@@ -309,12 +926,18 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
         Ok(())
     }
 
-    fn remount(&self, cmd: FsBackendMountCmd) -> DaemonResult<()> {
+    fn remount(&self, mut cmd: FsBackendMountCmd) -> DaemonResult<()> {
         let rootfs = self
             .backend_from_mountpoint(&cmd.mountpoint)?
             .ok_or(DaemonError::NotFound)?;
-        let rafs_config = RafsConfig::from_str(&&cmd.config)?;
-        let mut bootstrap = <dyn RafsIoRead>::from_file(&&cmd.source)?;
+        cmd.config = effective_fs_config(&cmd.config)?;
+        snapshot::prepare_mount(&mut cmd)?;
+        let bootstrap_path = bootstrap_path(&cmd)?;
+        let mut rafs_config = RafsConfig::from_str(&&cmd.config)?;
+        rafs_config.set_default_signature(&bootstrap_path);
+        let bootstrap_path = bootstrap_open_path(&cmd, bootstrap_path);
+        let mut bootstrap = <dyn RafsIoRead>::from_file(&bootstrap_path)?;
+        check_trust_policy(&rafs_config, &mut bootstrap)?;
         let any_fs = rootfs.deref().as_any();
         let rafs = any_fs
             .downcast_ref::<Rafs>()
@@ -325,6 +948,12 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
                 RafsError::Unsupported => DaemonError::Unsupported,
                 e => DaemonError::Rafs(e),
             })?;
+        invalidate_mount(self, &cmd.mountpoint, true).unwrap_or_else(|e| {
+            warn!(
+                "failed to invalidate kernel caches of mount {}, {}",
+                cmd.mountpoint, e
+            )
+        });
 
         // Update mounts opaque from UpgradeManager
         if let Some(mut mgr_guard) = self.upgrade_mgr() {
@@ -334,11 +963,78 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
         Ok(())
     }
 
+    /// Switch the storage backend of the mount at `mountpoint` to `backend`, e.g. from OSS to a
+    /// registry mirror, without unmounting it.
+    fn switch_backend(&self, mountpoint: &str, backend: BackendConfig) -> DaemonResult<()> {
+        let rootfs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let rafs = rootfs
+            .deref()
+            .as_any()
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+
+        rafs.switch_backend(backend.clone())
+            .map_err(DaemonError::Rafs)?;
+        self.backend_collection().set_backend(mountpoint, &backend)
+    }
+
+    /// Hold reads of the mount at `mountpoint` until it's thawed or `timeout` passes, e.g. while
+    /// migrating its blob cache or rotating credentials of its storage backend.
+    fn freeze(&self, mountpoint: &str, timeout: Duration) -> DaemonResult<()> {
+        let rootfs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let rafs = rootfs
+            .deref()
+            .as_any()
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+
+        rafs.freeze(timeout).map_err(DaemonError::Rafs)
+    }
+
+    /// Let reads of the mount at `mountpoint` held by `freeze()` go on.
+    fn thaw(&self, mountpoint: &str) -> DaemonResult<()> {
+        let rootfs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let rafs = rootfs
+            .deref()
+            .as_any()
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        rafs.thaw();
+
+        Ok(())
+    }
+
     fn umount(&self, cmd: FsBackendUmountCmd) -> DaemonResult<()> {
-        let _ = self
+        let fs = self
             .backend_from_mountpoint(&cmd.mountpoint)?
             .ok_or(DaemonError::NotFound)?;
+        if let Some(rafs) = fs.deref().as_any().downcast_ref::<Rafs>() {
+            // Reads held by freezing the mount fail after umounting instead of waiting forever.
+            rafs.thaw();
+            rafs.flush().unwrap_or_else(|e| {
+                error!(
+                    "failed to flush blob caches of mount {}, {}",
+                    cmd.mountpoint, e
+                )
+            });
+        }
         self.get_vfs().umount(&cmd.mountpoint)?;
+        invalidate_mount(self, &cmd.mountpoint, false).unwrap_or_else(|e| {
+            warn!(
+                "failed to invalidate kernel caches of mount {}, {}",
+                cmd.mountpoint, e
+            )
+        });
+        MOUNTED_FS
+            .write()
+            .unwrap()
+            .retain(|_, mounted| !Arc::ptr_eq(mounted, &fs));
 
         self.backend_collection().del(&cmd.mountpoint);
 
@@ -351,6 +1047,134 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
     }
 }
 
+/// Cap the resident set size of the daemon to `limit` bytes, by periodically reclaiming memory
+/// of metadata, blob cache state and cached chunk buffers when the cap is exceeded.
+pub fn start_memory_monitor(
+    daemon: Arc<dyn NydusDaemon + Send + Sync>,
+    limit: u64,
+) -> DaemonResult<()> {
+    MEMORY_LIMIT.store(limit, Ordering::Relaxed);
+    thread::Builder::new()
+        .name("memory_monitor".to_string())
+        .spawn(move || loop {
+            thread::sleep(MEMORY_CHECK_INTERVAL);
+            if let Err(e) = check_memory_limit(limit, || daemon.shrink_memory()) {
+                error!("failed to check memory limit, {}", e);
+            }
+        })
+        .map_err(DaemonError::ThreadSpawn)?;
+
+    Ok(())
+}
+
+/// Reclaim memory by `shrink` if the resident set size of the daemon exceeds `limit`, and return
+/// whether the limit is exceeded.
+fn check_memory_limit<F>(limit: u64, shrink: F) -> DaemonResult<bool>
+where
+    F: FnOnce() -> DaemonResult<()>,
+{
+    let rss = || {
+        process_rss()
+            .map_err(|e| DaemonError::Common(format!("failed to get resident set size, {}", e)))
+    };
+    let before = rss()?;
+    if before <= limit {
+        return Ok(false);
+    }
+
+    shrink()?;
+    let after = rss()?;
+    warn!(
+        "resident set size {} exceeds the limit {}, reclaimed {} bytes",
+        before,
+        limit,
+        before.saturating_sub(after)
+    );
+
+    Ok(true)
+}
+
+/// Check whether the FUSE request is a read of a mount holding reads, e.g. because it's frozen.
+///
+/// Held reads wait until the mount is thawed, so transports serve them off their worker threads.
+pub fn is_held_read(ih: &InHeader) -> bool {
+    if ih.opcode != Opcode::Read as u32 || !storage::device::any_frozen() {
+        return false;
+    }
+    // The VFS keeps the index of the mount in the high bits of inode numbers.
+    let index = (ih.nodeid >> VFS_MAX_INO.count_ones()) as u8;
+    MOUNTED_FS
+        .read()
+        .unwrap()
+        .get(&index)
+        .and_then(|fs| fs.deref().as_any().downcast_ref::<Rafs>())
+        .map(|rafs| rafs.holds_reads())
+        .unwrap_or(false)
+}
+
+thread_local! {
+    static REQUEST_SPAN: RefCell<Option<SpanGuard>> = RefCell::new(None);
+}
+
+/// Bind the FUSE request to the calling thread serving it, so it can be interrupted and traced.
+pub fn begin_request(ih: &InHeader) {
+    request::begin(ih.unique);
+
+    // Spans restore the context active when they're started, so end a span left over by a request
+    // not ended before starting a new one, or the new span would be detached by it.
+    drop(REQUEST_SPAN.with(|s| s.borrow_mut().take()));
+    let span = tracing::span("fuse.request");
+    span.set_attribute_i64("opcode", ih.opcode as i64);
+    span.set_attribute_i64("inode", ih.nodeid as i64);
+    span.set_attribute_i64("unique", ih.unique as i64);
+    REQUEST_SPAN.with(|s| *s.borrow_mut() = Some(span));
+}
+
+/// Mark the FUSE request served by the calling thread as done.
+pub fn end_request(oh: Option<&OutHeader>) {
+    if let Some(span) = REQUEST_SPAN.with(|s| s.borrow_mut().take()) {
+        if let Some(oh) = oh {
+            span.set_attribute_i64("error", oh.error as i64);
+        }
+    }
+    request::end();
+}
+
+/// Bind FUSE requests to the threads serving them, so they can be interrupted and traced.
+#[cfg(feature = "virtiofs")]
+pub struct RequestTracker {}
+
+#[cfg(feature = "virtiofs")]
+impl MetricsHook for RequestTracker {
+    fn collect(&self, ih: &InHeader) {
+        begin_request(ih);
+    }
+
+    fn release(&self, oh: Option<&OutHeader>) {
+        end_request(oh);
+    }
+}
+
+/// Cancel backend reads of the request targeted by a FUSE INTERRUPT request, with `reader`
+/// positioned right after the header of the INTERRUPT request.
+///
+/// The INTERRUPT request itself needs no reply.
+pub fn interrupt_request(reader: &mut Reader) {
+    match reader.read_obj::<InterruptIn>() {
+        Ok(arg) => {
+            if request::interrupt(arg.unique) {
+                info!("interrupt request {}", arg.unique);
+            } else {
+                debug!(
+                    "request {} to interrupt is not inflight, defer it",
+                    arg.unique
+                );
+            }
+        }
+        Err(e) => warn!("failed to decode interrupt request, {}", e),
+    }
+}
+
 /// Validate prefetch file list command line parameter.
 ///
 /// A string including multiple directories and regular files should be separated by white-spaces, e.g.
@@ -374,13 +1198,76 @@ fn input_prefetch_files_verify(input: &Option<Vec<String>>) -> DaemonResult<Opti
     Ok(prefetch_files)
 }
 
+/// Get path of the rafs bootstrap to mount, which is converted from the TOC for stargz layers.
+fn bootstrap_path(cmd: &FsBackendMountCmd) -> DaemonResult<PathBuf> {
+    match cmd.fs_type {
+        FsBackendType::Stargz => stargz::prepare_bootstrap(cmd),
+        _ => Ok(PathBuf::from(&cmd.source)),
+    }
+}
+
+/// Get path to open the bootstrap at `path` by, which refers to the local source already opened
+/// and checked by the mount access control list if there's one.
+fn bootstrap_open_path(cmd: &FsBackendMountCmd, path: PathBuf) -> PathBuf {
+    match cmd.fs_type {
+        FsBackendType::Stargz => path,
+        _ => PathBuf::from(cmd.source_path()),
+    }
+}
+
+/// Invalidate kernel caches of the pseudo mount at `mountpoint` after it's been updated, or
+/// removed if `updated` is false, so the new content is visible without remounting.
+///
+/// Dropping the dentry of the mountpoint drops all cached dentries below it, and inodes without
+/// dentries or open files are evicted together with their page cache, while the root inode of
+/// an updated mount is kept and needs to be invalidated explicitly.
+fn invalidate_mount<D: NydusDaemon + ?Sized>(
+    daemon: &D,
+    mountpoint: &str,
+    updated: bool,
+) -> Result<()> {
+    // The kernel returns ENOENT if it doesn't cache the inode or dentry at all.
+    let ignore_enoent = |r: Result<()>| match r {
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        r => r,
+    };
+    let ctx = Context::new();
+    let vfs = daemon.get_vfs();
+    let mut names = Path::new(mountpoint)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(n) => Some(CString::new(n.as_bytes())),
+            _ => None,
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let name = match names.pop() {
+        Some(name) => name,
+        None => return ignore_enoent(daemon.notify_inval_inode(ROOT_ID)),
+    };
+
+    let mut parent = ROOT_ID;
+    for n in names.iter() {
+        parent = vfs.lookup(&ctx, parent.into(), n)?.inode;
+    }
+    if updated {
+        let ino = vfs.lookup(&ctx, parent.into(), &name)?.inode;
+        ignore_enoent(daemon.notify_inval_inode(ino))?;
+    }
+
+    ignore_enoent(daemon.notify_inval_entry(parent, &name))
+}
+
 fn fs_backend_factory(cmd: &FsBackendMountCmd) -> DaemonResult<BackFileSystem> {
     let prefetch_files = input_prefetch_files_verify(&cmd.prefetch_files)?;
 
     match cmd.fs_type {
-        FsBackendType::Rafs => {
-            let rafs_config = RafsConfig::from_str(cmd.config.as_str())?;
-            let mut bootstrap = <dyn RafsIoRead>::from_file(&cmd.source)?;
+        FsBackendType::Rafs | FsBackendType::Stargz => {
+            let bootstrap_path = bootstrap_path(cmd)?;
+            let mut rafs_config = RafsConfig::from_str(cmd.config.as_str())?;
+            rafs_config.set_default_signature(&bootstrap_path);
+            let bootstrap_path = bootstrap_open_path(cmd, bootstrap_path);
+            let mut bootstrap = <dyn RafsIoRead>::from_file(&bootstrap_path)?;
+            check_trust_policy(&rafs_config, &mut bootstrap)?;
             let mut rafs = Rafs::new(rafs_config, &cmd.mountpoint, &mut bootstrap)?;
             rafs.import(bootstrap, prefetch_files)?;
             info!("Rafs imported");
@@ -391,7 +1278,7 @@ fn fs_backend_factory(cmd: &FsBackendMountCmd) -> DaemonResult<BackFileSystem> {
             // needs to specify them explicitly.
             // TODO(liubo): enable no_open_dir.
             let fs_cfg = Config {
-                root_dir: cmd.source.to_string(),
+                root_dir: cmd.source_path(),
                 do_import: false,
                 writeback: true,
                 no_open: true,
@@ -620,6 +1507,79 @@ mod tests {
         assert_eq!(stat, DaemonState::UNKNOWN);
     }
 
+    #[test]
+    fn it_should_merge_mount_config_over_defaults() {
+        let mut config = serde_json::json!({
+            "device": {
+                "backend": {"type": "oss", "config": {"bucket_name": "b1", "timeout": 5}},
+                "cache": {"type": "blobcache", "config": {"work_dir": "/cache"}}
+            },
+            "mode": "direct",
+            "digest_validate": false,
+            "fs_prefetch": {"enable": true, "threads_count": 4}
+        });
+        let overlay = serde_json::json!({
+            "device": {"backend": {"config": {"bucket_name": "b2"}}},
+            "digest_validate": true,
+            "fs_prefetch": null
+        });
+        merge_fs_config(&mut config, overlay);
+
+        assert_eq!(config["device"]["backend"]["type"], "oss");
+        assert_eq!(config["device"]["backend"]["config"]["bucket_name"], "b2");
+        assert_eq!(config["device"]["backend"]["config"]["timeout"], 5);
+        assert_eq!(config["device"]["cache"]["config"]["work_dir"], "/cache");
+        assert_eq!(config["mode"], "direct");
+        assert_eq!(config["digest_validate"], true);
+        assert!(config.get("fs_prefetch").is_none());
+        let rafs_config = RafsConfig::from_str(&config.to_string()).unwrap();
+        assert!(!rafs_config.fs_prefetch.enable);
+    }
+
+    #[test]
+    fn it_should_not_inherit_credentials_of_other_backends() {
+        let default = serde_json::json!({
+            "device": {
+                "backend": {"type": "registry", "config": {
+                    "host": "registry.internal", "repo": "app", "auth": "c2VjcmV0"
+                }},
+                "cache": {"type": "blobcache", "config": {"work_dir": "/cache"}}
+            },
+            "mode": "direct"
+        });
+
+        // Same registry, settings are inherited.
+        let mut config = default.clone();
+        let overlay = serde_json::json!({
+            "device": {"backend": {"config": {"repo": "other"}}}
+        });
+        merge_fs_config(&mut config, overlay);
+        assert_eq!(config["device"]["backend"]["config"]["auth"], "c2VjcmV0");
+        assert_eq!(config["device"]["backend"]["config"]["repo"], "other");
+
+        // Another registry, the backend is replaced.
+        let mut config = default.clone();
+        let overlay = serde_json::json!({
+            "device": {"backend": {"config": {"host": "docker.io", "repo": "app"}}}
+        });
+        merge_fs_config(&mut config, overlay);
+        assert!(config["device"]["backend"]["config"].get("auth").is_none());
+        assert!(config["device"]["backend"].get("type").is_none());
+        assert_eq!(config["device"]["cache"]["config"]["work_dir"], "/cache");
+
+        // Another type of backend, the backend is replaced.
+        let mut config = default;
+        let overlay = serde_json::json!({
+            "device": {"backend": {"type": "oss", "config": {
+                "endpoint": "oss.example.com", "bucket_name": "b1"
+            }}}
+        });
+        merge_fs_config(&mut config, overlay);
+        assert_eq!(config["device"]["backend"]["type"], "oss");
+        assert!(config["device"]["backend"]["config"].get("auth").is_none());
+        assert!(config["device"]["backend"]["config"].get("repo").is_none());
+    }
+
     #[test]
     fn it_should_convert_str_to_fsbackendtype() {
         let backend_type: FsBackendType = "rafs".parse().unwrap();
@@ -628,6 +1588,9 @@ mod tests {
         let backend_type: FsBackendType = "passthrough_fs".parse().unwrap();
         assert!(backend_type == FsBackendType::PassthroughFs);
 
+        let backend_type: FsBackendType = "stargz".parse().unwrap();
+        assert!(backend_type == FsBackendType::Stargz);
+
         assert!("xxxxxxxxxxxxx".parse::<FsBackendType>().is_err());
     }
 
@@ -643,6 +1606,8 @@ mod tests {
                     mountpoint: "testmonutount".to_string(),
                     source: "testsource".to_string(),
                     prefetch_files: Some(vec!["testfile".to_string()]),
+                    labels: HashMap::new(),
+                    source_file: None,
                 },
             )
             .is_err()
@@ -650,11 +1615,42 @@ mod tests {
             panic!("failed to add backend collection")
         }
         assert_eq!(col.0.len(), 1);
+        assert_eq!(col.get("test").unwrap().source, "testsource");
+        assert!(col.get("test1").is_none());
 
         col.del("test");
         assert_eq!(col.0.len(), 0);
     }
 
+    #[test]
+    fn it_should_switch_backend() {
+        let mut col: FsBackendCollection = Default::default();
+        let cmd = FsBackendMountCmd {
+            fs_type: FsBackendType::Rafs,
+            config:
+                r#"{"device":{"backend":{"type":"oss","config":{"bucket":"b"}}},"mode":"direct"}"#
+                    .to_string(),
+            mountpoint: "/mnt".to_string(),
+            source: "bootstrap".to_string(),
+            prefetch_files: None,
+            labels: HashMap::new(),
+            source_file: None,
+        };
+        col.add("/mnt", &cmd).unwrap();
+
+        let backend = BackendConfig {
+            backend_type: "registry".to_string(),
+            backend_config: serde_json::json!({"host": "mirror", "auth": "secret"}),
+        };
+        col.set_backend("/mnt", &backend).unwrap();
+        let config = col.get("/mnt").unwrap().config.as_ref().unwrap();
+        assert_eq!(config["device"]["backend"]["type"], "registry");
+        assert_eq!(config["device"]["backend"]["config"]["host"], "mirror");
+        assert!(config["device"]["backend"]["config"]["auth"].is_null());
+        assert!(config["device"]["backend"]["config"]["bucket"].is_null());
+        assert_eq!(config["mode"], "direct");
+    }
+
     #[test]
     fn it_should_verify_prefetch_files() {
         match input_prefetch_files_verify(&Some(vec!["/etc/passwd".to_string()])) {
@@ -697,13 +1693,15 @@ mod tests {
               "bandwidth_rate": 10485760
             }
           }"#;
-        let bootstrap = "./tests/texture/bootstrap/nydusd_daemon_test_bootstrap";
+        let bootstrap = "./tests/texture/bootstrap/image_v2.boot";
         if fs_backend_factory(&FsBackendMountCmd {
             fs_type: FsBackendType::Rafs,
             config: config.to_string(),
             mountpoint: "testmountpoint".to_string(),
             source: bootstrap.to_string(),
             prefetch_files: Some(vec!["/testfile".to_string()]),
+            labels: HashMap::new(),
+            source_file: None,
         })
         .unwrap()
         .as_any()
@@ -713,4 +1711,87 @@ mod tests {
             panic!("failed to create rafs backend")
         }
     }
+
+    #[test]
+    fn it_should_reclaim_memory_over_limit() {
+        let config = r#"
+        {
+            "device": {
+              "backend": {
+                "type": "oss",
+                "config": {
+                  "endpoint": "test",
+                  "access_key_id": "test",
+                  "access_key_secret": "test",
+                  "bucket_name": "antsys-nydus",
+                  "object_prefix":"nydus_v2/",
+                  "scheme": "http"
+                }
+              }
+            },
+            "mode": "direct",
+            "digest_validate": false
+          }"#;
+        let fs = fs_backend_factory(&FsBackendMountCmd {
+            fs_type: FsBackendType::Rafs,
+            config: config.to_string(),
+            mountpoint: "testmountpoint".to_string(),
+            source: "./tests/texture/bootstrap/image_v2.boot".to_string(),
+            prefetch_files: None,
+            labels: HashMap::new(),
+            source_file: None,
+        })
+        .unwrap();
+        let rafs = fs.as_any().downcast_ref::<Rafs>().unwrap();
+
+        // Fault in metadata of the bootstrap mapping by walking the root directory.
+        let ctx = Context::new();
+        let mut entries = 0;
+        rafs.readdir(&ctx, ROOT_ID, 0, 0x10000, 0, &mut |_| {
+            entries += 1;
+            Ok(1)
+        })
+        .unwrap();
+        assert!(entries > 0);
+        let usage = rafs.memory_usage();
+        assert!(usage > 0);
+
+        // Nothing is reclaimed under the limit.
+        assert!(!check_memory_limit(u64::MAX, || panic!("shrink under the limit")).unwrap());
+        assert_eq!(rafs.memory_usage(), usage);
+
+        assert!(check_memory_limit(1, || {
+            rafs.shrink();
+            storage::cache::trim_buffer_pool();
+            Ok(())
+        })
+        .unwrap());
+        assert!(rafs.memory_usage() < usage);
+    }
+
+    #[test]
+    fn it_should_check_writable_dir() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        assert!(check_writable(dir.as_path()).is_ok());
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 0);
+        assert!(check_writable(&dir.as_path().join("nonexistent")).is_err());
+
+        // Concurrent checks don't race on the probe file.
+        let path = dir.as_path().to_path_buf();
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || (0..100).all(|_| check_writable(&path).is_ok()))
+            })
+            .collect();
+        assert!(handles.into_iter().all(|h| h.join().unwrap()));
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 0);
+
+        let check = HealthCheck::new(
+            "cache".to_string(),
+            Err(io::Error::new(io::ErrorKind::Other, "read-only")),
+        );
+        assert!(!check.healthy);
+        assert!(check.message.unwrap().contains("read-only"));
+    }
 }
@@ -0,0 +1,764 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serve EROFS images for the in-kernel EROFS filesystem over the fscache on-demand read mode.
+//!
+//! Since Linux 5.19, the cachefiles module supports an on-demand read mode, in which cache misses
+//! of fscache objects are reported to a userspace daemon through `/dev/cachefiles`. The kernel
+//! EROFS filesystem mounts RAFS v6 images natively on top of it, so nydusd only needs to fetch
+//! data on cache misses, instead of serving all filesystem requests through FUSE.
+//!
+//! Images are registered by the mount API, with the EROFS `fsid` in place of the mountpoint,
+//! and then mounted by `mount -t erofs none -o fsid=<fsid> <mountpoint>`. The kernel opens one
+//! fscache object for the bootstrap, whose cookie key is `fsid`, and one for each data blob,
+//! whose cookie key is the blob id.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicI32, Ordering},
+    mpsc::{channel, Receiver},
+    Arc, Mutex, MutexGuard, RwLock,
+};
+use std::thread::{self, JoinHandle};
+
+use fuse_backend_rs::api::{Vfs, VfsOptions};
+use nix::poll::{poll, PollFd, PollFlags};
+use nydus::{FsBackendDesc, FsBackendType};
+use nydus_app::BuildTimeInfo;
+use nydus_utils::metrics::MemoryMetrics;
+use rafs::fs::RafsConfig;
+use rafs::metadata::{RafsMode, RafsSuper};
+use rafs::RafsIoReader;
+use storage::cache::BlobCache;
+use storage::device::BlobInfo;
+use storage::factory::{FactoryConfig, BLOB_FACTORY};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::daemon::{
+    DaemonError, DaemonResult, DaemonState, DaemonStateMachineContext, DaemonStateMachineInput,
+    DaemonStateMachineSubscriber, FsBackendCollection, FsBackendMountCmd, FsBackendUmountCmd,
+    NydusDaemon, Trigger,
+};
+use crate::policy::check_trust_policy;
+use crate::snapshot;
+use crate::upgrade::UpgradeManager;
+
+const CACHEFILES_DEV: &str = "/dev/cachefiles";
+// Maximum size of messages from the cachefiles module, CACHEFILES_MSG_MAX_SIZE.
+const CACHEFILES_MSG_MAX_SIZE: usize = 1024;
+// Prefix of fscache volume keys of EROFS filesystems.
+const EROFS_VOLUME_PREFIX: &str = "erofs,";
+// Size of data copied from the bootstrap file at a time.
+const BOOTSTRAP_COPY_SIZE: u64 = 0x10_0000;
+
+const CACHEFILES_OP_OPEN: u32 = 0;
+const CACHEFILES_OP_CLOSE: u32 = 1;
+const CACHEFILES_OP_READ: u32 = 2;
+
+// CACHEFILES_IOC_READ_COMPLETE, notify the kernel that data of a read request is ready.
+nix::ioctl_write_int!(cachefiles_read_complete, 0x98, 1);
+
+/// Header of messages from the cachefiles module, `struct cachefiles_msg`.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct MsgHeader {
+    msg_id: u32,
+    opcode: u32,
+    len: u32,
+    object_id: u32,
+}
+
+/// Payload of `CACHEFILES_OP_OPEN` messages, `struct cachefiles_open`, followed by the volume
+/// key and the cookie key.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct MsgOpen {
+    volume_key_size: u32,
+    cookie_key_size: u32,
+    fd: u32,
+    flags: u32,
+}
+
+/// Payload of `CACHEFILES_OP_READ` messages, `struct cachefiles_read`.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct MsgRead {
+    off: u64,
+    len: u64,
+}
+
+// Parse a `#[repr(C)]` structure composed of integers from `buf`.
+fn parse_msg<T: Default>(buf: &[u8]) -> Result<T> {
+    if buf.len() < size_of::<T>() {
+        return Err(einval!("cachefiles message is too short"));
+    }
+    let mut msg = T::default();
+    // Safe because `T` is a plain structure of integers and `buf` is big enough.
+    unsafe {
+        std::ptr::copy_nonoverlapping(buf.as_ptr(), &mut msg as *mut T as *mut u8, size_of::<T>())
+    };
+
+    Ok(msg)
+}
+
+// Convert a key from the cachefiles module to string, with the trailing NUL stripped.
+fn parse_key(buf: &[u8]) -> Result<String> {
+    let key = match buf.iter().position(|c| *c == 0) {
+        Some(pos) => &buf[..pos],
+        None => buf,
+    };
+    std::str::from_utf8(key)
+        .map(|s| s.to_string())
+        .map_err(|e| einval!(e))
+}
+
+/// An image registered for the in-kernel EROFS filesystem.
+struct FsCacheImage {
+    bootstrap: Arc<File>,
+    blobs: Vec<Arc<BlobInfo>>,
+    config: Arc<FactoryConfig>,
+}
+
+/// A fscache object opened by the kernel, backed by an anonymous cache file.
+enum FsCacheObject {
+    Bootstrap {
+        file: File,
+        bootstrap: Arc<File>,
+    },
+    DataBlob {
+        file: Arc<File>,
+        cache: Arc<dyn BlobCache>,
+        blob: Arc<BlobInfo>,
+        config: Arc<FactoryConfig>,
+    },
+}
+
+impl FsCacheObject {
+    fn fd(&self) -> RawFd {
+        match self {
+            FsCacheObject::Bootstrap { file, .. } => file.as_raw_fd(),
+            FsCacheObject::DataBlob { file, .. } => file.as_raw_fd(),
+        }
+    }
+
+    // Release the blob cache holding the cache file, so the file is closed once the object is
+    // dropped.
+    fn release(&self) {
+        if let FsCacheObject::DataBlob { blob, config, .. } = self {
+            BLOB_FACTORY.release_blob_cache(config, blob);
+        }
+    }
+
+    // Fill range [off, off + len) of the cache file.
+    fn fill(&self, off: u64, len: u64) -> Result<()> {
+        match self {
+            FsCacheObject::Bootstrap { file, bootstrap } => {
+                let size = bootstrap.metadata()?.len();
+                let end = std::cmp::min(off.saturating_add(len), size);
+                let mut buf = vec![0u8; std::cmp::min(len, BOOTSTRAP_COPY_SIZE) as usize];
+                let mut pos = off;
+                while pos < end {
+                    let sz = std::cmp::min(end - pos, buf.len() as u64) as usize;
+                    bootstrap.read_exact_at(&mut buf[..sz], pos)?;
+                    file.write_all_at(&buf[..sz], pos)?;
+                    pos += sz as u64;
+                }
+                Ok(())
+            }
+            FsCacheObject::DataBlob { cache, .. } => {
+                let obj = cache.get_blob_object().ok_or_else(|| {
+                    enosys!(format!(
+                        "blob {} doesn't support fscache mode",
+                        cache.blob_id()
+                    ))
+                })?;
+                obj.fetch_range_uncompressed(off, len).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Handler of requests from the cachefiles module.
+struct FsCacheHandler {
+    file: File,
+    images: RwLock<HashMap<String, FsCacheImage>>,
+    // Objects opened by the kernel, with the fsid of the image they belong to.
+    objects: RwLock<HashMap<u32, (String, Arc<FsCacheObject>)>>,
+}
+
+impl FsCacheHandler {
+    /// Bind to the cachefiles module in on-demand mode, with `dir` as the cache directory.
+    fn new(dir: &str, tag: Option<&str>) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(CACHEFILES_DEV)
+            .map_err(|e| {
+                error!("Failed to open {}, {}", CACHEFILES_DEV, e);
+                e
+            })?;
+        file.write_all(format!("dir {}", dir).as_bytes())?;
+        if let Some(tag) = tag {
+            file.write_all(format!("tag {}", tag).as_bytes())?;
+        }
+        file.write_all(b"bind ondemand")?;
+
+        Ok(FsCacheHandler {
+            file,
+            images: RwLock::new(HashMap::new()),
+            objects: RwLock::new(HashMap::new()),
+        })
+    }
+
+    fn register_image(&self, fsid: &str, image: FsCacheImage) -> DaemonResult<()> {
+        let mut guard = self.images.write().unwrap();
+        if guard.contains_key(fsid) {
+            return Err(DaemonError::AlreadyExists);
+        }
+        guard.insert(fsid.to_string(), image);
+        Ok(())
+    }
+
+    fn unregister_image(&self, fsid: &str) -> DaemonResult<()> {
+        self.images
+            .write()
+            .unwrap()
+            .remove(fsid)
+            .ok_or(DaemonError::NotFound)?;
+
+        // Close cache files of objects the kernel hasn't closed yet.
+        let mut objects = self.objects.write().unwrap();
+        let ids = objects
+            .iter()
+            .filter(|(_, (id, _))| id == fsid)
+            .map(|(object_id, _)| *object_id)
+            .collect::<Vec<_>>();
+        for object_id in ids {
+            if let Some((_, object)) = objects.remove(&object_id) {
+                object.release();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Get blob caches of data blob objects opened for the image `fsid`, or for all images.
+    fn blob_caches(&self, fsid: Option<&str>) -> Vec<Arc<dyn BlobCache>> {
+        self.objects
+            .read()
+            .unwrap()
+            .values()
+            .filter(|(id, _)| fsid.map(|fsid| fsid == id).unwrap_or(true))
+            .filter_map(|(_, object)| match object.as_ref() {
+                FsCacheObject::DataBlob { cache, .. } => Some(cache.clone()),
+                FsCacheObject::Bootstrap { .. } => None,
+            })
+            .collect()
+    }
+
+    fn close_object(&self, object_id: u32) {
+        let object = self.objects.write().unwrap().remove(&object_id);
+        if let Some((fsid, object)) = object {
+            object.release();
+            info!("fscache object {} of image {} closed", object_id, fsid);
+        }
+    }
+
+    /// Handle requests until `exit_fd` gets readable.
+    fn run(&self, exit_fd: RawFd) -> Result<()> {
+        let mut buf = vec![0u8; CACHEFILES_MSG_MAX_SIZE];
+        let mut fds = [
+            PollFd::new(self.file.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(exit_fd, PollFlags::POLLIN),
+        ];
+
+        loop {
+            match poll(&mut fds, -1) {
+                Ok(_) => {}
+                Err(nix::Error::EINTR) => continue,
+                Err(e) => return Err(eother!(e)),
+            }
+            if fds[1].revents().map_or(false, |r| !r.is_empty()) {
+                info!("fscache server exits");
+                return Ok(());
+            }
+            if !fds[0]
+                .revents()
+                .map_or(false, |r| r.contains(PollFlags::POLLIN))
+            {
+                continue;
+            }
+
+            // Each read returns one message, and zero if the message is taken by other threads.
+            let size = match nix::unistd::read(self.file.as_raw_fd(), &mut buf) {
+                Ok(0) | Err(nix::Error::EAGAIN) | Err(nix::Error::EINTR) => continue,
+                Ok(size) => size,
+                Err(e) => return Err(eother!(e)),
+            };
+            if let Err(e) = self.handle_msg(&buf[..size]) {
+                error!("Failed to handle cachefiles message, {}", e);
+            }
+        }
+    }
+
+    fn handle_msg(&self, buf: &[u8]) -> Result<()> {
+        let hdr: MsgHeader = parse_msg(buf)?;
+        let data = &buf[size_of::<MsgHeader>()..];
+        trace!("cachefiles message {:?}", hdr);
+
+        match hdr.opcode {
+            CACHEFILES_OP_OPEN => self.handle_open(&hdr, data),
+            CACHEFILES_OP_CLOSE => {
+                self.close_object(hdr.object_id);
+                Ok(())
+            }
+            CACHEFILES_OP_READ => self.handle_read(&hdr, data),
+            op => Err(einval!(format!("unknown cachefiles opcode {}", op))),
+        }
+    }
+
+    fn handle_open(&self, hdr: &MsgHeader, data: &[u8]) -> Result<()> {
+        let msg: MsgOpen = parse_msg(data)?;
+        // Take ownership of the anonymous file, so it's closed on failure.
+        let file = unsafe { File::from_raw_fd(msg.fd as RawFd) };
+        let keys = &data[size_of::<MsgOpen>()..];
+        let (volume_key_size, cookie_key_size) =
+            (msg.volume_key_size as usize, msg.cookie_key_size as usize);
+
+        let result = if keys.len() < volume_key_size + cookie_key_size {
+            Err(einval!("invalid key size of cachefiles open message"))
+        } else {
+            parse_key(&keys[..volume_key_size]).and_then(|volume| {
+                let cookie = parse_key(&keys[volume_key_size..volume_key_size + cookie_key_size])?;
+                self.open_object(hdr.object_id, &volume, &cookie, file)
+            })
+        };
+
+        let reply = match result {
+            Ok(size) => format!("copen {},{}", hdr.msg_id, size),
+            Err(e) => {
+                error!("Failed to open fscache object {}, {}", hdr.object_id, e);
+                format!(
+                    "copen {},{}",
+                    hdr.msg_id,
+                    -e.raw_os_error().unwrap_or(libc::EIO)
+                )
+            }
+        };
+        (&self.file).write_all(reply.as_bytes())
+    }
+
+    // Open the fscache object for the bootstrap or a data blob, and return size of the object.
+    fn open_object(&self, object_id: u32, volume: &str, cookie: &str, file: File) -> Result<u64> {
+        let fsid = volume
+            .strip_prefix(EROFS_VOLUME_PREFIX)
+            .ok_or_else(|| einval!(format!("unknown fscache volume {}", volume)))?;
+        let guard = self.images.read().unwrap();
+        let image = guard
+            .get(fsid)
+            .ok_or_else(|| enoent!(format!("no image registered with fsid {}", fsid)))?;
+
+        let (object, size) = if cookie == fsid {
+            let size = image.bootstrap.metadata()?.len();
+            let object = FsCacheObject::Bootstrap {
+                file,
+                bootstrap: image.bootstrap.clone(),
+            };
+            (object, size)
+        } else {
+            let blob = image
+                .blobs
+                .iter()
+                .find(|b| b.blob_id() == cookie)
+                .ok_or_else(|| enoent!(format!("no blob {} in image {}", cookie, fsid)))?;
+            let file = Arc::new(file);
+            let mut blob_info = blob.as_ref().clone();
+            blob_info.set_fscache_file(Some(file.clone()));
+            let blob_info = Arc::new(blob_info);
+            let cache = BLOB_FACTORY.new_blob_cache(&image.config, &blob_info)?;
+            let object = FsCacheObject::DataBlob {
+                file,
+                cache,
+                blob: blob_info,
+                config: image.config.clone(),
+            };
+            (object, blob.uncompressed_size())
+        };
+        info!(
+            "fscache object {} opened for {} of image {}",
+            object_id, cookie, fsid
+        );
+        self.objects
+            .write()
+            .unwrap()
+            .insert(object_id, (fsid.to_string(), Arc::new(object)));
+
+        Ok(size)
+    }
+
+    fn handle_read(&self, hdr: &MsgHeader, data: &[u8]) -> Result<()> {
+        let msg: MsgRead = parse_msg(data)?;
+        let object = self
+            .objects
+            .read()
+            .unwrap()
+            .get(&hdr.object_id)
+            .map(|(_, object)| object.clone())
+            .ok_or_else(|| enoent!(format!("no fscache object {}", hdr.object_id)))?;
+
+        if let Err(e) = object.fill(msg.off, msg.len) {
+            // The kernel fails the read request if the data is still not ready.
+            warn!(
+                "Failed to fetch range [{}, {}) of fscache object {}, {}",
+                msg.off,
+                msg.off + msg.len,
+                hdr.object_id,
+                e
+            );
+        }
+        // Safe because the file descriptor is valid and the ioctl takes an integer argument.
+        unsafe { cachefiles_read_complete(object.fd(), hdr.msg_id as libc::c_ulong) }
+            .map(|_| ())
+            .map_err(|e| eother!(e))
+    }
+}
+
+/// A nydus daemon serving the in-kernel EROFS filesystem over fscache.
+pub struct FsCacheDaemon {
+    bti: BuildTimeInfo,
+    id: Option<String>,
+    supervisor: Option<String>,
+    threads_cnt: u32,
+    vfs: Vfs,
+
+    event_fd: EventFd,
+    handler: Arc<FsCacheHandler>,
+    state: AtomicI32,
+
+    backend_collection: Mutex<FsBackendCollection>,
+    result_receiver: Mutex<Receiver<DaemonResult<()>>>,
+    trigger: Arc<Mutex<Trigger>>,
+    threads: Mutex<Vec<JoinHandle<Result<()>>>>,
+}
+
+impl FsCacheDaemon {
+    fn kick_one_server(&self) -> Result<()> {
+        let handler = self.handler.clone();
+        let evtfd = self.event_fd.try_clone()?;
+        let thread = thread::Builder::new()
+            .name("fscache_server".to_string())
+            .spawn(move || {
+                let result = handler.run(evtfd.as_raw_fd());
+                if let Err(e) = &result {
+                    error!("fscache server fails, {}", e);
+                }
+                result
+            })
+            .map_err(DaemonError::ThreadSpawn)?;
+
+        self.threads.lock().unwrap().push(thread);
+
+        Ok(())
+    }
+
+    // Load the bootstrap and prepare storage configuration to serve the image.
+    fn load_image(cmd: &FsBackendMountCmd) -> DaemonResult<FsCacheImage> {
+        if cmd.fs_type != FsBackendType::Rafs {
+            return Err(DaemonError::InvalidArguments(
+                "fscache mode only supports rafs".to_string(),
+            ));
+        }
+
+        // Check and load the bootstrap from the file served to the kernel, so it can't be
+        // replaced after being checked. A signed bootstrap is replaced by its verified copy.
+        let open_err = |e: std::io::Error| {
+            DaemonError::DaemonFailure(format!("failed to open bootstrap, {}", e))
+        };
+        let mut reader = Box::new(File::open(&cmd.source).map_err(open_err)?) as RafsIoReader;
+        let mut rafs_config = RafsConfig::from_str(&cmd.config)?;
+        rafs_config.set_default_signature(Path::new(&cmd.source));
+        check_trust_policy(&rafs_config, &mut reader)?;
+        if let Some(signature) = rafs_config.verify_signature.as_ref() {
+            signature.verify(&mut reader)?;
+        }
+
+        let mut rs = RafsSuper {
+            mode: RafsMode::Direct,
+            ..Default::default()
+        };
+        rs.load(&mut reader)
+            .map_err(|e| DaemonError::DaemonFailure(format!("failed to load bootstrap, {}", e)))?;
+        let fd = nix::unistd::dup(reader.as_raw_fd())
+            .map_err(|e| open_err(std::io::Error::from_raw_os_error(e as i32)))?;
+        // Safe because we own the duplicated fd.
+        let bootstrap = unsafe { File::from_raw_fd(fd) };
+        if !rs.meta.is_v6() {
+            return Err(DaemonError::InvalidArguments(
+                "fscache mode only supports rafs v6".to_string(),
+            ));
+        }
+
+        let mut config = rafs_config.device;
+        // Blob caches of different images must not share the chunk map.
+        config.id = cmd.mountpoint.clone();
+        config.cache.cache_type = "fscache".to_string();
+        config.cache.cache_validate = rafs_config.digest_validate;
+
+        Ok(FsCacheImage {
+            bootstrap: Arc::new(bootstrap),
+            blobs: rs.superblock.get_blob_infos(),
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl DaemonStateMachineSubscriber for FsCacheDaemon {
+    fn on_event(&self, event: DaemonStateMachineInput) -> DaemonResult<()> {
+        self.trigger
+            .lock()
+            .unwrap()
+            .send(event)
+            .map_err(|e| DaemonError::Channel(format!("send {:?}", e)))?;
+
+        self.result_receiver
+            .lock()
+            .expect("Not expect poisoned lock!")
+            .recv()
+            .map_err(|e| DaemonError::Channel(format!("recv {:?}", e)))?
+    }
+}
+
+impl NydusDaemon for FsCacheDaemon {
+    #[inline]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn start(&self) -> DaemonResult<()> {
+        for _ in 0..self.threads_cnt {
+            self.kick_one_server()
+                .map_err(|e| DaemonError::StartService(format!("{:?}", e)))?;
+        }
+
+        Ok(())
+    }
+
+    fn wait(&self) -> DaemonResult<()> {
+        let mut guard = self.threads.lock().unwrap();
+
+        while let Some(handle) = guard.pop() {
+            handle
+                .join()
+                .map_err(|e| {
+                    DaemonError::WaitDaemon(
+                        *e.downcast::<std::io::Error>()
+                            .unwrap_or_else(|e| Box::new(eother!(e))),
+                    )
+                })?
+                .map_err(DaemonError::WaitDaemon)?;
+        }
+
+        Ok(())
+    }
+
+    fn disconnect(&self) -> DaemonResult<()> {
+        // The cachefiles module unbinds the cache when the device file is closed on exit.
+        self.interrupt();
+        Ok(())
+    }
+
+    #[inline]
+    fn id(&self) -> Option<String> {
+        self.id.clone()
+    }
+
+    #[inline]
+    fn supervisor(&self) -> Option<String> {
+        self.supervisor.clone()
+    }
+
+    #[inline]
+    fn interrupt(&self) {
+        self.event_fd.write(1).expect("Stop fscache service loop");
+    }
+
+    #[inline]
+    fn set_state(&self, state: DaemonState) {
+        self.state.store(state as i32, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn get_state(&self) -> DaemonState {
+        self.state.load(Ordering::Relaxed).into()
+    }
+
+    fn save(&self) -> DaemonResult<()> {
+        Err(DaemonError::Unsupported)
+    }
+
+    fn restore(&self) -> DaemonResult<()> {
+        Err(DaemonError::Unsupported)
+    }
+
+    #[inline]
+    fn get_vfs(&self) -> &Vfs {
+        &self.vfs
+    }
+
+    #[inline]
+    fn upgrade_mgr(&self) -> Option<MutexGuard<UpgradeManager>> {
+        None
+    }
+
+    fn backend_collection(&self) -> MutexGuard<FsBackendCollection> {
+        self.backend_collection.lock().unwrap()
+    }
+
+    fn version(&self) -> BuildTimeInfo {
+        self.bti.clone()
+    }
+
+    fn export_inflight_ops(&self) -> DaemonResult<Option<String>> {
+        Err(DaemonError::Unsupported)
+    }
+
+    fn image_blobs(
+        &self,
+        desc: &FsBackendDesc,
+    ) -> DaemonResult<Option<(String, Vec<Arc<BlobInfo>>)>> {
+        Ok(self
+            .handler
+            .images
+            .read()
+            .unwrap()
+            .get(&desc.mountpoint)
+            .map(|image| (image.config.id.clone(), image.blobs.clone())))
+    }
+
+    /// Metadata of images served through fscache is consumed by the kernel, so only blob cache
+    /// state and in-flight buffers are accounted to the daemon.
+    fn image_memory(&self, desc: &FsBackendDesc) -> DaemonResult<Option<MemoryMetrics>> {
+        let cache_id = match self.handler.images.read().unwrap().get(&desc.mountpoint) {
+            Some(image) => image.config.id.clone(),
+            None => return Ok(None),
+        };
+        let cache_state = self
+            .handler
+            .blob_caches(Some(&desc.mountpoint))
+            .iter()
+            .map(|cache| cache.memory_usage())
+            .sum();
+
+        Ok(Some(MemoryMetrics::collect(&cache_id, 0, cache_state)))
+    }
+
+    fn shrink_memory(&self) -> DaemonResult<()> {
+        for cache in self.handler.blob_caches(None) {
+            cache.shrink();
+        }
+        storage::cache::trim_buffer_pool();
+
+        Ok(())
+    }
+
+    /// Register an image to serve, with `cmd.mountpoint` as the fsid of the EROFS filesystem.
+    fn mount(&self, mut cmd: FsBackendMountCmd) -> DaemonResult<()> {
+        if self.backend_collection().get(&cmd.mountpoint).is_some() {
+            return Err(DaemonError::AlreadyExists);
+        }
+        snapshot::prepare_mount(&mut cmd)?;
+        let image = Self::load_image(&cmd)?;
+        self.handler.register_image(&cmd.mountpoint, image)?;
+        info!(
+            "image {} registered with fsid {}",
+            cmd.source, cmd.mountpoint
+        );
+        self.backend_collection().add(&cmd.mountpoint, &cmd)
+    }
+
+    fn remount(&self, _cmd: FsBackendMountCmd) -> DaemonResult<()> {
+        Err(DaemonError::Unsupported)
+    }
+
+    fn umount(&self, cmd: FsBackendUmountCmd) -> DaemonResult<()> {
+        self.handler.unregister_image(&cmd.mountpoint)?;
+        self.backend_collection().del(&cmd.mountpoint);
+        Ok(())
+    }
+}
+
+pub fn create_fscache_daemon(
+    dir: &str,
+    tag: Option<&str>,
+    supervisor: Option<String>,
+    id: Option<String>,
+    threads_cnt: u32,
+    bti: BuildTimeInfo,
+) -> Result<Arc<dyn NydusDaemon + Send + Sync>> {
+    let handler = FsCacheHandler::new(dir, tag)?;
+    let (trigger, events_rx) = channel::<DaemonStateMachineInput>();
+    let (result_sender, result_receiver) = channel::<DaemonResult<()>>();
+
+    let daemon = Arc::new(FsCacheDaemon {
+        bti,
+        id,
+        supervisor,
+        threads_cnt,
+        vfs: Vfs::new(VfsOptions::default()),
+
+        event_fd: EventFd::new(0)?,
+        handler: Arc::new(handler),
+        state: AtomicI32::new(DaemonState::INIT as i32),
+
+        backend_collection: Default::default(),
+        result_receiver: Mutex::new(result_receiver),
+        trigger: Arc::new(Mutex::new(trigger)),
+        threads: Mutex::new(Vec::new()),
+    });
+
+    let machine = DaemonStateMachineContext::new(daemon.clone(), events_rx, result_sender);
+    machine.kick_state_machine()?;
+    daemon
+        .on_event(DaemonStateMachineInput::Mount)
+        .map_err(|e| eother!(e))?;
+
+    Ok(daemon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_msg() {
+        let mut buf = Vec::new();
+        for v in [1u32, CACHEFILES_OP_OPEN, 46, 3, 9, 5, 10, 0].iter() {
+            buf.extend_from_slice(&v.to_ne_bytes());
+        }
+        buf.extend_from_slice(b"erofs,id\0blob1");
+
+        let hdr: MsgHeader = parse_msg(&buf).unwrap();
+        assert_eq!(hdr.msg_id, 1);
+        assert_eq!(hdr.opcode, CACHEFILES_OP_OPEN);
+        assert_eq!(hdr.object_id, 3);
+        let data = &buf[size_of::<MsgHeader>()..];
+        let open: MsgOpen = parse_msg(data).unwrap();
+        assert_eq!(open.volume_key_size, 9);
+        assert_eq!(open.cookie_key_size, 5);
+        assert_eq!(open.fd, 10);
+
+        let keys = &data[size_of::<MsgOpen>()..];
+        assert_eq!(parse_key(&keys[..9]).unwrap(), "erofs,id");
+        assert_eq!(parse_key(&keys[9..14]).unwrap(), "blob1");
+        assert!(parse_msg::<MsgRead>(&buf[..8]).is_err());
+    }
+}
@@ -6,7 +6,8 @@
 use std::any::Any;
 use std::ffi::{CStr, CString};
 use std::fs::metadata;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::mem::size_of;
 use std::ops::Deref;
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::ffi::OsStrExt;
@@ -20,7 +21,9 @@ use std::sync::{
 use std::thread::{self, JoinHandle};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use fuse_backend_rs::abi::linux_abi::{InHeader, OutHeader};
+use fuse_backend_rs::abi::linux_abi::{
+    InHeader, NotifyInvalEntryOut, NotifyInvalInodeOut, NotifyOpcode, Opcode, OutHeader,
+};
 use fuse_backend_rs::api::server::{MetricsHook, Server};
 use fuse_backend_rs::api::Vfs;
 use fuse_backend_rs::transport::fusedev::{FuseChannel, FuseSession};
@@ -30,12 +33,16 @@ use serde::Serialize;
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::daemon::{
-    DaemonError, DaemonResult, DaemonState, DaemonStateMachineContext, DaemonStateMachineInput,
-    DaemonStateMachineSubscriber, FsBackendCollection, FsBackendMountCmd, NydusDaemon, Trigger,
+    begin_request, end_request, interrupt_request, is_held_read, DaemonError, DaemonResult,
+    DaemonState, DaemonStateMachineContext, DaemonStateMachineInput, DaemonStateMachineSubscriber,
+    FsBackendCollection, FsBackendMountCmd, NydusDaemon, Trigger,
 };
 use crate::exit_event_manager;
 use crate::upgrade::{self, FailoverPolicy, UpgradeManager};
 
+/// Directory where the kernel exports established fuse connections.
+const FUSE_CONNECTIONS_DIR: &str = "/sys/fs/fuse/connections";
+
 #[derive(Serialize)]
 struct FuseOp {
     inode: u64,
@@ -84,9 +91,11 @@ impl MetricsHook for FuseOpWrapper {
         };
 
         *self.op.lock().expect("Not expect poisoned lock") = Some(op);
+        begin_request(ih);
     }
 
-    fn release(&self, _oh: Option<&OutHeader>) {
+    fn release(&self, oh: Option<&OutHeader>) {
+        end_request(oh);
         *self.op.lock().expect("Not expect poisoned lock") = None
     }
 }
@@ -104,7 +113,13 @@ impl FuseServer {
         })
     }
 
-    fn svc_loop(&mut self, metrics_hook: &dyn MetricsHook) -> Result<()> {
+    /// Serve FUSE requests until the session is shut down, or a held read is handed over to a
+    /// spare server, in which case `Ok(true)` is returned after serving the held read.
+    fn svc_loop(
+        &mut self,
+        metrics_hook: &dyn MetricsHook,
+        spares: &Arc<SpareServers>,
+    ) -> Result<bool> {
         // Given error EBADF, it means kernel has shut down this session.
         let _ebadf = std::io::Error::from_raw_os_error(libc::EBADF);
 
@@ -114,6 +129,20 @@ impl FuseServer {
                 .get_request()
                 .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?
             {
+                // Interrupts are served by the server as no-op, cancel the interrupted request
+                // here since the server doesn't expose the request body to the filesystem.
+                let mut peek = reader.clone();
+                let mut relieved = false;
+                if let Ok(ih) = peek.read_obj::<InHeader>() {
+                    if ih.opcode == Opcode::Interrupt as u32 {
+                        interrupt_request(&mut peek);
+                    } else if is_held_read(&ih) {
+                        // Keep serving other requests with a spare server while waiting for
+                        // the mount to be thawed.
+                        relieved = spares.relieve();
+                    }
+                }
+
                 if let Err(e) = self
                     .server
                     .handle_message(reader, writer, None, Some(metrics_hook))
@@ -124,20 +153,69 @@ impl FuseServer {
                         }
                         _ => {
                             error!("Handling fuse message, {}", DaemonError::ProcessQueue(e));
-                            continue;
                         }
                     }
                 }
+                if relieved {
+                    return Ok(true);
+                }
             } else {
                 info!("fuse server exits");
                 break;
             }
         }
 
-        Ok(())
+        Ok(false)
     }
 }
 
+/// Idle FUSE servers, to take over from worker threads blocked by held reads.
+struct SpareServers {
+    servers: Mutex<Vec<(FuseServer, FuseOpWrapper)>>,
+    threads: Arc<Mutex<Vec<JoinHandle<Result<()>>>>>,
+}
+
+impl SpareServers {
+    /// Start serving FUSE requests with a spare server, return false if there's no spare server.
+    fn relieve(self: &Arc<Self>) -> bool {
+        let spare = self.servers.lock().unwrap().pop();
+        match spare {
+            Some((server, inflight_op)) => match serve(server, inflight_op, self.clone()) {
+                Ok(thread) => {
+                    self.threads.lock().unwrap().push(thread);
+                    true
+                }
+                Err(e) => {
+                    error!("failed to start spare fuse server, {}", e);
+                    false
+                }
+            },
+            None => {
+                warn!("no spare fuse server, serve held read on the worker thread");
+                false
+            }
+        }
+    }
+}
+
+// Serve FUSE requests by `server` in a new thread, which returns the server to `spares` once
+// it's relieved by a spare server.
+fn serve(
+    mut server: FuseServer,
+    inflight_op: FuseOpWrapper,
+    spares: Arc<SpareServers>,
+) -> Result<JoinHandle<Result<()>>> {
+    thread::Builder::new()
+        .name("fuse_server".to_string())
+        .spawn(move || {
+            match server.svc_loop(&inflight_op, &spares) {
+                Ok(true) => spares.servers.lock().unwrap().push((server, inflight_op)),
+                _ => exit_event_manager(),
+            }
+            Ok(())
+        })
+}
+
 pub struct FusedevDaemon {
     /// Fuse connection ID which usually equals to `st_dev`
     pub conn: AtomicU64,
@@ -159,28 +237,41 @@ pub struct FusedevDaemon {
     inflight_ops: Mutex<Vec<FuseOpWrapper>>,
     result_receiver: Mutex<Receiver<DaemonResult<()>>>,
     trigger: Arc<Mutex<Trigger>>,
-    threads: Mutex<Vec<JoinHandle<Result<()>>>>,
+    threads: Arc<Mutex<Vec<JoinHandle<Result<()>>>>>,
 }
 
 impl FusedevDaemon {
-    fn kick_one_server(&self) -> Result<()> {
+    fn new_server(&self) -> Result<FuseServer> {
         // Clone event fd must succeed, otherwise fusedev daemon should not work.
         let evtfd = self.event_fd.try_clone()?;
-        let mut s = FuseServer::new(
+        FuseServer::new(
             self.server.clone(),
             self.session.lock().unwrap().deref(),
             evtfd,
-        )?;
+        )
+    }
+
+    /// Send a FUSE notification of `opcode` with `payload` to the kernel.
+    fn notify(&self, opcode: NotifyOpcode, payload: &[u8]) -> Result<()> {
+        let len = size_of::<OutHeader>() + payload.len();
+        let mut buf = Vec::with_capacity(len);
+        // Notifications are replies with zero unique and the opcode in the error field.
+        buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        buf.extend_from_slice(&(opcode as i32).to_ne_bytes());
+        buf.extend_from_slice(&0u64.to_ne_bytes());
+        buf.extend_from_slice(payload);
+
+        let mut session = self.session.lock().unwrap();
+        let mut file = session.get_fuse_file().ok_or_else(|| {
+            Error::new(ErrorKind::NotConnected, "fuse session is not established")
+        })?;
+        file.write_all(&buf)
+    }
 
+    fn kick_one_server(&self, spares: &Arc<SpareServers>) -> Result<()> {
+        let s = self.new_server()?;
         let inflight_op = self.create_inflight_op();
-        let thread = thread::Builder::new()
-            .name("fuse_server".to_string())
-            .spawn(move || {
-                let _ = s.svc_loop(&inflight_op);
-                exit_event_manager();
-                Ok(())
-            })
-            .map_err(DaemonError::ThreadSpawn)?;
+        let thread = serve(s, inflight_op, spares.clone()).map_err(DaemonError::ThreadSpawn)?;
 
         self.threads.lock().unwrap().push(thread);
 
@@ -220,8 +311,20 @@ impl NydusDaemon for FusedevDaemon {
     }
 
     fn start(&self) -> DaemonResult<()> {
+        let mut servers = Vec::with_capacity(self.threads_cnt as usize);
+        for _ in 0..self.threads_cnt {
+            let s = self
+                .new_server()
+                .map_err(|e| DaemonError::StartService(format!("{:?}", e)))?;
+            servers.push((s, self.create_inflight_op()));
+        }
+        let spares = Arc::new(SpareServers {
+            servers: Mutex::new(servers),
+            threads: self.threads.clone(),
+        });
+
         for _ in 0..self.threads_cnt {
-            self.kick_one_server()
+            self.kick_one_server(&spares)
                 .map_err(|e| DaemonError::StartService(format!("{:?}", e)))?;
         }
 
@@ -229,9 +332,9 @@ impl NydusDaemon for FusedevDaemon {
     }
 
     fn wait(&self) -> DaemonResult<()> {
-        let mut guard = self.threads.lock().unwrap();
-
-        while let Some(handle) = guard.pop() {
+        // Don't hold the lock while joining, threads relieved by spare servers add new threads.
+        let pop = || self.threads.lock().unwrap().pop();
+        while let Some(handle) = pop() {
             handle
                 .join()
                 .map_err(|e| {
@@ -279,6 +382,56 @@ impl NydusDaemon for FusedevDaemon {
         self.state.load(Ordering::Relaxed).into()
     }
 
+    fn check_session(&self) -> Result<()> {
+        let conn = self.conn.load(Ordering::Relaxed);
+        if conn == 0 {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "fuse session is not established",
+            ));
+        }
+        // The kernel removes the connection once the session is aborted or umounted.
+        let path = Path::new(FUSE_CONNECTIONS_DIR).join(conn.to_string());
+        if !path.exists() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("fuse connection {} has gone", conn),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn notify_inval_inode(&self, ino: u64) -> Result<()> {
+        // A negative offset only invalidates attributes, and a non-positive length invalidates
+        // the page cache up to the end of the file.
+        let out = NotifyInvalInodeOut {
+            ino,
+            off: 0,
+            len: 0,
+        };
+        let mut payload = Vec::with_capacity(size_of::<NotifyInvalInodeOut>());
+        payload.extend_from_slice(&out.ino.to_ne_bytes());
+        payload.extend_from_slice(&out.off.to_ne_bytes());
+        payload.extend_from_slice(&out.len.to_ne_bytes());
+        self.notify(NotifyOpcode::InvalInode, &payload)
+    }
+
+    fn notify_inval_entry(&self, parent: u64, name: &CStr) -> Result<()> {
+        let out = NotifyInvalEntryOut {
+            parent,
+            namelen: name.to_bytes().len() as u32,
+            padding: 0,
+        };
+        let mut payload =
+            Vec::with_capacity(size_of::<NotifyInvalEntryOut>() + name.to_bytes_with_nul().len());
+        payload.extend_from_slice(&out.parent.to_ne_bytes());
+        payload.extend_from_slice(&out.namelen.to_ne_bytes());
+        payload.extend_from_slice(&out.padding.to_ne_bytes());
+        payload.extend_from_slice(name.to_bytes_with_nul());
+        self.notify(NotifyOpcode::InvalEntry, &payload)
+    }
+
     fn save(&self) -> DaemonResult<()> {
         upgrade::fusedev_upgrade::save(self)
     }
@@ -431,7 +584,7 @@ pub fn create_nydus_daemon(
         inflight_ops: Mutex::new(Vec::new()),
         result_receiver: Mutex::new(result_receiver),
         trigger: Arc::new(Mutex::new(trigger)),
-        threads: Mutex::new(Vec::new()),
+        threads: Arc::new(Mutex::new(Vec::new())),
     });
 
     let machine = DaemonStateMachineContext::new(daemon.clone(), events_rx, result_sender);
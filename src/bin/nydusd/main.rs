@@ -15,44 +15,66 @@ extern crate serde_json;
 #[macro_use]
 extern crate nydus_error;
 
+use std::collections::HashMap;
 #[cfg(feature = "fusedev")]
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::{Read, Result};
 use std::ops::DerefMut;
+use std::str::FromStr;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     mpsc::channel,
     Arc, Mutex,
 };
 use std::thread;
+use std::time::Duration;
 use std::{io, process};
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use event_manager::{EventManager, EventSubscriber, SubscriberOps};
 use fuse_backend_rs::api::{Vfs, VfsOptions};
 use nix::sys::signal;
 use rlimit::{rlim, Resource};
+use storage::utils::{set_hugepage_mode, HugePageMode};
 use vmm_sys_util::eventfd::EventFd;
 
 use nydus::FsBackendType;
-use nydus_api::http::start_http_thread;
-use nydus_app::{dump_program_info, setup_logging, BuildTimeInfo};
+use nydus_api::http::{start_http_thread, HttpLimits};
+use nydus_app::{dump_program_info, setup_logging_with_options, BuildTimeInfo, LoggingOptions};
+use nydus_utils::tracing;
 
 use self::api_server_glue::{ApiServer, ApiSeverSubscriber};
-use self::daemon::{DaemonError, FsBackendMountCmd, NydusDaemonSubscriber};
+use self::audit::AuditLog;
+use self::daemon::{
+    set_default_fs_config, set_dump_dir, start_memory_monitor, DaemonError, FsBackendMountCmd,
+    NydusDaemonSubscriber,
+};
+use self::policy::{set_mount_acl, set_trust_policy, MountAcl, TrustPolicy};
 
 #[cfg(feature = "virtiofs")]
 mod virtiofs;
 #[cfg(feature = "virtiofs")]
-use self::virtiofs::create_nydus_daemon;
+use self::virtiofs::{create_nydus_daemon, parse_cpu_list, parse_id_list, SockAccess};
+#[cfg(feature = "fusedev")]
+mod fs_cache;
+#[cfg(feature = "fusedev")]
+use self::fs_cache::create_fscache_daemon;
 #[cfg(feature = "fusedev")]
 mod fusedev;
 #[cfg(feature = "fusedev")]
 use self::fusedev::create_nydus_daemon;
 
 mod api_server_glue;
+mod audit;
 mod daemon;
+mod policy;
+mod preheat;
+mod seccomp;
+mod seeder;
+mod snapshot;
+mod stargz;
+mod telemetry;
 mod upgrade;
 
 lazy_static! {
@@ -111,213 +133,514 @@ extern "C" fn sig_exit(_sig: std::os::raw::c_int) {
     }
 }
 
-fn main() -> Result<()> {
-    let (bti_string, bti) = BuildTimeInfo::dump(crate_version!());
+/// Arguments shared by all working modes.
+fn common_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("apisock")
+            .long("apisock")
+            .short("A")
+            .help("Administration API socket")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("config")
+            .long("config")
+            .short("C")
+            .help("Configuration file")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("id")
+            .long("id")
+            .help("Nydus image service identifier")
+            .takes_value(true)
+            .required(false)
+            .requires("supervisor"),
+        Arg::with_name("log-level")
+            .long("log-level")
+            .short("l")
+            .help("Log level:")
+            .default_value("info")
+            .possible_values(&["trace", "debug", "info", "warn", "error"])
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("log-file")
+            .long("log-file")
+            .short("L")
+            .help("Log messages to the file. If file extension is not specified, the default extension \".log\" will be appended.")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("log-format")
+            .long("log-format")
+            .help("Format of log messages, json outputs one JSON object per line")
+            .default_value("text")
+            .possible_values(&["text", "json"])
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("log-rotation-size")
+            .long("log-rotation-size")
+            .help("Rotate the log file when it's bigger than the size in MB, 0 to disable")
+            .default_value("0")
+            .takes_value(true)
+            .required(false)
+            .validator(|v| parse_log_rotation_size(&v).map(|_| ())),
+        Arg::with_name("log-rotation-age")
+            .long("log-rotation-age")
+            .help("Rotate the log file when it's older than the age")
+            .possible_values(&["hourly", "daily"])
+            .takes_value(true)
+            .required(false)
+            .requires("log-file"),
+        Arg::with_name("log-keep-files")
+            .long("log-keep-files")
+            .help("Number of rotated log files to keep, 0 to keep all of them")
+            .default_value("0")
+            .takes_value(true)
+            .required(false)
+            .validator(|v| {
+                v.parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|e| format!("Invalid number of log files, {}", e))
+            }),
+        Arg::with_name("rlimit-nofile")
+            .long("rlimit-nofile")
+            .default_value("1,000,000")
+            .help("Tune the maximum number of file descriptors (0 leaves rlimit unchanged)")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("supervisor")
+            .long("supervisor")
+            .short("S")
+            .help("Supervisor API socket")
+            .takes_value(true)
+            .required(false)
+            .requires("id"),
+        Arg::with_name("hugepage")
+            .long("hugepage")
+            .help("Back metadata and chunk buffers with huge pages")
+            .takes_value(true)
+            .possible_values(&["never", "transparent", "explicit"])
+            .default_value("never")
+            .required(false),
+        Arg::with_name("api-max-body-size")
+            .long("api-max-body-size")
+            .help("Maximum size of API request bodies in bytes")
+            .takes_value(true)
+            .default_value("1048576")
+            .required(false)
+            .validator(|v| match v.parse::<usize>() {
+                Ok(s) if s > 0 => Ok(()),
+                _ => Err(format!("Invalid API request body size {}", v)),
+            }),
+        Arg::with_name("api-max-connections")
+            .long("api-max-connections")
+            .help("Maximum number of open API connections, more connections are closed")
+            .takes_value(true)
+            .default_value("16")
+            .required(false)
+            .validator(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(format!("Invalid number of API connections {}", v)),
+            }),
+        Arg::with_name("api-max-pending")
+            .long("api-max-pending")
+            .help("Maximum number of API requests being handled, more requests are rejected with 429")
+            .takes_value(true)
+            .default_value("64")
+            .required(false)
+            .validator(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(format!("Invalid number of pending API requests {}", v)),
+            }),
+        Arg::with_name("api-rate-limit")
+            .long("api-rate-limit")
+            .help("API requests per second allowed for each user, 0 for no limit")
+            .takes_value(true)
+            .default_value("0")
+            .required(false)
+            .validator(|v| {
+                v.parse::<u32>()
+                    .map(|_| ())
+                    .map_err(|_| format!("Invalid API rate limit {}", v))
+            }),
+        Arg::with_name("api-rate-burst")
+            .long("api-rate-burst")
+            .help("Burst of API requests allowed for each user, default to the rate limit")
+            .takes_value(true)
+            .default_value("0")
+            .required(false)
+            .validator(|v| {
+                v.parse::<u32>()
+                    .map(|_| ())
+                    .map_err(|_| format!("Invalid API rate burst {}", v))
+            }),
+        Arg::with_name("debug-api")
+            .long("debug-api")
+            .help("Serve CPU profiles and allocator statistics of nydusd by the API server")
+            .takes_value(false)
+            .required(false),
+        Arg::with_name("dump-dir")
+            .long("dump-dir")
+            .help("Directory to write snapshots of the daemon state requested by API clients to")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("disable-seccomp")
+            .long("disable-seccomp")
+            .help("Don't restrict syscalls of nydusd by seccomp after initialization")
+            .takes_value(false)
+            .required(false),
+        Arg::with_name("audit-log")
+            .long("audit-log")
+            .help("Append records of API requests changing state of nydusd to the file")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("trust-policy")
+            .long("trust-policy")
+            .help("Reject mounting images violating the content trust policy in the JSON file")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("mount-acl")
+            .long("mount-acl")
+            .help("Restrict mountpoints and sources of mounts requested by API clients as the JSON file")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("seeder-address")
+            .long("seeder-address")
+            .help("Serve cached blob data to peer nodes by HTTP on the address, e.g. 0.0.0.0:8100")
+            .takes_value(true)
+            .requires("seeder-token-file")
+            .required(false),
+        Arg::with_name("seeder-token-file")
+            .long("seeder-token-file")
+            .help("File containing the token peer nodes must present to read cached blob data")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("otlp-endpoint")
+            .long("otlp-endpoint")
+            .help("Export tracing spans to the OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("telemetry-endpoint")
+            .long("telemetry-endpoint")
+            .help("Opt in to report anonymized usage statistics to the HTTP endpoint, e.g. http://stats.example.com/nydusd")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("telemetry-interval")
+            .long("telemetry-interval")
+            .help("Interval in seconds between two telemetry reports")
+            .takes_value(true)
+            .default_value("3600")
+            .required(false)
+            .validator(|v| match v.parse::<u64>() {
+                Ok(i) if i > 0 => Ok(()),
+                _ => Err(format!("Invalid telemetry interval {}", v)),
+            }),
+        Arg::with_name("memory-limit")
+            .long("memory-limit")
+            .help("Cap of resident memory in bytes, reclaiming metadata, cache state and chunk buffers when exceeded")
+            .takes_value(true)
+            .required(false)
+            .validator(|v| match v.parse::<u64>() {
+                Ok(l) if l > 0 => Ok(()),
+                _ => Err(format!("Invalid memory limit {}", v)),
+            }),
+    ]
+}
 
-    let cmd_arguments = App::new("")
-        .version(bti_string.as_str())
-        .about("Nydus Image Service")
-        .arg(
-            Arg::with_name("apisock")
-                .long("apisock")
-                .short("A")
-                .help("Administration API socket")
-                .takes_value(true)
-                .required(false),
-        )
-        .arg(
-            Arg::with_name("config")
-                .long("config")
-                .short("C")
-                .help("Configuration file")
-                .takes_value(true)
-                .required(false)
-        )
-        .arg(
-            Arg::with_name("failover-policy")
-                .long("failover-policy")
-                .default_value("resend")
-                .help("Nydus image service failover policy")
-                .possible_values(&["resend", "flush"])
-                .takes_value(true)
-                .required(false)
-                .global(true),
-        )
-        .arg(
-            Arg::with_name("id")
-                .long("id")
-                .help("Nydus image service identifier")
-                .takes_value(true)
-                .required(false)
-                .requires("supervisor")
-                .global(true),
-        )
-        .arg(
-            Arg::with_name("log-level")
-                .long("log-level")
-                .short("l")
-                .help("Log level:")
-                .default_value("info")
-                .possible_values(&["trace", "debug", "info", "warn", "error"])
-                .takes_value(true)
-                .required(false)
-                .global(true),
-        )
-        .arg(
-            Arg::with_name("log-file")
-                .long("log-file")
-                .short("L")
-                .help("Log messages to the file. If file extension is not specified, the default extension \".log\" will be appended.")
-                .takes_value(true)
-                .required(false)
-                .global(true),
-        )
-        .arg(
-            Arg::with_name("prefetch-files")
-                .long("prefetch-files")
-                .help("List of file/directory to prefetch")
-                .takes_value(true)
-                .required(false)
-                .multiple(true)
-                .global(true),
-        )
-        .arg(
-            Arg::with_name("rlimit-nofile")
-                .long("rlimit-nofile")
-                .default_value("1,000,000")
-                .help("Tune the maximum number of file descriptors (0 leaves rlimit unchanged)")
-                .takes_value(true)
-                .required(false)
-                .global(true),
-        )
-        .arg(
-            Arg::with_name("supervisor")
-                .long("supervisor")
-                .short("S")
-                .help("Supervisor API socket")
-                .takes_value(true)
-                .required(false)
-                .requires("id")
-                .global(true),
-        )
-        .arg(
-            Arg::with_name("upgrade")
-                .long("upgrade")
-                .short("U")
-                .help("Start in upgrade mode")
-                .takes_value(false)
-                .required(false)
-                .global(true),
-        )
-        .arg(
-            Arg::with_name("virtual-mountpoint")
-                .long("virtual-mountpoint")
-                .short("V")
-                .help("Virtual mountpoint for the filesystem")
-                .takes_value(true)
-                .default_value("/")
-                .required(false)
-                .global(true),
-        ).arg(
-            Arg::with_name("bootstrap")
-                .long("bootstrap")
-                .short("B")
-                .help("Rafs filesystem bootstrap/metadata file")
-                .takes_value(true)
-                .conflicts_with("shared-dir")
-        )
-        .arg(
-            Arg::with_name("shared-dir")
-                .long("shared-dir")
-                .short("s")
-                .help("Directory to pass through to the guest VM")
-                .takes_value(true)
-                .conflicts_with("bootstrap"),
-        )
-        .arg(
-            Arg::with_name("hybrid-mode").long("hybrid-mode")
+/// Arguments to mount a filesystem when starting, for the FUSE and virtiofs modes.
+fn mount_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("prefetch-files")
+            .long("prefetch-files")
+            .help("List of file/directory to prefetch")
+            .takes_value(true)
+            .required(false)
+            .multiple(true),
+        Arg::with_name("virtual-mountpoint")
+            .long("virtual-mountpoint")
+            .short("V")
+            .help("Virtual mountpoint for the filesystem")
+            .takes_value(true)
+            .default_value("/")
+            .required(false),
+        Arg::with_name("bootstrap")
+            .long("bootstrap")
+            .short("B")
+            .help("Rafs filesystem bootstrap/metadata file")
+            .takes_value(true)
+            .conflicts_with("shared-dir"),
+        Arg::with_name("shared-dir")
+            .long("shared-dir")
+            .short("s")
+            .help("Directory to pass through to the guest VM")
+            .takes_value(true)
+            .conflicts_with("bootstrap"),
+        Arg::with_name("hybrid-mode")
+            .long("hybrid-mode")
             .help("run nydusd in rafs and passthroughfs hybrid mode")
             .required(false)
+            .takes_value(false),
+    ]
+}
+
+#[cfg(feature = "fusedev")]
+fn threads_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("threads")
+        .long("thread-num")
+        .short("T")
+        .default_value("1")
+        .help("Number of working threads to serve IO requests")
+        .takes_value(true)
+        .required(false)
+        .validator(|v| {
+            if let Ok(t) = v.parse::<i32>() {
+                if t > 0 && t <= 1024 {
+                    Ok(())
+                } else {
+                    Err("Invalid working thread number {}, valid values: [1-1024]".to_string())
+                }
+            } else {
+                Err("Input thread number is not legal".to_string())
+            }
+        })
+}
+
+/// Arguments of the FUSE mode, `legacy` for the command line without subcommands.
+#[cfg(feature = "fusedev")]
+fn fuse_args<'a, 'b>(legacy: bool) -> Vec<Arg<'a, 'b>> {
+    let mountpoint = Arg::with_name("mountpoint")
+        .long("mountpoint")
+        .short("M")
+        .help("Fuse mount point")
+        .takes_value(true);
+    let mountpoint = if legacy {
+        mountpoint.required_unless("fscache")
+    } else {
+        mountpoint.required(true)
+    };
+
+    vec![
+        mountpoint,
+        Arg::with_name("writable")
+            .long("writable")
+            .help("set fuse mountpoint non-readonly")
+            .takes_value(false),
+        Arg::with_name("upgrade")
+            .long("upgrade")
+            .short("U")
+            .help("Start in upgrade mode")
             .takes_value(false)
-            .global(true)
-        );
+            .required(false),
+        Arg::with_name("failover-policy")
+            .long("failover-policy")
+            .default_value("resend")
+            .help("Nydus image service failover policy")
+            .possible_values(&["resend", "flush"])
+            .takes_value(true)
+            .required(false),
+    ]
+}
 
-    #[cfg(feature = "fusedev")]
-    let cmd_arguments = cmd_arguments
-        .arg(
-            Arg::with_name("mountpoint")
-                .long("mountpoint")
-                .short("M")
-                .help("Fuse mount point")
-                .takes_value(true)
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("threads")
-                .long("thread-num")
-                .short("T")
-                .default_value("1")
-                .help("Number of working threads to serve IO requests")
-                .takes_value(true)
-                .required(false)
-                .global(true)
-                .validator(|v| {
-                    if let Ok(t) = v.parse::<i32>() {
-                        if t > 0 && t <= 1024 {
-                            Ok(())
-                        } else {
-                            Err("Invalid working thread number {}, valid values: [1-1024]"
-                                .to_string())
-                        }
-                    } else {
-                        Err("Input thread number is not legal".to_string())
-                    }
-                }),
-        )
-        .arg(
-            Arg::with_name("writable")
-                .long("writable")
-                .help("set fuse mountpoint non-readonly")
-                .takes_value(false),
-        );
+/// Arguments of the fscache mode, `legacy` for the command line without subcommands.
+#[cfg(feature = "fusedev")]
+fn fscache_args<'a, 'b>(legacy: bool) -> Vec<Arg<'a, 'b>> {
+    let fscache = Arg::with_name("fscache")
+        .long("fscache")
+        .help("Serve the in-kernel EROFS filesystem in fscache on-demand mode, with the specified cache directory")
+        .takes_value(true);
+    let fscache = if legacy {
+        fscache.conflicts_with_all(&["mountpoint", "shared-dir", "bootstrap", "upgrade"])
+    } else {
+        fscache.required(true)
+    };
 
-    #[cfg(feature = "virtiofs")]
-    let cmd_arguments = cmd_arguments.arg(
+    vec![
+        fscache,
+        Arg::with_name("fscache-tag")
+            .long("fscache-tag")
+            .help("Tag of the fscache cache to bind")
+            .takes_value(true)
+            .requires("fscache"),
+    ]
+}
+
+/// Arguments of the virtiofs mode.
+#[cfg(feature = "virtiofs")]
+fn virtiofs_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
         Arg::with_name("sock")
             .long("sock")
             .help("Vhost-user API socket")
             .takes_value(true)
             .required(true),
+        Arg::with_name("sock-mode")
+            .long("sock-mode")
+            .help("Permission bits of the vhost-user socket in octal")
+            .takes_value(true)
+            .default_value("0600")
+            .required(false)
+            .validator(|v| {
+                u32::from_str_radix(&v, 8)
+                    .ok()
+                    .filter(|m| *m <= 0o777)
+                    .map(|_| ())
+                    .ok_or_else(|| format!("Invalid socket mode {}", v))
+            }),
+        Arg::with_name("allowed-uids")
+            .long("allowed-uids")
+            .help("Uids of front-ends allowed to connect to the vhost-user socket, e.g. 0,107")
+            .takes_value(true)
+            .required(false)
+            .validator(|v| {
+                parse_id_list(&v)
+                    .map(|_| ())
+                    .map_err(|e| format!("Invalid uid list, {}", e))
+            }),
+        Arg::with_name("allowed-gids")
+            .long("allowed-gids")
+            .help("Gids of front-ends allowed to connect to the vhost-user socket, e.g. 0,107")
+            .takes_value(true)
+            .required(false)
+            .validator(|v| {
+                parse_id_list(&v)
+                    .map(|_| ())
+                    .map_err(|e| format!("Invalid gid list, {}", e))
+            }),
+        Arg::with_name("affinity")
+            .long("affinity")
+            .help("CPU list to pin virtio queue worker threads to, e.g. 0-3,8")
+            .takes_value(true)
+            .required(false)
+            .validator(|v| {
+                parse_cpu_list(&v)
+                    .map(|_| ())
+                    .map_err(|e| format!("Invalid CPU list, {}", e))
+            }),
+    ]
+}
+
+/// Build the command line parser, with a subcommand for each working mode.
+///
+/// All arguments of the supported modes are also accepted without subcommands, to be compatible
+/// with existing users, and the working mode is then deduced from the arguments.
+fn prepare_commandline_options(version: &str) -> App {
+    let app = App::new("")
+        .version(version)
+        .about("Nydus Image Service")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&common_args())
+        .args(&mount_args());
+
+    #[cfg(feature = "fusedev")]
+    let app = app
+        .args(&fuse_args(true))
+        .args(&fscache_args(true))
+        .arg(threads_arg())
+        .subcommand(
+            SubCommand::with_name("fuse")
+                .about("Serve filesystems by FUSE")
+                .args(&common_args())
+                .args(&mount_args())
+                .args(&fuse_args(false))
+                .arg(threads_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("fscache")
+                .about("Serve in-kernel EROFS filesystems in fscache on-demand mode")
+                .args(&common_args())
+                .args(&fscache_args(false))
+                .arg(threads_arg()),
+        );
+
+    #[cfg(feature = "virtiofs")]
+    let app = app.args(&virtiofs_args()).subcommand(
+        SubCommand::with_name("virtiofs")
+            .about("Serve filesystems by virtiofs to virtual machines through vhost-user")
+            .args(&common_args())
+            .args(&mount_args())
+            .args(&virtiofs_args()),
     );
 
-    let cmd_arguments_parsed = cmd_arguments.get_matches();
+    app
+}
 
-    let logging_file = cmd_arguments_parsed.value_of("log-file").map(|l| l.into());
+// Parse the log rotation size in MB into bytes.
+fn parse_log_rotation_size(v: &str) -> std::result::Result<u64, String> {
+    let size = v
+        .parse::<u64>()
+        .map_err(|e| format!("Invalid log rotation size, {}", e))?;
+    size.checked_mul(1 << 20)
+        .ok_or_else(|| format!("Log rotation size {}MB is too big", size))
+}
+
+/// Deduce the working mode from arguments of the command line without subcommands.
+#[cfg(feature = "fusedev")]
+fn legacy_mode(args: &ArgMatches) -> &'static str {
+    if args.is_present("fscache") {
+        "fscache"
+    } else {
+        "fuse"
+    }
+}
+
+/// Deduce the working mode from arguments of the command line without subcommands.
+#[cfg(feature = "virtiofs")]
+fn legacy_mode(_args: &ArgMatches) -> &'static str {
+    "virtiofs"
+}
+
+fn main() -> Result<()> {
+    let (bti_string, bti) = BuildTimeInfo::dump(crate_version!());
+
+    let cmd_arguments_parsed = prepare_commandline_options(bti_string.as_str()).get_matches();
+    let (mode, args) = match cmd_arguments_parsed.subcommand() {
+        (mode, Some(args)) => (mode, args),
+        _ => (legacy_mode(&cmd_arguments_parsed), &cmd_arguments_parsed),
+    };
+    // The helper is forked while nydusd has a single thread, before logging starts threads.
+    if !args.is_present("disable-seccomp") {
+        stargz::start_builder_helper()?;
+    }
+
+    let logging_file = args.value_of("log-file").map(|l| l.into());
     // Safe to unwrap because it has default value and possible values are defined
-    let level = cmd_arguments_parsed
-        .value_of("log-level")
-        .unwrap()
-        .parse()
-        .unwrap();
-    setup_logging(logging_file, level)?;
+    let level = args.value_of("log-level").unwrap().parse().unwrap();
+    // Safe to unwrap because they have default values and are validated
+    let logging_options = LoggingOptions {
+        format: args.value_of("log-format").unwrap().parse().unwrap(),
+        rotation_size: parse_log_rotation_size(args.value_of("log-rotation-size").unwrap())
+            .unwrap(),
+        rotation_age: args
+            .value_of("log-rotation-age")
+            .map(|v| v.parse().unwrap()),
+        keep_files: args.value_of("log-keep-files").unwrap().parse().unwrap(),
+    };
+    setup_logging_with_options(logging_file, level, &logging_options)?;
 
     dump_program_info(crate_version!());
+    info!("nydusd starts in {} mode", mode);
 
     // Retrieve arguments
     // shared-dir means fs passthrough
-    let shared_dir = cmd_arguments_parsed.value_of("shared-dir");
+    let shared_dir = args.value_of("shared-dir");
     // bootstrap means rafs only
-    let bootstrap = cmd_arguments_parsed.value_of("bootstrap");
-    // safe as virtual_mountpoint default to "/"
-    let virtual_mnt = cmd_arguments_parsed.value_of("virtual-mountpoint").unwrap();
+    let bootstrap = args.value_of("bootstrap");
+    // virtual_mountpoint defaults to "/", and isn't available in fscache mode
+    let virtual_mnt = args.value_of("virtual-mountpoint").unwrap_or("/");
     // apisock means admin api socket support
-    let apisock = cmd_arguments_parsed.value_of("apisock");
+    let apisock = args.value_of("apisock");
     let rlimit_nofile_default = get_default_rlimit_nofile()?;
-    let rlimit_nofile: rlim = cmd_arguments_parsed
+    let rlimit_nofile: rlim = args
         .value_of("rlimit-nofile")
         .map(|n| n.parse().unwrap_or(rlimit_nofile_default))
         .unwrap_or(rlimit_nofile_default);
 
+    // The configuration file provides defaults for all Rafs mounts.
+    if let Some(config) = args.value_of("config") {
+        set_default_fs_config(&std::fs::read_to_string(config)?)?;
+    }
+    if let Some(dir) = args.value_of("dump-dir") {
+        set_dump_dir(dir)?;
+    }
+
     let mut opts = VfsOptions::default();
     let mount_cmd = if let Some(shared_dir) = shared_dir {
         if rlimit_nofile != 0 {
@@ -334,6 +657,8 @@ fn main() -> Result<()> {
             config: "".to_string(),
             mountpoint: virtual_mnt.to_string(),
             prefetch_files: None,
+            labels: HashMap::new(),
+            source_file: None,
         };
 
         // passthroughfs requires !no_open
@@ -342,11 +667,11 @@ fn main() -> Result<()> {
 
         Some(cmd)
     } else if let Some(b) = bootstrap {
-        let config = cmd_arguments_parsed.value_of("config").ok_or_else(|| {
+        let config = args.value_of("config").ok_or_else(|| {
             DaemonError::InvalidArguments("config file is not provided".to_string())
         })?;
 
-        let prefetch_files: Option<Vec<String>> = cmd_arguments_parsed
+        let prefetch_files: Option<Vec<String>> = args
             .values_of("prefetch-files")
             .map(|files| files.map(|s| s.to_string()).collect());
 
@@ -356,6 +681,8 @@ fn main() -> Result<()> {
             config: std::fs::read_to_string(config)?,
             mountpoint: virtual_mnt.to_string(),
             prefetch_files,
+            labels: HashMap::new(),
+            source_file: None,
         };
 
         // rafs can be readonly and skip open
@@ -367,11 +694,35 @@ fn main() -> Result<()> {
     };
 
     // Enable all options required by passthroughfs
-    if cmd_arguments_parsed.is_present("hybrid-mode") {
+    if args.is_present("hybrid-mode") {
         opts.no_open = false;
         opts.killpriv_v2 = true;
     }
 
+    // Safe to unwrap because the value has been validated and has a default value.
+    let hugepage = args.value_of("hugepage").unwrap();
+    set_hugepage_mode(HugePageMode::from_str(hugepage).unwrap());
+
+    if let Some(path) = args.value_of("trust-policy") {
+        let policy = TrustPolicy::from_file(path).map_err(|e| {
+            error!("Failed to load trust policy {}, {}", path, e);
+            e
+        })?;
+        set_trust_policy(policy);
+    }
+
+    if let Some(path) = args.value_of("mount-acl") {
+        let acl = MountAcl::from_file(path).map_err(|e| {
+            error!("Failed to load mount acl {}, {}", path, e);
+            e
+        })?;
+        set_mount_acl(acl);
+    }
+
+    if let Some(endpoint) = args.value_of("otlp-endpoint") {
+        tracing::init(endpoint, "nydusd")?;
+    }
+
     let vfs = Vfs::new(opts);
 
     let mut event_manager = EventManager::<Arc<dyn EventSubscriber>>::new().unwrap();
@@ -382,28 +733,45 @@ fn main() -> Result<()> {
 
     let vfs = Arc::new(vfs);
     // Basically, below two arguments are essential for live-upgrade/failover/ and external management.
-    let daemon_id = cmd_arguments_parsed.value_of("id").map(|id| id.to_string());
-    let supervisor = cmd_arguments_parsed
-        .value_of("supervisor")
-        .map(|s| s.to_string());
+    let daemon_id = args.value_of("id").map(|id| id.to_string());
+    let supervisor = args.value_of("supervisor").map(|s| s.to_string());
 
     #[cfg(feature = "virtiofs")]
     let daemon = {
         // sock means vhost-user-backend only
-        let vu_sock = cmd_arguments_parsed.value_of("sock").ok_or_else(|| {
+        let vu_sock = args.value_of("sock").ok_or_else(|| {
             DaemonError::InvalidArguments("vhost socket must be provided!".to_string())
         })?;
-        create_nydus_daemon(daemon_id, supervisor, vu_sock, vfs, mount_cmd, bti)?
+        // Safe to unwrap because the CPU list has been validated.
+        let affinity = args
+            .value_of("affinity")
+            .map(|v| parse_cpu_list(v).unwrap())
+            .unwrap_or_default();
+        // Safe to unwrap because the mode and id lists have been validated.
+        let access = SockAccess {
+            mode: u32::from_str_radix(args.value_of("sock-mode").unwrap(), 8).unwrap(),
+            allowed_uids: args
+                .value_of("allowed-uids")
+                .map(|v| parse_id_list(v).unwrap())
+                .unwrap_or_default(),
+            allowed_gids: args
+                .value_of("allowed-gids")
+                .map(|v| parse_id_list(v).unwrap())
+                .unwrap_or_default(),
+        };
+        create_nydus_daemon(
+            daemon_id, supervisor, vu_sock, access, vfs, mount_cmd, affinity, bti,
+        )?
     };
     #[cfg(feature = "fusedev")]
     let daemon = {
         // threads means number of fuse service threads
-        let threads: u32 = cmd_arguments_parsed
+        let threads: u32 = args
             .value_of("threads")
             .map(|n| n.parse().unwrap_or(1))
             .unwrap_or(1);
 
-        let p = cmd_arguments_parsed
+        let p = args
             .value_of("failover-policy")
             .unwrap_or("flush")
             .try_into()
@@ -412,51 +780,121 @@ fn main() -> Result<()> {
                 e
             })?;
 
-        // mountpoint means fuse device only
-        let mountpoint = cmd_arguments_parsed.value_of("mountpoint").ok_or_else(|| {
-            DaemonError::InvalidArguments("Mountpoint must be provided!".to_string())
-        })?;
+        if mode == "fscache" {
+            // Safe to unwrap because the cache directory is required in fscache mode.
+            let dir = args.value_of("fscache").unwrap();
+            // Images are registered by the mount API with their fsids.
+            if apisock.is_none() {
+                return Err(DaemonError::InvalidArguments(
+                    "API socket is required in fscache mode".to_string(),
+                )
+                .into());
+            }
+            create_fscache_daemon(
+                dir,
+                args.value_of("fscache-tag"),
+                supervisor,
+                daemon_id,
+                threads,
+                bti,
+            )
+            .map(|d| {
+                info!("Fscache daemon started!");
+                d
+            })
+            .map_err(|e| {
+                error!("Failed in starting daemon, {}", e);
+                e
+            })?
+        } else {
+            // mountpoint means fuse device only
+            let mountpoint = args.value_of("mountpoint").ok_or_else(|| {
+                DaemonError::InvalidArguments("Mountpoint must be provided!".to_string())
+            })?;
 
-        create_nydus_daemon(
-            mountpoint,
-            vfs,
-            supervisor,
-            daemon_id,
-            threads,
-            apisock,
-            cmd_arguments_parsed.is_present("upgrade"),
-            !cmd_arguments_parsed.is_present("writable"),
-            p,
-            mount_cmd,
-            bti,
-        )
-        .map(|d| {
-            info!("Fuse daemon started!");
-            d
-        })
-        .map_err(|e| {
-            error!("Failed in starting daemon, {}", e);
-            e
-        })?
+            create_nydus_daemon(
+                mountpoint,
+                vfs,
+                supervisor,
+                daemon_id,
+                threads,
+                apisock,
+                args.is_present("upgrade"),
+                !args.is_present("writable"),
+                p,
+                mount_cmd,
+                bti,
+            )
+            .map(|d| {
+                info!("Fuse daemon started!");
+                d
+            })
+            .map_err(|e| {
+                error!("Failed in starting daemon, {}", e);
+                e
+            })?
+        }
     };
 
+    if let Some(address) = args.value_of("seeder-address") {
+        // Safe to unwrap because `seeder-address` requires `seeder-token-file`.
+        let token_file = args.value_of("seeder-token-file").unwrap();
+        seeder::start_seeder(address, token_file).map_err(|e| {
+            error!("Failed to start seeder on {}, {}", address, e);
+            e
+        })?;
+    }
+
+    if let Some(endpoint) = args.value_of("telemetry-endpoint") {
+        // Safe to unwrap because the interval has a default value and has been validated.
+        let interval = args
+            .value_of("telemetry-interval")
+            .unwrap()
+            .parse()
+            .unwrap();
+        telemetry::start_telemetry(daemon.clone(), endpoint, Duration::from_secs(interval))?;
+    }
+
+    if let Some(limit) = args.value_of("memory-limit") {
+        // Safe to unwrap because the limit has been validated.
+        start_memory_monitor(daemon.clone(), limit.parse().unwrap())?;
+    }
+
     let mut http_thread: Option<thread::JoinHandle<Result<()>>> = None;
     let http_exit_evtfd = EventFd::new(0).unwrap();
     if let Some(apisock) = apisock {
         let (to_api, from_http) = channel();
-        let (to_http, from_api) = channel();
 
-        let api_server = ApiServer::new(to_http, daemon.clone())?;
+        let audit_log = match args.value_of("audit-log") {
+            Some(path) => Some(AuditLog::open(path).map_err(|e| {
+                error!("Failed to open audit log {}, {}", path, e);
+                e
+            })?),
+            None => None,
+        };
+        let api_server = ApiServer::new(daemon.clone(), args.is_present("debug-api"), audit_log)?;
 
         let api_server_subscriber = Arc::new(ApiSeverSubscriber::new(api_server, from_http)?);
         let evtfd = api_server_subscriber.get_event_fd()?;
         event_manager.add_subscriber(api_server_subscriber);
+        // Safe to unwrap because arguments have default values and have been validated.
+        let limits = HttpLimits {
+            max_body_size: args.value_of("api-max-body-size").unwrap().parse().unwrap(),
+            max_connections: args
+                .value_of("api-max-connections")
+                .unwrap()
+                .parse()
+                .unwrap(),
+            max_pending_requests: args.value_of("api-max-pending").unwrap().parse().unwrap(),
+            rate: args.value_of("api-rate-limit").unwrap().parse().unwrap(),
+            burst: args.value_of("api-rate-burst").unwrap().parse().unwrap(),
+        };
         let ret = start_http_thread(
             apisock,
             evtfd,
             to_api,
-            from_api,
             http_exit_evtfd.try_clone().unwrap(),
+            limits,
         )?;
         http_thread = Some(ret);
         info!("api server running at {}", apisock);
@@ -466,6 +904,10 @@ fn main() -> Result<()> {
     nydus_app::signal::register_signal_handler(signal::SIGINT, sig_exit);
     nydus_app::signal::register_signal_handler(signal::SIGTERM, sig_exit);
 
+    if !args.is_present("disable-seccomp") {
+        seccomp::apply_seccomp_filter()?;
+    }
+
     while EVENT_MANAGER_RUN.load(Ordering::Relaxed) {
         // If event manager dies, so does nydusd
         event_manager.run().unwrap();
@@ -483,7 +925,80 @@ fn main() -> Result<()> {
 
     daemon.stop().unwrap_or_else(|e| error!("{}", e));
     daemon.wait().unwrap_or_else(|e| error!("{}", e));
+    daemon.flush_caches().unwrap_or_else(|e| error!("{}", e));
+    tracing::shutdown();
     info!("nydusd quits");
 
     Ok(())
 }
+
+#[cfg(all(test, feature = "fusedev"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subcommands() {
+        let app = prepare_commandline_options("0.1.0");
+
+        let matches = app
+            .clone()
+            .get_matches_from_safe(vec![
+                "nydusd",
+                "fuse",
+                "--mountpoint",
+                "/mnt",
+                "--log-level",
+                "debug",
+                "--thread-num",
+                "4",
+            ])
+            .unwrap();
+        let (mode, args) = matches.subcommand();
+        assert_eq!(mode, "fuse");
+        let args = args.unwrap();
+        assert_eq!(args.value_of("mountpoint"), Some("/mnt"));
+        assert_eq!(args.value_of("log-level"), Some("debug"));
+        assert_eq!(args.value_of("threads"), Some("4"));
+
+        let matches = app
+            .clone()
+            .get_matches_from_safe(vec!["nydusd", "fscache", "--fscache", "/cache"])
+            .unwrap();
+        let args = matches.subcommand_matches("fscache").unwrap();
+        assert_eq!(args.value_of("fscache"), Some("/cache"));
+        assert!(args.value_of("bootstrap").is_none());
+        // Generic arguments with default values are available in all modes.
+        assert_eq!(args.value_of("hugepage"), Some("never"));
+        assert_eq!(args.value_of("api-max-pending"), Some("64"));
+        assert_eq!(args.value_of("api-max-connections"), Some("16"));
+
+        // Required arguments and arguments of other modes are checked by subcommands.
+        assert!(app
+            .clone()
+            .get_matches_from_safe(vec!["nydusd", "fuse"])
+            .is_err());
+        assert!(app
+            .clone()
+            .get_matches_from_safe(vec!["nydusd", "fscache", "--fscache", "/c", "-M", "/mnt"])
+            .is_err());
+
+        // The command line without subcommands is still supported.
+        let matches = app
+            .clone()
+            .get_matches_from_safe(vec!["nydusd", "--mountpoint", "/mnt"])
+            .unwrap();
+        assert_eq!(legacy_mode(&matches), "fuse");
+        let matches = app
+            .get_matches_from_safe(vec!["nydusd", "--fscache", "/cache"])
+            .unwrap();
+        assert_eq!(legacy_mode(&matches), "fscache");
+    }
+
+    #[test]
+    fn test_parse_log_rotation_size() {
+        assert_eq!(parse_log_rotation_size("0").unwrap(), 0);
+        assert_eq!(parse_log_rotation_size("2").unwrap(), 2 << 20);
+        assert!(parse_log_rotation_size("-1").is_err());
+        assert!(parse_log_rotation_size(&(u64::MAX >> 19).to_string()).is_err());
+    }
+}
@@ -0,0 +1,452 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Content trust policy of images to mount.
+//!
+//! The policy is composed of rules matched against the registry and repository of the image,
+//! which may require the bootstrap to be signed, restrict digest algorithms of the image and limit
+//! the size of the image. Rafs mounts and remounts violating the policy are rejected.
+//!
+//! The mount access control list restricts mountpoints and sources of mounts requested by API
+//! clients, so a compromised client can't mount over sensitive paths or expose arbitrary files.
+
+use std::fs::{self, File};
+use std::io::{Result, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use nydus::FsBackendType;
+use nydus_utils::digest;
+use rafs::fs::RafsConfig;
+use rafs::metadata::{RafsMode, RafsSuper};
+use rafs::RafsIoReader;
+use serde::Deserialize;
+use storage::device::BlobFeatures;
+
+use crate::daemon::{DaemonError, DaemonResult};
+use crate::snapshot::is_remote_source;
+
+lazy_static! {
+    static ref TRUST_POLICY: RwLock<Option<Arc<TrustPolicy>>> = RwLock::new(None);
+    static ref MOUNT_ACL: RwLock<Option<Arc<MountAcl>>> = RwLock::new(None);
+}
+
+/// Requirements on images from a registry and repository.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PolicyRule {
+    /// Registry host the rule applies to, empty or `*` to match all registries.
+    pub registry: String,
+    /// Repository the rule applies to, empty or ending with `*` to match by prefix.
+    pub repo: String,
+    /// Require the bootstrap to be signed.
+    pub require_signature: bool,
+    /// Digest algorithms allowed for the image, empty to allow all algorithms.
+    pub allowed_digesters: Vec<String>,
+    /// Maximum size of the bootstrap and compressed blobs in bytes, 0 for no limit.
+    pub max_image_size: u64,
+}
+
+impl PolicyRule {
+    fn matches(&self, registry: &str, repo: &str) -> bool {
+        Self::match_pattern(&self.registry, registry) && Self::match_pattern(&self.repo, repo)
+    }
+
+    fn match_pattern(pattern: &str, value: &str) -> bool {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => pattern.is_empty() || pattern == value,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        for d in self.allowed_digesters.iter() {
+            digest::Algorithm::from_str(d)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Content trust policy, the first rule matching the image applies, or the default one.
+#[derive(Debug, Default, Deserialize)]
+pub struct TrustPolicy {
+    #[serde(default)]
+    pub default: PolicyRule,
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+impl TrustPolicy {
+    /// Load the trust policy from a JSON file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let file = File::open(path)?;
+        let policy: TrustPolicy = serde_json::from_reader(file)
+            .map_err(|e| einval!(format!("failed to parse trust policy, {}", e)))?;
+        policy.default.validate()?;
+        for rule in policy.rules.iter() {
+            rule.validate()?;
+        }
+
+        Ok(policy)
+    }
+
+    fn rule(&self, registry: &str, repo: &str) -> &PolicyRule {
+        self.rules
+            .iter()
+            .find(|r| r.matches(registry, repo))
+            .unwrap_or(&self.default)
+    }
+
+    /// Check the image to mount from `bootstrap` by `config` against the policy.
+    ///
+    /// The bootstrap is rewound to the start afterwards, so the checked bootstrap can be loaded
+    /// from the same file.
+    pub fn evaluate(&self, config: &RafsConfig, bootstrap: &mut RafsIoReader) -> DaemonResult<()> {
+        let backend = &config.device.backend;
+        let (registry, repo) = if backend.backend_type == "registry" {
+            (
+                backend.backend_config["host"].as_str().unwrap_or_default(),
+                backend.backend_config["repo"].as_str().unwrap_or_default(),
+            )
+        } else {
+            ("", "")
+        };
+        let rule = self.rule(registry, repo);
+        let violation = |msg: String| {
+            DaemonError::PolicyViolation(format!("image {}/{}: {}", registry, repo, msg))
+        };
+
+        if rule.require_signature && config.verify_signature.is_none() {
+            return Err(violation("signature verification is required".to_string()));
+        }
+        if rule.allowed_digesters.is_empty() && rule.max_image_size == 0 {
+            return Ok(());
+        }
+
+        let read_err =
+            |e| DaemonError::Rafs(rafs::RafsError::ReadMetadata(e, "bootstrap".to_string()));
+        let mut sb = RafsSuper {
+            mode: RafsMode::Direct,
+            ..Default::default()
+        };
+        let loaded = sb.load(bootstrap);
+        let bootstrap_size = bootstrap.seek(SeekFrom::End(0)).map_err(read_err)?;
+        bootstrap.seek(SeekFrom::Start(0)).map_err(read_err)?;
+        loaded.map_err(|e| DaemonError::Rafs(rafs::RafsError::FillSuperblock(e)))?;
+
+        let digester = sb.meta.get_digester();
+        if !rule.allowed_digesters.is_empty()
+            && !rule
+                .allowed_digesters
+                .iter()
+                .any(|d| digest::Algorithm::from_str(d).ok() == Some(digester))
+        {
+            return Err(violation(format!(
+                "digest algorithm {} is not allowed",
+                digester
+            )));
+        }
+
+        if rule.max_image_size > 0 {
+            let mut size = bootstrap_size;
+            for blob in sb.superblock.get_blob_infos() {
+                // Sizes of blobs are only recorded in the extended blob table of Rafs v5.
+                if blob.has_feature(BlobFeatures::V5_NO_EXT_BLOB_TABLE) {
+                    return Err(violation(format!(
+                        "size of blob {} is unknown",
+                        blob.blob_id()
+                    )));
+                }
+                size = size.saturating_add(blob.compressed_size());
+            }
+            if size > rule.max_image_size {
+                return Err(violation(format!(
+                    "image size {} exceeds limit {}",
+                    size, rule.max_image_size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Set the process wide trust policy, which should be set before mounting any filesystem.
+pub fn set_trust_policy(policy: TrustPolicy) {
+    *TRUST_POLICY.write().unwrap() = Some(Arc::new(policy));
+}
+
+/// Check the image to mount against the process wide trust policy if there's one.
+///
+/// The bootstrap must be loaded from `bootstrap` afterwards, instead of opening it again.
+pub fn check_trust_policy(config: &RafsConfig, bootstrap: &mut RafsIoReader) -> DaemonResult<()> {
+    let policy = TRUST_POLICY.read().unwrap().clone();
+    match policy {
+        Some(policy) => policy.evaluate(config, bootstrap),
+        None => Ok(()),
+    }
+}
+
+/// Restrictions on mounts requested by API clients.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MountAcl {
+    /// Prefixes of mountpoints allowed, empty to allow all mountpoints.
+    pub allowed_mountpoints: Vec<String>,
+    /// Prefixes of local source paths allowed, empty to allow all sources.
+    pub allowed_sources: Vec<String>,
+}
+
+impl MountAcl {
+    /// Load the mount access control list from a JSON file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut acl: MountAcl = serde_json::from_reader(file)
+            .map_err(|e| einval!(format!("failed to parse mount acl, {}", e)))?;
+        for prefix in acl
+            .allowed_mountpoints
+            .iter()
+            .chain(acl.allowed_sources.iter())
+        {
+            if !Path::new(prefix).is_absolute() {
+                return Err(einval!(format!(
+                    "prefix {} is not an absolute path",
+                    prefix
+                )));
+            }
+        }
+        // Sources are resolved before being checked, so resolve the prefixes too.
+        for prefix in acl.allowed_sources.iter_mut() {
+            if let Ok(path) = fs::canonicalize(&prefix) {
+                *prefix = path.to_string_lossy().to_string();
+            }
+        }
+
+        Ok(acl)
+    }
+
+    fn allowed(prefixes: &[String], path: &Path) -> bool {
+        prefixes.is_empty() || prefixes.iter().any(|p| path.starts_with(p))
+    }
+
+    /// Check the mount of `source` at `mountpoint` against the access control list.
+    ///
+    /// Sources referring to the storage backend, such as `manifest://` and `blob://` and stargz
+    /// layer digests, are subject to the trust policy instead. Local sources are opened and the
+    /// opened file is resolved before being checked, so symbolic links can't escape the allowed
+    /// directories. The opened file is returned to be mounted, instead of opening `source` again.
+    pub fn check(
+        &self,
+        mountpoint: &str,
+        fs_type: &FsBackendType,
+        source: &str,
+    ) -> DaemonResult<Option<File>> {
+        let mp = Path::new(mountpoint);
+        if !mp.is_absolute() || mp.components().any(|c| c == Component::ParentDir) {
+            return Err(DaemonError::InvalidArguments(format!(
+                "invalid mountpoint {}",
+                mountpoint
+            )));
+        }
+        if !Self::allowed(&self.allowed_mountpoints, mp) {
+            return Err(DaemonError::PolicyViolation(format!(
+                "mountpoint {} is not allowed",
+                mountpoint
+            )));
+        }
+
+        let remote = match fs_type {
+            FsBackendType::Rafs => is_remote_source(source),
+            FsBackendType::Stargz => true,
+            FsBackendType::PassthroughFs => false,
+        };
+        if self.allowed_sources.is_empty() || source.is_empty() || remote {
+            return Ok(None);
+        }
+        let file = File::open(source).map_err(|e| {
+            DaemonError::InvalidArguments(format!("invalid source {}, {}", source, e))
+        })?;
+        let path = fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).map_err(|e| {
+            DaemonError::InvalidArguments(format!("failed to resolve source {}, {}", source, e))
+        })?;
+        if !Self::allowed(&self.allowed_sources, &path) {
+            return Err(DaemonError::PolicyViolation(format!(
+                "source {} is not allowed",
+                source
+            )));
+        }
+
+        Ok(Some(file))
+    }
+}
+
+/// Set the process wide mount access control list for mounts requested by API clients.
+pub fn set_mount_acl(acl: MountAcl) {
+    *MOUNT_ACL.write().unwrap() = Some(Arc::new(acl));
+}
+
+/// Check the mount requested by API clients against the mount access control list if there's one.
+///
+/// Return the opened local source if it has been checked, which must be mounted instead of the
+/// source path.
+pub fn check_mount_acl(
+    mountpoint: &str,
+    fs_type: &FsBackendType,
+    source: &str,
+) -> DaemonResult<Option<File>> {
+    let acl = MOUNT_ACL.read().unwrap().clone();
+    match acl {
+        Some(acl) => acl.check(mountpoint, fs_type, source),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rafs::RafsIoRead;
+    use std::path::PathBuf;
+
+    fn test_config(signed: bool) -> RafsConfig {
+        let signature = if signed {
+            r#", "verify_signature": {"public_key": "/path/to/key.pem"}"#
+        } else {
+            ""
+        };
+        let config = format!(
+            r#"{{
+                "device": {{
+                    "backend": {{
+                        "type": "registry",
+                        "config": {{"host": "docker.io", "repo": "library/busybox"}}
+                    }}
+                }},
+                "mode": "direct"{}
+            }}"#,
+            signature
+        );
+        RafsConfig::from_str(&config).unwrap()
+    }
+
+    fn test_bootstrap() -> PathBuf {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        PathBuf::from(root_dir).join("tests/texture/bootstrap/image_v2.boot")
+    }
+
+    #[test]
+    fn test_match_rule() {
+        let policy: TrustPolicy = serde_json::from_str(
+            r#"{
+                "default": {"require_signature": true},
+                "rules": [
+                    {"registry": "docker.io", "repo": "library/*"},
+                    {"registry": "*", "repo": "internal", "max_image_size": 1}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(
+            !policy
+                .rule("docker.io", "library/busybox")
+                .require_signature
+        );
+        assert!(policy.rule("docker.io", "busybox").require_signature);
+        assert_eq!(policy.rule("example.com", "internal").max_image_size, 1);
+        assert!(policy.rule("", "").require_signature);
+    }
+
+    #[test]
+    fn test_evaluate_policy() {
+        let mut bootstrap = <dyn RafsIoRead>::from_file(test_bootstrap()).unwrap();
+        let mut policy = TrustPolicy::default();
+        policy
+            .evaluate(&test_config(false), &mut bootstrap)
+            .unwrap();
+
+        policy.default.require_signature = true;
+        assert!(matches!(
+            policy.evaluate(&test_config(false), &mut bootstrap),
+            Err(DaemonError::PolicyViolation(_))
+        ));
+        policy.evaluate(&test_config(true), &mut bootstrap).unwrap();
+
+        policy.default.max_image_size = 1;
+        assert!(matches!(
+            policy.evaluate(&test_config(true), &mut bootstrap),
+            Err(DaemonError::PolicyViolation(_))
+        ));
+
+        policy.default.max_image_size = 0;
+        policy.default.allowed_digesters = vec!["blake3".to_string(), "sha256".to_string()];
+        policy.evaluate(&test_config(true), &mut bootstrap).unwrap();
+        // The bootstrap is rewound to be loaded after being checked.
+        assert_eq!(bootstrap.seek(SeekFrom::Current(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_mount_acl() {
+        let bootstrap = test_bootstrap();
+        let texture = bootstrap.parent().unwrap().parent().unwrap();
+        let allowed = fs::canonicalize(texture.join("bootstrap")).unwrap();
+        let acl = MountAcl {
+            allowed_mountpoints: vec!["/images".to_string()],
+            allowed_sources: vec![allowed.to_str().unwrap().to_string()],
+        };
+
+        let source = bootstrap.to_str().unwrap();
+        let rafs = &FsBackendType::Rafs;
+        let file = acl.check("/images", rafs, source).unwrap().unwrap();
+        assert_eq!(
+            file.metadata().unwrap().len(),
+            fs::metadata(&bootstrap).unwrap().len()
+        );
+        acl.check("/images/sub", rafs, source).unwrap().unwrap();
+        assert!(acl
+            .check("/images/sub", rafs, "manifest://latest")
+            .unwrap()
+            .is_none());
+        assert!(acl
+            .check("/images/sub", &FsBackendType::Stargz, "sha256:abcd")
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            acl.check("/imagesx", rafs, source),
+            Err(DaemonError::PolicyViolation(_))
+        ));
+        assert!(matches!(
+            acl.check("/images/../etc", rafs, source),
+            Err(DaemonError::InvalidArguments(_))
+        ));
+        assert!(matches!(
+            acl.check(
+                "/images/sub",
+                rafs,
+                texture.join("bootstrap/../").to_str().unwrap()
+            ),
+            Err(DaemonError::PolicyViolation(_))
+        ));
+        assert!(matches!(
+            acl.check("/images/sub", rafs, "/nonexistent/bootstrap"),
+            Err(DaemonError::InvalidArguments(_))
+        ));
+        // Local paths looking like remote sources are still checked.
+        let fake = texture.join("bootstrap/x://../../");
+        assert!(acl
+            .check("/images/sub", rafs, fake.to_str().unwrap())
+            .is_err());
+        assert!(matches!(
+            acl.check("/images/sub", &FsBackendType::PassthroughFs, "/etc"),
+            Err(DaemonError::PolicyViolation(_))
+        ));
+
+        assert!(MountAcl::default()
+            .check("/etc", rafs, "/etc/passwd")
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Warm up the blob cache in background before images are mounted.
+//!
+//! Schedulers know which images a pod needs before placing it on a node, so the node may fetch
+//! data of the images ahead of the pod start. A preheat task fetches files of images, either all
+//! files or those recorded in a trace of the image, e.g. by `/api/v1/metrics/access`, and ranges
+//! of blobs already known by the daemon. Tasks are queued and run in background one by one, and
+//! their status is kept for pending tasks and the most recent finished tasks.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use nydus::{FsBackendType, LABEL_IMAGE_REF};
+use nydus_api::http_endpoint::{ApiPreheatBlob, ApiPreheatCmd, ApiPreheatImage};
+use nydus_utils::metrics::ERROR_HOLDER;
+use rafs::fs::{Rafs, RafsConfig};
+use rafs::RafsIoRead;
+use serde::Serialize;
+use storage::factory::BLOB_FACTORY;
+
+use crate::daemon::{effective_fs_config, DaemonError, DaemonResult, FsBackendMountCmd};
+use crate::policy::check_trust_policy;
+use crate::snapshot;
+
+/// Number of recent finished tasks to keep status for.
+const MAX_TASKS: usize = 16;
+/// Maximum number of tasks waiting to run.
+const MAX_PENDING_TASKS: usize = 16;
+
+// Fetch a range of a blob into the blob cache, return the amount of data fetched.
+type BlobFetcher = fn(&ApiPreheatBlob) -> DaemonResult<usize>;
+
+struct PreheatJob {
+    task: Arc<PreheatTask>,
+    cmd: ApiPreheatCmd,
+    fetch_blob: BlobFetcher,
+}
+
+lazy_static! {
+    static ref TASKS: Mutex<VecDeque<Arc<PreheatTask>>> = Mutex::new(VecDeque::new());
+    // Tasks are run one by one by a single worker, to bound the load on the node and the storage
+    // backend.
+    static ref QUEUE: Mutex<Option<SyncSender<PreheatJob>>> = Mutex::new(None);
+}
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum TaskState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct TaskStatus {
+    id: u64,
+    state: TaskState,
+    /// Amount of data fetched from storage backends, in unit of Byte.
+    fetched_bytes: u64,
+    errors: Vec<String>,
+}
+
+struct PreheatTask {
+    status: Mutex<TaskStatus>,
+}
+
+impl PreheatTask {
+    fn update<F: FnOnce(&mut TaskStatus)>(&self, f: F) {
+        f(&mut self.status.lock().unwrap())
+    }
+
+    fn is_finished(&self) -> bool {
+        let state = self.status.lock().unwrap().state;
+        state == TaskState::Done || state == TaskState::Failed
+    }
+
+    fn run(&self, cmd: ApiPreheatCmd, fetch_blob: BlobFetcher) {
+        self.update(|s| s.state = TaskState::Running);
+
+        // Filesystems are kept until blob ranges are fetched, so blob caches of the images can be
+        // found by blob ids.
+        let mut filesystems = Vec::with_capacity(cmd.images.len());
+        match effective_fs_config(&cmd.config) {
+            Ok(config) => {
+                for image in cmd.images.iter() {
+                    match preheat_image(&config, image) {
+                        Ok((rafs, size)) => {
+                            self.update(|s| s.fetched_bytes += size as u64);
+                            filesystems.push(rafs);
+                        }
+                        Err(e) => self
+                            .update(|s| s.errors.push(format!("image {}: {}", image.reference, e))),
+                    }
+                }
+            }
+            Err(e) => self.update(|s| s.errors.push(format!("invalid config: {}", e))),
+        }
+
+        for blob in cmd.blobs.iter() {
+            match fetch_blob(blob) {
+                Ok(size) => self.update(|s| s.fetched_bytes += size as u64),
+                Err(e) => self.update(|s| s.errors.push(format!("blob {}: {}", blob.blob_id, e))),
+            }
+        }
+
+        for rafs in filesystems.iter() {
+            if let Err(e) = rafs.flush() {
+                warn!("failed to flush blob caches of preheated image, {}", e);
+            }
+        }
+
+        let status = {
+            let mut status = self.status.lock().unwrap();
+            status.state = if status.errors.is_empty() {
+                TaskState::Done
+            } else {
+                TaskState::Failed
+            };
+            status.clone()
+        };
+        let event = format!(
+            "preheat task {} {:?}, fetched {} bytes, {} errors",
+            status.id,
+            status.state,
+            status.fetched_bytes,
+            status.errors.len()
+        );
+        info!("{}", event);
+        ERROR_HOLDER
+            .lock()
+            .unwrap()
+            .push(&event)
+            .unwrap_or_else(|_| error!("Failed when try to hold event"));
+    }
+}
+
+// Fetch files of the image into the blob cache, return the filesystem instance of the image and
+// the amount of data fetched.
+fn preheat_image(config: &str, image: &ApiPreheatImage) -> DaemonResult<(Rafs, usize)> {
+    let mut labels = HashMap::new();
+    labels.insert(LABEL_IMAGE_REF.to_string(), image.reference.clone());
+    // The bootstrap of the image is resolved by the image reference as for mounts by reference.
+    let mut cmd = FsBackendMountCmd {
+        fs_type: FsBackendType::Rafs,
+        source: String::new(),
+        config: config.to_string(),
+        mountpoint: String::new(),
+        prefetch_files: None,
+        labels,
+        source_file: None,
+    };
+    snapshot::prepare_mount(&mut cmd)?;
+
+    let bootstrap_path = PathBuf::from(&cmd.source);
+    let mut rafs_config = RafsConfig::from_str(&cmd.config)?;
+    rafs_config.set_default_signature(&bootstrap_path);
+    let mut bootstrap = <dyn RafsIoRead>::from_file(&bootstrap_path)?;
+    check_trust_policy(&rafs_config, &mut bootstrap)?;
+    let id = format!("preheat:{}", image.reference);
+    let rafs = Rafs::new(rafs_config, &id, &mut bootstrap)?;
+
+    let files = image
+        .files
+        .as_ref()
+        .map(|f| f.iter().map(PathBuf::from).collect::<Vec<_>>());
+    let size = rafs
+        .fetch_files(files.as_deref())
+        .map_err(|e| DaemonError::Common(format!("failed to fetch files, {}", e)))?;
+    info!(
+        "preheated image {}, {} bytes fetched",
+        image.reference, size
+    );
+
+    Ok((rafs, size))
+}
+
+// Fetch the range of a blob known by the daemon into the blob cache.
+fn preheat_blob(blob: &ApiPreheatBlob) -> DaemonResult<usize> {
+    let cache = BLOB_FACTORY
+        .find_blob_cache(&blob.blob_id)
+        .ok_or(DaemonError::NotFound)?;
+
+    cache
+        .fetch_range(blob.offset, blob.size)
+        .map_err(|e| DaemonError::Common(format!("failed to fetch range, {}", e)))
+}
+
+/// Start a task to warm up the blob cache in background, return the id of the task.
+pub fn start_preheat(cmd: ApiPreheatCmd) -> DaemonResult<u64> {
+    queue_task(cmd, preheat_blob)
+}
+
+fn queue_task(cmd: ApiPreheatCmd, fetch_blob: BlobFetcher) -> DaemonResult<u64> {
+    if cmd.images.is_empty() && cmd.blobs.is_empty() {
+        return Err(DaemonError::InvalidArguments(
+            "nothing to preheat".to_string(),
+        ));
+    }
+
+    let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+    let task = Arc::new(PreheatTask {
+        status: Mutex::new(TaskStatus {
+            id,
+            state: TaskState::Pending,
+            fetched_bytes: 0,
+            errors: Vec::new(),
+        }),
+    });
+    let job = PreheatJob {
+        task: task.clone(),
+        cmd,
+        fetch_blob,
+    };
+
+    // Hold the task list while queueing, so the task is listed before the worker finishes it.
+    let mut tasks = TASKS.lock().unwrap();
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.is_none() {
+        *queue = Some(start_worker()?);
+    }
+    match queue.as_ref().unwrap().try_send(job) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            return Err(DaemonError::Common(format!(
+                "too many pending preheat tasks, at most {}",
+                MAX_PENDING_TASKS
+            )))
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            *queue = None;
+            return Err(DaemonError::Common("preheat worker has exited".to_string()));
+        }
+    }
+    tasks.push_back(task);
+
+    // Only drop status of finished tasks, pending tasks are bounded by the queue.
+    let mut finished = tasks.iter().filter(|t| t.is_finished()).count();
+    while finished > MAX_TASKS {
+        if let Some(idx) = tasks.iter().position(|t| t.is_finished()) {
+            tasks.remove(idx);
+        }
+        finished -= 1;
+    }
+
+    Ok(id)
+}
+
+// Start the worker running queued tasks one by one.
+fn start_worker() -> DaemonResult<SyncSender<PreheatJob>> {
+    let (sender, receiver) = sync_channel::<PreheatJob>(MAX_PENDING_TASKS);
+    thread::Builder::new()
+        .name("preheat".to_string())
+        .spawn(move || {
+            while let Ok(job) = receiver.recv() {
+                job.task.run(job.cmd, job.fetch_blob);
+            }
+        })
+        .map_err(DaemonError::ThreadSpawn)?;
+
+    Ok(sender)
+}
+
+/// Export status of recent preheating tasks as a JSON array.
+pub fn export_preheat_status() -> DaemonResult<String> {
+    let status = TASKS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|t| t.status.lock().unwrap().clone())
+        .collect::<Vec<_>>();
+
+    serde_json::to_string(&status).map_err(DaemonError::Serde)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn blob_cmd(blob_id: &str, size: u64) -> ApiPreheatCmd {
+        ApiPreheatCmd {
+            config: String::new(),
+            images: Vec::new(),
+            blobs: vec![ApiPreheatBlob {
+                blob_id: blob_id.to_string(),
+                offset: 0,
+                size,
+            }],
+        }
+    }
+
+    fn task_status(id: u64) -> Option<serde_json::Value> {
+        let status: serde_json::Value =
+            serde_json::from_str(&export_preheat_status().unwrap()).unwrap();
+        status
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|t| t["id"] == id)
+            .cloned()
+    }
+
+    fn wait_task(id: u64) -> serde_json::Value {
+        let start = Instant::now();
+        loop {
+            let status = task_status(id).unwrap();
+            if status["state"] == "done" || status["state"] == "failed" {
+                return status;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_start_preheat() {
+        let cmd = ApiPreheatCmd {
+            config: String::new(),
+            images: Vec::new(),
+            blobs: Vec::new(),
+        };
+        assert!(start_preheat(cmd).is_err());
+
+        let id = start_preheat(blob_cmd("preheat-test-blob", 4096)).unwrap();
+        let status = wait_task(id);
+        assert_eq!(status["state"], "failed");
+        assert_eq!(status["fetched_bytes"], 0);
+        assert_eq!(status["errors"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_queue_task() {
+        let fetch: BlobFetcher = |blob| {
+            thread::sleep(Duration::from_millis(20));
+            Ok(blob.size as usize)
+        };
+        let ids = (0..8)
+            .map(|idx| queue_task(blob_cmd("preheat-queued-blob", 1024 * idx), fetch).unwrap())
+            .collect::<Vec<_>>();
+        // Status of pending tasks is kept.
+        for id in ids.iter() {
+            assert!(task_status(*id).is_some());
+        }
+        for (idx, id) in ids.iter().enumerate() {
+            let status = wait_task(*id);
+            assert_eq!(status["state"], "done");
+            assert_eq!(status["fetched_bytes"], 1024 * idx as u64);
+            assert!(status["errors"].as_array().unwrap().is_empty());
+        }
+    }
+}
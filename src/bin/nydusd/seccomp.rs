@@ -0,0 +1,361 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Seccomp sandbox of nydusd.
+//!
+//! Once initialized, nydusd only needs a limited set of syscalls to serve filesystem requests,
+//! talk to storage backends and handle API requests. Other syscalls are rejected by a seccomp
+//! filter applied to all threads, to reduce the damage a compromised daemon may do when parsing
+//! untrusted images. A rejected syscall kills the daemon by `SIGSYS`, with the syscall number
+//! recorded in the audit log of the kernel.
+//!
+//! Programs can't be executed under the filter, `nydus-image` to convert stargz layers is spawned
+//! by a helper process forked before, see [`crate::stargz::start_builder_helper`]. Signals may
+//! only be sent to nydusd itself.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::Result;
+
+use seccompiler::{
+    apply_filter_all_threads, BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp,
+    SeccompCondition, SeccompFilter, SeccompRule,
+};
+
+/// Syscalls available on all supported architectures.
+const COMMON_SYSCALLS: &[libc::c_long] = &[
+    // File and memory access
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_lseek,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_fcntl,
+    libc::SYS_flock,
+    libc::SYS_ioctl,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_readahead,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_linkat,
+    libc::SYS_symlinkat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_utimensat,
+    libc::SYS_fgetxattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_flistxattr,
+    libc::SYS_fremovexattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_copy_file_range,
+    libc::SYS_splice,
+    libc::SYS_sendfile,
+    libc::SYS_getcwd,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_umask,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_msync,
+    libc::SYS_mincore,
+    libc::SYS_brk,
+    // Local blob and cache file IO through io_uring
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    // Disconnecting the FUSE session on exit
+    libc::SYS_umount2,
+    // Network and IPC
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_shutdown,
+    // Event loops
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    // Threads, signals and time
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_setaffinity,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_restart_syscall,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_wait4,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    // Sampling CPU profiles
+    libc::SYS_setitimer,
+    // Process information
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_capget,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    // Lowering priority of background threads, e.g. the scrubber
+    libc::SYS_setpriority,
+    libc::SYS_sysinfo,
+    libc::SYS_uname,
+    libc::SYS_getrandom,
+    libc::SYS_memfd_create,
+];
+
+/// Legacy syscalls only available on x86_64, which are still used by some libraries.
+#[cfg(target_arch = "x86_64")]
+const ARCH_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_unlink,
+    libc::SYS_rename,
+    libc::SYS_mkdir,
+    libc::SYS_getdents,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_wait,
+    libc::SYS_arch_prctl,
+    libc::SYS_time,
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const ARCH_SYSCALLS: &[libc::c_long] = &[];
+
+/// Build the seccomp filter allowing syscalls needed by nydusd running as process `pid`.
+fn build_filter(pid: libc::pid_t) -> Result<BpfProgram> {
+    let mut rules = COMMON_SYSCALLS
+        .iter()
+        .chain(ARCH_SYSCALLS.iter())
+        .map(|nr| (*nr as i64, vec![]))
+        .collect::<BTreeMap<_, _>>();
+    // Exiting by `SIGTERM` to itself.
+    let kill_self = SeccompCondition::new(0, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, pid as u64)
+        .and_then(|c| SeccompRule::new(vec![c]))
+        .map_err(|e| eother!(format!("failed to create seccomp rule, {:?}", e)))?;
+    rules.insert(libc::SYS_kill as i64, vec![kill_self]);
+    let arch = std::env::consts::ARCH
+        .try_into()
+        .map_err(|e| eother!(format!("unsupported architecture for seccomp, {:?}", e)))?;
+    let filter = SeccompFilter::new(rules, SeccompAction::Trap, SeccompAction::Allow, arch)
+        .map_err(|e| eother!(format!("failed to create seccomp filter, {:?}", e)))?;
+
+    filter
+        .try_into()
+        .map_err(|e| eother!(format!("failed to compile seccomp filter, {:?}", e)))
+}
+
+/// Restrict all threads of nydusd to syscalls needed after initialization.
+pub fn apply_seccomp_filter() -> Result<()> {
+    let filter = build_filter(std::process::id() as libc::pid_t)?;
+    apply_filter_all_threads(&filter)
+        .map_err(|e| eother!(format!("failed to apply seccomp filter, {:?}", e)))?;
+    info!("seccomp filter applied to all threads");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    // Run `f` in a child process restricted by the filter, and check it isn't killed by `SIGSYS`.
+    fn run_filtered(f: fn()) {
+        assert_eq!(run_in_child(f), None);
+    }
+
+    // Run `f` in a child process restricted by the filter, and check it's killed by `SIGSYS`.
+    fn run_trapped(f: fn()) {
+        assert_eq!(run_in_child(f), Some(libc::SIGSYS));
+    }
+
+    // Run `f` in a child process restricted by the filter, and return the signal killing it.
+    fn run_in_child(f: fn()) -> Option<libc::c_int> {
+        // The filter is built before forking, so only the parent pid is known.
+        let filter = build_filter(std::process::id() as libc::pid_t).unwrap();
+        match unsafe { libc::fork() } {
+            0 => {
+                // The forked child only has the calling thread, so filtering it is enough.
+                let code = if seccompiler::apply_filter(&filter).is_err() {
+                    2
+                } else if std::panic::catch_unwind(f).is_err() {
+                    1
+                } else {
+                    0
+                };
+                unsafe { libc::_exit(code) };
+            }
+            pid => {
+                assert!(pid > 0);
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                if libc::WIFSIGNALED(status) {
+                    Some(libc::WTERMSIG(status))
+                } else {
+                    assert_eq!(libc::WEXITSTATUS(status), 0);
+                    None
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_filter() {
+        let filter = build_filter(std::process::id() as libc::pid_t).unwrap();
+        assert!(!filter.is_empty());
+        for nr in &[
+            libc::SYS_ptrace,
+            libc::SYS_mount,
+            libc::SYS_execve,
+            libc::SYS_execveat,
+            libc::SYS_kill,
+            libc::SYS_setresuid,
+            libc::SYS_capset,
+            libc::SYS_mknodat,
+        ] {
+            assert!(!COMMON_SYSCALLS.contains(nr));
+            assert!(!ARCH_SYSCALLS.contains(nr));
+        }
+    }
+
+    #[test]
+    fn test_filtered_local_io() {
+        run_filtered(|| {
+            let file = File::open("/proc/self/exe").unwrap();
+            let mut buf = vec![0u8; 4096];
+            // Submitted through io_uring with the `io-uring` feature.
+            storage::utils::pread(file.as_raw_fd(), &mut buf, 0).unwrap();
+            storage::utils::readahead(file.as_raw_fd(), 0, 0x10000);
+            // Fails with invalid arguments or without io_uring support, but isn't trapped.
+            unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_setup,
+                    0,
+                    std::ptr::null_mut::<libc::c_void>(),
+                )
+            };
+        });
+    }
+
+    #[test]
+    fn test_filtered_flush() {
+        run_filtered(|| {
+            // Persisted chunk maps are flushed by msync().
+            let size = 4096;
+            let addr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            assert_ne!(addr, libc::MAP_FAILED);
+            assert_eq!(unsafe { libc::msync(addr, size, libc::MS_SYNC) }, 0);
+            unsafe { libc::munmap(addr, size) };
+        });
+    }
+
+    #[test]
+    fn test_filtered_setpriority() {
+        run_filtered(|| {
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+            assert_eq!(
+                unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, 19) },
+                0
+            );
+        });
+    }
+
+    #[test]
+    fn test_trapped_execve() {
+        run_trapped(|| {
+            let path = CString::new("/bin/sh").unwrap();
+            let argv = [path.as_ptr(), std::ptr::null()];
+            let envp = [std::ptr::null()];
+            unsafe { libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr()) };
+        });
+    }
+
+    #[test]
+    fn test_trapped_kill() {
+        // The filter of the child allows killing the parent only, which stands in for nydusd.
+        run_filtered(|| {
+            assert_eq!(unsafe { libc::kill(libc::getppid(), 0) }, 0);
+        });
+        run_trapped(|| {
+            unsafe { libc::kill(1, 0) };
+        });
+    }
+}
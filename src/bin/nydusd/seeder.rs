@@ -0,0 +1,391 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serve cached blob data to peer nodes.
+//!
+//! In large clusters, every node pulls the same blobs from the registry, so the registry egress
+//! grows with the number of nodes. The seeder is a lightweight HTTP listener serving ranges of
+//! blobs from the local blob cache, so each nydusd becomes a cache peer of other nodes. Blobs are
+//! content addressed, a range is requested by `GET /blobs/<blob_id>` with a `Range` header and
+//! returned as stored on the storage backend. Ranges not fully cached or failing validation are
+//! answered by 404, so peers fall back to the registry.
+//!
+//! Requests must carry the token shared among nodes of the cluster as a bearer token.
+
+use std::io::{BufRead, BufReader, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use storage::backend::peer::{
+    load_peer_token, peer_token_matches, remaining_time, MAX_PEER_HEADER_SIZE as MAX_HEADER_SIZE,
+    MAX_PEER_RANGE_SIZE as MAX_RANGE_SIZE, PEER_BLOB_PATH,
+};
+use storage::factory::BLOB_FACTORY;
+
+/// Maximum number of connections served concurrently.
+const MAX_CONNECTIONS: usize = 64;
+/// Maximum size of blob data buffered by all connections.
+const MAX_BUFFERED_SIZE: usize = 64 << 20;
+/// Timeout of reading a request from a peer, and of writing a response to a peer.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Size of blob data buffered by all connections.
+static BUFFERED_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+// Reservation of buffer space for a response, released when dropped.
+struct BufferReservation(usize);
+
+impl BufferReservation {
+    fn reserve(size: usize) -> Option<Self> {
+        let mut current = BUFFERED_SIZE.load(Ordering::Relaxed);
+        loop {
+            if current + size > MAX_BUFFERED_SIZE {
+                return None;
+            }
+            match BUFFERED_SIZE.compare_exchange_weak(
+                current,
+                current + size,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(BufferReservation(size)),
+                Err(v) => current = v,
+            }
+        }
+    }
+}
+
+impl Drop for BufferReservation {
+    fn drop(&mut self) {
+        BUFFERED_SIZE.fetch_sub(self.0, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct SeedRequest {
+    blob_id: String,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum SeedError {
+    BadRequest,
+    Unauthorized,
+    MethodNotAllowed,
+    NotFound,
+    RangeNotSatisfiable,
+    ServiceUnavailable,
+}
+
+impl SeedError {
+    fn status(&self) -> &'static str {
+        match self {
+            SeedError::BadRequest => "400 Bad Request",
+            SeedError::Unauthorized => "401 Unauthorized",
+            SeedError::MethodNotAllowed => "405 Method Not Allowed",
+            SeedError::NotFound => "404 Not Found",
+            SeedError::RangeNotSatisfiable => "416 Range Not Satisfiable",
+            SeedError::ServiceUnavailable => "503 Service Unavailable",
+        }
+    }
+}
+
+// Parse a range of the form `bytes=<first>-<last>`, with the last byte inclusive.
+fn parse_range(value: &str) -> std::result::Result<(u64, u64), SeedError> {
+    let (first, last) = value
+        .trim()
+        .strip_prefix("bytes=")
+        .and_then(|v| v.split_once('-'))
+        .ok_or(SeedError::RangeNotSatisfiable)?;
+    let first = first
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| SeedError::RangeNotSatisfiable)?;
+    let last = last
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| SeedError::RangeNotSatisfiable)?;
+    if last < first || last - first >= MAX_RANGE_SIZE {
+        return Err(SeedError::RangeNotSatisfiable);
+    }
+
+    Ok((first, last - first + 1))
+}
+
+// Find the value of the header `name` in the header lines.
+fn find_header<'a>(lines: &'a [String], name: &str) -> Option<&'a str> {
+    lines
+        .iter()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// Parse the request line and headers of a request, authenticated by `token`.
+fn parse_request(lines: &[String], token: &str) -> std::result::Result<SeedRequest, SeedError> {
+    let mut parts = lines
+        .first()
+        .ok_or(SeedError::BadRequest)?
+        .split_whitespace();
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method, path)
+        }
+        _ => return Err(SeedError::BadRequest),
+    };
+    let authorized = find_header(&lines[1..], "authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| peer_token_matches(token, v.trim()))
+        .unwrap_or(false);
+    if !authorized {
+        return Err(SeedError::Unauthorized);
+    }
+    if method != "GET" {
+        return Err(SeedError::MethodNotAllowed);
+    }
+    let blob_id = path
+        .strip_prefix(PEER_BLOB_PATH)
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()))
+        .ok_or(SeedError::NotFound)?;
+
+    let range = find_header(&lines[1..], "range").ok_or(SeedError::RangeNotSatisfiable)?;
+    let (offset, size) = parse_range(range)?;
+
+    Ok(SeedRequest {
+        blob_id: blob_id.to_string(),
+        offset,
+        size,
+    })
+}
+
+// Read a request within `IO_TIMEOUT`, however slowly the peer sends it.
+fn read_request(stream: &TcpStream, token: &str) -> std::result::Result<SeedRequest, SeedError> {
+    let deadline = Instant::now() + IO_TIMEOUT;
+    let mut reader = BufReader::new(stream);
+    let mut lines = Vec::new();
+    let mut total = 0;
+    loop {
+        let timeout = remaining_time(deadline).map_err(|_| SeedError::BadRequest)?;
+        stream
+            .set_read_timeout(Some(timeout))
+            .map_err(|_| SeedError::BadRequest)?;
+        let mut line = String::new();
+        let n = (&mut reader)
+            .take((MAX_HEADER_SIZE - total) as u64 + 1)
+            .read_line(&mut line)
+            .map_err(|_| SeedError::BadRequest)?;
+        total += n;
+        if n == 0 || total > MAX_HEADER_SIZE || !line.ends_with('\n') {
+            return Err(SeedError::BadRequest);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    parse_request(&lines, token)
+}
+
+fn serve(mut stream: TcpStream, token: &str) -> Result<()> {
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let result = read_request(&stream, token).and_then(|req| {
+        let cache = BLOB_FACTORY
+            .find_blob_cache(&req.blob_id)
+            .ok_or(SeedError::NotFound)?;
+        // Don't wait for frozen blob caches, let the peer fall back to the storage backend.
+        if cache.is_frozen() {
+            return Err(SeedError::ServiceUnavailable);
+        }
+        let reservation =
+            BufferReservation::reserve(req.size as usize).ok_or(SeedError::ServiceUnavailable)?;
+        match cache.read_cached_range(req.offset, req.size) {
+            Ok(Some(data)) => Ok((req, data, reservation)),
+            Ok(None) => Err(SeedError::NotFound),
+            Err(e) => {
+                warn!(
+                    "failed to read {} bytes at {} of cached blob {}, {}",
+                    req.size, req.offset, req.blob_id, e
+                );
+                Err(SeedError::NotFound)
+            }
+        }
+    });
+
+    match result {
+        Ok((req, data, _reservation)) => {
+            write!(
+                stream,
+                "HTTP/1.1 206 Partial Content\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/*\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                req.offset,
+                req.offset + req.size - 1,
+                data.len()
+            )?;
+            stream.write_all(&data)?;
+        }
+        Err(e) => write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            e.status()
+        )?,
+    }
+
+    stream.flush()
+}
+
+/// Start to serve cached blob data to peer nodes on `address`, such as `0.0.0.0:8100`, to
+/// requests carrying the token in `token_file`.
+pub fn start_seeder(address: &str, token_file: &str) -> Result<()> {
+    let token: Arc<str> = load_peer_token(token_file)?.into();
+    let listener = TcpListener::bind(address)?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    info!("seeder listening on {}", listener.local_addr()?);
+
+    thread::Builder::new()
+        .name("seeder".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("seeder failed to accept connection, {}", e);
+                        continue;
+                    }
+                };
+                if connections.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::AcqRel);
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    );
+                    continue;
+                }
+
+                let counter = connections.clone();
+                let token = token.clone();
+                let ret = thread::Builder::new()
+                    .name("seeder_conn".to_string())
+                    .spawn(move || {
+                        if let Err(e) = serve(stream, &token) {
+                            debug!("seeder failed to serve peer, {}", e);
+                        }
+                        counter.fetch_sub(1, Ordering::AcqRel);
+                    });
+                if let Err(e) = ret {
+                    warn!("seeder failed to spawn thread, {}", e);
+                    connections.fetch_sub(1, Ordering::AcqRel);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(lines: &[&str]) -> std::result::Result<SeedRequest, SeedError> {
+        let lines = lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        parse_request(&lines, "secret")
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-4095"), Ok((0, 4096)));
+        assert_eq!(parse_range(" bytes=100-100"), Ok((100, 1)));
+        assert_eq!(
+            parse_range("bytes=10-9"),
+            Err(SeedError::RangeNotSatisfiable)
+        );
+        assert_eq!(
+            parse_range("bytes=10-"),
+            Err(SeedError::RangeNotSatisfiable)
+        );
+        assert_eq!(
+            parse_range("items=0-1"),
+            Err(SeedError::RangeNotSatisfiable)
+        );
+        assert_eq!(
+            parse_range(&format!("bytes=0-{}", MAX_RANGE_SIZE)),
+            Err(SeedError::RangeNotSatisfiable)
+        );
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            request(&[
+                "GET /blobs/abc123 HTTP/1.1",
+                "Host: node",
+                "authorization: Bearer secret",
+                "range: bytes=0-9"
+            ]),
+            Ok(SeedRequest {
+                blob_id: "abc123".to_string(),
+                offset: 0,
+                size: 10,
+            })
+        );
+        assert_eq!(
+            request(&[
+                "PUT /blobs/abc123 HTTP/1.1",
+                "Range: bytes=0-9",
+                "Authorization: Bearer secret"
+            ]),
+            Err(SeedError::MethodNotAllowed)
+        );
+        assert_eq!(
+            request(&[
+                "GET /blobs/../abc HTTP/1.1",
+                "Range: bytes=0-9",
+                "Authorization: Bearer secret"
+            ]),
+            Err(SeedError::NotFound)
+        );
+        assert_eq!(
+            request(&["GET /blobs/abc123 HTTP/1.1", "Authorization: Bearer secret"]),
+            Err(SeedError::RangeNotSatisfiable)
+        );
+        assert_eq!(request(&["GET /blobs/abc123"]), Err(SeedError::BadRequest));
+        assert_eq!(request(&[]), Err(SeedError::BadRequest));
+    }
+
+    #[test]
+    fn test_authorization() {
+        assert_eq!(
+            request(&["GET /blobs/abc123 HTTP/1.1", "Range: bytes=0-9"]),
+            Err(SeedError::Unauthorized)
+        );
+        assert_eq!(
+            request(&[
+                "GET /blobs/abc123 HTTP/1.1",
+                "Authorization: Bearer secreT",
+                "Range: bytes=0-9"
+            ]),
+            Err(SeedError::Unauthorized)
+        );
+        assert_eq!(
+            request(&[
+                "GET /blobs/abc123 HTTP/1.1",
+                "Authorization: Basic secret",
+                "Range: bytes=0-9"
+            ]),
+            Err(SeedError::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn test_buffer_reservation() {
+        let first = BufferReservation::reserve(MAX_BUFFERED_SIZE - 1).unwrap();
+        assert!(BufferReservation::reserve(2).is_none());
+        drop(first);
+        assert!(BufferReservation::reserve(2).is_some());
+    }
+}
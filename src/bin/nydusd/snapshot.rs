@@ -0,0 +1,877 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Support of containerd snapshotters mounting nydus images by snapshot labels.
+//!
+//! A containerd snapshotter may mount a nydus image without preparing the bootstrap itself, by
+//! passing labels of the bootstrap layer snapshot along with the mount request:
+//! - `containerd.io/snapshot/cri.image-ref`: the image reference, used to fill in registry host
+//!   and repository of the `registry` storage backend if they are absent from the configuration.
+//! - `containerd.io/snapshot/cri.layer-digest`: digest of the nydus bootstrap layer, which is
+//!   fetched from the storage backend, verified, and unpacked into the blob cache working
+//!   directory if the mount request doesn't specify a bootstrap file. For stargz mounts, it's
+//!   the digest of the stargz layer to mount.
+//!
+//! Without the layer digest label, the nydus bootstrap layer is looked up in the manifest of
+//! the image reference, so an image can be mounted by reference only, e.g. as a data volume.
+//! The manifest of multi-platform images is selected for the platform in the label
+//! `containerd.io/snapshot/nydus-platform`, e.g. `linux/arm64/v8`, defaulting to the platform
+//! nydusd runs on.
+//!
+//! The source of a Rafs mount may also refer to a bootstrap on the storage backend, which is
+//! fetched into the blob cache working directory before mounting:
+//! - `manifest://<tag or digest>`: the nydus bootstrap layer of the image manifest in the
+//!   repository of the `registry` backend.
+//! - `blob://<blob id>`: a bootstrap layer or a plain bootstrap file stored as a blob of the
+//!   storage backend, e.g. a layer digest of the `registry` backend or an object of the `oss`
+//!   backend.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use flate2::read::GzDecoder;
+use serde_json::Value;
+use tar::Archive;
+
+use nydus::{FsBackendType, LABEL_IMAGE_REF, LABEL_LAYER_DIGEST, LABEL_PLATFORM};
+use nydus_utils::digest::{self, DigestHasher, RafsDigest};
+use rafs::signature::{default_signature_path, SIGNATURE_FILE_SUFFIX};
+use storage::factory::{BackendConfig, BLOB_FACTORY};
+
+use crate::daemon::{DaemonError, DaemonResult, FsBackendMountCmd};
+
+/// Path of the bootstrap file in nydus bootstrap layers.
+const BOOTSTRAP_FILE_IN_LAYER: &str = "image/image.boot";
+const DOCKER_HUB_REGISTRY: &str = "docker.io";
+const DOCKER_HUB_REGISTRY_HOST: &str = "registry-1.docker.io";
+const FETCH_BUF_SIZE: usize = 0x10_0000;
+/// Annotation marking the nydus bootstrap layer in image manifests.
+const BOOTSTRAP_LAYER_ANNOTATION: &str = "containerd.io/snapshot/nydus-bootstrap";
+/// OS feature marking nydus manifests in image indexes.
+const NYDUS_OS_FEATURE: &str = "nydus.remoteimage.v1";
+/// Prefix of mount sources referring to the bootstrap layer by an image manifest.
+const SOURCE_MANIFEST_PREFIX: &str = "manifest://";
+/// Prefix of mount sources referring to the bootstrap by a blob of the storage backend.
+const SOURCE_BLOB_PREFIX: &str = "blob://";
+/// Magic number of gzip streams, to tell bootstrap layers from plain bootstrap files.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Complete the mount command according to containerd snapshot labels and remote sources.
+///
+/// Fill in registry host and repository of the storage backend configuration with the image
+/// reference, and fetch the bootstrap from the storage backend if no bootstrap file is given or
+/// the source refers to a remote bootstrap.
+pub fn prepare_mount(cmd: &mut FsBackendMountCmd) -> DaemonResult<()> {
+    if cmd.fs_type != FsBackendType::Rafs && cmd.fs_type != FsBackendType::Stargz {
+        return Ok(());
+    }
+    let remote = cmd.fs_type == FsBackendType::Rafs && is_remote_source(&cmd.source);
+    if cmd.labels.is_empty() && !remote {
+        return Ok(());
+    }
+
+    let mut config: Value = serde_json::from_str(&cmd.config).map_err(DaemonError::Serde)?;
+    if let Some(image_ref) = cmd.labels.get(LABEL_IMAGE_REF) {
+        if fill_registry_config(&mut config, image_ref)? {
+            cmd.config = config.to_string();
+        }
+    }
+
+    if remote {
+        let platform = mount_platform(cmd)?;
+        let bootstrap = fetch_remote_bootstrap(&config, &cmd.source, &platform)?;
+        cmd.source = bootstrap.to_string_lossy().to_string();
+    } else if cmd.source.is_empty() && cmd.source_file.is_none() {
+        let digest = match cmd.labels.get(LABEL_LAYER_DIGEST) {
+            Some(digest) => digest.to_string(),
+            None if cmd.fs_type == FsBackendType::Rafs => {
+                let image_ref = cmd.labels.get(LABEL_IMAGE_REF).ok_or_else(|| {
+                    DaemonError::InvalidArguments(format!(
+                        "neither bootstrap file nor label {} or {} is specified",
+                        LABEL_LAYER_DIGEST, LABEL_IMAGE_REF
+                    ))
+                })?;
+                resolve_bootstrap_layer(&config, image_ref, &mount_platform(cmd)?)?
+            }
+            None => {
+                return Err(DaemonError::InvalidArguments(format!(
+                    "neither stargz layer nor label {} is specified",
+                    LABEL_LAYER_DIGEST
+                )))
+            }
+        };
+        if cmd.fs_type == FsBackendType::Stargz {
+            // The stargz layer itself is the data blob to mount.
+            cmd.source = digest;
+        } else {
+            let bootstrap = fetch_bootstrap(&config, &digest)?;
+            cmd.source = bootstrap.to_string_lossy().to_string();
+        }
+    }
+
+    Ok(())
+}
+
+// Get the platform to select manifests of multi-platform images for, only parse the label when
+// a manifest is to be selected, so a bad label doesn't fail mounts of local bootstraps.
+fn mount_platform(cmd: &FsBackendMountCmd) -> DaemonResult<Platform> {
+    match cmd.labels.get(LABEL_PLATFORM) {
+        Some(platform) => Platform::parse(platform),
+        None => Ok(Platform::current()),
+    }
+}
+
+/// Check whether the mount source refers to a bootstrap on the storage backend.
+pub fn is_remote_source(source: &str) -> bool {
+    source.starts_with(SOURCE_MANIFEST_PREFIX) || source.starts_with(SOURCE_BLOB_PREFIX)
+}
+
+/// Fetch the bootstrap referred by the remote mount source `source`.
+fn fetch_remote_bootstrap(
+    config: &Value,
+    source: &str,
+    platform: &Platform,
+) -> DaemonResult<PathBuf> {
+    // Signatures of bootstraps fetched by digest can't be looked up on the storage backend, so
+    // they must be given explicitly, instead of taking the missing `<bootstrap>.sig`.
+    let by_digest = match source.strip_prefix(SOURCE_BLOB_PREFIX) {
+        Some(blob_id) => sha256_hex(blob_id).is_some(),
+        None => true,
+    };
+    if by_digest && default_signature_wanted(config) {
+        return Err(DaemonError::InvalidConfig(format!(
+            "signature of bootstrap {} must be specified to verify it",
+            source
+        )));
+    }
+
+    if let Some(reference) = source.strip_prefix(SOURCE_MANIFEST_PREFIX) {
+        let digest = resolve_manifest_layer(config, reference, source, platform)?;
+        fetch_bootstrap(config, &digest)
+    } else if let Some(blob_id) = source.strip_prefix(SOURCE_BLOB_PREFIX) {
+        if sha256_hex(blob_id).is_some() {
+            fetch_bootstrap(config, blob_id)
+        } else {
+            fetch_bootstrap_object(config, blob_id)
+        }
+    } else {
+        Err(DaemonError::InvalidArguments(format!(
+            "invalid remote source {}",
+            source
+        )))
+    }
+}
+
+/// Check whether the bootstrap should be verified by the default signature file `<bootstrap>.sig`.
+fn default_signature_wanted(config: &Value) -> bool {
+    let signature = &config["verify_signature"];
+    signature.is_object()
+        && signature["signature"]
+            .as_str()
+            .unwrap_or_default()
+            .is_empty()
+}
+
+/// Get the hex string of a sha256 digest, with or without the `sha256:` prefix.
+fn sha256_hex(digest: &str) -> Option<&str> {
+    let hex = digest.strip_prefix("sha256:").unwrap_or(digest);
+    if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(hex)
+    } else {
+        None
+    }
+}
+
+/// Split an image reference, e.g. `docker.io/library/busybox:latest`, into registry host and
+/// repository, following the normalization rules of docker image references.
+fn parse_image_ref(reference: &str) -> DaemonResult<(String, String)> {
+    // Strip the digest and the tag.
+    let name = reference.split('@').next().unwrap_or_default();
+    let name = match name.rfind(':') {
+        Some(idx) if !name[idx..].contains('/') => &name[..idx],
+        _ => name,
+    };
+
+    let (host, repo) = match name.find('/') {
+        Some(idx)
+            if name[..idx].contains('.')
+                || name[..idx].contains(':')
+                || &name[..idx] == "localhost" =>
+        {
+            (&name[..idx], &name[idx + 1..])
+        }
+        _ => (DOCKER_HUB_REGISTRY, name),
+    };
+    if repo.is_empty() {
+        return Err(DaemonError::InvalidArguments(format!(
+            "invalid image reference {}",
+            reference
+        )));
+    }
+
+    if host == DOCKER_HUB_REGISTRY {
+        let repo = if repo.contains('/') {
+            repo.to_string()
+        } else {
+            format!("library/{}", repo)
+        };
+        Ok((DOCKER_HUB_REGISTRY_HOST.to_string(), repo))
+    } else {
+        Ok((host.to_string(), repo.to_string()))
+    }
+}
+
+/// Get the tag or digest of an image reference, which defaults to `latest`.
+fn parse_image_tag(reference: &str) -> &str {
+    if let Some(idx) = reference.find('@') {
+        return &reference[idx + 1..];
+    }
+    match reference.rfind(':') {
+        Some(idx) if !reference[idx..].contains('/') => &reference[idx + 1..],
+        _ => "latest",
+    }
+}
+
+/// Find the digest of the nydus bootstrap layer of the image `image_ref` through its manifest.
+fn resolve_bootstrap_layer(
+    config: &Value,
+    image_ref: &str,
+    platform: &Platform,
+) -> DaemonResult<String> {
+    resolve_manifest_layer(config, parse_image_tag(image_ref), image_ref, platform)
+}
+
+/// Find the digest of the nydus bootstrap layer through the manifest `reference`, a tag or
+/// digest, of the image `image_ref` in the repository of the storage backend.
+fn resolve_manifest_layer(
+    config: &Value,
+    reference: &str,
+    image_ref: &str,
+    platform: &Platform,
+) -> DaemonResult<String> {
+    let backend_config: BackendConfig =
+        serde_json::from_value(config["device"]["backend"].clone()).map_err(DaemonError::Serde)?;
+    let fetch = |reference: &str| -> DaemonResult<Value> {
+        let manifest = BLOB_FACTORY
+            .fetch_manifest(backend_config.clone(), reference)
+            .map_err(|e| {
+                DaemonError::DaemonFailure(format!(
+                    "failed to fetch manifest {} of image {}, {}",
+                    reference, image_ref, e
+                ))
+            })?;
+        serde_json::from_slice(&manifest).map_err(DaemonError::Serde)
+    };
+
+    let mut manifest = fetch(reference)?;
+    if manifest.get("manifests").is_some() {
+        let digest = select_manifest(&manifest, platform).ok_or_else(|| {
+            DaemonError::InvalidArguments(format!(
+                "image {} has no manifest for platform {}",
+                image_ref, platform
+            ))
+        })?;
+        manifest = fetch(&digest)?;
+    }
+    let digest = bootstrap_layer(&manifest).ok_or_else(|| {
+        DaemonError::InvalidArguments(format!("image {} has no bootstrap layer", image_ref))
+    })?;
+    info!("resolved bootstrap layer {} of image {}", digest, image_ref);
+
+    Ok(digest)
+}
+
+/// Platform of images in OCI image indexes.
+#[derive(Debug, PartialEq)]
+struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl Platform {
+    /// Get the platform nydusd runs on.
+    fn current() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            arch => arch,
+        };
+        Platform {
+            os: "linux".to_string(),
+            architecture: architecture.to_string(),
+            variant: None,
+        }
+    }
+
+    /// Parse a platform in the form of `<os>/<arch>[/<variant>]`.
+    fn parse(platform: &str) -> DaemonResult<Self> {
+        let parts: Vec<&str> = platform.split('/').collect();
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|p| p.is_empty()) {
+            return Err(DaemonError::InvalidArguments(format!(
+                "invalid platform {}",
+                platform
+            )));
+        }
+
+        Ok(Platform {
+            os: parts[0].to_string(),
+            architecture: parts[1].to_string(),
+            variant: parts.get(2).map(|v| v.to_string()),
+        })
+    }
+
+    /// Check whether the platform of a manifest descriptor matches, a variant is only compared
+    /// if both specify it.
+    fn matches(&self, platform: &Value) -> bool {
+        platform["os"] == self.os.as_str()
+            && platform["architecture"] == self.architecture.as_str()
+            && match (self.variant.as_deref(), platform["variant"].as_str()) {
+                (Some(v), Some(variant)) => v == variant,
+                _ => true,
+            }
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = self.variant.as_ref() {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// Select the manifest for the platform from an image index, preferring nydus manifests to be
+/// compatible with images carrying both OCI and nydus manifests.
+fn select_manifest(index: &Value, platform: &Platform) -> Option<String> {
+    let candidates: Vec<&Value> = index["manifests"]
+        .as_array()?
+        .iter()
+        .filter(|m| platform.matches(&m["platform"]))
+        .collect();
+    let is_nydus = |m: &&&Value| {
+        m["platform"]["os.features"]
+            .as_array()
+            .map_or(false, |f| f.iter().any(|f| f == NYDUS_OS_FEATURE))
+    };
+    let manifest = candidates
+        .iter()
+        .find(is_nydus)
+        .or_else(|| candidates.first())?;
+
+    manifest["digest"].as_str().map(|d| d.to_string())
+}
+
+/// Get the digest of the nydus bootstrap layer from an image manifest, which is the layer
+/// annotated as bootstrap, or the last layer for images built by older tools.
+fn bootstrap_layer(manifest: &Value) -> Option<String> {
+    let layers = manifest["layers"].as_array()?;
+    let layer = layers
+        .iter()
+        .find(|l| l["annotations"][BOOTSTRAP_LAYER_ANNOTATION] == "true")
+        .or_else(|| layers.last())?;
+
+    layer["digest"].as_str().map(|d| d.to_string())
+}
+
+/// Fill in registry host and repository of the `registry` backend configuration if absent,
+/// return whether the configuration has been changed.
+fn fill_registry_config(config: &mut Value, image_ref: &str) -> DaemonResult<bool> {
+    let backend = match config.pointer_mut("/device/backend") {
+        Some(b) if b["type"] == "registry" => b,
+        _ => return Ok(false),
+    };
+    let backend_config = match backend.get_mut("config").and_then(|c| c.as_object_mut()) {
+        Some(c) => c,
+        None => return Ok(false),
+    };
+
+    let (host, repo) = parse_image_ref(image_ref)?;
+    let mut changed = false;
+    for (key, value) in [("host", host), ("repo", repo)].iter() {
+        let absent = backend_config
+            .get(*key)
+            .and_then(|v| v.as_str())
+            .map_or(true, |v| v.is_empty());
+        if absent {
+            backend_config.insert(key.to_string(), Value::String(value.clone()));
+            changed = true;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Fetch the bootstrap layer with digest `digest` from the storage backend, and unpack the
+/// bootstrap file into the working directory of the blob cache.
+///
+/// The unpacked bootstrap file is reused by following mounts of the same layer.
+fn fetch_bootstrap(config: &Value, digest: &str) -> DaemonResult<PathBuf> {
+    let hex = sha256_hex(digest).ok_or_else(|| {
+        DaemonError::InvalidArguments(format!("invalid bootstrap layer digest {}", digest))
+    })?;
+
+    let work_dir = cache_work_dir(config)?;
+    let bootstrap = Path::new(work_dir).join(format!("{}.boot", hex));
+    if bootstrap.exists() {
+        info!("reuse bootstrap {:?} of layer {}", bootstrap, digest);
+        return Ok(bootstrap);
+    }
+
+    download_bootstrap(config, hex, hex, true, &bootstrap).map_err(|e| {
+        DaemonError::DaemonFailure(format!("failed to fetch bootstrap layer {}, {}", digest, e))
+    })?;
+    info!("fetched bootstrap {:?} of layer {}", bootstrap, digest);
+
+    Ok(bootstrap)
+}
+
+/// Fetch the bootstrap stored as the object `object` of the storage backend, into the working
+/// directory of the blob cache.
+///
+/// Objects aren't addressed by content, so the bootstrap is fetched again for each mount.
+fn fetch_bootstrap_object(config: &Value, object: &str) -> DaemonResult<PathBuf> {
+    if object.is_empty() {
+        return Err(DaemonError::InvalidArguments(
+            "empty bootstrap object name".to_string(),
+        ));
+    }
+    let name = RafsDigest::from_buf(object.as_bytes(), digest::Algorithm::Sha256).to_string();
+
+    let work_dir = cache_work_dir(config)?;
+    let bootstrap = Path::new(work_dir).join(format!("{}.object.boot", name));
+    download_bootstrap(config, object, &name, false, &bootstrap).map_err(|e| {
+        DaemonError::DaemonFailure(format!(
+            "failed to fetch bootstrap object {}, {}",
+            object, e
+        ))
+    })?;
+    info!("fetched bootstrap {:?} of object {}", bootstrap, object);
+
+    // The detached signature is stored beside the bootstrap object, as `<object>.sig`.
+    if default_signature_wanted(config) {
+        let object = format!("{}{}", object, SIGNATURE_FILE_SUFFIX);
+        download_signature(config, &object, &default_signature_path(&bootstrap)).map_err(|e| {
+            DaemonError::DaemonFailure(format!("failed to fetch signature {}, {}", object, e))
+        })?;
+    }
+
+    Ok(bootstrap)
+}
+
+/// Download the blob `blob_id` through a temporary file named by `name`, and save the bootstrap
+/// in it, either a bootstrap layer or a plain bootstrap file, to `bootstrap`.
+fn download_bootstrap(
+    config: &Value,
+    blob_id: &str,
+    name: &str,
+    verify: bool,
+    bootstrap: &Path,
+) -> io::Result<()> {
+    let backend_config: BackendConfig =
+        serde_json::from_value(config["device"]["backend"].clone()).map_err(|e| einval!(e))?;
+    // Safe to unwrap because the bootstrap is in the working directory.
+    let work_dir = bootstrap.parent().unwrap();
+    fs::create_dir_all(work_dir)?;
+    let layer_path = temp_path(&work_dir.join(name), ".layer.tmp");
+    let mut layer = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&layer_path)?;
+    // The temporary file is removed as soon as it's opened.
+    fs::remove_file(&layer_path)?;
+
+    download_layer(backend_config, blob_id, verify, &mut layer)?;
+    layer.seek(SeekFrom::Start(0))?;
+    let mut magic = [0u8; 2];
+    let is_gzip = layer.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    layer.seek(SeekFrom::Start(0))?;
+    if is_gzip {
+        unpack_bootstrap(&mut layer, bootstrap)
+    } else {
+        save_file(&mut layer, bootstrap)
+    }
+}
+
+/// Download the signature object `object` of the storage backend to `signature`.
+fn download_signature(config: &Value, object: &str, signature: &Path) -> io::Result<()> {
+    let backend_config: BackendConfig =
+        serde_json::from_value(config["device"]["backend"].clone()).map_err(|e| einval!(e))?;
+    let mut data = Vec::new();
+    download_layer(backend_config, object, false, &mut data)?;
+
+    save_file(&mut data.as_slice(), signature)
+}
+
+/// Get a temporary file path of `path` with `suffix`, unique among concurrent fetches.
+fn temp_path(path: &Path, suffix: &str) -> PathBuf {
+    static TEMP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(
+        ".{}-{}{}",
+        process::id(),
+        TEMP_SEQ.fetch_add(1, Ordering::Relaxed),
+        suffix
+    ));
+    PathBuf::from(name)
+}
+
+/// Get the working directory of the blob cache, where bootstraps prepared by nydusd are saved.
+pub fn cache_work_dir(config: &Value) -> DaemonResult<&str> {
+    config["device"]["cache"]["config"]["work_dir"]
+        .as_str()
+        .ok_or_else(|| {
+            DaemonError::InvalidConfig(
+                "work_dir of blob cache is required to prepare bootstrap".to_string(),
+            )
+        })
+}
+
+/// Download the layer blob `blob_id` into `writer`, and verify its sha256 digest if `verify`.
+fn download_layer<W: Write>(
+    config: BackendConfig,
+    blob_id: &str,
+    verify: bool,
+    writer: &mut W,
+) -> io::Result<()> {
+    let reader = BLOB_FACTORY.new_reader(config, blob_id)?;
+    let size = reader.blob_size().map_err(|e| eother!(e))?;
+    let mut hasher = RafsDigest::hasher(digest::Algorithm::Sha256);
+    let mut buf = vec![0u8; FETCH_BUF_SIZE];
+    let mut offset = 0;
+
+    while offset < size {
+        let len = std::cmp::min(size - offset, buf.len() as u64) as usize;
+        let n = reader
+            .read(&mut buf[..len], offset)
+            .map_err(|e| eother!(e))?;
+        if n == 0 {
+            return Err(eother!("unexpected end of layer blob"));
+        }
+        hasher.digest_update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+        offset += n as u64;
+    }
+
+    let actual = hasher.digest_finalize().to_string();
+    if verify && actual != blob_id {
+        return Err(einval!(format!(
+            "layer digest mismatches, expect {}, got {}",
+            blob_id, actual
+        )));
+    }
+
+    Ok(())
+}
+
+/// Extract the bootstrap file from the gzip compressed tar stream of a bootstrap layer.
+fn unpack_bootstrap(layer: &mut File, bootstrap: &Path) -> io::Result<()> {
+    let mut archive = Archive::new(GzDecoder::new(layer));
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? != Path::new(BOOTSTRAP_FILE_IN_LAYER) {
+            continue;
+        }
+        return save_file(&mut entry, bootstrap);
+    }
+
+    Err(enoent!(format!(
+        "no {} in bootstrap layer",
+        BOOTSTRAP_FILE_IN_LAYER
+    )))
+}
+
+/// Save data read from `reader` to the file `path`.
+fn save_file<R: Read>(reader: &mut R, path: &Path) -> io::Result<()> {
+    // Write into a temporary file and rename it, so concurrent mounts never see a partial file,
+    // and concurrent fetches of the same file don't write the same temporary file.
+    let tmp = temp_path(path, ".tmp");
+    let mut save = || -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        io::copy(reader, &mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    };
+    save().map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_image_ref() {
+        let cases = [
+            ("busybox", "registry-1.docker.io", "library/busybox"),
+            (
+                "docker.io/library/busybox:latest",
+                "registry-1.docker.io",
+                "library/busybox",
+            ),
+            ("user/app:v1", "registry-1.docker.io", "user/app"),
+            ("localhost:5000/app@sha256:abcd", "localhost:5000", "app"),
+            (
+                "my-registry.com/ns/app:v1@sha256:abcd",
+                "my-registry.com",
+                "ns/app",
+            ),
+        ];
+        for (reference, host, repo) in cases.iter() {
+            assert_eq!(
+                parse_image_ref(reference).unwrap(),
+                (host.to_string(), repo.to_string())
+            );
+        }
+        assert!(parse_image_ref("my-registry.com/").is_err());
+    }
+
+    #[test]
+    fn test_parse_image_tag() {
+        assert_eq!(parse_image_tag("busybox"), "latest");
+        assert_eq!(parse_image_tag("docker.io/library/busybox:1.35"), "1.35");
+        assert_eq!(parse_image_tag("localhost:5000/app"), "latest");
+        assert_eq!(
+            parse_image_tag("localhost:5000/app:v1@sha256:abcd"),
+            "sha256:abcd"
+        );
+    }
+
+    #[test]
+    fn test_prepare_mount_local() {
+        // The platform label is only needed to select manifests of remote images.
+        let mut cmd = FsBackendMountCmd {
+            fs_type: FsBackendType::Rafs,
+            source: "/path/to/bootstrap".to_string(),
+            config: "{}".to_string(),
+            mountpoint: "/".to_string(),
+            prefetch_files: None,
+            labels: [(LABEL_PLATFORM.to_string(), "linux".to_string())]
+                .iter()
+                .cloned()
+                .collect(),
+            source_file: None,
+        };
+        prepare_mount(&mut cmd).unwrap();
+        assert_eq!(cmd.source, "/path/to/bootstrap");
+
+        cmd.source = "manifest://sha256:abcd".to_string();
+        let err = prepare_mount(&mut cmd).unwrap_err();
+        assert!(format!("{:?}", err).contains("invalid platform"));
+    }
+
+    #[test]
+    fn test_parse_platform() {
+        assert_eq!(
+            Platform::parse("linux/arm64/v8").unwrap(),
+            Platform {
+                os: "linux".to_string(),
+                architecture: "arm64".to_string(),
+                variant: Some("v8".to_string()),
+            }
+        );
+        assert_eq!(
+            Platform::parse("linux/amd64").unwrap().to_string(),
+            "linux/amd64"
+        );
+        assert!(Platform::parse("linux").is_err());
+        assert!(Platform::parse("linux//v8").is_err());
+        assert!(Platform::parse("linux/arm/v7/x").is_err());
+    }
+
+    #[test]
+    fn test_select_manifest() {
+        let platform = Platform::current();
+        let arch = platform.architecture.as_str();
+        let index = serde_json::json!({
+            "manifests": [
+                {"digest": "sha256:other", "platform": {"os": "linux", "architecture": "s390"}},
+                {"digest": "sha256:oci", "platform": {"os": "linux", "architecture": arch}},
+                {"digest": "sha256:nydus", "platform": {
+                    "os": "linux", "architecture": arch, "os.features": [NYDUS_OS_FEATURE]
+                }}
+            ]
+        });
+        assert_eq!(select_manifest(&index, &platform).unwrap(), "sha256:nydus");
+
+        let index = serde_json::json!({
+            "manifests": [
+                {"digest": "sha256:oci", "platform": {"os": "linux", "architecture": arch}}
+            ]
+        });
+        assert_eq!(select_manifest(&index, &platform).unwrap(), "sha256:oci");
+        assert!(select_manifest(&serde_json::json!({"manifests": []}), &platform).is_none());
+
+        // Manifests of multi-platform nydus images are selected by the specified platform.
+        let index = serde_json::json!({
+            "manifests": [
+                {"digest": "sha256:amd64", "platform": {
+                    "os": "linux", "architecture": "amd64", "os.features": [NYDUS_OS_FEATURE]
+                }},
+                {"digest": "sha256:armv7", "platform": {
+                    "os": "linux", "architecture": "arm", "variant": "v7",
+                    "os.features": [NYDUS_OS_FEATURE]
+                }},
+                {"digest": "sha256:armv6", "platform": {
+                    "os": "linux", "architecture": "arm", "variant": "v6",
+                    "os.features": [NYDUS_OS_FEATURE]
+                }}
+            ]
+        });
+        let platform = Platform::parse("linux/arm/v6").unwrap();
+        assert_eq!(select_manifest(&index, &platform).unwrap(), "sha256:armv6");
+        let platform = Platform::parse("linux/amd64").unwrap();
+        assert_eq!(select_manifest(&index, &platform).unwrap(), "sha256:amd64");
+        let platform = Platform::parse("linux/arm64").unwrap();
+        assert!(select_manifest(&index, &platform).is_none());
+    }
+
+    #[test]
+    fn test_bootstrap_layer() {
+        let manifest = serde_json::json!({
+            "layers": [
+                {"digest": "sha256:blob"},
+                {"digest": "sha256:boot", "annotations": {BOOTSTRAP_LAYER_ANNOTATION: "true"}},
+                {"digest": "sha256:extra"}
+            ]
+        });
+        assert_eq!(bootstrap_layer(&manifest).unwrap(), "sha256:boot");
+
+        let manifest = serde_json::json!({
+            "layers": [{"digest": "sha256:blob"}, {"digest": "sha256:boot"}]
+        });
+        assert_eq!(bootstrap_layer(&manifest).unwrap(), "sha256:boot");
+        assert!(bootstrap_layer(&serde_json::json!({"layers": []})).is_none());
+    }
+
+    #[test]
+    fn test_fill_registry_config() {
+        let mut config: Value = serde_json::from_str(
+            r#"{"device":{"backend":{"type":"registry","config":{"scheme":"https","host":""}}}}"#,
+        )
+        .unwrap();
+        assert!(fill_registry_config(&mut config, "my-registry.com/ns/app:v1").unwrap());
+        assert_eq!(
+            config["device"]["backend"]["config"]["host"],
+            "my-registry.com"
+        );
+        assert_eq!(config["device"]["backend"]["config"]["repo"], "ns/app");
+        // Existing configuration takes precedence.
+        assert!(!fill_registry_config(&mut config, "busybox").unwrap());
+        assert_eq!(config["device"]["backend"]["config"]["repo"], "ns/app");
+
+        let mut config: Value = serde_json::from_str(
+            r#"{"device":{"backend":{"type":"localfs","config":{"dir":"/tmp"}}}}"#,
+        )
+        .unwrap();
+        assert!(!fill_registry_config(&mut config, "busybox").unwrap());
+    }
+
+    #[test]
+    fn test_remote_source() {
+        assert!(is_remote_source("manifest://v1"));
+        assert!(is_remote_source("blob://sha256:abcd"));
+        assert!(!is_remote_source("/path/to/bootstrap"));
+
+        let hex = "a".repeat(64);
+        assert_eq!(sha256_hex(&format!("sha256:{}", hex)), Some(hex.as_str()));
+        assert_eq!(sha256_hex(&hex), Some(hex.as_str()));
+        assert!(sha256_hex("sha256:abcd").is_none());
+        assert!(sha256_hex("path/to/object").is_none());
+    }
+
+    #[test]
+    fn test_fetch_bootstrap_object() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let blob_dir = dir.as_path().join("blobs");
+        let work_dir = dir.as_path().join("cache");
+        fs::create_dir_all(&blob_dir).unwrap();
+        fs::write(blob_dir.join("bootstrap"), b"rafs bootstrap").unwrap();
+        let config = serde_json::json!({
+            "device": {
+                "backend": {"type": "localfs", "config": {"dir": blob_dir}},
+                "cache": {"type": "blobcache", "config": {"work_dir": work_dir}}
+            }
+        });
+
+        let bootstrap =
+            fetch_remote_bootstrap(&config, "blob://bootstrap", &Platform::current()).unwrap();
+        assert!(bootstrap.starts_with(&work_dir));
+        assert_eq!(fs::read(&bootstrap).unwrap(), b"rafs bootstrap");
+        assert!(fetch_remote_bootstrap(&config, "blob://missing", &Platform::current()).is_err());
+        assert!(fetch_remote_bootstrap(&config, "blob://", &Platform::current()).is_err());
+        // No temporary file is left behind.
+        assert_eq!(fs::read_dir(&work_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_fetch_bootstrap_signature() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let blob_dir = dir.as_path().join("blobs");
+        let work_dir = dir.as_path().join("cache");
+        fs::create_dir_all(&blob_dir).unwrap();
+        fs::write(blob_dir.join("bootstrap"), b"rafs bootstrap").unwrap();
+        fs::write(blob_dir.join("bootstrap.sig"), b"signature").unwrap();
+        let mut config = serde_json::json!({
+            "device": {
+                "backend": {"type": "localfs", "config": {"dir": blob_dir}},
+                "cache": {"type": "blobcache", "config": {"work_dir": work_dir}}
+            },
+            "verify_signature": {"public_key": "/path/to/key.pub"}
+        });
+
+        let bootstrap =
+            fetch_remote_bootstrap(&config, "blob://bootstrap", &Platform::current()).unwrap();
+        assert_eq!(
+            fs::read(default_signature_path(&bootstrap)).unwrap(),
+            b"signature"
+        );
+        let source = format!("blob://sha256:{}", "a".repeat(64));
+        assert!(matches!(
+            fetch_remote_bootstrap(&config, &source, &Platform::current()),
+            Err(DaemonError::InvalidConfig(_))
+        ));
+
+        fs::remove_file(blob_dir.join("bootstrap.sig")).unwrap();
+        assert!(fetch_remote_bootstrap(&config, "blob://bootstrap", &Platform::current()).is_err());
+        config["verify_signature"]["signature"] = serde_json::json!("/path/to/bootstrap.sig");
+        assert!(fetch_remote_bootstrap(&config, "blob://bootstrap", &Platform::current()).is_ok());
+    }
+
+    #[test]
+    fn test_unpack_bootstrap() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let layer_path = dir.as_path().join("layer");
+        let bootstrap = dir.as_path().join("bootstrap");
+
+        let gz = flate2::write::GzEncoder::new(
+            File::create(&layer_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(gz);
+        let data = b"rafs bootstrap";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, BOOTSTRAP_FILE_IN_LAYER, &data[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let mut layer = File::open(&layer_path).unwrap();
+        unpack_bootstrap(&mut layer, &bootstrap).unwrap();
+        assert_eq!(fs::read(&bootstrap).unwrap(), data);
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mount stargz and eStargz layers directly.
+//!
+//! A stargz layer is a gzip compressed tarball, with a TOC (table of contents) recording the
+//! gzip stream offset of each file chunk appended at the end, and a footer pointing to the TOC.
+//! To mount a stargz layer, nydusd fetches the TOC from the storage backend and converts it to
+//! a rafs bootstrap, which maps file chunks to gzip offsets in the layer, so data is fetched
+//! from the original stargz blob on demand. The bootstrap is saved in the blob cache working
+//! directory and reused by following mounts of the same layer.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tar::Archive;
+
+use nydus::LABEL_STARGZ_TOC_DIGEST;
+use nydus_utils::digest::{self, RafsDigest};
+use storage::backend::BlobReader;
+use storage::factory::{BackendConfig, BLOB_FACTORY};
+
+use crate::daemon::{DaemonError, DaemonResult, FsBackendMountCmd};
+use crate::snapshot::cache_work_dir;
+
+/// Size of the eStargz footer, a gzip stream with the TOC offset in a `SG` extra subfield.
+const ESTARGZ_FOOTER_SIZE: usize = 51;
+/// Size of the legacy stargz footer, a gzip stream with the TOC offset as the extra field.
+const STARGZ_FOOTER_SIZE: usize = 47;
+const STARGZ_MAGIC: &[u8] = b"STARGZ";
+const TOC_FILE_NAME: &str = "stargz.index.json";
+const NYDUS_IMAGE: &str = "nydus-image";
+/// Maximum size of a response from the builder helper.
+const MAX_HELPER_RESPONSE: u64 = 4096;
+
+lazy_static! {
+    /// Connection to the helper process spawning `nydus-image`, if it has been started.
+    static ref BUILDER_HELPER: Mutex<Option<UnixStream>> = Mutex::new(None);
+}
+
+/// Request to the builder helper to convert the TOC file `toc` to the bootstrap `bootstrap`.
+#[derive(Serialize, Deserialize)]
+struct BuildRequest {
+    toc: PathBuf,
+    blob_id: String,
+    bootstrap: PathBuf,
+}
+
+/// Fork a helper process to spawn `nydus-image` for converting stargz layers.
+///
+/// nydusd isn't allowed to execute programs once the seccomp filter is applied, so conversions
+/// are delegated to the helper, which must be forked before any other thread is created. The
+/// helper only runs `nydus-image` to convert TOC files, and exits together with nydusd.
+pub fn start_builder_helper() -> io::Result<()> {
+    let (parent, child) = UnixStream::pair()?;
+    match unsafe { libc::fork() } {
+        -1 => Err(last_error!("failed to fork builder helper")),
+        0 => {
+            drop(parent);
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+            serve_build_requests(&child);
+            unsafe { libc::_exit(0) }
+        }
+        _ => {
+            drop(child);
+            *BUILDER_HELPER.lock().unwrap() = Some(parent);
+            Ok(())
+        }
+    }
+}
+
+// Convert TOC files requested by nydusd until the connection is closed. Errors are reported by
+// their messages, since the helper has no logger.
+fn serve_build_requests(mut stream: &UnixStream) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        let result = serde_json::from_str::<BuildRequest>(&line)
+            .map_err(|e| einval!(e))
+            .and_then(|req| run_builder(&req.toc, &req.blob_id, &req.bootstrap));
+        let resp = json!({ "error": result.err().map(|e| e.to_string()) });
+        if writeln!(stream, "{}", resp).is_err() {
+            return;
+        }
+    }
+}
+
+// Ask the builder helper to convert the TOC file and wait for the result.
+fn request_build(mut helper: &UnixStream, req: &BuildRequest) -> io::Result<()> {
+    let req = serde_json::to_string(req).map_err(|e| eother!(e))?;
+    writeln!(helper, "{}", req)?;
+    let mut line = String::new();
+    BufReader::new(helper.take(MAX_HELPER_RESPONSE)).read_line(&mut line)?;
+    let resp: Value = serde_json::from_str(&line)
+        .map_err(|e| eother!(format!("invalid response of builder helper, {}", e)))?;
+    match resp["error"].as_str() {
+        Some(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        None => Ok(()),
+    }
+}
+
+/// Prepare the rafs bootstrap for the stargz layer specified by `cmd.source`, which is the
+/// digest of the layer and the blob id in the storage backend.
+pub fn prepare_bootstrap(cmd: &FsBackendMountCmd) -> DaemonResult<PathBuf> {
+    let blob_id = cmd.source.strip_prefix("sha256:").unwrap_or(&cmd.source);
+    if blob_id.is_empty() || blob_id.contains('/') {
+        return Err(DaemonError::InvalidArguments(format!(
+            "invalid stargz layer {}",
+            cmd.source
+        )));
+    }
+
+    let config: Value = serde_json::from_str(&cmd.config).map_err(DaemonError::Serde)?;
+    let work_dir = cache_work_dir(&config)?;
+    let bootstrap = Path::new(work_dir).join(format!("{}.stargz.boot", blob_id));
+    if bootstrap.exists() {
+        info!(
+            "reuse bootstrap {:?} of stargz layer {}",
+            bootstrap, blob_id
+        );
+        return Ok(bootstrap);
+    }
+
+    let backend_config: BackendConfig =
+        serde_json::from_value(config["device"]["backend"].clone()).map_err(DaemonError::Serde)?;
+    let toc_digest = cmd.labels.get(LABEL_STARGZ_TOC_DIGEST).map(|d| d.as_str());
+    let convert = || -> io::Result<()> {
+        fs::create_dir_all(work_dir)?;
+        let reader = BLOB_FACTORY.new_reader(backend_config, blob_id)?;
+        let toc = fetch_toc(reader.as_ref(), toc_digest)?;
+        let toc_path = Path::new(work_dir).join(format!("{}.{}", blob_id, TOC_FILE_NAME));
+        File::create(&toc_path)?.write_all(&toc)?;
+        let result = build_bootstrap(&toc_path, blob_id, &bootstrap);
+        let _ = fs::remove_file(&toc_path);
+        result
+    };
+    convert().map_err(|e| {
+        DaemonError::DaemonFailure(format!(
+            "failed to prepare bootstrap for stargz layer {}, {}",
+            blob_id, e
+        ))
+    })?;
+    info!(
+        "converted stargz layer {} to bootstrap {:?}",
+        blob_id, bootstrap
+    );
+
+    Ok(bootstrap)
+}
+
+/// Parse the TOC offset from the footer at the end of a stargz or eStargz layer, return the
+/// offset and the size of the footer.
+fn parse_footer(tail: &[u8]) -> io::Result<(u64, usize)> {
+    // The extra field starts after the 10 bytes gzip header and the 2 bytes XLEN.
+    let parse = |footer: &[u8], xlen: usize, payload: usize| -> Option<u64> {
+        if footer[0] != 0x1f || footer[1] != 0x8b || footer[3] & 0x4 == 0 {
+            return None;
+        }
+        if u16::from_le_bytes([footer[10], footer[11]]) as usize != xlen {
+            return None;
+        }
+        let payload = &footer[payload..payload + 22];
+        if &payload[16..] != STARGZ_MAGIC {
+            return None;
+        }
+        let offset = std::str::from_utf8(&payload[..16]).ok()?;
+        u64::from_str_radix(offset, 16).ok()
+    };
+
+    if tail.len() >= ESTARGZ_FOOTER_SIZE {
+        let footer = &tail[tail.len() - ESTARGZ_FOOTER_SIZE..];
+        // Subfield `SG` with 22 bytes of data.
+        if &footer[12..16] == b"SG\x16\x00" {
+            if let Some(offset) = parse(footer, 26, 16) {
+                return Ok((offset, ESTARGZ_FOOTER_SIZE));
+            }
+        }
+    }
+    if tail.len() >= STARGZ_FOOTER_SIZE {
+        let footer = &tail[tail.len() - STARGZ_FOOTER_SIZE..];
+        if let Some(offset) = parse(footer, 22, 12) {
+            return Ok((offset, STARGZ_FOOTER_SIZE));
+        }
+    }
+
+    Err(einval!("no valid stargz footer found"))
+}
+
+/// Fetch the TOC of the stargz layer, and verify it with `toc_digest` if given.
+fn fetch_toc(reader: &dyn BlobReader, toc_digest: Option<&str>) -> io::Result<Vec<u8>> {
+    let size = reader.blob_size().map_err(|e| eother!(e))?;
+    if size < ESTARGZ_FOOTER_SIZE as u64 {
+        return Err(einval!("stargz layer is too small"));
+    }
+    let tail = read_range(
+        reader,
+        size - ESTARGZ_FOOTER_SIZE as u64,
+        ESTARGZ_FOOTER_SIZE,
+    )?;
+    let (toc_offset, footer_size) = parse_footer(&tail)?;
+    let toc_end = size - footer_size as u64;
+    if toc_offset >= toc_end {
+        return Err(einval!(format!("invalid stargz TOC offset {}", toc_offset)));
+    }
+
+    let toc_tar = read_range(reader, toc_offset, (toc_end - toc_offset) as usize)?;
+    let toc = extract_toc(&toc_tar)?;
+    if let Some(expected) = toc_digest {
+        let actual = RafsDigest::from_buf(&toc, digest::Algorithm::Sha256);
+        if format!("sha256:{}", actual) != expected {
+            return Err(einval!(format!(
+                "stargz TOC digest mismatches, expect {}, got sha256:{}",
+                expected, actual
+            )));
+        }
+    }
+
+    Ok(toc)
+}
+
+fn read_range(reader: &dyn BlobReader, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; size];
+    let mut pos = 0;
+    while pos < size {
+        let n = reader
+            .read(&mut buf[pos..], offset + pos as u64)
+            .map_err(|e| eother!(e))?;
+        if n == 0 {
+            return Err(eother!("unexpected end of stargz layer"));
+        }
+        pos += n;
+    }
+
+    Ok(buf)
+}
+
+/// Extract the TOC file from the gzip compressed tar stream at the end of the layer.
+fn extract_toc(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut archive = Archive::new(GzDecoder::new(data));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == Path::new(TOC_FILE_NAME) {
+            let mut toc = Vec::new();
+            entry.read_to_end(&mut toc)?;
+            return Ok(toc);
+        }
+    }
+
+    Err(enoent!(format!("no {} in stargz layer", TOC_FILE_NAME)))
+}
+
+/// Convert the TOC to a rafs bootstrap by `nydus-image`, through the builder helper if it has
+/// been started.
+fn build_bootstrap(toc: &Path, blob_id: &str, bootstrap: &Path) -> io::Result<()> {
+    let mut tmp_name = bootstrap.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+
+    // Conversions are serialized by the connection to the helper.
+    let result = match BUILDER_HELPER.lock().unwrap().as_ref() {
+        Some(helper) => request_build(
+            helper,
+            &BuildRequest {
+                toc: toc.to_path_buf(),
+                blob_id: blob_id.to_string(),
+                bootstrap: tmp.clone(),
+            },
+        ),
+        None => run_builder(toc, blob_id, &tmp),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    fs::rename(&tmp, bootstrap)
+}
+
+/// Run `nydus-image` to convert the TOC file to the bootstrap, which is looked up in the
+/// directory of nydusd first and then `PATH`.
+fn run_builder(toc: &Path, blob_id: &str, bootstrap: &Path) -> io::Result<()> {
+    let builder = std::env::current_exe()
+        .ok()
+        .and_then(|p| p.parent().map(|d| d.join(NYDUS_IMAGE)))
+        .filter(|p| p.exists())
+        .unwrap_or_else(|| PathBuf::from(NYDUS_IMAGE));
+
+    let status = Command::new(&builder)
+        .arg("create")
+        .args(&["--source-type", "stargz_index"])
+        .arg("--bootstrap")
+        .arg(bootstrap)
+        .args(&["--blob-id", blob_id])
+        .args(&["--log-level", "warn"])
+        .arg(toc)
+        .status()
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to execute {:?}, {}", builder, e),
+            )
+        })?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{:?} exits with {}", builder, status),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip_footer(extra: &[u8]) -> Vec<u8> {
+        let mut footer = vec![0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff];
+        footer.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        footer.extend_from_slice(extra);
+        // Empty deflate block, crc32 and input size.
+        footer.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+        footer.extend_from_slice(&[0u8; 8]);
+        footer
+    }
+
+    #[test]
+    fn test_parse_footer() {
+        let payload = format!("{:016x}STARGZ", 0x1234);
+        let mut extra = b"SG\x16\x00".to_vec();
+        extra.extend_from_slice(payload.as_bytes());
+        let mut tail = vec![0u8; 10];
+        tail.extend_from_slice(&gzip_footer(&extra));
+        assert_eq!(tail.len(), ESTARGZ_FOOTER_SIZE + 10);
+        assert_eq!(parse_footer(&tail).unwrap(), (0x1234, ESTARGZ_FOOTER_SIZE));
+
+        let mut tail = vec![0u8; 4];
+        tail.extend_from_slice(&gzip_footer(payload.as_bytes()));
+        assert_eq!(tail.len(), ESTARGZ_FOOTER_SIZE);
+        assert_eq!(parse_footer(&tail).unwrap(), (0x1234, STARGZ_FOOTER_SIZE));
+
+        let payload = format!("{:016x}NOTGZ!", 0x1234);
+        let tail = gzip_footer(payload.as_bytes());
+        assert!(parse_footer(&tail).is_err());
+        assert!(parse_footer(&[0u8; 8]).is_err());
+    }
+
+    #[test]
+    fn test_extract_toc() {
+        let gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(gz);
+        let data = br#"{"version":1,"entries":[]}"#;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, TOC_FILE_NAME, &data[..])
+            .unwrap();
+        let stream = builder.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(extract_toc(&stream).unwrap(), data.to_vec());
+        assert!(extract_toc(&stream[..10]).is_err());
+    }
+
+    #[test]
+    fn test_builder_helper() {
+        let (parent, child) = UnixStream::pair().unwrap();
+        let helper = std::thread::spawn(move || serve_build_requests(&child));
+
+        // Errors of the builder are reported back.
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let req = BuildRequest {
+            toc: dir.as_path().join("missing.json"),
+            blob_id: "blob-1".to_string(),
+            bootstrap: dir.as_path().join("blob-1.boot"),
+        };
+        let err = request_build(&parent, &req).unwrap_err();
+        assert!(err.to_string().contains(NYDUS_IMAGE), "{}", err);
+        assert!(!req.bootstrap.exists());
+
+        // Invalid requests are rejected without running the builder.
+        writeln!(&parent, "garbage").unwrap();
+        let mut line = String::new();
+        BufReader::new(&parent).read_line(&mut line).unwrap();
+        assert!(serde_json::from_str::<Value>(&line).unwrap()["error"].is_string());
+
+        drop(parent);
+        helper.join().unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Opt-in reporting of anonymized usage statistics.
+//!
+//! Operators of large fleets want to know how nydusd is used across their nodes, e.g. which
+//! versions are running, how many filesystems are mounted and how effective the blob cache is.
+//! When enabled by `--telemetry-endpoint`, nydusd periodically posts a report of aggregate
+//! counters to the endpoint as JSON. Reports carry no mountpoints, image references, blob ids,
+//! hostnames or configurations, only a random instance id generated at startup to tell reports of
+//! different processes apart.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nydus_utils::metrics;
+use serde_json::{json, Value};
+
+use crate::daemon::{DaemonError, DaemonResult, NydusDaemon};
+
+/// Timeout of connecting to, sending reports to and receiving responses from the endpoint.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum size of the response status line read from the endpoint.
+const MAX_STATUS_LINE: u64 = 1024;
+
+/// HTTP endpoint to receive reports, in the form of `http://<host>[:<port>][/<path>]`, where
+/// IPv6 hosts are enclosed in brackets, e.g. `http://[::1]:8080`.
+#[derive(Debug, PartialEq)]
+struct Endpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> std::result::Result<Self, String> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            format!(
+                "telemetry endpoint {} is not a http:// URL, forward reports by a local agent for https",
+                url
+            )
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        let invalid_host = || format!("invalid host of telemetry endpoint {}", url);
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']').ok_or_else(invalid_host)?;
+                match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None if rest.is_empty() => (host, None),
+                    None => return Err(invalid_host()),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, _)) if host.contains(':') => return Err(invalid_host()),
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid_host());
+        }
+        let port = match port {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port of telemetry endpoint {}", url))?,
+            None => 80,
+        };
+
+        Ok(Endpoint {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    // Post the JSON `report` and check the response status.
+    fn post(&self, report: &Value) -> Result<()> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no address of telemetry endpoint"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let body = report.to_string();
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            host,
+            self.port,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes())?;
+
+        let mut status = String::new();
+        // Only the status line matters, don't let the endpoint make us buffer an unbounded line.
+        BufReader::new(stream.take(MAX_STATUS_LINE)).read_line(&mut status)?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                format!("telemetry endpoint responds {}", status.trim_end()),
+            )),
+        }
+    }
+}
+
+// Generate a random id for the process, unrelated to the host and the daemon id.
+fn instance_id() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/random/uuid")
+        .map(|id| id.trim().to_string())
+        .unwrap_or_default()
+}
+
+// Build a report of aggregate statistics of the daemon.
+fn build_report(daemon: &dyn NydusDaemon, instance: &str, uptime: Duration) -> Value {
+    let (hits, reads) = metrics::blobcache_hits();
+    let hit_ratio = if reads == 0 {
+        0.0
+    } else {
+        hits as f64 / reads as f64
+    };
+    let version = daemon.version();
+
+    json!({
+        "instance": instance,
+        "version": version.package_ver,
+        "git_commit": version.git_commit,
+        "uptime_secs": uptime.as_secs(),
+        "mounts": daemon.backend_collection().count_by_type(),
+        "cache_reads": reads,
+        "cache_hits": hits,
+        "cache_hit_ratio": hit_ratio,
+    })
+}
+
+/// Post a report of anonymized usage statistics to `endpoint` every `interval`.
+pub fn start_telemetry(
+    daemon: Arc<dyn NydusDaemon + Send + Sync>,
+    endpoint: &str,
+    interval: Duration,
+) -> DaemonResult<()> {
+    let endpoint = Endpoint::parse(endpoint).map_err(DaemonError::InvalidArguments)?;
+    let instance = instance_id();
+    let started = Instant::now();
+
+    thread::Builder::new()
+        .name("telemetry".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let report = build_report(daemon.as_ref(), &instance, started.elapsed());
+            // Failures are only logged, reports are best effort and never affect the daemon.
+            endpoint
+                .post(&report)
+                .unwrap_or_else(|e| warn!("failed to send telemetry report, {}", e));
+        })
+        .map_err(DaemonError::ThreadSpawn)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            Endpoint::parse("http://stats.example.com:8080/v1/report").unwrap(),
+            Endpoint {
+                host: "stats.example.com".to_string(),
+                port: 8080,
+                path: "/v1/report".to_string(),
+            }
+        );
+        assert_eq!(
+            Endpoint::parse("http://127.0.0.1").unwrap(),
+            Endpoint {
+                host: "127.0.0.1".to_string(),
+                port: 80,
+                path: "/".to_string(),
+            }
+        );
+        assert!(Endpoint::parse("https://stats.example.com").is_err());
+        assert!(Endpoint::parse("http://stats.example.com:http/").is_err());
+        assert!(Endpoint::parse("http://:8080/").is_err());
+        assert_eq!(
+            Endpoint::parse("http://[::1]:8080/report").unwrap(),
+            Endpoint {
+                host: "::1".to_string(),
+                port: 8080,
+                path: "/report".to_string(),
+            }
+        );
+        assert_eq!(Endpoint::parse("http://[fe80::1]").unwrap().port, 80);
+        assert!(Endpoint::parse("http://::1:8080/").is_err());
+        assert!(Endpoint::parse("http://[::1/").is_err());
+        assert!(Endpoint::parse("http://[::1]8080/").is_err());
+        assert!(Endpoint::parse("http://[]:8080/").is_err());
+    }
+
+    #[test]
+    fn test_post_report() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+            let mut buf = vec![0u8; 4096];
+            let mut request = String::new();
+            while !request.ends_with('}') {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0);
+                request.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            request
+        });
+
+        let endpoint = Endpoint::parse(&format!("http://127.0.0.1:{}/report", port)).unwrap();
+        endpoint.post(&json!({ "version": "2.0.0" })).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /report HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"version\":\"2.0.0\"}"));
+    }
+
+    #[test]
+    fn test_post_report_long_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(&vec![b'x'; 1 << 20]);
+        });
+
+        let endpoint = Endpoint::parse(&format!("http://127.0.0.1:{}/report", port)).unwrap();
+        let err = endpoint.post(&json!({ "version": "2.0.0" })).unwrap_err();
+        assert!(err.to_string().len() < MAX_STATUS_LINE as usize + 64);
+        server.join().unwrap();
+    }
+}
@@ -5,42 +5,59 @@
 // SPDX-License-Identifier: (Apache-2.0 AND BSD-3-Clause)
 
 use std::any::Any;
-use std::io::Result;
+use std::fs::{self, Permissions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::mem::size_of;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicI32, Ordering},
+    atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering},
     mpsc::{channel, Receiver},
-    Arc, Mutex, MutexGuard, RwLock,
+    Arc, Condvar, Mutex, MutexGuard,
 };
 use std::thread;
 
 use libc::EFD_NONBLOCK;
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::sys::socket::{
+    bind, getsockname, getsockopt, listen, shutdown, socket, sockopt, AddressFamily, Shutdown,
+    SockAddr, SockFlag, SockType, UnixCredentials,
+};
+use nix::sys::stat::{fchmod, Mode};
+use nix::unistd::Pid;
 
+use fuse_backend_rs::abi::linux_abi::{InHeader, Opcode, OutHeader};
 use fuse_backend_rs::api::{server::Server, Vfs};
 use fuse_backend_rs::transport::{FsCacheReqHandler, Reader, Writer};
 
 use vhost::vhost_user::{message::*, Listener, SlaveFsCacheReq};
-use vhost_user_backend::{
-    VhostUserBackend, VhostUserBackendMut, VhostUserDaemon, VringMutex, VringState, VringT,
-};
+use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, VringMutex, VringState, VringT};
 use virtio_bindings::bindings::virtio_ring::{
     VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
 };
 use virtio_queue::DescriptorChain;
-use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryLoadGuard, GuestMemoryMmap};
+use vm_memory::{
+    ByteValued, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryLoadGuard,
+    GuestMemoryMmap, GuestMemoryRegion,
+};
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 
 use nydus_app::BuildTimeInfo;
 
 use crate::daemon::{
-    DaemonError, DaemonResult, DaemonState, DaemonStateMachineContext, DaemonStateMachineInput,
-    DaemonStateMachineSubscriber, FsBackendCollection, FsBackendMountCmd, NydusDaemon, Trigger,
+    interrupt_request, is_held_read, DaemonError, DaemonResult, DaemonState,
+    DaemonStateMachineContext, DaemonStateMachineInput, DaemonStateMachineSubscriber,
+    FsBackendCollection, FsBackendMountCmd, NydusDaemon, RequestTracker, Trigger,
 };
 use crate::upgrade::UpgradeManager;
 
 const VIRTIO_F_VERSION_1: u32 = 32;
 const QUEUE_SIZE: usize = 1024;
 const NUM_QUEUES: usize = 2;
+// Maximum number of held reads served off the vring worker, others are served by the worker.
+const MAX_HELD_READS: usize = 64;
 
 // The guest queued an available buffer for the high priority queue.
 const HIPRIO_QUEUE_EVENT: u16 = 0;
@@ -48,35 +65,309 @@ const HIPRIO_QUEUE_EVENT: u16 = 0;
 const REQ_QUEUE_EVENT: u16 = 1;
 // The device has been dropped.
 // const KILL_EVENT: u16 = 2;
+// Storage backends stopped being saturated, resume the parked request queue.
+const REQ_QUEUE_RESUME_EVENT: u16 = 3;
 
 type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
 
+// Number of held reads being served off the vring worker.
+static HELD_READS: AtomicUsize = AtomicUsize::new(0);
+
+/// Backpressure on the request queue while storage backends are saturated.
+///
+/// Instead of pulling requests from the avail ring only to wait for saturated backends, the worker
+/// of the request queue parks the queue and leaves requests in the guest. The queue is re-armed by
+/// `resume_evt`, which is signaled once backends stop being saturated.
+struct Backpressure {
+    parked: AtomicBool,
+    resume_evt: EventFd,
+    saturated: fn() -> bool,
+}
+
+impl Backpressure {
+    fn new(saturated: fn() -> bool) -> Result<Self> {
+        Ok(Backpressure {
+            parked: AtomicBool::new(false),
+            resume_evt: EventFd::new(EFD_NONBLOCK).map_err(DaemonError::Epoll)?,
+            saturated,
+        })
+    }
+
+    // Park the request queue if backends are saturated, and return whether it's parked.
+    fn park(&self) -> bool {
+        if !(self.saturated)() {
+            return false;
+        }
+        // Mark the queue as parked before checking again, so either the check sees backends
+        // aren't saturated any more, or `resume()` sees the queue parked and re-arms it.
+        self.parked.store(true, Ordering::SeqCst);
+        if (self.saturated)() {
+            return true;
+        }
+        self.parked.store(false, Ordering::SeqCst);
+        false
+    }
+
+    // Re-arm the request queue if it's parked, called once backends stop being saturated.
+    fn resume(&self) {
+        if self.parked.swap(false, Ordering::SeqCst) {
+            if let Err(e) = self.resume_evt.write(1) {
+                error!("failed to resume request queue, {}", e);
+            }
+        }
+    }
+}
+
+/// Access control of the vhost-user socket.
+#[derive(Clone, Debug, Default)]
+pub struct SockAccess {
+    /// Permission bits of the socket file.
+    pub mode: u32,
+    /// Uids of processes allowed to connect.
+    pub allowed_uids: Vec<u32>,
+    /// Gids of processes allowed to connect.
+    pub allowed_gids: Vec<u32>,
+}
+
+impl SockAccess {
+    fn allows(&self, cred: &UnixCredentials) -> bool {
+        (self.allowed_uids.is_empty() && self.allowed_gids.is_empty())
+            || self.allowed_uids.contains(&cred.uid())
+            || self.allowed_gids.contains(&cred.gid())
+    }
+
+    fn is_restricted(&self) -> bool {
+        !self.allowed_uids.is_empty() || !self.allowed_gids.is_empty()
+    }
+
+    /// Check credentials of front-ends connected to the socket `sock` against the allow-list.
+    fn verify_peers(&self, sock: &Path) -> Result<()> {
+        let peers = session_peers(sock)?;
+        if peers.is_empty() {
+            return Err(eacces!("no vhost-user session found to verify"));
+        }
+        for (fd, cred) in peers.iter() {
+            if !self.allows(cred) {
+                // Safe to ignore errors because the session is refused anyway.
+                let _ = shutdown(*fd, Shutdown::Both);
+                return Err(eacces!(format!(
+                    "vhost-user front-end pid {} uid {} gid {} is not allowed",
+                    cred.pid(),
+                    cred.uid(),
+                    cred.gid()
+                )));
+            }
+            info!(
+                "vhost-user front-end pid {} uid {} gid {} connected",
+                cred.pid(),
+                cred.uid(),
+                cred.gid()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Verdict on the vhost-user front-end, made as soon as its connection is accepted.
+#[derive(Default)]
+struct PeerVerdict {
+    allowed: Mutex<Option<bool>>,
+    cond: Condvar,
+}
+
+impl PeerVerdict {
+    fn set(&self, allowed: bool) {
+        *self.allowed.lock().unwrap() = Some(allowed);
+        self.cond.notify_all();
+    }
+
+    /// Wait until the front-end has been verified, and return whether it's allowed.
+    fn wait(&self) -> bool {
+        let mut allowed = self.allowed.lock().unwrap();
+        while allowed.is_none() {
+            allowed = self.cond.wait(allowed).unwrap();
+        }
+        allowed.unwrap()
+    }
+}
+
+/// Create the listening vhost-user socket at `path` with permission bits `mode`.
+///
+/// Permissions of a unix socket file are taken from the socket when it's bound, so set them on the
+/// socket before binding it, and no process can connect before the permissions are in effect.
+fn bind_socket(path: &Path, mode: u32) -> Result<Listener> {
+    let _ = fs::remove_file(path);
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .map_err(|e| eother!(e))?;
+    // Safe because the fd has just been created and is owned by the listener from now on.
+    let listener = unsafe { Listener::from_raw_fd(fd) };
+    fchmod(fd, Mode::from_bits_truncate(mode)).map_err(|e| eother!(e))?;
+    bind(fd, &SockAddr::new_unix(path).map_err(|e| eother!(e))?).map_err(|e| eother!(e))?;
+    // The umask applies on binding, which only removes permission bits, so restore them.
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    listen(fd, 1).map_err(|e| eother!(e))?;
+
+    Ok(listener)
+}
+
+/// Get credentials of peers connected to the listening unix socket `sock`.
+///
+/// The vhost-user daemon accepts connections internally, so look for accepted sockets among
+/// opened file descriptors, whose local address is the path of the listening socket.
+fn session_peers(sock: &Path) -> Result<Vec<(RawFd, UnixCredentials)>> {
+    let mut peers = Vec::new();
+
+    for entry in fs::read_dir("/proc/self/fd")? {
+        let fd: RawFd = match entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+        // Skip listening sockets, whose peer credentials are those of the listener.
+        if getsockopt(fd, sockopt::AcceptConn) != Ok(false) {
+            continue;
+        }
+        match getsockname(fd) {
+            Ok(SockAddr::Unix(addr)) if addr.path() == Some(sock) => {}
+            _ => continue,
+        }
+        if let Ok(cred) = getsockopt(fd, sockopt::PeerCredentials) {
+            peers.push((fd, cred));
+        }
+    }
+
+    Ok(peers)
+}
+
+/// Parse a comma separated list of uids or gids, such as `0,107`.
+pub fn parse_id_list(list: &str) -> Result<Vec<u32>> {
+    list.split(',')
+        .map(|id| {
+            id.trim()
+                .parse()
+                .map_err(|_| einval!(format!("invalid id {}", id)))
+        })
+        .collect()
+}
+
 struct VhostUserFsBackendHandler {
-    backend: Mutex<VhostUserFsBackend>,
+    // One backend for each vring, so vrings are processed by their own worker threads in parallel.
+    backends: Vec<Mutex<VhostUserFsBackend>>,
+    // CPUs to pin vring worker threads to, the nth worker is pinned to the nth CPU modulo length.
+    affinity: Vec<usize>,
+    pinned: Vec<AtomicBool>,
+    // Guest memory shared by all backends, updated without waiting for vrings being processed.
+    mem: GuestMemoryHolder,
+    // Whether the vhost-user front-end has set up the guest memory.
+    connected: Arc<AtomicBool>,
+    peer: Arc<PeerVerdict>,
+    backpressure: Arc<Backpressure>,
 }
 
+// The guest memory may consist of multiple regions, and regions may be added or removed by memory
+// hotplug of the VM. `GuestMemoryAtomic` swaps the whole memory map atomically on each update, and
+// requests keep using the snapshot they're started with, so regions removed are only unmapped once
+// all requests using them are done.
+type GuestMemoryHolder = Arc<Mutex<Option<GuestMemoryAtomic<GuestMemoryMmap>>>>;
+
 struct VhostUserFsBackend {
-    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    mem: GuestMemoryHolder,
     kill_evt: EventFd,
     event_idx: bool,
     server: Arc<Server<Arc<Vfs>>>,
     // handle request from slave to master
     vu_req: Option<SlaveFsCacheReq>,
+    backpressure: Arc<Backpressure>,
 }
 
 impl VhostUserFsBackendHandler {
-    fn new(vfs: Arc<Vfs>) -> Result<Self> {
+    fn new(
+        vfs: Arc<Vfs>,
+        affinity: Vec<usize>,
+        connected: Arc<AtomicBool>,
+        peer: Arc<PeerVerdict>,
+    ) -> Result<Self> {
+        let mem: GuestMemoryHolder = Arc::new(Mutex::new(None));
+        let backpressure = Arc::new(Backpressure::new(storage::backend::is_saturated)?);
+        let weak = Arc::downgrade(&backpressure);
+        storage::backend::add_saturation_listener(Arc::new(move || {
+            if let Some(backpressure) = weak.upgrade() {
+                backpressure.resume();
+            }
+        }));
         let backend = VhostUserFsBackend {
-            mem: None,
+            mem: mem.clone(),
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(DaemonError::Epoll)?,
             event_idx: false,
             server: Arc::new(Server::new(vfs)),
             vu_req: None,
+            backpressure: backpressure.clone(),
         };
         Ok(VhostUserFsBackendHandler {
-            backend: Mutex::new(backend),
+            backends: (0..NUM_QUEUES)
+                .map(|_| Mutex::new(backend.clone()))
+                .collect(),
+            affinity,
+            pinned: (0..NUM_QUEUES).map(|_| AtomicBool::new(false)).collect(),
+            mem,
+            connected,
+            peer,
+            backpressure,
         })
     }
+
+    fn for_each_backend<F: FnMut(&mut VhostUserFsBackend)>(&self, mut f: F) {
+        for backend in self.backends.iter() {
+            f(&mut backend.lock().unwrap());
+        }
+    }
+
+    // Worker threads are created by vhost-user-backend, so pin them on their first event.
+    fn pin_worker(&self, thread_id: usize) {
+        if self.affinity.is_empty() || self.pinned[thread_id].swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let cpu = self.affinity[thread_id % self.affinity.len()];
+        let mut cpu_set = CpuSet::new();
+        let ret = cpu_set
+            .set(cpu)
+            .and_then(|_| sched_setaffinity(Pid::from_raw(0), &cpu_set));
+        match ret {
+            Ok(_) => info!("pin vring worker {} to cpu {}", thread_id, cpu),
+            Err(e) => warn!(
+                "failed to pin vring worker {} to cpu {}, {}",
+                thread_id, cpu, e
+            ),
+        }
+    }
+}
+
+/// Parse a CPU list like `0-3,8,10-11`.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for item in list.split(',').map(|i| i.trim()) {
+        let parse = |v: &str| {
+            v.parse::<usize>()
+                .map_err(|e| einval!(format!("invalid cpu {}, {}", v, e)))
+        };
+        let (start, end) = match item.find('-') {
+            Some(pos) => (parse(&item[..pos])?, parse(&item[pos + 1..])?),
+            None => (parse(item)?, parse(item)?),
+        };
+        if start > end || end >= CpuSet::count() {
+            return Err(einval!(format!("invalid cpu range {}", item)));
+        }
+        cpus.extend(start..=end);
+    }
+
+    Ok(cpus)
 }
 
 impl Clone for VhostUserFsBackend {
@@ -87,77 +378,221 @@ impl Clone for VhostUserFsBackend {
             event_idx: self.event_idx,
             server: self.server.clone(),
             vu_req: self.vu_req.clone(),
+            backpressure: self.backpressure.clone(),
         }
     }
 }
 
+// Requests allowed on the high priority queue. None of them touches storage backends, so they
+// are never stuck behind slow reads on the request queue.
+fn is_hiprio_request(opcode: u32) -> bool {
+    opcode == Opcode::Forget as u32
+        || opcode == Opcode::BatchForget as u32
+        || opcode == Opcode::Interrupt as u32
+}
+
 impl VhostUserFsBackend {
+    // Reply EINVAL to a request not allowed on the high priority queue, without handling it.
+    fn reject_request(
+        mem: &GuestMemoryLoadGuard<GuestMemoryMmap>,
+        chain: DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>,
+        ih: &InHeader,
+    ) -> Result<usize> {
+        let mut writer = Writer::new(mem, chain).map_err(DaemonError::InvalidDescriptorChain)?;
+        let oh = OutHeader {
+            len: size_of::<OutHeader>() as u32,
+            error: -libc::EINVAL,
+            unique: ih.unique,
+        };
+        writer.write_all(oh.as_slice())?;
+
+        Ok(writer.bytes_written())
+    }
+
+    // Serve a read held by a frozen mount in a new thread, so the vring worker goes on serving
+    // other requests. Return the chain back if there are too many held reads already.
+    fn serve_held_read(
+        &self,
+        mem: GuestMemoryLoadGuard<GuestMemoryMmap>,
+        chain: DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>,
+        vring: &VringMutex,
+    ) -> Result<Option<DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>>> {
+        if HELD_READS.fetch_add(1, Ordering::AcqRel) >= MAX_HELD_READS {
+            HELD_READS.fetch_sub(1, Ordering::AcqRel);
+            warn!("too many held reads, serve held read on the vring worker");
+            return Ok(Some(chain));
+        }
+
+        let server = self.server.clone();
+        let mut vu_req = self.vu_req.clone();
+        let vring = vring.clone();
+        let event_idx = self.event_idx;
+        thread::Builder::new()
+            .name("held_read".to_string())
+            .spawn(move || {
+                let head_index = chain.head_index();
+                let ret = Reader::new(&mem, chain.clone())
+                    .and_then(|r| Writer::new(&mem, chain).map(|w| (r, w)))
+                    .map_err(DaemonError::InvalidDescriptorChain)
+                    .and_then(|(reader, writer)| {
+                        server
+                            .handle_message(
+                                reader,
+                                writer,
+                                vu_req.as_mut().map(|x| x as &mut dyn FsCacheReqHandler),
+                                Some(&RequestTracker {}),
+                            )
+                            .map_err(DaemonError::ProcessQueue)
+                    });
+                match ret {
+                    Ok(len) => {
+                        let mut vring_state = vring.get_mut();
+                        if vring_state.add_used(head_index, len as u32).is_err() {
+                            warn!("Couldn't return used descriptors to the ring");
+                        }
+                        notify_guest(&mut vring_state, event_idx);
+                    }
+                    Err(e) => error!("failed to serve held read, {}", e),
+                }
+                HELD_READS.fetch_sub(1, Ordering::AcqRel);
+            })
+            .map_err(DaemonError::ThreadSpawn)?;
+
+        Ok(None)
+    }
+
     // There's no way to recover if error happens during processing a virtq, let the caller
     // to handle it.
-    fn process_queue(&mut self, vring_state: &mut MutexGuard<VringState>) -> Result<bool> {
+    //
+    // Return true if the request queue is parked by backpressure with requests left in the avail
+    // ring, which is processed again on `REQ_QUEUE_RESUME_EVENT`.
+    fn process_queue(
+        &mut self,
+        vring: &VringMutex,
+        vring_state: &mut MutexGuard<VringState>,
+        hiprio: bool,
+    ) -> Result<bool> {
         let mut used_any = false;
-        let mem = self
+        let mut parked = false;
+        let atomic_mem = self
             .mem
-            .as_ref()
-            .ok_or(DaemonError::NoMemoryConfigured)?
-            .memory();
-
-        let avail_chains: Vec<DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>> = vring_state
-            .get_queue_mut()
-            .iter()
-            .map_err(|_| DaemonError::IterateQueue)?
-            .collect();
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(DaemonError::NoMemoryConfigured)?;
 
-        for chain in avail_chains {
+        loop {
+            // Leave requests in the avail ring while storage backends are saturated, instead of
+            // pulling them into the daemon to wait for backends. Requests on the high priority
+            // queue never touch storage backends.
+            if !hiprio && self.backpressure.park() {
+                parked = true;
+                break;
+            }
+            let chain = match vring_state
+                .get_queue_mut()
+                .iter()
+                .map_err(|_| DaemonError::IterateQueue)?
+                .next()
+            {
+                Some(chain) => chain,
+                None => break,
+            };
             used_any = true;
+            // Load the latest memory map for each request, so descriptors pointing to regions
+            // hotplugged while processing the queue are accessible.
+            let mem = atomic_mem.memory();
 
             let head_index = chain.head_index();
 
+            // Reads of frozen mounts wait until the mounts are thawed, don't let them block
+            // other requests on the queue.
+            let chain = if !hiprio && storage::device::any_frozen() {
+                let held = Reader::new(&mem, chain.clone())
+                    .ok()
+                    .and_then(|mut r| r.read_obj::<InHeader>().ok())
+                    .map(|ih| is_held_read(&ih))
+                    .unwrap_or(false);
+                if held {
+                    match self.serve_held_read(atomic_mem.memory(), chain, vring)? {
+                        Some(chain) => chain,
+                        None => continue,
+                    }
+                } else {
+                    chain
+                }
+            } else {
+                chain
+            };
+
+            if hiprio {
+                // Malformed headers are left to the server to report.
+                let mut peek = Reader::new(&mem, chain.clone()).ok();
+                let ih = peek.as_mut().and_then(|r| r.read_obj::<InHeader>().ok());
+                if let (Some(ih), Some(r)) = (ih.as_ref(), peek.as_mut()) {
+                    if ih.opcode == Opcode::Interrupt as u32 {
+                        interrupt_request(r);
+                    }
+                }
+                if let Some(ih) = ih.filter(|ih| !is_hiprio_request(ih.opcode)) {
+                    warn!(
+                        "unexpected opcode {} on high priority queue, unique {}",
+                        ih.opcode, ih.unique
+                    );
+                    let len = Self::reject_request(&mem, chain, &ih)?;
+                    if vring_state.add_used(head_index, len as u32).is_err() {
+                        warn!("Couldn't return used descriptors to the ring");
+                    }
+                    continue;
+                }
+            }
+
             let reader =
                 Reader::new(&mem, chain.clone()).map_err(DaemonError::InvalidDescriptorChain)?;
             let writer =
                 Writer::new(&mem, chain.clone()).map_err(DaemonError::InvalidDescriptorChain)?;
 
-            self.server
+            let len = self
+                .server
                 .handle_message(
                     reader,
                     writer,
                     self.vu_req
                         .as_mut()
                         .map(|x| x as &mut dyn FsCacheReqHandler),
-                    None,
+                    Some(&RequestTracker {}),
                 )
                 .map_err(DaemonError::ProcessQueue)?;
 
-            if self.event_idx {
-                if vring_state.add_used(head_index, 0).is_err() {
-                    warn!("Couldn't return used descriptors to the ring");
-                }
-
-                match vring_state.needs_notification() {
-                    Err(_) => {
-                        warn!("Couldn't check if queue needs to be notified");
-                        vring_state.signal_used_queue().unwrap();
-                    }
-                    Ok(needs_notification) => {
-                        if needs_notification {
-                            vring_state.signal_used_queue().unwrap();
-                        }
-                    }
-                }
-            } else {
-                if vring_state.add_used(head_index, 0).is_err() {
-                    warn!("Couldn't return used descriptors to the ring");
-                }
-                vring_state.signal_used_queue().unwrap();
+            // Return all handled descriptors to the used ring before notifying the guest once.
+            if vring_state.add_used(head_index, len as u32).is_err() {
+                warn!("Couldn't return used descriptors to the ring");
             }
         }
 
-        Ok(used_any)
+        if used_any {
+            notify_guest(vring_state, self.event_idx);
+        }
+
+        Ok(parked)
     }
 }
 
-impl VhostUserBackendMut<VringMutex> for VhostUserFsBackendHandler {
+// Notify the guest of descriptors returned to the used ring.
+fn notify_guest(vring_state: &mut MutexGuard<VringState>, event_idx: bool) {
+    // With EVENT_IDX, the guest is only notified if it has asked for notifications since the
+    // last one.
+    let needs_notification = !event_idx
+        || vring_state.needs_notification().unwrap_or_else(|_| {
+            warn!("Couldn't check if queue needs to be notified");
+            true
+        });
+    if needs_notification && vring_state.signal_used_queue().is_err() {
+        warn!("Couldn't signal used queue");
+    }
+}
+
+impl VhostUserBackend<VringMutex> for VhostUserFsBackendHandler {
     fn num_queues(&self) -> usize {
         NUM_QUEUES
     }
@@ -167,6 +602,9 @@ impl VhostUserBackendMut<VringMutex> for VhostUserFsBackendHandler {
     }
 
     fn features(&self) -> u64 {
+        // Features are the first thing queried by a front-end, so hold the session here until the
+        // front-end has been verified. A refused session has been shut down by then.
+        self.peer.wait();
         1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_RING_F_INDIRECT_DESC
             | 1 << VIRTIO_RING_F_EVENT_IDX
@@ -174,78 +612,115 @@ impl VhostUserBackendMut<VringMutex> for VhostUserFsBackendHandler {
     }
 
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
-        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::SLAVE_REQ
+        // CONFIGURE_MEM_SLOTS lets the front-end add and remove memory regions on memory hotplug,
+        // instead of resetting the whole memory table.
+        VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::SLAVE_REQ
+            | VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS
     }
 
-    fn set_event_idx(&mut self, _enabled: bool) {
-        self.backend.lock().unwrap().event_idx = true
+    fn set_event_idx(&self, enabled: bool) {
+        self.for_each_backend(|b| b.event_idx = enabled)
     }
 
-    fn update_memory(
-        &mut self,
-        mem: GuestMemoryAtomic<GuestMemoryMmap>,
-    ) -> VhostUserBackendResult<()> {
-        self.backend.lock().unwrap().mem = Some(mem);
+    fn update_memory(&self, mem: GuestMemoryAtomic<GuestMemoryMmap>) -> VhostUserBackendResult<()> {
+        if !self.peer.wait() {
+            return Err(eacces!("vhost-user front-end is not allowed"));
+        }
+        {
+            let snapshot = mem.memory();
+            info!(
+                "guest memory updated, {} regions of {} bytes",
+                snapshot.num_regions(),
+                snapshot.iter().map(|r| r.len()).sum::<u64>()
+            );
+        }
+        *self.mem.lock().unwrap() = Some(mem);
+        self.connected.store(true, Ordering::Release);
         Ok(())
     }
 
     fn handle_event(
-        &mut self,
+        &self,
         device_event: u16,
         evset: EventSet,
         vrings: &[VringMutex],
-        _thread_id: usize,
+        thread_id: usize,
     ) -> VhostUserBackendResult<bool> {
         if evset != EventSet::IN {
             return Err(DaemonError::HandleEventNotEpollIn.into());
         }
 
-        let mut vring_state = match device_event {
+        let vring = match device_event {
             HIPRIO_QUEUE_EVENT => {
                 debug!("HIPRIO_QUEUE_EVENT");
-                vrings[0].get_mut()
+                &vrings[0]
             }
             REQ_QUEUE_EVENT => {
                 debug!("QUEUE_EVENT");
-                vrings[1].get_mut()
+                &vrings[1]
+            }
+            REQ_QUEUE_RESUME_EVENT => {
+                debug!("REQ_QUEUE_RESUME_EVENT");
+                // Safe to ignore errors because the queue is processed anyway.
+                let _ = self.backpressure.resume_evt.read();
+                &vrings[1]
             }
             _ => return Err(DaemonError::HandleEventUnknownEvent.into()),
         };
+        let mut vring_state = vring.get_mut();
+        // The request queue may have been stopped since it was parked.
+        if device_event == REQ_QUEUE_RESUME_EVENT && !vring_state.get_queue().ready() {
+            return Ok(false);
+        }
 
-        if self.backend.lock().unwrap().event_idx {
+        let hiprio = device_event == HIPRIO_QUEUE_EVENT;
+        self.pin_worker(thread_id);
+        let mut backend = self.backends[thread_id].lock().unwrap();
+        if backend.event_idx {
             // vm-virtio's Queue implementation only checks avail_index
             // once, so to properly support EVENT_IDX we need to keep
             // calling process_queue() until it stops finding new
             // requests on the queue.
+            // A parked queue is left with notifications disabled, until it's resumed.
             loop {
                 vring_state.disable_notification().unwrap();
-                self.backend
-                    .lock()
-                    .unwrap()
-                    .process_queue(&mut vring_state)?;
+                if backend.process_queue(vring, &mut vring_state, hiprio)? {
+                    break;
+                }
                 if !vring_state.enable_notification().unwrap() {
                     break;
                 }
             }
         } else {
             // Without EVENT_IDX, a single call is enough.
-            self.backend
-                .lock()
-                .unwrap()
-                .process_queue(&mut vring_state)?;
+            backend.process_queue(vring, &mut vring_state, hiprio)?;
         }
 
         Ok(false)
     }
 
-    fn exit_event(&self, _thread_index: usize) -> Option<EventFd> {
+    // One worker thread for each vring, so the high priority queue is served by a dedicated
+    // worker and never waits for requests on the request queue.
+    fn queues_per_thread(&self) -> Vec<u64> {
+        (0..NUM_QUEUES).map(|i| 1u64 << i).collect()
+    }
+
+    fn exit_event(&self, thread_index: usize) -> Option<EventFd> {
         // FIXME: need to patch vhost-user-backend to return KILL_EVENT
         // so that daemon stop event gets popped up.
-        Some(self.backend.lock().unwrap().kill_evt.try_clone().unwrap())
+        Some(
+            self.backends[thread_index]
+                .lock()
+                .unwrap()
+                .kill_evt
+                .try_clone()
+                .unwrap(),
+        )
     }
 
-    fn set_slave_req_fd(&mut self, vu_req: SlaveFsCacheReq) {
-        self.backend.lock().unwrap().vu_req = Some(vu_req);
+    fn set_slave_req_fd(&self, vu_req: SlaveFsCacheReq) {
+        self.for_each_backend(|b| b.vu_req = Some(vu_req.clone()));
     }
 }
 
@@ -253,6 +728,8 @@ struct VirtiofsDaemon<S: 'static + VhostUserBackend<VringMutex> + Clone> {
     vfs: Arc<Vfs>,
     daemon: Arc<Mutex<VhostUserDaemon<S, VringMutex>>>,
     sock: String,
+    access: SockAccess,
+    peer: Arc<PeerVerdict>,
     id: Option<String>,
     supervisor: Option<String>,
     upgrade_mgr: Option<Mutex<UpgradeManager>>,
@@ -261,22 +738,38 @@ struct VirtiofsDaemon<S: 'static + VhostUserBackend<VringMutex> + Clone> {
     backend_collection: Mutex<FsBackendCollection>,
     bti: BuildTimeInfo,
     state: AtomicI32,
+    connected: Arc<AtomicBool>,
 }
 
 impl<S: 'static + VhostUserBackend<VringMutex> + Clone> NydusDaemon for VirtiofsDaemon<S> {
     fn start(&self) -> DaemonResult<()> {
-        let listener = Listener::new(&self.sock, true)
+        let sock = PathBuf::from(&self.sock);
+        let listener = bind_socket(&sock, self.access.mode)
             .map_err(|e| DaemonError::StartService(format!("{:?}", e)))?;
 
         let vu_daemon = self.daemon.clone();
+        let access = self.access.clone();
+        let peer = self.peer.clone();
         let _ = thread::Builder::new()
             .name("vhost_user_listener".to_string())
             .spawn(move || {
-                vu_daemon
-                    .lock()
-                    .unwrap()
-                    .start(listener)
-                    .unwrap_or_else(|e| error!("{:?}", e));
+                // Only one front-end is served, so remove the socket once it has connected.
+                let started = vu_daemon.lock().unwrap().start(listener);
+                let _ = fs::remove_file(&sock);
+                if let Err(e) = started {
+                    error!("{:?}", e);
+                    peer.set(false);
+                } else if access.is_restricted() {
+                    match access.verify_peers(&sock) {
+                        Ok(()) => peer.set(true),
+                        Err(e) => {
+                            error!("refused vhost-user session, {}", e);
+                            peer.set(false);
+                        }
+                    }
+                } else {
+                    peer.set(true);
+                }
             })
             .map_err(DaemonError::ThreadSpawn)?;
 
@@ -315,6 +808,18 @@ impl<S: 'static + VhostUserBackend<VringMutex> + Clone> NydusDaemon for Virtiofs
         self.state.store(state as i32, Ordering::Relaxed);
     }
 
+    fn check_session(&self) -> Result<()> {
+        // The daemon exits once the vhost-user session ends, so it's alive if it has been set up.
+        if self.connected.load(Ordering::Acquire) {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::Other,
+                "vhost-user front-end has not connected",
+            ))
+        }
+    }
+
     fn save(&self) -> DaemonResult<()> {
         unimplemented!();
     }
@@ -366,16 +871,35 @@ pub fn create_nydus_daemon(
     id: Option<String>,
     supervisor: Option<String>,
     sock: &str,
+    access: SockAccess,
     vfs: Arc<Vfs>,
     mount_cmd: Option<FsBackendMountCmd>,
+    affinity: Vec<usize>,
     bti: BuildTimeInfo,
-) -> Result<Arc<dyn NydusDaemon + Send>> {
+) -> Result<Arc<dyn NydusDaemon + Send + Sync>> {
+    let connected = Arc::new(AtomicBool::new(false));
+    let peer = Arc::new(PeerVerdict::default());
+    let handler = Arc::new(VhostUserFsBackendHandler::new(
+        vfs.clone(),
+        affinity,
+        connected.clone(),
+        peer.clone(),
+    )?);
+    let backpressure = handler.backpressure.clone();
     let vu_daemon = VhostUserDaemon::new(
         String::from("vhost-user-fs-backend"),
-        Arc::new(RwLock::new(VhostUserFsBackendHandler::new(vfs.clone())?)),
+        handler,
         GuestMemoryAtomic::new(GuestMemoryMmap::new()),
     )
     .map_err(|e| DaemonError::DaemonFailure(format!("{:?}", e)))?;
+    // Resume the parked request queue on the worker thread of the request queue.
+    vu_daemon.get_epoll_handlers()[REQ_QUEUE_EVENT as usize]
+        .register_listener(
+            backpressure.resume_evt.as_raw_fd(),
+            EventSet::IN,
+            REQ_QUEUE_RESUME_EVENT as u64,
+        )
+        .map_err(DaemonError::Epoll)?;
 
     let (trigger, events_rx) = channel::<DaemonStateMachineInput>();
     let (result_sender, result_receiver) = channel::<DaemonResult<()>>();
@@ -384,6 +908,8 @@ pub fn create_nydus_daemon(
         vfs,
         daemon: Arc::new(Mutex::new(vu_daemon)),
         sock: sock.to_string(),
+        access,
+        peer,
         id,
         supervisor,
         upgrade_mgr: None,
@@ -392,6 +918,7 @@ pub fn create_nydus_daemon(
         bti,
         backend_collection: Default::default(),
         state: AtomicI32::new(DaemonState::INIT as i32),
+        connected,
     });
 
     let machine = DaemonStateMachineContext::new(daemon.clone(), events_rx, result_sender);
@@ -410,3 +937,98 @@ pub fn create_nydus_daemon(
 
     Ok(daemon)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_id_list() {
+        assert_eq!(parse_id_list("0, 107").unwrap(), vec![0, 107]);
+        assert!(parse_id_list("0,root").is_err());
+    }
+
+    #[test]
+    fn test_session_peers() {
+        use std::io::Read;
+        use std::os::unix::net::UnixStream;
+        use vmm_sys_util::tempdir::TempDir;
+
+        let dir = TempDir::new().unwrap();
+        let sock = dir.as_path().join("vhost.sock");
+        let listener = bind_socket(&sock, 0o660).unwrap();
+        assert_eq!(
+            fs::metadata(&sock).unwrap().permissions().mode() & 0o777,
+            0o660
+        );
+        assert!(session_peers(&sock).unwrap().is_empty());
+
+        let mut client = UnixStream::connect(&sock).unwrap();
+        let _conn = listener.accept().unwrap().unwrap();
+        let peers = session_peers(&sock).unwrap();
+        assert_eq!(peers.len(), 1);
+        let cred = peers[0].1;
+        assert_eq!(cred.uid(), nix::unistd::geteuid().as_raw());
+
+        let mut access = SockAccess::default();
+        assert!(!access.is_restricted());
+        access.verify_peers(&sock).unwrap();
+        access.allowed_gids = vec![cred.gid()];
+        access.verify_peers(&sock).unwrap();
+
+        // A refused session is shut down.
+        access.allowed_uids = vec![cred.uid() + 1];
+        access.allowed_gids = vec![cred.gid() + 1];
+        assert!(access.verify_peers(&sock).is_err());
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_peer_verdict() {
+        let peer = Arc::new(PeerVerdict::default());
+        let p = peer.clone();
+        let waiter = thread::spawn(move || p.wait());
+        peer.set(false);
+        assert!(!waiter.join().unwrap());
+        assert!(!peer.wait());
+    }
+
+    #[test]
+    fn test_backpressure() {
+        static SATURATED: AtomicBool = AtomicBool::new(false);
+        fn saturated() -> bool {
+            SATURATED.load(Ordering::SeqCst)
+        }
+
+        let backpressure = Backpressure::new(saturated).unwrap();
+        assert!(!backpressure.park());
+        // Nothing to resume if the queue isn't parked.
+        backpressure.resume();
+        assert!(backpressure.resume_evt.read().is_err());
+
+        SATURATED.store(true, Ordering::SeqCst);
+        assert!(backpressure.park());
+        assert!(backpressure.resume_evt.read().is_err());
+        SATURATED.store(false, Ordering::SeqCst);
+        backpressure.resume();
+        assert_eq!(backpressure.resume_evt.read().unwrap(), 1);
+        // Resumed once only.
+        backpressure.resume();
+        assert!(backpressure.resume_evt.read().is_err());
+        assert!(!backpressure.park());
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert_eq!(
+            parse_cpu_list("0-2, 5,7-8").unwrap(),
+            vec![0, 1, 2, 5, 7, 8]
+        );
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a-1").is_err());
+        assert!(parse_cpu_list("100000").is_err());
+    }
+}
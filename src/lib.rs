@@ -4,6 +4,7 @@
 
 extern crate serde_json;
 
+use std::collections::HashMap;
 use std::fmt::{self, Display};
 use std::str::FromStr;
 
@@ -11,6 +12,17 @@ use chrono::{self, DateTime, Local};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 
+/// Label of containerd snapshots carrying the reference of the image, e.g.
+/// `docker.io/library/busybox:latest`.
+pub const LABEL_IMAGE_REF: &str = "containerd.io/snapshot/cri.image-ref";
+/// Label of containerd snapshots carrying the digest of the layer, e.g. `sha256:<hex>`.
+pub const LABEL_LAYER_DIGEST: &str = "containerd.io/snapshot/cri.layer-digest";
+/// Label of containerd snapshots carrying the digest of the TOC of an eStargz layer.
+pub const LABEL_STARGZ_TOC_DIGEST: &str = "containerd.io/snapshot/stargz/toc.digest";
+/// Label of containerd snapshots carrying the platform of the image, e.g. `linux/arm64/v8`, to
+/// select the manifest from multi-platform images mounted by reference.
+pub const LABEL_PLATFORM: &str = "containerd.io/snapshot/nydus-platform";
+
 /// Error code related to Nydus library.
 #[derive(Debug)]
 pub enum NydusError {
@@ -31,6 +43,8 @@ pub type Result<T> = std::result::Result<T, NydusError>;
 pub enum FsBackendType {
     Rafs,
     PassthroughFs,
+    /// Stargz or eStargz layer, served as rafs converted from the TOC of the layer.
+    Stargz,
 }
 
 impl FromStr for FsBackendType {
@@ -39,8 +53,9 @@ impl FromStr for FsBackendType {
         match s {
             "rafs" => Ok(FsBackendType::Rafs),
             "passthrough_fs" => Ok(FsBackendType::PassthroughFs),
+            "stargz" => Ok(FsBackendType::Stargz),
             o => Err(NydusError::InvalidArguments(format!(
-                "Fs backend type only accepts 'rafs' and 'passthrough_fs', but {} was specified",
+                "Fs backend type only accepts 'rafs', 'passthrough_fs' and 'stargz', but {} was specified",
                 o
             ))),
         }
@@ -58,7 +73,12 @@ impl Display for FsBackendType {
 pub struct FsBackendDesc {
     pub backend_type: FsBackendType,
     pub mountpoint: String,
+    #[serde(default)]
+    pub source: String,
     #[serde_as(as = "DisplayFromStr")]
     pub mounted_time: DateTime<Local>,
     pub config: Option<serde_json::Value>,
+    /// Labels attached to the mount by the client, such as containerd snapshot labels.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
 }
@@ -12,25 +12,69 @@ use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
+use nydus_utils::metrics::ErrorClass;
 use reqwest::header::HeaderMap;
 use reqwest::{
     self,
-    blocking::{Body, Client, Response},
+    blocking::{Body, Client, ClientBuilder, Response},
     redirect::Policy,
-    Method, StatusCode, Url,
+    Certificate, Identity, Method, StatusCode, Url,
 };
 
-use crate::backend::CommonConfig;
+use crate::backend::limiter::{ConcurrencyLimiter, Permit};
+use crate::backend::{request, CommonConfig, TlsConfig};
 
 const HEADER_AUTHORIZATION: &str = "Authorization";
 
 /// Error codes related to network communication.
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum ConnectionError {
+    #[error("connection is shut down")]
     Disconnected,
-    ErrorWithMsg(String),
+    /// The server responds with an unsuccessful status code and message.
+    #[error("server responds with {0}, {1:?}")]
+    ErrorWithMsg(StatusCode, String),
+    #[error("{0}")]
     Common(reqwest::Error),
+    #[error("failed to parse response, {0}")]
     Format(reqwest::Error),
+    /// The deadline has passed while waiting for permission to send the request.
+    #[error("deadline exceeded while waiting for concurrency permit")]
+    Deadline,
+}
+
+impl ConnectionError {
+    /// Get class of the error for error accounting.
+    pub fn error_class(&self) -> ErrorClass {
+        match self {
+            ConnectionError::ErrorWithMsg(status, _) if status.is_server_error() => {
+                ErrorClass::BackendServer
+            }
+            ConnectionError::ErrorWithMsg(_, _) => ErrorClass::BackendClient,
+            ConnectionError::Common(e) | ConnectionError::Format(e) if e.is_timeout() => {
+                ErrorClass::Timeout
+            }
+            ConnectionError::Deadline => ErrorClass::Timeout,
+            _ => ErrorClass::Other,
+        }
+    }
+
+    /// Check whether the error is permanent, so retrying the request won't help.
+    ///
+    /// Missing objects and denied access are permanent. Callers refresh credentials before
+    /// reporting authentication failures, so those are permanent too.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            ConnectionError::ErrorWithMsg(status, _) => matches!(
+                *status,
+                StatusCode::UNAUTHORIZED
+                    | StatusCode::FORBIDDEN
+                    | StatusCode::NOT_FOUND
+                    | StatusCode::GONE
+            ),
+            _ => false,
+        }
+    }
 }
 
 /// Specialized `Result` for network communication.
@@ -117,8 +161,9 @@ pub(crate) fn respond(resp: Response, catch_status: bool) -> ConnectionResult<Re
     if !catch_status || is_success_status(resp.status()) {
         Ok(resp)
     } else {
+        let status = resp.status();
         let msg = resp.text().map_err(ConnectionError::Format)?;
-        Err(ConnectionError::ErrorWithMsg(msg))
+        Err(ConnectionError::ErrorWithMsg(status, msg))
     }
 }
 
@@ -127,12 +172,14 @@ pub(crate) fn respond(resp: Response, catch_status: bool) -> ConnectionResult<Re
 pub(crate) struct Connection {
     client: Client,
     proxy: Option<Proxy>,
+    limiter: Option<Arc<ConcurrencyLimiter>>,
+    timeout: Option<Duration>,
     shutdown: AtomicBool,
 }
 
 impl Connection {
-    /// Create a new connection according to the configuration.
-    pub fn new(config: &CommonConfig) -> Result<Arc<Connection>> {
+    /// Create a new connection to `host` according to the configuration.
+    pub fn new(config: &CommonConfig, host: &str) -> Result<Arc<Connection>> {
         info!("backend config: {:?}", config);
         let client = Self::build_connection("", config)?;
         let proxy = if !config.proxy.url.is_empty() {
@@ -149,9 +196,21 @@ impl Connection {
         } else {
             None
         };
+        let limiter = if config.max_concurrency > 0 {
+            Some(ConcurrencyLimiter::get(host, config.max_concurrency))
+        } else {
+            None
+        };
+        let timeout = if config.timeout != 0 {
+            Some(Duration::from_secs(config.timeout))
+        } else {
+            None
+        };
         let connection = Arc::new(Connection {
             client,
             proxy,
+            limiter,
+            timeout,
             shutdown: AtomicBool::new(false),
         });
 
@@ -196,6 +255,25 @@ impl Connection {
         self.shutdown.store(true, Ordering::Release);
     }
 
+    /// Wait for permission to read from the host if concurrent reads are limited.
+    ///
+    /// The permission should be held until the whole response body has been received. It fails
+    /// if the deadline of the backend read passes before the permission is granted.
+    pub fn acquire_permit(&self) -> ConnectionResult<Option<Permit>> {
+        match self.limiter.as_ref() {
+            Some(limiter) => limiter
+                .acquire(request::remaining_time())
+                .map(Some)
+                .ok_or(ConnectionError::Deadline),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the current limit of concurrent reads from the host, 0 if there's no limit.
+    pub fn concurrency_limit(&self) -> u64 {
+        self.limiter.as_ref().map(|l| l.limit()).unwrap_or(0)
+    }
+
     /// Send a request to server and wait for response.
     pub fn call<R: Read + Send + 'static>(
         &self,
@@ -280,10 +358,53 @@ impl Connection {
         if !proxy.is_empty() {
             cb = cb.proxy(reqwest::Proxy::all(proxy).map_err(|e| einval!(e))?)
         }
+        cb = Self::configure_tls(cb, &config.tls)?;
 
         cb.build().map_err(|e| einval!(e))
     }
 
+    fn configure_tls(mut cb: ClientBuilder, tls: &TlsConfig) -> Result<ClientBuilder> {
+        if !tls.ca_file.is_empty() {
+            let bundle = read_pem_file(&tls.ca_file)?;
+            let certs = pem_certificates(&bundle);
+            if certs.is_empty() {
+                return Err(einval!(format!(
+                    "no certificate found in CA file {}",
+                    tls.ca_file
+                )));
+            }
+            for cert in certs {
+                let cert = Certificate::from_pem(cert.as_bytes()).map_err(|e| {
+                    einval!(format!("invalid certificate in {}, {}", tls.ca_file, e))
+                })?;
+                cb = cb.add_root_certificate(cert);
+            }
+        }
+
+        match (tls.cert_file.is_empty(), tls.key_file.is_empty()) {
+            (true, true) => {}
+            (false, false) => {
+                let cert = read_pem_file(&tls.cert_file)?;
+                let key = read_pem_file(&tls.key_file)?;
+                let identity = Identity::from_pkcs8_pem(cert.as_bytes(), key.as_bytes())
+                    .map_err(|e| einval!(format!("invalid client certificate or key, {}", e)))?;
+                cb = cb.identity(identity);
+            }
+            _ => {
+                return Err(einval!(
+                    "both cert_file and key_file are required for mutual TLS"
+                ))
+            }
+        }
+
+        if tls.skip_verify {
+            warn!("TLS certificate verification of storage backend is disabled");
+            cb = cb.danger_accept_invalid_certs(true);
+        }
+
+        Ok(cb)
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn call_inner<R: Read + Send + 'static>(
         &self,
@@ -313,6 +434,10 @@ impl Connection {
         if let Some(q) = query.as_ref() {
             rb = rb.query(q);
         }
+        // Don't run past the deadline of the backend read issuing the request.
+        if let Some(remaining) = request::remaining_time() {
+            rb = rb.timeout(self.timeout.map_or(remaining, |t| t.min(remaining)));
+        }
 
         let ret;
         if let Some(data) = data {
@@ -332,6 +457,19 @@ impl Connection {
             ret = rb.body("").send();
         }
 
+        if let Some(limiter) = self.limiter.as_ref() {
+            let congested = match ret.as_ref() {
+                Ok(resp) => {
+                    resp.status() == StatusCode::TOO_MANY_REQUESTS
+                        || resp.status() == StatusCode::SERVICE_UNAVAILABLE
+                }
+                Err(err) => err.is_timeout(),
+            };
+            if congested {
+                limiter.congested();
+            }
+        }
+
         match ret {
             Err(err) => Err(ConnectionError::Common(err)),
             Ok(resp) => respond(resp, catch_status),
@@ -339,6 +477,35 @@ impl Connection {
     }
 }
 
+fn read_pem_file(path: &str) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| {
+        error!("failed to read PEM file {}, {}", path, e);
+        e
+    })
+}
+
+/// Split a PEM bundle into certificates, since only the first one is parsed by
+/// `Certificate::from_pem()`.
+fn pem_certificates(bundle: &str) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let mut certs = Vec::new();
+    let mut rest = bundle;
+    while let Some(start) = rest.find(BEGIN) {
+        match rest[start..].find(END) {
+            Some(len) => {
+                let end = start + len + END.len();
+                certs.push(&rest[start..end]);
+                rest = &rest[end..];
+            }
+            None => break,
+        }
+    }
+
+    certs
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -362,6 +529,38 @@ mod tests {
         assert_eq!(buf1[1], 4);
     }
 
+    #[test]
+    fn test_pem_certificates() {
+        let bundle = "# CA 1\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                      # CA 2\n-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n\
+                      -----BEGIN CERTIFICATE-----\nCCCC\n";
+        let certs = pem_certificates(bundle);
+        assert_eq!(certs.len(), 2);
+        assert_eq!(
+            certs[0],
+            "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----"
+        );
+        assert!(certs[1].contains("BBBB"));
+        assert!(pem_certificates("").is_empty());
+    }
+
+    #[test]
+    fn test_configure_tls() {
+        let tls = TlsConfig {
+            ca_file: String::new(),
+            cert_file: "/path/to/cert.pem".to_string(),
+            key_file: String::new(),
+            skip_verify: true,
+        };
+        assert!(Connection::configure_tls(Client::builder(), &tls).is_err());
+
+        let tls = TlsConfig {
+            ca_file: "/nonexistent/ca.pem".to_string(),
+            ..Default::default()
+        };
+        assert!(Connection::configure_tls(Client::builder(), &tls).is_err());
+    }
+
     #[test]
     fn test_proxy_health() {
         let checker = ProxyHealth::new(5, None);
@@ -383,4 +582,15 @@ mod tests {
         assert_eq!(is_success_status(StatusCode::PERMANENT_REDIRECT), true);
         assert_eq!(is_success_status(StatusCode::BAD_REQUEST), false);
     }
+
+    #[test]
+    fn test_permanent_error() {
+        let err = |status| ConnectionError::ErrorWithMsg(status, String::new());
+        assert!(err(StatusCode::NOT_FOUND).is_permanent());
+        assert!(err(StatusCode::UNAUTHORIZED).is_permanent());
+        assert!(err(StatusCode::FORBIDDEN).is_permanent());
+        assert!(!err(StatusCode::TOO_MANY_REQUESTS).is_permanent());
+        assert!(!err(StatusCode::SERVICE_UNAVAILABLE).is_permanent());
+        assert!(!ConnectionError::Disconnected.is_permanent());
+    }
 }
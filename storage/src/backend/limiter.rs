@@ -0,0 +1,391 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Adaptive concurrency limiter for requests to a remote storage host.
+//!
+//! Bursts of cold reads may issue many concurrent requests to a registry or OSS host, which may
+//! throttle them and cause retry storms. The limiter bounds the number of concurrent reads from
+//! a host by an AIMD (additive increase, multiplicative decrease) limit:
+//! - the limit is decreased by `BACKOFF_RATIO` on each throttled or timed out request.
+//! - the limit is increased by one on each completed request while more than half of the limit
+//!   is in use, up to the configured maximum.
+//!
+//! Waiting for a permit is bounded by the deadline of the backend read, if any.
+//!
+//! Demand reads take priority over prefetching: requests from prefetch threads may hold at most
+//! half of the limit, and wait while demand reads are waiting for permits, so prefetching can't
+//! starve reads of users.
+//!
+//! A limiter is saturated while demand reads are waiting for permits, so request sources like vrings
+//! may stop accepting new requests instead of queueing unbounded requests in the daemon. Sources
+//! register a listener to be notified once no limiter is saturated any more, instead of polling.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Ratio to decrease the limit by on congestion.
+const BACKOFF_RATIO: f64 = 0.9;
+
+lazy_static::lazy_static! {
+    static ref LIMITERS: Mutex<HashMap<String, Arc<ConcurrencyLimiter>>> =
+        Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    // Whether the current thread issues requests for prefetching.
+    static PREFETCH_THREAD: Cell<bool> = Cell::new(false);
+}
+
+/// Mark the current thread as a prefetch thread, whose requests yield to demand reads.
+pub(crate) fn set_prefetch_thread() {
+    PREFETCH_THREAD.with(|p| p.set(true));
+}
+
+/// Callback invoked once a limiter stops being saturated.
+pub type SaturationListener = Arc<dyn Fn() + Send + Sync>;
+
+lazy_static::lazy_static! {
+    static ref LISTENERS: Mutex<Vec<SaturationListener>> = Mutex::new(Vec::new());
+}
+
+/// Register `listener` to be notified each time a limiter stops being saturated.
+pub(crate) fn add_listener(listener: SaturationListener) {
+    LISTENERS.lock().unwrap().push(listener);
+}
+
+fn notify_listeners() {
+    for listener in LISTENERS.lock().unwrap().iter() {
+        listener();
+    }
+}
+
+struct LimiterState {
+    limit: f64,
+    inflight: usize,
+    // Number of inflight requests for prefetching.
+    prefetch_inflight: usize,
+    // Number of demand reads waiting for permits, which go before prefetch requests.
+    demand_waiting: usize,
+}
+
+impl LimiterState {
+    fn prefetch_blocked(&self) -> bool {
+        let prefetch_limit = std::cmp::max(self.limit as usize / 2, 1);
+        self.inflight >= self.limit as usize
+            || self.prefetch_inflight >= prefetch_limit
+            || self.demand_waiting > 0
+    }
+}
+
+/// AIMD concurrency limiter shared by all connections to a host.
+pub(crate) struct ConcurrencyLimiter {
+    max: usize,
+    state: Mutex<LimiterState>,
+    cond: Condvar,
+}
+
+impl ConcurrencyLimiter {
+    fn new(max: usize) -> Self {
+        ConcurrencyLimiter {
+            max,
+            state: Mutex::new(LimiterState {
+                limit: max as f64,
+                inflight: 0,
+                prefetch_inflight: 0,
+                demand_waiting: 0,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Get the limiter for `host`, the maximum limit is decided by the first user of the host.
+    pub fn get(host: &str, max: usize) -> Arc<Self> {
+        LIMITERS
+            .lock()
+            .unwrap()
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(ConcurrencyLimiter::new(max)))
+            .clone()
+    }
+
+    /// Wait until the number of inflight requests is below the limit.
+    ///
+    /// `None` is returned if no permit is available within `timeout`.
+    pub fn acquire(self: &Arc<Self>, timeout: Option<Duration>) -> Option<Permit> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let prefetch = PREFETCH_THREAD.with(|p| p.get());
+        let mut state = self.state.lock().unwrap();
+        let mut expired = false;
+        let mut unsaturated = false;
+        if prefetch {
+            while !expired && state.prefetch_blocked() {
+                let (s, e) = self.wait(state, deadline);
+                state = s;
+                expired = e;
+            }
+        } else {
+            let mut waited = false;
+            state.demand_waiting += 1;
+            while !expired && state.inflight >= state.limit as usize {
+                let (s, e) = self.wait(state, deadline);
+                state = s;
+                expired = e;
+                waited = true;
+            }
+            state.demand_waiting -= 1;
+            if state.demand_waiting == 0 {
+                // Prefetch requests may go on if no more demand reads are waiting.
+                self.cond.notify_all();
+                // The limiter was only seen saturated by others if the request has waited.
+                unsaturated = waited;
+            }
+        }
+        if !expired {
+            state.inflight += 1;
+            if prefetch {
+                state.prefetch_inflight += 1;
+            }
+        }
+        drop(state);
+        if unsaturated {
+            notify_listeners();
+        }
+        if expired {
+            return None;
+        }
+
+        Some(Permit {
+            limiter: self.clone(),
+            prefetch,
+        })
+    }
+
+    // Wait for a permit to be released, return true without waiting if `deadline` has passed.
+    fn wait<'a>(
+        &self,
+        state: MutexGuard<'a, LimiterState>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, LimiterState>, bool) {
+        match deadline {
+            None => (self.cond.wait(state).unwrap(), false),
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    (state, true)
+                } else {
+                    (
+                        self.cond.wait_timeout(state, deadline - now).unwrap().0,
+                        false,
+                    )
+                }
+            }
+        }
+    }
+
+    /// Decrease the limit because the host is throttling requests or responding slowly.
+    pub fn congested(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.limit = (state.limit * BACKOFF_RATIO).max(1.0);
+    }
+
+    /// Get the current limit.
+    pub fn limit(&self) -> u64 {
+        self.state.lock().unwrap().limit as u64
+    }
+
+    /// Check whether demand reads are waiting for permits.
+    pub fn is_saturated(&self) -> bool {
+        self.state.lock().unwrap().demand_waiting > 0
+    }
+
+    fn release(&self, prefetch: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.inflight * 2 >= state.limit as usize {
+            state.limit = (state.limit + 1.0).min(self.max as f64);
+        }
+        state.inflight -= 1;
+        if prefetch {
+            state.prefetch_inflight -= 1;
+        }
+        // Demand reads and prefetch requests wait for different conditions, wake up all of them.
+        self.cond.notify_all();
+    }
+}
+
+/// Check whether any limiter is saturated.
+pub(crate) fn any_saturated() -> bool {
+    LIMITERS.lock().unwrap().values().any(|l| l.is_saturated())
+}
+
+/// Permission to send a request, which is returned to the limiter on drop.
+pub(crate) struct Permit {
+    limiter: Arc<ConcurrencyLimiter>,
+    prefetch: bool,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(self.prefetch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_aimd_limit() {
+        let limiter = ConcurrencyLimiter::get("test_aimd_limit", 4);
+        assert_eq!(limiter.limit(), 4);
+        assert!(Arc::ptr_eq(
+            &limiter,
+            &ConcurrencyLimiter::get("test_aimd_limit", 8)
+        ));
+
+        for _ in 0..20 {
+            limiter.congested();
+        }
+        assert_eq!(limiter.limit(), 1);
+
+        drop(limiter.acquire(None).unwrap());
+        assert_eq!(limiter.limit(), 2);
+        // Sequential requests use one slot only, so the limit doesn't grow beyond twice of it.
+        for _ in 0..10 {
+            drop(limiter.acquire(None).unwrap());
+        }
+        assert_eq!(limiter.limit(), 3);
+    }
+
+    #[test]
+    fn test_acquire_blocks_at_limit() {
+        let limiter = ConcurrencyLimiter::get("test_acquire_blocks_at_limit", 1);
+        let permit = limiter.acquire(None).unwrap();
+
+        let l = limiter.clone();
+        let acquired = Arc::new(AtomicBool::new(false));
+        let flag = acquired.clone();
+        let waiter = thread::spawn(move || {
+            let _permit = l.acquire(None).unwrap();
+            flag.store(true, Ordering::Release);
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(!acquired.load(Ordering::Acquire));
+        assert!(limiter.is_saturated());
+        assert!(any_saturated());
+        drop(permit);
+        waiter.join().unwrap();
+        assert!(acquired.load(Ordering::Acquire));
+        assert!(!limiter.is_saturated());
+    }
+
+    #[test]
+    fn test_acquire_timeout() {
+        let limiter = ConcurrencyLimiter::get("test_acquire_timeout", 1);
+        let permit = limiter.acquire(Some(Duration::from_secs(0))).unwrap();
+
+        let start = Instant::now();
+        assert!(limiter.acquire(Some(Duration::from_millis(100))).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(limiter.acquire(Some(Duration::from_secs(0))).is_none());
+        // Requests given up don't keep the limiter saturated.
+        assert!(!limiter.is_saturated());
+        assert_eq!(limiter.state.lock().unwrap().inflight, 1);
+
+        drop(permit);
+        assert!(limiter.acquire(Some(Duration::from_secs(0))).is_some());
+    }
+
+    #[test]
+    fn test_saturation_listener() {
+        let limiter = ConcurrencyLimiter::get("test_saturation_listener", 1);
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let armed = Arc::new(AtomicBool::new(false));
+        let (l, a) = (limiter.clone(), armed.clone());
+        // Other tests share the listener list, only record unsaturation once this limiter has
+        // been saturated.
+        add_listener(Arc::new(move || {
+            if a.load(Ordering::Acquire) && !l.is_saturated() {
+                let _ = tx.lock().unwrap().send(());
+            }
+        }));
+        let permit = limiter.acquire(None).unwrap();
+
+        let l = limiter.clone();
+        let waiter = thread::spawn(move || {
+            let _permit = l.acquire(None).unwrap();
+        });
+        while !limiter.is_saturated() {
+            thread::yield_now();
+        }
+        armed.store(true, Ordering::Release);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(permit);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+        assert!(!limiter.is_saturated());
+    }
+
+    #[test]
+    fn test_demand_before_prefetch() {
+        let limiter = ConcurrencyLimiter::get("test_demand_before_prefetch", 4);
+        // Acquire a permit in a new thread, and hold it until `release` is dropped.
+        let spawn_acquire = |prefetch: bool| {
+            let l = limiter.clone();
+            let (acquired_tx, acquired_rx) = mpsc::channel();
+            let (release_tx, release_rx) = mpsc::channel::<()>();
+            let handle = thread::spawn(move || {
+                if prefetch {
+                    set_prefetch_thread();
+                }
+                let _permit = l.acquire(None).unwrap();
+                acquired_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            });
+            (handle, acquired_rx, release_tx)
+        };
+        let timeout = Duration::from_millis(100);
+
+        let demand1 = limiter.acquire(None).unwrap();
+        let demand2 = limiter.acquire(None).unwrap();
+        let demand3 = limiter.acquire(None).unwrap();
+        let (p1, p1_acquired, p1_release) = spawn_acquire(true);
+        p1_acquired.recv().unwrap();
+
+        // Both wait for a full limiter, the demand read goes first.
+        let (p2, p2_acquired, p2_release) = spawn_acquire(true);
+        assert!(p2_acquired.recv_timeout(timeout).is_err());
+        // Waiting prefetch requests don't saturate the limiter.
+        assert!(!limiter.is_saturated());
+        let (d4, d4_acquired, d4_release) = spawn_acquire(false);
+        assert!(d4_acquired.recv_timeout(timeout).is_err());
+        assert!(limiter.is_saturated());
+        drop(demand1);
+        d4_acquired.recv().unwrap();
+        assert!(p2_acquired.recv_timeout(timeout).is_err());
+        drop(demand2);
+        p2_acquired.recv().unwrap();
+
+        // Prefetch requests hold at most half of the limit.
+        drop(demand3);
+        drop(d4_release);
+        d4.join().unwrap();
+        let (p3, p3_acquired, p3_release) = spawn_acquire(true);
+        assert!(p3_acquired.recv_timeout(timeout).is_err());
+        drop(p1_release);
+        p1.join().unwrap();
+        p3_acquired.recv().unwrap();
+
+        drop(p2_release);
+        drop(p3_release);
+        p2.join().unwrap();
+        p3.join().unwrap();
+    }
+}
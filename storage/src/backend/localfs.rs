@@ -16,11 +16,10 @@ use std::thread;
 use std::time::Duration;
 
 use fuse_backend_rs::transport::FileVolatileSlice;
-use nix::sys::uio;
 use nydus_utils::{metrics::BackendMetrics, round_down_4k, try_round_up_4k};
 
 use crate::backend::{BackendError, BackendResult, BlobBackend, BlobReader};
-use crate::utils::{readahead, readv, MemSliceCursor};
+use crate::utils::{pread, readahead, readv, MemSliceCursor};
 
 const BLOB_ACCESSED_SUFFIX: &str = ".access";
 const BLOB_ACCESS_RECORD_SECOND: u32 = 10;
@@ -35,13 +34,19 @@ type LocalFsResult<T> = std::result::Result<T, LocalFsError>;
 type AccessLogEntry = (u64, u32, u32);
 
 /// Error codes related to localfs storage backend.
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum LocalFsError {
+    #[error("failed to open blob file, {0}")]
     BlobFile(Error),
+    #[error("failed to read blob, {0}")]
     ReadVecBlob(Error),
-    ReadBlob(nix::Error),
+    #[error("failed to read blob, {0}")]
+    ReadBlob(Error),
+    #[error("failed to copy data, {0}")]
     CopyData(Error),
+    #[error("failed to readahead blob, {0}")]
     Readahead(Error),
+    #[error("failed to record access log, {0}")]
     AccessLog(Error),
 }
 
@@ -95,7 +100,7 @@ impl BlobReader for LocalFsEntry {
             self.id,
         );
 
-        uio::pread(self.file.as_raw_fd(), buf, offset as i64)
+        pread(self.file.as_raw_fd(), buf, offset)
             .map(|v| {
                 debug!("local blob file read {} bytes", v);
                 self.trace.record(offset, v as u32);
@@ -200,6 +205,10 @@ impl BlobReader for LocalFsEntry {
     fn metrics(&self) -> &BackendMetrics {
         &self.metrics
     }
+
+    fn blob_id(&self) -> Option<&str> {
+        Some(&self.id)
+    }
 }
 
 /// Storage backend based on local filesystem.
@@ -13,41 +13,94 @@
 //!   The [LocalFs](localfs/struct.LocalFs.html) storage backend supports backend level data
 //!   prefetching, which is to load data into page cache.
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use fuse_backend_rs::transport::FileVolatileSlice;
-use nydus_utils::metrics::{BackendMetrics, ERROR_HOLDER};
+use nydus_utils::metrics::{BackendMetrics, ErrorClass, ERROR_HOLDER};
+use nydus_utils::tracing;
 
 use crate::utils::copyv;
 use crate::StorageError;
 
 #[cfg(any(feature = "backend-oss", feature = "backend-registry"))]
 pub mod connection;
+#[cfg(any(feature = "backend-oss", feature = "backend-registry"))]
+mod limiter;
 #[cfg(feature = "backend-localfs")]
 pub mod localfs;
 #[cfg(feature = "backend-oss")]
 pub mod oss;
+pub mod peer;
 #[cfg(feature = "backend-registry")]
 pub mod registry;
+pub mod request;
+#[cfg(any(feature = "backend-oss", feature = "backend-registry"))]
+pub mod secret;
+pub mod switch;
 
 /// Error codes related to storage backend operations.
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum BackendError {
     /// Unsupported operation.
+    #[error("unsupported operation, {0}")]
     Unsupported(String),
     /// Failed to copy data from/into blob.
+    #[error("failed to copy data, {0}")]
     CopyData(StorageError),
+    /// Failed to read data from blob before the deadline.
+    #[error("failed to read data within {0:?}")]
+    Timeout(Duration),
+    /// The filesystem request reading data from blob has been interrupted.
+    #[error("request is interrupted")]
+    Interrupted,
+    /// The blob has failed permanently, such as missing from the storage backend.
+    #[error("blob failed permanently, {0}")]
+    BlobFailed(String),
     #[cfg(feature = "backend-registry")]
     /// Error from Registry storage backend.
+    #[error("registry backend, {0}")]
     Registry(self::registry::RegistryError),
     #[cfg(feature = "backend-localfs")]
     /// Error from LocalFs storage backend.
+    #[error("localfs backend, {0}")]
     LocalFs(self::localfs::LocalFsError),
     #[cfg(feature = "backend-oss")]
     /// Error from OSS storage backend.
+    #[error("oss backend, {0}")]
     Oss(self::oss::OssError),
 }
 
+impl BackendError {
+    /// Get class of the error for error accounting.
+    pub fn error_class(&self) -> ErrorClass {
+        match self {
+            BackendError::Timeout(_) => ErrorClass::Timeout,
+            BackendError::BlobFailed(_) => ErrorClass::BackendClient,
+            #[cfg(feature = "backend-registry")]
+            BackendError::Registry(e) => e.error_class(),
+            #[cfg(feature = "backend-oss")]
+            BackendError::Oss(e) => e.error_class(),
+            _ => ErrorClass::Other,
+        }
+    }
+
+    /// Check whether the error is permanent, so retrying the request won't help.
+    ///
+    /// Missing objects and denied access after refreshing credentials are permanent, while
+    /// timeouts, server errors and throttling are transient.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            BackendError::BlobFailed(_) => true,
+            #[cfg(feature = "backend-registry")]
+            BackendError::Registry(e) => e.is_permanent(),
+            #[cfg(feature = "backend-oss")]
+            BackendError::Oss(e) => e.is_permanent(),
+            _ => false,
+        }
+    }
+}
+
 /// Specialized `Result` for storage backends.
 pub type BackendResult<T> = std::result::Result<T, BackendError>;
 
@@ -72,24 +125,98 @@ impl Default for ProxyConfig {
     }
 }
 
+/// Configuration information for TLS connections.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM bundle of CA certificates to trust in addition to the system ones.
+    ca_file: String,
+    /// PEM client certificate chain for mutual TLS, used along with `key_file`.
+    cert_file: String,
+    /// PEM private key in PKCS#8 format of the client certificate.
+    key_file: String,
+    /// Accept any server certificate. Never enable it except for testing.
+    skip_verify: bool,
+}
+
 /// Generic configuration for storage backends.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default)]
 pub struct CommonConfig {
     proxy: ProxyConfig,
+    tls: TlsConfig,
     timeout: u64,
     connect_timeout: u64,
     retry_limit: u8,
+    /// Deadline in seconds to read data from blob including all retries, 0 for no deadline.
+    deadline: u64,
+    /// Maximum number of concurrent reads from a host, adapted to throttling. 0 for no limit.
+    max_concurrency: usize,
 }
 
 impl Default for CommonConfig {
     fn default() -> Self {
         Self {
             proxy: ProxyConfig::default(),
+            tls: TlsConfig::default(),
             timeout: 5,
             connect_timeout: 5,
             retry_limit: 0,
+            deadline: 0,
+            max_concurrency: 0,
+        }
+    }
+}
+
+impl CommonConfig {
+    /// Get the deadline to read data from blob, including all retries.
+    pub fn deadline(&self) -> Option<Duration> {
+        if self.deadline == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.deadline))
+        }
+    }
+}
+
+/// Interval to let a read of a failed blob through, to check whether it has become available.
+const FAILURE_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Permanent failure of a blob on the storage backend.
+///
+/// Reads of a failed blob fail fast instead of hitting the storage backend again, so missing
+/// objects don't cause retry storms. One read per `FAILURE_RECHECK_INTERVAL` is still let through,
+/// and the failure is cleared once a read succeeds.
+#[derive(Debug, Default)]
+pub struct BlobFailure(Mutex<Option<(String, Instant)>>);
+
+impl BlobFailure {
+    /// Mark the blob as failed with the error message `msg`.
+    pub fn set(&self, msg: String) {
+        *self.0.lock().unwrap() = Some((msg, Instant::now()));
+    }
+
+    /// Clear the failure of the blob.
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    /// Get the error message if the blob has failed.
+    pub fn message(&self) -> Option<String> {
+        self.0.lock().unwrap().as_ref().map(|(msg, _)| msg.clone())
+    }
+
+    // Reject the read unless it's time to check the failed blob again.
+    fn check(&self) -> BackendResult<()> {
+        let mut failure = self.0.lock().unwrap();
+        if let Some((msg, at)) = failure.as_mut() {
+            if at.elapsed() < FAILURE_RECHECK_INTERVAL {
+                return Err(BackendError::BlobFailed(msg.clone()));
+            }
+            *at = Instant::now();
         }
+
+        Ok(())
     }
 }
 
@@ -112,18 +239,57 @@ pub trait BlobReader: Send + Sync {
     /// - error code if error happens
     ///
     /// It will try `BlobBackend::retry_limit()` times at most and return the first successfully
-    /// read data.
+    /// read data. It stops retrying once `BlobBackend::deadline()` has passed, the filesystem
+    /// request being served has been interrupted, or the error is permanent. Each try is bounded
+    /// by the time remaining before the deadline. Blobs failed with permanent errors are marked
+    /// as failed, and following reads fail fast.
     fn read(&self, buf: &mut [u8], offset: u64) -> BackendResult<usize> {
+        if let Some(failure) = self.failure() {
+            failure.check()?;
+        }
+
         let mut retry_count = self.retry_limit();
         let begin_time = self.metrics().begin();
+        let start = Instant::now();
+        let _deadline = request::set_deadline(self.deadline().map(|d| start + d));
+        let span = tracing::span("backend.read");
+        span.set_attribute_i64("offset", offset as i64);
+        span.set_attribute_i64("size", buf.len() as i64);
 
         loop {
-            match self.try_read(buf, offset) {
+            let result = if request::is_interrupted() {
+                Err(BackendError::Interrupted)
+            } else {
+                self.try_read(buf, offset)
+            };
+            match result {
                 Ok(size) => {
                     self.metrics().end(&begin_time, buf.len(), false);
+                    if let Some(blob_id) = self.blob_id() {
+                        self.metrics().blob_read(blob_id, buf.len());
+                    }
+                    if let Some(failure) = self.failure() {
+                        failure.clear();
+                    }
                     return Ok(size);
                 }
                 Err(err) => {
+                    let err = match (err, self.deadline()) {
+                        (BackendError::Interrupted, _) => {
+                            retry_count = 0;
+                            BackendError::Interrupted
+                        }
+                        (err, Some(deadline)) if start.elapsed() >= deadline => {
+                            warn!("Read from backend failed: {:?}, deadline exceeded", err);
+                            retry_count = 0;
+                            BackendError::Timeout(deadline)
+                        }
+                        (err, _) if err.is_permanent() => {
+                            retry_count = 0;
+                            err
+                        }
+                        (err, _) => err,
+                    };
                     if retry_count > 0 {
                         warn!(
                             "Read from backend failed: {:?}, retry count {}",
@@ -131,7 +297,13 @@ pub trait BlobReader: Send + Sync {
                         );
                         retry_count -= 1;
                     } else {
+                        span.set_attribute_str("error", &format!("{:?}", err));
                         self.metrics().end(&begin_time, buf.len(), true);
+                        if let Some(failure) = self.failure() {
+                            if err.is_permanent() {
+                                failure.set(format!("{:?}", err));
+                            }
+                        }
                         ERROR_HOLDER
                             .lock()
                             .unwrap()
@@ -192,6 +364,36 @@ pub trait BlobReader: Send + Sync {
     fn retry_limit(&self) -> u8 {
         0
     }
+
+    /// Get the deadline to read data including all retries.
+    fn deadline(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Get the permanent failure state of the blob, if failures are tracked by the reader.
+    fn failure(&self) -> Option<&BlobFailure> {
+        None
+    }
+
+    /// Get id of the blob, if data read is accounted per blob.
+    fn blob_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Trait to upload a blob file to storage backends while the blob is being generated.
+///
+/// Data is buffered and uploaded in parts, so the whole blob file needn't be staged on local
+/// disk. Failed parts are retried for `BlobReader::retry_limit()` times at most.
+pub trait BlobUploader: Send {
+    /// Append data to the blob file being uploaded.
+    fn write(&mut self, buf: &[u8]) -> BackendResult<()>;
+
+    /// Upload all buffered data and save the blob file as `blob_id`.
+    fn commit(&mut self, blob_id: &str) -> BackendResult<()>;
+
+    /// Cancel the upload and discard uploaded data.
+    fn abort(&mut self) -> BackendResult<()>;
 }
 
 /// Trait to access blob files on backend storages, such as OSS, registry, local fs etc.
@@ -204,6 +406,49 @@ pub trait BlobBackend: Send + Sync {
 
     /// Get a blob reader object to access blod `blob_id`.
     fn get_reader(&self, blob_id: &str) -> BackendResult<Arc<dyn BlobReader>>;
+
+    /// Get a blob uploader object to upload a new blob file.
+    ///
+    /// The `blob_id` is the expected id of the new blob file if it's known in advance, some
+    /// storage backends need it to start uploading.
+    fn get_uploader(&self, _blob_id: Option<&str>) -> BackendResult<Box<dyn BlobUploader>> {
+        Err(BackendError::Unsupported(
+            "storage backend does not support uploading blobs".to_string(),
+        ))
+    }
+}
+
+/// Check whether demand reads from any remote storage host are waiting for the concurrency limiter.
+///
+/// Callers may stop accepting new requests while storage backends are saturated, and register a
+/// listener by [`add_saturation_listener`] to resume once they aren't.
+pub fn is_saturated() -> bool {
+    #[cfg(any(feature = "backend-oss", feature = "backend-registry"))]
+    {
+        limiter::any_saturated()
+    }
+    #[cfg(not(any(feature = "backend-oss", feature = "backend-registry")))]
+    {
+        false
+    }
+}
+
+/// Mark the current thread as a prefetch thread, whose requests to remote storage hosts yield to
+/// demand reads when concurrent reads are limited.
+pub(crate) fn set_prefetch_thread() {
+    #[cfg(any(feature = "backend-oss", feature = "backend-registry"))]
+    limiter::set_prefetch_thread();
+}
+
+/// Register `listener` to be called each time requests to a remote storage host stop waiting for
+/// the concurrency limiter.
+///
+/// The listener is called on threads issuing backend requests, so it must not block.
+pub fn add_saturation_listener(listener: Arc<dyn Fn() + Send + Sync>) {
+    #[cfg(any(feature = "backend-oss", feature = "backend-registry"))]
+    limiter::add_listener(listener);
+    #[cfg(not(any(feature = "backend-oss", feature = "backend-registry")))]
+    drop(listener);
 }
 
 #[cfg(any(feature = "backend-oss", feature = "backend-registry"))]
@@ -229,9 +474,87 @@ mod tests {
         assert_eq!(config.timeout, 5);
         assert_eq!(config.connect_timeout, 5);
         assert_eq!(config.retry_limit, 0);
+        assert_eq!(config.deadline(), None);
+        assert_eq!(config.max_concurrency, 0);
         assert_eq!(config.proxy.check_interval, 5);
         assert_eq!(config.proxy.fallback, true);
         assert_eq!(config.proxy.ping_url, "");
         assert_eq!(config.proxy.url, "");
+        assert_eq!(config.tls.ca_file, "");
+        assert!(!config.tls.skip_verify);
+    }
+
+    #[cfg(feature = "backend-registry")]
+    #[test]
+    fn test_permanent_failure() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use reqwest::StatusCode;
+
+        use self::connection::ConnectionError;
+        use self::registry::RegistryError;
+
+        #[derive(Default)]
+        struct MissingReader {
+            reads: AtomicUsize,
+            metrics: BackendMetrics,
+            failure: BlobFailure,
+        }
+
+        impl BlobReader for MissingReader {
+            fn blob_size(&self) -> BackendResult<u64> {
+                Ok(0)
+            }
+
+            fn try_read(&self, _buf: &mut [u8], _offset: u64) -> BackendResult<usize> {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                Err(BackendError::Registry(RegistryError::Request(
+                    ConnectionError::ErrorWithMsg(StatusCode::NOT_FOUND, String::new()),
+                )))
+            }
+
+            fn prefetch_blob_data_range(
+                &self,
+                _ra_offset: u32,
+                _ra_size: u32,
+            ) -> BackendResult<()> {
+                Ok(())
+            }
+
+            fn stop_data_prefetch(&self) -> BackendResult<()> {
+                Ok(())
+            }
+
+            fn metrics(&self) -> &BackendMetrics {
+                &self.metrics
+            }
+
+            fn retry_limit(&self) -> u8 {
+                3
+            }
+
+            fn failure(&self) -> Option<&BlobFailure> {
+                Some(&self.failure)
+            }
+        }
+
+        let reader = MissingReader::default();
+        let mut buf = [0u8; 16];
+        let err = reader.read(&mut buf, 0).unwrap_err();
+        assert!(err.is_permanent());
+        // Permanent errors are not retried.
+        assert_eq!(reader.reads.load(Ordering::Relaxed), 1);
+        assert!(reader.failure.message().is_some());
+
+        // Reads of the failed blob fail fast.
+        assert!(matches!(
+            reader.read(&mut buf, 0),
+            Err(BackendError::BlobFailed(_))
+        ));
+        assert_eq!(reader.reads.load(Ordering::Relaxed), 1);
+
+        reader.failure.clear();
+        assert!(reader.read(&mut buf, 0).is_err());
+        assert_eq!(reader.reads.load(Ordering::Relaxed), 2);
     }
 }
@@ -6,35 +6,63 @@
 //! Storage backend driver to access blobs on Oss(Object Storage System).
 use std::io::{Error, Result};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use hmac::{Hmac, Mac, NewMac};
-use nydus_utils::metrics::BackendMetrics;
-use reqwest::header::{HeaderMap, CONTENT_LENGTH};
+use nydus_utils::metrics::{BackendMetrics, ErrorClass};
+use reqwest::blocking::Response;
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, ETAG};
 use reqwest::Method;
 use sha1::Sha1;
 
-use crate::backend::connection::{Connection, ConnectionError};
+use crate::backend::connection::{Connection, ConnectionError, ReqBody};
+use crate::backend::secret::Secret;
 use crate::backend::{
-    default_http_scheme, BackendError, BackendResult, BlobBackend, BlobReader, CommonConfig,
+    default_http_scheme, BackendError, BackendResult, BlobBackend, BlobFailure, BlobReader,
+    BlobUploader, CommonConfig,
 };
 
 const HEADER_DATE: &str = "Date";
 const HEADER_AUTHORIZATION: &str = "Authorization";
+/// Size of parts in multipart upload, except for the last part.
+const OSS_UPLOAD_PART_SIZE: usize = 0x100_0000;
 
 type HmacSha1 = Hmac<Sha1>;
 
 /// Error codes related to OSS storage backend.
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum OssError {
+    #[error("failed to sign request, {0}")]
     Auth(Error),
+    #[error("invalid url, {0}")]
     Url(String),
+    #[error("request failed, {0}")]
     Request(ConnectionError),
+    #[error("failed to construct header, {0}")]
     ConstructHeader(String),
+    #[error("failed to transfer data, {0}")]
     Transport(reqwest::Error),
+    #[error("invalid response, {0}")]
     Response(String),
 }
 
+impl OssError {
+    pub(crate) fn error_class(&self) -> ErrorClass {
+        match self {
+            OssError::Request(e) => e.error_class(),
+            OssError::Transport(e) if e.is_timeout() => ErrorClass::Timeout,
+            _ => ErrorClass::Other,
+        }
+    }
+
+    pub(crate) fn is_permanent(&self) -> bool {
+        match self {
+            OssError::Request(e) => e.is_permanent(),
+            _ => false,
+        }
+    }
+}
+
 impl From<OssError> for BackendError {
     fn from(error: OssError) -> Self {
         BackendError::Oss(error)
@@ -44,8 +72,22 @@ impl From<OssError> for BackendError {
 #[derive(Clone, Deserialize, Serialize)]
 struct OssConfig {
     endpoint: String,
-    access_key_id: String,
-    access_key_secret: String,
+    #[serde(default)]
+    access_key_id: Option<String>,
+    /// Name of the environment variable holding `access_key_id`.
+    #[serde(default)]
+    access_key_id_env: Option<String>,
+    /// Path of the file holding `access_key_id`, which is reloaded once modified.
+    #[serde(default)]
+    access_key_id_file: Option<String>,
+    #[serde(default)]
+    access_key_secret: Option<String>,
+    /// Name of the environment variable holding `access_key_secret`.
+    #[serde(default)]
+    access_key_secret_env: Option<String>,
+    /// Path of the file holding `access_key_secret`, which is reloaded once modified.
+    #[serde(default)]
+    access_key_secret_file: Option<String>,
     bucket_name: String,
     #[serde(default = "default_http_scheme")]
     scheme: String,
@@ -60,13 +102,14 @@ struct OssConfig {
 // `OssState` is almost identical to `OssConfig`, but let's keep them separated.
 #[derive(Debug)]
 struct OssState {
-    access_key_id: String,
-    access_key_secret: String,
+    access_key_id: Secret,
+    access_key_secret: Secret,
     scheme: String,
     object_prefix: String,
     endpoint: String,
     bucket_name: String,
     retry_limit: u8,
+    deadline: Option<Duration>,
 }
 
 impl OssState {
@@ -124,12 +167,13 @@ impl OssState {
             data.insert(4, canonicalized_oss_headers.as_str());
         }
         let data = data.join("\n");
-        let mut mac =
-            HmacSha1::new_varkey(self.access_key_secret.as_bytes()).map_err(|e| einval!(e))?;
+        // Load the access key for each request since it may have been rotated.
+        let access_key_secret = self.access_key_secret.get()?;
+        let mut mac = HmacSha1::new_varkey(access_key_secret.as_bytes()).map_err(|e| einval!(e))?;
         mac.update(data.as_bytes());
         let signature = base64::encode(&mac.finalize().into_bytes());
 
-        let authorization = format!("OSS {}:{}", self.access_key_id, signature);
+        let authorization = format!("OSS {}:{}", self.access_key_id.get()?, signature);
 
         headers.insert(HEADER_DATE, date.as_str().parse().map_err(|e| einval!(e))?);
         headers.insert(
@@ -146,6 +190,7 @@ struct OssReader {
     connection: Arc<Connection>,
     state: Arc<OssState>,
     metrics: Arc<BackendMetrics>,
+    failure: BlobFailure,
 }
 
 impl BlobReader for OssReader {
@@ -191,6 +236,12 @@ impl BlobReader for OssReader {
             .sign(Method::GET, &mut headers, resource.as_str())
             .map_err(OssError::Auth)?;
 
+        let _permit = self
+            .connection
+            .acquire_permit()
+            .map_err(OssError::Request)?;
+        self.metrics
+            .set_concurrency_limit(self.connection.concurrency_limit());
         // Safe because the the call() is a synchronous operation.
         let mut resp = self
             .connection
@@ -222,6 +273,193 @@ impl BlobReader for OssReader {
     fn retry_limit(&self) -> u8 {
         self.state.retry_limit
     }
+
+    fn deadline(&self) -> Option<Duration> {
+        self.state.deadline
+    }
+
+    fn failure(&self) -> Option<&BlobFailure> {
+        Some(&self.failure)
+    }
+
+    fn blob_id(&self) -> Option<&str> {
+        Some(&self.blob_id)
+    }
+}
+
+/// Blob uploader to push blobs to OSS by multipart upload.
+struct OssUploader {
+    blob_id: String,
+    connection: Arc<Connection>,
+    state: Arc<OssState>,
+    upload_id: String,
+    // Buffered data of the next part.
+    buf: Vec<u8>,
+    // ETags of uploaded parts, the part number is index plus one.
+    etags: Vec<String>,
+}
+
+impl OssUploader {
+    /// Initiate a multipart upload session for object `blob_id`.
+    ///
+    /// Request:  POST /<object_key>?uploads
+    /// Response: <InitiateMultipartUploadResult><UploadId>..</UploadId>..
+    fn new(
+        connection: Arc<Connection>,
+        state: Arc<OssState>,
+        blob_id: &str,
+    ) -> BackendResult<Self> {
+        let resp = Self::call(
+            &connection,
+            &state,
+            Method::POST,
+            blob_id,
+            &["uploads"],
+            None,
+        )?;
+        let body = resp.text().map_err(OssError::Transport)?;
+        let upload_id = parse_xml_value(&body, "UploadId")
+            .ok_or_else(|| OssError::Response(format!("no upload id in response: {}", body)))?;
+
+        Ok(OssUploader {
+            blob_id: blob_id.to_string(),
+            connection,
+            state,
+            upload_id,
+            buf: Vec::with_capacity(OSS_UPLOAD_PART_SIZE),
+            etags: Vec::new(),
+        })
+    }
+
+    fn call(
+        connection: &Connection,
+        state: &OssState,
+        method: Method,
+        object_key: &str,
+        query: &[&str],
+        data: Option<Vec<u8>>,
+    ) -> BackendResult<Response> {
+        let (resource, url) = state.url(object_key, query);
+        let mut headers = HeaderMap::new();
+
+        state
+            .sign(method.clone(), &mut headers, resource.as_str())
+            .map_err(OssError::Auth)?;
+
+        Ok(connection
+            .call::<&[u8]>(
+                method,
+                url.as_str(),
+                None,
+                data.map(ReqBody::Buf),
+                headers,
+                true,
+            )
+            .map_err(OssError::Request)?)
+    }
+
+    /// Upload buffered data as the next part, parts are uploaded again on failure.
+    ///
+    /// Request:  PUT /<object_key>?partNumber=<n>&uploadId=<upload_id>
+    /// Response: header: etag: <etag>
+    fn upload_part(&mut self) -> BackendResult<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let part_number = format!("partNumber={}", self.etags.len() + 1);
+        let upload_id = format!("uploadId={}", self.upload_id);
+        let mut retry_count = self.state.retry_limit;
+        let resp = loop {
+            match Self::call(
+                &self.connection,
+                &self.state,
+                Method::PUT,
+                &self.blob_id,
+                &[&part_number, &upload_id],
+                Some(self.buf.clone()),
+            ) {
+                Ok(resp) => break resp,
+                Err(err) => {
+                    if retry_count == 0 {
+                        return Err(err);
+                    }
+                    warn!(
+                        "Upload part {} to oss failed: {:?}, retry count {}",
+                        self.etags.len() + 1,
+                        err,
+                        retry_count
+                    );
+                    retry_count -= 1;
+                }
+            }
+        };
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .ok_or_else(|| OssError::Response("no etag of uploaded part".to_string()))?
+            .to_str()
+            .map_err(|e| OssError::Response(format!("invalid etag of uploaded part: {}", e)))?;
+
+        self.etags.push(etag.to_string());
+        self.buf.clear();
+
+        Ok(())
+    }
+}
+
+impl BlobUploader for OssUploader {
+    fn write(&mut self, mut buf: &[u8]) -> BackendResult<()> {
+        while !buf.is_empty() {
+            let size = std::cmp::min(OSS_UPLOAD_PART_SIZE - self.buf.len(), buf.len());
+            self.buf.extend_from_slice(&buf[..size]);
+            buf = &buf[size..];
+            if self.buf.len() >= OSS_UPLOAD_PART_SIZE {
+                self.upload_part()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Request:  POST /<object_key>?uploadId=<upload_id>
+    ///           body: <CompleteMultipartUpload><Part>..</Part>..</CompleteMultipartUpload>
+    fn commit(&mut self, blob_id: &str) -> BackendResult<()> {
+        if blob_id != self.blob_id {
+            return Err(BackendError::Oss(OssError::Url(format!(
+                "blob id {} doesn't match object key {} of the upload",
+                blob_id, self.blob_id
+            ))));
+        }
+        self.upload_part()?;
+
+        let upload_id = format!("uploadId={}", self.upload_id);
+        Self::call(
+            &self.connection,
+            &self.state,
+            Method::POST,
+            &self.blob_id,
+            &[&upload_id],
+            Some(complete_multipart_upload_body(&self.etags).into_bytes()),
+        )?;
+
+        Ok(())
+    }
+
+    /// Request:  DELETE /<object_key>?uploadId=<upload_id>
+    fn abort(&mut self) -> BackendResult<()> {
+        let upload_id = format!("uploadId={}", self.upload_id);
+        Self::call(
+            &self.connection,
+            &self.state,
+            Method::DELETE,
+            &self.blob_id,
+            &[&upload_id],
+            None,
+        )?;
+
+        Ok(())
+    }
 }
 
 /// Storage backend to access data stored in OSS.
@@ -239,16 +477,28 @@ impl Oss {
         let common_config: CommonConfig =
             serde_json::from_value(config.clone()).map_err(|e| einval!(e))?;
         let retry_limit = common_config.retry_limit;
-        let connection = Connection::new(&common_config)?;
+        let deadline = common_config.deadline();
         let oss_config: OssConfig = serde_json::from_value(config).map_err(|e| einval!(e))?;
+        let connection = Connection::new(&common_config, &oss_config.endpoint)?;
         let state = Arc::new(OssState {
             scheme: oss_config.scheme,
             object_prefix: oss_config.object_prefix,
             endpoint: oss_config.endpoint,
-            access_key_id: oss_config.access_key_id,
-            access_key_secret: oss_config.access_key_secret,
+            access_key_id: Self::access_key(
+                "access_key_id",
+                oss_config.access_key_id,
+                oss_config.access_key_id_env,
+                oss_config.access_key_id_file,
+            )?,
+            access_key_secret: Self::access_key(
+                "access_key_secret",
+                oss_config.access_key_secret,
+                oss_config.access_key_secret_env,
+                oss_config.access_key_secret_file,
+            )?,
             bucket_name: oss_config.bucket_name,
             retry_limit,
+            deadline,
         });
         let metrics = id.map(|i| BackendMetrics::new(i, "oss"));
 
@@ -259,6 +509,20 @@ impl Oss {
             id: id.map(|i| i.to_string()),
         })
     }
+
+    fn access_key(
+        name: &str,
+        inline: Option<String>,
+        env: Option<String>,
+        file: Option<String>,
+    ) -> Result<Secret> {
+        Secret::new(name, inline, env, file)?.ok_or_else(|| {
+            einval!(format!(
+                "{0}, {0}_env or {0}_file of oss backend is required",
+                name
+            ))
+        })
+    }
 }
 
 impl BlobBackend for Oss {
@@ -279,6 +543,7 @@ impl BlobBackend for Oss {
                 state: self.state.clone(),
                 connection: self.connection.clone(),
                 metrics: metrics.clone(),
+                failure: BlobFailure::default(),
             }))
         } else {
             Err(BackendError::Unsupported(
@@ -286,6 +551,40 @@ impl BlobBackend for Oss {
             ))
         }
     }
+
+    fn get_uploader(&self, blob_id: Option<&str>) -> BackendResult<Box<dyn BlobUploader>> {
+        let blob_id = blob_id.ok_or_else(|| {
+            BackendError::Unsupported("oss backend requires blob id to upload blobs".to_string())
+        })?;
+        let uploader = OssUploader::new(self.connection.clone(), self.state.clone(), blob_id)?;
+
+        Ok(Box::new(uploader))
+    }
+}
+
+/// Get text of the first element `tag` from a simple XML document.
+fn parse_xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start_tag = format!("<{}>", tag);
+    let end_tag = format!("</{}>", tag);
+    let start = xml.find(&start_tag)? + start_tag.len();
+    let end = start + xml[start..].find(&end_tag)?;
+
+    Some(xml[start..end].to_string())
+}
+
+/// Generate request body to complete multipart upload with ETags of uploaded parts.
+fn complete_multipart_upload_body(etags: &[String]) -> String {
+    let mut body = String::from("<CompleteMultipartUpload>");
+    for (idx, etag) in etags.iter().enumerate() {
+        body.push_str(&format!(
+            "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+            idx + 1,
+            etag
+        ));
+    }
+    body.push_str("</CompleteMultipartUpload>");
+
+    body
 }
 
 impl Drop for Oss {
@@ -304,13 +603,14 @@ mod tests {
     #[test]
     fn test_oss_state() {
         let state = OssState {
-            access_key_id: "key".to_string(),
-            access_key_secret: "secret".to_string(),
+            access_key_id: Secret::Inline("key".to_string()),
+            access_key_secret: Secret::Inline("secret".to_string()),
             scheme: "https".to_string(),
             object_prefix: "nydus".to_string(),
             endpoint: "oss".to_string(),
             bucket_name: "images".to_string(),
             retry_limit: 5,
+            deadline: None,
         };
 
         assert_eq!(
@@ -330,6 +630,22 @@ mod tests {
         assert!(signature.to_str().unwrap().contains("OSS key:"));
     }
 
+    #[test]
+    fn test_multipart_upload_xml() {
+        let xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?><InitiateMultipartUploadResult><Bucket>images</Bucket><Key>blob</Key><UploadId>0004B9894A22E5B1888A1E29F823****</UploadId></InitiateMultipartUploadResult>";
+        assert_eq!(
+            parse_xml_value(xml, "UploadId").unwrap(),
+            "0004B9894A22E5B1888A1E29F823****"
+        );
+        assert!(parse_xml_value(xml, "ETag").is_none());
+
+        let etags = vec!["\"etag1\"".to_string(), "\"etag2\"".to_string()];
+        assert_eq!(
+            complete_multipart_upload_body(&etags),
+            "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber><ETag>\"etag1\"</ETag></Part><Part><PartNumber>2</PartNumber><ETag>\"etag2\"</ETag></Part></CompleteMultipartUpload>"
+        );
+    }
+
     #[test]
     fn test_oss_new() {
         let json_str = "{\"access_key_id\":\"key\",\"access_key_secret\":\"secret\",\"bucket_name\":\"images\",\"endpoint\":\"/oss\",\"object_prefix\":\"nydus\",\"scheme\":\"\",\"proxy\":{\"url\":\"\",\"ping_url\":\"\",\"fallback\":true,\"check_interval\":5},\"timeout\":5,\"connect_timeout\":5,\"retry_limit\":5}";
@@ -343,4 +659,43 @@ mod tests {
 
         oss.shutdown();
     }
+
+    #[test]
+    fn test_oss_access_key_file() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        std::fs::write(file.as_path(), "key-from-file\n").unwrap();
+        let json = serde_json::json!({
+            "access_key_id_file": file.as_path().to_str().unwrap(),
+            "access_key_secret": "secret",
+            "bucket_name": "images",
+            "endpoint": "oss",
+        });
+        let oss = Oss::new(json, Some("test-image")).unwrap();
+
+        let mut headers = HeaderMap::new();
+        oss.state
+            .sign(Method::HEAD, &mut headers, "/images")
+            .unwrap();
+        let signature = headers.get(HEADER_AUTHORIZATION).unwrap();
+        assert!(signature
+            .to_str()
+            .unwrap()
+            .starts_with("OSS key-from-file:"));
+
+        let json = serde_json::json!({
+            "access_key_id": "key",
+            "access_key_id_env": "OSS_ACCESS_KEY_ID",
+            "bucket_name": "images",
+            "endpoint": "oss",
+        });
+        assert!(Oss::new(json, Some("test-image")).is_err());
+
+        let json = serde_json::json!({
+            "access_key_id": "key",
+            "bucket_name": "images",
+            "endpoint": "oss",
+        });
+        let err = Oss::new(json, Some("test-image")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
 }
@@ -0,0 +1,407 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read blob data from peer nodes serving their blob caches.
+//!
+//! Peers are nydusd instances started with `--seeder-address`, which serve ranges of blobs as
+//! stored on the storage backend by `GET /blobs/<blob_id>` with a `Range` header, authenticated
+//! by a token shared among nodes of the cluster. Reads are sent to the peers in turn, and fall
+//! back to the storage backend if the peer fails or doesn't have the whole range cached.
+//!
+//! Peers aren't trusted more than the storage backend, so blob caches reading through peers
+//! always validate chunks by their digests.
+
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nydus_utils::metrics::BackendMetrics;
+
+use crate::backend::{request, BackendResult, BlobFailure, BlobReader};
+
+/// Path prefix of blobs served by peers.
+pub const PEER_BLOB_PATH: &str = "/blobs/";
+/// Maximum size of a range to request from peers, larger reads go to the storage backend.
+pub const MAX_PEER_RANGE_SIZE: u64 = 16 << 20;
+/// Maximum size of the status line and headers of requests and responses.
+pub const MAX_PEER_HEADER_SIZE: usize = 8192;
+
+fn default_peer_timeout() -> u64 {
+    5
+}
+
+/// Configuration information to read blob data from peer nodes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PeerConfig {
+    /// Addresses of peers, such as `10.0.0.2:8100`.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// File containing the token shared among peers to authenticate requests.
+    #[serde(default)]
+    pub token_file: String,
+    /// Timeout in seconds of a request to a peer, including receiving the whole response.
+    #[serde(default = "default_peer_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        PeerConfig {
+            addresses: Vec::new(),
+            token_file: String::new(),
+            timeout: default_peer_timeout(),
+        }
+    }
+}
+
+/// Load the token shared among peers from the file `path`.
+pub fn load_peer_token(path: &str) -> Result<String> {
+    let token = fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() || !token.bytes().all(|c| c.is_ascii_graphic()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid peer token in {}", path),
+        ));
+    }
+
+    Ok(token)
+}
+
+/// Compare the token presented by a peer with the expected one in constant time.
+pub fn peer_token_matches(expected: &str, presented: &str) -> bool {
+    let (expected, presented) = (expected.as_bytes(), presented.as_bytes());
+    expected.len() == presented.len()
+        && expected
+            .iter()
+            .zip(presented)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Get the time left before `deadline`, or a timeout error if it has passed.
+pub fn remaining_time(deadline: Instant) -> Result<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        Err(Error::new(ErrorKind::TimedOut, "deadline exceeded"))
+    } else {
+        Ok(deadline - now)
+    }
+}
+
+/// Group of peers shared by all blobs of a blob cache manager.
+pub struct Peers {
+    addresses: Vec<String>,
+    token: String,
+    timeout: Duration,
+    next: AtomicUsize,
+}
+
+impl Peers {
+    /// Create a group of peers, or `None` if no peer is configured.
+    pub fn new(config: &PeerConfig) -> Result<Option<Arc<Self>>> {
+        if config.addresses.is_empty() {
+            return Ok(None);
+        }
+        if config.token_file.is_empty() {
+            return Err(einval!("token file of peers is not configured"));
+        }
+        if config.timeout == 0 {
+            return Err(einval!("timeout of peers must not be zero"));
+        }
+
+        Ok(Some(Arc::new(Peers {
+            addresses: config.addresses.clone(),
+            token: load_peer_token(&config.token_file)?,
+            timeout: Duration::from_secs(config.timeout),
+            next: AtomicUsize::new(0),
+        })))
+    }
+
+    // Fetch the range `[offset, offset + buf.len())` of the blob from the next peer.
+    fn fetch(&self, blob_id: &str, buf: &mut [u8], offset: u64) -> Result<()> {
+        // Don't run past the deadline of the backend read.
+        let timeout = request::remaining_time().map_or(self.timeout, |t| t.min(self.timeout));
+        let deadline = Instant::now() + timeout;
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.addresses.len();
+        let address = &self.addresses[index];
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no address of peer"))?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, remaining_time(deadline)?)?;
+        stream.set_write_timeout(Some(remaining_time(deadline)?))?;
+        write!(
+            stream,
+            "GET {}{} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nRange: bytes={}-{}\r\nConnection: close\r\n\r\n",
+            PEER_BLOB_PATH,
+            blob_id,
+            address,
+            self.token,
+            offset,
+            offset + buf.len() as u64 - 1
+        )?;
+
+        // Read the status line and headers, byte by byte to leave the body in the stream.
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_PEER_HEADER_SIZE {
+                return Err(einval!("response headers of peer are too large"));
+            }
+            stream.set_read_timeout(Some(remaining_time(deadline)?))?;
+            if stream.read(&mut byte)? == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "peer closed connection",
+                ));
+            }
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("206") {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("peer {} responds {}", address, status),
+            ));
+        }
+        let length = lines
+            .filter_map(|l| l.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+        if length != Some(buf.len()) {
+            return Err(einval!(format!(
+                "peer {} responds {:?} bytes for {} bytes",
+                address,
+                length,
+                buf.len()
+            )));
+        }
+
+        let mut read = 0;
+        while read < buf.len() {
+            stream.set_read_timeout(Some(remaining_time(deadline)?))?;
+            match stream.read(&mut buf[read..])? {
+                0 => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "peer closed connection",
+                    ))
+                }
+                n => read += n,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Blob reader trying peers before the storage backend.
+pub struct PeerReader {
+    blob_id: String,
+    peers: Arc<Peers>,
+    inner: Arc<dyn BlobReader>,
+}
+
+impl PeerReader {
+    /// Create a reader of the blob `blob_id` trying `peers` before the storage backend `inner`.
+    pub fn new(blob_id: &str, peers: Arc<Peers>, inner: Arc<dyn BlobReader>) -> Self {
+        PeerReader {
+            blob_id: blob_id.to_string(),
+            peers,
+            inner,
+        }
+    }
+}
+
+impl BlobReader for PeerReader {
+    fn blob_size(&self) -> BackendResult<u64> {
+        self.inner.blob_size()
+    }
+
+    fn try_read(&self, buf: &mut [u8], offset: u64) -> BackendResult<usize> {
+        if !buf.is_empty() && buf.len() as u64 <= MAX_PEER_RANGE_SIZE {
+            match self.peers.fetch(&self.blob_id, buf, offset) {
+                Ok(()) => return Ok(buf.len()),
+                Err(e) => debug!(
+                    "failed to read {} bytes at {} of blob {} from peers, {}",
+                    buf.len(),
+                    offset,
+                    self.blob_id,
+                    e
+                ),
+            }
+        }
+
+        self.inner.try_read(buf, offset)
+    }
+
+    fn prefetch_blob_data_range(&self, ra_offset: u32, ra_size: u32) -> BackendResult<()> {
+        self.inner.prefetch_blob_data_range(ra_offset, ra_size)
+    }
+
+    fn stop_data_prefetch(&self) -> BackendResult<()> {
+        self.inner.stop_data_prefetch()
+    }
+
+    fn metrics(&self) -> &BackendMetrics {
+        self.inner.metrics()
+    }
+
+    fn retry_limit(&self) -> u8 {
+        self.inner.retry_limit()
+    }
+
+    fn deadline(&self) -> Option<Duration> {
+        self.inner.deadline()
+    }
+
+    fn failure(&self) -> Option<&BlobFailure> {
+        self.inner.failure()
+    }
+
+    fn blob_id(&self) -> Option<&str> {
+        Some(&self.blob_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    struct NoBackend(BackendMetrics);
+
+    impl BlobReader for NoBackend {
+        fn blob_size(&self) -> BackendResult<u64> {
+            Ok(0)
+        }
+
+        fn try_read(&self, buf: &mut [u8], _offset: u64) -> BackendResult<usize> {
+            buf.iter_mut().for_each(|b| *b = 0xff);
+            Ok(buf.len())
+        }
+
+        fn prefetch_blob_data_range(&self, _ra_offset: u32, _ra_size: u32) -> BackendResult<()> {
+            Ok(())
+        }
+
+        fn stop_data_prefetch(&self) -> BackendResult<()> {
+            Ok(())
+        }
+
+        fn metrics(&self) -> &BackendMetrics {
+            &self.0
+        }
+    }
+
+    // Serve one request by `status` and `body`, and return the request headers.
+    fn serve_once(
+        status: &'static str,
+        body: &'static [u8],
+    ) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            while !request.ends_with("\r\n\r\n") {
+                assert!(reader.read_line(&mut request).unwrap() > 0);
+            }
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                status,
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+            request
+        });
+
+        (address, server)
+    }
+
+    fn new_reader(address: String) -> (PeerReader, TempFile) {
+        let token = TempFile::new().unwrap();
+        token.as_file().write_all(b"secret\n").unwrap();
+        let config = PeerConfig {
+            addresses: vec![address],
+            token_file: token.as_path().to_str().unwrap().to_string(),
+            timeout: 5,
+        };
+        let peers = Peers::new(&config).unwrap().unwrap();
+        let inner = Arc::new(NoBackend(BackendMetrics::default()));
+
+        (PeerReader::new("blob1", peers, inner), token)
+    }
+
+    #[test]
+    fn test_peer_token() {
+        assert!(peer_token_matches("secret", "secret"));
+        assert!(!peer_token_matches("secret", "secreT"));
+        assert!(!peer_token_matches("secret", "secret1"));
+        assert!(!peer_token_matches("secret", ""));
+
+        let file = TempFile::new().unwrap();
+        assert!(load_peer_token(file.as_path().to_str().unwrap()).is_err());
+        file.as_file().write_all(b" token\n").unwrap();
+        assert_eq!(
+            load_peer_token(file.as_path().to_str().unwrap()).unwrap(),
+            "token"
+        );
+    }
+
+    #[test]
+    fn test_peers_config() {
+        assert!(Peers::new(&PeerConfig::default()).unwrap().is_none());
+        let config = PeerConfig {
+            addresses: vec!["127.0.0.1:8100".to_string()],
+            ..Default::default()
+        };
+        assert!(Peers::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_read_from_peer() {
+        let (address, server) = serve_once("206 Partial Content", b"0123");
+        let (reader, _token) = new_reader(address);
+        let mut buf = vec![0u8; 4];
+        assert_eq!(reader.try_read(&mut buf, 100).unwrap(), 4);
+        assert_eq!(&buf, b"0123");
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /blobs/blob1 HTTP/1.1\r\n"));
+        assert!(request.contains("Authorization: Bearer secret\r\n"));
+        assert!(request.contains("Range: bytes=100-103\r\n"));
+    }
+
+    #[test]
+    fn test_fall_back_to_backend() {
+        let (address, server) = serve_once("404 Not Found", b"");
+        let (reader, _token) = new_reader(address);
+        let mut buf = vec![0u8; 4];
+        assert_eq!(reader.try_read(&mut buf, 0).unwrap(), 4);
+        assert_eq!(buf, vec![0xff; 4]);
+        server.join().unwrap();
+
+        // Short responses aren't accepted either.
+        let (address, server) = serve_once("206 Partial Content", b"01");
+        let (reader, _token) = new_reader(address);
+        assert_eq!(reader.try_read(&mut buf, 0).unwrap(), 4);
+        assert_eq!(buf, vec![0xff; 4]);
+        server.join().unwrap();
+    }
+}
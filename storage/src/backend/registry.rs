@@ -6,38 +6,77 @@
 use std::collections::HashMap;
 use std::io::{Error, Read, Result};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use nydus_utils::metrics::BackendMetrics;
+use nydus_utils::metrics::{BackendMetrics, ErrorClass};
 use reqwest::blocking::Response;
 pub use reqwest::header::HeaderMap;
-use reqwest::header::{HeaderValue, CONTENT_LENGTH};
+use reqwest::header::{
+    HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE,
+};
 use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
 use url::{ParseError, Url};
 
 use crate::backend::connection::{
     is_success_status, respond, Connection, ConnectionError, ReqBody,
 };
+use crate::backend::secret::Secret;
 use crate::backend::{
-    default_http_scheme, BackendError, BackendResult, BlobBackend, BlobReader, CommonConfig,
+    default_http_scheme, BackendError, BackendResult, BlobBackend, BlobFailure, BlobReader,
+    BlobUploader, CommonConfig,
 };
 
 const REGISTRY_CLIENT_ID: &str = "nydus-registry-client";
 const HEADER_AUTHORIZATION: &str = "Authorization";
 const HEADER_WWW_AUTHENTICATE: &str = "www-authenticate";
+/// Size of data to upload by a single request in chunked upload.
+const REGISTRY_UPLOAD_CHUNK_SIZE: usize = 0x80_0000;
+/// Media types of image manifests and indexes accepted when fetching manifests.
+const MANIFEST_MEDIA_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+application/vnd.docker.distribution.manifest.list.v2+json, \
+application/vnd.oci.image.manifest.v1+json, \
+application/vnd.docker.distribution.manifest.v2+json";
 
 /// Error codes related to registry storage backend operations.
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum RegistryError {
+    #[error("{0}")]
     Common(String),
+    #[error("invalid url, {0}")]
     Url(ParseError),
+    #[error("request failed, {0}")]
     Request(ConnectionError),
+    #[error("invalid scheme, {0}")]
     Scheme(String),
-    Auth(String),
+    #[error("failed to authenticate, {0}")]
+    Auth(Error),
+    #[error("invalid response header, {0}")]
     ResponseHead(String),
+    #[error("invalid response, {0}")]
     Response(Error),
+    #[error("failed to transfer data, {0}")]
     Transport(reqwest::Error),
 }
 
+impl RegistryError {
+    pub(crate) fn error_class(&self) -> ErrorClass {
+        match self {
+            RegistryError::Request(e) => e.error_class(),
+            RegistryError::Auth(e) => ErrorClass::of(e),
+            RegistryError::Transport(e) if e.is_timeout() => ErrorClass::Timeout,
+            _ => ErrorClass::Other,
+        }
+    }
+
+    pub(crate) fn is_permanent(&self) -> bool {
+        match self {
+            RegistryError::Request(e) => e.is_permanent(),
+            _ => false,
+        }
+    }
+}
+
 impl From<RegistryError> for BackendError {
     fn from(error: RegistryError) -> Self {
         BackendError::Registry(error)
@@ -104,6 +143,12 @@ struct RegistryConfig {
     // sent to registry auth server to get a bearer token.
     #[serde(default)]
     auth: Option<String>,
+    // Name of the environment variable holding `auth`.
+    #[serde(default)]
+    auth_env: Option<String>,
+    // Path of the file holding `auth`, which is reloaded once modified.
+    #[serde(default)]
+    auth_file: Option<String>,
     // The field is a bearer token to be sent to registry
     // to authorize registry requests.
     #[serde(default)]
@@ -145,11 +190,11 @@ struct RegistryState {
     // Image repo name like: library/ubuntu
     repo: String,
     // Base64 encoded registry auth
-    auth: Option<String>,
-    username: String,
-    password: String,
+    auth: Option<Secret>,
     // Retry limit for read operation
     retry_limit: u8,
+    // Deadline for read operation including all retries
+    deadline: Option<Duration>,
     // Scheme specified for blob server
     blob_url_scheme: String,
     // Replace registry redirected url host with the given host
@@ -166,6 +211,16 @@ struct RegistryState {
 }
 
 impl RegistryState {
+    /// Get the base64 encoded registry auth, which may have been rotated.
+    fn auth(&self) -> Result<Option<String>> {
+        match self.auth.as_ref() {
+            Some(secret) => secret
+                .get()
+                .map(|auth| Some(auth).filter(|a| !a.is_empty())),
+            None => Ok(None),
+        }
+    }
+
     fn url(&self, path: &str, query: &[&str]) -> std::result::Result<String, ParseError> {
         let path = if query.is_empty() {
             format!("/v2/{}{}", self.repo, path)
@@ -185,12 +240,13 @@ impl RegistryState {
         // the query and in the body to be compatible with different registry
         // implementations, which have been tested on these platforms:
         // docker hub, harbor, github ghcr, aliyun acr.
+        let (username, password) = Registry::get_authorization_info(&self.auth()?)?;
         let query = vec![
             ("service", auth.service.as_str()),
             ("scope", auth.scope.as_str()),
             ("grant_type", "password"),
-            ("username", self.username.as_str()),
-            ("password", self.password.as_str()),
+            ("username", username.as_str()),
+            ("password", password.as_str()),
             ("client_id", REGISTRY_CLIENT_ID),
         ];
 
@@ -213,7 +269,10 @@ impl RegistryState {
                 headers,
                 true,
             )
-            .map_err(|e| einval!(format!("registry auth server request failed {:?}", e)))?;
+            .map_err(|e| {
+                e.error_class()
+                    .error(format!("registry auth server request failed, {}", e))
+            })?;
         let ret: TokenResponse = token_resp.json().map_err(|e| {
             einval!(format!(
                 "registry auth server response decode failed: {:?}",
@@ -226,8 +285,7 @@ impl RegistryState {
     fn get_auth_header(&self, auth: Auth, connection: &Arc<Connection>) -> Result<String> {
         match auth {
             Auth::Basic(_) => self
-                .auth
-                .as_ref()
+                .auth()?
                 .map(|auth| format!("Basic {}", auth))
                 .ok_or_else(|| einval!("invalid auth config")),
             Auth::Bearer(auth) => {
@@ -290,16 +348,7 @@ impl RegistryState {
             _ => None,
         }
     }
-}
 
-struct RegistryReader {
-    blob_id: String,
-    connection: Arc<Connection>,
-    state: Arc<RegistryState>,
-    metrics: Arc<BackendMetrics>,
-}
-
-impl RegistryReader {
     /// Request registry server with `authorization` header
     ///
     /// Bearer token authenticate workflow:
@@ -328,6 +377,7 @@ impl RegistryReader {
     /// Response: status: 200 Ok
     fn request<R: Read + Send + 'static>(
         &self,
+        connection: &Arc<Connection>,
         method: Method,
         url: &str,
         data: Option<ReqBody<R>>,
@@ -336,7 +386,7 @@ impl RegistryReader {
     ) -> RegistryResult<Response> {
         // Try get authorization header from cache for this request
         let mut last_cached_auth = String::new();
-        let cached_auth = self.state.cached_auth.get();
+        let cached_auth = self.cached_auth.get();
         if !cached_auth.is_empty() {
             last_cached_auth = cached_auth.clone();
             headers.insert(
@@ -348,40 +398,37 @@ impl RegistryReader {
         // For upload request with payload, the auth header should be cached
         // after create_upload(), so we can request registry server directly
         if let Some(data) = data {
-            return self
-                .connection
+            return connection
                 .call(method, url, None, Some(data), headers, catch_status)
                 .map_err(RegistryError::Request);
         }
 
         // Try to request registry server with `authorization` header
-        let resp = self
-            .connection
+        let resp = connection
             .call::<&[u8]>(method.clone(), url, None, None, headers.clone(), false)
             .map_err(RegistryError::Request)?;
         if resp.status() == StatusCode::UNAUTHORIZED {
             if let Some(resp_auth_header) = resp.headers().get(HEADER_WWW_AUTHENTICATE) {
                 // Get token from registry authorization server
-                if let Some(auth) = RegistryState::parse_auth(resp_auth_header, &self.state.auth) {
+                let auth = self.auth().map_err(RegistryError::Auth)?;
+                if let Some(auth) = Self::parse_auth(resp_auth_header, &auth) {
                     let auth_header = self
-                        .state
-                        .get_auth_header(auth, &self.connection)
-                        .map_err(|e| RegistryError::Common(e.to_string()))?;
+                        .get_auth_header(auth, connection)
+                        .map_err(RegistryError::Auth)?;
                     headers.insert(
                         HEADER_AUTHORIZATION,
                         HeaderValue::from_str(auth_header.as_str()).unwrap(),
                     );
 
                     // Try to request registry server with `authorization` header again
-                    let resp = self
-                        .connection
+                    let resp = connection
                         .call(method, url, None, data, headers, catch_status)
                         .map_err(RegistryError::Request)?;
 
                     let status = resp.status();
                     if is_success_status(status) {
                         // Cache authorization header for next request
-                        self.state.cached_auth.set(&last_cached_auth, auth_header)
+                        self.cached_auth.set(&last_cached_auth, auth_header)
                     }
                     return respond(resp, catch_status).map_err(RegistryError::Request);
                 }
@@ -390,7 +437,17 @@ impl RegistryReader {
 
         respond(resp, catch_status).map_err(RegistryError::Request)
     }
+}
+
+struct RegistryReader {
+    blob_id: String,
+    connection: Arc<Connection>,
+    state: Arc<RegistryState>,
+    metrics: Arc<BackendMetrics>,
+    failure: BlobFailure,
+}
 
+impl RegistryReader {
     /// Read data from registry server
     ///
     /// Step:
@@ -447,8 +504,14 @@ impl RegistryReader {
                 return self._try_read(buf, offset, false);
             }
         } else {
-            resp =
-                self.request::<&[u8]>(Method::GET, url.as_str(), None, headers.clone(), false)?;
+            resp = self.state.request::<&[u8]>(
+                &self.connection,
+                Method::GET,
+                url.as_str(),
+                None,
+                headers.clone(),
+                false,
+            )?;
             let status = resp.status();
             // Handle redirect request and cache redirect url
             if vec![
@@ -515,8 +578,14 @@ impl BlobReader for RegistryReader {
             .state
             .url(&format!("/blobs/sha256:{}", self.blob_id), &[])
             .map_err(RegistryError::Url)?;
-        let resp =
-            self.request::<&[u8]>(Method::HEAD, url.as_str(), None, HeaderMap::new(), true)?;
+        let resp = self.state.request::<&[u8]>(
+            &self.connection,
+            Method::HEAD,
+            url.as_str(),
+            None,
+            HeaderMap::new(),
+            true,
+        )?;
         let content_length = resp
             .headers()
             .get(CONTENT_LENGTH)
@@ -530,6 +599,12 @@ impl BlobReader for RegistryReader {
     }
 
     fn try_read(&self, buf: &mut [u8], offset: u64) -> BackendResult<usize> {
+        let _permit = self
+            .connection
+            .acquire_permit()
+            .map_err(|e| BackendError::Registry(RegistryError::Request(e)))?;
+        self.metrics
+            .set_concurrency_limit(self.connection.concurrency_limit());
         self._try_read(buf, offset, true)
             .map_err(BackendError::Registry)
     }
@@ -553,6 +628,237 @@ impl BlobReader for RegistryReader {
     fn retry_limit(&self) -> u8 {
         self.state.retry_limit
     }
+
+    fn deadline(&self) -> Option<Duration> {
+        self.state.deadline
+    }
+
+    fn failure(&self) -> Option<&BlobFailure> {
+        Some(&self.failure)
+    }
+
+    fn blob_id(&self) -> Option<&str> {
+        Some(&self.blob_id)
+    }
+}
+
+/// Blob uploader to push blobs to registry by chunked upload.
+struct RegistryUploader {
+    connection: Arc<Connection>,
+    state: Arc<RegistryState>,
+    // URL to upload the next chunk, which is updated by every response of registry server.
+    location: String,
+    // Buffered data which hasn't been accepted by registry server.
+    buf: Vec<u8>,
+    // Size of data accepted by registry server.
+    offset: u64,
+    hasher: Sha256,
+}
+
+impl RegistryUploader {
+    /// Start an upload session on registry server.
+    ///
+    /// Request:  POST /blobs/uploads/
+    /// Response: status: 202 Accepted
+    ///           header: location: /v2/<repo>/blobs/uploads/<uuid>
+    fn new(connection: Arc<Connection>, state: Arc<RegistryState>) -> RegistryResult<Self> {
+        let url = state
+            .url("/blobs/uploads/", &[])
+            .map_err(RegistryError::Url)?;
+        let resp = state.request::<&[u8]>(
+            &connection,
+            Method::POST,
+            url.as_str(),
+            None,
+            HeaderMap::new(),
+            true,
+        )?;
+        let location = Self::get_location(&state, &resp)?;
+
+        Ok(RegistryUploader {
+            connection,
+            state,
+            location,
+            buf: Vec::with_capacity(REGISTRY_UPLOAD_CHUNK_SIZE),
+            offset: 0,
+            hasher: Sha256::new(),
+        })
+    }
+
+    /// Get URL for the next request of the upload session, it may be relative to the registry.
+    fn get_location(state: &RegistryState, resp: &Response) -> RegistryResult<String> {
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .ok_or_else(|| RegistryError::ResponseHead("no upload location".to_string()))?
+            .to_str()
+            .map_err(|e| RegistryError::ResponseHead(format!("invalid upload location: {}", e)))?;
+        let base = Url::parse(&format!("{}://{}", state.scheme, state.host))
+            .map_err(RegistryError::Url)?;
+        let location = base.join(location).map_err(RegistryError::Url)?;
+
+        Ok(location.to_string())
+    }
+
+    /// Upload all buffered data, and resume the upload from where registry server has received
+    /// if it fails.
+    fn upload_buffered(&mut self) -> RegistryResult<()> {
+        let mut retry_count = self.state.retry_limit;
+
+        loop {
+            match self.try_upload_buffered() {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    if retry_count == 0 {
+                        return Err(err);
+                    }
+                    warn!(
+                        "Upload to registry failed: {:?}, retry count {}",
+                        err, retry_count
+                    );
+                    retry_count -= 1;
+                    if let Err(err) = self.resume() {
+                        warn!("Failed to get upload progress from registry: {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Request:  PATCH /v2/<repo>/blobs/uploads/<uuid>
+    ///           header: content-range: <start>-<end>
+    /// Response: status: 202 Accepted
+    ///           header: location: /v2/<repo>/blobs/uploads/<uuid>
+    fn try_upload_buffered(&mut self) -> RegistryResult<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let end = self.offset + self.buf.len() as u64 - 1;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&format!("{}-{}", self.offset, end)).unwrap(),
+        );
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        let resp = self.state.request::<&[u8]>(
+            &self.connection,
+            Method::PATCH,
+            &self.location,
+            Some(ReqBody::Buf(self.buf.clone())),
+            headers,
+            true,
+        )?;
+        self.location = Self::get_location(&self.state, &resp)?;
+        self.offset = end + 1;
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    /// Get the size of data received by registry server and drop them from the buffer.
+    ///
+    /// Request:  GET /v2/<repo>/blobs/uploads/<uuid>
+    /// Response: status: 204 No Content
+    ///           header: range: 0-<end>
+    fn resume(&mut self) -> RegistryResult<()> {
+        let resp = self.state.request::<&[u8]>(
+            &self.connection,
+            Method::GET,
+            &self.location,
+            None,
+            HeaderMap::new(),
+            true,
+        )?;
+        let received = match resp.headers().get(RANGE) {
+            Some(range) => {
+                let range = range.to_str().unwrap_or_default();
+                parse_upload_range(range).ok_or_else(|| {
+                    RegistryError::ResponseHead(format!("invalid upload range {}", range))
+                })?
+            }
+            None => 0,
+        };
+        if received < self.offset || received > self.offset + self.buf.len() as u64 {
+            return Err(RegistryError::Common(format!(
+                "registry received {} bytes, but {} bytes have been uploaded",
+                received, self.offset
+            )));
+        }
+
+        self.buf.drain(..(received - self.offset) as usize);
+        self.offset = received;
+        self.location = Self::get_location(&self.state, &resp)?;
+
+        Ok(())
+    }
+
+    /// Request:  PUT /v2/<repo>/blobs/uploads/<uuid>?digest=sha256:<blob_id>
+    /// Response: status: 201 Created
+    fn complete(&mut self, blob_id: &str) -> RegistryResult<()> {
+        self.upload_buffered()?;
+
+        let digest = format!("{:x}", self.hasher.clone().finalize());
+        if digest != blob_id {
+            return Err(RegistryError::Common(format!(
+                "blob id {} doesn't match digest sha256:{} of uploaded data",
+                blob_id, digest
+            )));
+        }
+
+        let mut url = Url::parse(&self.location).map_err(RegistryError::Url)?;
+        url.query_pairs_mut()
+            .append_pair("digest", &format!("sha256:{}", digest));
+        self.state.request::<&[u8]>(
+            &self.connection,
+            Method::PUT,
+            url.as_str(),
+            None,
+            HeaderMap::new(),
+            true,
+        )?;
+
+        Ok(())
+    }
+}
+
+impl BlobUploader for RegistryUploader {
+    fn write(&mut self, mut buf: &[u8]) -> BackendResult<()> {
+        self.hasher.update(buf);
+
+        while !buf.is_empty() {
+            let size = std::cmp::min(REGISTRY_UPLOAD_CHUNK_SIZE - self.buf.len(), buf.len());
+            self.buf.extend_from_slice(&buf[..size]);
+            buf = &buf[size..];
+            if self.buf.len() >= REGISTRY_UPLOAD_CHUNK_SIZE {
+                self.upload_buffered()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn commit(&mut self, blob_id: &str) -> BackendResult<()> {
+        self.complete(blob_id).map_err(BackendError::Registry)
+    }
+
+    /// Request:  DELETE /v2/<repo>/blobs/uploads/<uuid>
+    /// Response: status: 204 No Content
+    fn abort(&mut self) -> BackendResult<()> {
+        self.state.request::<&[u8]>(
+            &self.connection,
+            Method::DELETE,
+            &self.location,
+            None,
+            HeaderMap::new(),
+            true,
+        )?;
+
+        Ok(())
+    }
 }
 
 /// Storage backend based on image registry.
@@ -569,11 +875,11 @@ impl Registry {
         let common_config: CommonConfig =
             serde_json::from_value(config.clone()).map_err(|e| einval!(e))?;
         let retry_limit = common_config.retry_limit;
-        let connection = Connection::new(&common_config)?;
+        let deadline = common_config.deadline();
         let config: RegistryConfig = serde_json::from_value(config).map_err(|e| einval!(e))?;
-        let auth = trim(config.auth);
+        let connection = Connection::new(&common_config, &config.host)?;
+        let auth = Secret::new("auth", trim(config.auth), config.auth_env, config.auth_file)?;
         let registry_token = trim(config.registry_token);
-        let (username, password) = Self::get_authorization_info(&auth)?;
         let cached_auth = if let Some(registry_token) = registry_token {
             // Store the registry bearer token to cached_auth, prefer to
             // use the token stored in cached_auth to request registry.
@@ -588,13 +894,14 @@ impl Registry {
             repo: config.repo,
             auth,
             cached_auth,
-            username,
-            password,
             retry_limit,
+            deadline,
             blob_url_scheme: config.blob_url_scheme,
             blob_redirected_host: config.blob_redirected_host,
             cached_redirect: HashCache::new(),
         });
+        // Validate the auth early, it's validated again once reloaded.
+        Self::get_authorization_info(&state.auth()?)?;
 
         Ok(Registry {
             connection,
@@ -603,6 +910,31 @@ impl Registry {
         })
     }
 
+    /// Fetch the manifest `reference`, a tag or a digest, of the image repository.
+    ///
+    /// The manifest may be an image index, depending on the image.
+    pub fn get_manifest(&self, reference: &str) -> BackendResult<Vec<u8>> {
+        let url = format!("/manifests/{}", reference);
+        let url = self
+            .state
+            .url(url.as_str(), &[])
+            .map_err(RegistryError::Url)?;
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(MANIFEST_MEDIA_TYPES));
+
+        let resp = self.state.request::<&[u8]>(
+            &self.connection,
+            Method::GET,
+            url.as_str(),
+            None,
+            headers,
+            true,
+        )?;
+        let manifest = resp.bytes().map_err(RegistryError::Transport)?;
+
+        Ok(manifest.to_vec())
+    }
+
     fn get_authorization_info(auth: &Option<String>) -> Result<(String, String)> {
         if let Some(auth) = &auth {
             let auth: Vec<u8> = base64::decode(auth.as_bytes()).map_err(|e| {
@@ -644,8 +976,15 @@ impl BlobBackend for Registry {
             state: self.state.clone(),
             connection: self.connection.clone(),
             metrics: self.metrics.clone(),
+            failure: BlobFailure::default(),
         }))
     }
+
+    fn get_uploader(&self, _blob_id: Option<&str>) -> BackendResult<Box<dyn BlobUploader>> {
+        let uploader = RegistryUploader::new(self.connection.clone(), self.state.clone())?;
+
+        Ok(Box::new(uploader))
+    }
 }
 
 impl Drop for Registry {
@@ -654,6 +993,19 @@ impl Drop for Registry {
     }
 }
 
+/// Parse the `range` header of upload status, which is the range of data received by registry
+/// server, and return size of the received data.
+fn parse_upload_range(range: &str) -> Option<u64> {
+    let mut parts = range.trim().trim_start_matches("bytes=").splitn(2, '-');
+    let start = parts.next()?.parse::<u64>().ok()?;
+    let end = parts.next()?.parse::<u64>().ok()?;
+    if start != 0 || end < start {
+        return None;
+    }
+
+    Some(end + 1)
+}
+
 fn trim(value: Option<String>) -> Option<String> {
     if let Some(val) = value.as_ref() {
         let trimmed_val = val.trim();
@@ -672,6 +1024,10 @@ fn trim(value: Option<String>) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+    use std::thread;
 
     #[test]
     fn test_string_cache() {
@@ -705,9 +1061,8 @@ mod tests {
             host: "alibaba-inc.com".to_string(),
             repo: "nydus".to_string(),
             auth: None,
-            username: "test".to_string(),
-            password: "password".to_string(),
             retry_limit: 5,
+            deadline: None,
             blob_url_scheme: "https".to_string(),
             blob_redirected_host: "oss.alibaba-inc.com".to_string(),
             cached_auth: Default::default(),
@@ -727,6 +1082,30 @@ mod tests {
         assert!(state.url("image", &[]).is_err());
     }
 
+    #[test]
+    fn test_auth_file() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        std::fs::write(file.as_path(), base64::encode("test:password")).unwrap();
+        let config = serde_json::json!({
+            "host": "alibaba-inc.com",
+            "repo": "nydus",
+            "auth_file": file.as_path().to_str().unwrap(),
+        });
+        let registry = Registry::new(config, Some("test")).unwrap();
+        assert_eq!(
+            Registry::get_authorization_info(&registry.state.auth().unwrap()).unwrap(),
+            ("test".to_string(), "password".to_string())
+        );
+
+        std::fs::write(file.as_path(), "invalid base64").unwrap();
+        let config = serde_json::json!({
+            "host": "alibaba-inc.com",
+            "repo": "nydus",
+            "auth_file": file.as_path().to_str().unwrap(),
+        });
+        assert!(Registry::new(config, Some("test")).is_err());
+    }
+
     #[test]
     fn test_parse_auth() {
         let str = "Bearer realm=\"https://auth.my-registry.com/token\",service=\"my-registry.com\",scope=\"repository:test/repo:pull,push\"";
@@ -754,6 +1133,16 @@ mod tests {
         assert!(RegistryState::parse_auth(&header, &None).is_none());
     }
 
+    #[test]
+    fn test_parse_upload_range() {
+        assert_eq!(parse_upload_range("0-0"), Some(1));
+        assert_eq!(parse_upload_range("0-1048575"), Some(0x100000));
+        assert_eq!(parse_upload_range("bytes=0-99"), Some(100));
+        assert_eq!(parse_upload_range("1-99"), None);
+        assert_eq!(parse_upload_range("0-"), None);
+        assert_eq!(parse_upload_range("invalid"), None);
+    }
+
     #[test]
     fn test_trim() {
         assert_eq!(trim(None), None);
@@ -765,4 +1154,263 @@ mod tests {
         assert_eq!(trim(Some("  te st  ".to_owned())), Some("te st".to_owned()));
         assert_eq!(trim(Some("te st".to_owned())), Some("te st".to_owned()));
     }
+
+    // An in-memory registry requiring bearer token authorization, which fails the first
+    // `fail_patches` PATCH requests after receiving half of the data.
+    #[derive(Default)]
+    struct MockRegistry {
+        address: String,
+        fail_patches: usize,
+        upload: Vec<u8>,
+        blobs: HashMap<String, Vec<u8>>,
+        requests: Vec<String>,
+    }
+
+    impl MockRegistry {
+        fn handle(
+            &mut self,
+            method: &str,
+            target: &str,
+            headers: &HashMap<String, String>,
+            body: Vec<u8>,
+        ) -> (&'static str, String, Vec<u8>) {
+            self.requests.push(format!("{} {}", method, target));
+            if target.starts_with("/token") {
+                return ("200 OK", String::new(), br#"{"token":"token1"}"#.to_vec());
+            }
+            if headers.get("authorization").map(|v| v.as_str()) != Some("Bearer token1") {
+                let challenge = format!(
+                    "Www-Authenticate: Bearer realm=\"http://{}/token\",service=\"mock\",scope=\"repository:test/repo:pull,push\"\r\n",
+                    self.address
+                );
+                return ("401 Unauthorized", challenge, Vec::new());
+            }
+
+            let mut parts = target.splitn(2, '?');
+            let path = parts.next().unwrap();
+            let query = parts.next().unwrap_or_default();
+            let blob_path = "/v2/test/repo/blobs/sha256:";
+            match (method, path) {
+                ("POST", "/v2/test/repo/blobs/uploads/") => {
+                    self.upload.clear();
+                    ("202 Accepted", self.location(), Vec::new())
+                }
+                ("PATCH", "/v2/test/repo/blobs/uploads/uuid1") => {
+                    let range = format!(
+                        "{}-{}",
+                        self.upload.len(),
+                        self.upload.len() + body.len() - 1
+                    );
+                    assert_eq!(headers.get("content-range"), Some(&range));
+                    if self.fail_patches > 0 {
+                        self.fail_patches -= 1;
+                        self.upload.extend_from_slice(&body[..body.len() / 2]);
+                        return ("500 Internal Server Error", String::new(), Vec::new());
+                    }
+                    self.upload.extend_from_slice(&body);
+                    ("202 Accepted", self.location(), Vec::new())
+                }
+                ("GET", "/v2/test/repo/blobs/uploads/uuid1") => {
+                    let mut head = self.location();
+                    if !self.upload.is_empty() {
+                        head += &format!("Range: 0-{}\r\n", self.upload.len() - 1);
+                    }
+                    ("204 No Content", head, Vec::new())
+                }
+                ("PUT", "/v2/test/repo/blobs/uploads/uuid1") => {
+                    let digest = format!("sha256:{:x}", Sha256::digest(&self.upload));
+                    let expected = Url::parse(&format!("http://{}{}", self.address, target))
+                        .unwrap()
+                        .query_pairs()
+                        .find(|(k, _)| k == "digest")
+                        .map(|(_, v)| v.to_string());
+                    assert!(query.contains("offset="));
+                    if expected.as_deref() != Some(digest.as_str()) {
+                        return ("400 Bad Request", String::new(), Vec::new());
+                    }
+                    let upload = std::mem::take(&mut self.upload);
+                    self.blobs.insert(digest[7..].to_string(), upload);
+                    ("201 Created", String::new(), Vec::new())
+                }
+                ("DELETE", "/v2/test/repo/blobs/uploads/uuid1") => {
+                    self.upload.clear();
+                    ("204 No Content", String::new(), Vec::new())
+                }
+                ("HEAD", _) | ("GET", _) if path.starts_with(blob_path) => {
+                    let blob = match self.blobs.get(&path[blob_path.len()..]) {
+                        Some(blob) => blob,
+                        None => return ("404 Not Found", String::new(), Vec::new()),
+                    };
+                    match headers.get("range") {
+                        Some(range) => {
+                            let mut range = range.trim_start_matches("bytes=").splitn(2, '-');
+                            let start: usize = range.next().unwrap().parse().unwrap();
+                            let end: usize = range.next().unwrap().parse().unwrap();
+                            let end = std::cmp::min(end + 1, blob.len());
+                            (
+                                "206 Partial Content",
+                                String::new(),
+                                blob[start..end].to_vec(),
+                            )
+                        }
+                        None => ("200 OK", String::new(), blob.clone()),
+                    }
+                }
+                _ => ("404 Not Found", String::new(), Vec::new()),
+            }
+        }
+
+        fn location(&self) -> String {
+            format!(
+                "Location: /v2/test/repo/blobs/uploads/uuid1?offset={}\r\n",
+                self.upload.len()
+            )
+        }
+    }
+
+    fn serve_mock_registry(fail_patches: usize) -> (String, Arc<Mutex<MockRegistry>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let registry = Arc::new(Mutex::new(MockRegistry {
+            address: address.clone(),
+            fail_patches,
+            ..Default::default()
+        }));
+        let state = registry.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = state.clone();
+                let stream = stream.unwrap();
+                thread::spawn(move || serve_mock_connection(stream, state));
+            }
+        });
+
+        (address, registry)
+    }
+
+    fn serve_mock_connection(mut stream: TcpStream, registry: Arc<Mutex<MockRegistry>>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap().to_string();
+            let target = parts.next().unwrap().to_string();
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                let mut kv = line.splitn(2, ':');
+                let key = kv.next().unwrap().trim().to_lowercase();
+                headers.insert(key, kv.next().unwrap_or_default().trim().to_string());
+            }
+            let size = headers
+                .get("content-length")
+                .map(|v| v.parse().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0u8; size];
+            reader.read_exact(&mut body).unwrap();
+
+            let (status, head, body) = registry
+                .lock()
+                .unwrap()
+                .handle(&method, &target, &headers, body);
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n",
+                status,
+                head,
+                body.len()
+            )
+            .unwrap();
+            if method != "HEAD" {
+                stream.write_all(&body).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_deadline() {
+        // The server accepts connections but never responds.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let _streams: Vec<TcpStream> = listener.incoming().map(|s| s.unwrap()).collect();
+        });
+        let config = serde_json::json!({
+            "scheme": "http",
+            "host": address,
+            "repo": "test/repo",
+            "timeout": 30,
+            "deadline": 1,
+            "retry_limit": 3,
+        });
+        let backend = Registry::new(config, Some("test")).unwrap();
+        let reader = backend.get_reader(&"0".repeat(64)).unwrap();
+
+        let start = std::time::Instant::now();
+        let mut buf = vec![0u8; 0x100];
+        match reader.read(&mut buf, 0) {
+            Err(BackendError::Timeout(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_mock_registry_upload() {
+        let (address, registry) = serve_mock_registry(1);
+        let config = serde_json::json!({
+            "scheme": "http",
+            "host": address,
+            "repo": "test/repo",
+            "auth": base64::encode("test:password"),
+            "retry_limit": 1,
+        });
+        let backend = Registry::new(config, Some("test")).unwrap();
+        let data: Vec<u8> = (0..REGISTRY_UPLOAD_CHUNK_SIZE + 0x1000)
+            .map(|v| (v % 251) as u8)
+            .collect();
+        let blob_id = format!("{:x}", Sha256::digest(&data));
+
+        // The first chunk fails halfway and is resumed from where the registry has received.
+        let mut uploader = backend.get_uploader(None).unwrap();
+        uploader.write(&data[..0x1000]).unwrap();
+        uploader.write(&data[0x1000..]).unwrap();
+        uploader.commit(&blob_id).unwrap();
+        assert_eq!(registry.lock().unwrap().blobs.get(&blob_id), Some(&data));
+        let requests = registry.lock().unwrap().requests.clone();
+        assert!(requests.iter().any(|r| r.starts_with("GET /token")));
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|r| r.starts_with("GET /v2/test/repo/blobs/uploads/uuid1"))
+                .count(),
+            1
+        );
+
+        let reader = backend.get_reader(&blob_id).unwrap();
+        assert_eq!(reader.blob_size().unwrap(), data.len() as u64);
+        let mut buf = vec![0u8; 0x100];
+        assert_eq!(reader.read(&mut buf, 0x2000).unwrap(), 0x100);
+        assert_eq!(buf, &data[0x2000..0x2100]);
+        assert!(backend
+            .get_reader(&"0".repeat(64))
+            .unwrap()
+            .blob_size()
+            .is_err());
+
+        // Data not matching the blob id is never committed, and the upload can be aborted.
+        let mut uploader = backend.get_uploader(None).unwrap();
+        uploader.write(&data[..0x1000]).unwrap();
+        assert!(uploader.commit(&blob_id).is_err());
+        uploader.abort().unwrap();
+        assert!(registry.lock().unwrap().upload.is_empty());
+        assert_eq!(registry.lock().unwrap().blobs.len(), 1);
+    }
 }
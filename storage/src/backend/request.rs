@@ -0,0 +1,239 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Track filesystem requests being served, so reads from storage backends on behalf of
+//! interrupted requests can be cancelled.
+//!
+//! A request is bound to the thread serving it by [begin()](fn.begin.html), and the FUSE
+//! INTERRUPT handler calls [interrupt()](fn.interrupt.html) with the unique id of the request.
+//! Backend reads check the state between retries and give up early, a single attempt is still
+//! bounded by the `timeout` of the storage backend. Helper threads reading data on behalf of a
+//! request may share its state by [attach()](fn.attach.html).
+//!
+//! The deadline of the backend read issued by a thread is tracked here as well, so each request
+//! sent to the storage backend is bounded by the time remaining before the deadline, instead of
+//! only checking the deadline between retries.
+//!
+//! Requests are fetched from the FUSE device by multiple threads, so an INTERRUPT may be handled
+//! before the request it interrupts has begun. Such interrupts are remembered for a while, and
+//! the request is interrupted as soon as it begins.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Maximum number of interrupts remembered for requests not being served yet.
+const MAX_PENDING_INTERRUPTS: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref INFLIGHT: Mutex<HashMap<u64, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+    // Always locked after `INFLIGHT`.
+    static ref PENDING: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<(u64, Arc<AtomicBool>)>> = RefCell::new(None);
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// Handle to a request being served, to share its state with helper threads.
+#[derive(Clone)]
+pub struct RequestHandle {
+    unique: u64,
+    state: Arc<AtomicBool>,
+}
+
+/// Mark the calling thread as serving the request with id `unique`.
+pub fn begin(unique: u64) {
+    let mut inflight = INFLIGHT.lock().unwrap();
+    let mut pending = PENDING.lock().unwrap();
+    let interrupted = match pending.iter().position(|u| *u == unique) {
+        Some(idx) => {
+            pending.remove(idx);
+            true
+        }
+        None => false,
+    };
+    drop(pending);
+    let state = Arc::new(AtomicBool::new(interrupted));
+    inflight.insert(unique, state.clone());
+    drop(inflight);
+    if let Some((prev, _)) = CURRENT.with(|c| c.borrow_mut().replace((unique, state))) {
+        INFLIGHT.lock().unwrap().remove(&prev);
+    }
+}
+
+/// Mark the request served by the calling thread as done.
+pub fn end() {
+    if let Some((unique, _)) = CURRENT.with(|c| c.borrow_mut().take()) {
+        INFLIGHT.lock().unwrap().remove(&unique);
+    }
+}
+
+/// Interrupt the request with id `unique`, return false if it's not being served.
+///
+/// The interrupt is remembered if the request is not being served, in case it begins later.
+pub fn interrupt(unique: u64) -> bool {
+    let inflight = INFLIGHT.lock().unwrap();
+    match inflight.get(&unique) {
+        Some(state) => {
+            state.store(true, Ordering::Release);
+            true
+        }
+        None => {
+            let mut pending = PENDING.lock().unwrap();
+            if pending.len() >= MAX_PENDING_INTERRUPTS {
+                pending.pop_front();
+            }
+            pending.push_back(unique);
+            false
+        }
+    }
+}
+
+/// Get a handle to the request served by the calling thread.
+pub fn current() -> Option<RequestHandle> {
+    CURRENT.with(|c| {
+        c.borrow().as_ref().map(|(unique, state)| RequestHandle {
+            unique: *unique,
+            state: state.clone(),
+        })
+    })
+}
+
+/// Mark the calling helper thread as serving the request of `handle` on behalf of its owner.
+///
+/// The helper thread should not call `begin()` or `end()`, the request is still owned by the
+/// thread which began it.
+pub fn attach(handle: RequestHandle) {
+    CURRENT.with(|c| *c.borrow_mut() = Some((handle.unique, handle.state)));
+}
+
+/// Mark the calling helper thread as no longer serving the request attached by `attach()`.
+pub fn detach() {
+    CURRENT.with(|c| c.borrow_mut().take());
+}
+
+/// Check whether the request served by the calling thread has been interrupted.
+pub fn is_interrupted() -> bool {
+    CURRENT.with(|c| {
+        c.borrow()
+            .as_ref()
+            .map(|(_, state)| state.load(Ordering::Acquire))
+            .unwrap_or(false)
+    })
+}
+
+/// Guard of the deadline set by [set_deadline()](fn.set_deadline.html), which restores the
+/// previous deadline on drop.
+pub(crate) struct DeadlineGuard(Option<Instant>);
+
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(self.0));
+    }
+}
+
+/// Set the deadline of backend reads issued by the calling thread, until the guard is dropped.
+///
+/// An earlier deadline set by the caller is kept.
+pub(crate) fn set_deadline(deadline: Option<Instant>) -> DeadlineGuard {
+    DEADLINE.with(|d| {
+        let prev = d.get();
+        let deadline = match (prev, deadline) {
+            (Some(prev), Some(deadline)) => Some(std::cmp::min(prev, deadline)),
+            (prev, deadline) => prev.or(deadline),
+        };
+        d.set(deadline);
+        DeadlineGuard(prev)
+    })
+}
+
+/// Get the time remaining before the deadline of backend reads issued by the calling thread.
+///
+/// Zero is returned if the deadline has passed, and `None` if there's no deadline.
+pub(crate) fn remaining_time() -> Option<Duration> {
+    DEADLINE.with(|d| {
+        d.get()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupt_request() {
+        assert!(!is_interrupted());
+
+        begin(0x1000);
+        assert!(!is_interrupted());
+        assert!(std::thread::spawn(|| interrupt(0x1000)).join().unwrap());
+        assert!(is_interrupted());
+        end();
+        assert!(!is_interrupted());
+        assert!(!interrupt(0x1000));
+
+        begin(0x1001);
+        let handle = current().unwrap();
+        let helper = std::thread::spawn(move || {
+            attach(handle);
+            is_interrupted()
+        });
+        assert!(!helper.join().unwrap());
+        let handle = current().unwrap();
+        assert!(interrupt(0x1001));
+        let helper = std::thread::spawn(move || {
+            attach(handle);
+            is_interrupted()
+        });
+        assert!(helper.join().unwrap());
+        begin(0x1002);
+        assert!(!interrupt(0x1001));
+        end();
+        assert!(INFLIGHT.lock().unwrap().is_empty());
+
+        // Interrupts may be handled before the requests begin.
+        assert!(!interrupt(0x2000));
+        begin(0x2000);
+        assert!(is_interrupted());
+        end();
+
+        // Interrupts of requests never served are forgotten eventually.
+        for unique in 0x3000..0x3000 + MAX_PENDING_INTERRUPTS as u64 + 1 {
+            assert!(!interrupt(unique));
+        }
+        begin(0x3000);
+        assert!(!is_interrupted());
+        end();
+        begin(0x3001);
+        assert!(is_interrupted());
+        end();
+    }
+
+    #[test]
+    fn test_deadline() {
+        assert_eq!(remaining_time(), None);
+
+        let guard = set_deadline(Some(Instant::now() + Duration::from_secs(10)));
+        let remaining = remaining_time().unwrap();
+        assert!(remaining > Duration::from_secs(9) && remaining <= Duration::from_secs(10));
+        {
+            // Nested reads can't extend the deadline, but may shorten it.
+            let _guard = set_deadline(Some(Instant::now() + Duration::from_secs(20)));
+            assert!(remaining_time().unwrap() <= Duration::from_secs(10));
+            let _guard = set_deadline(None);
+            assert!(remaining_time().unwrap() <= Duration::from_secs(10));
+            let _guard = set_deadline(Some(Instant::now()));
+            assert_eq!(remaining_time(), Some(Duration::from_secs(0)));
+        }
+        assert!(remaining_time().unwrap() > Duration::from_secs(9));
+        assert_eq!(std::thread::spawn(remaining_time).join().unwrap(), None);
+        drop(guard);
+        assert_eq!(remaining_time(), None);
+    }
+}
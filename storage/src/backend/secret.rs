@@ -0,0 +1,201 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Credentials of storage backends.
+//!
+//! Credentials may be configured inline, or referenced from environment variables or files so
+//! they don't appear in plaintext in configuration files. Files are reloaded once modified, to
+//! support short-lived credentials rotated by other tools, such as Kubernetes secrets.
+
+use std::fmt::{self, Debug, Formatter};
+use std::fs;
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// A credential configured inline, or referenced from an environment variable or a file.
+pub enum Secret {
+    Inline(String),
+    Env(String),
+    File(SecretFile),
+}
+
+impl Secret {
+    /// Create a secret from the inline value, the environment variable name or the file path.
+    ///
+    /// At most one of them may be given, `None` is returned if none of them is given.
+    pub fn new(
+        name: &str,
+        inline: Option<String>,
+        env: Option<String>,
+        file: Option<String>,
+    ) -> Result<Option<Self>> {
+        let secret = match (inline, env, file) {
+            (None, None, None) => return Ok(None),
+            (Some(v), None, None) => Secret::Inline(v),
+            (None, Some(var), None) => Secret::Env(var),
+            (None, None, Some(path)) => Secret::File(SecretFile::new(PathBuf::from(path))),
+            _ => {
+                return Err(einval!(format!(
+                    "only one of {0}, {0}_env and {0}_file may be configured",
+                    name
+                )))
+            }
+        };
+        // Fail early on missing environment variables or files.
+        secret.get()?;
+
+        Ok(Some(secret))
+    }
+
+    /// Get the current value of the secret, with leading and trailing whitespaces trimmed.
+    pub fn get(&self) -> Result<String> {
+        match self {
+            Secret::Inline(v) => Ok(v.trim().to_string()),
+            Secret::Env(var) => std::env::var(var)
+                .map(|v| v.trim().to_string())
+                .map_err(|e| einval!(format!("failed to get secret from env {}, {}", var, e))),
+            Secret::File(file) => file.get(),
+        }
+    }
+}
+
+// Never print values of secrets.
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Secret::Inline(_) => write!(f, "Secret::Inline(***)"),
+            Secret::Env(var) => write!(f, "Secret::Env({})", var),
+            Secret::File(file) => write!(f, "Secret::File({})", file.path.display()),
+        }
+    }
+}
+
+/// A credential stored in a file, which is reloaded once modified.
+pub struct SecretFile {
+    path: PathBuf,
+    // Modification time of the file when the value is loaded, and the value.
+    cached: Mutex<Option<(SystemTime, String)>>,
+}
+
+impl SecretFile {
+    fn new(path: PathBuf) -> Self {
+        SecretFile {
+            path,
+            cached: Mutex::new(None),
+        }
+    }
+
+    fn get(&self) -> Result<String> {
+        // Follow symlinks, Kubernetes updates secrets by switching symlinks.
+        let modified = fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .map_err(|e| {
+                einval!(format!(
+                    "failed to get secret file {}, {}",
+                    self.path.display(),
+                    e
+                ))
+            })?;
+
+        let mut cached = self.cached.lock().unwrap();
+        match cached.as_ref() {
+            Some((time, value)) if *time == modified => Ok(value.clone()),
+            _ => {
+                let value = fs::read_to_string(&self.path)
+                    .map_err(|e| {
+                        einval!(format!(
+                            "failed to read secret file {}, {}",
+                            self.path.display(),
+                            e
+                        ))
+                    })?
+                    .trim()
+                    .to_string();
+                if cached.is_some() {
+                    info!("reloaded secret file {}", self.path.display());
+                }
+                *cached = Some((modified, value.clone()));
+                Ok(value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_secret_source() {
+        assert!(Secret::new("auth", None, None, None).unwrap().is_none());
+
+        let secret = Secret::new("auth", Some(" inline\n".to_string()), None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(secret.get().unwrap(), "inline");
+        assert!(!format!("{:?}", secret).contains("inline"));
+
+        std::env::set_var("NYDUS_TEST_SECRET", "from-env");
+        let secret = Secret::new("auth", None, Some("NYDUS_TEST_SECRET".to_string()), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(secret.get().unwrap(), "from-env");
+
+        assert!(Secret::new("auth", None, Some("NYDUS_TEST_NO_SECRET".to_string()), None).is_err());
+        assert!(Secret::new(
+            "auth",
+            Some("inline".to_string()),
+            Some("NYDUS_TEST_SECRET".to_string()),
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_secret_file_reload() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_path_buf();
+        fs::write(&path, "first\n").unwrap();
+        let secret = Secret::new("auth", None, None, Some(path.display().to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(secret.get().unwrap(), "first");
+
+        fs::write(&path, "second").unwrap();
+        // Make sure the modification time changes on file systems with coarse timestamps.
+        set_mtime(&path, SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(secret.get().unwrap(), "second");
+
+        fs::remove_file(&path).unwrap();
+        assert!(secret.get().is_err());
+    }
+
+    fn set_mtime(path: &std::path::Path, time: SystemTime) {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let times = [
+            libc::timespec {
+                tv_sec: secs as libc::time_t,
+                tv_nsec: 0,
+            },
+            libc::timespec {
+                tv_sec: secs as libc::time_t,
+                tv_nsec: 0,
+            },
+        ];
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // Safe because the path and times are valid.
+        let ret = unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) };
+        assert_eq!(ret, 0);
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A blob reader whose storage backend can be switched at runtime.
+//!
+//! Blob caches read blob data through a [SwitchableReader], so a mount can be moved to another
+//! storage backend, e.g. from OSS to a registry mirror, without recreating its blob caches.
+
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use nydus_utils::metrics::BackendMetrics;
+
+use crate::backend::{BackendResult, BlobFailure, BlobReader};
+use crate::utils::{IoGate, IoGateClosed};
+
+/// A [BlobReader] forwarding reads to a storage backend which can be switched at runtime.
+pub struct SwitchableReader {
+    current: ArcSwap<Arc<dyn BlobReader>>,
+    // Readers switched out, kept alive so references returned by `metrics()` and `failure()`
+    // stay valid as long as `self`.
+    retired: Mutex<Vec<Arc<dyn BlobReader>>>,
+    gate: IoGate,
+}
+
+impl SwitchableReader {
+    /// Create a reader forwarding reads to `reader`.
+    pub fn new(reader: Arc<dyn BlobReader>) -> Self {
+        SwitchableReader {
+            current: ArcSwap::new(Arc::new(reader)),
+            retired: Mutex::new(Vec::new()),
+            gate: IoGate::default(),
+        }
+    }
+
+    /// Hold new reads and wait for reads in progress to finish within `timeout`.
+    ///
+    /// Reads go on once the returned guard is dropped, after switching the storage backend by
+    /// [ReaderSwitch::switch()].
+    pub fn drain(&self, timeout: Duration) -> Result<ReaderSwitch> {
+        Ok(ReaderSwitch {
+            reader: self,
+            _closed: self.gate.close(timeout)?,
+        })
+    }
+
+    // Get the reader in use.
+    fn current(&self) -> &dyn BlobReader {
+        let reader = self.current.load();
+        // Safe because readers are kept by `current` or `retired` until `self` is dropped.
+        unsafe { &*(&***reader as *const dyn BlobReader) }
+    }
+}
+
+/// Guard holding reads of a [SwitchableReader] to switch its storage backend.
+pub struct ReaderSwitch<'a> {
+    reader: &'a SwitchableReader,
+    _closed: IoGateClosed<'a>,
+}
+
+impl ReaderSwitch<'_> {
+    /// Forward following reads to `reader`.
+    pub fn switch(&self, reader: Arc<dyn BlobReader>) {
+        let old = self.reader.current.swap(Arc::new(reader));
+        old.stop_data_prefetch()
+            .unwrap_or_else(|e| warn!("failed to stop prefetching of old backend, {:?}", e));
+        self.reader.retired.lock().unwrap().push((*old).clone());
+    }
+}
+
+impl BlobReader for SwitchableReader {
+    fn blob_size(&self) -> BackendResult<u64> {
+        let _entry = self.gate.enter();
+        self.current().blob_size()
+    }
+
+    fn try_read(&self, buf: &mut [u8], offset: u64) -> BackendResult<usize> {
+        let _entry = self.gate.enter();
+        self.current().try_read(buf, offset)
+    }
+
+    // Retries are done by the reader in use, without leaving the gate, so a switch doesn't wait
+    // for a read entering the gate again.
+    fn read(&self, buf: &mut [u8], offset: u64) -> BackendResult<usize> {
+        let _entry = self.gate.enter();
+        self.current().read(buf, offset)
+    }
+
+    fn prefetch_blob_data_range(&self, ra_offset: u32, ra_size: u32) -> BackendResult<()> {
+        let _entry = self.gate.enter();
+        self.current().prefetch_blob_data_range(ra_offset, ra_size)
+    }
+
+    fn stop_data_prefetch(&self) -> BackendResult<()> {
+        self.current().stop_data_prefetch()
+    }
+
+    fn metrics(&self) -> &BackendMetrics {
+        self.current().metrics()
+    }
+
+    fn retry_limit(&self) -> u8 {
+        self.current().retry_limit()
+    }
+
+    fn deadline(&self) -> Option<Duration> {
+        self.current().deadline()
+    }
+
+    fn failure(&self) -> Option<&BlobFailure> {
+        self.current().failure()
+    }
+
+    fn blob_id(&self) -> Option<&str> {
+        self.current().blob_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    struct FilledBackend(u8, BackendMetrics);
+
+    impl BlobReader for FilledBackend {
+        fn blob_size(&self) -> BackendResult<u64> {
+            Ok(4096)
+        }
+
+        fn try_read(&self, buf: &mut [u8], _offset: u64) -> BackendResult<usize> {
+            buf.iter_mut().for_each(|b| *b = self.0);
+            Ok(buf.len())
+        }
+
+        fn prefetch_blob_data_range(&self, _ra_offset: u32, _ra_size: u32) -> BackendResult<()> {
+            Ok(())
+        }
+
+        fn stop_data_prefetch(&self) -> BackendResult<()> {
+            Ok(())
+        }
+
+        fn metrics(&self) -> &BackendMetrics {
+            &self.1
+        }
+    }
+
+    #[test]
+    fn test_switch_reader() {
+        let reader = Arc::new(SwitchableReader::new(Arc::new(FilledBackend(
+            1,
+            BackendMetrics::default(),
+        ))));
+        let mut buf = [0u8; 16];
+        assert_eq!(reader.read(&mut buf, 0).unwrap(), 16);
+        assert_eq!(buf, [1u8; 16]);
+
+        let switch = reader.drain(Duration::from_secs(1)).unwrap();
+        let reader2 = reader.clone();
+        let pending = thread::spawn(move || {
+            let mut buf = [0u8; 16];
+            reader2.read(&mut buf, 0).unwrap();
+            (buf, Instant::now())
+        });
+        thread::sleep(Duration::from_millis(50));
+        switch.switch(Arc::new(FilledBackend(2, BackendMetrics::default())));
+        let switched = Instant::now();
+        drop(switch);
+
+        // The read held while switching is served by the new backend.
+        let (buf, done) = pending.join().unwrap();
+        assert_eq!(buf, [2u8; 16]);
+        assert!(done >= switched);
+        assert_eq!(reader.blob_size().unwrap(), 4096);
+    }
+}
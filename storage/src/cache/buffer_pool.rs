@@ -0,0 +1,262 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A pool of reusable page aligned buffers for chunk data.
+//!
+//! Scratch buffers to download and decompress chunks are needed by every cache miss, allocating
+//! and freeing them per request puts pressure on the memory allocator under high IOPS. Buffers
+//! are grouped into power of two size classes from `MIN_BUFFER_SIZE` to `MAX_BUFFER_SIZE`, and
+//! freed buffers are kept for reuse until `MAX_CACHED_BYTES` bytes are cached. Buffers bigger
+//! than `MAX_BUFFER_SIZE` are allocated and freed on demand. Cached buffers may be freed by
+//! `trim_buffer_pool()` to reclaim memory.
+//!
+//! Buffers of `HUGEPAGE_SIZE` or bigger are backed by huge pages according to the process wide
+//! [HugePageMode](../../utils/enum.HugePageMode.html), to reduce TLB pressure.
+
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::utils::{advise_hugepage, hugepage_mode, map_hugetlb, HugePageMode, HUGEPAGE_SIZE};
+
+const BUFFER_ALIGNMENT: usize = 0x1000;
+const MIN_BUFFER_SHIFT: u32 = 12;
+const MIN_BUFFER_SIZE: usize = 1 << MIN_BUFFER_SHIFT;
+const MAX_BUFFER_SHIFT: u32 = 24;
+const MAX_BUFFER_SIZE: usize = 1 << MAX_BUFFER_SHIFT;
+const MAX_CACHED_BYTES: usize = 64 << 20;
+
+lazy_static::lazy_static! {
+    static ref BUFFER_POOL: BufferPool = BufferPool::new();
+}
+
+struct BufferPool {
+    // Addresses of free buffers for each size class, and whether they are from hugetlbfs.
+    classes: Vec<Mutex<Vec<(usize, bool)>>>,
+    cached_bytes: AtomicUsize,
+}
+
+impl BufferPool {
+    fn new() -> Self {
+        BufferPool {
+            classes: (MIN_BUFFER_SHIFT..=MAX_BUFFER_SHIFT)
+                .map(|_| Mutex::new(Vec::new()))
+                .collect(),
+            cached_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    fn class_index(capacity: usize) -> Option<usize> {
+        if capacity > MAX_BUFFER_SIZE {
+            None
+        } else {
+            Some((capacity.trailing_zeros() - MIN_BUFFER_SHIFT) as usize)
+        }
+    }
+
+    fn get(&self, capacity: usize) -> (*mut u8, bool) {
+        if let Some(idx) = Self::class_index(capacity) {
+            if let Some((addr, hugetlb)) = self.classes[idx].lock().unwrap().pop() {
+                self.cached_bytes.fetch_sub(capacity, Ordering::Relaxed);
+                return (addr as *mut u8, hugetlb);
+            }
+        }
+
+        let mode = hugepage_mode();
+        if capacity >= HUGEPAGE_SIZE && mode == HugePageMode::Explicit {
+            match map_hugetlb(capacity) {
+                Ok(ptr) => return (ptr, true),
+                Err(e) => debug!("fall back to normal pages for chunk buffer, {}", e),
+            }
+        }
+
+        let layout = Self::layout(capacity);
+        // Safe because the layout has non-zero size.
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        if capacity >= HUGEPAGE_SIZE && mode != HugePageMode::Never {
+            advise_hugepage(ptr, capacity);
+        }
+
+        (ptr, false)
+    }
+
+    fn put(&self, ptr: *mut u8, capacity: usize, hugetlb: bool) {
+        if let Some(idx) = Self::class_index(capacity) {
+            if self.cached_bytes.fetch_add(capacity, Ordering::Relaxed) + capacity
+                <= MAX_CACHED_BYTES
+            {
+                self.classes[idx]
+                    .lock()
+                    .unwrap()
+                    .push((ptr as usize, hugetlb));
+                return;
+            }
+            self.cached_bytes.fetch_sub(capacity, Ordering::Relaxed);
+        }
+
+        Self::free(ptr, capacity, hugetlb);
+    }
+
+    // Free all cached buffers, and return number of bytes freed.
+    fn trim(&self) -> usize {
+        let mut freed = 0;
+        for (idx, class) in self.classes.iter().enumerate() {
+            let capacity = MIN_BUFFER_SIZE << idx;
+            let buffers = std::mem::take(&mut *class.lock().unwrap());
+            for (addr, hugetlb) in buffers {
+                self.cached_bytes.fetch_sub(capacity, Ordering::Relaxed);
+                Self::free(addr as *mut u8, capacity, hugetlb);
+                freed += capacity;
+            }
+        }
+
+        freed
+    }
+
+    fn free(ptr: *mut u8, capacity: usize, hugetlb: bool) {
+        if hugetlb {
+            // Safe because the buffer is mapped with the same size by `get()`.
+            unsafe { libc::munmap(ptr as *mut libc::c_void, capacity) };
+        } else {
+            // Safe because the buffer is allocated with the same layout by `get()`.
+            unsafe { dealloc(ptr, Self::layout(capacity)) };
+        }
+    }
+
+    fn layout(capacity: usize) -> Layout {
+        // Align big buffers to huge pages, so they may be backed by transparent huge pages.
+        let align = if capacity >= HUGEPAGE_SIZE {
+            HUGEPAGE_SIZE
+        } else {
+            BUFFER_ALIGNMENT
+        };
+        // Safe to unwrap because the alignment is a power of two and capacity is rounded up.
+        Layout::from_size_align(capacity, align).unwrap()
+    }
+}
+
+/// Get number of bytes of free chunk buffers cached for reuse.
+pub fn buffer_pool_size() -> u64 {
+    BUFFER_POOL.cached_bytes.load(Ordering::Relaxed) as u64
+}
+
+/// Free chunk buffers cached for reuse, and return number of bytes freed.
+pub fn trim_buffer_pool() -> u64 {
+    BUFFER_POOL.trim() as u64
+}
+
+/// A page aligned buffer from the pool, which is returned to the pool on drop.
+///
+/// Like `alloc_buf()`, content of the buffer is not initialized.
+pub(crate) struct PooledBuffer {
+    ptr: *mut u8,
+    len: usize,
+    capacity: usize,
+    hugetlb: bool,
+}
+
+// Safe because the buffer is exclusively owned.
+unsafe impl Send for PooledBuffer {}
+unsafe impl Sync for PooledBuffer {}
+
+impl PooledBuffer {
+    /// Get a buffer of `len` bytes from the pool.
+    pub fn new(len: usize) -> Self {
+        let capacity = if len <= MIN_BUFFER_SIZE {
+            MIN_BUFFER_SIZE
+        } else if len <= MAX_BUFFER_SIZE {
+            len.next_power_of_two()
+        } else {
+            (len + HUGEPAGE_SIZE - 1) & !(HUGEPAGE_SIZE - 1)
+        };
+        let (ptr, hugetlb) = BUFFER_POOL.get(capacity);
+
+        PooledBuffer {
+            ptr,
+            len,
+            capacity,
+            hugetlb,
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safe because the buffer is valid for `capacity` bytes.
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safe because the buffer is valid for `capacity` bytes.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self.deref()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        BUFFER_POOL.put(self.ptr, self.capacity, self.hugetlb);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new();
+        let (ptr, hugetlb) = pool.get(0x4000);
+        assert_eq!(ptr as usize % BUFFER_ALIGNMENT, 0);
+        assert!(!hugetlb);
+        pool.put(ptr, 0x4000, hugetlb);
+        assert_eq!(pool.cached_bytes.load(Ordering::Relaxed), 0x4000);
+
+        // The freed buffer is reused by the next request of the same size class.
+        assert_eq!(pool.get(0x4000), (ptr, false));
+        assert_eq!(pool.cached_bytes.load(Ordering::Relaxed), 0);
+        pool.put(ptr, 0x4000, false);
+
+        let size = MAX_BUFFER_SIZE + HUGEPAGE_SIZE;
+        let (ptr, hugetlb) = pool.get(size);
+        assert_eq!(ptr as usize % HUGEPAGE_SIZE, 0);
+        pool.put(ptr, size, hugetlb);
+        assert_eq!(pool.cached_bytes.load(Ordering::Relaxed), 0x4000);
+
+        let (ptr, hugetlb) = pool.get(0x2000);
+        pool.put(ptr, 0x2000, hugetlb);
+        assert_eq!(pool.trim(), 0x6000);
+        assert_eq!(pool.cached_bytes.load(Ordering::Relaxed), 0);
+        assert!(pool.classes.iter().all(|c| c.lock().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn test_pooled_buffer() {
+        let mut buf = PooledBuffer::new(0x3000);
+        assert_eq!(buf.len(), 0x3000);
+        assert_eq!(buf.capacity, 0x4000);
+        buf[0x2fff] = 0x5a;
+        assert_eq!(buf[0x2fff], 0x5a);
+
+        let buf = PooledBuffer::new(1);
+        assert_eq!(buf.capacity, MIN_BUFFER_SIZE);
+        let buf = PooledBuffer::new(MAX_BUFFER_SIZE + 1);
+        assert_eq!(buf.capacity, MAX_BUFFER_SIZE + HUGEPAGE_SIZE);
+        assert_eq!(BufferPool::class_index(buf.capacity), None);
+    }
+}
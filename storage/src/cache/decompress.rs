@@ -0,0 +1,189 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A pool of worker threads to decompress chunk data.
+//!
+//! Decompressing a big chunk may take a while, offloading it to a sized pool of worker threads
+//! bounds the CPU time consumed by decompression, so threads serving requests are available for
+//! unrelated metadata operations. Jobs are only queued while workers can take them, otherwise
+//! the calling thread decompresses data by itself rather than waiting behind other requests.
+
+use std::fs::File;
+use std::io::Result;
+use std::slice;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{bounded, Sender, TrySendError};
+
+use crate::compress;
+
+struct DecompressJob {
+    src: *const u8,
+    src_len: usize,
+    src_file: Option<File>,
+    dst: *mut u8,
+    dst_len: usize,
+    algorithm: compress::Algorithm,
+    done: SyncSender<Result<usize>>,
+}
+
+// Safe because the submitter keeps the buffers alive until the job completes.
+unsafe impl Send for DecompressJob {}
+
+impl DecompressJob {
+    fn run(self) {
+        // Safe because the submitter keeps the buffers alive until the job completes.
+        let src = unsafe { slice::from_raw_parts(self.src, self.src_len) };
+        let dst = unsafe { slice::from_raw_parts_mut(self.dst, self.dst_len) };
+        let ret = compress::decompress(src, self.src_file, dst, self.algorithm);
+        let _ = self.done.send(ret);
+    }
+}
+
+/// A pool of worker threads to decompress chunk data, with a completion channel per request.
+pub(crate) struct DecompressPool {
+    sender: RwLock<Option<Sender<DecompressJob>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl DecompressPool {
+    /// Create a pool with `threads_count` worker threads.
+    pub fn new(threads_count: usize) -> Result<Self> {
+        // At most one job is queued for each worker.
+        let (sender, receiver) = bounded::<DecompressJob>(threads_count);
+        let mut workers = Vec::with_capacity(threads_count);
+
+        for idx in 0..threads_count {
+            let receiver = receiver.clone();
+            let worker = thread::Builder::new()
+                .name(format!("decompress-{}", idx))
+                .spawn(move || {
+                    // Exit when the sender is dropped.
+                    while let Ok(job) = receiver.recv() {
+                        job.run();
+                    }
+                })?;
+            workers.push(worker);
+        }
+
+        Ok(DecompressPool {
+            sender: RwLock::new(Some(sender)),
+            workers: Mutex::new(workers),
+        })
+    }
+
+    /// Decompress `src` or `src_file` into `dst` by a worker thread, and wait for completion.
+    ///
+    /// Fall back to decompress by the calling thread if all workers are busy or the pool has been
+    /// stopped.
+    pub fn decompress(
+        &self,
+        src: &[u8],
+        src_file: Option<File>,
+        dst: &mut [u8],
+        algorithm: compress::Algorithm,
+    ) -> Result<usize> {
+        let (done, completion) = sync_channel(1);
+        let job = DecompressJob {
+            src: src.as_ptr(),
+            src_len: src.len(),
+            src_file,
+            dst: dst.as_mut_ptr(),
+            dst_len: dst.len(),
+            algorithm,
+            done,
+        };
+
+        let job = match self.sender.read().unwrap().as_ref() {
+            Some(sender) => match sender.try_send(job) {
+                Ok(_) => None,
+                Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => Some(job),
+            },
+            None => Some(job),
+        };
+        if let Some(job) = job {
+            let src_file = job.src_file;
+            return compress::decompress(src, src_file, dst, algorithm);
+        }
+
+        // The job is dropped without completion only if the worker panics.
+        completion
+            .recv()
+            .unwrap_or_else(|_| Err(eio!("decompression worker exited unexpectedly")))
+    }
+
+    /// Stop all worker threads after pending jobs are done.
+    pub fn stop(&self) {
+        self.sender.write().unwrap().take();
+        for worker in self.workers.lock().unwrap().drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for DecompressPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress_pool() {
+        let data = vec![0x5au8; 0x10000];
+        let (compressed, _) = compress::compress(&data, compress::Algorithm::Lz4Block).unwrap();
+        let pool = DecompressPool::new(2).unwrap();
+
+        let mut buf = vec![0u8; data.len()];
+        let size = pool
+            .decompress(&compressed, None, &mut buf, compress::Algorithm::Lz4Block)
+            .unwrap();
+        assert_eq!(size, data.len());
+        assert_eq!(buf, data);
+
+        let mut buf = vec![0u8; data.len()];
+        assert!(pool
+            .decompress(&data[..16], None, &mut buf, compress::Algorithm::Lz4Block)
+            .is_err());
+
+        pool.stop();
+        let mut buf = vec![0u8; data.len()];
+        pool.decompress(&compressed, None, &mut buf, compress::Algorithm::Lz4Block)
+            .unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_decompress_pool_busy() {
+        let data = vec![0x5au8; 0x10000];
+        let (compressed, _) = compress::compress(&data, compress::Algorithm::Lz4Block).unwrap();
+
+        // Without workers taking jobs, requests are served by the calling threads.
+        let pool = DecompressPool::new(0).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        pool.decompress(&compressed, None, &mut buf, compress::Algorithm::Lz4Block)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        let pool = DecompressPool::new(1).unwrap();
+        crossbeam_utils::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|_| {
+                    for _ in 0..16 {
+                        let mut buf = vec![0u8; data.len()];
+                        pool.decompress(&compressed, None, &mut buf, compress::Algorithm::Lz4Block)
+                            .unwrap();
+                        assert_eq!(buf, data);
+                    }
+                });
+            }
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Access cache files by direct IO, bypassing the page cache of the host.
+//!
+//! Data cached in files is cached again by the page cache of the host when accessed by buffered
+//! IO, which competes memory with workloads, virtual machines for example, on memory-constrained
+//! nodes. Files opened with `O_DIRECT` bypass the page cache, but require offsets, sizes and
+//! buffer addresses of IO requests aligned to the logical block size of the underlying storage.
+//! [DirectFile] bounces requests through page aligned buffers, and partial blocks at both ends of
+//! a write are read, modified and written back. Callers needing data in a scratch buffer anyway
+//! should take it from [DirectFile::read()] to avoid copying it to another bounce buffer.
+
+use std::fs::{File, OpenOptions};
+use std::io::Result;
+use std::ops::Deref;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+
+use crate::cache::buffer_pool::PooledBuffer;
+use crate::utils::{pread, pwrite};
+
+/// Alignment of offsets, sizes and buffer addresses of direct IO requests.
+pub(crate) const DIRECT_IO_ALIGNMENT: u64 = 0x1000;
+
+fn align_down(v: u64) -> u64 {
+    v & !(DIRECT_IO_ALIGNMENT - 1)
+}
+
+fn align_up(v: u64) -> u64 {
+    align_down(v + DIRECT_IO_ALIGNMENT - 1)
+}
+
+/// Data read by direct IO, kept in the page aligned buffer covering blocks of the request.
+pub(crate) struct DirectBuffer {
+    buf: PooledBuffer,
+    skip: usize,
+    size: usize,
+}
+
+impl Deref for DirectBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.skip..self.skip + self.size]
+    }
+}
+
+/// A file opened with `O_DIRECT`, accepting IO requests of any offset and size.
+pub(crate) struct DirectFile {
+    file: File,
+    // Partial blocks may be shared by adjacent chunks written concurrently, so writes are
+    // serialized to avoid losing data when reading, modifying and writing back the blocks.
+    write_lock: Mutex<()>,
+}
+
+impl DirectFile {
+    /// Open the existing file at `path` by direct IO.
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .map_err(|e| {
+                einval!(format!(
+                    "failed to open cache file {} by direct IO, {}",
+                    path, e
+                ))
+            })?;
+
+        Ok(DirectFile {
+            file,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Read `size` bytes of data at `offset`.
+    ///
+    /// Less data than `size` is read at the end of the file.
+    pub fn read(&self, offset: u64, size: usize) -> Result<DirectBuffer> {
+        let start = align_down(offset);
+        let end = align_up(offset + size as u64);
+        let mut buf = PooledBuffer::new((end - start) as usize);
+        // A direct read returns less data than requested only at the end of the file.
+        let nr_read = pread(self.file.as_raw_fd(), &mut buf, start)?;
+        let skip = (offset - start) as usize;
+        let size = std::cmp::min(nr_read.saturating_sub(skip), size);
+
+        Ok(DirectBuffer { buf, skip, size })
+    }
+
+    /// Read data at `offset` into `buf`, return number of bytes read.
+    ///
+    /// Less data than the size of `buf` is read at the end of the file.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let data = self.read(offset, buf.len())?;
+        buf[..data.len()].copy_from_slice(&data);
+
+        Ok(data.len())
+    }
+
+    /// Write all data of `buf` at `offset`.
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let _guard = self.write_lock.lock().unwrap();
+        let fd = self.file.as_raw_fd();
+        let data_end = offset + buf.len() as u64;
+        let start = align_down(offset);
+        let end = align_up(data_end);
+        let size = (end - start) as usize;
+        let block = DIRECT_IO_ALIGNMENT as usize;
+        let mut bounce = PooledBuffer::new(size);
+
+        // Fetch partial blocks at both ends, which may be partially beyond the end of the file.
+        if offset != start {
+            let nr_read = pread(fd, &mut bounce[..block], start)?;
+            bounce[nr_read..block].iter_mut().for_each(|v| *v = 0);
+        }
+        if data_end != end && (end - start > DIRECT_IO_ALIGNMENT || offset == start) {
+            let nr_read = pread(fd, &mut bounce[size - block..], end - block as u64)?;
+            bounce[size - block + nr_read..]
+                .iter_mut()
+                .for_each(|v| *v = 0);
+        }
+        let head = (offset - start) as usize;
+        bounce[head..head + buf.len()].copy_from_slice(buf);
+
+        // Writing the last partial block may extend the file beyond the data written, so restore
+        // the file size afterwards.
+        let file_size = self.file.metadata()?.len();
+        let nr_write = pwrite(fd, &bounce, start)?;
+        if nr_write != size {
+            return Err(eio!("failed to write data to cache file by direct IO"));
+        }
+        if end > file_size {
+            self.file.set_len(std::cmp::max(file_size, data_end))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_direct_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("cache");
+        File::create(&path).unwrap();
+        // Direct IO may be unsupported by the file system of the temporary directory, handling of
+        // unaligned requests is still tested by buffered IO then.
+        let file = DirectFile::open(path.to_str().unwrap()).unwrap_or_else(|_| DirectFile {
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap(),
+            write_lock: Mutex::new(()),
+        });
+
+        let data: Vec<u8> = (0..0x3000u32).map(|v| v as u8).collect();
+        file.write_at(&data[0x800..0x1800], 0x800).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0x1800);
+        file.write_at(&data[0x1800..0x2100], 0x1800).unwrap();
+        file.write_at(&data[..0x800], 0).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0x2100);
+
+        let mut buf = vec![0u8; 0x3000];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), 0x2100);
+        assert_eq!(&buf[..0x2100], &data[..0x2100]);
+        assert_eq!(file.read_at(&mut buf[..0x10], 0x1ff8).unwrap(), 0x10);
+        assert_eq!(&buf[..0x10], &data[0x1ff8..0x2008]);
+        assert_eq!(file.read_at(&mut buf[..0x10], 0x3000).unwrap(), 0);
+
+        let data_read = file.read(0xff0, 0x20).unwrap();
+        assert_eq!(&data_read[..], &data[0xff0..0x1010]);
+        assert_eq!(file.read(0x20f0, 0x20).unwrap().len(), 0x10);
+    }
+}
@@ -20,24 +20,31 @@
 //!   return true to enable data prefetching.
 use std::io::Result;
 use std::sync::Arc;
+use std::time::Duration;
 
 use fuse_backend_rs::transport::FileVolatileSlice;
 use nydus_utils::digest;
 
+use crate::backend::switch::SwitchableReader;
 use crate::backend::{BlobBackend, BlobReader};
+use crate::cache::buffer_pool::PooledBuffer;
 use crate::cache::state::{ChunkMap, NoopChunkMap};
 use crate::cache::{BlobCache, BlobCacheMgr};
+use crate::crypt::{Cipher, CipherConfig};
 use crate::device::{BlobChunkInfo, BlobInfo, BlobIoDesc, BlobIoVec, BlobPrefetchRequest};
 use crate::factory::CacheConfig;
-use crate::utils::{alloc_buf, copyv};
+use crate::utils::{copyv, IoGate};
 use crate::{compress, StorageError, StorageResult};
 
 struct DummyCache {
     blob_id: String,
     chunk_map: Arc<dyn ChunkMap>,
-    reader: Arc<dyn BlobReader>,
+    reader: SwitchableReader,
+    // Closed while the blob is frozen, entered by reads and prefetching.
+    gate: IoGate,
     compressor: compress::Algorithm,
     digester: digest::Algorithm,
+    cipher: Option<Arc<Cipher>>,
     is_stargz: bool,
     prefetch: bool,
     validate: bool,
@@ -56,6 +63,10 @@ impl BlobCache for DummyCache {
         self.compressor
     }
 
+    fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_deref()
+    }
+
     fn digester(&self) -> digest::Algorithm {
         self.digester
     }
@@ -69,7 +80,11 @@ impl BlobCache for DummyCache {
     }
 
     fn reader(&self) -> &dyn BlobReader {
-        &*self.reader
+        &self.reader
+    }
+
+    fn switchable_reader(&self) -> Option<&SwitchableReader> {
+        Some(&self.reader)
     }
 
     fn get_chunk_map(&self) -> &Arc<dyn ChunkMap> {
@@ -83,6 +98,7 @@ impl BlobCache for DummyCache {
         _bios: &[BlobIoDesc],
     ) -> StorageResult<usize> {
         if self.prefetch {
+            let _entry = self.gate.enter();
             let mut cnt = 0usize;
             for p in prefetches.iter() {
                 if p.blob_id == self.blob_id
@@ -109,12 +125,25 @@ impl BlobCache for DummyCache {
         Ok(())
     }
 
+    fn freeze(&self, timeout: Duration) -> Result<()> {
+        self.gate.hold(timeout)
+    }
+
+    fn thaw(&self) {
+        self.gate.release()
+    }
+
+    fn is_frozen(&self) -> bool {
+        self.gate.is_closed()
+    }
+
     fn read(&self, iovec: &mut BlobIoVec, bufs: &[FileVolatileSlice]) -> Result<usize> {
         let bios = &iovec.bi_vec;
 
         if iovec.bi_size == 0 || bios.is_empty() {
             return Err(einval!("parameter `bios` is empty"));
         }
+        let _entry = self.gate.enter();
 
         let bios_len = bios.len();
         let offset = bios[0].offset;
@@ -130,11 +159,11 @@ impl BlobCache for DummyCache {
         }
 
         let mut user_size = 0;
-        let mut buffer_holder: Vec<Vec<u8>> = Vec::with_capacity(bios.len());
+        let mut buffer_holder: Vec<PooledBuffer> = Vec::with_capacity(bios.len());
         for bio in bios.iter() {
             if bio.user_io {
-                let mut d = alloc_buf(bio.chunkinfo.uncompress_size() as usize);
-                self.read_raw_chunk(&bio.chunkinfo, d.as_mut_slice(), false, None)?;
+                let mut d = PooledBuffer::new(bio.chunkinfo.uncompress_size() as usize);
+                self.read_raw_chunk(&bio.chunkinfo, &mut d, false, None)?;
                 buffer_holder.push(d);
                 user_size += bio.size;
             }
@@ -157,6 +186,7 @@ pub struct DummyCacheMgr {
     cached: bool,
     prefetch: bool,
     validate: bool,
+    cipher_config: CipherConfig,
 }
 
 impl DummyCacheMgr {
@@ -172,6 +202,7 @@ impl DummyCacheMgr {
             cached,
             validate: config.cache_validate,
             prefetch: enable_prefetch,
+            cipher_config: config.cipher_config,
         })
     }
 }
@@ -196,9 +227,11 @@ impl BlobCacheMgr for DummyCacheMgr {
         Ok(Arc::new(DummyCache {
             blob_id,
             chunk_map: Arc::new(NoopChunkMap::new(self.cached)),
-            reader,
+            reader: SwitchableReader::new(reader),
+            gate: IoGate::default(),
             compressor: blob_info.compressor(),
             digester: blob_info.digester(),
+            cipher: self.cipher_config.blob_cipher(blob_info)?,
             is_stargz: blob_info.is_stargz(),
             prefetch: self.prefetch,
             validate: self.validate,
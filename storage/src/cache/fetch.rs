@@ -0,0 +1,163 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A pool of worker threads to help fetching chunks of large reads from storage backends.
+//!
+//! Chunks of a large read may be fetched by concurrent backend reads. Instead of spawning threads
+//! for each read, a sized pool of worker threads is shared by all reads of the blob cache, which
+//! bounds the number of threads no matter how many reads are served concurrently. Like
+//! [DecompressPool](../decompress/struct.DecompressPool.html), jobs are only queued while workers
+//! can take them, and the calling thread always works on its read, so a read never waits behind
+//! other reads even if all workers are busy.
+
+use std::io::Result;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::bounded;
+
+type FetchJob = Box<dyn FnOnce() + Send + 'static>;
+
+// Wait for all helper jobs of a call to complete, even if the calling thread panics, because
+// the jobs borrow data from the stack of the calling thread.
+struct Completion(Receiver<()>);
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        // Each job drops its sender when done, or when dropped without being run.
+        while self.0.recv().is_ok() {}
+    }
+}
+
+/// A pool of worker threads helping the calling thread to fetch chunks.
+pub(crate) struct FetchPool {
+    sender: RwLock<Option<crossbeam_channel::Sender<FetchJob>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl FetchPool {
+    /// Create a pool with `threads_count` worker threads.
+    pub fn new(threads_count: usize) -> Result<Self> {
+        // At most one job is queued for each worker.
+        let (sender, receiver) = bounded::<FetchJob>(threads_count);
+        let mut workers = Vec::with_capacity(threads_count);
+
+        for idx in 0..threads_count {
+            let receiver = receiver.clone();
+            let worker = thread::Builder::new()
+                .name(format!("fetch-{}", idx))
+                .spawn(move || {
+                    // Exit when the sender is dropped.
+                    while let Ok(job) = receiver.recv() {
+                        job();
+                    }
+                })?;
+            workers.push(worker);
+        }
+
+        Ok(FetchPool {
+            sender: RwLock::new(Some(sender)),
+            workers: Mutex::new(workers),
+        })
+    }
+
+    /// Run `job` by up to `count` idle worker threads while the calling thread runs `work`, and
+    /// wait for all of them to complete.
+    ///
+    /// `job` and `work` are expected to take their share of the work from a common queue, as the
+    /// number of workers taking the job is not known in advance.
+    pub fn run<F, W>(&self, count: usize, job: &F, work: W)
+    where
+        F: Fn() + Sync,
+        W: FnOnce(),
+    {
+        let (done, completion) = channel::<()>();
+        let completion = Completion(completion);
+
+        if let Some(sender) = self.sender.read().unwrap().as_ref() {
+            for _ in 0..count {
+                let job = Self::make_job(job, done.clone());
+                if sender.try_send(job).is_err() {
+                    break;
+                }
+            }
+        }
+        drop(done);
+
+        work();
+        drop(completion);
+    }
+
+    fn make_job<F: Fn() + Sync>(job: &F, done: Sender<()>) -> FetchJob {
+        let job: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+            job();
+            drop(done);
+        });
+        // Safe because `run()` waits for completion of all jobs before returning, even on panic,
+        // so `job` outlives them.
+        unsafe { std::mem::transmute(job) }
+    }
+
+    /// Stop all worker threads after pending jobs are done.
+    pub fn stop(&self) {
+        self.sender.write().unwrap().take();
+        for worker in self.workers.lock().unwrap().drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for FetchPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_fetch_pool() {
+        let pool = FetchPool::new(2).unwrap();
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(vec![0; 64]);
+        let fetch = || loop {
+            let idx = next.fetch_add(1, Ordering::Relaxed);
+            if idx >= 64 {
+                break;
+            }
+            results.lock().unwrap()[idx] = idx * 2;
+        };
+        pool.run(2, &fetch, fetch);
+        assert_eq!(
+            results.into_inner().unwrap(),
+            (0..64).map(|v| v * 2).collect::<Vec<_>>()
+        );
+
+        // Both workers help, so three threads run concurrently.
+        let barrier = Barrier::new(3);
+        pool.run(
+            2,
+            &|| {
+                barrier.wait();
+            },
+            || {
+                barrier.wait();
+            },
+        );
+
+        // The calling thread does all the work after the pool has been stopped.
+        pool.stop();
+        let count = AtomicUsize::new(0);
+        let job = || {
+            count.fetch_add(1, Ordering::Relaxed);
+        };
+        pool.run(2, &job, job);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+}
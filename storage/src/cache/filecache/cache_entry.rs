@@ -8,45 +8,69 @@ use std::io::{ErrorKind, Result, Seek, SeekFrom};
 use std::mem::ManuallyDrop;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::slice;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use fuse_backend_rs::transport::FileVolatileSlice;
-use nix::sys::uio;
 use nix::unistd::dup;
 use nydus_utils::digest;
-use nydus_utils::metrics::{BlobcacheMetrics, Metric};
+use nydus_utils::metrics::{BlobProgress, BlobcacheMetrics, ErrorClass, Metric};
+use nydus_utils::tracing::{self, TraceContext};
 use tokio::runtime::Runtime;
 
-use crate::backend::BlobReader;
+use crate::backend::peer::PeerReader;
+use crate::backend::switch::SwitchableReader;
+use crate::backend::{request, BlobReader};
+use crate::cache::buffer_pool::PooledBuffer;
+use crate::cache::decompress::DecompressPool;
+use crate::cache::direct_io::DirectFile;
+use crate::cache::fetch::FetchPool;
 use crate::cache::filecache::FileCacheMgr;
-use crate::cache::state::{BlobStateMap, ChunkMap, DigestedChunkMap, IndexedChunkMap};
+use crate::cache::state::{
+    BlobStateMap, ChunkMap, DigestedChunkMap, IndexedChunkMap, PrefetchProgress,
+};
 use crate::cache::worker::{
     AsyncPrefetchConfig, AsyncRequestMessage, AsyncRequestState, AsyncWorkerMgr,
 };
 use crate::cache::{BlobCache, BlobIoMergeState};
+use crate::crypt::Cipher;
 use crate::device::{
     BlobChunkInfo, BlobFeatures, BlobInfo, BlobIoChunk, BlobIoDesc, BlobIoRange, BlobIoSegment,
     BlobIoTag, BlobIoVec, BlobObject, BlobPrefetchRequest,
 };
 use crate::meta::{BlobMetaChunk, BlobMetaInfo};
-use crate::utils::{alloc_buf, copyv, readv, MemSliceCursor};
+use crate::utils::{alloc_buf, copyv, pread, pwrite, readv, IoGate, MemSliceCursor};
 use crate::{compress, StorageError, StorageResult, RAFS_DEFAULT_CHUNK_SIZE};
 
 pub(crate) struct FileCacheEntry {
     blob_info: Arc<BlobInfo>,
     chunk_map: Arc<dyn ChunkMap>,
     file: Arc<File>,
+    // Access chunk data of the cache file by direct IO if available.
+    direct_file: Option<Arc<DirectFile>>,
     meta: Option<Arc<BlobMetaInfo>>,
     metrics: Arc<BlobcacheMetrics>,
     prefetch_state: Arc<AtomicU32>,
+    // Ranges prefetched, persisted only if readiness of chunks is persisted too.
+    prefetch_progress: Option<PrefetchProgress>,
+    progress: Arc<BlobProgress>,
     reader: Arc<dyn BlobReader>,
+    // Reader of the storage backend, wrapped by `reader` if reading from peers.
+    backend_reader: Arc<SwitchableReader>,
+    // Closed while the blob is frozen, entered by all accesses to the cache file and the backend.
+    gate: Arc<IoGate>,
     runtime: Arc<Runtime>,
     workers: Arc<AsyncWorkerMgr>,
+    decompress_pool: Option<Arc<DecompressPool>>,
+    fetch_pool: Option<Arc<FetchPool>>,
+    cipher: Option<Arc<Cipher>>,
 
     blob_size: u64,
     compressor: compress::Algorithm,
     digester: digest::Algorithm,
+    // Maximum number of concurrent backend reads for a user IO request.
+    fetch_concurrency: usize,
     // Whether `get_blob_object()` is supported.
     is_get_blob_object_supported: bool,
     // The compressed data instead of uncompressed data is cached if `compressed` is true.
@@ -69,25 +93,55 @@ impl FileCacheEntry {
         workers: Arc<AsyncWorkerMgr>,
     ) -> Result<Self> {
         let blob_file_path = format!("{}/{}", mgr.work_dir, blob_info.blob_id());
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(&blob_file_path)?;
+        let file = if mgr.is_fscache {
+            // The cache file is provided and managed by the Linux fscache subsystem.
+            if !blob_info.meta_ci_is_valid() {
+                return Err(einval!(format!(
+                    "fscache mode requires chunk information array of blob {}",
+                    blob_info.blob_id()
+                )));
+            }
+            blob_info.get_fscache_file().ok_or_else(|| {
+                einval!(format!(
+                    "no fscache file associated with blob {}",
+                    blob_info.blob_id()
+                ))
+            })?
+        } else {
+            Arc::new(
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .read(true)
+                    .open(&blob_file_path)?,
+            )
+        };
         let (chunk_map, is_direct_chunkmap) =
             Self::create_chunk_map(mgr, &blob_info, &blob_file_path)?;
-        let reader = mgr
+        let backend_reader = mgr
             .backend
             .get_reader(blob_info.blob_id())
             .map_err(|_e| eio!("failed to get blob reader"))?;
+        let backend_reader = Arc::new(SwitchableReader::new(backend_reader));
+        let reader: Arc<dyn BlobReader> = backend_reader.clone();
 
         let blob_size = Self::get_blob_size(&reader, &blob_info)?;
         let compressor = blob_info.compressor();
         let digester = blob_info.digester();
         let is_stargz = blob_info.is_stargz();
         let is_compressed = mgr.is_compressed || is_stargz;
-        let need_validate = (mgr.validate || !is_direct_chunkmap) && !is_stargz;
+        // Stargz chunks can't be validated, so they are never read from peers.
+        let use_peers = mgr.peers.is_some() && !is_stargz;
+        let need_validate = (mgr.validate || !is_direct_chunkmap || use_peers) && !is_stargz;
         let is_get_blob_object_supported = !mgr.is_compressed && is_direct_chunkmap && !is_stargz;
+        let cipher = mgr.cipher_config.blob_cipher(&blob_info)?;
+        // Only chunk data fetched from the storage backend is decrypted.
+        if cipher.is_some() && is_compressed {
+            return Err(einval!(format!(
+                "compressed blob cache doesn't support encrypted blob {}",
+                blob_info.blob_id()
+            )));
+        }
 
         trace!(
             "comp {} direct {} startgz {}",
@@ -96,12 +150,14 @@ impl FileCacheEntry {
             is_stargz
         );
         let meta = if is_get_blob_object_supported && blob_info.meta_ci_is_valid() {
-            // Set cache file to its expected size.
-            let file_size = file.metadata()?.len();
-            if file_size == 0 {
-                file.set_len(blob_info.uncompressed_size())?;
-            } else {
-                assert_eq!(file_size, blob_info.uncompressed_size());
+            // Set cache file to its expected size, fscache has already sized the cache file.
+            if !mgr.is_fscache {
+                let file_size = file.metadata()?.len();
+                if file_size == 0 {
+                    file.set_len(blob_info.uncompressed_size())?;
+                } else {
+                    assert_eq!(file_size, blob_info.uncompressed_size());
+                }
             }
 
             Some(Arc::new(BlobMetaInfo::new(
@@ -112,21 +168,68 @@ impl FileCacheEntry {
         } else {
             None
         };
+        // Data read from peers is validated as data read from the storage backend, so peers
+        // don't need to be trusted more than the storage backend.
+        let reader: Arc<dyn BlobReader> = match mgr.peers.as_ref() {
+            Some(peers) if use_peers => {
+                Arc::new(PeerReader::new(blob_info.blob_id(), peers.clone(), reader))
+            }
+            _ => reader,
+        };
+
+        // Stargz chunks are decompressed from streams of the cache file, by buffered IO.
+        let direct_file = if mgr.direct_io && !is_stargz {
+            // Not all file systems support direct IO, fall back to buffered IO in that case.
+            match DirectFile::open(&blob_file_path) {
+                Ok(f) => Some(Arc::new(f)),
+                Err(e) => {
+                    warn!("fall back to buffered IO for cache file, {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // Chunks cached before restart are accounted as fetched.
+        let cached_bytes = match (meta.as_ref(), chunk_map.as_range_map()) {
+            (Some(meta), Some(map)) if chunk_map.is_persist() => meta
+                .get_compressed_sizes()
+                .enumerate()
+                .filter(|(index, _)| map.is_range_ready(*index as u32, 1).unwrap_or(false))
+                .map(|(_, size)| size as u64)
+                .sum(),
+            _ => 0,
+        };
+        let progress = BlobProgress::new(blob_info.blob_id(), blob_size, cached_bytes);
+        let prefetch_progress = if is_direct_chunkmap && !mgr.is_fscache {
+            Some(PrefetchProgress::open(&blob_file_path))
+        } else {
+            None
+        };
 
         Ok(FileCacheEntry {
             blob_info,
             chunk_map,
-            file: Arc::new(file),
+            file,
+            direct_file,
             meta,
             metrics: mgr.metrics.clone(),
             prefetch_state: Arc::new(AtomicU32::new(AsyncRequestState::Init as u32)),
+            prefetch_progress,
+            progress,
             reader,
+            backend_reader,
+            gate: Arc::new(IoGate::default()),
             runtime,
             workers,
+            decompress_pool: mgr.decompress_pool.clone(),
+            fetch_pool: mgr.fetch_pool.clone(),
+            cipher,
 
             blob_size,
             compressor,
             digester,
+            fetch_concurrency: mgr.fetch_concurrency,
             is_get_blob_object_supported,
             is_compressed,
             is_direct_chunkmap,
@@ -152,15 +255,31 @@ impl FileCacheEntry {
             direct_chunkmap = false;
             Arc::new(BlobStateMap::from(DigestedChunkMap::new()))
         } else {
-            Arc::new(BlobStateMap::from(IndexedChunkMap::new(
-                blob_file,
-                blob_info.chunk_count(),
-            )?))
+            let mut map = IndexedChunkMap::new(blob_file, blob_info.chunk_count())?;
+            if let Some(shm_dir) = mgr.shm_dir.as_ref() {
+                // Observers of the shared chunk map are optional, so don't fail the blob.
+                if let Err(e) = map.share(shm_dir, blob_info.blob_id()) {
+                    warn!(
+                        "failed to share chunk map of blob {} in {}: {}",
+                        blob_info.blob_id(),
+                        shm_dir,
+                        e
+                    );
+                }
+            }
+            Arc::new(BlobStateMap::from(map))
         };
 
         Ok((chunk_map, direct_chunkmap))
     }
 
+    // Check whether the entry caches data into the fscache file associated with `blob_info`.
+    pub(crate) fn is_same_fscache_file(&self, blob_info: &BlobInfo) -> bool {
+        blob_info
+            .get_fscache_file()
+            .map_or(false, |f| Arc::ptr_eq(&f, &self.file))
+    }
+
     fn get_blob_size(reader: &Arc<dyn BlobReader>, blob_info: &BlobInfo) -> Result<u64> {
         // Stargz needs blob size information, so hacky!
         let size = if blob_info.is_stargz() {
@@ -186,6 +305,22 @@ impl BlobCache for FileCacheEntry {
         self.compressor
     }
 
+    fn decompress(
+        &self,
+        raw_buffer: &[u8],
+        raw_stream: Option<File>,
+        buffer: &mut [u8],
+    ) -> Result<usize> {
+        match self.decompress_pool.as_ref() {
+            Some(pool) => pool.decompress(raw_buffer, raw_stream, buffer, self.compressor),
+            None => compress::decompress(raw_buffer, raw_stream, buffer, self.compressor),
+        }
+    }
+
+    fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_deref()
+    }
+
     fn digester(&self) -> digest::Algorithm {
         self.digester
     }
@@ -202,10 +337,88 @@ impl BlobCache for FileCacheEntry {
         &*self.reader
     }
 
+    fn switchable_reader(&self) -> Option<&SwitchableReader> {
+        Some(&self.backend_reader)
+    }
+
     fn get_chunk_map(&self) -> &Arc<dyn ChunkMap> {
         &self.chunk_map
     }
 
+    fn prefetch_progress(&self) -> Option<&PrefetchProgress> {
+        self.prefetch_progress.as_ref()
+    }
+
+    fn fetch_range(&self, offset: u64, size: u64) -> Result<usize> {
+        if !self.is_direct_chunkmap || self.is_stargz {
+            return Err(enosys!("doesn't support fetch_range() without chunk info"));
+        }
+
+        // Fetch by pieces of the merging size, so a large range isn't read into one buffer.
+        let piece = match self.prefetch_config.merging_size as u64 {
+            0 => RAFS_DEFAULT_CHUNK_SIZE,
+            size => size,
+        };
+        let end = offset
+            .checked_add(size)
+            .ok_or_else(|| einval!("invalid range to fetch"))?;
+        let mut total = 0;
+        let mut pos = offset;
+        while pos < end {
+            let len = std::cmp::min(piece, end - pos);
+            total += self.fetch_range_uncompressed(pos, len)?;
+            pos += len;
+        }
+
+        Ok(total)
+    }
+
+    fn read_cached_range(&self, offset: u64, size: u64) -> Result<Option<Vec<u8>>> {
+        let _entry = self.gate.enter();
+        // Only compressed caches keep blob data at the same offsets as the storage backend.
+        let meta = match self.meta.as_ref() {
+            Some(meta) if self.is_compressed && !self.is_stargz => meta,
+            _ => return Ok(None),
+        };
+        if size == 0 || size > usize::MAX as u64 {
+            return Err(einval!("invalid size of blob range"));
+        }
+        let chunks = meta.get_chunks_compressed(offset, size)?;
+        // Only serve whole chunks, so each of them can be validated before returned.
+        match (chunks.first(), chunks.last()) {
+            (Some(first), Some(last))
+                if first.as_base().compress_offset() == offset
+                    && last.as_base().compress_offset() + last.as_base().compress_size() as u64
+                        == offset + size => {}
+            _ => return Ok(None),
+        }
+        for chunk in chunks.iter() {
+            if !self.chunk_map.is_ready(chunk.as_base())? {
+                return Ok(None);
+            }
+        }
+
+        let mut buf = alloc_buf(size as usize);
+        let nr_read = pread(self.file.as_raw_fd(), &mut buf, offset)?;
+        if nr_read != buf.len() {
+            return Err(eio!(format!(
+                "request for {} bytes but got {} bytes from cache file",
+                size, nr_read
+            )));
+        }
+
+        // The cache file may be corrupted, don't spread corrupted data to other nodes.
+        for chunk in chunks.iter() {
+            let chunk = chunk.as_base();
+            let start = (chunk.compress_offset() - offset) as usize;
+            let raw = &buf[start..start + chunk.compress_size() as usize];
+            let mut scratch = alloc_buf(chunk.uncompress_size() as usize);
+            self.process_raw_chunk(chunk, raw, None, &mut scratch, chunk.is_compressed(), true)?;
+        }
+
+        Ok(Some(buf))
+    }
+
     fn get_blob_object(&self) -> Option<&dyn BlobObject> {
         if self.is_get_blob_object_supported {
             Some(self)
@@ -260,14 +473,74 @@ impl BlobCache for FileCacheEntry {
         Ok(())
     }
 
+    fn freeze(&self, timeout: Duration) -> Result<()> {
+        self.gate.hold(timeout)
+    }
+
+    fn thaw(&self) {
+        self.gate.release()
+    }
+
+    fn is_frozen(&self) -> bool {
+        self.gate.is_closed()
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Chunk data must reach the storage before the chunk map marking it ready.
+        self.file.sync_data()?;
+        self.chunk_map.flush()?;
+        match self.prefetch_progress.as_ref() {
+            Some(progress) => progress.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.chunk_map.memory_usage() + self.meta.as_ref().map_or(0, |m| m.memory_usage())
+    }
+
+    fn shrink(&self) {
+        self.chunk_map.shrink();
+        if let Some(meta) = self.meta.as_ref() {
+            meta.shrink();
+        }
+    }
+
+    fn scrub_chunk(&self, chunk: &BlobIoChunk, remote: bool) -> Result<bool> {
+        if self.is_stargz {
+            return Ok(false);
+        }
+        let _entry = self.gate.enter();
+        let mut buffer = alloc_buf(chunk.uncompress_size() as usize);
+        if remote {
+            self.read_raw_chunk(chunk, &mut buffer, true, None)?;
+        } else if self.chunk_map.is_ready(chunk.as_base())? {
+            self.read_file_cache(chunk, &mut buffer, true)
+                .map_err(|e| match ErrorClass::of(&e) {
+                    ErrorClass::DigestMismatch => e,
+                    // Partially written or truncated cache data can't be verified either.
+                    _ => ErrorClass::DigestMismatch.error(format!(
+                        "failed to verify cached chunk {}, {}",
+                        chunk.id(),
+                        e
+                    )),
+                })?;
+        } else {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
     fn prefetch_range(&self, range: &BlobIoRange) -> Result<usize> {
+        let _entry = self.gate.enter();
         let mut pending = Vec::with_capacity(range.chunks.len());
         if !self.chunk_map.is_persist() {
             let mut d_size = 0;
             for c in range.chunks.iter() {
                 d_size = std::cmp::max(d_size, c.uncompress_size() as usize);
             }
-            let mut buf = alloc_buf(d_size);
+            let mut buf = PooledBuffer::new(d_size);
 
             for c in range.chunks.iter() {
                 if let Ok(true) = self.chunk_map.check_ready_and_mark_pending(c.as_base()) {
@@ -282,8 +555,7 @@ impl BlobCache for FileCacheEntry {
                     Ok(_v) => {
                         // The cached data is valid, set the chunk as ready.
                         let _ = self
-                            .chunk_map
-                            .set_ready_and_clear_pending(c.as_base())
+                            .set_chunk_ready(c.as_base())
                             .map_err(|e| error!("Failed to set chunk ready: {:?}", e));
                     }
                     Err(_e) => {
@@ -313,7 +585,8 @@ impl BlobCache for FileCacheEntry {
 
             // Find a range with continuous chunk id
             let blob_offset = pending[start].compress_offset();
-            let blob_end = pending[end].compress_offset() + pending[end].compress_size() as u64;
+            let blob_end =
+                pending[end - 1].compress_offset() + pending[end - 1].compress_size() as u64;
             let blob_size = (blob_end - blob_offset) as usize;
             match self.read_chunks(blob_offset, blob_size, &pending[start..end]) {
                 Ok(v) => {
@@ -324,9 +597,9 @@ impl BlobCache for FileCacheEntry {
                         } else {
                             pending[idx].uncompress_offset()
                         };
-                        match Self::persist_chunk(&self.file, offset, &v[idx - start]) {
+                        match self.persist_chunk_data(offset, &v[idx - start]) {
                             Ok(_) => {
-                                let _ = self.chunk_map.set_ready_and_clear_pending(&pending[idx]);
+                                let _ = self.set_chunk_ready(&pending[idx]);
                             }
                             Err(_) => self.chunk_map.clear_pending(&pending[idx]),
                         }
@@ -347,6 +620,7 @@ impl BlobCache for FileCacheEntry {
 
     fn read(&self, iovec: &mut BlobIoVec, buffers: &[FileVolatileSlice]) -> Result<usize> {
         debug_assert!(iovec.validate());
+        let _entry = self.gate.enter();
         self.metrics.total.inc();
         self.workers.consume_prefetch_budget(buffers);
 
@@ -361,8 +635,22 @@ impl BlobCache for FileCacheEntry {
         }
 
         if iovec.bi_vec.is_empty() {
-            Ok(0)
-        } else if iovec.bi_vec.len() == 1 {
+            return Ok(0);
+        }
+
+        // Reads are classified as cache hits or misses for latency accounting only, so the
+        // readiness of chunks may change before they are actually read.
+        let start = Instant::now();
+        let hit = iovec.bi_vec.iter().all(|b| {
+            self.chunk_map
+                .is_ready(b.chunkinfo.as_base())
+                .unwrap_or(false)
+        });
+        let span = tracing::span("cache.read");
+        span.set_attribute_str("blob", self.blob_info.blob_id());
+        span.set_attribute_i64("size", iovec.bi_size as i64);
+        span.set_attribute_i64("hit", hit as i64);
+        let result = if iovec.bi_vec.len() == 1 {
             let mut state = FileIoMergeState::new();
             let mut cursor = MemSliceCursor::new(buffers);
             let req = BlobIoRange::new(&iovec.bi_vec[0], 1);
@@ -370,7 +658,25 @@ impl BlobCache for FileCacheEntry {
             self.dispatch_one_range(&req, &mut cursor, &mut state)
         } else {
             self.read_iter(&mut iovec.bi_vec, buffers)
+        };
+        if hit {
+            self.metrics.read_latency_hit.record(&start.elapsed());
+        } else {
+            self.metrics.read_latency_miss.record(&start.elapsed());
+        }
+
+        result
+    }
+}
+
+impl Drop for FileCacheEntry {
+    fn drop(&mut self) {
+        if let Some(progress) = self.prefetch_progress.as_ref() {
+            progress
+                .flush()
+                .unwrap_or_else(|e| warn!("failed to save prefetch progress, {}", e));
         }
+        self.progress.release();
     }
 }
 
@@ -394,6 +700,7 @@ impl BlobObject for FileCacheEntry {
     }
 
     fn fetch_range_compressed(&self, offset: u64, size: u64) -> Result<usize> {
+        let _entry = self.gate.enter();
         let meta = self.meta.as_ref().ok_or_else(|| einval!())?;
         let chunks = meta.get_chunks_compressed(offset, size)?;
         debug_assert!(!chunks.is_empty());
@@ -402,6 +709,7 @@ impl BlobObject for FileCacheEntry {
     }
 
     fn fetch_range_uncompressed(&self, offset: u64, size: u64) -> Result<usize> {
+        let _entry = self.gate.enter();
         let meta = self.meta.as_ref().ok_or_else(|| einval!())?;
 
         // TODO: read amplify the range to naturally aligned 2M?
@@ -416,6 +724,7 @@ impl BlobObject for FileCacheEntry {
         if chunks.is_empty() {
             return Ok(0);
         }
+        let _entry = self.gate.enter();
 
         for idx in 1..chunks.len() {
             if chunks[idx - 1].id() + 1 != chunks[idx].id() {
@@ -475,13 +784,17 @@ impl FileCacheEntry {
                             chunks[idx].uncompress_offset()
                         };
                         trace!("persist_chunk idx {}", idx);
-                        Self::persist_chunk(&self.file, offset, &v[idx - start_idx]).map_err(
-                            |e| eio!(format!("do_fetch_chunk failed to persist {:?}", e)),
-                        )?;
+                        self.persist_chunk_data(offset, &v[idx - start_idx])
+                            .map_err(|e| {
+                                eio!(format!("do_fetch_chunk failed to persist {:?}", e))
+                            })?;
                     }
 
                     bitmap
                         .set_range_ready_and_clear_pending(pending[start], (end - start) as u32)?;
+                    for chunk in &chunks[start_idx..=end_idx] {
+                        self.progress.ready(chunk.compress_size() as u64);
+                    }
                 }
                 Err(e) => {
                     bitmap.clear_range_pending(pending[start], (end - start) as u32);
@@ -519,6 +832,10 @@ impl FileCacheEntry {
         let mut cursor = MemSliceCursor::new(buffers);
         let mut total_read: usize = 0;
 
+        if self.fetch_pool.is_some() && requests.len() > 1 {
+            return self.dispatch_ranges_parallel(&requests, &mut cursor, &mut state);
+        }
+
         for req in requests {
             total_read += self.dispatch_one_range(&req, &mut cursor, &mut state)?;
             state.reset();
@@ -536,6 +853,152 @@ impl FileCacheEntry {
         let mut total_read: usize = 0;
 
         trace!("dispatch single io range {:?}", req);
+        self.classify_one_range(req, state)?;
+
+        for r in &state.regions {
+            use RegionType::*;
+
+            total_read += match r.r#type {
+                CacheFast => self.dispatch_cache_fast(cursor, r)?,
+                CacheSlow => self.dispatch_cache_slow(cursor, r)?,
+                Backend => self.dispatch_backend(cursor, r)?,
+            }
+        }
+
+        Ok(total_read)
+    }
+
+    // Dispatch all ranges of a large user IO request, with data of backend regions fetched by
+    // up to `fetch_concurrency` concurrent backend reads and then copied to the user buffer in
+    // order.
+    fn dispatch_ranges_parallel(
+        &self,
+        requests: &[BlobIoRange],
+        cursor: &mut MemSliceCursor,
+        state: &mut FileIoMergeState,
+    ) -> Result<usize> {
+        let mut total_read: usize = 0;
+        let mut regions = Vec::with_capacity(requests.len());
+
+        // Regions never span ranges, just like dispatching ranges one by one.
+        for req in requests {
+            trace!("dispatch io range {:?}", req);
+            let result = self.classify_one_range(req, state);
+            regions.append(&mut state.regions);
+            if let Err(e) = result {
+                self.clear_pending_regions(&regions, &[]);
+                return Err(e);
+            }
+        }
+
+        let mut results = self.fetch_backend_regions(&regions);
+        let mut chunk_buffers = Vec::with_capacity(regions.len());
+        let mut error = None;
+        for (r, result) in regions.iter().zip(results.drain(..)) {
+            match result {
+                Some(Ok(chunks)) => {
+                    chunk_buffers.push(Some(self.persist_backend_chunks(r, chunks)))
+                }
+                Some(Err(e)) => {
+                    error.get_or_insert(e);
+                    chunk_buffers.push(None);
+                }
+                None => chunk_buffers.push(None),
+            }
+        }
+        if let Some(e) = error {
+            self.clear_pending_regions(&regions, &chunk_buffers);
+            return Err(e);
+        }
+
+        for (idx, (r, buffers)) in regions.iter().zip(chunk_buffers.iter()).enumerate() {
+            use RegionType::*;
+
+            let result = match (r.r#type, buffers) {
+                (CacheFast, _) => self.dispatch_cache_fast(cursor, r),
+                (CacheSlow, _) => self.dispatch_cache_slow(cursor, r),
+                (Backend, Some(buffers)) => self.copy_backend_chunks(cursor, r, buffers),
+                (Backend, None) => self.dispatch_backend(cursor, r),
+            };
+            match result {
+                Ok(size) => total_read += size,
+                Err(e) => {
+                    self.clear_pending_regions(&regions[idx + 1..], &chunk_buffers[idx + 1..]);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(total_read)
+    }
+
+    // Clear pending state of chunks of regions which won't be dispatched, so other requests
+    // waiting for the chunks fetch them by themselves instead of timing out. Chunks fetched from
+    // the backend, i.e. with buffers in `chunk_buffers`, are cleared when persisted.
+    fn clear_pending_regions(
+        &self,
+        regions: &[Region],
+        chunk_buffers: &[Option<Vec<Arc<DataBuffer>>>],
+    ) {
+        for (idx, r) in regions.iter().enumerate() {
+            let fetched = matches!(chunk_buffers.get(idx), Some(Some(_)));
+            if r.r#type == RegionType::CacheFast || fetched {
+                continue;
+            }
+            for c in &r.chunks {
+                // Only chunks not ready yet have been marked pending by this request.
+                if !self.chunk_map.is_ready(c.as_base()).unwrap_or(false) {
+                    self.chunk_map.clear_pending(c.as_base());
+                }
+            }
+        }
+    }
+
+    // Fetch data of backend regions with user IO by concurrent backend reads, results are
+    // returned in the order of `regions`.
+    fn fetch_backend_regions(&self, regions: &[Region]) -> Vec<Option<Result<Vec<Vec<u8>>>>> {
+        let pending: Vec<usize> = regions
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| {
+                r.r#type == RegionType::Backend && !r.chunks.is_empty() && r.has_user_io()
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+        let results = Mutex::new((0..regions.len()).map(|_| None).collect::<Vec<_>>());
+        let next = AtomicUsize::new(0);
+        let handle = request::current();
+        let trace_context = TraceContext::current();
+        let fetch = || {
+            while let Some(idx) = pending.get(next.fetch_add(1, Ordering::Relaxed)) {
+                let r = &regions[*idx];
+                debug!("total backend data {}KB", r.blob_len / 1024);
+                let result = self.read_chunks(r.blob_address, r.blob_len as usize, &r.chunks);
+                results.lock().unwrap()[*idx] = Some(result);
+            }
+        };
+        // Worker threads of the pool serve the request on behalf of the calling thread.
+        let help = || {
+            if let Some(handle) = handle.clone() {
+                request::attach(handle);
+            }
+            let _guard = trace_context.clone().attach();
+            fetch();
+            request::detach();
+        };
+
+        // The calling thread serves as one of the concurrent backend reads.
+        let helpers = std::cmp::min(self.fetch_concurrency, pending.len()).saturating_sub(1);
+        match self.fetch_pool.as_ref() {
+            Some(pool) => pool.run(helpers, &help, fetch),
+            None => fetch(),
+        }
+
+        results.into_inner().unwrap()
+    }
+
+    // Classify chunks of the range into regions to be read from the file cache or the backend.
+    fn classify_one_range(&self, req: &BlobIoRange, state: &mut FileIoMergeState) -> Result<()> {
         for (i, chunk) in req.chunks.iter().enumerate() {
             let is_ready = self
                 .chunk_map
@@ -592,26 +1055,33 @@ impl FileCacheEntry {
             }
         }
 
-        for r in &state.regions {
-            use RegionType::*;
-
-            total_read += match r.r#type {
-                CacheFast => self.dispatch_cache_fast(cursor, r)?,
-                CacheSlow => self.dispatch_cache_slow(cursor, r)?,
-                Backend => self.dispatch_backend(cursor, r)?,
-            }
-        }
-
-        Ok(total_read)
+        Ok(())
     }
 
     // Directly read data requested by user from the file cache into the user memory buffer.
     fn dispatch_cache_fast(&self, cursor: &mut MemSliceCursor, region: &Region) -> Result<usize> {
         let offset = region.blob_address + region.seg.offset as u64;
         let size = region.seg.len as usize;
-        let iovec = cursor.consume(size);
 
         self.metrics.partial_hits.inc();
+        if let Some(f) = self.direct_file.as_ref() {
+            // User buffers are not aligned for direct IO, so copy data from the bounce buffer.
+            let buf = f.read(offset, size)?;
+            let nr_read = buf.len();
+            let (total_read, _) = copyv(
+                &[&buf[..]],
+                cursor.mem_slice,
+                0,
+                nr_read,
+                cursor.index,
+                cursor.offset,
+            )
+            .map_err(|e| eio!(format!("failed to copy from cache file to buf: {:?}", e)))?;
+            cursor.move_cursor(total_read);
+            return Ok(total_read);
+        }
+        let iovec = cursor.consume(size);
+
         readv(self.file.as_raw_fd(), &iovec, offset)
     }
 
@@ -643,10 +1113,20 @@ impl FileCacheEntry {
 
         let blob_size = region.blob_len as usize;
         debug!("total backend data {}KB", blob_size / 1024);
-        let mut chunks = self.read_chunks(region.blob_address, blob_size, &region.chunks)?;
+        let chunks = self.read_chunks(region.blob_address, blob_size, &region.chunks)?;
+        let buffer_holder = self.persist_backend_chunks(region, chunks);
+
+        self.copy_backend_chunks(mem_cursor, region, &buffer_holder)
+    }
+
+    // Persist chunks fetched from the backend, and return buffers of chunks with user IO.
+    fn persist_backend_chunks(
+        &self,
+        region: &Region,
+        mut chunks: Vec<Vec<u8>>,
+    ) -> Vec<Arc<DataBuffer>> {
         assert_eq!(region.chunks.len(), chunks.len());
 
-        let mut chunk_buffers = Vec::with_capacity(region.chunks.len());
         let mut buffer_holder = Vec::with_capacity(region.chunks.len());
         for (i, v) in chunks.drain(..).enumerate() {
             let d = Arc::new(DataBuffer::Allocated(v));
@@ -655,6 +1135,17 @@ impl FileCacheEntry {
             }
             self.delay_persist(region.chunks[i].clone(), d);
         }
+
+        buffer_holder
+    }
+
+    fn copy_backend_chunks(
+        &self,
+        mem_cursor: &mut MemSliceCursor,
+        region: &Region,
+        buffer_holder: &[Arc<DataBuffer>],
+    ) -> Result<usize> {
+        let mut chunk_buffers = Vec::with_capacity(buffer_holder.len());
         for d in buffer_holder.iter() {
             chunk_buffers.push(d.as_ref().slice());
         }
@@ -679,27 +1170,33 @@ impl FileCacheEntry {
 
     fn delay_persist(&self, chunk_info: BlobIoChunk, buffer: Arc<DataBuffer>) {
         let delayed_chunk_map = self.chunk_map.clone();
+        let progress = self.progress.clone();
         let file = self.file.clone();
+        let direct_file = self.direct_file.clone();
         let offset = if self.is_compressed {
             chunk_info.compress_offset()
         } else {
             chunk_info.uncompress_offset()
         };
         let metrics = self.metrics.clone();
+        // Persisting is part of the read, so it's drained before the blob is frozen.
+        let entry = self.gate.enter_derived();
 
         metrics.buffered_backend_size.add(buffer.size() as u64);
         self.runtime.spawn_blocking(move || {
+            let _entry = entry;
             metrics.buffered_backend_size.sub(buffer.size() as u64);
-            match Self::persist_chunk(&file, offset, buffer.slice()) {
-                Ok(_) => delayed_chunk_map
-                    .set_ready_and_clear_pending(chunk_info.as_base())
-                    .unwrap_or_else(|e| {
-                        error!(
+            match Self::persist_chunk(&file, direct_file.as_deref(), offset, buffer.slice()) {
+                Ok(_) => {
+                    match delayed_chunk_map.set_ready_and_clear_pending(chunk_info.as_base()) {
+                        Ok(_) => progress.ready(chunk_info.compress_size() as u64),
+                        Err(e) => error!(
                             "Failed change caching state for chunk of offset {}, {:?}",
                             chunk_info.compress_offset(),
                             e
-                        )
-                    }),
+                        ),
+                    }
+                }
                 Err(e) => {
                     error!(
                         "Persist chunk of offset {} failed, {:?}",
@@ -712,13 +1209,32 @@ impl FileCacheEntry {
         });
     }
 
+    // Mark the chunk ready in the chunk map, and account it to the download progress.
+    fn set_chunk_ready(&self, chunk: &dyn BlobChunkInfo) -> Result<()> {
+        self.chunk_map.set_ready_and_clear_pending(chunk)?;
+        self.progress.ready(chunk.compress_size() as u64);
+        Ok(())
+    }
+
+    fn persist_chunk_data(&self, offset: u64, buffer: &[u8]) -> Result<()> {
+        Self::persist_chunk(&self.file, self.direct_file.as_deref(), offset, buffer)
+    }
+
     /// Persist a single chunk into local blob cache file. We have to write to the cache
     /// file in unit of chunk size
-    fn persist_chunk(file: &Arc<File>, offset: u64, buffer: &[u8]) -> Result<()> {
+    fn persist_chunk(
+        file: &Arc<File>,
+        direct_file: Option<&DirectFile>,
+        offset: u64,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if let Some(f) = direct_file {
+            return f.write_at(buffer, offset);
+        }
         let fd = file.as_raw_fd();
 
         let n = loop {
-            let ret = uio::pwrite(fd, buffer, offset as i64).map_err(|_| last_error!());
+            let ret = pwrite(fd, buffer, offset);
             match ret {
                 Ok(nr_write) => {
                     trace!("write {}(offset={}) bytes to cache file", nr_write, offset);
@@ -752,17 +1268,32 @@ impl FileCacheEntry {
         let is_ready = self.chunk_map.is_ready(chunk.as_base())?;
         let buffer_holder;
         let d_size = chunk.uncompress_size() as usize;
-        let mut d = DataBuffer::Allocated(alloc_buf(d_size));
-
         // Try to read and validate data from cache if:
         // - it's an stargz image and the chunk is ready.
         // - chunk data validation is enabled.
         // - digested or dummy chunk map is used.
-        let try_cache = is_ready || (!self.is_stargz && !self.is_direct_chunkmap);
-        let buffer = if try_cache && self.read_file_cache(chunk, d.mut_slice()).is_ok() {
+        let mut try_cache = is_ready || (!self.is_stargz && !self.is_direct_chunkmap);
+
+        // Read ready chunks from the cache directly into the user buffer, typically the guest
+        // memory for virtio-fs, if the whole chunk is requested and the user buffer is contiguous.
+        // The user buffer may be modified concurrently by the guest, so data which changes state
+        // of the cache, i.e. validated to mark chunks ready or persisted into the cache, is always
+        // handled in a private buffer.
+        if is_ready && user_offset == 0 && size as usize == d_size {
+            if let Some(buf) = mem_cursor.contiguous_slice(d_size) {
+                if self.read_file_cache(chunk, buf, false).is_ok() {
+                    self.metrics.whole_hits.inc();
+                    mem_cursor.move_cursor(d_size);
+                    return Ok(d_size);
+                }
+                try_cache = false;
+            }
+        }
+
+        let mut d = DataBuffer::Allocated(alloc_buf(d_size));
+        let buffer = if try_cache && self.read_file_cache(chunk, d.mut_slice(), false).is_ok() {
             self.metrics.whole_hits.inc();
-            self.chunk_map
-                .set_ready_and_clear_pending(chunk.as_base())?;
+            self.set_chunk_ready(chunk.as_base())?;
             trace!(
                 "recover blob cache {} {} offset {} size {}",
                 chunk.id(),
@@ -777,21 +1308,17 @@ impl FileCacheEntry {
             self.delay_persist(chunk.clone(), buffer_holder.clone());
             buffer_holder.as_ref()
         } else {
-            let persist_compressed = |buffer: &[u8]| match Self::persist_chunk(
-                &self.file,
-                chunk.compress_offset(),
-                buffer,
-            ) {
-                Ok(_) => {
-                    self.chunk_map
-                        .set_ready_and_clear_pending(chunk.as_base())
-                        .unwrap_or_else(|e| error!("set ready failed, {}", e));
-                }
-                Err(e) => {
-                    error!("Failed in writing compressed blob cache index, {}", e);
-                    self.chunk_map.clear_pending(chunk.as_base())
-                }
-            };
+            let persist_compressed =
+                |buffer: &[u8]| match self.persist_chunk_data(chunk.compress_offset(), buffer) {
+                    Ok(_) => {
+                        self.set_chunk_ready(chunk.as_base())
+                            .unwrap_or_else(|e| error!("set ready failed, {}", e));
+                    }
+                    Err(e) => {
+                        error!("Failed in writing compressed blob cache index, {}", e);
+                        self.chunk_map.clear_pending(chunk.as_base())
+                    }
+                };
             self.read_raw_chunk(chunk, d.mut_slice(), false, Some(&persist_compressed))?;
             &d
         };
@@ -815,7 +1342,12 @@ impl FileCacheEntry {
         Ok(read_size)
     }
 
-    fn read_file_cache(&self, chunk: &BlobIoChunk, buffer: &mut [u8]) -> Result<()> {
+    fn read_file_cache(
+        &self,
+        chunk: &BlobIoChunk,
+        buffer: &mut [u8],
+        force_validation: bool,
+    ) -> Result<()> {
         let offset = if self.is_compressed {
             chunk.compress_offset()
         } else {
@@ -829,8 +1361,18 @@ impl FileCacheEntry {
             // gzip is special that it doesn't carry compress_size, instead, we make an IO stream
             // out of the file cache. So no need for an internal buffer here.
             let c_size = chunk.compress_size() as usize;
-            d = alloc_buf(c_size);
-            d.as_mut_slice()
+            if let Some(f) = self.direct_file.as_ref() {
+                // Decompress from the bounce buffer of direct IO instead of copying data out.
+                let data = f.read(offset, c_size)?;
+                if data.len() != c_size {
+                    return Err(einval!());
+                }
+                return self
+                    .process_raw_chunk(chunk, &data, None, buffer, true, force_validation)
+                    .map(|_| ());
+            }
+            d = PooledBuffer::new(c_size);
+            &mut d[..]
         } else {
             // We have this unsafe assignment as it can directly store data into call's buffer.
             unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr(), buffer.len()) }
@@ -851,8 +1393,10 @@ impl FileCacheEntry {
                 offset,
                 raw_buffer.len()
             );
-            let nr_read = uio::pread(self.file.as_raw_fd(), raw_buffer, offset as i64)
-                .map_err(|_| last_error!())?;
+            let nr_read = match self.direct_file.as_ref() {
+                Some(f) => f.read_at(raw_buffer, offset)?,
+                None => pread(self.file.as_raw_fd(), raw_buffer, offset)?,
+            };
             if nr_read == 0 || nr_read != raw_buffer.len() {
                 return Err(einval!());
             }
@@ -865,7 +1409,7 @@ impl FileCacheEntry {
             raw_stream,
             buffer,
             self.is_compressed,
-            false,
+            force_validation,
         )?;
 
         Ok(())
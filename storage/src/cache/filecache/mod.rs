@@ -3,7 +3,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Result;
 use std::sync::{Arc, RwLock};
@@ -14,9 +14,13 @@ use tokio::runtime::{Builder, Runtime};
 use nydus_utils::metrics::BlobcacheMetrics;
 
 use self::cache_entry::FileCacheEntry;
+use crate::backend::peer::{PeerConfig, Peers};
 use crate::backend::BlobBackend;
+use crate::cache::decompress::DecompressPool;
+use crate::cache::fetch::FetchPool;
 use crate::cache::worker::{AsyncPrefetchConfig, AsyncWorkerMgr};
 use crate::cache::{BlobCache, BlobCacheMgr};
+use crate::crypt::CipherConfig;
 use crate::device::BlobInfo;
 use crate::factory::CacheConfig;
 
@@ -32,6 +36,25 @@ struct BlobCacheConfig {
     work_dir: String,
     #[serde(default)]
     disable_indexed_map: bool,
+    /// Number of threads to decompress chunks, chunks are decompressed by the threads serving
+    /// requests if it's zero.
+    #[serde(default)]
+    decompress_threads: usize,
+    /// Maximum number of concurrent backend reads to serve a large read spanning many chunks,
+    /// chunks are fetched sequentially if it's zero or one. Helper threads are shared by all
+    /// reads, so concurrent reads may get fewer backend reads each.
+    #[serde(default)]
+    fetch_concurrency: usize,
+    /// Access cache files by direct IO, to avoid caching data again in the page cache.
+    #[serde(default)]
+    direct_io: bool,
+    /// Directory on a shared memory filesystem to mirror chunk maps of blobs into, so external
+    /// agents know which chunks are available locally.
+    #[serde(default)]
+    shm_dir: Option<String>,
+    /// Peer nodes to read blob data from before the storage backend.
+    #[serde(default)]
+    peers: PeerConfig,
 }
 
 impl BlobCacheConfig {
@@ -69,10 +92,19 @@ pub struct FileCacheMgr {
     prefetch_config: Arc<AsyncPrefetchConfig>,
     runtime: Arc<Runtime>,
     worker_mgr: Arc<AsyncWorkerMgr>,
+    decompress_pool: Option<Arc<DecompressPool>>,
+    fetch_pool: Option<Arc<FetchPool>>,
+    fetch_concurrency: usize,
+    direct_io: bool,
     work_dir: String,
+    shm_dir: Option<String>,
     validate: bool,
     disable_indexed_map: bool,
     is_compressed: bool,
+    // Cache files are provided by the Linux fscache subsystem instead of created in `work_dir`.
+    is_fscache: bool,
+    cipher_config: CipherConfig,
+    peers: Option<Arc<Peers>>,
 }
 
 impl FileCacheMgr {
@@ -83,7 +115,40 @@ impl FileCacheMgr {
         id: &str,
     ) -> Result<FileCacheMgr> {
         let blob_config: BlobCacheConfig =
-            serde_json::from_value(config.cache_config).map_err(|e| einval!(e))?;
+            serde_json::from_value(config.cache_config.clone()).map_err(|e| einval!(e))?;
+        Self::new_with_config(config, blob_config, backend, id, false)
+    }
+
+    /// Create a new instance of `FileCacheMgr` caching uncompressed blob data into files
+    /// provided by the Linux fscache subsystem.
+    ///
+    /// Cache files are associated with blobs by [BlobInfo::set_fscache_file()], and state files
+    /// of the blobs are kept in a per-instance subdirectory of the working directory.
+    ///
+    /// [BlobInfo::set_fscache_file()]: ../../device/struct.BlobInfo.html#method.set_fscache_file
+    pub fn new_fscache(
+        config: CacheConfig,
+        backend: Arc<dyn BlobBackend>,
+        id: &str,
+    ) -> Result<FileCacheMgr> {
+        let mut blob_config: BlobCacheConfig =
+            serde_json::from_value(config.cache_config.clone()).map_err(|e| einval!(e))?;
+        if config.cache_compressed || blob_config.disable_indexed_map || blob_config.direct_io {
+            return Err(einval!(
+                "fscache mode doesn't support compressed cache, disabling indexed chunk map or direct IO"
+            ));
+        }
+        blob_config.work_dir = format!("{}/{}", blob_config.work_dir, id);
+        Self::new_with_config(config, blob_config, backend, id, true)
+    }
+
+    fn new_with_config(
+        config: CacheConfig,
+        blob_config: BlobCacheConfig,
+        backend: Arc<dyn BlobBackend>,
+        id: &str,
+        is_fscache: bool,
+    ) -> Result<FileCacheMgr> {
         let work_dir = blob_config.get_work_dir()?;
         let metrics = BlobcacheMetrics::new(id, work_dir);
         let runtime = Arc::new(
@@ -97,6 +162,20 @@ impl FileCacheMgr {
         );
         let prefetch_config: Arc<AsyncPrefetchConfig> = Arc::new(config.prefetch_config.into());
         let worker_mgr = AsyncWorkerMgr::new(metrics.clone(), prefetch_config.clone())?;
+        let decompress_pool = if blob_config.decompress_threads > 0 {
+            Some(Arc::new(DecompressPool::new(
+                blob_config.decompress_threads,
+            )?))
+        } else {
+            None
+        };
+        // The calling thread serves as one of the concurrent backend reads.
+        let fetch_pool = if blob_config.fetch_concurrency > 1 {
+            Some(Arc::new(FetchPool::new(blob_config.fetch_concurrency - 1)?))
+        } else {
+            None
+        };
+        let peers = Peers::new(&blob_config.peers)?;
 
         Ok(FileCacheMgr {
             blobs: Arc::new(RwLock::new(HashMap::new())),
@@ -105,10 +184,18 @@ impl FileCacheMgr {
             prefetch_config,
             runtime,
             worker_mgr: Arc::new(worker_mgr),
+            decompress_pool,
+            fetch_pool,
+            fetch_concurrency: blob_config.fetch_concurrency,
+            direct_io: blob_config.direct_io,
             work_dir: work_dir.to_owned(),
+            shm_dir: blob_config.shm_dir,
             disable_indexed_map: blob_config.disable_indexed_map,
             validate: config.cache_validate,
             is_compressed: config.cache_compressed,
+            is_fscache,
+            cipher_config: config.cipher_config,
+            peers,
         })
     }
 
@@ -121,7 +208,11 @@ impl FileCacheMgr {
     // return the existing one.
     fn get_or_create_cache_entry(&self, blob: &Arc<BlobInfo>) -> Result<Arc<FileCacheEntry>> {
         if let Some(entry) = self.get(blob) {
-            return Ok(entry);
+            if !self.is_fscache || entry.is_same_fscache_file(blob) {
+                return Ok(entry);
+            }
+            // The kernel has reopened the cache object with a new cache file.
+            self.blobs.write().unwrap().remove(blob.blob_id());
         }
 
         let entry = FileCacheEntry::new(
@@ -154,30 +245,57 @@ impl BlobCacheMgr for FileCacheMgr {
 
     fn destroy(&self) {
         self.worker_mgr.stop();
+        if let Some(pool) = self.decompress_pool.as_ref() {
+            pool.stop();
+        }
+        if let Some(pool) = self.fetch_pool.as_ref() {
+            pool.stop();
+        }
         self.backend().shutdown();
         self.metrics.release().unwrap_or_else(|e| error!("{:?}", e));
     }
 
-    fn gc(&self) {
-        let mut reclaim = Vec::new();
+    fn blobs_in_use(&self) -> Vec<String> {
+        self.blobs
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| Arc::strong_count(entry) > 1)
+            .map(|(id, _)| id.to_owned())
+            .collect()
+    }
 
-        {
-            let guard = self.blobs.write().unwrap();
-            for (id, entry) in guard.iter() {
-                if Arc::strong_count(entry) == 1 {
-                    reclaim.push(id.to_owned());
-                }
+    fn gc(&self, in_use: &HashSet<String>) {
+        // Hold the lock until cache files are removed, so a blob cache being created won't
+        // lose its files.
+        let mut guard = self.blobs.write().unwrap();
+        guard.retain(|id, entry| Arc::strong_count(entry) > 1 || in_use.contains(id));
+        self.metrics
+            .underlying_files
+            .lock()
+            .unwrap()
+            .retain(|id| guard.contains_key(id));
+
+        let entries = match fs::read_dir(&self.work_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("failed to read blobcache work_dir {}: {}", self.work_dir, e);
+                return;
             }
-        }
-
-        for key in reclaim.iter() {
-            let mut guard = self.blobs.write().unwrap();
-            if let Some(entry) = guard.get(key) {
-                if Arc::strong_count(entry) > 1 {
-                    continue;
+        };
+        for entry in entries.flatten() {
+            if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                continue;
+            }
+            // Cache files and state files of a blob are all named after the blob id.
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let blob_id = name.split('.').next().unwrap_or_default();
+            if !guard.contains_key(blob_id) && !in_use.contains(blob_id) {
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    warn!("failed to remove blobcache file {:?}: {}", entry.path(), e);
                 }
             }
-            guard.remove(key);
         }
     }
 
@@ -189,6 +307,29 @@ impl BlobCacheMgr for FileCacheMgr {
         self.get_or_create_cache_entry(blob_info)
             .map(|v| v as Arc<dyn BlobCache>)
     }
+
+    fn find_blob_cache(&self, blob_id: &str) -> Option<Arc<dyn BlobCache>> {
+        self.blobs
+            .read()
+            .unwrap()
+            .get(blob_id)
+            .map(|v| v.clone() as Arc<dyn BlobCache>)
+    }
+
+    fn release_blob_cache(&self, blob_info: &BlobInfo) {
+        let mut guard = self.blobs.write().unwrap();
+        // Keep the entry if the kernel has reopened the cache object with a new cache file.
+        if guard.get(blob_info.blob_id()).map_or(false, |entry| {
+            !self.is_fscache || entry.is_same_fscache_file(blob_info)
+        }) {
+            guard.remove(blob_info.blob_id());
+            self.metrics
+                .underlying_files
+                .lock()
+                .unwrap()
+                .remove(blob_info.blob_id());
+        }
+    }
 }
 
 #[cfg(test)]
@@ -251,6 +392,33 @@ pub mod blob_cache_tests {
         assert!(blob_config.get_work_dir().is_err());
     }
 
+    #[test]
+    fn test_gc_unmapped_blobs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path();
+        let config = CacheConfig {
+            cache_type: "blobcache".to_string(),
+            cache_config: serde_json::json!({ "work_dir": work_dir }),
+            ..Default::default()
+        };
+        let backend = serde_json::json!({ "dir": work_dir });
+        let backend = Arc::new(crate::backend::localfs::LocalFs::new(backend, Some("gc")).unwrap());
+        let mgr = FileCacheMgr::new(config, backend, "gc").unwrap();
+
+        for name in ["blob1", "blob1.chunk_map", "blob2", "blob2.blob.meta"].iter() {
+            fs::write(work_dir.join(name), b"data").unwrap();
+        }
+        fs::create_dir(work_dir.join("blob3")).unwrap();
+
+        let in_use = vec!["blob2".to_string()].into_iter().collect();
+        mgr.gc(&in_use);
+        assert!(!work_dir.join("blob1").exists());
+        assert!(!work_dir.join("blob1.chunk_map").exists());
+        assert!(work_dir.join("blob2").exists());
+        assert!(work_dir.join("blob2.blob.meta").exists());
+        assert!(work_dir.join("blob3").exists());
+    }
+
     /*
        #[test]
        fn test_add() {
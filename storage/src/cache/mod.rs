@@ -16,28 +16,41 @@
 //!   `BlobCacheMgr`, simply reporting each chunk as cached or not cached according to
 //!   configuration.
 
+use std::borrow::Cow;
 use std::cmp;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::Result;
+use std::io::{Error, Result};
 use std::slice;
 use std::sync::Arc;
+use std::time::Duration;
 
 use fuse_backend_rs::transport::FileVolatileSlice;
 
+pub use buffer_pool::{buffer_pool_size, trim_buffer_pool};
 pub use dummycache::DummyCacheMgr;
 pub use filecache::FileCacheMgr;
 use nydus_utils::digest;
-
-use crate::backend::{BlobBackend, BlobReader};
-use crate::cache::state::ChunkMap;
+use nydus_utils::error::{ErrorContext, ResultExt};
+use nydus_utils::metrics::ErrorClass;
+use nydus_utils::tracing;
+
+use self::buffer_pool::PooledBuffer;
+use crate::backend::switch::SwitchableReader;
+use crate::backend::{BackendError, BlobBackend, BlobReader};
+use crate::cache::state::{ChunkMap, PrefetchProgress};
 use crate::device::{
     BlobChunkInfo, BlobInfo, BlobIoChunk, BlobIoDesc, BlobIoRange, BlobIoVec, BlobObject,
     BlobPrefetchRequest,
 };
 use crate::utils::{alloc_buf, digest_check};
-use crate::{compress, StorageResult, RAFS_MAX_CHUNK_SIZE};
+use crate::{compress, crypt, StorageResult, RAFS_MAX_CHUNK_SIZE};
 
+mod buffer_pool;
+mod decompress;
+mod direct_io;
 mod dummycache;
+mod fetch;
 mod filecache;
 pub mod state;
 mod worker;
@@ -45,6 +58,18 @@ mod worker;
 /// Timeout in milli-seconds to retrieve blob data from backend storage.
 pub const SINGLE_INFLIGHT_WAIT_TIMEOUT: u64 = 2000;
 
+// Interrupted requests fail with EINTR as FUSE expects, and other backend errors with EIO,
+// classified for error accounting.
+fn backend_io_error(e: BackendError) -> Error {
+    match e {
+        BackendError::Interrupted => Error::from_raw_os_error(libc::EINTR),
+        e => {
+            error!("failed to read from storage backend: {}", e);
+            e.error_class().error(&e)
+        }
+    }
+}
+
 struct BlobIoMergeState<'a, F: FnMut(BlobIoRange)> {
     cb: F,
     size: u32,
@@ -151,14 +176,40 @@ pub trait BlobCache: Send + Sync {
     /// Get the [BlobReader](../backend/trait.BlobReader.html) to read data from storage backend.
     fn reader(&self) -> &dyn BlobReader;
 
+    /// Get the reader whose storage backend can be switched at runtime, if supported.
+    fn switchable_reader(&self) -> Option<&SwitchableReader> {
+        None
+    }
+
     /// Get the underlying `ChunkMap` object.
     fn get_chunk_map(&self) -> &Arc<dyn ChunkMap>;
 
+    /// Get the persisted progress of prefetching the blob, if any.
+    fn prefetch_progress(&self) -> Option<&PrefetchProgress> {
+        None
+    }
+
     /// Get a `BlobObject` instance to directly access uncompressed blob file.
     fn get_blob_object(&self) -> Option<&dyn BlobObject> {
         None
     }
 
+    /// Fetch `size` bytes of uncompressed blob data at `offset` into the cache synchronously.
+    ///
+    /// Large ranges are fetched by pieces, so `size` isn't limited by memory. Return the amount
+    /// of data fetched from the storage backend.
+    fn fetch_range(&self, _offset: u64, _size: u64) -> Result<usize> {
+        Err(enosys!("doesn't support fetch_range()"))
+    }
+
+    /// Read `size` bytes of blob data at `offset` from the cache, as stored on the storage backend.
+    ///
+    /// Return `Ok(None)` if any chunk of the range isn't cached yet, or if the cache doesn't keep
+    /// blob data as stored on the storage backend.
+    fn read_cached_range(&self, _offset: u64, _size: u64) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
     /// Start to prefetch requested data in background.
     fn prefetch(
         &self,
@@ -170,6 +221,51 @@ pub trait BlobCache: Send + Sync {
     /// Stop prefetching blob data in background.
     fn stop_prefetch(&self) -> StorageResult<()>;
 
+    /// Write cached data and readiness state of the cache back to the storage.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get memory used by state of the cache, such as chunk readiness and blob metadata.
+    fn memory_usage(&self) -> u64 {
+        0
+    }
+
+    /// Release memory used by state of the cache which can be reloaded on demand.
+    fn shrink(&self) {}
+
+    /// Hold new accesses to the blob, including background prefetching, until [thaw()] is called,
+    /// and wait for accesses in progress to finish within `timeout`.
+    ///
+    /// Each successful call must be paired with a call to [thaw()].
+    ///
+    /// [thaw()]: BlobCache::thaw
+    fn freeze(&self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    /// Let accesses held by [freeze()](BlobCache::freeze) go on, once all freezers have thawed.
+    fn thaw(&self) {}
+
+    /// Check whether accesses to the blob are held by [freeze()](BlobCache::freeze).
+    fn is_frozen(&self) -> bool {
+        false
+    }
+
+    /// Verify digest of the chunk in the cache, or on the storage backend if `remote` is true.
+    ///
+    /// Return `Ok(false)` if the chunk is skipped, such as chunks not cached yet, and an error of
+    /// class `DigestMismatch` if the chunk is corrupted.
+    fn scrub_chunk(&self, chunk: &BlobIoChunk, remote: bool) -> Result<bool> {
+        if !remote || self.is_stargz() {
+            return Ok(false);
+        }
+        let mut buffer = alloc_buf(chunk.uncompress_size() as usize);
+        self.read_raw_chunk(chunk, &mut buffer, true, None)?;
+
+        Ok(true)
+    }
+
     /// Execute filesystem data prefetch.
     fn prefetch_range(&self, _range: &BlobIoRange) -> Result<usize> {
         Err(enosys!("doesn't support prefetch_range()"))
@@ -194,13 +290,18 @@ pub trait BlobCache: Send + Sync {
         chunks: &[BlobIoChunk],
     ) -> Result<Vec<Vec<u8>>> {
         // Read requested data from the backend by altogether.
-        let mut c_buf = alloc_buf(blob_size);
+        let mut c_buf = PooledBuffer::new(blob_size);
         let nr_read = self
             .reader()
-            .read(c_buf.as_mut_slice(), blob_offset)
-            .map_err(|e| eio!(e))?;
+            .read(&mut c_buf, blob_offset)
+            .map_err(backend_io_error)
+            .context(|| {
+                ErrorContext::default()
+                    .blob(self.blob_id())
+                    .request(format!("read {} bytes at {}", blob_size, blob_offset))
+            })?;
         if nr_read != blob_size {
-            return Err(eio!(format!(
+            return Err(ErrorClass::BackendServer.error(format!(
                 "request for {} bytes but got {} bytes",
                 blob_size, nr_read
             )));
@@ -219,16 +320,26 @@ pub trait BlobCache: Send + Sync {
                 || ((!self.is_stargz() && d_size as u64 > RAFS_MAX_CHUNK_SIZE)
                     || (self.is_stargz() && d_size > 4 << 20))
             {
-                return Err(eio!(format!("chunks to read_chunks() is invalid, offset {} last {} blob_offset {} d_size {}",
-                                        offset, last, blob_offset, d_size)));
+                return Err(ErrorClass::MetadataCorruption.error(format!(
+                    "chunks to read_chunks() is invalid, offset {} last {} blob_offset {} d_size {}",
+                    offset, last, blob_offset, d_size
+                )));
             }
 
             let offset_merged = (offset - blob_offset) as usize;
             let end_merged = offset_merged + size as usize;
-            let buf = &c_buf[offset_merged..end_merged];
+            let chunk_context = || {
+                ErrorContext::default()
+                    .blob(self.blob_id())
+                    .chunk(chunk.id())
+            };
+            let buf = self
+                .decrypt_raw_chunk(chunk.as_base(), &c_buf[offset_merged..end_merged])
+                .context(chunk_context)?;
             let mut buffer = alloc_buf(d_size);
 
-            self.process_raw_chunk(chunk, buf, None, &mut buffer, chunk.is_compressed(), false)?;
+            self.process_raw_chunk(chunk, &buf, None, &mut buffer, chunk.is_compressed(), false)
+                .context(chunk_context)?;
             buffers.push(buffer);
             last = offset + size as u64;
         }
@@ -251,8 +362,8 @@ pub trait BlobCache: Send + Sync {
     ) -> Result<usize> {
         let mut d;
         let offset = chunk.compress_offset();
-        let raw_chunk = if chunk.is_compressed() {
-            // Need a scratch buffer to decompress compressed data.
+        let raw_chunk = if chunk.is_compressed() || self.cipher().is_some() {
+            // Need a scratch buffer to decrypt or decompress data.
             let c_size = if self.is_stargz() {
                 let blob_size = self.blob_size()?;
                 let max_size = blob_size.checked_sub(offset).ok_or_else(|| {
@@ -263,26 +374,43 @@ pub trait BlobCache: Send + Sync {
             } else {
                 chunk.compress_size() as usize
             };
-            d = alloc_buf(c_size);
-            d.as_mut_slice()
+            d = PooledBuffer::new(c_size);
+            &mut d[..]
         } else {
             // We have this unsafe assignment as it can directly store data into call's buffer.
             unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr(), buffer.len()) }
         };
 
-        let size = self.reader().read(raw_chunk, offset).map_err(|e| eio!(e))?;
+        let chunk_context = || {
+            ErrorContext::default()
+                .blob(self.blob_id())
+                .chunk(chunk.id())
+        };
+        let size = self
+            .reader()
+            .read(raw_chunk, offset)
+            .map_err(backend_io_error)
+            .context(|| {
+                chunk_context().request(format!("read {} bytes at {}", raw_chunk.len(), offset))
+            })?;
         if size != raw_chunk.len() {
-            return Err(eio!("storage backend returns less data than requested"));
+            return Err(
+                ErrorClass::BackendServer.error("storage backend returns less data than requested")
+            );
         }
 
+        let decrypted = self
+            .decrypt_raw_chunk(chunk.as_base(), raw_chunk)
+            .context(chunk_context)?;
         self.process_raw_chunk(
             chunk.as_base(),
-            raw_chunk,
+            &decrypted,
             None,
             buffer,
             chunk.is_compressed(),
             force_validation,
-        )?;
+        )
+        .context(chunk_context)?;
         if let Some(hook) = raw_hook {
             hook(raw_chunk)
         }
@@ -290,6 +418,39 @@ pub trait BlobCache: Send + Sync {
         Ok(buffer.len())
     }
 
+    /// Get the cipher to decrypt chunk data, if chunk data of the blob is encrypted.
+    fn cipher(&self) -> Option<&crypt::Cipher> {
+        None
+    }
+
+    /// Decrypt chunk data received from storage backend, if chunk data of the blob is encrypted.
+    fn decrypt_raw_chunk<'a>(
+        &self,
+        chunk: &dyn BlobChunkInfo,
+        raw_buffer: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>> {
+        match self.cipher() {
+            None => Ok(Cow::Borrowed(raw_buffer)),
+            Some(cipher) => cipher
+                .decrypt(raw_buffer, &crypt::chunk_aad(self.blob_id(), chunk.id()))
+                .map(Cow::Owned)
+                .map_err(|e| {
+                    error!("failed to decrypt chunk {}: {}", chunk.id(), e);
+                    ErrorClass::Decrypt.error(e)
+                }),
+        }
+    }
+
+    /// Decompress chunk data from `raw_buffer` or `raw_stream` into `buffer`.
+    fn decompress(
+        &self,
+        raw_buffer: &[u8],
+        raw_stream: Option<File>,
+        buffer: &mut [u8],
+    ) -> Result<usize> {
+        compress::decompress(raw_buffer, raw_stream, buffer, self.compressor())
+    }
+
     /// Hook point to post-process data received from storage backend.
     ///
     /// This hook method provides a chance to transform data received from storage backend into
@@ -304,13 +465,17 @@ pub trait BlobCache: Send + Sync {
         force_validation: bool,
     ) -> Result<usize> {
         if need_decompress {
-            compress::decompress(raw_buffer, raw_stream, buffer, self.compressor()).map_err(
-                |e| {
+            let span = tracing::span("decompress");
+            span.set_attribute_i64("size", buffer.len() as i64);
+            self.decompress(raw_buffer, raw_stream, buffer)
+                .map_err(|e| {
                     error!("failed to decompress chunk: {}", e);
-                    e
-                },
-            )?;
+                    ErrorClass::Decompress.error(e)
+                })?;
         } else if raw_buffer.as_ptr() != buffer.as_ptr() {
+            if raw_buffer.len() != buffer.len() {
+                return Err(eio!("raw chunk size and buffer size doesn't match"));
+            }
             // raw_chunk and chunk may point to the same buffer, so only copy data when needed.
             buffer.copy_from_slice(raw_buffer);
         }
@@ -321,7 +486,9 @@ pub trait BlobCache: Send + Sync {
         } else if (self.need_validate() || force_validation)
             && !digest_check(buffer, chunk.chunk_id(), self.digester())
         {
-            Err(eio!())
+            error!("digest of chunk {} mismatches", chunk.id());
+            Err(ErrorClass::DigestMismatch
+                .error(format!("digest of chunk {} mismatches", chunk.id())))
         } else {
             Ok(d_size)
         }
@@ -339,14 +506,28 @@ pub trait BlobCacheMgr: Send + Sync {
     /// Tear down the blob cache manager.
     fn destroy(&self);
 
-    /// Garbage-collect unused resources.
-    fn gc(&self) {}
+    /// Get ids of blobs whose blob caches are in use.
+    fn blobs_in_use(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Garbage-collect unused resources, keeping cached data of blobs in `in_use`.
+    fn gc(&self, _in_use: &HashSet<String>) {}
 
     /// Get the underlying `BlobBackend` object of the blob cache object.
     fn backend(&self) -> &(dyn BlobBackend);
 
     /// Get the blob cache to provide access to the `blob` object.
     fn get_blob_cache(&self, blob_info: &Arc<BlobInfo>) -> Result<Arc<dyn BlobCache>>;
+
+    /// Find the blob cache created for the blob with id `blob_id`, if any.
+    fn find_blob_cache(&self, _blob_id: &str) -> Option<Arc<dyn BlobCache>> {
+        None
+    }
+
+    /// Release the blob cache created for `blob_info`, so resources it holds, such as the fscache
+    /// file, are freed once the blob cache isn't referenced anymore.
+    fn release_blob_cache(&self, _blob_info: &BlobInfo) {}
 }
 
 #[cfg(test)]
@@ -11,6 +11,8 @@ use std::io::Result;
 use std::sync::{Arc, Condvar, Mutex, WaitTimeoutResult};
 use std::time::Duration;
 
+use nydus_utils::metrics::ErrorClass;
+
 use crate::cache::state::{BlobRangeMap, ChunkIndexGetter, ChunkMap, IndexedChunkMap, RangeMap};
 use crate::cache::SINGLE_INFLIGHT_WAIT_TIMEOUT;
 use crate::device::BlobChunkInfo;
@@ -122,7 +124,7 @@ where
                     index,
                     chunk.compress_offset()
                 );
-                Err(eio!("timeout when read data from backend"))
+                Err(ErrorClass::Timeout.error("timeout when read data from backend"))
             } else {
                 self.check_ready_and_mark_pending(chunk)
             }
@@ -156,6 +158,18 @@ where
         self.c.is_persist()
     }
 
+    fn flush(&self) -> Result<()> {
+        self.c.flush()
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.c.memory_usage()
+    }
+
+    fn shrink(&self) {
+        self.c.shrink()
+    }
+
     fn as_range_map(&self) -> Option<&dyn RangeMap<I = u32>> {
         let any = self as &dyn Any;
 
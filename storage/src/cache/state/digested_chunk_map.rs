@@ -50,6 +50,11 @@ impl ChunkMap for DigestedChunkMap {
         self.cache.write().unwrap().insert(*chunk.chunk_id());
         Ok(())
     }
+
+    // The hash set is the only record of ready chunks, so it can't be released.
+    fn memory_usage(&self) -> u64 {
+        (self.cache.read().unwrap().capacity() * std::mem::size_of::<RafsDigest>()) as u64
+    }
 }
 
 impl ChunkIndexGetter for DigestedChunkMap {
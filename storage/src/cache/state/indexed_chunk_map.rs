@@ -8,6 +8,14 @@
 //! This module provides a chunk state tracking driver based on a bitmap file. There's a state bit
 //! in the bitmap file for each chunk, and atomic operations are used to manipulate the bitmap.
 //! So it supports concurrent downloading.
+//!
+//! The bitmap may also be mirrored into a file on a shared memory filesystem, such as `/dev/shm`,
+//! so external agents, e.g. P2P seeders, learn which chunks of a blob are available on this node
+//! by mapping the file instead of querying nydusd for each chunk. The mirror has the same layout
+//! as the chunk map file: a 4096-byte header, with the `all_ready` field at offset 12 set to
+//! `0x4D4D_4150` once all chunks are ready, followed by a bit per chunk, where chunk `i` is bit
+//! `7 - i % 8` of byte `4096 + i / 8`. Bits are only ever set while nydusd uses the blob.
+use std::fs;
 use std::io::Result;
 
 use crate::cache::state::persist_map::PersistMap;
@@ -30,6 +38,8 @@ const FILE_SUFFIX: &str = "chunk_map";
 /// set_ready(3), the layout should be changed to [0b00010000, 0b00000000].
 pub struct IndexedChunkMap {
     map: PersistMap,
+    // Mirror of the bitmap in shared memory for external observers, with its file path.
+    shared: Option<(PersistMap, String)>,
 }
 
 impl IndexedChunkMap {
@@ -37,7 +47,46 @@ impl IndexedChunkMap {
     pub fn new(blob_path: &str, chunk_count: u32) -> Result<Self> {
         let filename = format!("{}.{}", blob_path, FILE_SUFFIX);
 
-        PersistMap::open(&filename, chunk_count, true).map(|map| IndexedChunkMap { map })
+        PersistMap::open(&filename, chunk_count, true)
+            .map(|map| IndexedChunkMap { map, shared: None })
+    }
+
+    /// Mirror the bitmap into the file `$shm_dir/$blob_id.chunk_map` for external observers.
+    ///
+    /// The mirror file is recreated from the current bitmap, so it doesn't report chunks which
+    /// have been evicted since last use, and it's removed when the chunk map is dropped.
+    pub fn share(&mut self, shm_dir: &str, blob_id: &str) -> Result<()> {
+        // The blob id comes from the image, don't let it escape from `shm_dir`.
+        if blob_id.is_empty() || blob_id.contains('/') || blob_id.contains("..") {
+            return Err(einval!(format!(
+                "invalid blob id {:?} for shared chunk map",
+                blob_id
+            )));
+        }
+        let filename = format!("{}/{}.{}", shm_dir, blob_id, FILE_SUFFIX);
+        match fs::remove_file(&filename) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        let shared = PersistMap::open(&filename, self.map.count, true)?;
+        for index in 0..self.map.count {
+            if self.map.is_range_all_ready() || self.map.is_chunk_ready(index).0 {
+                shared.set_chunk_ready(index)?;
+            }
+        }
+        self.shared = Some((shared, filename));
+
+        Ok(())
+    }
+
+    fn set_chunk_ready(&self, index: u32) -> Result<()> {
+        self.map.set_chunk_ready(index)?;
+        if let Some((shared, _)) = self.shared.as_ref() {
+            shared.set_chunk_ready(index)?;
+        }
+
+        Ok(())
     }
 
     /// Create a new instance of `IndexedChunkMap` from an existing chunk map file.
@@ -45,7 +94,18 @@ impl IndexedChunkMap {
         let filename = format!("{}/{}.{}", workdir, blob_info.blob_id(), FILE_SUFFIX);
 
         PersistMap::open(&filename, blob_info.chunk_count(), false)
-            .map(|map| IndexedChunkMap { map })
+            .map(|map| IndexedChunkMap { map, shared: None })
+    }
+}
+
+impl Drop for IndexedChunkMap {
+    fn drop(&mut self) {
+        if let Some((shared, filename)) = self.shared.take() {
+            drop(shared);
+            if let Err(e) = fs::remove_file(&filename) {
+                warn!("failed to remove shared chunk map {}: {}", filename, e);
+            }
+        }
     }
 }
 
@@ -60,16 +120,31 @@ impl ChunkMap for IndexedChunkMap {
     }
 
     fn set_ready_and_clear_pending(&self, chunk: &dyn BlobChunkInfo) -> Result<()> {
-        self.map.set_chunk_ready(chunk.id())
+        self.set_chunk_ready(chunk.id())
     }
 
     fn is_persist(&self) -> bool {
         true
     }
 
+    fn flush(&self) -> Result<()> {
+        self.map.flush()
+    }
+
     fn as_range_map(&self) -> Option<&dyn RangeMap<I = u32>> {
         Some(self)
     }
+
+    fn memory_usage(&self) -> u64 {
+        self.map.memory_usage() + self.shared.as_ref().map_or(0, |(s, _)| s.memory_usage())
+    }
+
+    fn shrink(&self) {
+        self.map.release();
+        if let Some((shared, _)) = self.shared.as_ref() {
+            shared.release();
+        }
+    }
 }
 
 impl RangeMap for IndexedChunkMap {
@@ -126,7 +201,7 @@ impl RangeMap for IndexedChunkMap {
         let end = start_index + count;
 
         for index in start_index..end {
-            self.map.set_chunk_ready(index)?;
+            self.set_chunk_ready(index)?;
         }
 
         Ok(())
@@ -251,6 +326,9 @@ mod tests {
         assert_eq!(map.is_ready(chunk.as_base()).unwrap(), false);
         map.set_ready_and_clear_pending(chunk.as_base()).unwrap();
         assert_eq!(map.is_ready(chunk.as_base()).unwrap(), true);
+
+        map.flush().unwrap();
+        assert_eq!(std::fs::read(&cache_path).unwrap()[0x1000] & 0x80, 0x80);
     }
 
     #[test]
@@ -343,4 +421,41 @@ mod tests {
         map.set_ready_and_clear_pending(chunk.as_base()).unwrap();
         assert_eq!(map.is_ready(chunk.as_base()).unwrap(), true);
     }
+
+    #[test]
+    fn test_indexed_shared_map() {
+        let dir = TempDir::new().unwrap();
+        let shm_dir = TempDir::new().unwrap();
+        let shm_path = shm_dir.as_path().to_str().unwrap();
+        let blob_path = dir.as_path().join("blob-1");
+        let blob_path = blob_path.as_os_str().to_str().unwrap().to_string();
+        let shared_path = shm_dir.as_path().join("blob-1.chunk_map");
+
+        let mut chunk = MockChunkInfo::new();
+        let map = IndexedChunkMap::new(&blob_path, 10).unwrap();
+        chunk.index = 9;
+        map.set_ready_and_clear_pending(chunk.as_base()).unwrap();
+        drop(map);
+
+        // Chunks ready before sharing are reported, stale content is discarded.
+        std::fs::write(&shared_path, b"stale").unwrap();
+        let mut map = IndexedChunkMap::new(&blob_path, 10).unwrap();
+        assert!(map.share(shm_path, "../blob-1").is_err());
+        assert!(map.share(shm_path, "dir/blob-1").is_err());
+        assert!(map.share(shm_path, "..").is_err());
+        map.share(shm_path, "blob-1").unwrap();
+        chunk.index = 2;
+        map.set_ready_and_clear_pending(chunk.as_base()).unwrap();
+        map.set_range_ready_and_clear_pending(4, 2).unwrap();
+
+        let content = std::fs::read(&shared_path).unwrap();
+        assert_eq!(content.len(), HEADER_SIZE + 2);
+        assert_eq!(&content[0..4], &MAGIC1.to_ne_bytes());
+        assert_eq!(&content[12..16], &[0u8; 4]);
+        assert_eq!(content[HEADER_SIZE], 0b0010_1100);
+        assert_eq!(content[HEADER_SIZE + 1], 0b0100_0000);
+
+        drop(map);
+        assert!(!shared_path.exists());
+    }
 }
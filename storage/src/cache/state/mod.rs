@@ -31,6 +31,9 @@
 //! - [NoopChunkMap](struct.NoopChunkMap.html): a no-operation chunk state tracking driver,
 //!   which just reports every chunk as always ready to use or not. It may be used to support disk
 //!   based backend storage or dummy cache.
+//!
+//! Besides readiness state, [PrefetchProgress](struct.PrefetchProgress.html) persists ranges of
+//! blobs which have been prefetched, so prefetching resumes after restarts.
 
 use std::any::Any;
 use std::io::Result;
@@ -41,6 +44,7 @@ pub use blob_state_map::BlobStateMap;
 pub use digested_chunk_map::DigestedChunkMap;
 pub use indexed_chunk_map::IndexedChunkMap;
 pub use noop_chunk_map::NoopChunkMap;
+pub use prefetch_progress::PrefetchProgress;
 pub use range_map::BlobRangeMap;
 
 mod blob_state_map;
@@ -48,6 +52,7 @@ mod digested_chunk_map;
 mod indexed_chunk_map;
 mod noop_chunk_map;
 mod persist_map;
+mod prefetch_progress;
 mod range_map;
 
 /// Trait to track chunk readiness state.
@@ -81,10 +86,23 @@ pub trait ChunkMap: Any + Send + Sync {
         false
     }
 
+    /// Write persisted readiness state back to the storage.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
     /// Convert the objet to an [RangeMap](trait.RangeMap.html) object.
     fn as_range_map(&self) -> Option<&dyn RangeMap<I = u32>> {
         None
     }
+
+    /// Get memory used by the readiness state, in unit of Byte.
+    fn memory_usage(&self) -> u64 {
+        0
+    }
+
+    /// Release memory used by the readiness state if it's persisted, to be reloaded on demand.
+    fn shrink(&self) {}
 }
 
 /// Trait to track chunk or data readiness state.
@@ -11,7 +11,7 @@ use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
 
 use nydus_utils::div_round_up;
 
-use crate::utils::readahead;
+use crate::utils::{readahead, release_pages, resident_size};
 
 pub(crate) const MAGIC1: u32 = 0x424D_4150;
 pub(crate) const MAGIC2: u32 = 0x434D_4150;
@@ -264,12 +264,35 @@ impl PersistMap {
         }
     }
 
+    /// Write the bitmap back to the file, so readiness state survives crashes of the host.
+    pub fn flush(&self) -> Result<()> {
+        let base = self.base as *const c_void as *mut c_void;
+        if unsafe { libc::msync(base, self.size, libc::MS_SYNC) } != 0 {
+            return Err(last_error!("failed to sync chunk map"));
+        }
+
+        Ok(())
+    }
+
     #[inline]
     pub fn is_range_all_ready(&self) -> bool {
         self.not_ready_count.load(Ordering::Acquire) == 0
     }
 }
 
+impl PersistMap {
+    /// Get size of resident pages of the bitmap mapping.
+    pub fn memory_usage(&self) -> u64 {
+        resident_size(self.base, self.size)
+    }
+
+    /// Drop resident pages of the bitmap mapping, which is a shared file mapping, so the state is
+    /// kept by the file and loaded again on next access.
+    pub fn release(&self) {
+        release_pages(self.base, self.size)
+    }
+}
+
 impl Drop for PersistMap {
     fn drop(&mut self) {
         if !self.base.is_null() {
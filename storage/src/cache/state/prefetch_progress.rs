@@ -0,0 +1,255 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Persisted progress of prefetching blob data.
+//!
+//! Prefetch requests are issued again from the start after nydusd gets restarted or upgraded,
+//! each consuming bandwidth of prefetching before the blob cache finds the data
+//! ready, so prefetching of big images may take long to catch up or never finish. The ranges of a
+//! blob which have been prefetched are recorded in the file `$blob_id.prefetch`, so requests
+//! covered by those ranges are skipped and prefetching resumes where it stopped.
+//!
+//! The progress is only a hint to skip prefetch requests, readiness of data is still tracked by
+//! the chunk map, so data lost from the cache is fetched on demand.
+
+use std::fs;
+use std::io::{Result, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The name suffix of prefetch progress files, named $blob_id.prefetch.
+const FILE_SUFFIX: &str = "prefetch";
+/// Minimum interval between saving the progress to the file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct ProgressState {
+    // Sorted and non-adjacent ranges of `[start, end)` which have been prefetched.
+    ranges: Vec<(u64, u64)>,
+    // Bumped on each change of `ranges`, to tell whether the saved snapshot is up to date.
+    version: u64,
+    saved_version: u64,
+    saved_at: Option<Instant>,
+}
+
+impl ProgressState {
+    fn insert(&mut self, start: u64, end: u64) {
+        // Merge with all ranges overlapping or adjacent to the new one.
+        let first = self.ranges.partition_point(|r| r.1 < start);
+        let last = self.ranges.partition_point(|r| r.0 <= end);
+        let (mut start, mut end) = (start, end);
+        if first < last {
+            start = std::cmp::min(start, self.ranges[first].0);
+            end = std::cmp::max(end, self.ranges[last - 1].1);
+        }
+        self.ranges
+            .splice(first..last, std::iter::once((start, end)));
+        self.version += 1;
+    }
+
+    fn contains(&self, start: u64, end: u64) -> bool {
+        let idx = self.ranges.partition_point(|r| r.1 < end);
+        idx < self.ranges.len() && self.ranges[idx].0 <= start && self.ranges[idx].1 >= end
+    }
+}
+
+/// Ranges of a blob which have been prefetched, persisted to survive restarts of nydusd.
+pub struct PrefetchProgress {
+    path: PathBuf,
+    state: Mutex<ProgressState>,
+    // Serializes writers of the file, so an older snapshot never replaces a newer one.
+    saving: Mutex<()>,
+}
+
+impl PrefetchProgress {
+    /// Open the prefetch progress of the blob cache file `blob_path`.
+    ///
+    /// Progress files which can't be parsed are ignored, so prefetching restarts from the start.
+    pub fn open(blob_path: &str) -> Self {
+        let path = PathBuf::from(format!("{}.{}", blob_path, FILE_SUFFIX));
+        let mut state = ProgressState::default();
+        if let Ok(data) = fs::read(&path) {
+            match serde_json::from_slice::<Vec<(u64, u64)>>(&data) {
+                Ok(ranges) => {
+                    for (start, end) in ranges.into_iter().filter(|(s, e)| s < e) {
+                        state.insert(start, end);
+                    }
+                    state.saved_version = state.version;
+                }
+                Err(e) => warn!("ignore invalid prefetch progress {:?}, {}", path, e),
+            }
+        }
+
+        PrefetchProgress {
+            path,
+            state: Mutex::new(state),
+            saving: Mutex::new(()),
+        }
+    }
+
+    /// Check whether `size` bytes of blob data at `offset` have been prefetched.
+    pub fn is_completed(&self, offset: u64, size: u64) -> bool {
+        match offset.checked_add(size) {
+            Some(end) if size > 0 => self.state.lock().unwrap().contains(offset, end),
+            _ => false,
+        }
+    }
+
+    /// Record that `size` bytes of blob data at `offset` have been prefetched.
+    pub fn complete(&self, offset: u64, size: u64) {
+        let end = match offset.checked_add(size) {
+            Some(end) if size > 0 => end,
+            _ => return,
+        };
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state.insert(offset, end);
+            state
+                .saved_at
+                .map_or(true, |t| t.elapsed() >= SAVE_INTERVAL)
+        };
+        // Don't stall prefetch workers behind another one which is saving the progress.
+        if due {
+            if let Err(e) = self.save(false) {
+                warn!("failed to save prefetch progress {:?}, {}", self.path, e);
+            }
+        }
+    }
+
+    /// Get the amount of blob data in bytes which has been prefetched.
+    pub fn completed_size(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.ranges.iter().map(|(s, e)| e - s).sum()
+    }
+
+    /// Save the progress to the file if it has been changed.
+    pub fn flush(&self) -> Result<()> {
+        self.save(true)
+    }
+
+    // Write a snapshot of the ranges to the file, without holding the state lock across IO.
+    // Skip saving if another thread is doing it and `wait` is false.
+    fn save(&self, wait: bool) -> Result<()> {
+        let _guard = if wait {
+            self.saving.lock().unwrap()
+        } else {
+            match self.saving.try_lock() {
+                Ok(guard) => guard,
+                Err(_) => return Ok(()),
+            }
+        };
+        let (data, version) = {
+            let mut state = self.state.lock().unwrap();
+            state.saved_at = Some(Instant::now());
+            if state.version == state.saved_version {
+                return Ok(());
+            }
+            let data = serde_json::to_vec(&state.ranges).map_err(|e| eother!(e))?;
+            (data, state.version)
+        };
+
+        // Replace the file atomically, so a crash never leaves a partially written file behind.
+        // The temporary file is named uniquely, in case the blob is cached by several entries.
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp = PathBuf::from(tmp);
+        let res = (|| {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp)?;
+            file.write_all(&data)?;
+            file.sync_data()?;
+            fs::rename(&tmp, &self.path)
+        })();
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+            return res;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.saved_version = std::cmp::max(state.saved_version, version);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_prefetch_progress() {
+        let dir = TempDir::new().unwrap();
+        let blob_path = dir.as_path().join("blob-1");
+        let blob_path = blob_path.to_str().unwrap();
+
+        let progress = PrefetchProgress::open(blob_path);
+        assert!(!progress.is_completed(0, 0x1000));
+        progress.complete(0x1000, 0x1000);
+        progress.complete(0x4000, 0x1000);
+        assert!(progress.is_completed(0x1000, 0x1000));
+        assert!(progress.is_completed(0x1800, 0x800));
+        assert!(!progress.is_completed(0x1000, 0x2000));
+        assert!(!progress.is_completed(0x1000, 0));
+
+        // Adjacent and overlapping ranges are merged.
+        progress.complete(0x2000, 0x2000);
+        assert!(progress.is_completed(0x1000, 0x4000));
+        progress.complete(0, 0x2000);
+        assert!(progress.is_completed(0, 0x5000));
+        assert_eq!(progress.completed_size(), 0x5000);
+        progress.complete(0x8000, 0x1000);
+        assert!(!progress.is_completed(0x4000, 0x5000));
+        progress.flush().unwrap();
+
+        let progress = PrefetchProgress::open(blob_path);
+        assert!(progress.is_completed(0, 0x5000));
+        assert!(progress.is_completed(0x8000, 0x1000));
+        assert_eq!(progress.completed_size(), 0x6000);
+
+        fs::write(format!("{}.{}", blob_path, FILE_SUFFIX), b"garbage").unwrap();
+        let progress = PrefetchProgress::open(blob_path);
+        assert_eq!(progress.completed_size(), 0);
+    }
+
+    #[test]
+    fn test_prefetch_progress_concurrent() {
+        let dir = TempDir::new().unwrap();
+        let blob_path = dir.as_path().join("blob-1");
+        let blob_path = blob_path.to_str().unwrap().to_string();
+
+        // Several entries of the same blob save their progress concurrently.
+        let handles: Vec<_> = (0..4u64)
+            .map(|i| {
+                let blob_path = blob_path.clone();
+                std::thread::spawn(move || {
+                    let progress = PrefetchProgress::open(&blob_path);
+                    for j in 0..64u64 {
+                        progress.complete((j * 4 + i % 2) * 0x1000, 0x1000);
+                        progress.flush().unwrap();
+                    }
+                    progress
+                })
+            })
+            .collect();
+        for handle in handles {
+            let progress = handle.join().unwrap();
+            assert_eq!(progress.completed_size(), 64 * 0x1000);
+        }
+
+        let progress = PrefetchProgress::open(&blob_path);
+        assert_eq!(progress.completed_size(), 64 * 0x1000);
+        let entries: Vec<_> = fs::read_dir(dir.as_path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+}
@@ -156,6 +156,7 @@ impl AsyncWorkerMgr {
             let res = thread::Builder::new()
                 .name(format!("blob_async_thread_{}", num))
                 .spawn(move || {
+                    crate::backend::set_prefetch_thread();
                     mgr2.grow_n(1);
                     mgr2.metrics
                         .prefetch_workers
@@ -192,7 +193,10 @@ impl AsyncWorkerMgr {
     /// Send an asynchronous service request message to the workers.
     pub fn send(&self, msg: AsyncRequestMessage) -> Result<()> {
         match &msg {
-            AsyncRequestMessage::FsPrefetch(_, _, req) => {
+            AsyncRequestMessage::FsPrefetch(_, cache, req) => {
+                if self.skip_prefetched(cache, req.blob_offset, req.blob_size) {
+                    return Ok(());
+                }
                 if let Some(ref limiter) = self.prefetch_limiter {
                     let size = std::cmp::min(req.blob_size, u32::MAX as u64) as u32;
                     let cells = match NonZeroU32::new(size) {
@@ -209,7 +213,10 @@ impl AsyncWorkerMgr {
                     }
                 }
             }
-            AsyncRequestMessage::BlobPrefetch(_, _, _, size) => {
+            AsyncRequestMessage::BlobPrefetch(_, cache, offset, size) => {
+                if self.skip_prefetched(cache, *offset, *size) {
+                    return Ok(());
+                }
                 if let Some(ref limiter) = self.prefetch_limiter {
                     let size = std::cmp::min(*size, u32::MAX as u64) as u32;
                     let cells = match NonZeroU32::new(size) {
@@ -236,6 +243,18 @@ impl AsyncWorkerMgr {
         })
     }
 
+    // Skip data which has been prefetched before, possibly by a previous instance of nydusd, so it
+    // doesn't consume bandwidth of prefetching again.
+    fn skip_prefetched(&self, cache: &Arc<dyn BlobCache>, offset: u64, size: u64) -> bool {
+        let prefetched = cache
+            .prefetch_progress()
+            .map_or(false, |p| p.is_completed(offset, size));
+        if prefetched {
+            self.metrics.prefetch_skipped_amount.add(size);
+        }
+        prefetched
+    }
+
     /// Consume network bandwidth budget for prefetching.
     pub fn consume_prefetch_budget(&self, buffers: &[FileVolatileSlice]) {
         if self.busy_workers.load(Ordering::Relaxed) > 0 {
@@ -295,14 +314,19 @@ impl AsyncWorkerMgr {
         }
 
         if let Some(obj) = cache.get_blob_object() {
-            if let Err(e) = obj.fetch_range_compressed(offset, size) {
-                warn!(
+            match obj.fetch_range_compressed(offset, size) {
+                Ok(_) => {
+                    if let Some(progress) = cache.prefetch_progress() {
+                        progress.complete(offset, size);
+                    }
+                }
+                Err(e) => warn!(
                     "Failed to prefetch data from blob {}, offset {}, size {}, {}",
                     cache.blob_id(),
                     offset,
                     size,
                     e
-                );
+                ),
             }
         } else if offset < u32::MAX as u64 && size < u32::MAX as u64 {
             let _ = cache.reader().prefetch_blob_data_range(
@@ -341,6 +365,18 @@ impl AsyncWorkerMgr {
             obj.fetch_chunks(req)?;
         } else {
             cache.prefetch_range(req)?;
+            // Chunks failed to be fetched are skipped by `prefetch_range()`.
+            let chunk_map = cache.get_chunk_map();
+            if !req
+                .chunks
+                .iter()
+                .all(|c| chunk_map.is_ready(c.as_base()).unwrap_or(false))
+            {
+                return Ok(());
+            }
+        }
+        if let Some(progress) = cache.prefetch_progress() {
+            progress.complete(blob_offset, blob_size);
         }
 
         Ok(())
@@ -0,0 +1,546 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encryption of chunk data in blobs.
+//!
+//! Data chunks are encrypted one by one after compression, each with a random IV, so chunks may be
+//! decrypted independently. An encrypted chunk is laid out as `[IV], [ciphertext], [tag]`. Only
+//! AEAD ciphers are supported, and the id of the blob and the index of the chunk are authenticated
+//! as additional data, so chunks can't be modified, swapped or moved to other blobs undetected.
+//! Blobs record the cipher and the id of the key used to encrypt their chunks, and keys are looked
+//! up by the id when accessing blobs.
+//!
+//! Keys are resolved by a pluggable key provider, which reads them from files, environment
+//! variables or an external KMS agent, so keys never live in configuration of the daemon.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+use std::fs;
+use std::io::{BufRead, BufReader, Error, Result, Write};
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use openssl::symm;
+
+use crate::device::BlobInfo;
+
+/// Maximum size of key ids recorded in metadata blobs.
+pub const MAX_KEY_ID_SIZE: usize = 32;
+
+const AES_256_KEY_SIZE: usize = 32;
+const AES_GCM_IV_SIZE: usize = 12;
+const AES_GCM_TAG_SIZE: usize = 16;
+
+/// Algorithms to encrypt chunk data.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Algorithm {
+    None = 0,
+    Aes256Gcm = 1,
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "aes256-gcm" => Ok(Self::Aes256Gcm),
+            _ => Err(einval!("cipher algorithm should be none or aes256-gcm")),
+        }
+    }
+}
+
+impl TryFrom<u32> for Algorithm {
+    type Error = ();
+
+    fn try_from(value: u32) -> std::result::Result<Self, Self::Error> {
+        if value == Algorithm::None as u32 {
+            Ok(Algorithm::None)
+        } else if value == Algorithm::Aes256Gcm as u32 {
+            Ok(Algorithm::Aes256Gcm)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl Algorithm {
+    pub fn is_none(self) -> bool {
+        self == Self::None
+    }
+
+    /// Get size of keys for the algorithm.
+    pub fn key_size(self) -> usize {
+        match self {
+            Algorithm::None => 0,
+            Algorithm::Aes256Gcm => AES_256_KEY_SIZE,
+        }
+    }
+
+    /// Get number of bytes added to each encrypted chunk.
+    pub fn overhead(self) -> usize {
+        self.iv_size() + self.tag_size()
+    }
+
+    fn iv_size(self) -> usize {
+        match self {
+            Algorithm::None => 0,
+            Algorithm::Aes256Gcm => AES_GCM_IV_SIZE,
+        }
+    }
+
+    fn tag_size(self) -> usize {
+        match self {
+            Algorithm::None => 0,
+            Algorithm::Aes256Gcm => AES_GCM_TAG_SIZE,
+        }
+    }
+}
+
+/// Get the additional data authenticated with chunk `chunk_index` of blob `blob_id`.
+pub fn chunk_aad(blob_id: &str, chunk_index: u32) -> Vec<u8> {
+    let mut aad = chunk_index.to_le_bytes().to_vec();
+    aad.extend_from_slice(blob_id.as_bytes());
+    aad
+}
+
+/// A cipher with key to encrypt or decrypt chunk data.
+pub struct Cipher {
+    algorithm: Algorithm,
+    key: Vec<u8>,
+}
+
+impl Cipher {
+    /// Create a cipher of `algorithm` with `key`.
+    pub fn new(algorithm: Algorithm, key: &[u8]) -> Result<Self> {
+        if algorithm.is_none() {
+            return Err(einval!("no cipher algorithm to create cipher"));
+        } else if key.len() != algorithm.key_size() {
+            return Err(einval!(format!(
+                "cipher {} requires {} bytes key, got {} bytes",
+                algorithm,
+                algorithm.key_size(),
+                key.len()
+            )));
+        }
+
+        Ok(Cipher {
+            algorithm,
+            key: key.to_vec(),
+        })
+    }
+
+    /// Get the cipher algorithm.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Encrypt a chunk with a random IV, authenticating `aad` from `chunk_aad()` along with it.
+    pub fn encrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut iv = vec![0u8; self.algorithm.iv_size()];
+        openssl::rand::rand_bytes(&mut iv)
+            .map_err(|e| eother!(format!("failed to generate IV, {}", e)))?;
+
+        let mut tag = vec![0u8; self.algorithm.tag_size()];
+        let encrypted = symm::encrypt_aead(
+            symm::Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&iv),
+            aad,
+            data,
+            &mut tag,
+        )
+        .map_err(|e| eother!(format!("failed to encrypt chunk, {}", e)))?;
+
+        let mut output = iv;
+        output.extend_from_slice(&encrypted);
+        output.extend_from_slice(&tag);
+
+        Ok(output)
+    }
+
+    /// Decrypt a chunk encrypted by `encrypt()` with the same `aad`.
+    pub fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let iv_size = self.algorithm.iv_size();
+        let tag_size = self.algorithm.tag_size();
+        if data.len() < iv_size + tag_size {
+            return Err(einval!(format!(
+                "encrypted chunk size {} is too small",
+                data.len()
+            )));
+        }
+        let (iv, rest) = data.split_at(iv_size);
+        let (encrypted, tag) = rest.split_at(rest.len() - tag_size);
+
+        symm::decrypt_aead(
+            symm::Cipher::aes_256_gcm(),
+            &self.key,
+            Some(iv),
+            aad,
+            encrypted,
+            tag,
+        )
+        .map_err(|e| eio!(format!("failed to decrypt chunk, {}", e)))
+    }
+}
+
+// Never print keys.
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Cipher({})", self.algorithm)
+    }
+}
+
+/// Provider of keys to decrypt blobs, which resolves key ids recorded in blob metadata to keys.
+pub trait KeyProvider {
+    /// Get the raw key identified by `key_id`.
+    fn get_key(&self, key_id: &str) -> Result<Vec<u8>>;
+}
+
+/// Key provider reading raw keys from files.
+pub struct FileKeyProvider {
+    key_files: HashMap<String, String>,
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn get_key(&self, key_id: &str) -> Result<Vec<u8>> {
+        let path = self
+            .key_files
+            .get(key_id)
+            .ok_or_else(|| enoent!(format!("no key file configured for key id {}", key_id)))?;
+
+        fs::read(path).map_err(|e| einval!(format!("failed to read key file {}, {}", path, e)))
+    }
+}
+
+/// Key provider reading hex encoded keys from environment variables.
+///
+/// The variable of a key is named by the prefix followed by the key id, in upper case and with
+/// characters other than ASCII letters and digits replaced by `_`.
+pub struct EnvKeyProvider {
+    prefix: String,
+}
+
+impl EnvKeyProvider {
+    fn env_name(&self, key_id: &str) -> String {
+        let id: String = key_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("{}{}", self.prefix, id)
+    }
+}
+
+impl KeyProvider for EnvKeyProvider {
+    fn get_key(&self, key_id: &str) -> Result<Vec<u8>> {
+        let name = self.env_name(key_id);
+        let value = std::env::var(&name)
+            .map_err(|e| enoent!(format!("failed to get key from env {}, {}", name, e)))?;
+
+        decode_hex(value.trim())
+    }
+}
+
+#[derive(Serialize)]
+struct KmsRequest<'a> {
+    op: &'a str,
+    key_id: &'a str,
+}
+
+#[derive(Deserialize)]
+struct KmsResponse {
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Key provider requesting keys from an external KMS agent listening on a unix socket.
+///
+/// Each request is a line of JSON `{"op": "get_key", "key_id": "<id>"}`, and the agent replies a
+/// line of JSON, either `{"key": "<hex encoded key>"}` or `{"error": "<message>"}`.
+pub struct KmsKeyProvider {
+    socket: String,
+    timeout: Duration,
+}
+
+impl KeyProvider for KmsKeyProvider {
+    fn get_key(&self, key_id: &str) -> Result<Vec<u8>> {
+        let kms_err = |e: Error| eother!(format!("failed to get key {} from KMS, {}", key_id, e));
+        let stream = UnixStream::connect(&self.socket).map_err(kms_err)?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .map_err(kms_err)?;
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(kms_err)?;
+
+        let mut request = serde_json::to_vec(&KmsRequest {
+            op: "get_key",
+            key_id,
+        })
+        .map_err(|e| kms_err(einval!(e)))?;
+        request.push(b'\n');
+        (&stream).write_all(&request).map_err(kms_err)?;
+
+        let mut line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut line)
+            .map_err(kms_err)?;
+        let resp: KmsResponse = serde_json::from_str(&line)
+            .map_err(|e| kms_err(einval!(format!("invalid response, {}", e))))?;
+        match (resp.key, resp.error) {
+            (_, Some(msg)) => Err(kms_err(eother!(msg))),
+            (Some(key), None) => decode_hex(&key),
+            (None, None) => Err(kms_err(einval!("no key in response"))),
+        }
+    }
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>> {
+    if value.len() % 2 != 0 || !value.is_ascii() {
+        return Err(einval!("invalid hex encoded key"));
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| einval!("invalid hex encoded key"))
+        })
+        .collect()
+}
+
+fn default_key_provider() -> String {
+    "file".to_string()
+}
+
+fn default_kms_timeout() -> u64 {
+    5
+}
+
+fn default_key_env_prefix() -> String {
+    "NYDUS_KEY_".to_string()
+}
+
+/// Configuration information for keys to decrypt blobs.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CipherConfig {
+    /// Provider of keys: `file`, `env` or `kms`.
+    #[serde(default = "default_key_provider")]
+    pub key_provider: String,
+    /// Paths of files containing raw keys indexed by key ids, for the `file` provider.
+    #[serde(default)]
+    pub key_files: HashMap<String, String>,
+    /// Prefix of environment variables containing hex encoded keys, for the `env` provider.
+    #[serde(default = "default_key_env_prefix")]
+    pub key_env_prefix: String,
+    /// Path of the unix socket of the KMS agent, for the `kms` provider.
+    #[serde(default)]
+    pub kms_socket: String,
+    /// Timeout in seconds of requests to the KMS agent.
+    #[serde(default = "default_kms_timeout")]
+    pub kms_timeout: u64,
+}
+
+impl Default for CipherConfig {
+    fn default() -> Self {
+        CipherConfig {
+            key_provider: default_key_provider(),
+            key_files: HashMap::new(),
+            key_env_prefix: default_key_env_prefix(),
+            kms_socket: String::new(),
+            kms_timeout: default_kms_timeout(),
+        }
+    }
+}
+
+impl CipherConfig {
+    /// Create the key provider configured.
+    pub fn key_provider(&self) -> Result<Box<dyn KeyProvider>> {
+        match self.key_provider.as_str() {
+            "file" => Ok(Box::new(FileKeyProvider {
+                key_files: self.key_files.clone(),
+            })),
+            "env" => Ok(Box::new(EnvKeyProvider {
+                prefix: self.key_env_prefix.clone(),
+            })),
+            "kms" => {
+                if self.kms_socket.is_empty() {
+                    return Err(einval!("kms_socket is required by the kms key provider"));
+                }
+                Ok(Box::new(KmsKeyProvider {
+                    socket: self.kms_socket.clone(),
+                    timeout: Duration::from_secs(self.kms_timeout),
+                }))
+            }
+            p => Err(einval!(format!(
+                "key provider should be file, env or kms, got {}",
+                p
+            ))),
+        }
+    }
+
+    /// Create a cipher of `algorithm` with the key identified by `key_id`.
+    pub fn new_cipher(&self, algorithm: Algorithm, key_id: &str) -> Result<Arc<Cipher>> {
+        let key = self.key_provider()?.get_key(key_id)?;
+
+        Cipher::new(algorithm, &key).map(Arc::new)
+    }
+
+    /// Create a cipher to decrypt chunk data of the blob, `None` if the blob isn't encrypted.
+    pub fn blob_cipher(&self, blob_info: &BlobInfo) -> Result<Option<Arc<Cipher>>> {
+        if blob_info.cipher().is_none() {
+            return Ok(None);
+        }
+
+        self.new_cipher(blob_info.cipher(), blob_info.cipher_key_id())
+            .map(Some)
+            .map_err(|e| {
+                einval!(format!(
+                    "failed to get key of encrypted blob {}, {}",
+                    blob_info.blob_id(),
+                    e
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_cipher_algorithm() {
+        assert_eq!(
+            Algorithm::from_str("aes256-gcm").unwrap(),
+            Algorithm::Aes256Gcm
+        );
+        assert!(Algorithm::from_str("aes128-gcm").is_err());
+        assert!(Algorithm::from_str("aes256-ctr").is_err());
+        assert_eq!(Algorithm::try_from(1), Ok(Algorithm::Aes256Gcm));
+        assert!(Algorithm::try_from(2).is_err());
+        assert_eq!(Algorithm::None.overhead(), 0);
+        assert_eq!(Algorithm::Aes256Gcm.overhead(), 28);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let data = vec![0x5au8; 4096];
+        let aad = chunk_aad("blob1", 1);
+        assert!(Cipher::new(Algorithm::Aes256Gcm, &[0u8; 16]).is_err());
+        assert!(Cipher::new(Algorithm::None, &[]).is_err());
+
+        let cipher = Cipher::new(Algorithm::Aes256Gcm, &[0x1u8; 32]).unwrap();
+        let encrypted = cipher.encrypt(&data, &aad).unwrap();
+        assert_eq!(
+            encrypted.len(),
+            data.len() + Algorithm::Aes256Gcm.overhead()
+        );
+        // IVs are random, so encrypting the same chunk twice gets different data.
+        assert_ne!(encrypted, cipher.encrypt(&data, &aad).unwrap());
+        assert_eq!(cipher.decrypt(&encrypted, &aad).unwrap(), data);
+
+        let other = Cipher::new(Algorithm::Aes256Gcm, &[0x2u8; 32]).unwrap();
+        assert!(other.decrypt(&encrypted, &aad).is_err());
+
+        // Chunks moved to other positions or other blobs are detected.
+        assert!(cipher.decrypt(&encrypted, &chunk_aad("blob1", 2)).is_err());
+        assert!(cipher.decrypt(&encrypted, &chunk_aad("blob2", 1)).is_err());
+
+        // Modified chunks are detected.
+        let mut modified = encrypted.clone();
+        modified[100] ^= 0xff;
+        assert!(cipher.decrypt(&modified, &aad).is_err());
+        assert!(cipher.decrypt(&encrypted[..20], &aad).is_err());
+    }
+
+    #[test]
+    fn test_cipher_config() {
+        let file = TempFile::new().unwrap();
+        fs::write(file.as_path(), &[0x1u8; 32]).unwrap();
+        let mut config = CipherConfig::default();
+        config
+            .key_files
+            .insert("key1".to_string(), file.as_path().display().to_string());
+
+        let cipher = config.new_cipher(Algorithm::Aes256Gcm, "key1").unwrap();
+        assert_eq!(cipher.algorithm(), Algorithm::Aes256Gcm);
+        assert!(!format!("{:?}", cipher).contains("1, 1"));
+        assert!(config.new_cipher(Algorithm::Aes256Gcm, "key2").is_err());
+    }
+
+    #[test]
+    fn test_env_key_provider() {
+        let config: CipherConfig =
+            serde_json::from_str(r#"{"key_provider": "env", "key_env_prefix": "NYDUS_TEST_KEY_"}"#)
+                .unwrap();
+        std::env::set_var("NYDUS_TEST_KEY_KEY_1", "01".repeat(32));
+        let cipher = config.new_cipher(Algorithm::Aes256Gcm, "key-1").unwrap();
+        assert_eq!(cipher.algorithm(), Algorithm::Aes256Gcm);
+
+        std::env::set_var("NYDUS_TEST_KEY_KEY_2", "0x");
+        assert!(config.new_cipher(Algorithm::Aes256Gcm, "key-2").is_err());
+        assert!(config.new_cipher(Algorithm::Aes256Gcm, "key-3").is_err());
+
+        let config: CipherConfig = serde_json::from_str(r#"{"key_provider": "vault"}"#).unwrap();
+        assert!(config.key_provider().is_err());
+    }
+
+    #[test]
+    fn test_kms_key_provider() {
+        use std::os::unix::net::UnixListener;
+        use vmm_sys_util::tempdir::TempDir;
+
+        let dir = TempDir::new().unwrap();
+        let socket = dir.as_path().join("kms.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let agent = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                let req: serde_json::Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(req["op"], "get_key");
+                let resp = if req["key_id"] == "key1" {
+                    format!("{{\"key\": \"{}\"}}\n", "02".repeat(32))
+                } else {
+                    "{\"error\": \"no such key\"}\n".to_string()
+                };
+                (&stream).write_all(resp.as_bytes()).unwrap();
+            }
+        });
+
+        let config = CipherConfig {
+            key_provider: "kms".to_string(),
+            kms_socket: socket.display().to_string(),
+            ..Default::default()
+        };
+        let provider = config.key_provider().unwrap();
+        assert_eq!(provider.get_key("key1").unwrap(), vec![0x2u8; 32]);
+        assert!(provider.get_key("key2").is_err());
+        agent.join().unwrap();
+    }
+}
@@ -22,9 +22,14 @@
 use std::any::Any;
 use std::cmp;
 use std::fmt::{Debug, Formatter};
+use std::fs::File;
 use std::io::{self, Error};
 use std::os::unix::io::AsRawFd;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use arc_swap::ArcSwap;
 use fuse_backend_rs::api::filesystem::ZeroCopyWriter;
@@ -32,11 +37,19 @@ use fuse_backend_rs::transport::{FileReadWriteVolatile, FileVolatileSlice};
 use nydus_utils::digest::{self, RafsDigest};
 use vm_memory::Bytes;
 
+use crate::backend::BlobReader;
 use crate::cache::BlobCache;
-use crate::compress;
 use crate::factory::{FactoryConfig, BLOB_FACTORY};
+use crate::meta;
+use crate::{compress, crypt};
 
 static ZEROS: &[u8] = &[0u8; 4096]; // why 4096? volatile slice default size, unfortunately
+/// Maximum number of threads to query sizes of blobs from storage backends concurrently.
+const CHECK_BLOBS_THREADS: usize = 8;
+/// Maximum amount of data to fetch by one backend request in [BlobDevice::fetch_io_vecs()].
+const FETCH_RANGE_MAX_SIZE: u64 = 8 << 20;
+/// Number of times to fetch chunks of a range in [BlobDevice::fetch_io_vecs()].
+const FETCH_RANGE_RETRY_COUNT: usize = 3;
 
 bitflags! {
     /// Features bits for blob management.
@@ -86,6 +99,10 @@ pub struct BlobInfo {
     validate_data: bool,
     /// The blob is for an stargz image.
     stargz: bool,
+    /// Algorithm to encrypt chunk data of the blob.
+    cipher: crypt::Algorithm,
+    /// Id of the key to encrypt chunk data of the blob.
+    cipher_key_id: String,
 
     /// V6: Version number of the blob metadata.
     meta_flags: u32,
@@ -97,6 +114,8 @@ pub struct BlobInfo {
     meta_ci_compressed_size: u64,
     /// V6: Size of the uncompressed chunk information array.
     meta_ci_uncompressed_size: u64,
+    /// V6: Cache file provided by the Linux fscache subsystem to store uncompressed blob data.
+    fscache_file: Option<Arc<File>>,
 }
 
 impl BlobInfo {
@@ -125,11 +144,14 @@ impl BlobInfo {
             readahead_size: 0,
             validate_data: false,
             stargz: false,
+            cipher: crypt::Algorithm::None,
+            cipher_key_id: String::new(),
             meta_ci_compressor: 0,
             meta_flags: 0,
             meta_ci_offset: 0,
             meta_ci_compressed_size: 0,
             meta_ci_uncompressed_size: 0,
+            fscache_file: None,
         };
 
         blob_info.compute_features();
@@ -262,6 +284,22 @@ impl BlobInfo {
         self.stargz = stargz;
     }
 
+    /// Get the algorithm to encrypt chunk data of the blob.
+    pub fn cipher(&self) -> crypt::Algorithm {
+        self.cipher
+    }
+
+    /// Get id of the key to encrypt chunk data of the blob.
+    pub fn cipher_key_id(&self) -> &str {
+        &self.cipher_key_id
+    }
+
+    /// Set the algorithm and id of the key to encrypt chunk data of the blob.
+    pub fn set_cipher(&mut self, cipher: crypt::Algorithm, key_id: String) {
+        self.cipher = cipher;
+        self.cipher_key_id = key_id;
+    }
+
     /// Set metadata information for a blob.
     ///
     /// The compressed blobs are laid out as:
@@ -318,6 +356,16 @@ impl BlobInfo {
             && self.meta_ci_compressed_size != 0
             && self.meta_ci_uncompressed_size != 0
     }
+
+    /// Set the cache file provided by the Linux fscache subsystem for the blob.
+    pub fn set_fscache_file(&mut self, file: Option<Arc<File>>) {
+        self.fscache_file = file;
+    }
+
+    /// Get the cache file provided by the Linux fscache subsystem for the blob.
+    pub fn get_fscache_file(&self) -> Option<Arc<File>> {
+        self.fscache_file.clone()
+    }
 }
 
 bitflags! {
@@ -806,6 +854,24 @@ pub struct BlobDevice {
     //meta: ArcSwap<Arc<dyn BlobCache>>,
     blobs: ArcSwap<Vec<Arc<dyn BlobCache>>>,
     blob_count: usize,
+    // Whether the device is frozen, checked without taking the lock of `freeze`.
+    frozen: Arc<AtomicBool>,
+    // Blobs frozen by the device and the deadline to thaw them, `None` if the device isn't frozen.
+    freeze: Arc<(Mutex<Option<FrozenBlobs>>, Condvar)>,
+}
+
+// Blobs frozen by a blob device.
+struct FrozenBlobs {
+    blobs: Arc<Vec<Arc<dyn BlobCache>>>,
+    deadline: Instant,
+}
+
+// Number of frozen blob devices.
+static FROZEN_DEVICES: AtomicUsize = AtomicUsize::new(0);
+
+/// Check whether any blob device is frozen.
+pub fn any_frozen() -> bool {
+    FROZEN_DEVICES.load(Ordering::Acquire) > 0
 }
 
 impl BlobDevice {
@@ -823,6 +889,8 @@ impl BlobDevice {
         Ok(BlobDevice {
             blobs: ArcSwap::new(Arc::new(blobs)),
             blob_count: blob_infos.len(),
+            frozen: Arc::new(AtomicBool::new(false)),
+            freeze: Arc::new((Mutex::new(None), Condvar::new())),
         })
     }
 
@@ -856,6 +924,151 @@ impl BlobDevice {
         Ok(())
     }
 
+    /// Switch all blobs to the storage backend of `config` without interrupting the filesystem.
+    ///
+    /// All blobs are located on the new storage backend by querying their sizes before switching,
+    /// so the switch fails without side effect if any blob is unavailable. Then new reads from the
+    /// storage backend are held, including prefetching, and reads in progress are drained within
+    /// `timeout`, before the blob caches switch to the new storage backend.
+    ///
+    /// Blob caches are shared by mounts with the same configuration, so such mounts switch to the
+    /// new storage backend too.
+    pub fn switch_backend(
+        &self,
+        config: &Arc<FactoryConfig>,
+        blob_infos: &[Arc<BlobInfo>],
+        timeout: Duration,
+    ) -> io::Result<()> {
+        if self.blob_count != blob_infos.len() {
+            return Err(einval!("number of blobs doesn't match"));
+        }
+        let blobs = self.blobs.load_full();
+        let mut switchable = Vec::with_capacity(blobs.len());
+        for blob in blobs.iter() {
+            let reader = blob.switchable_reader().ok_or_else(|| {
+                enosys!(format!(
+                    "blob cache of {} doesn't support switching storage backends",
+                    blob.blob_id()
+                ))
+            })?;
+            switchable.push(reader);
+        }
+
+        let mut readers = Vec::with_capacity(blob_infos.len());
+        let mut failures = Vec::new();
+        for blob_info in blob_infos.iter() {
+            match BLOB_FACTORY.new_reader(config.backend.clone(), blob_info.blob_id()) {
+                Ok(reader) => readers.push(reader),
+                Err(e) => failures.push(format!("{}: {}", blob_info.blob_id(), e)),
+            }
+        }
+        if failures.is_empty() {
+            failures = find_unavailable_blobs(&readers, blob_infos)?;
+        }
+        if !failures.is_empty() {
+            return Err(unavailable_blobs_error(&failures, blob_infos.len()));
+        }
+
+        // Drain reads of all blobs before switching any of them, so either all blobs switch or
+        // none does.
+        let deadline = Instant::now() + timeout;
+        let mut switches = Vec::with_capacity(switchable.len());
+        for reader in switchable {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            switches.push(reader.drain(remaining)?);
+        }
+        for (switch, reader) in switches.iter().zip(readers) {
+            switch.switch(reader);
+        }
+
+        Ok(())
+    }
+
+    /// Freeze the blob device, so new accesses to its blobs are held until it's thawed or `timeout`
+    /// passes.
+    ///
+    /// Accesses include reads, background prefetching, preheating and serving peers. Accesses in
+    /// progress are drained within `drain_timeout`, otherwise no blob is frozen and an error is
+    /// returned. Freezing a frozen device only resets the time to thaw it.
+    ///
+    /// Blob caches are shared by devices with the same configuration, so accesses to the blobs by
+    /// other devices are held too.
+    pub fn freeze(&self, timeout: Duration, drain_timeout: Duration) -> io::Result<()> {
+        let (lock, cvar) = &*self.freeze;
+        let mut state = lock.lock().unwrap();
+        if let Some(frozen) = state.as_mut() {
+            frozen.deadline = Instant::now() + timeout;
+            cvar.notify_all();
+            return Ok(());
+        }
+
+        let blobs = self.blobs.load_full();
+        let drain_deadline = Instant::now() + drain_timeout;
+        for (idx, blob) in blobs.iter().enumerate() {
+            let remaining = drain_deadline.saturating_duration_since(Instant::now());
+            if let Err(e) = blob.freeze(remaining) {
+                blobs[..idx].iter().for_each(|b| b.thaw());
+                return Err(eio!(format!(
+                    "failed to freeze blob {}, {}",
+                    blob.blob_id(),
+                    e
+                )));
+            }
+        }
+        *state = Some(FrozenBlobs {
+            blobs,
+            deadline: Instant::now() + timeout,
+        });
+        self.frozen.store(true, Ordering::Release);
+        FROZEN_DEVICES.fetch_add(1, Ordering::AcqRel);
+        drop(state);
+
+        // Thaw the device when the deadline passes, in case the maintenance never finishes.
+        let (freeze, frozen) = (self.freeze.clone(), self.frozen.clone());
+        let ret = thread::Builder::new()
+            .name("blob_thaw".to_string())
+            .spawn(move || {
+                let (lock, cvar) = &*freeze;
+                let mut state = lock.lock().unwrap();
+                while let Some(deadline) = state.as_ref().map(|f| f.deadline) {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        warn!(
+                            "blob device isn't thawed before the deadline, thaw it automatically"
+                        );
+                        thaw_blobs(&mut state, &frozen);
+                        cvar.notify_all();
+                        break;
+                    }
+                    state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+                }
+            });
+        if let Err(e) = ret {
+            self.thaw();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Thaw the blob device, so accesses held by [freeze()](BlobDevice::freeze) go on.
+    pub fn thaw(&self) {
+        let (lock, cvar) = &*self.freeze;
+        thaw_blobs(&mut lock.lock().unwrap(), &self.frozen);
+        cvar.notify_all();
+    }
+
+    /// Check whether the blob device is frozen.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Acquire)
+    }
+
+    /// Check whether reads of the blob device are held, because the device is frozen, or blob
+    /// caches shared with other devices are frozen by them.
+    pub fn holds_reads(&self) -> bool {
+        self.is_frozen() || (any_frozen() && self.blobs.load().iter().any(|b| b.is_frozen()))
+    }
+
     /// Close the blob device.
     pub fn close(&self) -> io::Result<()> {
         for blob in self.blobs.load().iter() {
@@ -865,6 +1078,109 @@ impl BlobDevice {
         Ok(())
     }
 
+    /// Write cached data and readiness state of all blobs back to the storage.
+    ///
+    /// Flushing may block on slow storage, so it's done by a separate thread and given up after
+    /// `timeout`, to bound the time to unmount or shut down.
+    pub fn flush(&self, timeout: Duration) -> io::Result<()> {
+        let blobs = self.blobs.load_full();
+        let (tx, rx) = channel();
+        thread::Builder::new()
+            .name("blob_flush".to_string())
+            .spawn(move || {
+                for blob in blobs.iter() {
+                    if let Err(e) = blob.flush() {
+                        warn!("failed to flush cache of blob {}, {}", blob.blob_id(), e);
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                }
+                let _ = tx.send(Ok(()));
+            })?;
+
+        match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(eio!(format!(
+                "flushing blob caches didn't finish in {:?}",
+                timeout
+            ))),
+            Err(RecvTimeoutError::Disconnected) => Err(eio!("flushing blob caches panicked")),
+        }
+    }
+
+    /// Get memory used by state of all blob caches, in unit of Byte.
+    pub fn memory_usage(&self) -> u64 {
+        self.blobs.load().iter().map(|b| b.memory_usage()).sum()
+    }
+
+    /// Release memory used by state of all blob caches, which will be reloaded on demand.
+    pub fn shrink(&self) {
+        for blob in self.blobs.load().iter() {
+            blob.shrink();
+        }
+    }
+
+    /// Check whether the storage backend is reachable, by querying size of the first blob.
+    pub fn check_backend(&self) -> io::Result<()> {
+        if let Some(blob) = self.blobs.load().first() {
+            blob.reader().blob_size().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("failed to query size of blob {}, {:?}", blob.blob_id(), e),
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether any blob has failed permanently, such as missing from the storage backend.
+    pub fn check_failed_blobs(&self) -> io::Result<()> {
+        let failures: Vec<String> = self
+            .blobs
+            .load()
+            .iter()
+            .filter_map(|blob| {
+                blob.reader()
+                    .failure()
+                    .and_then(|f| f.message())
+                    .map(|msg| format!("{}: {}", blob.blob_id(), msg))
+            })
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} blobs failed permanently, {}",
+                    failures.len(),
+                    failures.join("; ")
+                ),
+            ))
+        }
+    }
+
+    /// Check whether all blobs exist on the storage backend with expected sizes.
+    ///
+    /// Sizes of blobs are queried from the storage backend concurrently, by HEAD requests for remote
+    /// backends, and compared with sizes recorded in the metadata if available. Blobs missing or mismatched
+    /// are all listed by the returned error.
+    pub fn check_blobs(&self, blob_infos: &[Arc<BlobInfo>]) -> io::Result<()> {
+        let blobs = self.blobs.load();
+        if blobs.len() != blob_infos.len() {
+            return Err(einval!("number of blobs doesn't match"));
+        }
+
+        let failures = find_unavailable_blobs(&blobs, blob_infos)?;
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(unavailable_blobs_error(&failures, blob_infos.len()))
+        }
+    }
+
     /// Read a range of data from blob into the provided writer
     pub fn read_to(&self, w: &mut dyn ZeroCopyWriter, desc: &mut BlobIoVec) -> io::Result<usize> {
         // Validate that:
@@ -992,6 +1308,182 @@ impl BlobDevice {
 
         Ok(())
     }
+
+    /// Verify digest of the chunk described by `desc`, in the cache or on the storage backend.
+    ///
+    /// Return `Ok(false)` if the chunk is skipped, see
+    /// [BlobCache::scrub_chunk()](../cache/trait.BlobCache.html#method.scrub_chunk).
+    pub fn scrub_chunk(&self, desc: &BlobIoDesc, remote: bool) -> io::Result<bool> {
+        let blob_index = desc.blob.blob_index() as usize;
+        if blob_index >= self.blob_count {
+            return Err(einval!("invalid blob index to scrub chunk"));
+        }
+        let blob = self.blobs.load()[blob_index].clone();
+
+        blob.scrub_chunk(&desc.chunkinfo, remote)
+    }
+
+    /// Fetch all chunks of the blob IO vectors into the cache synchronously.
+    ///
+    /// Chunks continuous in a blob are fetched from the storage backend by requests of at most
+    /// `FETCH_RANGE_MAX_SIZE` bytes, and chunks failing to be fetched are retried.
+    pub fn fetch_io_vecs(&self, io_vecs: &[BlobIoVec]) -> io::Result<usize> {
+        let mut total = 0;
+
+        for io_vec in io_vecs.iter() {
+            let blob = match self.get_blob_by_iovec(io_vec) {
+                Some(blob) => blob,
+                None => continue,
+            };
+            let bios = &io_vec.bi_vec;
+            if bios.is_empty() {
+                continue;
+            }
+
+            let mut range = BlobIoRange::new(&bios[0], bios.len());
+            for idx in 1..bios.len() {
+                let size = range.blob_size + bios[idx].chunkinfo.compress_size() as u64;
+                if bios[idx].is_continuous(&bios[idx - 1]) && size <= FETCH_RANGE_MAX_SIZE {
+                    range.merge(&bios[idx]);
+                } else {
+                    total += Self::fetch_range(&blob, &range)?;
+                    range = BlobIoRange::new(&bios[idx], bios.len() - idx);
+                }
+            }
+            total += Self::fetch_range(&blob, &range)?;
+        }
+
+        Ok(total)
+    }
+
+    // Fetch chunks of the range into the cache, retrying chunks not ready after fetching.
+    fn fetch_range(blob: &Arc<dyn BlobCache>, range: &BlobIoRange) -> io::Result<usize> {
+        let chunk_map = blob.get_chunk_map();
+        let mut total = 0;
+
+        for _ in 0..FETCH_RANGE_RETRY_COUNT {
+            // Chunks already ready are skipped, so only chunks failed last time are fetched.
+            total += blob.prefetch_range(range)?;
+            if range
+                .chunks
+                .iter()
+                .all(|c| chunk_map.is_ready(c.as_base()).unwrap_or(false))
+            {
+                return Ok(total);
+            }
+        }
+
+        Err(eio!(format!(
+            "failed to fetch {} bytes at {} of blob {}",
+            range.blob_size,
+            range.blob_offset,
+            blob.blob_id()
+        )))
+    }
+}
+
+// Check whether the blob exists on the storage backend with the expected size, and is readable
+// if the blob metadata is available.
+fn check_blob(reader: &dyn BlobReader, blob_info: &BlobInfo) -> std::result::Result<(), String> {
+    let size = reader
+        .blob_size()
+        .map_err(|e| format!("{}: {:?}", blob_info.blob_id(), e))?;
+    let expected = meta::blob_file_size(blob_info);
+    // Sizes of blobs are unknown without the extended blob table of Rafs v5.
+    if !blob_info.has_feature(BlobFeatures::V5_NO_EXT_BLOB_TABLE)
+        && expected != 0
+        && size != expected
+    {
+        return Err(format!(
+            "{}: size {} doesn't match expected {}",
+            blob_info.blob_id(),
+            size,
+            expected
+        ));
+    }
+    if blob_info.meta_ci_is_valid() {
+        meta::check_blob_meta_header(blob_info, reader)
+            .map_err(|e| format!("{}: {}", blob_info.blob_id(), e))?;
+    }
+
+    Ok(())
+}
+
+// Objects reading blobs from storage backends, to check availability of the blobs.
+trait BlobSource: Clone + Send + Sync + 'static {
+    fn blob_reader(&self) -> &dyn BlobReader;
+}
+
+impl BlobSource for Arc<dyn BlobCache> {
+    fn blob_reader(&self) -> &dyn BlobReader {
+        self.reader()
+    }
+}
+
+impl BlobSource for Arc<dyn BlobReader> {
+    fn blob_reader(&self) -> &dyn BlobReader {
+        self.as_ref()
+    }
+}
+
+// Check blobs by multiple threads, since each check may take a round trip to the storage backend,
+// return descriptions of unavailable blobs in the order of `blobs`.
+fn find_unavailable_blobs<T: BlobSource>(
+    blobs: &[T],
+    blob_infos: &[Arc<BlobInfo>],
+) -> io::Result<Vec<String>> {
+    let jobs: Arc<Vec<(T, Arc<BlobInfo>)>> = Arc::new(
+        blobs
+            .iter()
+            .cloned()
+            .zip(blob_infos.iter().cloned())
+            .collect(),
+    );
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = channel();
+
+    for _ in 0..cmp::min(CHECK_BLOBS_THREADS, jobs.len()) {
+        let (jobs, next, tx) = (jobs.clone(), next.clone(), tx.clone());
+        thread::Builder::new()
+            .name("blob_check".to_string())
+            .spawn(move || loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                if idx >= jobs.len() {
+                    break;
+                }
+                let (blob, blob_info) = &jobs[idx];
+                if let Err(msg) = check_blob(blob.blob_reader(), blob_info) {
+                    let _ = tx.send((idx, msg));
+                }
+            })?;
+    }
+    drop(tx);
+
+    let mut failures = rx.iter().collect::<Vec<_>>();
+    failures.sort_by_key(|(idx, _)| *idx);
+
+    Ok(failures.into_iter().map(|(_, msg)| msg).collect())
+}
+
+// Thaw blobs frozen by a blob device, if the device is frozen.
+fn thaw_blobs(state: &mut Option<FrozenBlobs>, frozen: &AtomicBool) {
+    if let Some(f) = state.take() {
+        f.blobs.iter().for_each(|b| b.thaw());
+        frozen.store(false, Ordering::Release);
+        FROZEN_DEVICES.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn unavailable_blobs_error(failures: &[String], total: usize) -> io::Error {
+    // Keep the list of blobs in the error message, which is reported to the user.
+    let msg = format!(
+        "{} of {} blobs unavailable, {}",
+        failures.len(),
+        total,
+        failures.join("; ")
+    );
+    error!("{}", msg);
+    io::Error::new(io::ErrorKind::NotFound, msg)
 }
 
 /// Struct to execute Io requests with a single blob.
@@ -1140,6 +1632,206 @@ mod tests {
         assert_eq!(iochunk.is_hole(), false);
     }
 
+    #[cfg(feature = "backend-localfs")]
+    #[test]
+    fn test_check_blobs() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        std::fs::write(dir.as_path().join("blob1"), vec![0u8; 0x100]).unwrap();
+        std::fs::write(dir.as_path().join("blob2"), vec![0u8; 0x200]).unwrap();
+        let config: FactoryConfig = serde_json::from_str(&format!(
+            r#"{{"backend": {{"type": "localfs", "config": {{"dir": "{}"}}}}}}"#,
+            dir.as_path().to_str().unwrap()
+        ))
+        .unwrap();
+        let config = Arc::new(config);
+        let blob_infos = vec![
+            Arc::new(BlobInfo::new(
+                0,
+                "blob1".to_string(),
+                0x1000,
+                0x100,
+                0x1000,
+                1,
+                BlobFeatures::empty(),
+            )),
+            Arc::new(BlobInfo::new(
+                1,
+                "blob2".to_string(),
+                0x1000,
+                0x100,
+                0x1000,
+                1,
+                BlobFeatures::empty(),
+            )),
+        ];
+
+        let device = BlobDevice::new(&config, &blob_infos).unwrap();
+        device.check_blobs(&blob_infos[..1]).unwrap_err();
+        let err = device.check_blobs(&blob_infos).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("blob2"));
+        assert!(!err.to_string().contains("blob1"));
+    }
+
+    #[cfg(feature = "backend-localfs")]
+    #[test]
+    fn test_check_blobs_with_meta() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let ci = [0u8; 16];
+        let mut header = meta::BlobMetaHeaderOndisk::default();
+        header.set_ci_compressed_offset(0x100);
+        header.set_ci_compressed_size(ci.len() as u64);
+        header.set_ci_uncompressed_size(ci.len() as u64);
+        let mut data = vec![0u8; 0x100];
+        data.extend_from_slice(&ci);
+        data.extend_from_slice(header.as_bytes());
+        std::fs::write(dir.as_path().join("blob1"), &data).unwrap();
+        // The blob metadata header is missing.
+        std::fs::write(dir.as_path().join("blob2"), &data[..0x100 + ci.len()]).unwrap();
+        let config: FactoryConfig = serde_json::from_str(&format!(
+            r#"{{"backend": {{"type": "localfs", "config": {{"dir": "{}"}}}}}}"#,
+            dir.as_path().to_str().unwrap()
+        ))
+        .unwrap();
+        let config = Arc::new(config);
+        let blob_infos = ["blob1", "blob2"]
+            .iter()
+            .enumerate()
+            .map(|(idx, id)| {
+                let mut blob_info = BlobInfo::new(
+                    idx as u32,
+                    id.to_string(),
+                    0x1000,
+                    0x100,
+                    0x1000,
+                    1,
+                    BlobFeatures::empty(),
+                );
+                blob_info.set_blob_meta_info(
+                    0,
+                    0x100,
+                    ci.len() as u64,
+                    ci.len() as u64,
+                    compress::Algorithm::None as u32,
+                );
+                Arc::new(blob_info)
+            })
+            .collect::<Vec<_>>();
+
+        let device = BlobDevice::new(&config, &blob_infos).unwrap();
+        let err = device.check_blobs(&blob_infos).unwrap_err();
+        assert!(err.to_string().starts_with("1 of 2 blobs unavailable"));
+        assert!(err.to_string().contains("blob2"));
+        assert!(!err.to_string().contains("blob1"));
+    }
+
+    #[cfg(feature = "backend-localfs")]
+    fn localfs_config(dir: &vmm_sys_util::tempdir::TempDir) -> Arc<FactoryConfig> {
+        let config: FactoryConfig = serde_json::from_str(&format!(
+            r#"{{"backend": {{"type": "localfs", "config": {{"dir": "{}"}}}}}}"#,
+            dir.as_path().to_str().unwrap()
+        ))
+        .unwrap();
+        Arc::new(config)
+    }
+
+    #[cfg(feature = "backend-localfs")]
+    #[test]
+    fn test_switch_backend() {
+        let dir1 = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let dir2 = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        for id in ["blob1", "blob2"].iter() {
+            std::fs::write(dir1.as_path().join(id), vec![1u8; 0x100]).unwrap();
+        }
+        std::fs::write(dir2.as_path().join("blob1"), vec![2u8; 0x100]).unwrap();
+        let blob_infos = ["blob1", "blob2"]
+            .iter()
+            .enumerate()
+            .map(|(idx, id)| {
+                Arc::new(BlobInfo::new(
+                    idx as u32,
+                    id.to_string(),
+                    0x1000,
+                    0x100,
+                    0x1000,
+                    1,
+                    BlobFeatures::empty(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        let device = BlobDevice::new(&localfs_config(&dir1), &blob_infos).unwrap();
+        let read_first_byte = |idx: usize| {
+            let mut buf = [0u8; 1];
+            device.blobs.load()[idx].reader().read(&mut buf, 0).unwrap();
+            buf[0]
+        };
+
+        // No blob switches if any blob is missing from the new storage backend.
+        let err = device
+            .switch_backend(&localfs_config(&dir2), &blob_infos, Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("blob2"));
+        assert_eq!(read_first_byte(0), 1);
+
+        std::fs::write(dir2.as_path().join("blob2"), vec![2u8; 0x100]).unwrap();
+        device
+            .switch_backend(&localfs_config(&dir2), &blob_infos, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(read_first_byte(0), 2);
+        assert_eq!(read_first_byte(1), 2);
+    }
+
+    #[cfg(feature = "backend-localfs")]
+    #[test]
+    fn test_freeze_thaw() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        std::fs::write(dir.as_path().join("blob1"), vec![1u8; 0x100]).unwrap();
+        let blob_info = Arc::new(BlobInfo::new(
+            0,
+            "blob1".to_string(),
+            0x1000,
+            0x100,
+            0x1000,
+            1,
+            BlobFeatures::empty(),
+        ));
+        let device = BlobDevice::new(&localfs_config(&dir), &[blob_info]).unwrap();
+        let blob = device.blobs.load()[0].clone();
+        assert!(!device.is_frozen());
+        assert!(!device.holds_reads());
+
+        device
+            .freeze(Duration::from_secs(60), Duration::from_secs(1))
+            .unwrap();
+        assert!(device.is_frozen());
+        assert!(device.holds_reads());
+        assert!(blob.is_frozen());
+        assert!(any_frozen());
+
+        // Freezing again only resets the deadline, and a single thaw is enough.
+        device
+            .freeze(Duration::from_secs(60), Duration::from_millis(0))
+            .unwrap();
+        assert!(device.is_frozen());
+        device.thaw();
+        assert!(!device.is_frozen());
+        assert!(!device.holds_reads());
+        assert!(!blob.is_frozen());
+
+        // The device is thawed when the deadline of freezing passes.
+        device
+            .freeze(Duration::from_millis(50), Duration::from_secs(1))
+            .unwrap();
+        let start = Instant::now();
+        while device.is_frozen() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(!blob.is_frozen());
+    }
+
     #[test]
     fn test_is_all_chunk_ready() {
         // TODO
@@ -10,7 +10,7 @@
 //! [FactoryConfig](struct.FactoryConfig.html). Those cached blob managers may be garbage-collected
 //! by [BlobFactory::gc()](struct.BlobFactory.html#method.gc).
 //! if not used anymore.
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::io::Result as IOResult;
@@ -24,8 +24,9 @@ use serde_json::value::Value;
 use crate::backend::oss;
 #[cfg(feature = "backend-registry")]
 use crate::backend::registry;
-use crate::backend::{localfs, BlobBackend};
+use crate::backend::{localfs, BlobBackend, BlobReader, BlobUploader};
 use crate::cache::{BlobCache, BlobCacheMgr, BlobPrefetchConfig, DummyCacheMgr, FileCacheMgr};
+use crate::crypt::CipherConfig;
 use crate::device::BlobInfo;
 
 /// Configuration information for storage backend.
@@ -83,6 +84,9 @@ pub struct CacheConfig {
     /// Configuration for blob data prefetching.
     #[serde(skip_serializing, skip_deserializing)]
     pub prefetch_config: BlobPrefetchConfig,
+    /// Keys to decrypt encrypted blobs.
+    #[serde(skip_serializing, skip_deserializing)]
+    pub cipher_config: CipherConfig,
 }
 
 /// Configuration information to create blob cache manager.
@@ -151,6 +155,11 @@ impl BlobFactory {
                 mgr.init()?;
                 Arc::new(mgr) as Arc<dyn BlobCacheMgr>
             }
+            "fscache" => {
+                let mgr = FileCacheMgr::new_fscache(config.cache.clone(), backend, &config.id)?;
+                mgr.init()?;
+                Arc::new(mgr) as Arc<dyn BlobCacheMgr>
+            }
             _ => {
                 let mgr = DummyCacheMgr::new(config.cache.clone(), backend, false, false)?;
                 mgr.init()?;
@@ -164,9 +173,86 @@ impl BlobFactory {
         mgr.get_blob_cache(blob_info)
     }
 
-    /// Garbage-collect unused blob cache managers and blob caches.
+    /// Release the blob cache created for `blob_info` with configuration `config`.
+    pub fn release_blob_cache(&self, config: &Arc<FactoryConfig>, blob_info: &BlobInfo) {
+        let key = BlobCacheMgrKey {
+            config: config.clone(),
+        };
+        if let Some(mgr) = self.mgrs.lock().unwrap().get(&key) {
+            mgr.release_blob_cache(blob_info);
+        }
+    }
+
+    /// Garbage-collect unused blob caches.
+    ///
+    /// Cached data of blobs not used by any blob cache manager is removed, including blobs
+    /// cached by earlier instances and never opened since. New blob caches can't be created
+    /// until garbage collection is done.
     pub fn gc(&self) {
-        unimplemented!("TODO")
+        let mgrs = self.mgrs.lock().unwrap();
+        let in_use = mgrs
+            .values()
+            .flat_map(|mgr| mgr.blobs_in_use())
+            .collect::<HashSet<_>>();
+
+        for mgr in mgrs.values() {
+            mgr.gc(&in_use);
+        }
+    }
+
+    /// Find the blob cache created for the blob with id `blob_id` by any blob cache manager.
+    pub fn find_blob_cache(&self, blob_id: &str) -> Option<Arc<dyn BlobCache>> {
+        let mgrs = self
+            .mgrs
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        mgrs.iter().find_map(|mgr| mgr.find_blob_cache(blob_id))
+    }
+
+    /// Create an uploader to push a new blob to the storage backend.
+    ///
+    /// The `blob_id` is the expected id of the new blob if it's known in advance, which is
+    /// mandatory for some storage backends.
+    pub fn new_uploader(
+        &self,
+        config: BackendConfig,
+        blob_id: Option<&str>,
+    ) -> IOResult<Box<dyn BlobUploader>> {
+        let backend = Self::new_backend(config, blob_id.unwrap_or("uploader"))?;
+
+        backend.get_uploader(blob_id).map_err(|e| eother!(e))
+    }
+
+    /// Create a reader to access the blob with id `blob_id` from the storage backend directly,
+    /// bypassing the blob cache.
+    pub fn new_reader(
+        &self,
+        config: BackendConfig,
+        blob_id: &str,
+    ) -> IOResult<Arc<dyn BlobReader>> {
+        let backend = Self::new_backend(config, blob_id)?;
+
+        backend.get_reader(blob_id).map_err(|e| eother!(e))
+    }
+
+    /// Fetch the image manifest `reference`, a tag or a digest, from a `registry` storage
+    /// backend.
+    #[cfg(feature = "backend-registry")]
+    pub fn fetch_manifest(&self, config: BackendConfig, reference: &str) -> IOResult<Vec<u8>> {
+        if config.backend_type != "registry" {
+            return Err(einval!(format!(
+                "can't fetch image manifest from '{}' backend",
+                config.backend_type
+            )));
+        }
+        let id = format!("manifest:{}", reference);
+        let registry = registry::Registry::new(config.backend_config, Some(&id))?;
+
+        registry.get_manifest(reference).map_err(|e| eother!(e))
     }
 
     /// Create a storage backend for the blob with id `blob_id`.
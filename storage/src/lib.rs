@@ -44,11 +44,10 @@ extern crate bitflags;
 #[macro_use]
 extern crate nydus_error;
 
-use std::fmt::{Display, Formatter};
-
 pub mod backend;
 pub mod cache;
 pub mod compress;
+pub mod crypt;
 pub mod device;
 pub mod factory;
 pub mod meta;
@@ -74,26 +73,19 @@ pub const RAFS_DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
 pub const RAFS_MAX_CHUNK_SIZE: u64 = 1024 * 1024;
 
 /// Error codes related to storage subsystem.
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum StorageError {
+    #[error("unsupported storage operation")]
     Unsupported,
+    #[error("timeout when reading data from storage backend")]
     Timeout,
+    #[error("{0}")]
     VolatileSlice(vm_memory::VolatileMemoryError),
+    #[error("memory overflow when doing storage backend IO")]
     MemOverflow,
+    #[error("address ranges are not continuous")]
     NotContinuous,
 }
 
-impl Display for StorageError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            StorageError::Unsupported => write!(f, "unsupported storage operation"),
-            StorageError::Timeout => write!(f, "timeout when reading data from storage backend"),
-            StorageError::MemOverflow => write!(f, "memory overflow when doing storage backend IO"),
-            StorageError::NotContinuous => write!(f, "address ranges are not continuous"),
-            StorageError::VolatileSlice(e) => write!(f, "{}", e),
-        }
-    }
-}
-
 /// Specialized std::result::Result for storage subsystem.
 pub type StorageResult<T> = std::result::Result<T, StorageError>;
@@ -26,6 +26,7 @@ use nydus_utils::digest::RafsDigest;
 use crate::backend::BlobReader;
 use crate::compress;
 use crate::device::{BlobChunkInfo, BlobInfo, BlobIoChunk};
+use crate::utils::{release_pages, resident_size};
 use std::any::Any;
 
 const BLOB_METADATA_MAX_CHUNKS: u32 = 0xf_ffff;
@@ -402,6 +403,22 @@ impl BlobMetaInfo {
         Ok(BlobMetaInfo { state })
     }
 
+    /// Get size of resident pages of the blob metadata mapping.
+    pub fn memory_usage(&self) -> u64 {
+        resident_size(self.state.base, self.state.unmap_len)
+    }
+
+    /// Drop resident pages of the blob metadata mapping, which is a shared file mapping, so the
+    /// metadata is loaded from the file again on next access.
+    pub fn shrink(&self) {
+        release_pages(self.state.base, self.state.unmap_len)
+    }
+
+    /// Get compressed sizes of all chunks of the blob, in order of chunk index.
+    pub fn get_compressed_sizes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.state.chunks.iter().map(|c| c.compressed_size())
+    }
+
     /// Get blob chunks covering uncompressed data range [start, start + size).
     ///
     /// The method returns error if any of following condition is true:
@@ -559,7 +576,7 @@ impl BlobMetaInfo {
 
             let size = reader
                 .read(&mut buf, blob_info.meta_ci_offset())
-                .map_err(|e| eio!(format!("failed to read metadata from backend, {:?}", e)))?;
+                .map_err(|e| eio!(format!("failed to read metadata from backend, {}", e)))?;
             if size as u64 != compressed_size {
                 return Err(eio!("failed to read blob metadata from backend"));
             }
@@ -578,6 +595,48 @@ impl BlobMetaInfo {
     }
 }
 
+/// Get size of the blob on the storage backend, including the blob metadata and header appended
+/// to the compressed chunk data.
+pub fn blob_file_size(blob_info: &BlobInfo) -> u64 {
+    if blob_info.meta_ci_is_valid() {
+        blob_info.meta_ci_offset() + blob_info.meta_ci_compressed_size() + BLOB_METADTAT_HEADER_SIZE
+    } else {
+        blob_info.compressed_size()
+    }
+}
+
+/// Read the blob metadata header from the storage backend and check it against `blob_info`.
+///
+/// It makes sure blob data is readable from the storage backend, besides the blob existing.
+pub fn check_blob_meta_header(blob_info: &BlobInfo, reader: &dyn BlobReader) -> Result<()> {
+    let offset = blob_info.meta_ci_offset() + blob_info.meta_ci_compressed_size();
+    let mut header = BlobMetaHeaderOndisk::default();
+    // Safe because the header is a plain old data structure.
+    let buf = unsafe {
+        std::slice::from_raw_parts_mut(
+            &mut header as *mut BlobMetaHeaderOndisk as *mut u8,
+            size_of::<BlobMetaHeaderOndisk>(),
+        )
+    };
+    let size = reader
+        .read(buf, offset)
+        .map_err(|e| eio!(format!("failed to read blob metadata header, {}", e)))?;
+    if size != buf.len() {
+        return Err(eio!("failed to read blob metadata header"));
+    }
+
+    if header.s_magic != BLOB_METADATA_MAGIC
+        || header.s_magic2 != BLOB_METADATA_MAGIC
+        || header.ci_compressed_offset() != blob_info.meta_ci_offset()
+        || header.ci_compressed_size() != blob_info.meta_ci_compressed_size()
+        || header.ci_uncompressed_size() != blob_info.meta_ci_uncompressed_size()
+    {
+        return Err(einval!("blob metadata header doesn't match the blob table"));
+    }
+
+    Ok(())
+}
+
 pub struct BlobMetaState {
     blob_index: u32,
     // The file size of blob file when it contains compressed chunks.
@@ -1023,4 +1082,56 @@ mod tests {
 
         assert_eq!(buffer, data);
     }
+
+    #[test]
+    fn test_check_blob_meta_header() {
+        let temp = TempFile::new().unwrap();
+        let mut w = temp.as_file().try_clone().unwrap();
+        let ci = [0u8; 32];
+        let data_size = 0x2000u64;
+        w.write_all(&vec![0u8; data_size as usize]).unwrap();
+        w.write_all(&ci).unwrap();
+        let mut header = BlobMetaHeaderOndisk::default();
+        header.set_ci_compressed_offset(data_size);
+        header.set_ci_compressed_size(ci.len() as u64);
+        header.set_ci_uncompressed_size(ci.len() as u64);
+        w.write_all(header.as_bytes()).unwrap();
+
+        let mut blob_info = BlobInfo::new(
+            0,
+            "dummy".to_string(),
+            0x4000,
+            data_size,
+            RAFS_MAX_CHUNK_SIZE as u32,
+            2,
+            BlobFeatures::default(),
+        );
+        assert_eq!(blob_file_size(&blob_info), data_size);
+        blob_info.set_blob_meta_info(
+            0,
+            data_size,
+            ci.len() as u64,
+            ci.len() as u64,
+            compress::Algorithm::None as u32,
+        );
+        assert_eq!(
+            blob_file_size(&blob_info),
+            temp.as_file().metadata().unwrap().len()
+        );
+
+        let reader = DummyBlobReader {
+            metrics: BackendMetrics::new("dummy", "localfs"),
+            file: temp.as_file().try_clone().unwrap(),
+        };
+        check_blob_meta_header(&blob_info, &reader).unwrap();
+
+        blob_info.set_blob_meta_info(
+            0,
+            data_size,
+            ci.len() as u64,
+            ci.len() as u64 * 2,
+            compress::Algorithm::None as u32,
+        );
+        assert!(check_blob_meta_header(&blob_info, &reader).is_err());
+    }
 }
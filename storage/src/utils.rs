@@ -5,12 +5,16 @@
 //! Utility helpers to supprt the storage subsystem.
 use std::cmp::{self, min};
 use std::io::{ErrorKind, Result};
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::slice::from_raw_parts_mut;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use fuse_backend_rs::transport::FileVolatileSlice;
 use libc::off64_t;
-use nix::sys::uio::{preadv, IoVec};
+use nix::sys::uio::{self, preadv, IoVec};
 use nydus_utils::{
     digest::{self, RafsDigest},
     round_down_4k,
@@ -20,7 +24,15 @@ use vm_memory::Bytes;
 use crate::{StorageError, StorageResult};
 
 /// Just a simple wrapper for posix `preadv`. Provide a slice of `IoVec` as input.
+///
+/// The read is submitted through a per thread io_uring instance when the `io-uring` feature is
+/// enabled and supported by the running kernel.
 pub fn readv(fd: RawFd, iovec: &[IoVec<&mut [u8]>], offset: u64) -> Result<usize> {
+    #[cfg(feature = "io-uring")]
+    if let Some(ret) = uring::readv(fd, iovec, offset) {
+        return ret;
+    }
+
     loop {
         match preadv(fd, iovec, offset as off64_t).map_err(|_| last_error!()) {
             Ok(ret) => return Ok(ret),
@@ -31,6 +43,259 @@ pub fn readv(fd: RawFd, iovec: &[IoVec<&mut [u8]>], offset: u64) -> Result<usize
     }
 }
 
+/// Just a simple wrapper for posix `pread`, with the same io_uring acceleration as [readv].
+pub fn pread(fd: RawFd, buf: &mut [u8], offset: u64) -> Result<usize> {
+    #[cfg(feature = "io-uring")]
+    if let Some(ret) = uring::pread(fd, buf, offset) {
+        return ret;
+    }
+
+    loop {
+        match uio::pread(fd, buf, offset as off64_t).map_err(|_| last_error!()) {
+            Ok(ret) => return Ok(ret),
+            Err(err) if err.kind() != ErrorKind::Interrupted => return Err(err),
+            _ => continue,
+        }
+    }
+}
+
+/// Just a simple wrapper for posix `pwrite`, with the same io_uring acceleration as [readv].
+pub fn pwrite(fd: RawFd, buf: &[u8], offset: u64) -> Result<usize> {
+    #[cfg(feature = "io-uring")]
+    if let Some(ret) = uring::pwrite(fd, buf, offset) {
+        return ret;
+    }
+
+    loop {
+        match uio::pwrite(fd, buf, offset as off64_t).map_err(|_| last_error!()) {
+            Ok(ret) => return Ok(ret),
+            Err(err) if err.kind() != ErrorKind::Interrupted => return Err(err),
+            _ => continue,
+        }
+    }
+}
+
+/// Synchronous local file IO through io_uring.
+///
+/// Each thread lazily sets up a small ring and submits one request at a time, which saves the
+/// syscall overhead of the many small reads issued by metadata heavy workloads while keeping
+/// the synchronous semantics of the posix calls. All helpers return `None` if io_uring is not
+/// usable, in which case callers fall back to the posix calls.
+#[cfg(feature = "io-uring")]
+mod uring {
+    use std::cell::{Cell, RefCell};
+    use std::io::{Error, Result};
+    use std::os::unix::io::RawFd;
+    use std::slice;
+
+    use io_uring::{opcode, squeue, types, IoUring, Probe};
+    use nix::sys::uio::IoVec;
+
+    const RING_ENTRIES: u32 = 8;
+
+    thread_local! {
+        static RING: RefCell<Option<IoUring>> = RefCell::new(setup());
+        static SEQUENCE: Cell<u64> = Cell::new(0);
+    }
+
+    fn setup() -> Option<IoUring> {
+        let ring = match IoUring::new(RING_ENTRIES) {
+            Ok(ring) => ring,
+            Err(e) => {
+                warn!("failed to setup io_uring, fall back to posix IO, {}", e);
+                return None;
+            }
+        };
+
+        // Opcodes used are only available since Linux 5.6.
+        let mut probe = Probe::new();
+        if ring.submitter().register_probe(&mut probe).is_err()
+            || !probe.is_supported(opcode::Read::CODE)
+            || !probe.is_supported(opcode::Write::CODE)
+            || !probe.is_supported(opcode::Readv::CODE)
+        {
+            warn!("io_uring opcodes are not supported, fall back to posix IO");
+            return None;
+        }
+
+        Some(ring)
+    }
+
+    fn submit(entry: squeue::Entry) -> Option<Result<usize>> {
+        RING.with(|cell| {
+            // Fall back if the ring is not available or already borrowed by a reentrant call.
+            let mut guard = cell.try_borrow_mut().ok()?;
+            let ring = guard.as_mut()?;
+            // Tag each request, so stale completions are never taken for the current one.
+            let user_data = SEQUENCE.with(|seq| {
+                let v = seq.get().wrapping_add(1);
+                seq.set(v);
+                v
+            });
+            let entry = entry.user_data(user_data);
+
+            loop {
+                // Safe because buffers referenced by the entry outlive the request, the request
+                // is always reaped before returning once submitted to the kernel.
+                if unsafe { ring.submission().push(&entry) }.is_err() {
+                    return None;
+                }
+
+                let res = loop {
+                    if let Some(res) = reap(ring, user_data) {
+                        break res;
+                    }
+                    match ring.submit_and_wait(1) {
+                        Ok(_) => {}
+                        Err(e)
+                            if matches!(
+                                e.raw_os_error(),
+                                Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY)
+                            ) => {}
+                        // The kernel hasn't taken the request, drop the ring and fall back to
+                        // posix IO.
+                        Err(e) if !ring.submission().is_empty() => {
+                            warn!("failed to submit io_uring request, {}", e);
+                            *guard = None;
+                            return None;
+                        }
+                        // The request is in flight and must be reaped, keep waiting for it.
+                        Err(e) => debug!("failed to wait for io_uring request, {}", e),
+                    }
+                };
+
+                match res {
+                    res if res >= 0 => return Some(Ok(res as usize)),
+                    // Retry if the IO is interrupted by signal.
+                    res if -res == libc::EINTR => continue,
+                    res => return Some(Err(Error::from_raw_os_error(-res))),
+                }
+            }
+        })
+    }
+
+    // Take the result of the request `user_data` from the completion queue, if it has completed.
+    fn reap(ring: &mut IoUring, user_data: u64) -> Option<i32> {
+        let mut res = None;
+        for cqe in ring.completion() {
+            if cqe.user_data() == user_data {
+                res = Some(cqe.result());
+            } else {
+                warn!("discard stale io_uring completion {}", cqe.user_data());
+            }
+        }
+        res
+    }
+
+    // Repeat `io` until `len` bytes are transferred or end of file is reached, because requests
+    // may complete partially like the posix calls.
+    fn transfer<F>(len: usize, mut io: F) -> Option<Result<usize>>
+    where
+        F: FnMut(usize) -> Option<Result<usize>>,
+    {
+        let mut done = 0;
+        while done < len {
+            match io(done) {
+                Some(Ok(0)) => break,
+                Some(Ok(n)) => done += n,
+                Some(Err(e)) if done == 0 => return Some(Err(e)),
+                None if done == 0 => return None,
+                _ => break,
+            }
+        }
+        Some(Ok(done))
+    }
+
+    pub(super) fn readv(
+        fd: RawFd,
+        iovec: &[IoVec<&mut [u8]>],
+        offset: u64,
+    ) -> Option<Result<usize>> {
+        // `IoVec` is a transparent wrapper of `libc::iovec`.
+        let mut iovs = unsafe {
+            slice::from_raw_parts(iovec.as_ptr() as *const libc::iovec, iovec.len()).to_vec()
+        };
+        let len = iovs.iter().map(|v| v.iov_len).sum();
+        let mut index = 0;
+        let mut advanced = 0;
+
+        transfer(len, |done| {
+            // Skip over data transferred by previous requests.
+            while index < iovs.len() && done - advanced >= iovs[index].iov_len {
+                advanced += iovs[index].iov_len;
+                index += 1;
+            }
+            let skip = done - advanced;
+            iovs[index].iov_base = unsafe { (iovs[index].iov_base as *mut u8).add(skip) } as _;
+            iovs[index].iov_len -= skip;
+            advanced += skip;
+
+            let entry = opcode::Readv::new(
+                types::Fd(fd),
+                iovs[index..].as_ptr(),
+                (iovs.len() - index) as u32,
+            )
+            .offset((offset + done as u64) as i64)
+            .build();
+            submit(entry)
+        })
+    }
+
+    pub(super) fn pread(fd: RawFd, buf: &mut [u8], offset: u64) -> Option<Result<usize>> {
+        transfer(buf.len(), |done| {
+            let buf = &mut buf[done..];
+            let entry = opcode::Read::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32)
+                .offset((offset + done as u64) as i64)
+                .build();
+            submit(entry)
+        })
+    }
+
+    pub(super) fn pwrite(fd: RawFd, buf: &[u8], offset: u64) -> Option<Result<usize>> {
+        transfer(buf.len(), |done| {
+            let buf = &buf[done..];
+            let entry = opcode::Write::new(types::Fd(fd), buf.as_ptr(), buf.len() as u32)
+                .offset((offset + done as u64) as i64)
+                .build();
+            submit(entry)
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+        use vmm_sys_util::tempfile::TempFile;
+
+        #[test]
+        fn test_uring_transfer() {
+            let file = TempFile::new().unwrap();
+            file.as_file().write_all(&[0x5a; 8192]).unwrap();
+            let fd = file.as_file().as_raw_fd();
+
+            let mut buf = vec![0u8; 4096];
+            match pread(fd, &mut buf, 6144) {
+                // io_uring is not supported by the running kernel.
+                None => return,
+                Some(ret) => assert_eq!(ret.unwrap(), 2048),
+            }
+            assert!(buf[..2048].iter().all(|b| *b == 0x5a));
+
+            let mut first = vec![0u8; 100];
+            let mut second = vec![0u8; 8192];
+            let iovec = [
+                IoVec::from_mut_slice(&mut first),
+                IoVec::from_mut_slice(&mut second),
+            ];
+            assert_eq!(readv(fd, &iovec, 0).unwrap().unwrap(), 8192);
+
+            assert_eq!(pwrite(fd, &[0xa5; 10], 8190).unwrap().unwrap(), 10);
+            assert_eq!(file.as_file().metadata().unwrap().len(), 8200);
+        }
+    }
+}
+
 /// Copy from buffer slice to another buffer slice.
 ///
 /// `offset` is where to start copy in the first buffer of source slice.
@@ -137,6 +402,19 @@ impl<'a> MemSliceCursor<'a> {
         }
     }
 
+    /// Get the `size` bytes of memory at the cursor if they are contiguous, without moving the
+    /// cursor.
+    pub fn contiguous_slice(&mut self, size: usize) -> Option<&mut [u8]> {
+        let slice = self.mem_slice.get(self.index)?;
+        if size == 0 || slice.len() - self.offset < size {
+            return None;
+        }
+
+        // Safe because self.offset is valid and we have checked `size`.
+        let p = unsafe { slice.as_ptr().add(self.offset) };
+        Some(unsafe { from_raw_parts_mut(p, size) })
+    }
+
     /// Consume `size` bytes of memory content from the cursor.
     pub fn consume(&mut self, mut size: usize) -> Vec<IoVec<&mut [u8]>> {
         let mut vectors: Vec<IoVec<&mut [u8]>> = Vec::with_capacity(8);
@@ -204,15 +482,295 @@ pub fn alloc_buf(size: usize) -> Vec<u8> {
     buf
 }
 
+/// Policy to back metadata mappings and chunk buffers with huge pages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HugePageMode {
+    /// Use normal pages.
+    Never,
+    /// Advise the kernel to use transparent huge pages.
+    Transparent,
+    /// Use pre-allocated huge pages from hugetlbfs, falling back to normal pages if none is
+    /// available.
+    Explicit,
+}
+
+impl FromStr for HugePageMode {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "never" => Ok(HugePageMode::Never),
+            "transparent" => Ok(HugePageMode::Transparent),
+            "explicit" => Ok(HugePageMode::Explicit),
+            _ => Err(einval!(format!("invalid huge page mode {}", s))),
+        }
+    }
+}
+
+static HUGEPAGE_MODE: AtomicU8 = AtomicU8::new(HugePageMode::Never as u8);
+
+/// Set the process wide policy to use huge pages, which should be set before loading any
+/// filesystem.
+pub fn set_hugepage_mode(mode: HugePageMode) {
+    HUGEPAGE_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Get the process wide policy to use huge pages.
+pub fn hugepage_mode() -> HugePageMode {
+    match HUGEPAGE_MODE.load(Ordering::Relaxed) {
+        v if v == HugePageMode::Transparent as u8 => HugePageMode::Transparent,
+        v if v == HugePageMode::Explicit as u8 => HugePageMode::Explicit,
+        _ => HugePageMode::Never,
+    }
+}
+
+/// Size of huge pages, which is 2MB on most architectures.
+pub const HUGEPAGE_SIZE: usize = 0x20_0000;
+
+/// Advise the kernel to back memory range [`addr`, `addr + size`) by transparent huge pages.
+pub fn advise_hugepage(addr: *const u8, size: usize) {
+    let ret = unsafe { libc::madvise(addr as *mut libc::c_void, size, libc::MADV_HUGEPAGE) };
+    if ret != 0 {
+        debug!("failed to advise huge pages, {}", last_error!());
+    }
+}
+
+/// Map `size` bytes of anonymous memory backed by huge pages from hugetlbfs.
+///
+/// The `size` must be aligned to `HUGEPAGE_SIZE`, and the memory should be freed by `munmap()`.
+pub fn map_hugetlb(size: usize) -> Result<*mut u8> {
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        Err(last_error!("failed to map huge pages"))
+    } else {
+        Ok(addr as *mut u8)
+    }
+}
+
+/// Get size of pages of memory range [`base`, `base + size`) mapped into the process, which are
+/// accounted in its resident set size.
+///
+/// Unlike `mincore()`, pages of file mappings only cached by the page cache are not counted.
+pub fn resident_size(base: *const u8, size: usize) -> u64 {
+    if base.is_null() || size == 0 {
+        return 0;
+    }
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = base as usize / page_size;
+    let count = (base as usize + size - 1) / page_size + 1 - start;
+    let mut entries = vec![0u8; count * 8];
+    let ret = std::fs::File::open("/proc/self/pagemap").and_then(|f| {
+        let mut pos = 0;
+        while pos < entries.len() {
+            match pread(f.as_raw_fd(), &mut entries[pos..], (start * 8 + pos) as u64)? {
+                0 => return Err(eio!("short read of pagemap")),
+                n => pos += n,
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = ret {
+        warn!("failed to get resident pages, {}", e);
+        return 0;
+    }
+
+    // Bit 63 of a pagemap entry is set if the page is present in memory.
+    let present = entries.chunks_exact(8).filter(|e| e[7] & 0x80 != 0).count();
+
+    (present * page_size) as u64
+}
+
+/// Drop pages of file backed memory range [`base`, `base + size`) from the process, to reclaim
+/// memory.
+///
+/// Pages are read from the page cache or the file again on next access, so the range must not be
+/// a private mapping with modified pages.
+pub fn release_pages(base: *const u8, size: usize) {
+    if base.is_null() || size == 0 {
+        return;
+    }
+
+    let ret = unsafe { libc::madvise(base as *mut libc::c_void, size, libc::MADV_DONTNEED) };
+    if ret != 0 {
+        warn!("failed to release resident pages, {}", last_error!());
+    }
+}
+
 /// Check hash of data matches provided one
 pub fn digest_check(data: &[u8], digest: &RafsDigest, digester: digest::Algorithm) -> bool {
     digest == &RafsDigest::from_buf(data, digester)
 }
 
+/// A gate to hold new IO and drain IO in progress, e.g. to switch storage backends.
+///
+/// Entering an open gate takes no lock: IO increases `inflight` before checking `closed`, and
+/// closing increases `closed` before checking `inflight`, so either the IO sees the gate closed,
+/// or the closer sees the IO in progress. The gate is fair to closers, IO arriving after the gate
+/// is closed waits until it's opened again.
+#[derive(Default)]
+pub(crate) struct IoGate {
+    // Number of holders keeping the gate closed.
+    closed: AtomicUsize,
+    // Number of IO in progress.
+    inflight: AtomicUsize,
+    lock: Mutex<()>,
+    cvar: Condvar,
+}
+
+impl IoGate {
+    /// Enter the gate for an IO, waiting while the gate is closed.
+    pub fn enter(&self) -> IoGateEntry {
+        loop {
+            self.inflight.fetch_add(1, Ordering::SeqCst);
+            if self.closed.load(Ordering::SeqCst) == 0 {
+                return IoGateEntry(self);
+            }
+            // Leave again so the closer doesn't wait for this IO.
+            drop(IoGateEntry(self));
+            let mut guard = self.lock.lock().unwrap();
+            while self.closed.load(Ordering::SeqCst) > 0 {
+                guard = self.cvar.wait(guard).unwrap();
+            }
+        }
+    }
+
+    /// Enter the gate for an IO derived from an IO which has entered the gate, e.g. to finish it
+    /// asynchronously, without waiting for the gate.
+    ///
+    /// The gate can't be drained while the caller is in progress, so the derived IO is drained
+    /// with it.
+    pub fn enter_derived(self: &Arc<Self>) -> IoGateSharedEntry {
+        self.inflight.fetch_add(1, Ordering::SeqCst);
+        IoGateSharedEntry(self.clone())
+    }
+
+    /// Close the gate and wait for IO in progress to leave within `timeout`.
+    ///
+    /// The gate is opened again when the returned guard is dropped, or if IO in progress doesn't
+    /// leave in time.
+    pub fn close(&self, timeout: Duration) -> Result<IoGateClosed> {
+        self.hold(timeout)?;
+
+        Ok(IoGateClosed(self))
+    }
+
+    /// Close the gate until [release()](IoGate::release) is called, and wait for IO in progress
+    /// to leave within `timeout`.
+    ///
+    /// The gate is opened again if IO in progress doesn't leave in time.
+    pub fn hold(&self, timeout: Duration) -> Result<()> {
+        self.closed.fetch_add(1, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        let mut guard = self.lock.lock().unwrap();
+        while self.inflight.load(Ordering::SeqCst) > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                drop(guard);
+                self.release();
+                return Err(eio!(format!(
+                    "IO in progress didn't finish in {:?}",
+                    timeout
+                )));
+            }
+            guard = self.cvar.wait_timeout(guard, deadline - now).unwrap().0;
+        }
+
+        Ok(())
+    }
+
+    /// Open the gate closed by [hold()](IoGate::hold), if there's no other holder.
+    pub fn release(&self) {
+        if self.closed.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.wake_up();
+        }
+    }
+
+    /// Check whether the gate is closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst) > 0
+    }
+
+    fn leave(&self) {
+        if self.inflight.fetch_sub(1, Ordering::SeqCst) == 1 && self.is_closed() {
+            self.wake_up();
+        }
+    }
+
+    // Wake up waiters of the gate, with the lock held so waiters checking their conditions don't
+    // miss the wakeup.
+    fn wake_up(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.cvar.notify_all();
+    }
+}
+
+/// Guard of an IO which has entered an [IoGate].
+pub(crate) struct IoGateEntry<'a>(&'a IoGate);
+
+impl Drop for IoGateEntry<'_> {
+    fn drop(&mut self) {
+        self.0.leave();
+    }
+}
+
+/// Guard of an IO which has entered an [IoGate], owning a reference to the gate.
+pub(crate) struct IoGateSharedEntry(Arc<IoGate>);
+
+impl Drop for IoGateSharedEntry {
+    fn drop(&mut self) {
+        self.0.leave();
+    }
+}
+
+/// Guard to keep an [IoGate] closed.
+pub(crate) struct IoGateClosed<'a>(&'a IoGate);
+
+impl Drop for IoGateClosed<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use fuse_backend_rs::transport::FileVolatileSlice;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_pread_pwrite_readv() {
+        let tmp = TempFile::new().unwrap();
+        let fd = tmp.as_file().as_raw_fd();
+
+        assert_eq!(pwrite(fd, b"hello nydus", 4096).unwrap(), 11);
+        let mut buf = [0u8; 5];
+        assert_eq!(pread(fd, &mut buf, 4102).unwrap(), 5);
+        assert_eq!(&buf, b"nydus");
+        assert_eq!(pread(fd, &mut buf, 8192).unwrap(), 0);
+
+        let mut buf1 = [0u8; 6];
+        let mut buf2 = [0u8; 8];
+        let iovec = [
+            IoVec::from_mut_slice(&mut buf1[..]),
+            IoVec::from_mut_slice(&mut buf2[..]),
+        ];
+        assert_eq!(readv(fd, &iovec, 4096).unwrap(), 11);
+        assert_eq!(&buf1, b"hello ");
+        assert_eq!(&buf2[..5], b"nydus");
+
+        assert!(pread(-1, &mut buf, 0).is_err());
+    }
 
     #[test]
     fn test_copyv() {
@@ -346,4 +904,118 @@ mod tests {
         assert_eq!(cursor.index, 2);
         assert_eq!(cursor.offset, 0);
     }
+
+    #[test]
+    fn test_mem_slice_cursor_contiguous_slice() {
+        let mut buf1 = vec![0x0u8; 4];
+        let vs1 = unsafe { FileVolatileSlice::new(buf1.as_mut_ptr(), buf1.len()) };
+        let mut buf2 = vec![0x0u8; 4];
+        let vs2 = unsafe { FileVolatileSlice::new(buf2.as_mut_ptr(), buf2.len()) };
+        let vs = [vs1, vs2];
+
+        let mut cursor = MemSliceCursor::new(&vs);
+        assert!(cursor.contiguous_slice(0).is_none());
+        assert!(cursor.contiguous_slice(5).is_none());
+        cursor.move_cursor(1);
+        assert!(cursor.contiguous_slice(4).is_none());
+        cursor
+            .contiguous_slice(3)
+            .unwrap()
+            .copy_from_slice(&[1, 2, 3]);
+        assert_eq!(cursor.index, 0);
+        assert_eq!(cursor.offset, 1);
+        assert_eq!(buf1, vec![0, 1, 2, 3]);
+
+        cursor.move_cursor(5);
+        assert_eq!(cursor.contiguous_slice(2).unwrap().len(), 2);
+        cursor.move_cursor(2);
+        assert!(cursor.contiguous_slice(1).is_none());
+    }
+
+    #[test]
+    fn test_hugepage_mode() {
+        assert_eq!(
+            HugePageMode::from_str("transparent").unwrap(),
+            HugePageMode::Transparent
+        );
+        assert_eq!(
+            HugePageMode::from_str("explicit").unwrap(),
+            HugePageMode::Explicit
+        );
+        assert!(HugePageMode::from_str("always").is_err());
+        assert_eq!(hugepage_mode(), HugePageMode::Never);
+    }
+
+    #[test]
+    fn test_io_gate() {
+        use std::thread;
+
+        let gate = Arc::new(IoGate::default());
+        let entry = gate.enter();
+        assert!(gate.close(Duration::from_millis(20)).is_err());
+        // The gate is opened again after failing to close it.
+        drop(gate.enter());
+        drop(entry);
+
+        let closed = gate.close(Duration::from_secs(1)).unwrap();
+        let gate2 = gate.clone();
+        let io = thread::spawn(move || {
+            let _entry = gate2.enter();
+            Instant::now()
+        });
+        thread::sleep(Duration::from_millis(50));
+        let opened = Instant::now();
+        drop(closed);
+        assert!(io.join().unwrap() >= opened);
+
+        // A closed gate can be closed by more holders.
+        let closed = gate.close(Duration::from_secs(1)).unwrap();
+        gate.hold(Duration::from_secs(1)).unwrap();
+        drop(closed);
+        assert!(gate.is_closed());
+        gate.release();
+        assert!(!gate.is_closed());
+
+        // Derived IO is drained with the IO it's derived from.
+        let entry = gate.enter();
+        let derived = gate.enter_derived();
+        drop(entry);
+        assert!(gate.hold(Duration::from_millis(20)).is_err());
+        assert!(!gate.is_closed());
+        drop(derived);
+        gate.hold(Duration::from_secs(1)).unwrap();
+        gate.release();
+    }
+
+    #[test]
+    fn test_resident_size() {
+        let tmp = TempFile::new().unwrap();
+        let size = 0x10000;
+        tmp.as_file().set_len(size as u64).unwrap();
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                tmp.as_file().as_raw_fd(),
+                0,
+            )
+        } as *const u8;
+        assert_ne!(base as *mut libc::c_void, libc::MAP_FAILED);
+        assert_eq!(resident_size(base, size), 0);
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut sum = 0u8;
+        for off in (0..size).step_by(page_size) {
+            sum = sum.wrapping_add(unsafe { std::ptr::read_volatile(base.add(off)) });
+        }
+        assert_eq!(sum, 0);
+        assert_eq!(resident_size(base, size), size as u64);
+
+        // Released pages are still in the page cache, but not accounted to the process.
+        release_pages(base, size);
+        assert_eq!(resident_size(base, size), 0);
+        unsafe { libc::munmap(base as *mut libc::c_void, size) };
+    }
 }
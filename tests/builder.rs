@@ -196,7 +196,7 @@ impl<'a> Builder<'a> {
 
         exec(
             format!(
-                "{:?} create --bootstrap {:?} --blob-dir {:?} --log-level info --compressor {} --whiteout-spec {} {:?}",
+                "{:?} create --bootstrap {:?} --blob-dir {:?} --log-level info --compressor {} --whiteout-spec {} --threads 4 {:?}",
                 self.builder,
                 self.work_dir.join("bootstrap-lower"),
                 self.work_dir.join("blobs"),
@@ -209,6 +209,93 @@ impl<'a> Builder<'a> {
         ).unwrap();
     }
 
+    /// Build lower rootfs again with a single worker thread, the generated bootstrap must be
+    /// identical to the one built with multiple worker threads.
+    pub fn check_lower_single_thread(&mut self, compressor: &str) {
+        let lower_dir = self.work_dir.join("lower");
+
+        self.create_dir(&self.work_dir.join("blobs-single-thread"));
+
+        exec(
+            format!(
+                "{:?} create --bootstrap {:?} --blob-dir {:?} --log-level info --compressor {} --whiteout-spec {} --threads 1 {:?}",
+                self.builder,
+                self.work_dir.join("bootstrap-lower-single-thread"),
+                self.work_dir.join("blobs-single-thread"),
+                compressor,
+                self.whiteout_spec,
+                lower_dir,
+            )
+            .as_str(),
+            false,
+        ).unwrap();
+        exec(
+            format!(
+                "cmp {:?} {:?}",
+                self.work_dir.join("bootstrap-lower"),
+                self.work_dir.join("bootstrap-lower-single-thread"),
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+    }
+
+    /// Build lower rootfs from a tar stream piped into the builder.
+    pub fn build_lower_tarball(&mut self, compressor: &str) {
+        exec(
+            format!(
+                "tar --xattrs -C {:?} -cf - . | {:?} create --source-type tarball --bootstrap {:?} --blob-dir {:?} --log-level info --compressor {} --whiteout-spec {} -",
+                self.work_dir.join("lower"),
+                self.builder,
+                self.work_dir.join("bootstrap-lower-tarball"),
+                self.work_dir.join("blobs"),
+                compressor,
+                self.whiteout_spec,
+            )
+            .as_str(),
+            false,
+        ).unwrap();
+    }
+
+    pub fn unpack_lower(&mut self) {
+        let unpacked_dir = self.work_dir.join("unpacked-lower");
+        self.create_dir(&unpacked_dir);
+
+        exec(
+            format!(
+                "{:?} unpack --bootstrap {:?} --blob-dir {:?} --output {:?} --log-level info",
+                self.builder,
+                self.work_dir.join("bootstrap-lower"),
+                self.work_dir.join("blobs"),
+                self.work_dir.join("lower.tar"),
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+        exec(
+            format!(
+                "tar --xattrs -xf {:?} -C {:?}",
+                self.work_dir.join("lower.tar"),
+                unpacked_dir,
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+        exec(
+            format!(
+                "diff -r --no-dereference {:?} {:?}",
+                self.work_dir.join("lower"),
+                unpacked_dir
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+    }
+
     pub fn build_upper(&mut self, compressor: &str) {
         let upper_dir = self.work_dir.join("upper");
 
@@ -228,6 +315,71 @@ impl<'a> Builder<'a> {
         ).unwrap();
     }
 
+    /// Build lower and upper rootfs as independent layers, then merge their bootstraps.
+    pub fn merge_layers(&mut self, compressor: &str) {
+        for layer in &["lower", "upper"] {
+            exec(
+                format!(
+                    "{:?} create --bootstrap {:?} --blob-dir {:?} --log-level info --compressor {} --whiteout-spec none {:?}",
+                    self.builder,
+                    self.work_dir.join(format!("bootstrap-{}-layer", layer)),
+                    self.work_dir.join("blobs"),
+                    compressor,
+                    self.work_dir.join(layer),
+                )
+                .as_str(),
+                false,
+            ).unwrap();
+        }
+
+        exec(
+            format!(
+                "{:?} merge --bootstrap {:?} --log-level info --whiteout-spec {} {:?} {:?}",
+                self.builder,
+                self.work_dir.join("bootstrap-merged"),
+                self.whiteout_spec,
+                self.work_dir.join("bootstrap-lower-layer"),
+                self.work_dir.join("bootstrap-upper-layer"),
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+    }
+
+    /// Copy the mounted overlay rootfs, as a complete snapshot of upper layer.
+    pub fn snapshot_overlay(&mut self, mount_path: &str) {
+        exec(
+            format!(
+                "cp -a {:?} {:?}",
+                self.work_dir.join(mount_path),
+                self.work_dir.join("snapshot-overlay"),
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+    }
+
+    /// Build upper layer from changes between the lower rootfs and the overlay snapshot.
+    pub fn build_diff(&mut self, compressor: &str) {
+        exec(
+            format!(
+                "{:?} create --parent-bootstrap {:?} --bootstrap {:?} --blob-dir {:?} --log-level info --compressor {} --whiteout-spec {} --diff {:?} {:?}",
+                self.builder,
+                self.work_dir.join("bootstrap-lower"),
+                self.work_dir.join("bootstrap-diff"),
+                self.work_dir.join("blobs"),
+                compressor,
+                self.whiteout_spec,
+                self.work_dir.join("lower"),
+                self.work_dir.join("snapshot-overlay"),
+            )
+            .as_str(),
+            false,
+        ).unwrap();
+    }
+
     pub fn build_stargz_lower(&mut self) {
         exec(
             format!(
@@ -78,6 +78,7 @@ fn test(
         // Create & build lower rootfs
         builder.make_lower();
         builder.build_lower(compressor);
+        builder.check_lower_single_thread(compressor);
 
         // Mount lower rootfs and check
         let nydusd = nydusd::new(
@@ -91,6 +92,23 @@ fn test(
         nydusd.start(Some("bootstrap-lower"), "mnt");
         nydusd.check(&lower_texture, "mnt");
         nydusd.umount("mnt");
+
+        // Unpack lower rootfs into tar archive and check
+        builder.unpack_lower();
+
+        // Build lower rootfs from tar stream and check
+        builder.build_lower_tarball(compressor);
+        let nydusd = nydusd::new(
+            &work_dir,
+            enable_cache,
+            cache_compressed,
+            rafs_mode.parse().unwrap(),
+            "api.sock".into(),
+            true,
+        );
+        nydusd.start(Some("bootstrap-lower-tarball"), "mnt");
+        nydusd.check(&lower_texture, "mnt");
+        nydusd.umount("mnt");
     }
 
     // Mount upper rootfs and check
@@ -110,6 +128,39 @@ fn test(
         );
         nydusd.start(Some("bootstrap-overlay"), "mnt");
         nydusd.check(&overlay_texture, "mnt");
+        // Take the mounted overlay rootfs as snapshot of upper layer
+        builder.snapshot_overlay("mnt");
+        nydusd.umount("mnt");
+
+        // Build upper layer from diff of lower and overlay snapshots and check
+        builder.build_diff(compressor);
+        let nydusd = nydusd::new(
+            &work_dir,
+            enable_cache,
+            cache_compressed,
+            rafs_mode.parse().unwrap(),
+            "api.sock".into(),
+            true,
+        );
+        nydusd.start(Some("bootstrap-diff"), "mnt");
+        nydusd.check(&overlay_texture, "mnt");
+        nydusd.umount("mnt");
+    }
+
+    // Merge independently built layers and check
+    {
+        builder.merge_layers(compressor);
+
+        let nydusd = nydusd::new(
+            &work_dir,
+            enable_cache,
+            cache_compressed,
+            rafs_mode.parse().unwrap(),
+            "api.sock".into(),
+            true,
+        );
+        nydusd.start(Some("bootstrap-merged"), "mnt");
+        nydusd.check(&overlay_texture, "mnt");
         nydusd.umount("mnt");
     }
 
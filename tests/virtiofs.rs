@@ -0,0 +1,483 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interoperability tests of the vhost-user-fs path against real VMMs.
+//!
+//! The test builds an image, exports it by a virtiofs nydusd, boots a guest with QEMU or
+//! cloud-hypervisor, mounts the filesystem inside the guest and runs conformance and I/O checks
+//! through the guest serial console. It's only built with feature `virtiofs-interop`, and the
+//! guest is configured by environment variables:
+//! - `VIRTIOFS_KERNEL`: guest kernel image, required.
+//! - `VIRTIOFS_ROOTFS`: guest disk image with root auto-login on the serial console, required.
+//! - `VIRTIOFS_VMM`: `qemu` or `cloud-hypervisor`, defaults to `qemu`.
+//! - `VIRTIOFS_VMM_BIN`: path to the VMM binary.
+//! - `VIRTIOFS_GUEST_ROOT`: root device of the guest, defaults to `/dev/vda1`.
+#![cfg(feature = "virtiofs-interop")]
+
+#[macro_use]
+extern crate log;
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use nydus_app::setup_logging;
+use nydus_utils::exec;
+use sha2::{Digest, Sha256};
+use vmm_sys_util::tempdir::TempDir;
+
+mod builder;
+
+const FS_TAG: &str = "nydus";
+const GUEST_MOUNT: &str = "/mnt";
+const GUEST_MEMORY_MB: u32 = 1024;
+const BOOT_TIMEOUT: Duration = Duration::from_secs(120);
+const CMD_TIMEOUT: Duration = Duration::from_secs(60);
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_MARKER: &str = "__NYDUS_READY__";
+const RC_MARKER: &str = "__NYDUS_RC__=";
+
+/// Supported virtual machine monitors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VmmType {
+    Qemu,
+    CloudHypervisor,
+}
+
+impl VmmType {
+    fn from_env() -> Self {
+        match std::env::var("VIRTIOFS_VMM").as_deref() {
+            Ok("cloud-hypervisor") | Ok("clh") => VmmType::CloudHypervisor,
+            Ok("qemu") | Err(_) => VmmType::Qemu,
+            Ok(v) => panic!("unsupported VMM {}", v),
+        }
+    }
+
+    fn default_bin(&self) -> &'static str {
+        match self {
+            VmmType::Qemu => "qemu-system-x86_64",
+            VmmType::CloudHypervisor => "cloud-hypervisor",
+        }
+    }
+}
+
+fn env_required(key: &str) -> String {
+    std::env::var(key).unwrap_or_else(|_| panic!("Please specify `{}` env", key))
+}
+
+fn wait_for_socket(path: &Path) {
+    let start = Instant::now();
+    while !path.exists() {
+        if start.elapsed() > SOCKET_TIMEOUT {
+            panic!("timeout to wait for socket {:?}", path);
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
+
+/// A child process killed on drop.
+struct Process {
+    name: &'static str,
+    child: Child,
+}
+
+impl Process {
+    fn spawn(name: &'static str, cmd: &mut Command) -> Self {
+        info!("spawn {} {:?}", name, cmd);
+        let child = cmd
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("failed to spawn {}, {}", name, e));
+        Process { name, child }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        if let Err(e) = self.child.kill() {
+            warn!("failed to kill {}, {}", self.name, e);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+/// Start a virtiofs nydusd exporting `bootstrap` through vhost-user socket `sock`.
+fn start_nydusd(work_dir: &Path, bootstrap: &Path, sock: &Path) -> Process {
+    let config = format!(
+        r###"
+        {{
+            "device": {{
+                "backend": {{
+                    "type": "localfs",
+                    "config": {{
+                        "dir": {:?}
+                    }}
+                }}
+            }},
+            "mode": "direct",
+            "digest_validate": true,
+            "enable_xattr": true
+        }}
+        "###,
+        work_dir.join("blobs")
+    );
+    let config_path = work_dir.join("config.json");
+    fs::write(&config_path, config).unwrap();
+
+    let nydusd = std::env::var("NYDUSD")
+        .unwrap_or_else(|_| String::from("./target-virtiofs/release/nydusd"));
+    let process = Process::spawn(
+        "nydusd",
+        Command::new(nydusd)
+            .arg("--config")
+            .arg(&config_path)
+            .arg("--bootstrap")
+            .arg(bootstrap)
+            .arg("--sock")
+            .arg(sock)
+            .args(&["--log-level", "info"]),
+    );
+    wait_for_socket(sock);
+
+    process
+}
+
+/// Boot a guest with the vhost-user-fs device connected to `fs_sock`, and the serial console
+/// exported by `console_sock`.
+fn start_vmm(vmm: VmmType, work_dir: &Path, fs_sock: &Path, console_sock: &Path) -> Process {
+    let bin = std::env::var("VIRTIOFS_VMM_BIN").unwrap_or_else(|_| vmm.default_bin().to_string());
+    let kernel = env_required("VIRTIOFS_KERNEL");
+    let root = std::env::var("VIRTIOFS_GUEST_ROOT").unwrap_or_else(|_| "/dev/vda1".to_string());
+    let cmdline = format!("console=ttyS0 root={} rw", root);
+
+    // Work on a copy of the disk image, so the test never changes the original one.
+    let rootfs = work_dir.join("rootfs.img");
+    exec(
+        format!(
+            "cp --sparse=always {:?} {:?}",
+            env_required("VIRTIOFS_ROOTFS"),
+            rootfs
+        )
+        .as_str(),
+        false,
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(bin);
+    match vmm {
+        VmmType::Qemu => {
+            cmd.args(&["-M", "pc", "-cpu", "host", "-enable-kvm", "-smp", "2"])
+                .arg("-m")
+                .arg(format!("{}M", GUEST_MEMORY_MB))
+                .arg("-object")
+                .arg(format!(
+                    "memory-backend-file,id=mem,size={}M,mem-path=/dev/shm,share=on",
+                    GUEST_MEMORY_MB
+                ))
+                .args(&["-numa", "node,memdev=mem"])
+                .arg("-chardev")
+                .arg(format!("socket,id=char0,path={}", fs_sock.display()))
+                .arg("-device")
+                .arg(format!(
+                    "vhost-user-fs-pci,chardev=char0,tag={},queue-size=1024",
+                    FS_TAG
+                ))
+                .arg("-kernel")
+                .arg(kernel)
+                .arg("-append")
+                .arg(cmdline)
+                .arg("-drive")
+                .arg(format!("if=virtio,format=raw,file={}", rootfs.display()))
+                .arg("-serial")
+                .arg(format!("unix:{},server,nowait", console_sock.display()))
+                .args(&["-display", "none", "-vga", "none", "-monitor", "none"]);
+        }
+        VmmType::CloudHypervisor => {
+            cmd.args(&["--cpus", "boot=2"])
+                .arg("--memory")
+                .arg(format!("size={}M,shared=on", GUEST_MEMORY_MB))
+                .arg("--kernel")
+                .arg(kernel)
+                .arg("--cmdline")
+                .arg(cmdline)
+                .arg("--disk")
+                .arg(format!("path={}", rootfs.display()))
+                .arg("--fs")
+                .arg(format!(
+                    "tag={},socket={},num_queues=1,queue_size=1024",
+                    FS_TAG,
+                    fs_sock.display()
+                ))
+                .arg("--serial")
+                .arg(format!("socket={}", console_sock.display()))
+                .args(&["--console", "off"]);
+        }
+    }
+
+    let process = Process::spawn("vmm", &mut cmd);
+    wait_for_socket(console_sock);
+
+    process
+}
+
+/// Shell session on the guest serial console.
+struct GuestConsole {
+    stream: UnixStream,
+    // Data received but not consumed yet.
+    pending: String,
+}
+
+impl GuestConsole {
+    /// Connect to the serial console and wait until the guest shell is ready.
+    fn connect(path: &Path) -> Self {
+        let stream = UnixStream::connect(path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut console = GuestConsole {
+            stream,
+            pending: String::new(),
+        };
+
+        // Keep poking the console until the auto-login shell disables echo and responds.
+        let start = Instant::now();
+        loop {
+            console.send(&format!("stty -echo; PS1=''; echo {}", READY_MARKER));
+            if console
+                .wait_line(|l| l == READY_MARKER, Duration::from_secs(5))
+                .is_some()
+            {
+                break;
+            }
+            if start.elapsed() > BOOT_TIMEOUT {
+                panic!("timeout to wait for the guest to boot");
+            }
+        }
+        info!("guest booted in {} seconds", start.elapsed().as_secs());
+
+        console
+    }
+
+    fn send(&mut self, line: &str) {
+        self.stream.write_all(line.as_bytes()).unwrap();
+        self.stream.write_all(b"\n").unwrap();
+    }
+
+    // Wait for a line matching `pred`, return lines received before it.
+    fn wait_line<F: FnMut(&str) -> bool>(
+        &mut self,
+        mut pred: F,
+        timeout: Duration,
+    ) -> Option<Vec<String>> {
+        let start = Instant::now();
+        let mut lines = Vec::new();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            while let Some(pos) = self.pending.find('\n') {
+                let line: String = self.pending.drain(..=pos).collect();
+                let line = line
+                    .trim_end_matches(|c| c == '\n' || c == '\r')
+                    .to_string();
+                debug!("guest: {}", line);
+                if pred(&line) {
+                    return Some(lines);
+                }
+                lines.push(line);
+            }
+            if start.elapsed() > timeout {
+                return None;
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => panic!("guest console is closed"),
+                Ok(n) => self.pending.push_str(&String::from_utf8_lossy(&buf[..n])),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => panic!("failed to read guest console, {}", e),
+            }
+        }
+    }
+
+    /// Run a shell command in the guest, return its exit code and output.
+    fn run(&mut self, cmd: &str) -> (i32, String) {
+        self.send(&format!("{}; echo {}$?", cmd, RC_MARKER));
+        let mut rc = None;
+        let lines = self
+            .wait_line(
+                |l| match l.strip_prefix(RC_MARKER).map(|v| v.parse::<i32>()) {
+                    Some(Ok(v)) => {
+                        rc = Some(v);
+                        true
+                    }
+                    _ => false,
+                },
+                CMD_TIMEOUT,
+            )
+            .unwrap_or_else(|| panic!("timeout to run `{}` in the guest", cmd));
+
+        (rc.unwrap(), lines.join("\n"))
+    }
+
+    /// Run a shell command in the guest and expect it to succeed.
+    fn check(&mut self, cmd: &str) -> String {
+        let (rc, output) = self.run(cmd);
+        assert_eq!(rc, 0, "`{}` failed in the guest:\n{}", cmd, output);
+        output
+    }
+}
+
+fn sha256_of_range(path: &Path, offset: u64, size: usize) -> String {
+    let mut buf = vec![0u8; size];
+    let n = File::open(path).unwrap().read_at(&mut buf, offset).unwrap();
+    format!("{:x}", Sha256::digest(&buf[..n]))
+}
+
+/// Check basic POSIX semantics of the read-only filesystem, in the style of pjdfstest.
+fn check_conformance(console: &mut GuestConsole) {
+    let cases = [
+        // File types and attributes.
+        ("regular file", "test -f /mnt/root-1 && test ! -L /mnt/root-1"),
+        ("directory", "test -d /mnt/sub/more/more-sub"),
+        ("file size", "test $(stat -c %s /mnt/root-1) -eq 12"),
+        ("file size of large file", "test $(stat -c %s /mnt/root-large) -eq 13631488"),
+        ("symlink", "test -L /mnt/sub/sub-root-large-symlink"),
+        ("readlink", "test $(readlink /mnt/sub/sub-root-large-symlink) = ../root-large"),
+        ("follow symlink", "cmp /mnt/root-large /mnt/sub/sub-root-large-symlink"),
+        ("hardlink count", "test $(stat -c %h /mnt/root-large-copy) -eq 3"),
+        (
+            "hardlink inode",
+            "test $(stat -c %i /mnt/root-large) -eq $(stat -c %i /mnt/sub/sub-root-large-hardlink)",
+        ),
+        ("long file name", "test $(ls /mnt | grep -c '^test-') -eq 1"),
+        ("readdir", "test $(ls -A /mnt/sub | wc -l) -eq 8"),
+        ("lookup missing entry", "! stat /mnt/not-exist 2>/dev/null"),
+        ("ENOTDIR", "! ls /mnt/root-1/ 2>/dev/null"),
+        // Extended attributes.
+        (
+            "getxattr",
+            "test $(getfattr --only-values -n user.key-foo /mnt/sub/sub-1) = value-foo",
+        ),
+        ("listxattr", "test $(getfattr -d /mnt/sub/sub-1 | grep -c '^user.key-') -eq 2"),
+        ("getxattr missing", "! getfattr -n user.not-exist /mnt/sub/sub-1 2>/dev/null"),
+        // Mutations must be rejected without leaving anything behind.
+        ("create", "! touch /mnt/new-file 2>/dev/null && test ! -e /mnt/new-file"),
+        ("mkdir", "! mkdir /mnt/new-dir 2>/dev/null && test ! -e /mnt/new-dir"),
+        ("write", "! sh -c 'echo data >> /mnt/root-1' 2>/dev/null && test $(stat -c %s /mnt/root-1) -eq 12"),
+        ("unlink", "! rm -f /mnt/root-2 2>/dev/null && test -f /mnt/root-2"),
+        ("rename", "! mv /mnt/root-2 /mnt/root-3 2>/dev/null && test -f /mnt/root-2"),
+        ("link", "! ln /mnt/root-2 /mnt/root-3 2>/dev/null && test ! -e /mnt/root-3"),
+        ("symlink creation", "! ln -s root-2 /mnt/root-3 2>/dev/null && test ! -e /mnt/root-3"),
+        ("chmod", "m=$(stat -c %a /mnt/root-2); ! chmod 777 /mnt/root-2 2>/dev/null; test $(stat -c %a /mnt/root-2) = $m"),
+        ("truncate", "! truncate -s 0 /mnt/root-2 2>/dev/null && test $(stat -c %s /mnt/root-2) -eq 12"),
+        ("setxattr", "! setfattr -n user.new -v v /mnt/root-2 2>/dev/null"),
+    ];
+
+    let mut failures = Vec::new();
+    for (name, cmd) in cases.iter() {
+        let (rc, output) = console.run(cmd);
+        if rc == 0 {
+            info!("conformance case {}: ok", name);
+        } else {
+            error!("conformance case {}: failed\n{}", name, output);
+            failures.push(*name);
+        }
+    }
+    assert!(failures.is_empty(), "failed cases: {:?}", failures);
+}
+
+/// Check data read in the guest against the source files on host.
+fn check_io(console: &mut GuestConsole, lower_dir: &Path) {
+    // Compare the whole tree with the texture shared with smoke tests.
+    let tree = console.check(&format!("tree -a -J -v {}", GUEST_MOUNT));
+    let md5 = console.check(&format!(
+        "find {} -type f -exec md5sum {{}} + | sort",
+        GUEST_MOUNT
+    ));
+    let ret = format!(
+        "{}\n{}",
+        tree.replace(GUEST_MOUNT, ""),
+        md5.replace(GUEST_MOUNT, "")
+    );
+    let expected = fs::read_to_string("./tests/texture/directory/lower.result").unwrap();
+    assert_eq!(ret.trim(), expected.trim());
+
+    // Aligned and unaligned reads at random offsets, crossing chunk boundaries.
+    let large = lower_dir.join("root-large");
+    for (offset, size) in [
+        (0u64, 4096usize),
+        (1_048_576 - 100, 200),
+        (4_194_304 + 1, 1_048_576),
+        (13_631_488 - 10, 4096),
+    ]
+    .iter()
+    {
+        let output = console.check(&format!(
+            "tail -c +{} /mnt/root-large | head -c {} | sha256sum",
+            offset + 1,
+            size
+        ));
+        let digest = output.split_whitespace().next().unwrap_or_default();
+        assert_eq!(
+            digest,
+            sha256_of_range(&large, *offset, *size),
+            "mismatched data at offset {} size {}",
+            offset,
+            size
+        );
+    }
+
+    // Concurrent readers of the same file.
+    let expected = sha256_of_range(&large, 0, 13_631_488);
+    let output = console
+        .check("for i in 1 2 3 4; do sha256sum /mnt/root-large /mnt/root-large-copy & done; wait");
+    let digests: Vec<&str> = output
+        .lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .collect();
+    assert_eq!(digests.len(), 8);
+    assert!(digests.iter().all(|d| *d == expected));
+
+    // Drop the page cache and read again, so requests go through the virtqueues once more.
+    console.check("sync; echo 3 > /proc/sys/vm/drop_caches");
+    let output = console.check("sha256sum /mnt/root-large");
+    assert!(output.starts_with(&expected));
+}
+
+#[test]
+fn integration_test_virtiofs_interop() {
+    let _ = setup_logging(None, log::LevelFilter::Info);
+    let vmm = VmmType::from_env();
+
+    let tmp_dir_prefix =
+        std::env::var("TEST_WORKDIR_PREFIX").expect("Please specify `TEST_WORKDIR_PREFIX` env");
+    let tmp_dir =
+        TempDir::new_with_prefix(format!("{}/", tmp_dir_prefix.trim_end_matches('/'))).unwrap();
+    let work_dir: PathBuf = tmp_dir.as_path().to_path_buf();
+
+    let mut builder = builder::new(&work_dir, "oci");
+    builder.make_lower();
+    builder.build_lower("lz4_block");
+
+    let fs_sock = work_dir.join("vhost-user-fs.sock");
+    let console_sock = work_dir.join("console.sock");
+    let _nydusd = start_nydusd(&work_dir, &work_dir.join("bootstrap-lower"), &fs_sock);
+    let _vmm = start_vmm(vmm, &work_dir, &fs_sock, &console_sock);
+
+    let mut console = GuestConsole::connect(&console_sock);
+    console.check(&format!(
+        "mkdir -p {} && mount -t virtiofs {} {}",
+        GUEST_MOUNT, FS_TAG, GUEST_MOUNT
+    ));
+    info!("virtiofs mounted in the guest with {:?}", vmm);
+
+    check_conformance(&mut console);
+    check_io(&mut console, &work_dir.join("lower"));
+
+    console.check(&format!("umount {}", GUEST_MOUNT));
+    console.send("poweroff -f");
+}
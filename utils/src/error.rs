@@ -0,0 +1,249 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Errors carrying context about the failed operation.
+//!
+//! Errors are propagated as `std::io::Error` across the filesystem, storage and daemon layers,
+//! because FUSE and virtio-fs only care about error codes. Which mount, inode, chunk or backend
+//! request failed used to be logged at the failure point at most, and was lost by the time the
+//! error reached the API or the error metrics. [Error] wraps an `io::Error` with an
+//! [ErrorContext], and is carried by an `io::Error` itself, so each layer attaches what it knows
+//! with [ResultExt::context()] while propagating the error unchanged otherwise.
+//!
+//! Context is attached to errors with an OS error code, such as `EINTR` or `ENOENT`, too, which
+//! hides their error codes from `io::Error::raw_os_error()`. Use [raw_os_error()] to check them,
+//! and [preserve_errno()] before returning them to FUSE, which reports other errors as `EIO`.
+
+use std::fmt::{self, Display};
+use std::io;
+
+use serde::Serialize;
+
+/// Context about the operation failed with an error.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ErrorContext {
+    /// Mountpoint of the filesystem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mount: Option<String>,
+    /// Inode number of the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    /// Id of the data blob.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+    /// Index of the chunk in the data blob.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<u32>,
+    /// Request to the storage backend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<String>,
+}
+
+impl ErrorContext {
+    /// Set the mountpoint of the filesystem.
+    pub fn mount(mut self, mount: &str) -> Self {
+        self.mount = Some(mount.to_string());
+        self
+    }
+
+    /// Set the inode number of the file.
+    pub fn inode(mut self, inode: u64) -> Self {
+        self.inode = Some(inode);
+        self
+    }
+
+    /// Set the id of the data blob.
+    pub fn blob(mut self, blob: &str) -> Self {
+        self.blob = Some(blob.to_string());
+        self
+    }
+
+    /// Set the index of the chunk in the data blob.
+    pub fn chunk(mut self, chunk: u32) -> Self {
+        self.chunk = Some(chunk);
+        self
+    }
+
+    /// Set the request to the storage backend.
+    pub fn request<R: Display>(mut self, request: R) -> Self {
+        self.request = Some(request.to_string());
+        self
+    }
+
+    // Fill fields not set yet with those of `other`, context attached earlier is more specific.
+    fn merge(&mut self, other: ErrorContext) {
+        if self.mount.is_none() {
+            self.mount = other.mount;
+        }
+        if self.inode.is_none() {
+            self.inode = other.inode;
+        }
+        if self.blob.is_none() {
+            self.blob = other.blob;
+        }
+        if self.chunk.is_none() {
+            self.chunk = other.chunk;
+        }
+        if self.request.is_none() {
+            self.request = other.request;
+        }
+    }
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(mount) = self.mount.as_ref() {
+            fields.push(format!("mount {}", mount));
+        }
+        if let Some(inode) = self.inode {
+            fields.push(format!("inode {}", inode));
+        }
+        if let Some(blob) = self.blob.as_ref() {
+            fields.push(format!("blob {}", blob));
+        }
+        if let Some(chunk) = self.chunk {
+            fields.push(format!("chunk {}", chunk));
+        }
+        if let Some(request) = self.request.as_ref() {
+            fields.push(format!("request {}", request));
+        }
+        write!(f, "{}", fields.join(", "))
+    }
+}
+
+/// An error with context about the failed operation.
+#[derive(Debug, thiserror::Error)]
+#[error("{error} ({context})")]
+pub struct Error {
+    context: ErrorContext,
+    error: io::Error,
+}
+
+impl Error {
+    /// Get context about the failed operation.
+    pub fn context(&self) -> &ErrorContext {
+        &self.context
+    }
+}
+
+/// Attach `context` to `err`, merging with context attached before.
+pub fn with_context(err: io::Error, context: ErrorContext) -> io::Error {
+    let kind = err.kind();
+    if err.get_ref().map_or(false, |e| e.is::<Error>()) {
+        // Safe to unwrap since the error is checked above.
+        let mut inner = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        inner.context.merge(context);
+        io::Error::new(kind, *inner)
+    } else {
+        io::Error::new(
+            kind,
+            Error {
+                context,
+                error: err,
+            },
+        )
+    }
+}
+
+/// Find the error of type `E` carried by `err`, looking through errors with context.
+pub fn find_error<E: std::error::Error + 'static>(err: &io::Error) -> Option<&E> {
+    let inner = err.get_ref()?;
+    if let Some(e) = inner.downcast_ref::<E>() {
+        Some(e)
+    } else if let Some(e) = inner.downcast_ref::<Error>() {
+        find_error(&e.error)
+    } else {
+        None
+    }
+}
+
+/// Get context attached to `err`, if any.
+pub fn context_of(err: &io::Error) -> Option<&ErrorContext> {
+    find_error::<Error>(err).map(|e| e.context())
+}
+
+/// Get the OS error code of `err`, looking through errors with context.
+pub fn raw_os_error(err: &io::Error) -> Option<i32> {
+    err.raw_os_error()
+        .or_else(|| find_error::<Error>(err).and_then(|e| e.error.raw_os_error()))
+}
+
+/// Drop context attached to `err` if it carries an OS error code, so FUSE replies with the code.
+pub fn preserve_errno(err: io::Error) -> io::Error {
+    if err.raw_os_error().is_some() || raw_os_error(&err).is_none() {
+        return err;
+    }
+    // Safe to unwrap since `raw_os_error()` found the code in the `Error` carried by `err`.
+    err.into_inner().unwrap().downcast::<Error>().unwrap().error
+}
+
+/// Extension to attach context to errors of `io::Result`.
+pub trait ResultExt<T> {
+    /// Attach context created by `f` to the error, if any.
+    fn context<F: FnOnce() -> ErrorContext>(self, f: F) -> io::Result<T>;
+}
+
+impl<T> ResultExt<T> for io::Result<T> {
+    fn context<F: FnOnce() -> ErrorContext>(self, f: F) -> io::Result<T> {
+        self.map_err(|e| with_context(e, f()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_context() {
+        let err = io::Error::new(io::ErrorKind::Other, "backend failure");
+        let err = with_context(err, ErrorContext::default().blob("blob1").chunk(3));
+        let err = with_context(
+            err,
+            ErrorContext::default().mount("/sub").inode(12).chunk(4),
+        );
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(
+            err.to_string(),
+            "backend failure (mount /sub, inode 12, blob blob1, chunk 3)"
+        );
+        let context = context_of(&err).unwrap();
+        assert_eq!(context.chunk, Some(3));
+        assert_eq!(context.mount.as_deref(), Some("/sub"));
+
+        // Error codes are kept for FUSE.
+        let res: io::Result<()> = Err(io::Error::from_raw_os_error(libc::EINTR));
+        let err = res
+            .context(|| ErrorContext::default().inode(1))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(raw_os_error(&err), Some(libc::EINTR));
+        assert_eq!(context_of(&err).unwrap().inode, Some(1));
+        let err = preserve_errno(err);
+        assert_eq!(err.raw_os_error(), Some(libc::EINTR));
+        assert!(context_of(&err).is_none());
+
+        // Other errors keep their context.
+        let err = with_context(
+            io::Error::new(io::ErrorKind::Other, "backend failure"),
+            ErrorContext::default().inode(1),
+        );
+        assert!(raw_os_error(&err).is_none());
+        assert!(context_of(&preserve_errno(err)).is_some());
+    }
+
+    #[test]
+    fn test_find_error() {
+        #[derive(Debug, thiserror::Error)]
+        #[error("inner")]
+        struct Inner;
+
+        let err = io::Error::new(io::ErrorKind::Other, Inner);
+        assert!(find_error::<Inner>(&err).is_some());
+        let err = with_context(err, ErrorContext::default().inode(1));
+        assert!(find_error::<Inner>(&err).is_some());
+        assert!(find_error::<Error>(&err).is_some());
+        assert!(find_error::<Inner>(&io::Error::new(io::ErrorKind::Other, "msg")).is_none());
+    }
+}
@@ -18,16 +18,20 @@ pub use self::inode_bitmap::InodeBitmap;
 pub use self::types::*;
 
 pub mod digest;
+pub mod error;
 pub mod exec;
 pub mod inode_bitmap;
 pub mod metrics;
+pub mod profiling;
+pub mod tracing;
 pub mod types;
 
 /// Round up and divide the value `n` by `d`.
 pub fn div_round_up(n: u64, d: u64) -> u64 {
     debug_assert!(d != 0);
     debug_assert!(d.is_power_of_two());
-    (n + d - 1) / d
+    // Avoid overflow when `n` comes from untrusted metadata.
+    n / d + (n % d != 0) as u64
 }
 
 /// Round up the value `n` to by `d`.
@@ -58,6 +62,9 @@ mod tests {
 
     #[test]
     fn test_rounders() {
+        assert_eq!(div_round_up(0, 4096), 0);
+        assert_eq!(div_round_up(4097, 4096), 2);
+        assert_eq!(div_round_up(u64::MAX, 4096), (u64::MAX >> 12) + 1);
         assert_eq!(round_down_4k(0), 0);
         assert_eq!(round_down_4k(100), 0);
         assert_eq!(round_down_4k(4300), 4096);
@@ -4,15 +4,18 @@
 
 //! Rafs fop stats accounting and exporting.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display};
+use std::io;
 use std::ops::{Deref, Drop};
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 use nydus_error::logger::ErrorHolder;
 use serde_json::Error as SerdeError;
 
+use crate::error::{context_of, find_error, ErrorContext};
 use crate::InodeBitmap;
 
 pub type Inode = u64;
@@ -34,6 +37,20 @@ pub enum StatsFop {
     Access,
     Forget,
     BatchForget,
+    // Write-class operations, always rejected by read-only filesystems and counted as errors.
+    Setattr,
+    Write,
+    Mknod,
+    Mkdir,
+    Unlink,
+    Rmdir,
+    Symlink,
+    Rename,
+    Link,
+    Create,
+    Setxattr,
+    Removexattr,
+    Fallocate,
     Max,
 }
 
@@ -88,6 +105,76 @@ fn latency_micros_range_index(elapsed: u64) -> usize {
     }
 }
 
+/// Number of buckets of latency histograms, the last bucket also counts all longer latencies.
+const LATENCY_HISTOGRAM_BUCKETS: usize = 28;
+
+/// Histogram of latencies in unit of micro-seconds.
+///
+/// Bucket `i` counts latencies in range [2^(i-1), 2^i) micro-seconds, so percentiles are
+/// reported as the upper bound of the bucket they fall into.
+#[derive(Default, Debug)]
+pub struct LatencyHistogram {
+    buckets: [BasicMetric; LATENCY_HISTOGRAM_BUCKETS],
+}
+
+impl LatencyHistogram {
+    #[inline]
+    fn bucket_index(micros: u64) -> usize {
+        std::cmp::min(
+            (64 - micros.leading_zeros()) as usize,
+            LATENCY_HISTOGRAM_BUCKETS - 1,
+        )
+    }
+
+    /// Record a latency.
+    pub fn record(&self, elapsed: &Duration) {
+        let micros = saturating_duration_micros(elapsed);
+        self.buckets[Self::bucket_index(micros)].inc();
+    }
+
+    /// Get the number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.count()).sum()
+    }
+
+    /// Get the `p`th percentile latency in unit of micro-seconds, 0 if nothing is recorded.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let counts: Vec<u64> = self.buckets.iter().map(|b| b.count()).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+
+        let rank = std::cmp::max((total as f64 * p / 100.0).ceil() as u64, 1);
+        let mut seen = 0;
+        for (idx, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return 1 << idx;
+            }
+        }
+
+        1 << (LATENCY_HISTOGRAM_BUCKETS - 1)
+    }
+}
+
+impl serde::Serialize for LatencyHistogram {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("LatencyHistogram", 5)?;
+        state.serialize_field("count", &self.count())?;
+        state.serialize_field("p50_us", &self.percentile(50.0))?;
+        state.serialize_field("p90_us", &self.percentile(90.0))?;
+        state.serialize_field("p99_us", &self.percentile(99.0))?;
+        state.serialize_field("buckets", &self.buckets)?;
+        state.end()
+    }
+}
+
 // Defining below global static metrics set so that a specific metrics counter can
 // be found as per the rafs backend mountpoint/id. Remind that nydusd can have
 // multiple backends mounted.
@@ -104,6 +191,10 @@ lazy_static! {
         Default::default();
 }
 
+lazy_static! {
+    static ref BLOB_PROGRESS: RwLock<HashMap<String, Arc<BlobProgress>>> = Default::default();
+}
+
 lazy_static! {
     pub static ref ERROR_HOLDER: Arc<Mutex<ErrorHolder>> =
         Arc::new(Mutex::new(ErrorHolder::new(500, 50 * 1024)));
@@ -137,8 +228,12 @@ pub struct GlobalIoStats {
     // Record how many times read latency drops to the ranges.
     // This helps us to understand the io service time stability.
     read_latency_dist: [BasicMetric; READ_LATENCY_RANGE_MAX],
+    // Latency histograms of file operations.
+    fop_latency_hist: [LatencyHistogram; StatsFop::Max as usize],
     // Total number of files that are currently open.
     nr_opens: BasicMetric,
+    // Counters of chunks verified by the background scrubber.
+    scrub: ScrubStats,
     // Rwlock closes the race that more than one threads are creating counters concurrently.
     #[serde(skip_serializing, skip_deserializing)]
     file_counters: RwLock<HashMap<Inode, Arc<InodeIoStats>>>,
@@ -147,6 +242,10 @@ pub struct GlobalIoStats {
     // record regular file read
     #[serde(skip_serializing, skip_deserializing)]
     recent_read_files: InodeBitmap,
+    #[serde(skip_serializing, skip_deserializing)]
+    access_recorder: AccessRecorder,
+    #[serde(skip_serializing, skip_deserializing)]
+    error_stats: ErrorStats,
 }
 
 #[derive(Default, Debug, Serialize)]
@@ -194,6 +293,297 @@ impl AccessPattern {
     }
 }
 
+/// A read request recorded by [AccessRecorder](struct.AccessRecorder.html).
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessRecord {
+    /// Time elapsed since the recorder was enabled, in unit of micro-seconds.
+    timestamp_us: u64,
+    ino: Inode,
+    offset: u64,
+    size: u32,
+    /// Chunks read by the request, as pairs of blob index and chunk index.
+    chunks: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Default)]
+struct AccessRecorderState {
+    max_records: usize,
+    start: Option<Instant>,
+    start_time_secs: u64,
+    records: Vec<AccessRecord>,
+    // Number of records dropped after `max_records` records have been recorded.
+    dropped: u64,
+}
+
+/// Callback to resolve the path of a file from its inode number.
+pub type PathResolver = Box<dyn Fn(Inode) -> Option<String> + Send + Sync>;
+
+/// Records the ordered list of files and chunks read after mount, as raw material to generate
+/// prefetch hints and to understand cold start behavior of workloads.
+///
+/// At most `max_records` reads are recorded, later reads are counted but dropped. Only inode
+/// numbers are recorded on the read path, paths of the files are resolved when exporting.
+#[derive(Default)]
+pub struct AccessRecorder {
+    enabled: AtomicBool,
+    state: Mutex<AccessRecorderState>,
+    resolver: RwLock<Option<PathResolver>>,
+}
+
+impl fmt::Debug for AccessRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AccessRecorder")
+            .field("enabled", &self.enabled)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+#[derive(Serialize)]
+struct AccessRecorderExport<'a> {
+    start_time_secs: u64,
+    dropped: u64,
+    files: &'a HashMap<Inode, String>,
+    records: &'a [AccessRecord],
+}
+
+impl AccessRecorder {
+    /// Start recording at most `max_records` reads, or stop recording if `max_records` is zero.
+    ///
+    /// Records recorded so far are discarded.
+    pub fn enable(&self, max_records: usize) {
+        let mut state = self.state.lock().unwrap();
+        *state = AccessRecorderState {
+            max_records,
+            start: Some(Instant::now()),
+            start_time_secs: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            ..Default::default()
+        };
+        self.enabled.store(max_records > 0, Ordering::Release);
+    }
+
+    /// Check whether reads are being recorded.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Set the callback to resolve paths of recorded files when exporting.
+    pub fn set_path_resolver(&self, resolver: PathResolver) {
+        *self.resolver.write().unwrap() = Some(resolver);
+    }
+
+    /// Record a read of `size` bytes at `offset` of file `ino`.
+    pub fn record(&self, ino: Inode, offset: u64, size: u32, chunks: Vec<(u32, u32)>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.records.len() >= state.max_records {
+            state.dropped += 1;
+            return;
+        }
+        let timestamp_us = state
+            .start
+            .map(|s| saturating_duration_micros(&s.elapsed()))
+            .unwrap_or_default();
+        state.records.push(AccessRecord {
+            timestamp_us,
+            ino,
+            offset,
+            size,
+            chunks,
+        });
+    }
+
+    fn export(&self) -> Result<String, IoStatsError> {
+        let (start_time_secs, dropped, records) = {
+            let state = self.state.lock().unwrap();
+            (state.start_time_secs, state.dropped, state.records.clone())
+        };
+
+        // Resolve paths without holding the state, so reads being recorded aren't blocked.
+        let mut files = HashMap::new();
+        if let Some(resolver) = self.resolver.read().unwrap().as_ref() {
+            for record in records.iter() {
+                files
+                    .entry(record.ino)
+                    .or_insert_with(|| resolver(record.ino));
+            }
+        }
+        let files: HashMap<Inode, String> = files
+            .into_iter()
+            .filter_map(|(ino, path)| path.map(|p| (ino, p)))
+            .collect();
+
+        serde_json::to_string(&AccessRecorderExport {
+            start_time_secs,
+            dropped,
+            files: &files,
+            records: &records,
+        })
+        .map_err(IoStatsError::Serialize)
+    }
+}
+
+/// Maximum number of recent errors kept for each filesystem instance.
+const MAX_RECENT_ERRORS: usize = 64;
+
+/// Classes of errors encountered when serving filesystem requests.
+///
+/// Errors of all classes are reported to FUSE as EIO, the class is kept in the `io::Error` by
+/// [ErrorClass::error()](enum.ErrorClass.html#method.error) for accounting.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// The storage backend rejected the request, such as HTTP 4xx responses.
+    BackendClient,
+    /// The storage backend failed to serve the request, such as HTTP 5xx responses.
+    BackendServer,
+    /// Requests to the storage backend timed out.
+    Timeout,
+    /// Digest of chunk data doesn't match the digest recorded in filesystem metadata.
+    DigestMismatch,
+    /// Failed to decompress chunk data.
+    Decompress,
+    /// Failed to decrypt chunk data, with a wrong key or modified chunk data.
+    Decrypt,
+    /// Filesystem metadata or chunk information is invalid.
+    MetadataCorruption,
+    /// Errors not belonging to any class above.
+    Other,
+}
+
+impl ErrorClass {
+    const ALL: [ErrorClass; 8] = [
+        ErrorClass::BackendClient,
+        ErrorClass::BackendServer,
+        ErrorClass::Timeout,
+        ErrorClass::DigestMismatch,
+        ErrorClass::Decompress,
+        ErrorClass::Decrypt,
+        ErrorClass::MetadataCorruption,
+        ErrorClass::Other,
+    ];
+
+    /// Create an `io::Error` of the class with message `msg`.
+    pub fn error<M: Display>(self, msg: M) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            ClassifiedError {
+                class: self,
+                msg: msg.to_string(),
+            },
+        )
+    }
+
+    /// Get the class of `err`, errors not created by `error()` are of class `Other`.
+    pub fn of(err: &io::Error) -> ErrorClass {
+        find_error::<ClassifiedError>(err)
+            .map(|e| e.class)
+            .unwrap_or(ErrorClass::Other)
+    }
+}
+
+/// Error payload carrying an [ErrorClass](enum.ErrorClass.html).
+#[derive(Debug)]
+pub struct ClassifiedError {
+    class: ErrorClass,
+    msg: String,
+}
+
+impl Display for ClassifiedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.class, self.msg)
+    }
+}
+
+impl std::error::Error for ClassifiedError {}
+
+/// Counters of chunks verified by the background scrubber.
+#[derive(Debug, Default, Serialize)]
+pub struct ScrubStats {
+    /// Number of finished passes over all chunks of the filesystem.
+    pub passes: BasicMetric,
+    /// Number of chunks verified in the cache.
+    pub cached_chunks: BasicMetric,
+    /// Number of chunks verified on the storage backend.
+    pub remote_chunks: BasicMetric,
+    /// Number of corrupted chunks found in the cache.
+    pub corrupted_cached_chunks: BasicMetric,
+    /// Number of corrupted chunks found on the storage backend.
+    pub corrupted_remote_chunks: BasicMetric,
+}
+
+/// An error recorded by [ErrorStats](struct.ErrorStats.html).
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorRecord {
+    /// Wall-time at which the error happened, in unit of seconds.
+    timestamp_secs: u64,
+    class: ErrorClass,
+    ino: Inode,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<ErrorContext>,
+}
+
+/// Counters of errors by class, and details of the last `MAX_RECENT_ERRORS` errors.
+#[derive(Debug, Default)]
+pub struct ErrorStats {
+    counts: [BasicMetric; ErrorClass::ALL.len()],
+    recent: Mutex<VecDeque<ErrorRecord>>,
+}
+
+#[derive(Serialize)]
+struct ErrorStatsExport<'a> {
+    counts: BTreeMap<ErrorClass, u64>,
+    recent: &'a VecDeque<ErrorRecord>,
+}
+
+impl ErrorStats {
+    /// Account `err` encountered when serving a request to file `ino`.
+    pub fn record(&self, ino: Inode, err: &io::Error) {
+        let class = ErrorClass::of(err);
+        self.counts[class as usize].inc();
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= MAX_RECENT_ERRORS {
+            recent.pop_front();
+        }
+        recent.push_back(ErrorRecord {
+            timestamp_secs: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            class,
+            ino,
+            message: err.to_string(),
+            context: context_of(err).cloned(),
+        });
+    }
+
+    /// Get number of errors of `class`.
+    pub fn count(&self, class: ErrorClass) -> u64 {
+        self.counts[class as usize].count()
+    }
+
+    fn export(&self) -> Result<String, IoStatsError> {
+        let recent = self.recent.lock().unwrap();
+        serde_json::to_string(&ErrorStatsExport {
+            counts: ErrorClass::ALL
+                .iter()
+                .map(|c| (*c, self.count(*c)))
+                .collect(),
+            recent: &recent,
+        })
+        .map_err(IoStatsError::Serialize)
+    }
+}
+
 pub trait InodeStatsCounter {
     fn stats_fop_inc(&self, fop: StatsFop);
     fn stats_fop_err_inc(&self, fop: StatsFop);
@@ -263,6 +653,21 @@ impl GlobalIoStats {
         record_latest_read_files_enabled
     );
 
+    /// Get the recorder of the ordered list of reads.
+    pub fn access_recorder(&self) -> &AccessRecorder {
+        &self.access_recorder
+    }
+
+    /// Get counters and details of errors encountered by the filesystem.
+    pub fn error_stats(&self) -> &ErrorStats {
+        &self.error_stats
+    }
+
+    /// Get counters of chunks verified by the background scrubber.
+    pub fn scrub_stats(&self) -> &ScrubStats {
+        &self.scrub
+    }
+
     /// For now, each inode has its iostats counter regardless whether it is
     /// enabled per rafs.
     pub fn new_file_counter(&self, ino: Inode) {
@@ -287,6 +692,15 @@ impl GlobalIoStats {
         }
     }
 
+    /// Release the iostats counter of the file once it's forgotten by the kernel.
+    ///
+    /// Access patterns are kept because they're used to generate prefetch lists.
+    pub fn release_file_counter(&self, ino: Inode) {
+        if self.files_enabled() {
+            self.file_counters.write().unwrap().remove(&ino);
+        }
+    }
+
     fn file_stats_update(&self, ino: Inode, fop: StatsFop, bsize: usize, success: bool) {
         self.global_update(fop, bsize, success);
 
@@ -372,7 +786,7 @@ impl GlobalIoStats {
         serde_json::json!(self.recent_read_files.bitmap_to_array_and_clear()).to_string()
     }
 
-    fn export_files_access_patterns(&self) -> Result<String, IoStatsError> {
+    pub fn export_files_access_patterns(&self) -> Result<String, IoStatsError> {
         serde_json::to_string(
             &self
                 .access_patterns
@@ -403,12 +817,16 @@ pub struct FopRecorder<'a> {
     // Now, the size only makes sense for `Read` FOP.
     size: usize,
     ios: &'a GlobalIoStats,
+    start: Option<Instant>,
 }
 
 impl<'a> Drop for FopRecorder<'a> {
     fn drop(&mut self) {
         self.ios
             .file_stats_update(self.inode, self.fop, self.size, self.success);
+        if let Some(start) = self.start {
+            self.ios.fop_latency_hist[self.fop as usize].record(&start.elapsed());
+        }
     }
 }
 
@@ -418,12 +836,20 @@ impl<'a> FopRecorder<'a> {
         T: AsRef<GlobalIoStats>,
         'b: 'a,
     {
+        let ios = ios.as_ref();
+        let start = if ios.measure_latency.load(Ordering::Relaxed) {
+            Some(Instant::now())
+        } else {
+            None
+        };
+
         FopRecorder {
             fop,
             inode,
             success: false,
             size: 0,
-            ios: ios.as_ref(),
+            ios,
+            start,
         }
     }
 
@@ -480,6 +906,42 @@ pub fn export_files_access_pattern(name: &Option<String>) -> Result<String, IoSt
     }
 }
 
+pub fn export_access_records(name: &Option<String>) -> Result<String, IoStatsError> {
+    let ios_set = IOS_SET.read().unwrap();
+    match name {
+        Some(k) => ios_set
+            .get(k)
+            .ok_or(IoStatsError::NoCounter)
+            .map(|v| v.access_recorder.export())?,
+        None => {
+            if ios_set.len() == 1 {
+                if let Some(ios) = ios_set.values().next() {
+                    return ios.access_recorder.export();
+                }
+            }
+            Err(IoStatsError::NoCounter)
+        }
+    }
+}
+
+pub fn export_error_stats(name: &Option<String>) -> Result<String, IoStatsError> {
+    let ios_set = IOS_SET.read().unwrap();
+    match name {
+        Some(k) => ios_set
+            .get(k)
+            .ok_or(IoStatsError::NoCounter)
+            .map(|v| v.error_stats.export())?,
+        None => {
+            if ios_set.len() == 1 {
+                if let Some(ios) = ios_set.values().next() {
+                    return ios.error_stats.export();
+                }
+            }
+            Err(IoStatsError::NoCounter)
+        }
+    }
+}
+
 pub fn export_global_stats(name: &Option<String>) -> Result<String, IoStatsError> {
     // With only one rafs instance, we allow caller to ask for an unknown ios name.
     let ios_set = IOS_SET.read().unwrap();
@@ -538,10 +1000,71 @@ pub fn export_blobcache_metrics(id: &Option<String>) -> IoStatsResult<String> {
     }
 }
 
+/// Export download progress of the blob `id`, or of all blobs if `id` is `None`.
+pub fn export_blob_progress(id: &Option<String>) -> IoStatsResult<String> {
+    let progress = BLOB_PROGRESS.read().unwrap();
+
+    match id {
+        Some(k) => progress
+            .get(k)
+            .ok_or(IoStatsError::NoCounter)
+            .map(|v| serde_json::to_string(v.deref()).map_err(IoStatsError::Serialize))?,
+        None => {
+            let mut all: Vec<&BlobProgress> = progress.values().map(|v| v.deref()).collect();
+            all.sort_by(|a, b| a.blob_id.cmp(&b.blob_id));
+            serde_json::to_string(&all).map_err(IoStatsError::Serialize)
+        }
+    }
+}
+
 pub fn export_events() -> IoStatsResult<String> {
     serde_json::to_string(ERROR_HOLDER.lock().unwrap().deref()).map_err(IoStatsError::Serialize)
 }
 
+/// Export metrics of all filesystem instances, storage backends and blob caches, together with
+/// recent events, for snapshots of the daemon state.
+pub fn export_all_metrics() -> IoStatsResult<serde_json::Value> {
+    let to_value = |s: String| serde_json::from_str(&s).map_err(IoStatsError::Serialize);
+
+    let mut global = serde_json::Map::new();
+    let mut errors = serde_json::Map::new();
+    for (id, ios) in IOS_SET.read().unwrap().iter() {
+        global.insert(id.clone(), to_value(ios.export_global_stats()?)?);
+        errors.insert(id.clone(), to_value(ios.error_stats.export()?)?);
+    }
+    let mut backends = serde_json::Map::new();
+    for (id, m) in BACKEND_METRICS.read().unwrap().iter() {
+        backends.insert(id.clone(), to_value(m.export_metrics()?)?);
+    }
+    let mut blobcaches = serde_json::Map::new();
+    for (id, m) in BLOBCACHE_METRICS.read().unwrap().iter() {
+        blobcaches.insert(id.clone(), to_value(m.export_metrics()?)?);
+    }
+
+    Ok(serde_json::json!({
+        "global": global,
+        "errors": errors,
+        "backends": backends,
+        "blobcaches": blobcaches,
+        "blob_progress": to_value(export_blob_progress(&None)?)?,
+        "events": to_value(export_events()?)?,
+    }))
+}
+
+/// Get the number of cache hits and reads summed over all blob caches.
+pub fn blobcache_hits() -> (u64, u64) {
+    BLOBCACHE_METRICS
+        .read()
+        .unwrap()
+        .values()
+        .fold((0, 0), |(hits, total), m| {
+            (
+                hits + m.partial_hits.count() + m.whole_hits.count(),
+                total + m.total.count(),
+            )
+        })
+}
+
 pub trait Metric {
     /// Adds `value` to the current counter.
     fn add(&self, value: u64);
@@ -593,6 +1116,13 @@ pub struct BackendMetrics {
     read_count_block_size_dist: [BasicMetric; BLOCK_READ_SIZES_MAX],
     // Categorize metrics as per their latency and request size
     read_latency_sizes_dist: [[BasicMetric; READ_LATENCY_RANGE_MAX]; BLOCK_READ_SIZES_MAX],
+    // Current limit of concurrent reads from the backend host, 0 if there's no limit.
+    concurrency_limit: BasicMetric,
+    // Latency histogram of all read requests to the backend.
+    read_latency_hist: LatencyHistogram,
+    // Amount of data read from the backend for each blob, in unit of Byte.
+    #[serde(skip_serializing, skip_deserializing)]
+    blob_read_amount: RwLock<HashMap<String, BasicMetric>>,
 }
 
 impl Metric for BasicMetric {
@@ -661,10 +1191,15 @@ impl BackendMetrics {
         SystemTime::now()
     }
 
+    pub fn set_concurrency_limit(&self, limit: u64) {
+        self.concurrency_limit.0.store(limit, Ordering::Relaxed);
+    }
+
     pub fn end(&self, begin: &SystemTime, size: usize, error: bool) {
         if let Ok(d) = SystemTime::elapsed(begin) {
             let elapsed = saturating_duration_millis(&d);
 
+            self.read_latency_hist.record(&d);
             self.read_count.inc();
             if error {
                 self.read_errors.inc();
@@ -680,6 +1215,30 @@ impl BackendMetrics {
         }
     }
 
+    /// Account `size` bytes read from the backend for the blob `blob_id`.
+    pub fn blob_read(&self, blob_id: &str, size: usize) {
+        if let Some(m) = self.blob_read_amount.read().unwrap().get(blob_id) {
+            m.add(size as u64);
+            return;
+        }
+        self.blob_read_amount
+            .write()
+            .unwrap()
+            .entry(blob_id.to_string())
+            .or_default()
+            .add(size as u64);
+    }
+
+    /// Get amount of data read from the backend for the blob `blob_id`.
+    pub fn blob_read_amount(&self, blob_id: &str) -> u64 {
+        self.blob_read_amount
+            .read()
+            .unwrap()
+            .get(blob_id)
+            .map(|m| m.count())
+            .unwrap_or_default()
+    }
+
     fn export_metrics(&self) -> IoStatsResult<String> {
         serde_json::to_string(self).map_err(IoStatsError::Serialize)
     }
@@ -709,7 +1268,13 @@ pub struct BlobcacheMetrics {
     pub prefetch_mr_count: BasicMetric,
     pub prefetch_workers: AtomicUsize,
     pub prefetch_unmerged_chunks: BasicMetric,
+    // Amount of prefetch data skipped since it has been prefetched before restarts, in unit of Bytes.
+    pub prefetch_skipped_amount: BasicMetric,
     pub buffered_backend_size: BasicMetric,
+    // Latency histogram of reads served from the cache file only.
+    pub read_latency_hit: LatencyHistogram,
+    // Latency histogram of reads needing data from the backend.
+    pub read_latency_miss: LatencyHistogram,
 }
 
 impl BlobcacheMetrics {
@@ -745,10 +1310,435 @@ impl BlobcacheMetrics {
     }
 }
 
+/*
+Lazy pull metrics of an image look like:
+```json
+{"image_size": 104857600, "downloaded_bytes": 7340032, "saved_ratio": 0.93,
+ "cache_reads": 2000, "cache_hits": 1800, "hit_ratio": 0.9}
+```
+*/
+/// Number of progress milestones of a blob, reported to the events stream when reached.
+const PROGRESS_MILESTONES: u64 = 4;
+
+/// Progress of downloading a blob from the storage backend into the blob cache.
+///
+/// Chunks are accounted once they are ready in the blob cache, so retried and amplified backend
+/// reads don't inflate the progress, and chunks cached before restart are accounted on creation.
+#[derive(Debug, Default)]
+pub struct BlobProgress {
+    blob_id: String,
+    // Compressed size of the blob, in unit of Byte.
+    blob_size: u64,
+    // Compressed size of chunks already cached on creation, in unit of Byte.
+    cached_bytes: u64,
+    fetched_bytes: BasicMetric,
+    // Wall-time in milliseconds when a chunk of the blob is fetched for the first time, 0 if not
+    // fetched yet.
+    start_millis: AtomicU64,
+    // Last milestone reported to the events stream.
+    milestone: AtomicU8,
+}
+
+#[derive(Serialize)]
+struct BlobProgressExport<'a> {
+    blob_id: &'a str,
+    blob_size: u64,
+    fetched_bytes: u64,
+    // Portion of the blob which has been fetched, in range [0, 1].
+    progress: f64,
+    // Average download rate since the first fetch, in unit of Byte per second.
+    rate: u64,
+    // Estimated seconds to fetch the rest of the blob, absent if unknown.
+    eta_secs: Option<u64>,
+}
+
+fn wall_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl BlobProgress {
+    /// Create and register a progress tracker for blob `blob_id` of `blob_size` bytes, of which
+    /// chunks of `cached_bytes` bytes are already in the blob cache.
+    pub fn new(blob_id: &str, blob_size: u64, cached_bytes: u64) -> Arc<Self> {
+        let progress = Arc::new(BlobProgress {
+            blob_id: blob_id.to_string(),
+            blob_size,
+            cached_bytes,
+            fetched_bytes: BasicMetric(AtomicU64::new(cached_bytes)),
+            milestone: AtomicU8::new(Self::milestone_of(cached_bytes, blob_size)),
+            ..Default::default()
+        });
+
+        BLOB_PROGRESS
+            .write()
+            .unwrap()
+            .insert(blob_id.to_string(), progress.clone());
+
+        progress
+    }
+
+    /// Unregister the progress tracker.
+    pub fn release(self: &Arc<Self>) {
+        let mut all = BLOB_PROGRESS.write().unwrap();
+        if all
+            .get(&self.blob_id)
+            .map(|p| Arc::ptr_eq(p, self))
+            .unwrap_or(false)
+        {
+            all.remove(&self.blob_id);
+        }
+    }
+
+    fn milestone_of(fetched: u64, blob_size: u64) -> u8 {
+        if blob_size == 0 {
+            return 0;
+        }
+        std::cmp::min(
+            fetched * PROGRESS_MILESTONES / blob_size,
+            PROGRESS_MILESTONES,
+        ) as u8
+    }
+
+    /// Account a chunk of `size` bytes of compressed data becoming ready in the blob cache.
+    pub fn ready(&self, size: u64) {
+        if size == 0 || self.blob_size == 0 {
+            return;
+        }
+        let _ = self.start_millis.compare_exchange(
+            0,
+            wall_time_millis(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        let fetched = self.fetched_bytes.0.fetch_add(size, Ordering::Relaxed) + size;
+
+        let milestone = Self::milestone_of(fetched, self.blob_size);
+        if self.milestone.fetch_max(milestone, Ordering::Relaxed) < milestone {
+            let event = format!(
+                "blob {} fetched {}% ({} of {} bytes)",
+                self.blob_id,
+                milestone as u64 * 100 / PROGRESS_MILESTONES,
+                std::cmp::min(fetched, self.blob_size),
+                self.blob_size
+            );
+            ERROR_HOLDER
+                .lock()
+                .unwrap()
+                .push(&event)
+                .unwrap_or_else(|_| error!("Failed when try to hold event"));
+        }
+    }
+
+    fn export(&self) -> BlobProgressExport {
+        let fetched = std::cmp::min(self.fetched_bytes.count(), self.blob_size);
+        let progress = if self.blob_size == 0 {
+            1.0
+        } else {
+            fetched as f64 / self.blob_size as f64
+        };
+        let start = self.start_millis.load(Ordering::Relaxed);
+        let elapsed = if start == 0 {
+            0.0
+        } else {
+            wall_time_millis().saturating_sub(start) as f64 / 1000.0
+        };
+        // Chunks cached before creation are not downloaded at the current rate.
+        let downloaded = fetched.saturating_sub(self.cached_bytes);
+        let rate = if elapsed > 0.0 {
+            (downloaded as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let eta_secs = if fetched >= self.blob_size {
+            Some(0)
+        } else if rate > 0 {
+            Some((self.blob_size - fetched) / rate)
+        } else {
+            None
+        };
+
+        BlobProgressExport {
+            blob_id: &self.blob_id,
+            blob_size: self.blob_size,
+            fetched_bytes: fetched,
+            progress,
+            rate,
+            eta_secs,
+        }
+    }
+}
+
+impl serde::Serialize for BlobProgress {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serde::Serialize::serialize(&self.export(), serializer)
+    }
+}
+
+/// How much data of an image has actually been downloaded compared to the size of the
+/// whole image, and how many reads have been served by the blob cache.
+#[derive(Debug, Default, Serialize)]
+pub struct PullMetrics {
+    // Sum of compressed sizes of all data blobs, in unit of Byte.
+    pub image_size: u64,
+    // Amount of data read from the storage backend, including prefetch, in unit of Byte.
+    pub downloaded_bytes: u64,
+    // Portion of the image which has not been downloaded.
+    pub saved_ratio: f64,
+    pub cache_reads: u64,
+    pub cache_hits: u64,
+    pub hit_ratio: f64,
+}
+
+impl PullMetrics {
+    /// Collect metrics for an image composed of `blobs`, as pairs of blob id and compressed
+    /// blob size, and served by the blob cache with id `cache_id`.
+    ///
+    /// Downloaded data is accounted per blob, so data downloaded for a blob shared by several
+    /// images is accounted to each of them. Blob cache metrics are shared by images mounted
+    /// with the same cache id.
+    pub fn collect(cache_id: &str, blobs: &[(String, u64)]) -> Self {
+        let mut m = PullMetrics::default();
+        let backends = BACKEND_METRICS.read().unwrap();
+        for (id, size) in blobs {
+            m.image_size += size;
+            // A backend serves all blobs of the caches created with the same configuration.
+            m.downloaded_bytes += backends
+                .values()
+                .map(|b| b.blob_read_amount(id))
+                .sum::<u64>();
+        }
+        if let Some(c) = BLOBCACHE_METRICS.read().unwrap().get(cache_id) {
+            m.cache_reads = c.total.count();
+            m.cache_hits = c.partial_hits.count() + c.whole_hits.count();
+        }
+
+        if m.image_size != 0 {
+            let downloaded = std::cmp::min(m.downloaded_bytes, m.image_size);
+            m.saved_ratio = 1.0 - downloaded as f64 / m.image_size as f64;
+        }
+        if m.cache_reads != 0 {
+            m.hit_ratio = m.cache_hits as f64 / m.cache_reads as f64;
+        }
+
+        m
+    }
+}
+
+/// Memory used by a mounted filesystem.
+#[derive(Debug, Default, Serialize)]
+pub struct MemoryMetrics {
+    // Memory used by filesystem metadata, in unit of Byte.
+    pub metadata: u64,
+    // Memory used by state of blob caches, such as chunk maps and blob metadata, in unit of Byte.
+    // It's shared by filesystems using the same blobs.
+    pub cache_state: u64,
+    // Data fetched from the storage backend but not persisted into the blob cache yet, in
+    // unit of Byte. It's shared by filesystems with the same blob cache id.
+    pub inflight_buffers: u64,
+}
+
+impl MemoryMetrics {
+    /// Collect metrics for a filesystem with `metadata` bytes of metadata and `cache_state` bytes
+    /// of blob cache state in memory, and served by the blob cache with id `cache_id`.
+    pub fn collect(cache_id: &str, metadata: u64, cache_state: u64) -> Self {
+        let inflight_buffers = BLOBCACHE_METRICS
+            .read()
+            .unwrap()
+            .get(cache_id)
+            .map(|c| c.buffered_backend_size.count())
+            .unwrap_or_default();
+
+        MemoryMetrics {
+            metadata,
+            cache_state,
+            inflight_buffers,
+        }
+    }
+}
+
+/// Get resident set size of the process, in unit of Byte.
+pub fn process_rss() -> std::io::Result<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm")?;
+    let pages = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| einval!(format!("invalid /proc/self/statm: {}", statm)))?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+    Ok(pages * page_size)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_process_rss() {
+        assert!(process_rss().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_access_recorder() {
+        let recorder = AccessRecorder::default();
+        recorder.record(2, 0, 0x1000, vec![(0, 0)]);
+        assert!(recorder.state.lock().unwrap().records.is_empty());
+
+        recorder.enable(3);
+        recorder.record(2, 0, 0x1000, vec![(0, 0)]);
+        recorder.record(2, 0x1000, 0x1000, vec![(0, 0)]);
+        recorder.record(3, 0, 0x1000, vec![(0, 1)]);
+        recorder.record(4, 0, 0x1000, vec![(0, 2)]);
+        {
+            let state = recorder.state.lock().unwrap();
+            assert_eq!(state.records.len(), 3);
+            assert_eq!(state.records[1].offset, 0x1000);
+            assert!(state.records[0].timestamp_us <= state.records[1].timestamp_us);
+            assert_eq!(state.dropped, 1);
+        }
+        let exported: serde_json::Value =
+            serde_json::from_str(&recorder.export().unwrap()).unwrap();
+        assert!(exported["files"].as_object().unwrap().is_empty());
+        assert_eq!(exported["records"][0]["chunks"][0][1], 0);
+
+        // Each file is resolved once, and files failed to resolve are left out.
+        let resolved = Arc::new(AtomicUsize::new(0));
+        let count = resolved.clone();
+        recorder.set_path_resolver(Box::new(move |ino| {
+            count.fetch_add(1, Ordering::Relaxed);
+            if ino == 2 {
+                Some("/a".to_string())
+            } else {
+                None
+            }
+        }));
+        let exported: serde_json::Value =
+            serde_json::from_str(&recorder.export().unwrap()).unwrap();
+        assert_eq!(exported["files"]["2"], "/a");
+        assert_eq!(exported["files"].as_object().unwrap().len(), 1);
+        assert_eq!(resolved.load(Ordering::Relaxed), 2);
+
+        recorder.enable(0);
+        assert!(!recorder.is_enabled());
+        assert!(recorder.state.lock().unwrap().records.is_empty());
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        assert_eq!(LatencyHistogram::bucket_index(0), 0);
+        assert_eq!(LatencyHistogram::bucket_index(1), 1);
+        assert_eq!(LatencyHistogram::bucket_index(3), 2);
+        assert_eq!(LatencyHistogram::bucket_index(1024), 11);
+        assert_eq!(
+            LatencyHistogram::bucket_index(u64::MAX),
+            LATENCY_HISTOGRAM_BUCKETS - 1
+        );
+
+        let hist = LatencyHistogram::default();
+        assert_eq!(hist.percentile(99.0), 0);
+        for _ in 0..90 {
+            hist.record(&Duration::from_micros(100));
+        }
+        for _ in 0..9 {
+            hist.record(&Duration::from_millis(10));
+        }
+        hist.record(&Duration::from_secs(1));
+        assert_eq!(hist.count(), 100);
+        assert_eq!(hist.percentile(50.0), 128);
+        assert_eq!(hist.percentile(90.0), 128);
+        assert_eq!(hist.percentile(99.0), 16384);
+        assert_eq!(hist.percentile(100.0), 1 << 20);
+
+        let exported: serde_json::Value = serde_json::to_value(&hist).unwrap();
+        assert_eq!(exported["p99_us"], 16384);
+        assert_eq!(exported["buckets"][7], 90);
+    }
+
+    #[test]
+    fn test_error_stats() {
+        let err = ErrorClass::DigestMismatch.error("chunk 0x10");
+        assert_eq!(ErrorClass::of(&err), ErrorClass::DigestMismatch);
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(
+            ErrorClass::of(&io::Error::from_raw_os_error(libc::EIO)),
+            ErrorClass::Other
+        );
+
+        let stats = ErrorStats::default();
+        for _ in 0..MAX_RECENT_ERRORS {
+            stats.record(2, &ErrorClass::Timeout.error("timed out"));
+        }
+        stats.record(3, &err);
+        assert_eq!(stats.count(ErrorClass::Timeout), MAX_RECENT_ERRORS as u64);
+        assert_eq!(stats.count(ErrorClass::DigestMismatch), 1);
+        assert_eq!(stats.count(ErrorClass::Other), 0);
+
+        let exported: serde_json::Value = serde_json::from_str(&stats.export().unwrap()).unwrap();
+        assert_eq!(exported["counts"]["digest_mismatch"], 1);
+        assert_eq!(exported["counts"]["backend_server"], 0);
+        let recent = exported["recent"].as_array().unwrap();
+        assert_eq!(recent.len(), MAX_RECENT_ERRORS);
+        assert_eq!(recent[MAX_RECENT_ERRORS - 1]["class"], "digest_mismatch");
+        assert_eq!(recent[MAX_RECENT_ERRORS - 1]["ino"], 3);
+    }
+
+    #[test]
+    fn test_export_all_metrics() {
+        let ios = new("test-all-metrics");
+        ios.error_stats()
+            .record(5, &ErrorClass::Timeout.error("timed out"));
+
+        let exported = export_all_metrics().unwrap();
+        assert!(exported["global"]["test-all-metrics"].is_object());
+        assert_eq!(
+            exported["errors"]["test-all-metrics"]["counts"]["timeout"],
+            1
+        );
+        assert!(exported["blob_progress"].is_array());
+        assert!(exported["backends"].is_object());
+    }
+
+    #[test]
+    fn test_blob_progress() {
+        let p = BlobProgress::new("test-progress-blob", 0x4000, 0);
+        let exported: serde_json::Value = serde_json::from_str(
+            &export_blob_progress(&Some("test-progress-blob".to_string())).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(exported["fetched_bytes"], 0);
+        assert_eq!(exported["eta_secs"], serde_json::Value::Null);
+
+        p.ready(0x1000);
+        assert_eq!(p.milestone.load(Ordering::Relaxed), 1);
+        p.ready(0x800);
+        assert_eq!(p.milestone.load(Ordering::Relaxed), 1);
+        p.ready(0x2800);
+        assert_eq!(p.milestone.load(Ordering::Relaxed), 4);
+        let exported = p.export();
+        assert_eq!(exported.fetched_bytes, 0x4000);
+        assert_eq!(exported.eta_secs, Some(0));
+        assert!((exported.progress - 1.0).abs() < f64::EPSILON);
+
+        // A released tracker doesn't unregister the tracker replacing it.
+        let q = BlobProgress::new("test-progress-blob", 0x4000, 0x2000);
+        p.release();
+        assert!(export_blob_progress(&Some("test-progress-blob".to_string())).is_ok());
+        // Chunks cached before creation are accounted, without reaching milestones again.
+        assert_eq!(q.milestone.load(Ordering::Relaxed), 2);
+        let exported = q.export();
+        assert_eq!(exported.fetched_bytes, 0x2000);
+        assert_eq!(exported.rate, 0);
+        assert_eq!(exported.eta_secs, None);
+        q.release();
+        assert!(export_blob_progress(&Some("test-progress-blob".to_string())).is_err());
+    }
+
     #[test]
     fn test_request_size_index() {
         assert_eq!(request_size_index(0x0), 0);
@@ -791,4 +1781,44 @@ mod tests {
         g.global_update(StatsFop::Read, 2015520, true);
         assert_eq!(g.block_count_read[3].count(), 2);
     }
+
+    #[test]
+    fn test_pull_metrics() {
+        let b1 = BackendMetrics::new("test-pull-blob1", "localfs");
+        let b2 = BackendMetrics::new("test-pull-backend2", "localfs");
+        let c = BlobcacheMetrics::new("test-pull-cache", "/tmp");
+        // The backend named after the first blob also serves the second one.
+        b1.blob_read("test-pull-blob1", 0x800);
+        b1.blob_read("test-pull-blob2", 0x2000);
+        b1.blob_read("test-pull-blob1", 0x800);
+        b2.blob_read("test-pull-blob2", 0x1000);
+        b2.blob_read("test-pull-other", 0x1000);
+        assert_eq!(b1.blob_read_amount("test-pull-blob1"), 0x1000);
+        assert_eq!(b1.blob_read_amount("test-pull-blob3"), 0);
+        c.total.add(10);
+        c.whole_hits.add(6);
+        c.partial_hits.add(2);
+
+        let blobs = vec![
+            ("test-pull-blob1".to_string(), 0x4000),
+            ("test-pull-blob2".to_string(), 0x4000),
+            ("test-pull-blob3".to_string(), 0x8000),
+        ];
+        let m = PullMetrics::collect("test-pull-cache", &blobs);
+        assert_eq!(m.image_size, 0x10000);
+        assert_eq!(m.downloaded_bytes, 0x4000);
+        assert!((m.saved_ratio - 0.75).abs() < f64::EPSILON);
+        assert_eq!(m.cache_reads, 10);
+        assert_eq!(m.cache_hits, 8);
+        assert!((m.hit_ratio - 0.8).abs() < f64::EPSILON);
+
+        let m = PullMetrics::collect("test-pull-nocache", &[]);
+        assert_eq!(m.image_size, 0);
+        assert_eq!(m.cache_reads, 0);
+        assert_eq!(m.saved_ratio, 0.0);
+
+        b1.release().unwrap();
+        b2.release().unwrap();
+        c.release().unwrap();
+    }
 }
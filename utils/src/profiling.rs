@@ -0,0 +1,137 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! On demand profiling of the running process.
+//!
+//! CPU profiles are sampled by pprof-rs and only available with the `pprof` feature.
+//!
+//! There's no heap profiler, allocations aren't tracked by call stacks. Instead, statistics of
+//! the glibc allocator reported by `malloc_info(3)` are available, which show the size of memory
+//! in use, free and cached by each arena, to tell whether memory is held by the allocator.
+
+use std::io::Result;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Sampling frequency of CPU profiles, in unit of Hz.
+pub const SAMPLING_FREQUENCY: i32 = 99;
+
+/// Output format of CPU profiles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProfileFormat {
+    /// Protocol buffers of pprof, to be analyzed by `go tool pprof`.
+    Protobuf,
+    /// Flame graph in SVG.
+    Flamegraph,
+}
+
+impl FromStr for ProfileFormat {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "protobuf" | "pb" => Ok(ProfileFormat::Protobuf),
+            "flamegraph" | "svg" => Ok(ProfileFormat::Flamegraph),
+            _ => Err(einval!(format!("invalid profile format {}", s))),
+        }
+    }
+}
+
+/// Sample call stacks of all threads for `duration`, and return the profile in `format`.
+///
+/// The calling thread sleeps during sampling, and only one profile may be collected at a time.
+#[cfg(feature = "pprof")]
+pub fn cpu_profile(duration: Duration, format: ProfileFormat) -> Result<Vec<u8>> {
+    use pprof::protos::Message;
+
+    let guard = pprof::ProfilerGuard::new(SAMPLING_FREQUENCY).map_err(|e| eother!(e))?;
+    std::thread::sleep(duration);
+    let report = guard.report().build().map_err(|e| eother!(e))?;
+
+    let mut buf = Vec::new();
+    match format {
+        ProfileFormat::Protobuf => {
+            let profile = report.pprof().map_err(|e| eother!(e))?;
+            profile.encode(&mut buf).map_err(|e| eother!(e))?;
+        }
+        ProfileFormat::Flamegraph => report.flamegraph(&mut buf).map_err(|e| eother!(e))?,
+    }
+
+    Ok(buf)
+}
+
+/// Sample call stacks of all threads for `duration`, and return the profile in `format`.
+#[cfg(not(feature = "pprof"))]
+pub fn cpu_profile(_duration: Duration, _format: ProfileFormat) -> Result<Vec<u8>> {
+    Err(enosys!(
+        "CPU profiling is not supported, please build with the pprof feature"
+    ))
+}
+
+/// Get statistics of the glibc allocator as the XML document generated by `malloc_info(3)`.
+#[cfg(target_env = "gnu")]
+pub fn malloc_info() -> Result<Vec<u8>> {
+    let mut ptr: *mut libc::c_char = std::ptr::null_mut();
+    let mut size: libc::size_t = 0;
+    // Safe because `ptr` and `size` are valid until the stream is closed.
+    let stream = unsafe { libc::open_memstream(&mut ptr, &mut size) };
+    if stream.is_null() {
+        return Err(last_error!("failed to open memory stream"));
+    }
+
+    // Safe because the stream is valid.
+    let ret = unsafe { libc::malloc_info(0, stream) };
+    let err = std::io::Error::last_os_error();
+    // Safe because the stream is valid, `ptr` and `size` are updated when it's closed.
+    unsafe { libc::fclose(stream) };
+    let data = if ptr.is_null() {
+        Vec::new()
+    } else {
+        // Safe because the buffer of `size` bytes is allocated by the stream, and freed once.
+        unsafe {
+            let data = std::slice::from_raw_parts(ptr as *const u8, size).to_vec();
+            libc::free(ptr as *mut libc::c_void);
+            data
+        }
+    };
+
+    if ret != 0 {
+        Err(err)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Get statistics of the glibc allocator as the XML document generated by `malloc_info(3)`.
+#[cfg(not(target_env = "gnu"))]
+pub fn malloc_info() -> Result<Vec<u8>> {
+    Err(enosys!(
+        "allocator statistics are only supported with glibc"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_format() {
+        assert_eq!(
+            ProfileFormat::from_str("pb").unwrap(),
+            ProfileFormat::Protobuf
+        );
+        assert_eq!(
+            ProfileFormat::from_str("flamegraph").unwrap(),
+            ProfileFormat::Flamegraph
+        );
+        assert!(ProfileFormat::from_str("json").is_err());
+    }
+
+    #[cfg(target_env = "gnu")]
+    #[test]
+    fn test_malloc_info() {
+        let data = malloc_info().unwrap();
+        assert!(String::from_utf8(data).unwrap().starts_with("<malloc"));
+    }
+}
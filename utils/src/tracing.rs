@@ -0,0 +1,320 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Optional distributed tracing with OpenTelemetry.
+//!
+//! Spans are created by [span()](fn.span.html) as children of the span active on the calling
+//! thread, and exported to an OTLP collector after [init()](fn.init.html) has been called. Without
+//! the `otel` feature, spans are empty and [init()](fn.init.html) always fails.
+//!
+//! The active span is thread local, threads doing work on behalf of another thread may inherit
+//! its span by [TraceContext::attach()](struct.TraceContext.html#method.attach).
+
+use std::io::Result;
+
+#[cfg(feature = "otel")]
+mod imp {
+    use std::borrow::Cow;
+    use std::io::Result;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::trace::{TraceContextExt, Tracer};
+    use opentelemetry::{global, runtime, Context, ContextGuard, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+
+    // Spans are only created once exporting has been set up, so the I/O path doesn't pay for
+    // tracing when it's disabled.
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+
+    pub struct SpanGuard {
+        cx: Option<Context>,
+        guard: Option<ContextGuard>,
+    }
+
+    impl SpanGuard {
+        fn recording(&self) -> Option<&Context> {
+            self.cx.as_ref().filter(|cx| cx.span().is_recording())
+        }
+
+        pub fn set_attribute_i64(&self, key: &'static str, value: i64) {
+            if let Some(cx) = self.recording() {
+                cx.span().set_attribute(KeyValue::new(key, value));
+            }
+        }
+
+        pub fn set_attribute_str(&self, key: &'static str, value: &str) {
+            if let Some(cx) = self.recording() {
+                cx.span()
+                    .set_attribute(KeyValue::new(key, value.to_string()));
+            }
+        }
+    }
+
+    impl Drop for SpanGuard {
+        fn drop(&mut self) {
+            // Restore the parent context before ending the span, so the ended span is never the
+            // active one.
+            drop(self.guard.take());
+            if let Some(cx) = self.cx.take() {
+                cx.span().end();
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct TraceContext(Context);
+
+    impl TraceContext {
+        pub fn current() -> Self {
+            TraceContext(Context::current())
+        }
+
+        pub fn attach(self) -> TraceContextGuard {
+            self.0.attach()
+        }
+    }
+
+    pub type TraceContextGuard = ContextGuard;
+
+    pub fn span(name: &'static str) -> SpanGuard {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return SpanGuard {
+                cx: None,
+                guard: None,
+            };
+        }
+
+        let span = global::tracer("nydus").start(Cow::Borrowed(name));
+        let cx = Context::current_with_span(span);
+        let guard = Some(cx.clone().attach());
+
+        SpanGuard {
+            cx: Some(cx),
+            guard,
+        }
+    }
+
+    pub fn init(endpoint: &str, service: &str) -> Result<()> {
+        // Spans are queued and exported in batches by a background thread, instead of blocking
+        // the thread ending the span.
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(
+                trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    service.to_string(),
+                )])),
+            )
+            .install_batch(runtime::TokioCurrentThread)
+            .map_err(|e| eother!(e))?;
+        ENABLED.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn shutdown() {
+        ENABLED.store(false, Ordering::Relaxed);
+        global::shutdown_tracer_provider();
+    }
+
+    #[cfg(test)]
+    pub(super) fn enable() {
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod imp {
+    use std::io::Result;
+
+    pub struct SpanGuard {}
+
+    impl SpanGuard {
+        #[inline]
+        pub fn set_attribute_i64(&self, _key: &'static str, _value: i64) {}
+
+        #[inline]
+        pub fn set_attribute_str(&self, _key: &'static str, _value: &str) {}
+    }
+
+    #[derive(Clone)]
+    pub struct TraceContext {}
+
+    impl TraceContext {
+        #[inline]
+        pub fn current() -> Self {
+            TraceContext {}
+        }
+
+        #[inline]
+        pub fn attach(self) -> TraceContextGuard {
+            TraceContextGuard {}
+        }
+    }
+
+    pub struct TraceContextGuard {}
+
+    #[inline]
+    pub fn span(_name: &'static str) -> SpanGuard {
+        SpanGuard {}
+    }
+
+    pub fn init(_endpoint: &str, _service: &str) -> Result<()> {
+        Err(enosys!(
+            "tracing is not supported, please build with the otel feature"
+        ))
+    }
+
+    pub fn shutdown() {}
+}
+
+/// A span active on the calling thread until the guard is dropped.
+pub struct SpanGuard(imp::SpanGuard);
+
+impl SpanGuard {
+    /// Attach an integer attribute to the span.
+    pub fn set_attribute_i64(&self, key: &'static str, value: i64) {
+        self.0.set_attribute_i64(key, value)
+    }
+
+    /// Attach a string attribute to the span, which is only copied if the span is being recorded.
+    pub fn set_attribute_str(&self, key: &'static str, value: &str) {
+        self.0.set_attribute_str(key, value)
+    }
+}
+
+/// Tracing context of a thread, to be inherited by helper threads.
+#[derive(Clone)]
+pub struct TraceContext(imp::TraceContext);
+
+impl TraceContext {
+    /// Get the tracing context of the calling thread.
+    pub fn current() -> Self {
+        TraceContext(imp::TraceContext::current())
+    }
+
+    /// Make the context active on the calling thread, spans created afterwards are children of
+    /// the active span in the context.
+    ///
+    /// The context stays active until the returned guard is dropped.
+    pub fn attach(self) -> TraceContextGuard {
+        TraceContextGuard(self.0.attach())
+    }
+}
+
+/// Guard to keep a [TraceContext](struct.TraceContext.html) active on the calling thread.
+pub struct TraceContextGuard(#[allow(dead_code)] imp::TraceContextGuard);
+
+/// Start a span named `name` as a child of the active span of the calling thread.
+pub fn span(name: &'static str) -> SpanGuard {
+    SpanGuard(imp::span(name))
+}
+
+/// Export spans to the OTLP/HTTP collector at `endpoint`, e.g. "http://localhost:4318/v1/traces".
+pub fn init(endpoint: &str, service: &str) -> Result<()> {
+    imp::init(endpoint, service)
+}
+
+/// Flush spans not exported yet and stop exporting spans.
+pub fn shutdown() {
+    imp::shutdown()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "otel"))]
+    #[test]
+    fn test_tracing_unsupported() {
+        let span = span("outer");
+        span.set_attribute_i64("size", 0x1000);
+        let _guard = TraceContext::current().attach();
+        assert_eq!(
+            init("http://localhost:4318/v1/traces", "test")
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOSYS)
+        );
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_span_nesting() {
+        use std::sync::{Arc, Mutex};
+
+        use opentelemetry::sdk::export::trace::SpanData;
+        use opentelemetry::sdk::trace::{Span, SpanProcessor, TracerProvider};
+        use opentelemetry::trace::{TraceContextExt, TraceResult};
+        use opentelemetry::{global, Context, Key, Value};
+
+        #[derive(Clone, Debug, Default)]
+        struct Collector(Arc<Mutex<Vec<SpanData>>>);
+
+        impl SpanProcessor for Collector {
+            fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+            fn on_end(&self, span: SpanData) {
+                self.0.lock().unwrap().push(span);
+            }
+
+            fn force_flush(&self) -> TraceResult<()> {
+                Ok(())
+            }
+
+            fn shutdown(&mut self) -> TraceResult<()> {
+                Ok(())
+            }
+        }
+
+        let collector = Collector::default();
+        let provider = TracerProvider::builder()
+            .with_span_processor(collector.clone())
+            .build();
+        let _ = global::set_tracer_provider(provider);
+
+        // Nothing is recorded before tracing is enabled.
+        span("disabled").set_attribute_i64("size", 0);
+        assert!(collector.0.lock().unwrap().is_empty());
+
+        imp::enable();
+        {
+            let outer = span("outer");
+            outer.set_attribute_i64("size", 0x1000);
+            let cx = TraceContext::current();
+            std::thread::spawn(move || {
+                let _guard = cx.attach();
+                let inner = span("inner");
+                inner.set_attribute_str("blob", "blob1");
+            })
+            .join()
+            .unwrap();
+            assert!(Context::current().has_active_span());
+        }
+        // The parent context is restored once the span ends.
+        assert!(!Context::current().has_active_span());
+
+        let spans = collector.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        let (inner, outer) = (&spans[0], &spans[1]);
+        assert_eq!(inner.name, "inner");
+        assert_eq!(outer.name, "outer");
+        assert_eq!(inner.parent_span_id, outer.span_context.span_id());
+        assert_eq!(inner.span_context.trace_id(), outer.span_context.trace_id());
+        assert_eq!(
+            inner.attributes.get(&Key::new("blob")),
+            Some(&Value::from("blob1"))
+        );
+        assert_eq!(
+            outer.attributes.get(&Key::new("size")),
+            Some(&Value::I64(0x1000))
+        );
+    }
+}